@@ -0,0 +1,27 @@
+fn main() {
+    // The BPF program of the libbpf backend needs clang to build, so it is only
+    // built when the backend is enabled
+    #[cfg(feature = "libbpf")]
+    build_bpf();
+}
+
+#[cfg(feature = "libbpf")]
+fn build_bpf() {
+    let source = "src/bpf/wachy.bpf.c";
+    let out_dir = std::path::PathBuf::from(std::env::var_os("OUT_DIR").unwrap());
+    // `PT_REGS_SP` of bpf_tracing.h needs to know the architecture's registers
+    let arch = match std::env::var("CARGO_CFG_TARGET_ARCH").unwrap().as_str() {
+        "x86_64" => "x86",
+        "aarch64" => "arm64",
+        "riscv64" => "riscv",
+        "s390x" => "s390",
+        "powerpc64" => "powerpc",
+        arch => panic!("The libbpf backend doesn't support {}", arch),
+    };
+    libbpf_cargo::SkeletonBuilder::new()
+        .source(source)
+        .clang_args(format!("-D__TARGET_ARCH_{}", arch))
+        .build_and_generate(out_dir.join("wachy.skel.rs"))
+        .unwrap_or_else(|err| panic!("Failed to build {}: {}", source, err));
+    println!("cargo:rerun-if-changed={}", source);
+}
@@ -0,0 +1,125 @@
+use crate::program::{FunctionName, OBJECT_SEPARATOR};
+use crate::templates;
+use crate::trace_structs::TraceStack;
+use std::collections::HashMap;
+
+/// How a function allocates or frees memory
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AllocatorKind {
+    Malloc,
+    Calloc,
+    Realloc,
+    /// C++ `operator new`, which usually allocates with `malloc` itself
+    OperatorNew,
+    Free,
+}
+
+impl AllocatorKind {
+    /// bpftrace expression of the bytes allocated on entry, `None` if it
+    /// doesn't allocate
+    pub fn size_expr(&self) -> Option<&'static str> {
+        match self {
+            AllocatorKind::Malloc | AllocatorKind::OperatorNew => Some("arg0"),
+            AllocatorKind::Calloc => Some("arg0 * arg1"),
+            AllocatorKind::Realloc => Some("arg1"),
+            AllocatorKind::Free => None,
+        }
+    }
+}
+
+/// Exported names of the allocators, `operator new` and `operator new[]` as
+/// mangled on 64 bit platforms
+const ALLOCATORS: [(&str, AllocatorKind); 6] = [
+    ("malloc", AllocatorKind::Malloc),
+    ("calloc", AllocatorKind::Calloc),
+    ("realloc", AllocatorKind::Realloc),
+    ("_Znwm", AllocatorKind::OperatorNew),
+    ("_Znam", AllocatorKind::OperatorNew),
+    ("free", AllocatorKind::Free),
+];
+
+/// Allocations and frees, and the bytes allocated
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Allocations {
+    pub count: u64,
+    pub bytes: u64,
+    pub frees: u64,
+}
+
+impl Allocations {
+    pub fn add(&mut self, other: Allocations) {
+        self.count += other.count;
+        self.bytes += other.bytes;
+        self.frees += other.frees;
+    }
+}
+
+/// Allocators in the libraries the program at `program_path` links. Like the
+/// dynamic linker, the first library that exports an allocator is the one it
+/// is called in, so e.g. jemalloc's `malloc` is found rather than libc's if the
+/// program links it.
+pub fn find_allocators(program_path: &str) -> Vec<(FunctionName, AllocatorKind)> {
+    let libraries = match templates::linked_libraries(program_path) {
+        Ok(libraries) => libraries,
+        Err(err) => {
+            log::warn!("{}", err);
+            return Vec::new();
+        }
+    };
+    ALLOCATORS
+        .iter()
+        .filter_map(|&(name, kind)| {
+            let path = libraries
+                .iter()
+                .find(|path| templates::exports(path, name))?;
+            let qualified = format!("{}{}{}", path, OBJECT_SEPARATOR, name);
+            Some((FunctionName(Box::leak(qualified.into_boxed_str())), kind))
+        })
+        .collect()
+}
+
+/// Allocations made from `function`, keyed by the offset within it of the
+/// return address of the call they were made through. Parsed from the
+/// `@alloc_counts`, `@alloc_bytes` and `@frees` maps printed by the program
+/// from `TraceStack::get_allocations_expr`.
+pub fn parse_allocations(output: &str, function: &str) -> HashMap<u64, Allocations> {
+    TraceStack::parse_stack_maps(output, function)
+        .into_iter()
+        .map(|(offset, maps)| {
+            let get = |map: &str| maps.get(map).copied().unwrap_or(0);
+            let allocations = Allocations {
+                count: get("@alloc_counts"),
+                bytes: get("@alloc_bytes"),
+                frees: get("@frees"),
+            };
+            (offset, allocations)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_allocations() {
+        let output = "Attaching 9 probes...\n\n\n@alloc_bytes[\n\tmalloc+0\n\tfoo+12\n\tmain+42\n]: 4096\n@alloc_bytes[\n\t_Znwm+0\n\tfoo+40\n]: 24\n\n@alloc_counts[\n\tmalloc+0\n\tfoo+12\n\tmain+42\n]: 2\n@alloc_counts[\n\t_Znwm+0\n\tfoo+40\n]: 1\n\n@frees[\n\tfree+0\n\tfoo+12\n\tmain+42\n]: 2\n";
+        let allocations = parse_allocations(output, "foo");
+        assert_eq!(
+            allocations[&12],
+            Allocations {
+                count: 2,
+                bytes: 4096,
+                frees: 2
+            }
+        );
+        assert_eq!(
+            allocations[&40],
+            Allocations {
+                count: 1,
+                bytes: 24,
+                frees: 0
+            }
+        );
+    }
+}
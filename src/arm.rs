@@ -0,0 +1,222 @@
+use crate::program::{Call, CallTarget, IsaMode};
+use std::convert::TryInto;
+
+/// Calls (BL and BLX) and direct unconditional jumps out of the function in
+/// 32-bit ARM code starting at `address`, decoded in `mode`. BLX switches
+/// between ARM and Thumb, so the mode of each target is tracked along with
+/// its address. Instructions (or Thumb halfwords) are stored `big_endian` in
+/// BE32 binaries.
+///
+/// Only the instructions relevant to calls are recognized. Literal pools
+/// embedded in the code are decoded like instructions, as there's no way to
+/// tell them apart without following the control flow.
+pub fn find_calls(mode: IsaMode, code: &[u8], address: u64, big_endian: bool) -> Vec<Call> {
+    let read_word = |bytes: [u8; 4]| {
+        if big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        }
+    };
+    let read_halfword = |bytes: [u8; 2]| {
+        if big_endian {
+            u16::from_be_bytes(bytes) as u32
+        } else {
+            u16::from_le_bytes(bytes) as u32
+        }
+    };
+    let end_address = address + code.len() as u64;
+    let tail_call = |ip: u64, length: u8, target: u64, mode: IsaMode| {
+        if (address..end_address).contains(&target) {
+            None
+        } else {
+            Some(Call {
+                ip,
+                length,
+                target: CallTarget::Address(target, mode),
+                tail_call: true,
+            })
+        }
+    };
+    let call = |ip: u64, length: u8, target: CallTarget| Call {
+        ip,
+        length,
+        target,
+        tail_call: false,
+    };
+    let mut calls = Vec::new();
+    match mode {
+        IsaMode::Arm => {
+            for (i, word) in code.chunks_exact(4).enumerate() {
+                let word = read_word(word.try_into().unwrap());
+                let ip = address + 4 * i as u64;
+                // The PC reads as the address of the instruction plus 8
+                let pc = ip.wrapping_add(8);
+                let condition = word >> 28;
+                let found = if condition == 0xf && (word >> 25) & 0x7 == 0b101 {
+                    // BLX <label>: always switches to Thumb, with the H bit
+                    // giving bit 1 of the target
+                    let offset = (word & 0xff_ffff) << 2 | (word >> 23) & 0b10;
+                    let target = pc.wrapping_add(sign_extend(offset, 26) as u64);
+                    Some(call(ip, 4, CallTarget::Address(target, IsaMode::Thumb)))
+                } else if condition == 0xf {
+                    None
+                } else if (word >> 24) & 0xf == 0b1011 {
+                    // BL <label>
+                    let target = pc.wrapping_add(sign_extend((word & 0xff_ffff) << 2, 26) as u64);
+                    Some(call(ip, 4, CallTarget::Address(target, IsaMode::Arm)))
+                } else if (word >> 24) & 0xf == 0b1010 && condition == 0xe {
+                    // B <label>
+                    let target = pc.wrapping_add(sign_extend((word & 0xff_ffff) << 2, 26) as u64);
+                    tail_call(ip, 4, target, IsaMode::Arm)
+                } else if word & 0x0fff_fff0 == 0x012f_ff30 {
+                    // BLX <Rm>: the mode is picked by bit 0 of the register
+                    Some(call(ip, 4, register(word & 0xf)))
+                } else {
+                    None
+                };
+                calls.extend(found);
+            }
+        }
+        IsaMode::Thumb => {
+            let mut offset = 0;
+            while offset + 2 <= code.len() {
+                let ip = address + offset as u64;
+                let first = read_halfword([code[offset], code[offset + 1]]);
+                if first >> 11 < 0b11101 {
+                    // 16-bit instruction
+                    if first & 0xff87 == 0x4780 {
+                        // BLX <Rm>
+                        calls.push(call(ip, 2, register((first >> 3) & 0xf)));
+                    }
+                    offset += 2;
+                    continue;
+                }
+                if offset + 4 > code.len() {
+                    break;
+                }
+                let second = read_halfword([code[offset + 2], code[offset + 3]]);
+                // The PC reads as the address of the instruction plus 4
+                let pc = ip.wrapping_add(4);
+                if first >> 11 == 0b11110 && second >> 15 == 1 {
+                    let s = (first >> 10) & 1;
+                    let i1 = !((second >> 13) & 1 ^ s) & 1;
+                    let i2 = !((second >> 11) & 1 ^ s) & 1;
+                    let high = s << 24 | i1 << 23 | i2 << 22 | (first & 0x3ff) << 12;
+                    let found = match ((second >> 14) & 1 == 1, (second >> 12) & 1 == 1) {
+                        // BL <label>
+                        (true, true) => {
+                            let offset = sign_extend(high | (second & 0x7ff) << 1, 25);
+                            let target = pc.wrapping_add(offset as u64);
+                            Some(call(ip, 4, CallTarget::Address(target, IsaMode::Thumb)))
+                        }
+                        // BLX <label>: always switches to ARM, relative to the
+                        // PC aligned down to 4 bytes
+                        (true, false) => {
+                            let offset = sign_extend(high | (second & 0x7fe) << 1, 25);
+                            let target = (pc & !3).wrapping_add(offset as u64);
+                            Some(call(ip, 4, CallTarget::Address(target, IsaMode::Arm)))
+                        }
+                        // B.W <label>
+                        (false, true) => {
+                            let offset = sign_extend(high | (second & 0x7ff) << 1, 25);
+                            tail_call(ip, 4, pc.wrapping_add(offset as u64), IsaMode::Thumb)
+                        }
+                        // Conditional branch
+                        (false, false) => None,
+                    };
+                    calls.extend(found);
+                }
+                offset += 4;
+            }
+        }
+        IsaMode::X86_64 | IsaMode::Unknown => {}
+    }
+    calls
+}
+
+/// Sign extend the lowest `bits` bits of `value`
+fn sign_extend(value: u32, bits: u32) -> i64 {
+    ((value << (32 - bits)) as i32 >> (32 - bits)) as i64
+}
+
+fn register(number: u32) -> CallTarget {
+    let name = match number {
+        13 => "sp".to_string(),
+        14 => "lr".to_string(),
+        15 => "pc".to_string(),
+        n => format!("r{}", n),
+    };
+    CallTarget::Register(name, None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn targets(
+        mode: IsaMode,
+        code: &[u8],
+        address: u64,
+        big_endian: bool,
+    ) -> Vec<(u64, CallTarget, bool)> {
+        find_calls(mode, code, address, big_endian)
+            .into_iter()
+            .map(|call| (call.ip, call.target, call.tail_call))
+            .collect()
+    }
+
+    /// `code` with the bytes of each `size` byte unit reversed
+    fn swap_bytes(code: &[u8], size: usize) -> Vec<u8> {
+        code.chunks(size)
+            .flat_map(|unit| unit.iter().rev())
+            .copied()
+            .collect()
+    }
+
+    #[test]
+    fn test_find_calls_arm() {
+        let code = [
+            0xfe, 0x03, 0x00, 0xeb, // bl 0x2000
+            0xfe, 0x03, 0x00, 0xfb, // blx 0x2006 (Thumb)
+            0x33, 0xff, 0x2f, 0xe1, // blx r3
+            0xfb, 0x03, 0x00, 0xea, // b 0x2000
+            0xfe, 0xff, 0xff, 0xea, // b 0x1010, within the function
+        ];
+        let expected = vec![
+            (0x1000, CallTarget::Address(0x2000, IsaMode::Arm), false),
+            (0x1004, CallTarget::Address(0x2006, IsaMode::Thumb), false),
+            (0x1008, CallTarget::Register("r3".into(), None), false),
+            (0x100c, CallTarget::Address(0x2000, IsaMode::Arm), true),
+        ];
+        assert_eq!(targets(IsaMode::Arm, &code, 0x1000, false), expected);
+        assert_eq!(
+            targets(IsaMode::Arm, &swap_bytes(&code, 4), 0x1000, true),
+            expected
+        );
+    }
+
+    #[test]
+    fn test_find_calls_thumb() {
+        let code = [
+            0x00, 0xbf, // nop
+            0x00, 0xf0, 0xfd, 0xff, // bl 0x2000
+            0x00, 0xf0, 0xfc, 0xef, // blx 0x2000 (ARM), from an unaligned PC
+            0x98, 0x47, // blx r3
+            0xff, 0xf7, 0xf6, 0xff, // bl 0xffc, backwards
+            0x00, 0xf0, 0xf6, 0xbf, // b.w 0x2000
+        ];
+        let expected = vec![
+            (0x1002, CallTarget::Address(0x2000, IsaMode::Thumb), false),
+            (0x1006, CallTarget::Address(0x2000, IsaMode::Arm), false),
+            (0x100a, CallTarget::Register("r3".into(), None), false),
+            (0x100c, CallTarget::Address(0xffc, IsaMode::Thumb), false),
+            (0x1010, CallTarget::Address(0x2000, IsaMode::Thumb), true),
+        ];
+        assert_eq!(targets(IsaMode::Thumb, &code, 0x1000, false), expected);
+        assert_eq!(
+            targets(IsaMode::Thumb, &swap_bytes(&code, 2), 0x1000, true),
+            expected
+        );
+    }
+}
@@ -0,0 +1,415 @@
+use crate::bpftrace_compiler::{BpftraceProgram, DTraceTarget};
+use crate::capabilities::Capabilities;
+use crate::error::Error;
+use crate::events::Event;
+#[cfg(feature = "libbpf")]
+use crate::libbpf_backend::LibbpfBackend;
+use crate::native::NativePlan;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::AtomicBool;
+use std::sync::{mpsc, Arc};
+
+/// A tool that can run the programs generated by `TraceStack`. Programs are
+/// always built as a `BpftraceProgram`, each backend compiles them to its own
+/// language and is responsible for running them such that trace output is
+/// printed on stdout.
+pub trait TraceBackend: Send + Sync {
+    /// Name of the underlying tool, used in messages
+    fn name(&self) -> &'static str;
+
+    /// Ensure the backend can be run in the current environment
+    fn run_prechecks(&self) -> Result<(), Error>;
+
+    /// What the environment traces run in supports
+    fn capabilities(&self) -> Capabilities {
+        Capabilities::unknown()
+    }
+
+    /// Whether programs are run with bpftrace on this host, so that they trace
+    /// the processes we see and bpftrace-only features (e.g. templates) work
+    fn is_local_bpftrace(&self) -> bool {
+        false
+    }
+
+    fn compile(&self, program: &BpftraceProgram) -> String;
+
+    /// Command that runs compiled `script`
+    fn run_command(&self, script: &str) -> Command;
+
+    /// Command that only checks that compiled `script` is valid
+    fn check_command(&self, script: &str) -> Command;
+
+    /// Command running the bpftrace binary programs are run with locally, if
+    /// any, e.g. to check its version
+    fn bpftrace_command(&self) -> Option<Command> {
+        None
+    }
+
+    /// Command that runs compiled `script` like `run_command`, but prints
+    /// output as JSON records (see `JsonOutput`), if the backend can
+    fn json_command(&self, _script: &str) -> Option<Command> {
+        None
+    }
+
+    /// Whether traces are run in-process with `run_native` when
+    /// `TraceStack::get_native_plan` supports them, rather than with
+    /// `run_command`
+    fn is_native(&self) -> bool {
+        false
+    }
+
+    /// Attach the probes of `plan` and send trace data for it to `tx` until
+    /// `stop` is set. Data is tagged with `counter`, as with `TraceStack::parse`.
+    fn run_native(
+        &self,
+        _plan: &NativePlan,
+        _counter: u64,
+        _tx: &mpsc::Sender<Event>,
+        _stop: &AtomicBool,
+    ) -> Result<(), Error> {
+        Err(format!("The {} backend can't trace in-process", self.name()).into())
+    }
+}
+
+/// Names accepted by `new_backend`
+#[cfg(not(feature = "libbpf"))]
+pub const BACKEND_NAMES: &[&str] = &["bpftrace", "dtrace", "android", "none"];
+#[cfg(feature = "libbpf")]
+pub const BACKEND_NAMES: &[&str] = &["libbpf", "bpftrace", "dtrace", "android", "none"];
+
+/// bpftrace is Linux only, other platforms (macOS, FreeBSD) have DTrace. When
+/// built with it, libbpf replaces bpftrace for the traces it supports.
+pub const DEFAULT_BACKEND: &str = if !cfg!(target_os = "linux") {
+    "dtrace"
+} else if cfg!(feature = "libbpf") {
+    "libbpf"
+} else {
+    "bpftrace"
+};
+
+/// Create the backend with the given name (one of `BACKEND_NAMES`). `pid` is
+/// the process to attach to, which is required by some backends.
+/// `device_path` is where the program is on a remote device, when
+/// `program_path` is a local copy. `bpftrace_path` overrides the bpftrace
+/// binary that is run, e.g. with the bundled runtime.
+pub fn new_backend(
+    name: &str,
+    program_path: &str,
+    pid: Option<u32>,
+    device_path: Option<&str>,
+    bpftrace_path: Option<PathBuf>,
+) -> Result<Arc<dyn TraceBackend>, Error> {
+    if bpftrace_path.is_some() && name != "bpftrace" && name != "libbpf" {
+        return Err(format!(
+            "The bundled runtime can't be used with the {} backend",
+            name
+        )
+        .into());
+    }
+    match name {
+        "bpftrace" => Ok(Arc::new(BpftraceBackend::new(
+            program_path.to_string(),
+            bpftrace_path.unwrap_or_else(|| PathBuf::from("bpftrace")),
+        ))),
+        #[cfg(feature = "libbpf")]
+        "libbpf" => Ok(Arc::new(LibbpfBackend::new(
+            program_path.to_string(),
+            BpftraceBackend::new(
+                program_path.to_string(),
+                bpftrace_path.unwrap_or_else(|| PathBuf::from("bpftrace")),
+            ),
+        ))),
+        "dtrace" => match pid {
+            Some(pid) => Ok(Arc::new(DTraceBackend::new(program_path, pid))),
+            None => Err("--pid is required with the dtrace backend".into()),
+        },
+        "android" => match device_path {
+            Some(device_path) => Ok(Arc::new(AndroidBackend::new(device_path.to_string()))),
+            None => Err("--device-path is required with the android backend".into()),
+        },
+        "none" => Ok(Arc::new(NoneBackend)),
+        _ => Err(format!("Unknown tracing backend {}", name).into()),
+    }
+}
+
+pub struct BpftraceBackend {
+    program_path: String,
+    bpftrace_path: PathBuf,
+}
+
+impl BpftraceBackend {
+    pub fn new(program_path: String, bpftrace_path: PathBuf) -> BpftraceBackend {
+        BpftraceBackend {
+            program_path,
+            bpftrace_path,
+        }
+    }
+
+    fn bpftrace_cmd(&self) -> Command {
+        Command::new(&self.bpftrace_path)
+    }
+}
+
+impl TraceBackend for BpftraceBackend {
+    fn name(&self) -> &'static str {
+        "bpftrace"
+    }
+
+    fn run_prechecks(&self) -> Result<(), Error> {
+        match self.bpftrace_cmd().arg("--version").output() {
+            Ok(output) => log::trace!("bpftrace version: {:?}", output),
+            Err(err) => {
+                let msg = match err.kind() {
+                    std::io::ErrorKind::NotFound => format!("bpftrace not found. See https://github.com/iovisor/bpftrace/blob/master/INSTALL.md for installation instructions, or pass --bundled-runtime to download a static build."),
+                    _ => format!("Error running bpftrace: {:?}", err),
+                };
+                return Err(msg.into());
+            }
+        }
+        // TODO ensure is root
+
+        Ok(())
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities::probe_local()
+    }
+
+    fn is_local_bpftrace(&self) -> bool {
+        true
+    }
+
+    fn bpftrace_command(&self) -> Option<Command> {
+        Some(self.bpftrace_cmd())
+    }
+
+    fn compile(&self, program: &BpftraceProgram) -> String {
+        program.compile(&self.program_path)
+    }
+
+    fn run_command(&self, script: &str) -> Command {
+        let mut command = self.bpftrace_cmd();
+        command.args(["-e", script]);
+        command
+    }
+
+    fn check_command(&self, script: &str) -> Command {
+        let mut command = self.bpftrace_cmd();
+        command.args(["-d", "-e", script]);
+        command
+    }
+
+    fn json_command(&self, script: &str) -> Option<Command> {
+        let mut command = self.bpftrace_cmd();
+        command.args(["-f", "json", "-e", script]);
+        Some(command)
+    }
+}
+
+/// Runs programs with DTrace's pid provider, which can only attach to a single
+/// running process. Used on macOS and FreeBSD.
+pub struct DTraceBackend {
+    target: DTraceTarget,
+    pid: u32,
+}
+
+impl DTraceBackend {
+    pub fn new(program_path: &str, pid: u32) -> DTraceBackend {
+        let module = Path::new(program_path)
+            .file_name()
+            .map(|f| f.to_string_lossy().into_owned())
+            .unwrap_or_else(|| program_path.to_string());
+        DTraceBackend {
+            target: DTraceTarget {
+                module,
+                strip_leading_underscore: cfg!(target_os = "macos"),
+            },
+            pid,
+        }
+    }
+
+    /// Each line of a compiled D program is a single clause, which is passed
+    /// with its own `-n`. Clauses are enabled in the order given.
+    fn command(&self, script: &str, flag: &str) -> Command {
+        let mut command = dtrace_cmd();
+        command.args([flag, "-p", &self.pid.to_string()]);
+        for clause in script.lines() {
+            command.args(["-n", clause]);
+        }
+        command
+    }
+}
+
+impl TraceBackend for DTraceBackend {
+    fn name(&self) -> &'static str {
+        "dtrace"
+    }
+
+    fn run_prechecks(&self) -> Result<(), Error> {
+        // dtrace has no version flag, `-V` prints the D API version
+        match dtrace_cmd().arg("-V").output() {
+            Ok(output) => log::trace!("dtrace version: {:?}", output),
+            Err(err) => {
+                let msg = match err.kind() {
+                    std::io::ErrorKind::NotFound => "dtrace not found".to_string(),
+                    _ => format!("Error running dtrace: {:?}", err),
+                };
+                return Err(msg.into());
+            }
+        }
+        if unsafe { libc::kill(self.pid as i32, 0) } != 0 {
+            return Err(format!("Process {} does not exist", self.pid).into());
+        }
+        Ok(())
+    }
+
+    fn compile(&self, program: &BpftraceProgram) -> String {
+        program.compile_dtrace(&self.target)
+    }
+
+    fn run_command(&self, script: &str) -> Command {
+        // Quiet mode, only print explicitly traced output
+        self.command(script, "-q")
+    }
+
+    fn check_command(&self, script: &str) -> Command {
+        // Exit after compiling
+        self.command(script, "-e")
+    }
+}
+
+fn dtrace_cmd() -> Command {
+    Command::new("dtrace")
+}
+
+/// Runs bpftrace on an Android device through `adb shell`, with output streamed
+/// back over adb. Programs are analyzed from a local copy of the binary, but
+/// probes refer to its path on the device. The device is selected the usual
+/// way for adb, e.g. with `ANDROID_SERIAL`.
+pub struct AndroidBackend {
+    device_path: String,
+}
+
+impl AndroidBackend {
+    pub fn new(device_path: String) -> AndroidBackend {
+        AndroidBackend { device_path }
+    }
+
+    /// adb joins its arguments into a single command line that is run by the
+    /// device's shell, so each one needs to be quoted.
+    fn command(&self, args: &[&str]) -> Command {
+        let mut command = adb_cmd();
+        command.arg("shell");
+        command.args(args.iter().map(|arg| shell_quote(arg)));
+        command
+    }
+}
+
+impl TraceBackend for AndroidBackend {
+    fn name(&self) -> &'static str {
+        "bpftrace (android)"
+    }
+
+    fn run_prechecks(&self) -> Result<(), Error> {
+        let output = match self.command(&["bpftrace", "--version"]).output() {
+            Ok(output) => output,
+            Err(err) => {
+                let msg = match err.kind() {
+                    std::io::ErrorKind::NotFound => "adb not found".to_string(),
+                    _ => format!("Error running adb: {:?}", err),
+                };
+                return Err(msg.into());
+            }
+        };
+        log::trace!("bpftrace version: {:?}", output);
+        if !output.status.success() {
+            return Err(format!(
+                "Failed to run bpftrace on device, ensure the device is connected, bpftrace is installed and adbd is running as root:\n{}",
+                String::from_utf8_lossy(&output.stderr)
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        match self.command(&["uname", "-r"]).output() {
+            Ok(output) if output.status.success() => {
+                Capabilities::for_release(&String::from_utf8_lossy(&output.stdout))
+            }
+            result => {
+                log::warn!("Failed to get kernel release of device: {:?}", result);
+                Capabilities::unknown()
+            }
+        }
+    }
+
+    fn compile(&self, program: &BpftraceProgram) -> String {
+        program.compile(&self.device_path)
+    }
+
+    fn run_command(&self, script: &str) -> Command {
+        // When the tracer kills adb, adbd hangs up on the shell which also
+        // stops bpftrace on the device.
+        self.command(&["bpftrace", "-e", script])
+    }
+
+    fn check_command(&self, script: &str) -> Command {
+        self.command(&["bpftrace", "-d", "-e", script])
+    }
+
+    fn json_command(&self, script: &str) -> Option<Command> {
+        Some(self.command(&["bpftrace", "-f", "json", "-e", script]))
+    }
+}
+
+fn adb_cmd() -> Command {
+    Command::new("adb")
+}
+
+/// Quote `arg` for a POSIX shell
+fn shell_quote(arg: &str) -> String {
+    format!("'{}'", arg.replace('\'', "'\\''"))
+}
+
+/// Doesn't trace anything, for browsing binaries statically (e.g. Windows
+/// binaries, which can't be traced)
+pub struct NoneBackend;
+
+impl TraceBackend for NoneBackend {
+    fn name(&self) -> &'static str {
+        "none"
+    }
+
+    fn run_prechecks(&self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn compile(&self, _program: &BpftraceProgram) -> String {
+        String::new()
+    }
+
+    fn run_command(&self, _script: &str) -> Command {
+        // Exits immediately without output, so no trace data is ever received
+        Command::new("true")
+    }
+
+    fn check_command(&self, script: &str) -> Command {
+        self.run_command(script)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shell_quote() {
+        assert_eq!(shell_quote("-e"), "'-e'");
+        assert_eq!(
+            shell_quote("printf(\"it's\\n\")"),
+            "'printf(\"it'\\''s\\n\")'"
+        );
+    }
+}
@@ -0,0 +1,415 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Program behind every probe of the libbpf backend, see `src/native.rs`. Each
+// probe's attach cookie tells it what to do: `kind << 24 | depth << 16 | slot`,
+// plus `RECURSIVE_CALLS`. Like the bpftrace programs, a thread's depth follows
+// the trace stack so that lines are only timed within calls of the functions
+// on it, and times are only committed once the outermost function returns.
+// Recursive calls of a frame's function are counted rather than followed, so
+// they don't end the frame early, and frames unwound by an exception or
+// longjmp are noticed by their stack pointer. When the plan reads hardware
+// counters, they are read along with the time lines start and end at.
+#include <linux/bpf.h>
+#include <linux/ptrace.h>
+#include <linux/types.h>
+#include <bpf/bpf_helpers.h>
+#include <bpf/bpf_tracing.h>
+
+// Must match `native::MAX_SLOTS`
+#define MAX_SLOTS 64
+#define NO_SLOT 0xffff
+// Set on the probes of the last frame when recursive calls of its function
+// are timed as well, must match `native::RECURSIVE_CALLS`
+#define RECURSIVE_CALLS (1ULL << 32)
+
+enum probe_kind {
+	FRAME_ENTRY = 0,
+	FRAME_EXIT = 1,
+	LINE_START = 2,
+	LINE_END = 3,
+};
+
+struct slot_key {
+	__u32 tid;
+	__u32 slot;
+};
+
+// Hardware counters of a CPU, and which CPU they were read on
+struct counter_values {
+	__u64 cycles;
+	__u64 instructions;
+	__u64 cache_misses;
+	__u32 cpu;
+	__u32 pad;
+};
+
+struct line_totals {
+	__u64 duration;
+	__u64 count;
+	__u64 cycles;
+	__u64 instructions;
+	__u64 cache_misses;
+	// Calls the counters were read for
+	__u64 counted;
+	// Calls that never returned
+	__u64 aborted;
+};
+
+// Sent for each line timed during a call of the outermost function, must
+// match `LineEvent` in `src/libbpf_backend.rs`
+struct line_event {
+	__u32 slot;
+	__u32 pad;
+	__u64 duration;
+	__u64 count;
+	__u64 cycles;
+	__u64 instructions;
+	__u64 cache_misses;
+	__u64 counted;
+	__u64 aborted;
+};
+
+// Depth of each thread in the trace stack, missing at depth 0
+struct {
+	__uint(type, BPF_MAP_TYPE_HASH);
+	__uint(max_entries, 65536);
+	__type(key, __u32);
+	__type(value, __u32);
+} depths SEC(".maps");
+
+// How many recursive calls of the function of each frame each thread is in,
+// keyed by the frame's depth. Missing outside of recursive calls.
+struct {
+	__uint(type, BPF_MAP_TYPE_HASH);
+	__uint(max_entries, 65536);
+	__type(key, struct slot_key);
+	__type(value, __u32);
+} recursion SEC(".maps");
+
+// Stack pointer each thread entered the frame at each depth with, keyed by the
+// depth. A call of the frame's function at or above it can't be a recursive
+// call, so the frame's own call must have been unwound.
+struct {
+	__uint(type, BPF_MAP_TYPE_HASH);
+	__uint(max_entries, 65536);
+	__type(key, struct slot_key);
+	__type(value, __u64);
+} frame_sps SEC(".maps");
+
+// Time each thread started timing a line at. Recursive calls of the last
+// frame's function are timed with their level in the upper half of the slot.
+struct {
+	__uint(type, BPF_MAP_TYPE_HASH);
+	__uint(max_entries, 65536);
+	__type(key, struct slot_key);
+	__type(value, __u64);
+} starts SEC(".maps");
+
+// Times gathered by each thread during the current call of the outermost
+// function
+struct {
+	__uint(type, BPF_MAP_TYPE_HASH);
+	__uint(max_entries, 65536);
+	__type(key, struct slot_key);
+	__type(value, struct line_totals);
+} pending SEC(".maps");
+
+// Counters each thread started timing a line at
+struct {
+	__uint(type, BPF_MAP_TYPE_HASH);
+	__uint(max_entries, 65536);
+	__type(key, struct slot_key);
+	__type(value, struct counter_values);
+} counter_starts SEC(".maps");
+
+// Hardware counters of each CPU, only filled in by `src/libbpf_backend.rs`
+// when the plan reads them. Reading them fails while they are empty.
+struct {
+	__uint(type, BPF_MAP_TYPE_PERF_EVENT_ARRAY);
+	__uint(key_size, sizeof(__u32));
+	__uint(value_size, sizeof(__u32));
+} cycles SEC(".maps");
+
+struct {
+	__uint(type, BPF_MAP_TYPE_PERF_EVENT_ARRAY);
+	__uint(key_size, sizeof(__u32));
+	__uint(value_size, sizeof(__u32));
+} instructions SEC(".maps");
+
+struct {
+	__uint(type, BPF_MAP_TYPE_PERF_EVENT_ARRAY);
+	__uint(key_size, sizeof(__u32));
+	__uint(value_size, sizeof(__u32));
+} cache_misses SEC(".maps");
+
+struct {
+	__uint(type, BPF_MAP_TYPE_RINGBUF);
+	__uint(max_entries, 256 * 1024);
+} events SEC(".maps");
+
+static int read_counter(void *map, __u64 *counter)
+{
+	struct bpf_perf_event_value value;
+	if (bpf_perf_event_read_value(map, BPF_F_CURRENT_CPU, &value,
+				      sizeof(value)))
+		return -1;
+	*counter = value.counter;
+	return 0;
+}
+
+static int read_counters(struct counter_values *values)
+{
+	if (read_counter(&cycles, &values->cycles) ||
+	    read_counter(&instructions, &values->instructions) ||
+	    read_counter(&cache_misses, &values->cache_misses))
+		return -1;
+	values->cpu = bpf_get_smp_processor_id();
+	values->pad = 0;
+	return 0;
+}
+
+static void start_line(struct slot_key *key, __u64 now)
+{
+	bpf_map_update_elem(&starts, key, &now, BPF_ANY);
+	struct counter_values values;
+	if (!read_counters(&values))
+		bpf_map_update_elem(&counter_starts, key, &values, BPF_ANY);
+}
+
+// Add the time since the line started at `start_key` to the totals of `key`
+static void add_time(struct slot_key *start_key, struct slot_key *key,
+		     __u64 now)
+{
+	__u64 *start = bpf_map_lookup_elem(&starts, start_key);
+	if (!start)
+		return;
+	struct line_totals new_totals = {
+		.duration = now - *start,
+		.count = 1,
+	};
+	// Counters are per CPU, so they only measure the thread if it stayed on
+	// the CPU it started on
+	struct counter_values *counter_start =
+		bpf_map_lookup_elem(&counter_starts, start_key);
+	struct counter_values values;
+	if (counter_start) {
+		if (!read_counters(&values) && values.cpu == counter_start->cpu) {
+			new_totals.cycles = values.cycles - counter_start->cycles;
+			new_totals.instructions =
+				values.instructions - counter_start->instructions;
+			new_totals.cache_misses =
+				values.cache_misses - counter_start->cache_misses;
+			new_totals.counted = 1;
+		}
+		bpf_map_delete_elem(&counter_starts, start_key);
+	}
+	struct line_totals *totals = bpf_map_lookup_elem(&pending, key);
+	if (totals) {
+		totals->duration += new_totals.duration;
+		totals->count += 1;
+		totals->cycles += new_totals.cycles;
+		totals->instructions += new_totals.instructions;
+		totals->cache_misses += new_totals.cache_misses;
+		totals->counted += new_totals.counted;
+	} else {
+		bpf_map_update_elem(&pending, key, &new_totals, BPF_ANY);
+	}
+	bpf_map_delete_elem(&starts, start_key);
+}
+
+// Count the call of the line being timed at `key` as aborted, if there is one
+static void abort_line(struct slot_key *key)
+{
+	if (!bpf_map_lookup_elem(&starts, key))
+		return;
+	bpf_map_delete_elem(&starts, key);
+	bpf_map_delete_elem(&counter_starts, key);
+	struct line_totals *totals = bpf_map_lookup_elem(&pending, key);
+	if (totals) {
+		totals->aborted += 1;
+	} else {
+		struct line_totals new_totals = { .aborted = 1 };
+		bpf_map_update_elem(&pending, key, &new_totals, BPF_ANY);
+	}
+}
+
+// Recursive calls the thread is in of the function of the frame at `depth`
+static __u32 recursion_level(__u32 tid, __u32 depth)
+{
+	struct slot_key key = { .tid = tid, .slot = depth };
+	__u32 *level = bpf_map_lookup_elem(&recursion, &key);
+	return level ? *level : 0;
+}
+
+static void set_recursion_level(__u32 tid, __u32 depth, __u32 level)
+{
+	struct slot_key key = { .tid = tid, .slot = depth };
+	if (level)
+		bpf_map_update_elem(&recursion, &key, &level, BPF_ANY);
+	else
+		bpf_map_delete_elem(&recursion, &key);
+}
+
+static void commit(__u32 tid)
+{
+	for (__u32 slot = 0; slot < MAX_SLOTS; slot++) {
+		struct slot_key key = { .tid = tid, .slot = slot };
+		// Lines still being timed once the outermost function returns
+		// never returned themselves
+		abort_line(&key);
+		struct line_totals *totals = bpf_map_lookup_elem(&pending, &key);
+		if (!totals)
+			continue;
+		struct line_event *event =
+			bpf_ringbuf_reserve(&events, sizeof(*event), 0);
+		if (event) {
+			event->slot = slot;
+			event->pad = 0;
+			event->duration = totals->duration;
+			event->count = totals->count;
+			event->cycles = totals->cycles;
+			event->instructions = totals->instructions;
+			event->cache_misses = totals->cache_misses;
+			event->counted = totals->counted;
+			event->aborted = totals->aborted;
+			bpf_ringbuf_submit(event, 0);
+		}
+		bpf_map_delete_elem(&pending, &key);
+	}
+}
+
+// The call of the frame at `depth` was unwound, along with those of the frames
+// deeper than it up to `current_depth`. Lines being timed were aborted, and if
+// the outermost frame was unwound the times gathered during its call are
+// dropped.
+static void unwind_frames(__u32 tid, __u32 depth, __u32 current_depth)
+{
+	for (__u32 slot = 0; slot < MAX_SLOTS; slot++) {
+		struct slot_key key = { .tid = tid, .slot = slot };
+		abort_line(&key);
+		if (depth)
+			continue;
+		struct line_totals *totals = bpf_map_lookup_elem(&pending, &key);
+		if (totals) {
+			struct line_totals aborted = { .aborted = totals->aborted };
+			*totals = aborted;
+		}
+	}
+	for (__u32 d = depth; d < current_depth && d < 256; d++)
+		set_recursion_level(tid, d, 0);
+	if (depth) {
+		bpf_map_update_elem(&depths, &tid, &depth, BPF_ANY);
+	} else {
+		bpf_map_delete_elem(&depths, &tid);
+		commit(tid);
+	}
+}
+
+static __always_inline int handle(struct pt_regs *ctx)
+{
+	__u64 cookie = bpf_get_attach_cookie(ctx);
+	__u32 kind = (cookie >> 24) & 0xff;
+	__u32 depth = (cookie >> 16) & 0xff;
+	__u32 slot = cookie & 0xffff;
+	__u32 tid = (__u32)bpf_get_current_pid_tgid();
+	__u64 now = bpf_ktime_get_ns();
+
+	__u32 *current = bpf_map_lookup_elem(&depths, &tid);
+	__u32 current_depth = current ? *current : 0;
+	struct slot_key key = { .tid = tid, .slot = slot };
+	int recursive_calls = slot != NO_SLOT && (cookie & RECURSIVE_CALLS);
+
+	switch (kind) {
+	case FRAME_ENTRY: {
+		struct slot_key frame_key = { .tid = tid, .slot = depth };
+		__u64 sp = PT_REGS_SP(ctx);
+		if (current_depth > depth) {
+			__u64 *frame_sp = bpf_map_lookup_elem(&frame_sps, &frame_key);
+			if (frame_sp && sp >= *frame_sp) {
+				unwind_frames(tid, depth, current_depth);
+				current_depth = depth;
+			}
+		}
+		if (current_depth > depth) {
+			// A recursive call, as the thread is already in the frame
+			__u32 level = recursion_level(tid, depth) + 1;
+			set_recursion_level(tid, depth, level);
+			if (recursive_calls) {
+				struct slot_key start_key = {
+					.tid = tid, .slot = slot | level << 16
+				};
+				start_line(&start_key, now);
+			}
+			return 0;
+		}
+		if (current_depth != depth)
+			return 0;
+		__u32 new_depth = depth + 1;
+		bpf_map_update_elem(&depths, &tid, &new_depth, BPF_ANY);
+		bpf_map_update_elem(&frame_sps, &frame_key, &sp, BPF_ANY);
+		if (slot != NO_SLOT) {
+			abort_line(&key);
+			start_line(&key, now);
+		}
+		break;
+	}
+	case FRAME_EXIT: {
+		if (current_depth < depth + 1)
+			return 0;
+		__u32 level = recursion_level(tid, depth);
+		if (level) {
+			if (recursive_calls) {
+				struct slot_key start_key = {
+					.tid = tid, .slot = slot | level << 16
+				};
+				add_time(&start_key, &key, now);
+			}
+			set_recursion_level(tid, depth, level - 1);
+			return 0;
+		}
+		if (current_depth != depth + 1)
+			return 0;
+		if (slot != NO_SLOT)
+			add_time(&key, &key, now);
+		if (depth == 0) {
+			bpf_map_delete_elem(&depths, &tid);
+			commit(tid);
+		} else {
+			bpf_map_update_elem(&depths, &tid, &depth, BPF_ANY);
+		}
+		break;
+	}
+	// Lines are only timed in the outermost call of the function, which
+	// includes the time of any recursive calls made on them
+	case LINE_START:
+		if (current_depth != depth || recursion_level(tid, depth - 1))
+			return 0;
+		// A call still being timed when the line is reached again never
+		// returned
+		abort_line(&key);
+		start_line(&key, now);
+		break;
+	case LINE_END:
+		if (current_depth != depth || recursion_level(tid, depth - 1))
+			return 0;
+		add_time(&key, &key, now);
+		break;
+	}
+	return 0;
+}
+
+SEC("uprobe")
+int probe(struct pt_regs *ctx)
+{
+	return handle(ctx);
+}
+
+// Same as `probe` for uprobe_multi links (Linux 6.6+), only loaded where they
+// are supported
+SEC("uprobe.multi")
+int probe_multi(struct pt_regs *ctx)
+{
+	return handle(ctx);
+}
+
+char LICENSE[] SEC("license") = "Dual MIT/GPL";
@@ -1,6 +1,11 @@
 use itertools::Itertools;
 
 use crate::program::FunctionName;
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::convert::TryFrom;
+use std::path::Path;
+use std::time::Duration;
 
 /// A simple AST representation of a bpftrace program which makes it a bit
 /// easier to generate. Compiles to bpftrace syntax, i.e. String.
@@ -14,16 +19,40 @@ pub struct Block {
     expressions: Vec<Expression>,
 }
 
-#[derive(Copy, Clone)]
+#[derive(Clone)]
 pub enum BlockType {
     Begin,
     /// Rate in seconds
     Interval {
         rate_seconds: i32,
     },
+    /// Rate in milliseconds, for intervals that aren't whole seconds
+    IntervalMs {
+        rate_ms: u64,
+    },
     Uprobe(FunctionName),
     UprobeOffset(FunctionName, u32),
     Uretprobe(FunctionName),
+    /// Alternative to `Uretprobe` that probes each of the given return
+    /// instruction offsets instead, see `Program::get_return_offsets`
+    ReturnInstructions(FunctionName, Vec<u32>),
+    /// Kernel tracepoint, e.g. `sched:sched_process_fork`
+    Tracepoint(&'static str),
+}
+
+impl BlockType {
+    /// Block run every `interval`, in whole seconds when it is some
+    pub fn interval(interval: Duration) -> BlockType {
+        if interval.subsec_millis() == 0 {
+            BlockType::Interval {
+                rate_seconds: i32::try_from(interval.as_secs()).unwrap_or(i32::MAX),
+            }
+        } else {
+            BlockType::IntervalMs {
+                rate_ms: u64::try_from(interval.as_millis()).unwrap_or(u64::MAX),
+            }
+        }
+    }
 }
 
 pub enum Expression {
@@ -50,6 +79,21 @@ impl BpftraceProgram {
         self.blocks.push(block);
     }
 
+    /// Number of probes the program attaches
+    pub fn num_probes(&self) -> usize {
+        self.blocks
+            .iter()
+            .map(|block| match &block.block_type {
+                BlockType::Begin | BlockType::Interval { .. } | BlockType::IntervalMs { .. } => 0,
+                BlockType::ReturnInstructions(_, offsets) => offsets.len(),
+                BlockType::Uprobe(_)
+                | BlockType::UprobeOffset(..)
+                | BlockType::Uretprobe(_)
+                | BlockType::Tracepoint(_) => 1,
+            })
+            .sum()
+    }
+
     pub fn iter_mut(&mut self) -> std::slice::IterMut<'_, Block> {
         self.blocks.iter_mut()
     }
@@ -61,6 +105,53 @@ impl BpftraceProgram {
             .map(|b| b.compile(program_path))
             .join(" ")
     }
+
+    /// Compile to a D program for DTrace's pid provider. Expressions are
+    /// written in bpftrace syntax, so they are translated as well.
+    pub fn compile_dtrace(&self, target: &DTraceTarget) -> String {
+        // Maps assigned with `hist()` are aggregations in D too, all other
+        // maps become global variables.
+        let mut aggregations = HashSet::new();
+        for block in &self.blocks {
+            Expression::collect_aggregations(&block.expressions, &mut aggregations);
+        }
+        let mut cond_counter = 0;
+        self.blocks
+            .iter()
+            .map(|b| b.compile_dtrace(target, &aggregations, &mut cond_counter))
+            .join("\n")
+    }
+}
+
+/// Describes how probes in a D program refer to the traced binary
+pub struct DTraceTarget {
+    /// Module name as seen by the pid provider, i.e. file name of the binary
+    pub module: String,
+    /// Mach-O symbols have a leading underscore that is not part of the probe
+    /// function name
+    pub strip_leading_underscore: bool,
+}
+
+impl DTraceTarget {
+    /// Module of `function`, which is the shared library it is in if any
+    fn probe_module(&self, function: FunctionName) -> Cow<str> {
+        match function
+            .object()
+            .and_then(|path| Path::new(path).file_name())
+        {
+            Some(file_name) => file_name.to_string_lossy(),
+            None => Cow::Borrowed(&self.module),
+        }
+    }
+
+    fn probe_function(&self, function: FunctionName) -> &'static str {
+        let name = function.unqualified().0;
+        if self.strip_leading_underscore {
+            name.strip_prefix('_').unwrap_or(name)
+        } else {
+            name
+        }
+    }
 }
 
 impl Block {
@@ -75,8 +166,8 @@ impl Block {
         }
     }
 
-    pub fn get_type(&self) -> BlockType {
-        self.block_type
+    pub fn get_type(&self) -> &BlockType {
+        &self.block_type
     }
 
     pub fn add(&mut self, expression: Expression) {
@@ -97,18 +188,34 @@ impl Block {
 
     pub fn compile(&self, program_path: &str) -> String {
         let mut out = String::new();
-        match self.block_type {
+        match &self.block_type {
             BlockType::Begin => out += "BEGIN",
             BlockType::Interval { rate_seconds } => out += &format!("interval:s:{}", rate_seconds),
+            BlockType::IntervalMs { rate_ms } => out += &format!("interval:ms:{}", rate_ms),
             BlockType::Uprobe(function) => {
-                out += &format!("uprobe:{}:{:?}", program_path, function)
+                out += &format!("uprobe:{}", probe_location(*function, 0, program_path))
             }
             BlockType::UprobeOffset(function, offset) => {
-                out += &format!("uprobe:{}:{:?}+{}", program_path, function, offset)
+                out += &format!(
+                    "uprobe:{}",
+                    probe_location(*function, *offset, program_path)
+                )
             }
             BlockType::Uretprobe(function) => {
-                out += &format!("uretprobe:{}:{:?}", program_path, function)
+                out += &format!("uretprobe:{}", probe_location(*function, 0, program_path))
+            }
+            BlockType::ReturnInstructions(function, offsets) => {
+                out += &offsets
+                    .iter()
+                    .map(|offset| {
+                        format!(
+                            "uprobe:{}",
+                            probe_location(*function, *offset, program_path)
+                        )
+                    })
+                    .join(", ")
             }
+            BlockType::Tracepoint(tracepoint) => out += &format!("tracepoint:{}", tracepoint),
         };
         if let Some(filter) = &self.filter {
             out += &format!(" /{}/", filter);
@@ -120,6 +227,84 @@ impl Block {
     }
 }
 
+/// Where to attach a uprobe `offset` bytes into `function`, as
+/// `<binary>:<location>`. Functions in shared libraries are probed in the
+/// library rather than `program_path`. Functions without a unique symbol are
+/// probed by address instead, see `FunctionName::synthesized_address`.
+fn probe_location(function: FunctionName, offset: u32, program_path: &str) -> String {
+    let path = function.object().unwrap_or(program_path);
+    match function.synthesized_address() {
+        Some(address) => format!("{}:{:#x}", path, address + offset as u64),
+        None if offset == 0 => format!("{}:{:?}", path, function.unqualified()),
+        None => format!("{}:{:?}+{}", path, function.unqualified(), offset),
+    }
+}
+
+impl Block {
+    /// D has no `if` statements on all platforms, so each `Expression::If` is
+    /// split out into its own clause. Clauses for the same probe run in
+    /// order and share clause-local (`this->`) variables, so we evaluate the
+    /// block filter and each condition once, in the same place the bpftrace
+    /// program would.
+    fn compile_dtrace(
+        &self,
+        target: &DTraceTarget,
+        aggregations: &HashSet<String>,
+        cond_counter: &mut u32,
+    ) -> String {
+        let probe = match &self.block_type {
+            BlockType::Begin => "BEGIN".to_string(),
+            BlockType::Interval { rate_seconds } => format!("tick-{}s", rate_seconds),
+            BlockType::IntervalMs { rate_ms } => format!("tick-{}ms", rate_ms),
+            BlockType::Uprobe(function) => format!(
+                "pid$target:{}:{}:entry",
+                target.probe_module(*function),
+                target.probe_function(*function)
+            ),
+            BlockType::UprobeOffset(function, offset) => format!(
+                "pid$target:{}:{}:{:x}",
+                target.probe_module(*function),
+                target.probe_function(*function),
+                offset
+            ),
+            // The pid provider already implements return probes by
+            // instrumenting return instructions
+            BlockType::Uretprobe(function) | BlockType::ReturnInstructions(function, _) => format!(
+                "pid$target:{}:{}:return",
+                target.probe_module(*function),
+                target.probe_function(*function)
+            ),
+            // D has no equivalent, the pid provider only traces one process.
+            // Left as is so that dtrace reports it.
+            BlockType::Tracepoint(tracepoint) => format!("tracepoint:{}", tracepoint),
+        };
+        let mut clauses = Vec::new();
+        let predicate = match &self.filter {
+            Some(filter) => {
+                let var = format!("this->cond{}", cond_counter);
+                *cond_counter += 1;
+                clauses.push(format!(
+                    "{} {{ {} = ({}); }}",
+                    probe,
+                    var,
+                    translate_dtrace(filter, aggregations)
+                ));
+                Some(var)
+            }
+            None => None,
+        };
+        Expression::compile_dtrace_clauses(
+            &self.expressions,
+            &probe,
+            predicate,
+            aggregations,
+            cond_counter,
+            &mut clauses,
+        );
+        clauses.join("\n")
+    }
+}
+
 impl Expression {
     pub fn compile(&self) -> String {
         match self {
@@ -153,6 +338,164 @@ impl Expression {
     pub fn compile_vec(expressions: &Vec<Expression>) -> String {
         expressions.iter().map(|e| e.compile()).join(" ")
     }
+
+    fn collect_aggregations(expressions: &[Expression], aggregations: &mut HashSet<String>) {
+        for expression in expressions {
+            match expression {
+                Expression::RawExpr(e) => {
+                    if let Some((lhs, rhs)) = e.split_once('=') {
+                        if rhs.trim_start().starts_with("hist(") {
+                            aggregations.insert(lhs.trim().trim_start_matches('@').to_string());
+                        }
+                    }
+                }
+                Expression::If { body, .. } => Expression::collect_aggregations(body, aggregations),
+                _ => (),
+            }
+        }
+    }
+
+    /// Append clauses for `expressions` to `clauses`, splitting out `If`s
+    fn compile_dtrace_clauses(
+        expressions: &[Expression],
+        probe: &str,
+        predicate: Option<String>,
+        aggregations: &HashSet<String>,
+        cond_counter: &mut u32,
+        clauses: &mut Vec<String>,
+    ) {
+        let mut statements = Vec::new();
+        let flush = |statements: &mut Vec<String>, clauses: &mut Vec<String>| {
+            if statements.is_empty() {
+                return;
+            }
+            let predicate_str = match &predicate {
+                Some(p) => format!(" /{}/", p),
+                None => String::new(),
+            };
+            clauses.push(format!(
+                "{}{} {{ {} }}",
+                probe,
+                predicate_str,
+                statements.join(" ")
+            ));
+            statements.clear();
+        };
+        for expression in expressions {
+            match expression {
+                Expression::If { condition, body } => {
+                    let var = format!("this->cond{}", cond_counter);
+                    *cond_counter += 1;
+                    let condition = translate_dtrace(condition, aggregations);
+                    statements.push(match &predicate {
+                        Some(p) => format!("{} = {} && ({});", var, p, condition),
+                        None => format!("{} = ({});", var, condition),
+                    });
+                    flush(&mut statements, clauses);
+                    Expression::compile_dtrace_clauses(
+                        body,
+                        probe,
+                        Some(var),
+                        aggregations,
+                        cond_counter,
+                        clauses,
+                    );
+                }
+                Expression::Printf { format, args } => {
+                    let args_suffix = args
+                        .iter()
+                        .map(|a| format!(", {}", translate_dtrace(a, aggregations)))
+                        .join("");
+                    statements.push(format!(
+                        r#"printf("{}"{});"#,
+                        format.replace('\"', r#"\""#),
+                        args_suffix
+                    ));
+                }
+                Expression::Print(val) => {
+                    statements.push(format!("printa({});", translate_dtrace(val, aggregations)))
+                }
+                Expression::RawExpr(e) => {
+                    // Raw expressions may themselves contain multiple
+                    // statements
+                    for statement in e.split(';').map(|s| s.trim()).filter(|s| !s.is_empty()) {
+                        statements.push(format!("{};", translate_dtrace(statement, aggregations)));
+                    }
+                }
+            }
+        }
+        flush(&mut statements, clauses);
+    }
+}
+
+/// Translate a bpftrace statement or expression to D. Only covers the subset
+/// of bpftrace that wachy generates, plus builtins commonly used in filters.
+fn translate_dtrace(expr: &str, aggregations: &HashSet<String>) -> String {
+    let expr = expr.trim();
+    // `delete(@map[key])` has no D equivalent, assigning 0 frees the entry
+    if let Some(inner) = expr
+        .strip_prefix("delete(")
+        .and_then(|e| e.strip_suffix(')'))
+    {
+        return format!("{} = 0", translate_dtrace(inner, aggregations));
+    }
+
+    let mut out = String::new();
+    let mut chars = expr.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '@' | '$' => {
+                let mut ident = String::new();
+                while let Some(&next) = chars.peek() {
+                    if next.is_alphanumeric() || next == '_' {
+                        ident.push(next);
+                        chars.next();
+                    } else {
+                        break;
+                    }
+                }
+                if c == '$' {
+                    out += &format!("this->{}", ident);
+                } else if aggregations.contains(&ident) {
+                    out += &format!("@{}", ident);
+                } else {
+                    out += &ident;
+                }
+            }
+            c if c.is_alphabetic() || c == '_' => {
+                let mut ident = c.to_string();
+                while let Some(&next) = chars.peek() {
+                    if next.is_alphanumeric() || next == '_' {
+                        ident.push(next);
+                        chars.next();
+                    } else {
+                        break;
+                    }
+                }
+                // `reg("sp")` reads the saved user registers, `uregs[R_SP]`
+                if ident == "reg" {
+                    let rest: String = chars.clone().take_while(|&c| c != ')').collect();
+                    if let Some(name) = rest
+                        .strip_prefix("(\"")
+                        .and_then(|rest| rest.strip_suffix('"'))
+                    {
+                        out += &format!("uregs[R_{}]", name.to_uppercase());
+                        chars.nth(rest.len());
+                        continue;
+                    }
+                }
+                out += match ident.as_str() {
+                    "nsecs" => "timestamp",
+                    "retval" => "arg1",
+                    "hist" => "quantize",
+                    "comm" => "execname",
+                    _ => &ident,
+                };
+            }
+            c => out.push(c),
+        }
+    }
+    out
 }
 
 impl From<String> for Expression {
@@ -165,3 +508,141 @@ impl From<&str> for Expression {
         Expression::RawExpr(e.to_string())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_translate_dtrace() {
+        let aggregations: HashSet<String> = vec!["histogram".to_string()].into_iter().collect();
+        assert_eq!(
+            translate_dtrace("@start5[tid] = nsecs", &aggregations),
+            "start5[tid] = timestamp"
+        );
+        assert_eq!(
+            translate_dtrace("delete(@start5[tid])", &aggregations),
+            "start5[tid] = 0"
+        );
+        assert_eq!(
+            translate_dtrace("@histogram = hist(@duration_tmp[tid])", &aggregations),
+            "@histogram = quantize(duration_tmp[tid])"
+        );
+        assert_eq!(
+            translate_dtrace("$duration > 1000", &aggregations),
+            "this->duration > 1000"
+        );
+        assert_eq!(
+            translate_dtrace(r#"reg("sp") >= @frame_sp0[tid]"#, &aggregations),
+            "uregs[R_SP] >= frame_sp0[tid]"
+        );
+    }
+
+    #[test]
+    fn test_compile_dtrace_if() {
+        let mut program = BpftraceProgram::new();
+        program.add(Block::new(
+            BlockType::Uretprobe(FunctionName("_foo")),
+            Some("@depth[tid] == 1".to_string()),
+            vec![
+                Expression::from("@depth[tid] = 0"),
+                Expression::If {
+                    condition: "arg0".to_string(),
+                    body: vec!["@count += 1".into()],
+                },
+            ],
+        ));
+        let target = DTraceTarget {
+            module: "a.out".to_string(),
+            strip_leading_underscore: true,
+        };
+        assert_eq!(
+            program.compile_dtrace(&target),
+            [
+                "pid$target:a.out:foo:return { this->cond0 = (depth[tid] == 1); }",
+                "pid$target:a.out:foo:return /this->cond0/ { depth[tid] = 0; this->cond1 = this->cond0 && (arg0); }",
+                "pid$target:a.out:foo:return /this->cond1/ { count += 1; }",
+            ]
+            .join("\n")
+        );
+    }
+
+    #[test]
+    fn test_compile_return_instructions() {
+        let block = Block::new(
+            BlockType::ReturnInstructions(FunctionName("main.foo"), vec![0x1a, 0x40]),
+            None,
+            vec!["@count += 1"],
+        );
+        assert_eq!(
+            block.compile("/bin/foo"),
+            "uprobe:/bin/foo:main.foo+26, uprobe:/bin/foo:main.foo+64 { @count += 1; }"
+        );
+    }
+
+    #[test]
+    fn test_compile_library_function() {
+        let function = FunctionName("/lib/libz.so.1`deflate");
+        let block = Block::new(
+            BlockType::UprobeOffset(function, 8),
+            None,
+            vec!["@count += 1"],
+        );
+        assert_eq!(
+            block.compile("/bin/foo"),
+            "uprobe:/lib/libz.so.1:deflate+8 { @count += 1; }"
+        );
+        let target = DTraceTarget {
+            module: "foo".to_string(),
+            strip_leading_underscore: false,
+        };
+        let mut program = BpftraceProgram::new();
+        program.add(Block::new(
+            BlockType::Uretprobe(function),
+            None,
+            vec!["@count += 1"],
+        ));
+        assert_eq!(
+            program.compile_dtrace(&target),
+            "pid$target:libz.so.1:deflate:return { count += 1; }"
+        );
+    }
+
+    #[test]
+    fn test_compile_synthesized_function() {
+        let block = Block::new(
+            BlockType::UprobeOffset(FunctionName("fn_0x401230"), 16),
+            None,
+            vec!["@count += 1"],
+        );
+        assert_eq!(
+            block.compile("/bin/foo"),
+            "uprobe:/bin/foo:0x401240 { @count += 1; }"
+        );
+    }
+
+    #[test]
+    fn test_compile_tracepoint() {
+        let block = Block::new(
+            BlockType::Tracepoint("sched:sched_process_fork"),
+            Some("@traced_pids[pid]".to_string()),
+            vec!["@traced_pids[args->child_pid] = 1"],
+        );
+        assert_eq!(
+            block.compile("/bin/foo"),
+            "tracepoint:sched:sched_process_fork /@traced_pids[pid]/ { @traced_pids[args->child_pid] = 1; }"
+        );
+    }
+
+    #[test]
+    fn test_compile_interval() {
+        let compile = |interval| {
+            Block::new(BlockType::interval(interval), None, vec!["exit()"]).compile("/bin/foo")
+        };
+        assert_eq!(compile(Duration::from_secs(2)), "interval:s:2 { exit(); }");
+        assert_eq!(
+            compile(Duration::from_millis(250)),
+            "interval:ms:250 { exit(); }"
+        );
+    }
+}
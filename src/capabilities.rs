@@ -0,0 +1,152 @@
+use std::fmt;
+
+/// bpftrace refuses to attach more probes than this in one program, unless
+/// overridden with `BPFTRACE_MAX_PROBES`
+const DEFAULT_MAX_PROBES: usize = 512;
+
+/// What the kernel running the traces supports, probed at startup so that
+/// features can be adapted or disabled up front rather than failing
+/// mid-session
+#[derive(Clone, Debug)]
+pub struct Capabilities {
+    /// Release of the kernel, e.g. `5.15.0-91-generic`, if known
+    pub kernel_release: Option<String>,
+    /// uretprobes (Linux 3.10+). Without them returns are traced by probing
+    /// each return instruction instead, like for Go programs.
+    pub uretprobes: bool,
+    /// Stack trace maps (Linux 4.6+), needed for `ustack`
+    pub stack_traces: bool,
+    /// Most probes a single program can attach
+    pub max_probes: Option<usize>,
+    /// BPF cookies (Linux 5.15+), which the libbpf backend's probes are told
+    /// apart by
+    pub bpf_cookies: bool,
+    /// uprobe_multi links (Linux 6.6+), which attach many probes of the
+    /// libbpf backend in one syscall
+    pub uprobe_multi: bool,
+}
+
+impl Capabilities {
+    /// Capabilities of a kernel that couldn't be probed, everything is assumed
+    /// to be supported
+    pub fn unknown() -> Capabilities {
+        Capabilities {
+            kernel_release: None,
+            uretprobes: true,
+            stack_traces: true,
+            max_probes: None,
+            bpf_cookies: true,
+            uprobe_multi: true,
+        }
+    }
+
+    /// Capabilities of a kernel with the given release, traced with bpftrace
+    pub fn for_release(release: &str) -> Capabilities {
+        let release = release.trim();
+        let version = parse_version(release);
+        let at_least = |min: (u32, u32)| version.map_or(true, |v| v >= min);
+        let max_probes = std::env::var("BPFTRACE_MAX_PROBES")
+            .ok()
+            .and_then(|max| max.parse().ok())
+            .unwrap_or(DEFAULT_MAX_PROBES);
+        Capabilities {
+            kernel_release: Some(release.to_string()),
+            uretprobes: at_least((3, 10)),
+            stack_traces: at_least((4, 6)),
+            max_probes: Some(max_probes),
+            bpf_cookies: at_least((5, 15)),
+            uprobe_multi: at_least((6, 6)),
+        }
+    }
+
+    /// Capabilities of the kernel we are running on
+    pub fn probe_local() -> Capabilities {
+        let mut uts: libc::utsname = unsafe { std::mem::zeroed() };
+        if unsafe { libc::uname(&mut uts) } != 0 {
+            log::warn!(
+                "uname failed, assuming all kernel features are supported: {}",
+                std::io::Error::last_os_error()
+            );
+            return Capabilities::unknown();
+        }
+        let release = unsafe { std::ffi::CStr::from_ptr(uts.release.as_ptr()) };
+        Capabilities::for_release(&release.to_string_lossy())
+    }
+
+    /// Descriptions of the features that are unavailable or adapted
+    pub fn limitations(&self) -> Vec<&'static str> {
+        let mut limitations = Vec::new();
+        if !self.uretprobes {
+            limitations.push(
+                "uretprobes are not supported (Linux 3.10+), returns are traced by probing return instructions instead",
+            );
+        }
+        if !self.stack_traces {
+            limitations.push(
+                "Stack traces are not supported (Linux 4.6+), captured calls (o) will not show the stack",
+            );
+        }
+        limitations
+    }
+}
+
+impl fmt::Display for Capabilities {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let yes_no = |supported: bool| if supported { "yes" } else { "no" };
+        writeln!(
+            f,
+            "Kernel: {}",
+            self.kernel_release.as_deref().unwrap_or("unknown")
+        )?;
+        writeln!(f, "uretprobes: {}", yes_no(self.uretprobes))?;
+        writeln!(f, "Stack traces: {}", yes_no(self.stack_traces))?;
+        writeln!(f, "BPF cookies: {}", yes_no(self.bpf_cookies))?;
+        writeln!(f, "uprobe_multi: {}", yes_no(self.uprobe_multi))?;
+        match self.max_probes {
+            Some(max_probes) => writeln!(f, "Max probes: {}", max_probes)?,
+            None => writeln!(f, "Max probes: unknown")?,
+        }
+        let limitations = self.limitations();
+        if !limitations.is_empty() {
+            writeln!(f, "\nLimitations:")?;
+            for limitation in limitations {
+                writeln!(f, "  {}", limitation)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// (major, minor) version from a kernel release like `5.15.0-91-generic`
+pub fn parse_version(release: &str) -> Option<(u32, u32)> {
+    let mut parts = release.split(|c: char| !c.is_ascii_digit());
+    let major = parts.next()?.parse().ok()?;
+    let minor = parts.next()?.parse().ok()?;
+    Some((major, minor))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_for_release() {
+        assert_eq!(parse_version("5.15.0-91-generic"), Some((5, 15)));
+        assert_eq!(parse_version("4.4.302+"), Some((4, 4)));
+        assert_eq!(parse_version("unknown"), None);
+
+        let capabilities = Capabilities::for_release("4.4.302+\n");
+        assert_eq!(capabilities.kernel_release.as_deref(), Some("4.4.302+"));
+        assert!(capabilities.uretprobes);
+        assert!(!capabilities.stack_traces);
+        assert!(!capabilities.bpf_cookies);
+        assert_eq!(capabilities.limitations().len(), 1);
+
+        let capabilities = Capabilities::for_release("3.2.0");
+        assert!(!capabilities.uretprobes);
+        assert!(Capabilities::for_release("5.15.0").bpf_cookies);
+        assert!(!Capabilities::for_release("6.1.0").uprobe_multi);
+        assert!(Capabilities::for_release("6.6.8").uprobe_multi);
+        assert!(Capabilities::for_release("6.1.0").limitations().is_empty());
+    }
+}
@@ -0,0 +1,310 @@
+use crate::backend::TraceBackend;
+use crate::capabilities::{self, Capabilities};
+use std::fmt;
+use std::process::Command;
+
+/// Oldest kernel bpftrace runs on
+const MIN_KERNEL_VERSION: (u32, u32) = (4, 9);
+
+/// Oldest bpftrace with everything the generated programs use (e.g. `-f json`
+/// output with histograms)
+const MIN_BPFTRACE_VERSION: (u32, u32) = (0, 14);
+
+const CAP_SYS_ADMIN: u32 = 21;
+const CAP_PERFMON: u32 = 38;
+const CAP_BPF: u32 = 39;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Status {
+    Passed,
+    /// Tracing works, but some features may not
+    Warning,
+    /// Tracing can't work
+    Failed,
+    /// Couldn't be checked, e.g. the kernel config isn't available
+    Skipped,
+}
+
+/// Outcome of a check of the environment traces run in
+pub struct Check {
+    pub name: &'static str,
+    pub status: Status,
+    /// What was found, e.g. the bpftrace version
+    pub found: String,
+    /// What to do about it if it didn't pass
+    pub advice: Option<String>,
+}
+
+impl Check {
+    fn new(name: &'static str, status: Status, found: String) -> Check {
+        Check {
+            name,
+            status,
+            found,
+            advice: None,
+        }
+    }
+
+    fn advice(mut self, advice: &str) -> Check {
+        self.advice = Some(advice.to_string());
+        self
+    }
+}
+
+impl fmt::Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let status = match self.status {
+            Status::Passed => "ok",
+            Status::Warning => "warning",
+            Status::Failed => "FAILED",
+            Status::Skipped => "skipped",
+        };
+        write!(f, "[{}] {}: {}", status, self.name, self.found)?;
+        if let Some(advice) = &self.advice {
+            write!(f, "\n    {}", advice)?;
+        }
+        Ok(())
+    }
+}
+
+/// Check that traces of `backend` can run, before any are started. Only
+/// backends that run bpftrace locally are checked, others run elsewhere.
+pub fn run(backend: &dyn TraceBackend, capabilities: &Capabilities) -> Vec<Check> {
+    if !backend.is_local_bpftrace() {
+        return Vec::new();
+    }
+    let is_root = unsafe { libc::geteuid() } == 0;
+    let checks = vec![
+        check_kernel(capabilities),
+        check_uprobes(capabilities),
+        check_bpftrace(backend),
+        check_privileges(is_root),
+        check_lockdown(),
+        check_perf_event_paranoid(is_root),
+    ];
+    for check in &checks {
+        log::info!("Check {}", check);
+    }
+    checks
+}
+
+pub fn any_failed(checks: &[Check]) -> bool {
+    checks.iter().any(|check| check.status == Status::Failed)
+}
+
+/// Whether any check needs the user's attention on startup
+pub fn any_problems(checks: &[Check]) -> bool {
+    checks
+        .iter()
+        .any(|check| matches!(check.status, Status::Failed | Status::Warning))
+}
+
+fn check_kernel(capabilities: &Capabilities) -> Check {
+    let release = match &capabilities.kernel_release {
+        Some(release) => release,
+        None => return Check::new("Kernel", Status::Skipped, "unknown".to_string()),
+    };
+    match capabilities::parse_version(release) {
+        Some(version) if version < MIN_KERNEL_VERSION => {
+            Check::new("Kernel", Status::Failed, release.clone()).advice(&format!(
+                "bpftrace needs Linux {}.{} or later, upgrade the kernel",
+                MIN_KERNEL_VERSION.0, MIN_KERNEL_VERSION.1
+            ))
+        }
+        _ => Check::new("Kernel", Status::Passed, release.clone()),
+    }
+}
+
+fn check_uprobes(capabilities: &Capabilities) -> Check {
+    let config = capabilities
+        .kernel_release
+        .as_ref()
+        .and_then(|release| std::fs::read_to_string(format!("/boot/config-{}", release)).ok())
+        .or_else(|| {
+            // Only there with CONFIG_IKCONFIG_PROC
+            let output = Command::new("zcat").arg("/proc/config.gz").output().ok()?;
+            Some(String::from_utf8_lossy(&output.stdout).into_owned())
+                .filter(|_| output.status.success())
+        });
+    let config = match config {
+        Some(config) => config,
+        None => {
+            return Check::new(
+                "Uprobes",
+                Status::Skipped,
+                "kernel config not found".to_string(),
+            )
+        }
+    };
+    match kernel_config_value(&config, "CONFIG_UPROBES") {
+        Some("y") => Check::new("Uprobes", Status::Passed, "CONFIG_UPROBES=y".to_string()),
+        _ => Check::new(
+            "Uprobes",
+            Status::Failed,
+            "kernel built without CONFIG_UPROBES".to_string(),
+        )
+        .advice("Functions can't be traced, use a kernel built with CONFIG_UPROBES=y"),
+    }
+}
+
+fn check_bpftrace(backend: &dyn TraceBackend) -> Check {
+    let mut command = match backend.bpftrace_command() {
+        Some(command) => command,
+        None => return Check::new("bpftrace", Status::Skipped, "not used".to_string()),
+    };
+    // The libbpf backend only runs it for what it doesn't support itself
+    let status = if backend.is_native() {
+        Status::Warning
+    } else {
+        Status::Failed
+    };
+    let install = format!(
+        "Install bpftrace {}.{} or later (see https://github.com/iovisor/bpftrace/blob/master/INSTALL.md), or pass --bundled-runtime",
+        MIN_BPFTRACE_VERSION.0, MIN_BPFTRACE_VERSION.1
+    );
+    let output = match command.arg("--version").output() {
+        Ok(output) => output,
+        Err(err) => return Check::new("bpftrace", status, err.to_string()).advice(&install),
+    };
+    let version = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    match parse_bpftrace_version(&version) {
+        Some(parsed) if parsed < MIN_BPFTRACE_VERSION => {
+            Check::new("bpftrace", status, version).advice(&install)
+        }
+        Some(_) => Check::new("bpftrace", Status::Passed, version),
+        None => Check::new(
+            "bpftrace",
+            Status::Skipped,
+            format!("unknown version {}", version),
+        ),
+    }
+}
+
+fn check_privileges(is_root: bool) -> Check {
+    if is_root {
+        return Check::new("Privileges", Status::Passed, "root".to_string());
+    }
+    let capabilities = std::fs::read_to_string("/proc/self/status")
+        .ok()
+        .and_then(|status| parse_effective_capabilities(&status))
+        .unwrap_or(0);
+    let has = |capability: u32| capabilities & (1 << capability) != 0;
+    if has(CAP_SYS_ADMIN) {
+        Check::new("Privileges", Status::Passed, "CAP_SYS_ADMIN".to_string())
+    } else if has(CAP_BPF) && has(CAP_PERFMON) {
+        Check::new(
+            "Privileges",
+            Status::Passed,
+            "CAP_BPF and CAP_PERFMON".to_string(),
+        )
+    } else {
+        Check::new(
+            "Privileges",
+            Status::Failed,
+            "not root, and without CAP_BPF and CAP_PERFMON".to_string(),
+        )
+        .advice("Run wachy with sudo")
+    }
+}
+
+fn check_lockdown() -> Check {
+    let mode = std::fs::read_to_string("/sys/kernel/security/lockdown")
+        .ok()
+        .and_then(|lockdown| parse_lockdown(&lockdown).map(|mode| mode.to_string()));
+    match mode.as_deref() {
+        None => Check::new("Lockdown", Status::Skipped, "unknown".to_string()),
+        Some("confidentiality") => {
+            Check::new("Lockdown", Status::Warning, "confidentiality".to_string()).advice(
+                "bpftrace may be unable to read memory (e.g. arguments of captured calls), lockdown is usually enabled by Secure Boot",
+            )
+        }
+        Some(mode) => Check::new("Lockdown", Status::Passed, mode.to_string()),
+    }
+}
+
+fn check_perf_event_paranoid(is_root: bool) -> Check {
+    let paranoid = std::fs::read_to_string("/proc/sys/kernel/perf_event_paranoid")
+        .ok()
+        .and_then(|value| value.trim().parse::<i32>().ok());
+    match paranoid {
+        None => Check::new(
+            "perf_event_paranoid",
+            Status::Skipped,
+            "unknown".to_string(),
+        ),
+        Some(paranoid) if is_root || paranoid <= 1 => {
+            Check::new("perf_event_paranoid", Status::Passed, paranoid.to_string())
+        }
+        Some(paranoid) => Check::new("perf_event_paranoid", Status::Warning, paranoid.to_string())
+            .advice(
+                "Sampling branches (B) needs root or `sudo sysctl kernel.perf_event_paranoid=1`",
+            ),
+    }
+}
+
+/// Value of `option` in a kernel config, e.g. `y` for `CONFIG_UPROBES=y`.
+/// Options that aren't set are commented out.
+fn kernel_config_value<'a>(config: &'a str, option: &str) -> Option<&'a str> {
+    config.lines().find_map(|line| {
+        let (name, value) = line.split_once('=')?;
+        Some(value).filter(|_| name == option)
+    })
+}
+
+/// (major, minor) version from `bpftrace --version` output like
+/// `bpftrace v0.14.1`
+fn parse_bpftrace_version(version: &str) -> Option<(u32, u32)> {
+    let version = version.split_whitespace().nth(1)?;
+    let mut parts = version.trim_start_matches('v').split('.');
+    let major = parts.next()?.parse().ok()?;
+    let minor = parts.next()?.parse().ok()?;
+    Some((major, minor))
+}
+
+/// Selected mode of `/sys/kernel/security/lockdown`, which lists all modes
+/// like `none [integrity] confidentiality`
+fn parse_lockdown(lockdown: &str) -> Option<&str> {
+    lockdown
+        .split_whitespace()
+        .find_map(|mode| mode.strip_prefix('[')?.strip_suffix(']'))
+}
+
+/// Effective capabilities from the contents of `/proc/<pid>/status`, where
+/// they are a bitmask in hex like `CapEff:	000001ffffffffff`
+fn parse_effective_capabilities(status: &str) -> Option<u64> {
+    let mask = status
+        .lines()
+        .find_map(|line| line.strip_prefix("CapEff:"))?;
+    u64::from_str_radix(mask.trim(), 16).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parsing() {
+        let config = "CONFIG_UPROBE_EVENTS=y\n# CONFIG_KPROBES is not set\nCONFIG_UPROBES=y\n";
+        assert_eq!(kernel_config_value(config, "CONFIG_UPROBES"), Some("y"));
+        assert_eq!(kernel_config_value(config, "CONFIG_KPROBES"), None);
+
+        assert_eq!(parse_bpftrace_version("bpftrace v0.14.1"), Some((0, 14)));
+        assert_eq!(
+            parse_bpftrace_version("bpftrace v0.19.0-52-gabcdef"),
+            Some((0, 19))
+        );
+        assert_eq!(parse_bpftrace_version("bpftrace"), None);
+
+        assert_eq!(
+            parse_lockdown("none [integrity] confidentiality\n"),
+            Some("integrity")
+        );
+        assert_eq!(parse_lockdown("none integrity"), None);
+
+        let status = "Name:\twachy\nCapInh:\t0000000000000000\nCapEff:\t000000c000000000\n";
+        let capabilities = parse_effective_capabilities(status).unwrap();
+        assert_ne!(capabilities & (1 << CAP_BPF), 0);
+        assert_ne!(capabilities & (1 << CAP_PERFMON), 0);
+        assert_eq!(capabilities & (1 << CAP_SYS_ADMIN), 0);
+    }
+}
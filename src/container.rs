@@ -0,0 +1,290 @@
+use crate::error::Error;
+use serde_json::Value;
+use std::process::Command;
+
+/// Container runtimes tried in order to resolve a container id or name
+const RUNTIMES: &[&str] = &["docker", "podman"];
+
+/// A running container, as seen from the host
+pub struct Container {
+    /// Host pid of the container's init process
+    pub pid: u32,
+    /// Path of the container's cgroup under `/sys/fs/cgroup`, if the host
+    /// uses cgroup v2
+    pub cgroup: Option<String>,
+}
+
+impl Container {
+    /// Find the container with the given id or name with docker or podman
+    pub fn resolve(container: &str) -> Result<Container, Error> {
+        let mut errors = Vec::new();
+        for runtime in RUNTIMES {
+            let output = match Command::new(runtime)
+                .args([
+                    "inspect",
+                    "--type",
+                    "container",
+                    "--format",
+                    "{{.State.Pid}}",
+                ])
+                .arg(container)
+                .output()
+            {
+                Ok(output) => output,
+                // Runtime isn't installed
+                Err(_) => continue,
+            };
+            if !output.status.success() {
+                errors.push(format!(
+                    "{}: {}",
+                    runtime,
+                    String::from_utf8_lossy(&output.stderr).trim()
+                ));
+                continue;
+            }
+            let pid = String::from_utf8_lossy(&output.stdout).trim().to_string();
+            let pid = match pid.parse::<u32>() {
+                Ok(0) => return Err(format!("Container {} is not running", container).into()),
+                Ok(pid) => pid,
+                Err(_) => {
+                    return Err(format!("Unexpected pid '{}' from {} inspect", pid, runtime).into())
+                }
+            };
+            let cgroup = std::fs::read_to_string(format!("/proc/{}/cgroup", pid))
+                .ok()
+                .and_then(|contents| parse_cgroup(&contents));
+            log::info!(
+                "Container {} has pid {} and cgroup {:?}",
+                container,
+                pid,
+                cgroup
+            );
+            return Ok(Container { pid, cgroup });
+        }
+        if errors.is_empty() {
+            Err("Neither docker nor podman found, needed for --container".into())
+        } else {
+            Err(format!(
+                "Failed to find container {}:\n{}",
+                container,
+                errors.join("\n")
+            )
+            .into())
+        }
+    }
+
+    /// Path on the host of `path` in the container's root filesystem. Uprobes
+    /// attach to the file through it too.
+    pub fn host_path(&self, path: &str) -> Result<String, Error> {
+        if !path.starts_with('/') {
+            return Err(
+                format!("Program {} must be an absolute path in the container", path).into(),
+            );
+        }
+        // Not canonicalized, that would resolve `/proc/<pid>/root` to the
+        // host's root
+        let host_path = format!("/proc/{}/root{}", self.pid, path);
+        match std::fs::metadata(&host_path) {
+            Ok(_) => Ok(host_path),
+            Err(err) => Err(format!("Failed to find {} in the container: {}", path, err).into()),
+        }
+    }
+
+    /// Find the container of a Kubernetes pod given as
+    /// `<namespace>/<pod>[/<container>]`, through the node's CRI runtime with
+    /// `crictl`. The container can be left out if the pod only has one.
+    pub fn resolve_pod(spec: &str) -> Result<Container, Error> {
+        let (namespace, pod, container) = parse_pod_spec(spec)?;
+        let pods = crictl(&["pods", "--namespace", namespace, "--state", "ready"])?;
+        let pod_id = find_pod_id(&pods, pod)
+            .ok_or_else(|| format!("No ready pod {} in namespace {}", pod, namespace))?;
+        let containers = crictl(&["ps", "--pod", &pod_id, "--state", "running"])?;
+        let container_id = find_container_id(&containers, container)
+            .map_err(|err| format!("Pod {}/{}: {}", namespace, pod, err))?;
+        let info = crictl(&["inspect", &container_id])?;
+        let pid = match info["info"]["pid"].as_u64() {
+            Some(pid) if pid > 0 && pid <= u32::MAX as u64 => pid as u32,
+            _ => {
+                return Err(
+                    format!("crictl inspect has no pid for container {}", container_id).into(),
+                )
+            }
+        };
+        let cgroup = std::fs::read_to_string(format!("/proc/{}/cgroup", pid))
+            .ok()
+            .and_then(|contents| parse_cgroup(&contents));
+        log::info!(
+            "Pod {} has container {} with pid {} and cgroup {:?}",
+            spec,
+            container_id,
+            pid,
+            cgroup
+        );
+        Ok(Container { pid, cgroup })
+    }
+
+    /// Path in the container of the binary its init process runs
+    pub fn executable(&self) -> Result<String, Error> {
+        // The link is relative to the process' root, i.e. the container's
+        std::fs::read_link(format!("/proc/{}/exe", self.pid))
+            .map(|path| path.to_string_lossy().into_owned())
+            .map_err(|err| {
+                format!(
+                    "Failed to find the binary of container process {}: {}",
+                    self.pid, err
+                )
+                .into()
+            })
+    }
+}
+
+/// Run `crictl` with `args`, returning its JSON output
+fn crictl(args: &[&str]) -> Result<Value, Error> {
+    let output = match Command::new("crictl")
+        .args(args)
+        .args(["--output", "json"])
+        .output()
+    {
+        Ok(output) => output,
+        Err(err) => return Err(format!("Failed to run crictl, needed for k8s: {}", err).into()),
+    };
+    if !output.status.success() {
+        return Err(format!(
+            "crictl {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )
+        .into());
+    }
+    serde_json::from_slice(&output.stdout)
+        .map_err(|err| format!("Unexpected output from crictl {}: {}", args[0], err).into())
+}
+
+/// Split `<namespace>/<pod>[/<container>]` into its parts
+fn parse_pod_spec(spec: &str) -> Result<(&str, &str, Option<&str>), Error> {
+    let parts: Vec<&str> = spec.split('/').collect();
+    match parts[..] {
+        [namespace, pod] if !namespace.is_empty() && !pod.is_empty() => Ok((namespace, pod, None)),
+        [namespace, pod, container]
+            if !namespace.is_empty() && !pod.is_empty() && !container.is_empty() =>
+        {
+            Ok((namespace, pod, Some(container)))
+        }
+        _ => Err(format!(
+            "Invalid pod {}, expected <namespace>/<pod>[/<container>]",
+            spec
+        )
+        .into()),
+    }
+}
+
+/// Id of the pod named `pod` in the output of `crictl pods`. Its `--name`
+/// filter matches substrings, so the name is compared here instead.
+fn find_pod_id(pods: &Value, pod: &str) -> Option<String> {
+    pods["items"]
+        .as_array()?
+        .iter()
+        .find(|item| item["metadata"]["name"] == pod)
+        .and_then(|item| item["id"].as_str())
+        .map(|id| id.to_string())
+}
+
+/// Id of the container named `container` in the output of `crictl ps`, or of
+/// the only container if no name is given
+fn find_container_id(containers: &Value, container: Option<&str>) -> Result<String, Error> {
+    let containers = containers["containers"]
+        .as_array()
+        .map(Vec::as_slice)
+        .unwrap_or_default();
+    let name = |c: &Value| c["metadata"]["name"].as_str().unwrap_or("").to_string();
+    let found = match container {
+        Some(container) => containers
+            .iter()
+            .find(|c| c["metadata"]["name"] == container),
+        None if containers.len() == 1 => containers.first(),
+        None if containers.is_empty() => None,
+        None => {
+            let names: Vec<String> = containers.iter().map(name).collect();
+            return Err(format!(
+                "has several containers, add one of {} to pick one",
+                names.join(", ")
+            )
+            .into());
+        }
+    };
+    match (found.and_then(|c| c["id"].as_str()), container) {
+        (Some(id), _) => Ok(id.to_string()),
+        (None, Some(container)) => Err(format!("no running container {}", container).into()),
+        (None, None) => Err("no running containers".into()),
+    }
+}
+
+/// Path of the cgroup v2 directory of a process, from the contents of
+/// `/proc/<pid>/cgroup`. Its line for the unified hierarchy is `0::<path>`.
+fn parse_cgroup(contents: &str) -> Option<String> {
+    contents
+        .lines()
+        .find_map(|line| line.strip_prefix("0::"))
+        .filter(|path| path.starts_with('/') && *path != "/")
+        .map(|path| format!("/sys/fs/cgroup{}", path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_cgroup() {
+        assert_eq!(
+            parse_cgroup("0::/system.slice/docker-4f1c.scope\n").as_deref(),
+            Some("/sys/fs/cgroup/system.slice/docker-4f1c.scope")
+        );
+        // cgroup v1 only
+        assert_eq!(
+            parse_cgroup("12:pids:/docker/4f1c\n1:name=systemd:/docker/4f1c\n"),
+            None
+        );
+        assert_eq!(parse_cgroup("0::/\n"), None);
+    }
+
+    #[test]
+    fn test_parse_pod_spec() {
+        assert_eq!(
+            parse_pod_spec("prod/web-7d4b").unwrap(),
+            ("prod", "web-7d4b", None)
+        );
+        assert_eq!(
+            parse_pod_spec("prod/web-7d4b/nginx").unwrap(),
+            ("prod", "web-7d4b", Some("nginx"))
+        );
+        assert!(parse_pod_spec("web-7d4b").is_err());
+        assert!(parse_pod_spec("prod//nginx").is_err());
+        assert!(parse_pod_spec("prod/web/nginx/extra").is_err());
+    }
+
+    #[test]
+    fn test_find_ids() {
+        let pods = serde_json::json!({"items": [
+            {"id": "a1", "metadata": {"name": "web-7d4b-x"}},
+            {"id": "b2", "metadata": {"name": "web-7d4b"}},
+        ]});
+        assert_eq!(find_pod_id(&pods, "web-7d4b").as_deref(), Some("b2"));
+        assert_eq!(find_pod_id(&pods, "db"), None);
+
+        let containers = serde_json::json!({"containers": [
+            {"id": "c3", "metadata": {"name": "nginx"}},
+            {"id": "d4", "metadata": {"name": "sidecar"}},
+        ]});
+        assert_eq!(
+            find_container_id(&containers, Some("sidecar")).unwrap(),
+            "d4"
+        );
+        assert!(find_container_id(&containers, Some("app")).is_err());
+        // Ambiguous without a name
+        assert!(find_container_id(&containers, None).is_err());
+        let containers = serde_json::json!({"containers": [
+            {"id": "c3", "metadata": {"name": "nginx"}},
+        ]});
+        assert_eq!(find_container_id(&containers, None).unwrap(), "c3");
+    }
+}
@@ -0,0 +1,83 @@
+use crate::program::{FunctionName, OBJECT_SEPARATOR};
+use crate::templates;
+use crate::trace_structs::TraceStack;
+use std::collections::HashMap;
+use std::path::Path;
+use std::time::Duration;
+
+const MUTEX_LOCK: &str = "pthread_mutex_lock";
+
+/// Time spent waiting for locks, and how many times
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct LockWaits {
+    pub duration: Duration,
+    pub count: u64,
+}
+
+impl LockWaits {
+    pub fn add(&mut self, other: LockWaits) {
+        self.duration += other.duration;
+        self.count += other.count;
+    }
+}
+
+/// `pthread_mutex_lock` in the libraries the program at `program_path` links,
+/// if it links one that exports it. Since glibc 2.34 it is in libc, and
+/// libpthread only keeps stubs.
+pub fn find_mutex_lock(program_path: &str) -> Option<FunctionName> {
+    let libraries = templates::linked_libraries(program_path)
+        .map_err(|err| log::warn!("{}", err))
+        .ok()?;
+    let path = libraries.iter().find(|path| {
+        let file_name = Path::new(path)
+            .file_name()
+            .map_or(String::new(), |name| name.to_string_lossy().into_owned());
+        (file_name.starts_with("libpthread.so") || file_name.starts_with("libc.so"))
+            && templates::exports(path, MUTEX_LOCK)
+    })?;
+    let qualified = format!("{}{}{}", path, OBJECT_SEPARATOR, MUTEX_LOCK);
+    Some(FunctionName(Box::leak(qualified.into_boxed_str())))
+}
+
+/// Lock waits reached from `function`, keyed by the offset within it of the
+/// return address of the call they were reached through. Parsed from the
+/// `@waits` and `@wait_counts` maps printed by the program from
+/// `TraceStack::get_lock_waits_expr`.
+pub fn parse_waits(output: &str, function: &str) -> HashMap<u64, LockWaits> {
+    TraceStack::parse_stack_maps(output, function)
+        .into_iter()
+        .map(|(offset, maps)| {
+            let waits = LockWaits {
+                duration: Duration::from_nanos(maps.get("@waits").copied().unwrap_or(0)),
+                count: maps.get("@wait_counts").copied().unwrap_or(0),
+            };
+            (offset, waits)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_waits() {
+        let output = "Attaching 7 probes...\n\n\n@depth[42]: 1\n@waits[\n\t__lll_lock_wait+48\n\tfoo+26\n\tmain+42\n]: 12000\n@waits[\n\tfoo+26\n\tmain+42\n]: 3000\n@waits[\n\tbar+10\n]: 500\n@waits[\n\tfoo+80\n]: 7000\n\n@wait_counts[\n\t__lll_lock_wait+48\n\tfoo+26\n\tmain+42\n]: 2\n@wait_counts[\n\tfoo+80\n]: 1\n";
+        let waits = parse_waits(output, "foo");
+        assert_eq!(waits.len(), 2);
+        assert_eq!(
+            waits[&26],
+            LockWaits {
+                duration: Duration::from_nanos(15000),
+                count: 2
+            }
+        );
+        assert_eq!(
+            waits[&80],
+            LockWaits {
+                duration: Duration::from_nanos(7000),
+                count: 1
+            }
+        );
+    }
+}
@@ -0,0 +1,310 @@
+use crate::error::Error;
+use crate::events::Event;
+use crate::trace_structs::ProcessFilter;
+use std::fmt;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::mpsc;
+use std::thread;
+
+/// Accepts line-based commands over a unix socket so external tools (e.g. test
+/// harnesses) can drive a session. Each command is forwarded to the controller
+/// as an `Event::ControlCommand`, and the reply is written back as a single
+/// line.
+pub struct ControlServer {
+    socket_path: String,
+}
+
+impl ControlServer {
+    pub fn new(socket_path: &str, tx: mpsc::Sender<Event>) -> Result<ControlServer, Error> {
+        // Remove stale socket left over from a previous session
+        let _ = std::fs::remove_file(socket_path);
+        let listener = UnixListener::bind(socket_path)
+            .map_err(|err| format!("Failed to bind control socket {}: {}", socket_path, err))?;
+        // This thread lives for the duration of the process, it is never
+        // joined.
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        let tx = tx.clone();
+                        thread::spawn(move || ControlServer::handle_connection(stream, tx));
+                    }
+                    Err(err) => log::warn!("Failed to accept control connection: {:?}", err),
+                }
+            }
+        });
+        Ok(ControlServer {
+            socket_path: socket_path.to_string(),
+        })
+    }
+
+    fn handle_connection(stream: UnixStream, tx: mpsc::Sender<Event>) {
+        let mut writer = match stream.try_clone() {
+            Ok(writer) => writer,
+            Err(err) => {
+                log::error!("Failed to clone control stream: {:?}", err);
+                return;
+            }
+        };
+        for line in BufReader::new(stream).lines() {
+            let command = match line {
+                Ok(command) => command,
+                Err(_) => return,
+            };
+            if command.trim().is_empty() {
+                continue;
+            }
+            log::info!("Control command: {}", command);
+            let (reply_tx, reply_rx) = mpsc::channel();
+            if tx
+                .send(Event::ControlCommand {
+                    command: command.trim().to_string(),
+                    reply_tx,
+                })
+                .is_err()
+            {
+                return;
+            }
+            let reply = reply_rx
+                .recv()
+                .unwrap_or_else(|_| "error: session ended".to_string());
+            if writeln!(writer, "{}", reply).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+impl Drop for ControlServer {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.socket_path);
+    }
+}
+
+/// Selects which call to trace when a line has more than one, or a manually
+/// specified range or span of lines.
+#[derive(Debug, PartialEq, Clone)]
+pub enum CallsiteSelector {
+    /// Offset of the call instruction relative to start of the function
+    Offset(u32),
+    /// Start and end offsets relative to start of the function
+    Manual { start: u32, end: u32 },
+    /// Code from the traced line to the given one, written as `..line`
+    Span { end_line: u32 },
+}
+
+/// Commands accepted over the control socket. These are also what session
+/// recordings consist of, so `Display` must produce parseable output.
+#[derive(Debug, PartialEq, Clone)]
+pub enum ControlCommand {
+    /// Trace a call on the given line of the current function
+    AddTrace {
+        function: String,
+        line: u32,
+        callsite: Option<CallsiteSelector>,
+    },
+    RemoveTrace {
+        function: String,
+        line: u32,
+    },
+    /// Push function (mangled name) onto the trace stack
+    Enter(String),
+    /// Pop the current function off the trace stack
+    Pop,
+    /// Set filter on entry of the current function, empty to clear
+    Filter(String),
+    /// Set filter on exit of the current function, empty to clear
+    RetFilter(String),
+    /// Only trace the given processes and threads, empty to clear
+    ProcessFilter(ProcessFilter),
+    /// Restart trace, clearing current aggregates
+    Restart,
+    /// Reply with current trace results as JSON
+    Snapshot,
+    /// Write current trace results as JSON to the given path
+    Export(String),
+}
+
+impl ControlCommand {
+    pub fn parse(command: &str) -> Result<ControlCommand, Error> {
+        let (name, arg) = match command.split_once(char::is_whitespace) {
+            Some((name, arg)) => (name, Some(arg.trim())),
+            None => (command, None),
+        };
+        // Function names can contain `:`, so line number is after the last one
+        let parse_location = |arg: Option<&str>| -> Result<(String, u32), Error> {
+            let arg = arg.ok_or_else(|| format!("{} requires an argument func:line", name))?;
+            match arg.rsplit_once(':') {
+                Some((function, line)) => match line.parse::<u32>() {
+                    Ok(line) if line > 0 => Ok((function.to_string(), line)),
+                    _ => Err(format!("Invalid line number '{}'", line).into()),
+                },
+                None => Err(format!("Expected func:line, got '{}'", arg).into()),
+            }
+        };
+        let parse_offset = |offset: &str| -> Result<u32, Error> {
+            offset
+                .parse::<u32>()
+                .map_err(|_| format!("Invalid offset '{}'", offset).into())
+        };
+        match name {
+            "add-trace" => {
+                // Versioned symbols contain `@` too, selector is only after
+                // the line number
+                let selector = arg
+                    .and_then(|a| a.rsplit_once('@'))
+                    .filter(|(_, callsite)| !callsite.contains(':'));
+                let (location, callsite) = match selector {
+                    Some((location, callsite)) => {
+                        let callsite = if let Some(end_line) = callsite.strip_prefix("..") {
+                            match end_line.parse::<u32>() {
+                                Ok(end_line) if end_line > 0 => CallsiteSelector::Span { end_line },
+                                _ => {
+                                    return Err(format!("Invalid line number '{}'", end_line).into())
+                                }
+                            }
+                        } else {
+                            match callsite.split_once('-') {
+                                Some((start, end)) => CallsiteSelector::Manual {
+                                    start: parse_offset(start)?,
+                                    end: parse_offset(end)?,
+                                },
+                                None => CallsiteSelector::Offset(parse_offset(callsite)?),
+                            }
+                        };
+                        (Some(location), Some(callsite))
+                    }
+                    None => (arg, None),
+                };
+                let (function, line) = parse_location(location)?;
+                Ok(ControlCommand::AddTrace {
+                    function,
+                    line,
+                    callsite,
+                })
+            }
+            "remove-trace" => {
+                let (function, line) = parse_location(arg)?;
+                Ok(ControlCommand::RemoveTrace { function, line })
+            }
+            "enter" => match arg {
+                Some(function) if !function.is_empty() => {
+                    Ok(ControlCommand::Enter(function.to_string()))
+                }
+                _ => Err("enter requires a function".into()),
+            },
+            "pop" => Ok(ControlCommand::Pop),
+            "filter" => Ok(ControlCommand::Filter(arg.unwrap_or("").to_string())),
+            "ret-filter" => Ok(ControlCommand::RetFilter(arg.unwrap_or("").to_string())),
+            "process-filter" => Ok(ControlCommand::ProcessFilter(ProcessFilter::parse(
+                arg.unwrap_or(""),
+            )?)),
+            "restart" => Ok(ControlCommand::Restart),
+            "snapshot" => Ok(ControlCommand::Snapshot),
+            "export" => match arg {
+                Some(path) if !path.is_empty() => Ok(ControlCommand::Export(path.to_string())),
+                _ => Err("export requires a path".into()),
+            },
+            _ => Err(format!("Unknown command '{}'", name).into()),
+        }
+    }
+}
+
+impl fmt::Display for ControlCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ControlCommand::AddTrace {
+                function,
+                line,
+                callsite,
+            } => {
+                write!(f, "add-trace {}:{}", function, line)?;
+                match callsite {
+                    Some(CallsiteSelector::Offset(offset)) => write!(f, "@{}", offset),
+                    Some(CallsiteSelector::Manual { start, end }) => {
+                        write!(f, "@{}-{}", start, end)
+                    }
+                    Some(CallsiteSelector::Span { end_line }) => write!(f, "@..{}", end_line),
+                    None => Ok(()),
+                }
+            }
+            ControlCommand::RemoveTrace { function, line } => {
+                write!(f, "remove-trace {}:{}", function, line)
+            }
+            ControlCommand::Enter(function) => write!(f, "enter {}", function),
+            ControlCommand::Pop => f.write_str("pop"),
+            ControlCommand::Filter(filter) => write!(f, "filter {}", filter),
+            ControlCommand::RetFilter(filter) => write!(f, "ret-filter {}", filter),
+            ControlCommand::ProcessFilter(filter) => write!(f, "process-filter {}", filter),
+            ControlCommand::Restart => f.write_str("restart"),
+            ControlCommand::Snapshot => f.write_str("snapshot"),
+            ControlCommand::Export(path) => write!(f, "export {}", path),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            ControlCommand::parse("add-trace ns::foo(int):12").unwrap(),
+            ControlCommand::AddTrace {
+                function: "ns::foo(int)".to_string(),
+                line: 12,
+                callsite: None,
+            }
+        );
+        assert_eq!(
+            ControlCommand::parse("add-trace foo:12@30-42").unwrap(),
+            ControlCommand::AddTrace {
+                function: "foo".to_string(),
+                line: 12,
+                callsite: Some(CallsiteSelector::Manual { start: 30, end: 42 }),
+            }
+        );
+        assert_eq!(
+            ControlCommand::parse("add-trace foo:12@..20").unwrap(),
+            ControlCommand::AddTrace {
+                function: "foo".to_string(),
+                line: 12,
+                callsite: Some(CallsiteSelector::Span { end_line: 20 }),
+            }
+        );
+        assert_eq!(
+            ControlCommand::parse("export /tmp/out.json").unwrap(),
+            ControlCommand::Export("/tmp/out.json".to_string())
+        );
+        assert_eq!(
+            ControlCommand::parse("snapshot").unwrap(),
+            ControlCommand::Snapshot
+        );
+        assert!(ControlCommand::parse("add-trace foo").is_err());
+        assert!(ControlCommand::parse("add-trace foo:0").is_err());
+        assert!(ControlCommand::parse("add-trace foo:12@..0").is_err());
+        assert!(ControlCommand::parse("bogus").is_err());
+        assert!(ControlCommand::parse("process-filter pid=abc").is_err());
+        assert!(ControlCommand::parse("process-filter user=root").is_err());
+    }
+
+    #[test]
+    fn test_display_roundtrip() {
+        for command in &[
+            "add-trace _Z3foov:12@7",
+            "add-trace _Z3foov:12@..20",
+            "remove-trace _Z3foov:12",
+            "enter _Z3barv",
+            "pop",
+            "ret-filter $duration > 1000",
+            "process-filter pid=12,34 comm=nginx uid=0",
+        ] {
+            assert_eq!(
+                &ControlCommand::parse(command).unwrap().to_string(),
+                command
+            );
+        }
+    }
+}
@@ -1,25 +1,53 @@
+use crate::allocations::{self, Allocations};
+use crate::backend::{NoneBackend, TraceBackend};
+use crate::checks::{self, Check};
+use crate::contention::{self, LockWaits};
+use crate::control::{CallsiteSelector, ControlCommand, ControlServer};
+use crate::debuginfod;
+use crate::decompiler;
 use crate::error::Error;
 use crate::events;
-use crate::events::{Event, TraceInfoMode};
+use crate::events::{
+    AfterDecompiling, Event, GroupCount, LineCounters, LineStatistics, Outlier, ReturnValueCount,
+    TraceInfoMode,
+};
+use crate::lbr::{self, BranchCounts};
+use crate::loops::{self, LoopIterations};
+use crate::losses::Losses;
+use crate::maps::{self, MappedObject};
+use crate::memory;
+use crate::messages::Messages;
 use crate::program;
-use crate::program::{FunctionName, Program};
+use crate::program::{CallTarget, FunctionName, Program};
+use crate::recording::{self, Recorder};
+use crate::sampling;
 use crate::search;
 use crate::search::Searcher;
-use crate::trace_structs::{CallInstruction, FrameInfo, InstructionType, TraceMode, TraceStack};
+use crate::share::{ShareMessage, ShareServer};
+use crate::store::History;
+use crate::syscalls::{self, SyscallTimes, Syscalls};
+use crate::templates;
+use crate::trace_structs::{
+    self, ArgumentColumn, CachedResults, CallInstruction, FrameInfo, InstructionType,
+    ProcessFilter, TraceMode, TraceStack,
+};
 use crate::tracer::Tracer;
 use crate::views;
 use crate::views::TraceState;
 use cursive::traits::{Nameable, Resizable};
-use cursive::views::{Dialog, LinearLayout};
+use cursive::utils::markup::StyledString;
+use cursive::views::{Checkbox, Dialog, EditView, LinearLayout, TextView};
 use cursive::{Cursive, CursiveRunnable, CursiveRunner};
 use program::SymbolInfo;
 use std::borrow::Cow;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, VecDeque};
 use std::convert::TryFrom;
+use std::fmt;
 use std::io::BufRead;
+use std::process::Stdio;
+use std::rc::Rc;
 use std::sync::{mpsc, Arc};
 use std::time::{Duration, Instant};
-use zydis::enums::generated::{Mnemonic, Register};
 
 pub struct Controller {
     program: Program,
@@ -27,21 +55,337 @@ pub struct Controller {
     tracer: Tracer,
     trace_stack: Arc<TraceStack>,
     key_handler: KeyHandler,
+    /// Set when the session is shared with read-only watchers
+    share_server: Option<ShareServer>,
+    /// Set when the session accepts commands over a control socket. Only held
+    /// to clean up the socket on exit.
+    _control_server: Option<ControlServer>,
+    /// Set when user actions are being recorded
+    recorder: Option<Recorder>,
+    history: History,
+    /// For sending events from background work
+    tx: mpsc::Sender<Event>,
+    /// Process id of the program waiting to capture a single call, if any
+    single_call_pid: Option<u32>,
+    /// Slow calls being captured, if any
+    outliers: Option<Outliers>,
+    /// Aggregates of traced lines as received each report interval, oldest
+    /// first
+    snapshots: Vec<Snapshot>,
+    /// Set while in time travel mode
+    scrubber: Option<Scrubber>,
+    /// Whether the frequency column shows the number of events in the last
+    /// reporting interval rather than the rate
+    show_count_delta: bool,
+    /// What the latency column shows
+    latency_statistic: LatencyStatistic,
+    /// Whether the latency column also shows the share of it spent off-CPU
+    show_off_cpu: bool,
+    /// Whether only the outermost calls of a recursive current function are
+    /// counted on its line
+    outermost_calls_only: bool,
+    /// Hardware counter shown in the counter column, if any
+    counter_column: CounterColumn,
+    /// Whether the column with each line's share of the function's time is
+    /// shown
+    show_parent_share: bool,
+    /// Trace templates that apply to the program, empty if the backend can't
+    /// run them
+    templates: Vec<templates::Applicable>,
+    /// Line whose stats are shown above the source view, if any
+    pinned: Option<Pinned>,
+    /// Time the trace had been running for and latest results of the current
+    /// function of each other workspace, by index
+    workspace_results: (Duration, HashMap<usize, events::TraceCumulative>),
+    /// Lines pinned with `P`, in the order they were pinned like the results
+    /// of the trace (see `TraceInfo::pinned`)
+    pinned_traces: Vec<PinnedTraceStats>,
+    /// Latest latency histogram of each line, if the histogram pane is shown
+    line_histograms: Option<HashMap<u32, String>>,
+    /// Line whose statistics are shown in a popup, if any
+    stats_line: Option<u32>,
+    /// Lines annotated with the results of the last analysis, e.g. branches
+    /// sampled with `B`, to clear before showing the next one
+    annotated_lines: Vec<u32>,
+    /// Function and line marked as the start of a span to trace, until its
+    /// end line is marked
+    span_start: Option<(FunctionName, u32)>,
+    /// Automatic drill-down started with `G`, if running
+    auto_profile: Option<AutoProfile>,
+    /// Incremented whenever the automatic drill-down starts timing calls, so
+    /// that only the latest `Event::AutoProfileTick` is acted on
+    auto_profile_counter: u64,
+    /// User facing strings in the selected locale
+    messages: Rc<Messages>,
+    /// Outcomes of the checks of the environment run on startup
+    checks: Vec<Check>,
+    /// Calls reached more often than this many times a second are only traced
+    /// once the user confirms, 0 to trace all calls right away
+    overhead_threshold: u64,
+    /// Checks of how often calls are made still running, see
+    /// `check_call_rate`
+    call_rate_checks: usize,
+    /// Data bpftrace dropped since tracing was last restarted
+    losses: Losses,
+    /// Error that ended the session, returned from `Controller::run` once the
+    /// UI has exited
+    fatal_error: Option<Error>,
+    /// Footer before it was marked stale, while the results of a popped back
+    /// to frame are shown from its cache. Restored once fresh results arrive.
+    stale_footer: Option<StyledString>,
+}
+
+/// Optional features of a session, as specified on the command line
+#[derive(Default)]
+pub struct SessionOptions<'a> {
+    /// Path of unix socket to share the session on
+    pub share_socket: Option<&'a str>,
+    /// Path of unix socket to accept control commands on
+    pub control_socket: Option<&'a str>,
+    /// Path to record user actions to
+    pub record_path: Option<&'a str>,
+    /// Path of recording to replay
+    pub replay_path: Option<&'a str>,
+    /// Trace templates defined by the user, offered alongside the built-in
+    /// ones
+    pub templates: Vec<templates::Template>,
+    /// Catalog of user facing strings
+    pub messages: Messages,
+    /// Processes and threads to trace from the start
+    pub process_filter: ProcessFilter,
+    /// See `Controller::overhead_threshold`
+    pub overhead_threshold: u64,
+    /// How often trace data is reported and the display refreshed, the
+    /// default if `None`
+    pub report_interval: Option<Duration>,
+}
+
+/// Calls of a function slower than a threshold, captured by a program run
+/// alongside the trace
+struct Outliers {
+    /// Process id of the program
+    pid: u32,
+    function: FunctionName,
+    threshold: Duration,
+    /// Latest last
+    calls: VecDeque<Outlier>,
+}
+
+/// Aggregates of all traced lines at one point in time
+struct Snapshot {
+    /// `TraceStack` counter of the trace they are from
+    counter: u64,
+    /// Time since the trace started
+    time: Duration,
+    lines: HashMap<u32, events::TraceCumulative>,
+    /// Tail latencies of the lines, only known while line histograms are
+    /// collected
+    tails: HashMap<u32, LineTail>,
+    /// Time the lines spent off-CPU, only known while it is measured
+    off_cpu: HashMap<u32, Duration>,
+    /// Calls of the lines that never returned, see `TraceInfo::aborted`
+    aborted: HashMap<u32, u64>,
+    /// Hardware counters of the lines, only known while they are read
+    counters: HashMap<u32, LineCounters>,
+}
+
+#[derive(Clone, Copy)]
+struct LineTail {
+    p50: Duration,
+    p99: Duration,
+    max: Duration,
+}
+
+/// Latency shown in the latency column, cycled through with `l`. Anything but
+/// the mean needs line histograms, which are collected while it is shown.
+#[derive(Clone, Copy, PartialEq)]
+enum LatencyStatistic {
+    Mean,
+    P50,
+    P99,
+    Max,
+}
+
+impl LatencyStatistic {
+    fn next(self) -> LatencyStatistic {
+        match self {
+            LatencyStatistic::Mean => LatencyStatistic::P50,
+            LatencyStatistic::P50 => LatencyStatistic::P99,
+            LatencyStatistic::P99 => LatencyStatistic::Max,
+            LatencyStatistic::Max => LatencyStatistic::Mean,
+        }
+    }
+
+    fn column_title(self, messages: &Messages) -> &str {
+        messages.get(match self {
+            LatencyStatistic::Mean => "column.latency",
+            LatencyStatistic::P50 => "column.latency_p50",
+            LatencyStatistic::P99 => "column.latency_p99",
+            LatencyStatistic::Max => "column.latency_max",
+        })
+    }
+}
+
+/// Hardware counter shown in the counter column, cycled through with `e`.
+/// Counters are read while the column is shown.
+#[derive(Clone, Copy, PartialEq)]
+enum CounterColumn {
+    Hidden,
+    Ipc,
+    CacheMisses,
+}
+
+impl CounterColumn {
+    fn next(self) -> CounterColumn {
+        match self {
+            CounterColumn::Hidden => CounterColumn::Ipc,
+            CounterColumn::Ipc => CounterColumn::CacheMisses,
+            CounterColumn::CacheMisses => CounterColumn::Hidden,
+        }
+    }
+
+    fn column_title(self, messages: &Messages) -> Option<&str> {
+        match self {
+            CounterColumn::Hidden => None,
+            CounterColumn::Ipc => Some(messages.get("column.ipc")),
+            CounterColumn::CacheMisses => Some(messages.get("column.cache_misses")),
+        }
+    }
+
+    fn value(self, counters: &LineCounters) -> Option<f32> {
+        match self {
+            CounterColumn::Hidden => None,
+            CounterColumn::Ipc => counters.ipc(),
+            CounterColumn::CacheMisses => counters.cache_misses_per_call(),
+        }
+    }
+}
+
+/// Traced line pinned above the source view with `p`, so that lines traced
+/// later can be compared with it side by side
+struct Pinned {
+    function: FunctionName,
+    line: u32,
+    source: String,
+    /// Counter of the trace and cumulative value of the line when last
+    /// received, to compute the latency in each interval
+    last: Option<(u64, events::TraceCumulative)>,
+    latency: TraceState<Duration>,
+    frequency: TraceState<f32>,
+    /// Average latency in each reporting interval, oldest first. `None` for
+    /// intervals without calls.
+    history: Vec<Option<Duration>>,
+}
+
+/// Line pinned with `P`, which stays traced in every frame (see
+/// `TraceStack::toggle_pinned_trace`), with its latest stats
+struct PinnedTraceStats {
+    function: FunctionName,
+    line: u32,
+    source: String,
+    latency: TraceState<Duration>,
+    frequency: TraceState<f32>,
+}
+
+/// State of the automatic drill-down started with `G`, which keeps entering the
+/// most expensive direct call of the current function
+struct AutoProfile {
+    /// How many more functions to enter
+    remaining_depth: u32,
+    /// Description of each function's most expensive call found so far
+    path: Vec<String>,
+    /// Whether the calls of the current function are being timed, which only
+    /// starts once the running trace includes all of them
+    timing: bool,
+}
+
+/// State of time travel mode, in which the source view shows a past snapshot
+/// rather than live aggregates
+struct Scrubber {
+    /// Index into `Controller::snapshots` being shown
+    index: usize,
+    function: FunctionName,
+    /// Footer before entering time travel mode, restored when leaving it
+    footer: StyledString,
 }
 
 impl Controller {
     /// For initial function, display searching UI after this many milliseconds
     const DISPLAY_SEARCHING_UI_MS: u128 = 100;
+    /// How long to sample last branch records for
+    const BRANCH_SAMPLE_SECONDS: u64 = 5;
+    /// How long trace templates run for
+    const TEMPLATE_SECONDS: u64 = 10;
+    /// How long to sample CPU time for
+    const CPU_SAMPLE_SECONDS: u64 = 5;
+    /// How long to trace lock waits for
+    const LOCK_WAIT_SECONDS: u64 = 5;
+    /// How long to trace allocations for
+    const ALLOCATION_SECONDS: u64 = 5;
+    /// How long to trace syscalls for
+    const SYSCALL_SECONDS: u64 = 5;
+    /// How long to count loop iterations for
+    const LOOP_SECONDS: u64 = 5;
+    /// How long to trace the targets of indirect calls for
+    const CALL_TARGET_SECONDS: u64 = 5;
+    /// How long the calls of each function are traced for before drilling
+    /// down into the most expensive one
+    const AUTO_PROFILE_SECONDS: u64 = 5;
+    /// How many functions deep to drill down by default
+    const AUTO_PROFILE_DEPTH: u32 = 5;
+    /// Label and view name of each field of the process filter, in the order
+    /// of `ProcessFilter::fields`
+    const PROCESS_FILTER_FIELDS: [(&'static str, &'static str); 5] = [
+        ("PIDs", "filter_pids"),
+        ("TIDs or thread names", "filter_tids"),
+        ("Process name", "filter_comm"),
+        ("UID", "filter_uid"),
+        ("Cgroup path", "filter_cgroup"),
+    ];
+    /// Number of intervals shown in the sparkline of the pinned line
+    const PINNED_HISTORY_LEN: usize = 40;
+    /// Lines are annotated when at least this many of their branches were
+    /// sampled and at least this fraction of them mispredicted
+    const MIN_BRANCH_SAMPLES: u64 = 20;
+    const HEAVY_MISPREDICT_RATE: f64 = 0.1;
+    /// Snapshots are kept for this long (at one per second), older ones are
+    /// dropped
+    const MAX_SNAPSHOTS: usize = 3600;
+    /// Only the latest this many slow calls are kept
+    const MAX_OUTLIERS: usize = 20;
 
-    pub fn run(program: Program, search: &str) -> Result<(), Error> {
-        Tracer::run_prechecks()?;
+    pub fn run(
+        mut program: Program,
+        backend: Arc<dyn TraceBackend>,
+        search: &str,
+        options: SessionOptions,
+    ) -> Result<(), Error> {
+        backend.run_prechecks()?;
+        let capabilities = backend.capabilities();
+        log::info!("{:?}", capabilities);
+        let checks = checks::run(&*backend, &capabilities);
+        // Traces would fail right away, the program can still be browsed
+        // instead
+        let backend: Arc<dyn TraceBackend> = if checks::any_failed(&checks) {
+            Arc::new(NoneBackend)
+        } else {
+            backend
+        };
+        if !capabilities.uretprobes {
+            program.set_probe_return_instructions();
+        }
+        let share_server = options.share_socket.map(ShareServer::new).transpose()?;
 
         let (tx, rx) = mpsc::channel();
         let mut siv = cursive::default().into_runner();
+        let mut history = History::load(&program.file_path);
         let function = Controller::get_initial_function(
             search,
+            &program,
+            &history,
             &mut siv,
             Searcher::new(tx.clone(), program.symbols_generator()),
+            &options.messages,
             tx.clone(),
             &rx,
         )?;
@@ -49,28 +393,87 @@ impl Controller {
             Some(f) => f,
             None => return Ok(()),
         };
+        history.record_visit(function);
+
+        // Nothing can be done until the initial function is shown, so it is
+        // decompiled in the foreground
+        if let Some(job) = program.decompile_job(function) {
+            siv.add_layer(views::new_dialog(&format!("Decompiling {}...", function)));
+            siv.refresh();
+            let result = job();
+            siv.pop_layer();
+            result?;
+        }
 
-        let mut sview = views::new_source_view();
+        let mut sview = views::new_source_view(&options.messages);
         let mut fview = views::new_footer_view();
-        let frame_info = Controller::setup_function(&program, function, &mut sview, &mut fview)?;
+        let (frame_info, source_found) =
+            Controller::setup_function(&program, function, &mut sview, &mut fview)?;
         siv.add_fullscreen_layer(
             cursive::views::Dialog::around(
                 LinearLayout::vertical()
-                    .child(sview.with_name("source_view").full_screen())
-                    .child(fview.with_name("footer_view")),
+                    .child(views::new_pinned_view().with_name("pinned_view"))
+                    .child(views::new_pinned_traces_view().with_name("pinned_traces_view"))
+                    .child(
+                        LinearLayout::horizontal()
+                            .child(sview.with_name("source_view").full_screen())
+                            .child(views::new_histogram_pane().with_name("histogram_pane")),
+                    )
+                    .child(
+                        LinearLayout::horizontal()
+                            .child(fview.with_name("footer_view").full_width())
+                            .child(views::new_losses_view().with_name("losses_view")),
+                    ),
             )
-            .title(format!("wachy | {}", program.file_path))
+            .title(Controller::title(&program, function))
+            .with_name("main_view")
             .full_screen(),
         );
 
+        let show_capabilities =
+            !capabilities.limitations().is_empty() || checks::any_problems(&checks);
+        let load_warnings = program.get_load_warnings().to_vec();
         let trace_stack = Arc::new(TraceStack::new(
-            program.file_path.clone(),
+            backend,
+            capabilities,
             frame_info,
             tx.clone(),
         ));
+        if !options.process_filter.is_empty() {
+            trace_stack.set_process_filter(options.process_filter)?;
+        }
+        if let Some(interval) = options.report_interval {
+            trace_stack.set_report_interval(interval);
+        }
+        if program.is_go() {
+            trace_stack.set_movable_stacks(true);
+        }
         let tracer = Tracer::new(Arc::clone(&trace_stack), tx.clone())?;
 
-        let searcher = Searcher::new(tx, program.symbols_generator());
+        let control_server = options
+            .control_socket
+            .map(|socket_path| ControlServer::new(socket_path, tx.clone()))
+            .transpose()?;
+        let recorder = options
+            .record_path
+            .map(|path| Recorder::new(path, &program.file_path, function.0))
+            .transpose()?;
+        if let Some(path) = options.replay_path {
+            recording::replay(path, tx.clone())?;
+        }
+        let searcher = Searcher::new(tx.clone(), program.symbols_generator());
+        let templates = Controller::applicable_templates(
+            &program,
+            trace_stack.get_backend(),
+            options.templates,
+        );
+        if trace_stack.get_backend().is_local_bpftrace() {
+            maps::watch(
+                program.file_path.clone(),
+                Arc::clone(&trace_stack),
+                tx.clone(),
+            );
+        }
         Controller::add_callbacks(&mut siv);
         let controller = Controller {
             program,
@@ -78,38 +481,131 @@ impl Controller {
             tracer,
             trace_stack,
             key_handler: KeyHandler::new(),
+            share_server,
+            _control_server: control_server,
+            recorder,
+            history,
+            tx,
+            single_call_pid: None,
+            outliers: None,
+            snapshots: Vec::new(),
+            scrubber: None,
+            show_count_delta: false,
+            latency_statistic: LatencyStatistic::Mean,
+            show_off_cpu: false,
+            outermost_calls_only: false,
+            counter_column: CounterColumn::Hidden,
+            show_parent_share: false,
+            templates,
+            pinned: None,
+            pinned_traces: Vec::new(),
+            workspace_results: (Duration::ZERO, HashMap::new()),
+            line_histograms: None,
+            stats_line: None,
+            annotated_lines: Vec::new(),
+            span_start: None,
+            auto_profile: None,
+            auto_profile_counter: 0,
+            call_rate_checks: 0,
+            messages: Rc::new(options.messages),
+            checks,
+            overhead_threshold: options.overhead_threshold,
+            losses: Losses::default(),
+            fatal_error: None,
+            stale_footer: None,
         };
         siv.set_user_data(controller);
+        if show_capabilities {
+            Controller::show_capabilities(&mut siv);
+        }
+        if !load_warnings.is_empty() {
+            Controller::show_load_warnings(&mut siv, &load_warnings);
+        }
+        if !source_found {
+            Controller::locate_source(&mut siv);
+        }
 
-        siv.refresh();
-        while siv.is_running() {
-            siv.step();
-
-            match rx.try_recv() {
-                Ok(data) => Controller::handle_event(&mut siv, data)?,
-                Err(mpsc::TryRecvError::Disconnected) => {
-                    return Err(format!("Unexpected error: channel disconnected").into())
+        // Events are handed to the UI thread as callbacks rather than polled
+        // for, so it only wakes up for input or when something changed
+        let cb_sink = siv.cb_sink().clone();
+        std::thread::spawn(move || {
+            for event in rx {
+                let callback = move |siv: &mut Cursive| {
+                    if let Err(err) = Controller::handle_event(siv, event) {
+                        siv.user_data::<Controller>()
+                            .expect("Bug: Controller does not exist")
+                            .fatal_error = Some(err);
+                        siv.quit();
+                    }
+                };
+                if cb_sink.send(Box::new(callback)).is_err() {
+                    // UI has exited
+                    return;
                 }
-                Err(mpsc::TryRecvError::Empty) => (),
             }
+            // Nothing would update the UI anymore
+            let _ = cb_sink.send(Box::new(|siv: &mut Cursive| {
+                siv.user_data::<Controller>()
+                    .expect("Bug: Controller does not exist")
+                    .fatal_error = Some("Unexpected error: event channel disconnected".into());
+                siv.quit();
+            }));
+        });
+
+        siv.refresh();
+        siv.run();
+        match siv
+            .user_data::<Controller>()
+            .and_then(|controller| controller.fatal_error.take())
+        {
+            Some(err) => Err(err),
+            None => Ok(()),
         }
-        Ok(())
     }
 
     fn get_initial_function(
         search: &str,
+        program: &Program,
+        history: &History,
         siv: &mut CursiveRunner<CursiveRunnable>,
         searcher: Searcher,
+        messages: &Messages,
         tx: mpsc::Sender<Event>,
         rx: &mpsc::Receiver<Event>,
     ) -> Result<Option<FunctionName>, Error> {
-        let empty_search_results = vec![(
-            "Type to select the top-level function to trace".to_string(),
-            None,
-        )];
-        searcher.setup_search(empty_search_results, Vec::new());
+        // A name shared by overloads or template instantiations must not be
+        // resolved to an arbitrary one of them, so they are offered to pick
+        // from instead
+        let matches = program.get_matches(search);
+        if matches.len() == 1 {
+            return Ok(Some(matches[0]));
+        }
+        let (empty_search_results, search) = if matches.is_empty() {
+            let results = Controller::empty_search_results(
+                program,
+                history,
+                "Type to select the top-level function to trace",
+            );
+            (results, search)
+        } else {
+            let hint = format!(
+                "{} functions match {}, select one or type to search",
+                matches.len(),
+                search
+            );
+            let results: Vec<_> = std::iter::once((hint, None))
+                .chain(
+                    matches
+                        .into_iter()
+                        .filter_map(|f| program.get_symbol_with_signature(f))
+                        .map(|s| (s.to_string(), Some(s))),
+                )
+                .collect();
+            (results, "")
+        };
+        searcher.setup_search(empty_search_results, Vec::new(), true);
         siv.set_user_data(searcher);
-        let search_view = views::new_search_view(
+        let search_view = views::new_multi_search_view(
             "Select the top-level function to trace",
             vec![("Searching...".to_string(), None)],
             move |siv: &mut Cursive, view_name: &str, search: &str, n_results: usize| {
@@ -118,10 +614,16 @@ impl Controller {
                     .expect("Bug: Searcher does not exist");
                 searcher.search(view_name, search, n_results);
             },
-            move |_, symbol: &SymbolInfo| {
+            Controller::pick_overload(move |siv: &mut Cursive, mut symbols: Vec<SymbolInfo>| {
                 // TODO cancel any pending searches
-                tx.send(Event::SelectedFunction(symbol.name)).unwrap();
-            },
+                if symbols.len() == 1 {
+                    siv.pop_layer();
+                    tx.send(Event::SelectedFunction(symbols.remove(0).name))
+                        .unwrap();
+                } else {
+                    tx.send(Event::CompareFunctions(symbols)).unwrap();
+                }
+            }),
         );
         siv.add_layer(search_view);
         // TODO pass name more cleanly
@@ -167,6 +669,9 @@ impl Controller {
                         siv.pop_layer();
                         return Ok(Some(function));
                     }
+                    Event::CompareFunctions(symbols) => {
+                        Controller::show_comparison(siv, program, messages, &symbols);
+                    }
                     _ => {
                         panic!("Bug: Unexpected event")
                     }
@@ -191,8 +696,8 @@ impl Controller {
         Ok(None)
     }
 
-    fn handle_event(siv: &mut CursiveRunner<CursiveRunnable>, event: Event) -> Result<(), Error> {
-        let result = match event {
+    fn handle_event(siv: &mut Cursive, event: Event) -> Result<(), Error> {
+        match event {
             Event::FatalTraceError { error_message } => {
                 siv.quit();
                 Err(error_message.into())
@@ -209,6 +714,7 @@ impl Controller {
                 {
                     return Ok(());
                 }
+                Controller::update_tracer_status(siv);
                 let data_time = data.time.as_secs_f32();
                 let get_latency = |t: &events::TraceCumulative| -> Duration {
                     t.duration / u32::try_from(t.count).unwrap()
@@ -217,36 +723,146 @@ impl Controller {
                     |t: &events::TraceCumulative| -> f32 { t.count as f32 / data_time };
 
                 match data.traces {
-                    TraceInfoMode::Lines(ref lines) => {
-                        siv.call_on_name("source_view", |sview: &mut views::SourceView| {
-                            for (line, info) in lines {
-                                let latency = if info.count != 0 {
-                                    TraceState::Traced(get_latency(info))
-                                } else {
-                                    TraceState::Untraced
-                                };
-                                let frequency = TraceState::Traced(get_frequency(info));
-                                Self::set_line_state(sview, *line, latency, frequency);
+                    TraceInfoMode::Lines(mut lines) => {
+                        let controller = siv
+                            .user_data::<Controller>()
+                            .expect("Bug: Controller does not exist");
+                        let mut off_cpu = data.off_cpu.unwrap_or_default();
+                        controller
+                            .trace_stack
+                            .scale_sampled_lines(&mut lines, &mut off_cpu);
+                        let tails = Controller::line_tails(
+                            data.histograms.as_ref(),
+                            data.statistics.as_ref(),
+                        );
+                        let stats_text = controller.stats_line.map(|line| {
+                            Controller::line_stats_text(
+                                controller.trace_stack.get_current_function(),
+                                line,
+                                lines.get(&line),
+                                data_time,
+                                data.histograms.as_ref().and_then(|h| h.get(&line)),
+                                data.statistics.as_ref().and_then(|s| s.get(&line)),
+                            )
+                        });
+                        if let Some(text) = stats_text {
+                            views::set_text_dialog_view(siv, "line_stats_view", &text);
+                        }
+                        let controller = siv
+                            .user_data::<Controller>()
+                            .expect("Bug: Controller does not exist");
+                        if controller.line_histograms.is_some() {
+                            controller.line_histograms = Some(data.histograms.unwrap_or_default());
+                            Controller::update_histogram_pane(siv);
+                        }
+                        let controller = siv
+                            .user_data::<Controller>()
+                            .expect("Bug: Controller does not exist");
+                        if let Some(share_server) = &controller.share_server {
+                            share_server.send(&ShareMessage::Lines {
+                                time: data.time.as_secs_f64(),
+                                lines: lines
+                                    .iter()
+                                    .map(|(line, info)| {
+                                        (*line, (info.duration.as_nanos() as u64, info.count))
+                                    })
+                                    .collect(),
+                            });
+                        }
+                        // A new trace was started, so earlier snapshots no longer
+                        // apply
+                        if controller
+                            .snapshots
+                            .last()
+                            .map_or(false, |s| s.counter != data.counter || s.time > data.time)
+                        {
+                            Controller::leave_time_travel(siv);
+                            siv.user_data::<Controller>()
+                                .expect("Bug: Controller does not exist")
+                                .snapshots
+                                .clear();
+                        }
+                        let controller = siv
+                            .user_data::<Controller>()
+                            .expect("Bug: Controller does not exist");
+                        if controller.snapshots.len() == Controller::MAX_SNAPSHOTS {
+                            controller.snapshots.remove(0);
+                            if let Some(scrubber) = &mut controller.scrubber {
+                                scrubber.index = scrubber.index.saturating_sub(1);
                             }
+                        }
+                        controller.snapshots.push(Snapshot {
+                            counter: data.counter,
+                            time: data.time,
+                            lines,
+                            tails,
+                            off_cpu,
+                            aborted: data.aborted.unwrap_or_default(),
+                            counters: data.counters.unwrap_or_default(),
                         });
+                        if let Some(footer) = controller.stale_footer.take() {
+                            siv.call_on_name("footer_view", |fview: &mut views::FooterView| {
+                                views::set_footer_view_styled(fview, footer)
+                            });
+                        }
+                        Controller::update_pinned(siv);
+                        Controller::update_pinned_traces(siv, data.counter, data.time, data.pinned);
+                        if let Some(workspaces) = data.workspaces {
+                            siv.user_data::<Controller>()
+                                .expect("Bug: Controller does not exist")
+                                .workspace_results = (data.time, workspaces);
+                        }
+                        if siv
+                            .find_name::<views::TextDialogView>("trace_tree_view")
+                            .is_some()
+                        {
+                            let text = Controller::trace_tree_text(
+                                siv.user_data::<Controller>()
+                                    .expect("Bug: Controller does not exist"),
+                            );
+                            views::set_text_dialog_view(siv, "trace_tree_view", &text);
+                        }
+                        Controller::auto_profile_timing(siv);
+                        let controller = siv
+                            .user_data::<Controller>()
+                            .expect("Bug: Controller does not exist");
+                        if controller.scrubber.is_some() {
+                            // Only the footer needs to be updated, to show the
+                            // new latest time
+                            Controller::show_snapshot(siv);
+                        } else {
+                            Controller::show_live_lines(siv);
+                        }
                     }
                     TraceInfoMode::Histogram(hist) => {
-                        let function = &siv
+                        let controller = siv
                             .user_data::<Controller>()
-                            .expect("Bug: Controller does not exist")
-                            .trace_stack
-                            .get_current_function();
-                        siv.call_on_name("histogram_view", |hview: &mut views::TextDialogView| {
-                            let hist_text = if !hist.is_empty() {
-                                hist
-                            } else {
-                                "<Empty>".to_string()
-                            };
-                            hview.set_content(format!(
-                                "Latency histogram in nanoseconds for {}:\n{}",
-                                function, hist_text
-                            ));
-                        });
+                            .expect("Bug: Controller does not exist");
+                        let line = match controller.trace_stack.get_mode() {
+                            TraceMode::Histogram { line } => line,
+                            _ => return Ok(()),
+                        };
+                        let frame = controller.trace_stack.get_current_frame();
+                        let hist_text = if !hist.is_empty() {
+                            views::formatting::annotate_percentiles(&hist)
+                        } else {
+                            controller.messages.get("histogram.empty").to_string()
+                        };
+                        let text = if line == frame.get_source_line() {
+                            controller.messages.format(
+                                "histogram.result",
+                                &[
+                                    ("function", &frame.get_function()),
+                                    ("histogram", &hist_text),
+                                ],
+                            )
+                        } else {
+                            controller.messages.format(
+                                "histogram.line_result",
+                                &[("line", &line), ("histogram", &hist_text)],
+                            )
+                        };
+                        views::set_text_dialog_view(siv, "histogram_view", &text);
                     }
                     TraceInfoMode::Breakdown {
                         last_frame_trace,
@@ -291,18 +907,113 @@ impl Controller {
                                         * (100 as f64)
                                 ));
                             });
-                        siv.call_on_name("breakdown_view", |bview: &mut views::TextDialogView| {
-                            bview.set_content(text.join("\n"));
-                        });
+                        views::set_text_dialog_view(siv, "breakdown_view", &text.join("\n"));
+                    }
+                    TraceInfoMode::Callers(callers) => {
+                        let controller = siv
+                            .user_data::<Controller>()
+                            .expect("Bug: Controller does not exist");
+                        let line = match controller.trace_stack.get_mode() {
+                            TraceMode::Callers { line, .. } => line,
+                            _ => return Ok(()),
+                        };
+                        let function = controller.trace_stack.get_current_function();
+                        let text = Controller::callers_text(
+                            controller.program.object(function),
+                            function,
+                            line,
+                            &callers,
+                        );
+                        views::set_text_dialog_view(siv, "callers_view", &text);
+                    }
+                    TraceInfoMode::ReturnValues(retvals) => {
+                        let controller = siv
+                            .user_data::<Controller>()
+                            .expect("Bug: Controller does not exist");
+                        let line = match controller.trace_stack.get_mode() {
+                            TraceMode::ReturnValues { line } => line,
+                            _ => return Ok(()),
+                        };
+                        let function = controller.trace_stack.get_current_function();
+                        let text = Controller::return_values_text(function, line, &retvals);
+                        views::set_text_dialog_view(siv, "retvals_view", &text);
+                    }
+                    TraceInfoMode::Arguments(values) => {
+                        let controller = siv
+                            .user_data::<Controller>()
+                            .expect("Bug: Controller does not exist");
+                        let line = match controller.trace_stack.get_mode() {
+                            TraceMode::Arguments { line } => line,
+                            _ => return Ok(()),
+                        };
+                        let function = controller.trace_stack.get_current_function();
+                        let text = Controller::arguments_text(
+                            function,
+                            line,
+                            &controller.trace_stack.get_argument_columns(),
+                            &values,
+                        );
+                        views::set_text_dialog_view(siv, "args_view", &text);
+                    }
+                    TraceInfoMode::Groups(groups) => {
+                        let controller = siv
+                            .user_data::<Controller>()
+                            .expect("Bug: Controller does not exist");
+                        let (line, column) = match (
+                            controller.trace_stack.get_mode(),
+                            controller.trace_stack.get_group_by(),
+                        ) {
+                            (TraceMode::GroupBy { line }, Some(column)) => (line, column),
+                            _ => return Ok(()),
+                        };
+                        let function = controller.trace_stack.get_current_function();
+                        if column == ArgumentColumn::thread() {
+                            let text = Controller::threads_text(function, line, data_time, &groups);
+                            views::set_text_dialog_view(siv, "threads_view", &text);
+                        } else {
+                            let text = Controller::groups_text(function, line, &column, &groups);
+                            views::set_text_dialog_view(siv, "groups_view", &text);
+                        }
                     }
                 }
                 Ok(())
             }
+            Event::TraceLosses(losses) => {
+                let controller = siv
+                    .user_data::<Controller>()
+                    .expect("Bug: Controller does not exist");
+                controller.losses.add(losses);
+                Controller::update_losses(siv);
+                Controller::update_tracer_status(siv);
+                Ok(())
+            }
             Event::TraceCommandModified => {
-                siv.user_data::<Controller>()
-                    .expect("Bug: Controller does not exist")
-                    .tracer
-                    .rerun_tracer();
+                // Watchers may not have the same checkout (or the source may
+                // not come from a file at all), so they are sent the source
+                // that is shown
+                let source_code: Vec<String> = siv
+                    .find_name::<views::SourceView>("source_view")
+                    .expect("Bug: source_view does not exist")
+                    .borrow_items()
+                    .iter()
+                    .map(|item| item.line.clone())
+                    .collect();
+                let controller = siv
+                    .user_data::<Controller>()
+                    .expect("Bug: Controller does not exist");
+                controller.tracer.rerun_tracer();
+                if let Some(share_server) = &controller.share_server {
+                    let frame_info = controller.trace_stack.get_current_frame();
+                    share_server.send(&ShareMessage::Frame {
+                        function: controller.trace_stack.get_current_function().to_string(),
+                        source_file: frame_info.get_source_file().to_string(),
+                        source_code: Some(source_code),
+                        source_line: frame_info.get_source_line(),
+                        marked_lines: frame_info.called_lines(),
+                        inlined_lines: frame_info.inlined_lines(),
+                        max_line: frame_info.max_line(),
+                    });
+                }
                 Ok(())
             }
             Event::SearchResults {
@@ -321,166 +1032,4580 @@ impl Controller {
                 views::update_search_view(siv, &view_name, results);
                 Ok(())
             }
-            Event::SelectedFunction(_) => {
+            Event::SelectedFunction(_) | Event::CompareFunctions(_) => {
                 panic!("Unexpected event");
             }
-        };
-        if result.is_ok() {
-            // We may not _need_ to refresh in all cases, but doing this on in
-            // one place makes things easier with minimal drawbacks.
-            siv.refresh();
-        }
-        result
-    }
-
-    fn setup_function(
-        program: &Program,
-        function: FunctionName,
-        sview: &mut views::SourceView,
-        fview: &mut views::FooterView,
-    ) -> Result<FrameInfo, Error> {
-        let frame_info = Controller::create_frame_info(program, function)?;
-        Controller::setup_source_view(&frame_info, sview, fview)?;
-        Ok(frame_info)
-    }
-
-    fn setup_source_view(
-        frame_info: &FrameInfo,
-        sview: &mut views::SourceView,
-        fview: &mut views::FooterView,
-    ) -> Result<(), Error> {
-        let source_code: Vec<String> = match std::fs::File::open(frame_info.get_source_file()) {
-            Ok(file) => {
-                // FIXME we can cache file contents
-                std::io::BufReader::new(file)
-                    .lines()
-                    .map(|l| l.unwrap())
-                    .collect()
-            }
-            Err(_) => {
-                // TODO show error and confirm user wants to display empty lines
-                // instead
-                let max_line = frame_info.max_line();
-                vec![String::new(); max_line as usize]
-            }
-        };
-        views::set_source_view(
-            sview,
-            source_code,
-            frame_info.get_source_line(),
-            frame_info.called_lines(),
-        );
-        views::set_footer_view(fview, frame_info.get_source_file());
-        Ok(())
-    }
-
-    fn create_frame_info(program: &Program, function: FunctionName) -> Result<FrameInfo, Error> {
-        let location = program.get_location(program.get_address(function)).ok_or_else(|| format!("Failed to get source information corresponding to function {}, please ensure {} has appropriate debugging symbols", function, program.file_path))?;
-        let source_file = location.file.unwrap();
-        let source_line = location.line.unwrap();
-        log::info!(
-            "Function {} is at {}:{}",
-            function,
-            source_file,
-            source_line
-        );
-
-        // TODO
-        let (start_address, code) = program.get_data(function).unwrap();
-        let decoder = program::create_decoder();
-
-        let mut line_to_callsites = HashMap::<u32, Vec<CallInstruction>>::new();
-        let mut unattached_callsites = Vec::<CallInstruction>::new();
-
-        for (instruction, ip) in
-            program::get_instructions_with_mnemonic(&decoder, start_address, code, Mnemonic::CALL)
-        {
-            let relative_ip = u32::try_from(ip - start_address).unwrap();
-            assert!(instruction.operand_count > 0);
-            let operand = &instruction.operands[0];
-            let call_instruction = match operand.reg {
-                Register::NONE => match operand.mem.base {
-                    Register::NONE => {
-                        let call_address = instruction
-                            .calc_absolute_address(ip, &instruction.operands[0])
-                            .unwrap();
-                        match program.get_function_for_address(call_address) {
-                            Some(function) => {
-                                if program.is_dynamic_symbol_address(call_address) {
-                                    CallInstruction::dynamic_symbol(
-                                        relative_ip,
-                                        instruction.length,
-                                        function,
-                                    )
-                                } else {
-                                    CallInstruction::function(
-                                        relative_ip,
-                                        instruction.length,
-                                        function,
-                                    )
-                                }
-                            }
-                            None => CallInstruction::unknown(relative_ip, instruction.length),
-                        }
-                    }
-                    r => CallInstruction::register(
-                        relative_ip,
-                        instruction.length,
-                        r.get_string().unwrap().to_string(),
-                        Some(operand.mem.disp.displacement),
-                    ),
-                },
-                r => {
-                    // TODO convert register string to bpftrace register
-                    CallInstruction::register(
-                        relative_ip,
-                        instruction.length,
-                        r.get_string().unwrap().to_string(),
-                        None,
-                    )
+            Event::BranchSamples { function, result } => {
+                let controller = siv
+                    .user_data::<Controller>()
+                    .expect("Bug: Controller does not exist");
+                let frame = controller.trace_stack.get_current_frame();
+                if frame.get_function() != function {
+                    // Function was changed while sampling
+                    return Ok(());
                 }
-            };
-            let location = program.get_location(ip).unwrap();
-            if location.file.unwrap() == source_file {
-                line_to_callsites
-                    .entry(location.line.unwrap())
-                    .or_default()
-                    .push(call_instruction);
-            } else {
-                // This is an inlined call. We don't know which line it
-                // corresponds to in the source file we are displaying.
-                log::trace!(
-                    "Not displaying function call {} from {}:{} because it is not in current source file {}",
-                    call_instruction,
-                    location.file.unwrap(),
-                    location.line.unwrap(),
-                    source_file
+                let branches = match result {
+                    Ok(branches) => branches,
+                    Err(err) => {
+                        views::set_text_dialog_view(
+                            siv,
+                            "branch_view",
+                            &format!("Failed to sample branches: {}", err),
+                        );
+                        return Ok(());
+                    }
+                };
+                let (lines, elsewhere) = controller.group_by_line(
+                    &frame,
+                    branches,
+                    false,
+                    |total: &mut BranchCounts, counts| {
+                        total.taken += counts.taken;
+                        total.mispredicted += counts.mispredicted;
+                    },
                 );
-                unattached_callsites.push(call_instruction);
+                Controller::annotate_lines(
+                    siv,
+                    lines
+                        .iter()
+                        .filter_map(|(&line, counts)| {
+                            Some((line, Controller::mispredict_annotation(counts)?))
+                        })
+                        .collect(),
+                );
+                let text = Controller::branch_text(function, &lines, &elsewhere);
+                views::set_text_dialog_view(siv, "branch_view", &text);
+                Ok(())
             }
-        }
-
-        log::trace!("{:?}", line_to_callsites);
-        let frame_info = FrameInfo::new(
+            Event::CallRate {
+                function,
+                callsites,
+                result,
+            } => {
+                let controller = siv
+                    .user_data::<Controller>()
+                    .expect("Bug: Controller does not exist");
+                controller.call_rate_checks -= 1;
+                if controller.trace_stack.get_current_function() != function {
+                    // Function was changed while checking
+                    return Ok(());
+                }
+                let rates = match result {
+                    Ok(rates) => rates,
+                    Err(err) => {
+                        log::warn!("Failed to check how often calls are made: {}", err);
+                        HashMap::new()
+                    }
+                };
+                let threshold = controller.overhead_threshold;
+                let (frequent, callsites): (Vec<_>, Vec<_>) = callsites
+                    .into_iter()
+                    .map(|(line, ci)| {
+                        let rate = rates.get(&ci.get_relative_ip()).copied().unwrap_or(0);
+                        (line, ci, rate)
+                    })
+                    .partition(|&(_, _, rate)| rate > threshold);
+                let callsites: Vec<_> = callsites
+                    .into_iter()
+                    .map(|(line, ci, _)| (line, ci))
+                    .collect();
+                if !callsites.is_empty() {
+                    Controller::add_callsites_now(siv, callsites);
+                }
+                if !frequent.is_empty() {
+                    Controller::warn_overhead(siv, frequent);
+                }
+                Controller::auto_profile_timing(siv);
+                Ok(())
+            }
+            Event::DebuginfodSource {
+                function,
+                source_file,
+                found,
+            } => {
+                let frame = siv
+                    .user_data::<Controller>()
+                    .expect("Bug: Controller does not exist")
+                    .trace_stack
+                    .get_current_frame();
+                if frame.get_function() != function || frame.get_source_file() != source_file {
+                    // Function was changed while downloading
+                    return Ok(());
+                }
+                if found {
+                    Controller::refresh_source_view(siv);
+                } else {
+                    Controller::ask_source_path(siv);
+                }
+                Ok(())
+            }
+            Event::CpuSamples { function, result } => {
+                let controller = siv
+                    .user_data::<Controller>()
+                    .expect("Bug: Controller does not exist");
+                let frame = controller.trace_stack.get_current_frame();
+                if frame.get_function() != function {
+                    // Function was changed while sampling
+                    return Ok(());
+                }
+                let samples = match result {
+                    Ok(samples) => samples,
+                    Err(err) => {
+                        views::set_text_dialog_view(
+                            siv,
+                            "cpu_samples_view",
+                            &format!("Failed to sample CPU time: {}", err),
+                        );
+                        return Ok(());
+                    }
+                };
+                let (lines, elsewhere) =
+                    controller
+                        .group_by_line(&frame, samples, false, |total, count| *total += count);
+                let total: u64 = lines.values().sum::<u64>() + elsewhere;
+                Controller::annotate_lines(
+                    siv,
+                    lines
+                        .iter()
+                        .map(|(&line, &count)| {
+                            (
+                                line,
+                                format!(
+                                    "{:.0}% of CPU samples",
+                                    count as f64 / total as f64 * 100.0
+                                ),
+                            )
+                        })
+                        .collect(),
+                );
+                let text = Controller::cpu_samples_text(function, &lines, elsewhere);
+                views::set_text_dialog_view(siv, "cpu_samples_view", &text);
+                Ok(())
+            }
+            Event::LockWaits { function, result } => {
+                let controller = siv
+                    .user_data::<Controller>()
+                    .expect("Bug: Controller does not exist");
+                let frame = controller.trace_stack.get_current_frame();
+                if frame.get_function() != function {
+                    // Function was changed while tracing
+                    return Ok(());
+                }
+                let waits = match result {
+                    Ok(waits) => waits,
+                    Err(err) => {
+                        views::set_text_dialog_view(
+                            siv,
+                            "lock_waits_view",
+                            &format!("Failed to trace lock waits: {}", err),
+                        );
+                        return Ok(());
+                    }
+                };
+                let (lines, elsewhere) =
+                    controller.group_by_line(&frame, waits, true, LockWaits::add);
+                Controller::annotate_lines(
+                    siv,
+                    lines
+                        .iter()
+                        .map(|(&line, waits)| {
+                            (
+                                line,
+                                format!(
+                                    "{} waiting for locks",
+                                    views::formatting::format_latency(waits.duration).trim()
+                                ),
+                            )
+                        })
+                        .collect(),
+                );
+                let text = Controller::lock_waits_text(function, &lines, elsewhere);
+                views::set_text_dialog_view(siv, "lock_waits_view", &text);
+                Ok(())
+            }
+            Event::CallTargets {
+                function,
+                line,
+                result,
+            } => {
+                if siv
+                    .find_name::<views::TextDialogView>("call_targets_view")
+                    .is_none()
+                {
+                    // Closed while tracing
+                    return Ok(());
+                }
+                let controller = siv
+                    .user_data::<Controller>()
+                    .expect("Bug: Controller does not exist");
+                if controller.trace_stack.get_current_function() != function {
+                    // Function was changed while tracing
+                    return Ok(());
+                }
+                let targets = match result {
+                    Ok(targets) if !targets.is_empty() => targets,
+                    Ok(_) => {
+                        let text =
+                            format!("No indirect calls on line {} were made while tracing", line);
+                        views::set_text_dialog_view(siv, "call_targets_view", &text);
+                        return Ok(());
+                    }
+                    Err(err) => {
+                        let text = format!("Failed to trace indirect calls: {}", err);
+                        views::set_text_dialog_view(siv, "call_targets_view", &text);
+                        return Ok(());
+                    }
+                };
+                let total: u64 = targets.iter().map(|(_, count)| count).sum();
+                let mut initial_results = Vec::new();
+                let mut symbols = Vec::new();
+                for (address, count) in targets {
+                    let program = controller.program.object(function);
+                    // Only calls to the start of a function in the program
+                    // can be entered
+                    let callee = program
+                        .get_function_containing(address)
+                        .filter(|&callee| program.get_address(callee) == address);
+                    let symbol = callee
+                        .and_then(|callee| controller.program.get_symbol_with_signature(callee));
+                    let label = match (&symbol, callee) {
+                        (Some(symbol), _) => symbol.to_string(),
+                        (None, Some(callee)) => callee.to_string(),
+                        (None, None) => format!("{:#x} (outside of program)", address),
+                    };
+                    initial_results.push((
+                        format!(
+                            "{:5.1}% {:>8}  {}",
+                            count as f64 / total as f64 * 100.0,
+                            count,
+                            label
+                        ),
+                        symbol.clone(),
+                    ));
+                    symbols.extend(symbol);
+                }
+                let title = format!(
+                    "Targets of indirect calls on line {}, most called first",
+                    line
+                );
+                controller
+                    .searcher
+                    .setup_search(initial_results.clone(), symbols, false);
+                let search_view = views::new_search_view(
+                    &title,
+                    initial_results,
+                    |siv: &mut Cursive, view_name: &str, search: &str, n_results: usize| {
+                        let controller = siv
+                            .user_data::<Controller>()
+                            .expect("Bug: Controller does not exist");
+                        controller.searcher.search(view_name, search, n_results);
+                    },
+                    move |siv: &mut Cursive, symbol: &SymbolInfo| {
+                        Controller::enter_symbol(siv, symbol, Some(line));
+                    },
+                );
+                siv.pop_layer();
+                siv.add_layer(search_view);
+                Ok(())
+            }
+            Event::Syscalls { function, result } => {
+                let controller = siv
+                    .user_data::<Controller>()
+                    .expect("Bug: Controller does not exist");
+                if controller.trace_stack.get_current_function() != function {
+                    // Function was changed while tracing
+                    return Ok(());
+                }
+                let text = match result {
+                    Ok(syscalls) => Controller::syscalls_text(function, &syscalls),
+                    Err(err) => format!("Failed to trace syscalls: {}", err),
+                };
+                views::set_text_dialog_view(siv, "syscalls_view", &text);
+                Ok(())
+            }
+            Event::LoopIterations { function, result } => {
+                let controller = siv
+                    .user_data::<Controller>()
+                    .expect("Bug: Controller does not exist");
+                let frame = controller.trace_stack.get_current_frame();
+                if frame.get_function() != function {
+                    // Function was changed while tracing
+                    return Ok(());
+                }
+                let iterations = match result {
+                    Ok(iterations) => iterations,
+                    Err(err) => {
+                        views::set_text_dialog_view(
+                            siv,
+                            "loops_view",
+                            &format!("Failed to count loop iterations: {}", err),
+                        );
+                        return Ok(());
+                    }
+                };
+                let program = controller.program.object(function);
+                let address = program.get_address(function);
+                // Line of the head of each loop, `None` if inlined from
+                // another file
+                let heads: Vec<(u32, Option<u32>)> = program
+                    .get_loops(function)
+                    .into_iter()
+                    .map(|l| {
+                        let line = program
+                            .get_location(address + u64::from(l.head))
+                            .filter(|l| l.file.unwrap() == frame.get_source_file())
+                            .and_then(|l| l.line);
+                        (l.head, line)
+                    })
+                    .collect();
+
+                if iterations.function_calls > 0 {
+                    let mut lines = BTreeMap::<u32, Vec<String>>::new();
+                    for &(head, line) in &heads {
+                        if let Some(line) = line {
+                            lines
+                                .entry(line)
+                                .or_default()
+                                .push(format!("{:.1}", iterations.per_call(head)));
+                        }
+                    }
+                    Controller::annotate_lines(
+                        siv,
+                        lines
+                            .into_iter()
+                            .map(|(line, per_call)| {
+                                (line, format!("{} iterations per call", per_call.join(", ")))
+                            })
+                            .collect(),
+                    );
+                }
+                let text = Controller::loops_text(function, &heads, &iterations);
+                views::set_text_dialog_view(siv, "loops_view", &text);
+                Ok(())
+            }
+            Event::Allocations { function, result } => {
+                let controller = siv
+                    .user_data::<Controller>()
+                    .expect("Bug: Controller does not exist");
+                let frame = controller.trace_stack.get_current_frame();
+                if frame.get_function() != function {
+                    // Function was changed while tracing
+                    return Ok(());
+                }
+                let allocations = match result {
+                    Ok(allocations) => allocations,
+                    Err(err) => {
+                        views::set_text_dialog_view(
+                            siv,
+                            "allocations_view",
+                            &format!("Failed to trace allocations: {}", err),
+                        );
+                        return Ok(());
+                    }
+                };
+                let (lines, elsewhere) =
+                    controller.group_by_line(&frame, allocations, true, Allocations::add);
+                Controller::annotate_lines(
+                    siv,
+                    lines
+                        .iter()
+                        .filter(|(_, allocations)| allocations.count > 0)
+                        .map(|(&line, allocations)| {
+                            (
+                                line,
+                                format!(
+                                    "{} allocations, {}",
+                                    allocations.count,
+                                    memory::format_bytes(allocations.bytes as usize)
+                                ),
+                            )
+                        })
+                        .collect(),
+                );
+                let text = Controller::allocations_text(function, &lines, elsewhere);
+                views::set_text_dialog_view(siv, "allocations_view", &text);
+                Ok(())
+            }
+            Event::SingleCall {
+                pid,
+                function,
+                result,
+            } => {
+                let controller = siv
+                    .user_data::<Controller>()
+                    .expect("Bug: Controller does not exist");
+                if controller.single_call_pid != Some(pid) {
+                    // Cancelled while waiting
+                    return Ok(());
+                }
+                controller.single_call_pid = None;
+                let text = match result {
+                    Ok(output) => format!("Call of {}\n\n{}", function, output),
+                    Err(err) => format!("Failed to capture a call of {}: {}", function, err),
+                };
+                views::set_text_dialog_view(siv, "single_call_view", &text);
+                Ok(())
+            }
+            Event::Outlier { pid, result } => {
+                let controller = siv
+                    .user_data::<Controller>()
+                    .expect("Bug: Controller does not exist");
+                let outliers = match &mut controller.outliers {
+                    Some(outliers) if outliers.pid == pid => outliers,
+                    // Cancelled in the meantime
+                    _ => return Ok(()),
+                };
+                let text = match result {
+                    Ok(outlier) => {
+                        if outliers.calls.len() == Controller::MAX_OUTLIERS {
+                            outliers.calls.pop_front();
+                        }
+                        outliers.calls.push_back(outlier);
+                        Controller::outliers_text(outliers)
+                    }
+                    Err(err) => {
+                        let text = format!(
+                            "Failed to capture slow calls of {}: {}",
+                            outliers.function, err
+                        );
+                        controller.outliers = None;
+                        text
+                    }
+                };
+                views::set_text_dialog_view(siv, "outliers_view", &text);
+                Ok(())
+            }
+            Event::Template { name, result } => {
+                let text = match result {
+                    Ok(output) => format!("{}\n\n{}", name, output),
+                    Err(err) => format!("Failed to run template {}: {}", name, err),
+                };
+                views::set_text_dialog_view(siv, "template_view", &text);
+                Ok(())
+            }
+            Event::AutoProfileTick { function, counter } => {
+                Controller::auto_profile_tick(siv, function, counter);
+                Ok(())
+            }
+            Event::Decompiled {
+                function,
+                then,
+                result,
+            } => {
+                if siv
+                    .find_name::<views::TextDialogView>("decompile_view")
+                    .is_none()
+                {
+                    // Closed while decompiling
+                    return Ok(());
+                }
+                if let Err(err) = result {
+                    let text = format!("Failed to decompile {}: {}", function, err);
+                    views::set_text_dialog_view(siv, "decompile_view", &text);
+                    return Ok(());
+                }
+                siv.pop_layer();
+                match then {
+                    AfterDecompiling::Enter(entered_from) => {
+                        if let Err(err) = Controller::push_function(siv, function, entered_from) {
+                            let text = Controller::messages(siv).format(
+                                "function.setup_error",
+                                &[("function", &function), ("error", &err)],
+                            );
+                            siv.add_layer(views::new_dialog(&text));
+                        }
+                    }
+                    AfterDecompiling::AddWorkspace(symbol) => {
+                        Controller::add_workspace(siv, &symbol)
+                    }
+                }
+                Ok(())
+            }
+            Event::ObjectsMapped(paths) => {
+                siv.add_layer(views::new_dialog(&format!(
+                    "Loaded at runtime:\n{}\n\nPress L to trace their functions.",
+                    paths.join("\n")
+                )));
+                Ok(())
+            }
+            Event::ControlCommand { command, reply_tx } => {
+                let reply = match Controller::handle_control_command(siv, &command) {
+                    Ok(reply) => reply,
+                    Err(err) => format!("error: {}", err),
+                };
+                // Client may have disconnected in the meantime
+                let _ = reply_tx.send(reply);
+                Ok(())
+            }
+        }
+    }
+
+    fn handle_control_command(siv: &mut Cursive, command: &str) -> Result<String, Error> {
+        let command = ControlCommand::parse(command)?;
+        let trace_stack = Arc::clone(
+            &siv.user_data::<Controller>()
+                .expect("Bug: Controller does not exist")
+                .trace_stack,
+        );
+        let check_function = |function: &str| -> Result<(), Error> {
+            let current_function = trace_stack.get_current_function();
+            if function != current_function.0 && function != current_function.to_string() {
+                return Err(format!(
+                    "{} is not the current function ({})",
+                    function, current_function
+                )
+                .into());
+            }
+            Ok(())
+        };
+        match command {
+            ControlCommand::AddTrace {
+                function,
+                line,
+                callsite,
+            } => {
+                check_function(&function)?;
+                let ci = match callsite {
+                    Some(CallsiteSelector::Manual { start, end }) => {
+                        if end <= start {
+                            return Err(format!("Invalid range {}-{}", start, end).into());
+                        }
+                        CallInstruction::manual(start, end - start)
+                    }
+                    Some(CallsiteSelector::Span { end_line }) => {
+                        let controller = siv
+                            .user_data::<Controller>()
+                            .expect("Bug: Controller does not exist");
+                        Controller::get_line_span(controller, line, end_line)?
+                    }
+                    Some(CallsiteSelector::Offset(offset)) => trace_stack
+                        .get_callsites(line)
+                        .into_iter()
+                        .chain(trace_stack.get_unattached_callsites())
+                        .find(|ci| ci.get_relative_ip() == offset)
+                        .ok_or_else(|| format!("No call found at offset {}", offset))?,
+                    None => {
+                        let mut callsites = trace_stack.get_callsites(line);
+                        match callsites.len() {
+                            0 => return Err(format!("No calls found on line {}", line).into()),
+                            1 => callsites.remove(0),
+                            n => {
+                                return Err(format!(
+                                    "{} calls found on line {}, specify one with @offset",
+                                    n, line
+                                )
+                                .into())
+                            }
+                        }
+                    }
+                };
+                Controller::remove_callsite(siv, line);
+                Controller::try_add_callsite(siv, line, ci)?;
+                Ok("ok".to_string())
+            }
+            ControlCommand::RemoveTrace { function, line } => {
+                check_function(&function)?;
+                if Controller::remove_callsite(siv, line) {
+                    Ok("ok".to_string())
+                } else {
+                    Err(format!("Line {} is not traced", line).into())
+                }
+            }
+            ControlCommand::Enter(function) => {
+                let controller = siv
+                    .user_data::<Controller>()
+                    .expect("Bug: Controller does not exist");
+                // Functions in shared libraries are qualified with their path
+                if let Some((path, _)) = function.split_once(program::OBJECT_SEPARATOR) {
+                    controller.program.load_library(path)?;
+                }
+                let symbol = controller
+                    .program
+                    .find_symbol(&function)
+                    .cloned()
+                    .ok_or_else(|| format!("Function {} not found", function))?;
+                let symbol = if Controller::is_dynamic(&controller.program, &symbol) {
+                    Controller::load_dynamic_symbol(&mut controller.program, symbol.name)?
+                } else {
+                    symbol
+                };
+                Controller::push_function(siv, symbol.name, None)?;
+                let controller = siv
+                    .user_data::<Controller>()
+                    .expect("Bug: Controller does not exist");
+                if controller.trace_stack.get_current_function() != symbol.name {
+                    return Ok("decompiling, entered once done".to_string());
+                }
+                Ok("ok".to_string())
+            }
+            ControlCommand::Pop => {
+                if Controller::pop_function(siv) {
+                    Ok("ok".to_string())
+                } else {
+                    Err("Cannot pop the last function".into())
+                }
+            }
+            ControlCommand::Filter(filter) => {
+                Controller::set_filter(siv, filter, false)?;
+                Ok("ok".to_string())
+            }
+            ControlCommand::RetFilter(filter) => {
+                Controller::set_filter(siv, filter, true)?;
+                Ok("ok".to_string())
+            }
+            ControlCommand::ProcessFilter(filter) => {
+                Controller::set_process_filter(siv, filter)?;
+                Ok("ok".to_string())
+            }
+            ControlCommand::Restart => {
+                Controller::restart(siv);
+                Ok("ok".to_string())
+            }
+            ControlCommand::Snapshot => {
+                let mut sview = siv
+                    .find_name::<views::SourceView>("source_view")
+                    .expect("Bug: source_view does not exist");
+                Ok(Controller::snapshot(&trace_stack, &mut sview).to_string())
+            }
+            ControlCommand::Export(path) => {
+                let mut sview = siv
+                    .find_name::<views::SourceView>("source_view")
+                    .expect("Bug: source_view does not exist");
+                let snapshot = Controller::snapshot(&trace_stack, &mut sview);
+                std::fs::write(&path, snapshot.to_string() + "\n")
+                    .map_err(|err| format!("Failed to write {}: {}", path, err))?;
+                Ok("ok".to_string())
+            }
+        }
+    }
+
+    fn record(siv: &mut Cursive, command: ControlCommand) {
+        let controller = siv
+            .user_data::<Controller>()
+            .expect("Bug: Controller does not exist");
+        if let Some(recorder) = &mut controller.recorder {
+            recorder.record(&command);
+        }
+    }
+
+    /// Show what the kernel supports and which features are limited because
+    /// of it
+    fn show_capabilities(siv: &mut Cursive) {
+        let controller = siv
+            .user_data::<Controller>()
+            .expect("Bug: Controller does not exist");
+        let mut text = format!(
+            "Tracing capabilities\n\n{}",
+            controller.trace_stack.get_capabilities()
+        );
+        if !controller.checks.is_empty() {
+            text += "\nChecks\n\n";
+            for check in &controller.checks {
+                text += &format!("{}\n", check);
+            }
+            if checks::any_failed(&controller.checks) {
+                text += "\nTracing is disabled until the failed checks are fixed, functions can still be browsed.\n";
+            }
+        }
+        let messages = Controller::messages(siv);
+        siv.add_layer(views::new_text_dialog_view(
+            &messages,
+            &text,
+            "capabilities_view",
+            |siv| {
+                siv.pop_layer();
+            },
+        ));
+    }
+
+    /// Show the program the tracer currently runs, with options to save it to
+    /// a file to run it manually and to edit it
+    fn show_script(siv: &mut Cursive) {
+        let trace_stack = &siv
+            .user_data::<Controller>()
+            .expect("Bug: Controller does not exist")
+            .trace_stack;
+        let backend = trace_stack.get_backend();
+        let (script, _) = trace_stack.get_bpftrace_expr();
+        if script.is_empty() {
+            let text = format!("The {} backend doesn't run programs", backend.name());
+            siv.add_layer(views::new_dialog(&text));
+            return;
+        }
+        let is_edited = trace_stack.is_script_edited();
+        let header = if is_edited {
+            format!(
+                "Edited program run with {} (the generated one is run again once the trace changes):",
+                backend.name()
+            )
+        } else if backend.is_native() && trace_stack.get_native_plan().is_ok() {
+            format!(
+                "The {} backend traces this in-process, with probes equivalent to this bpftrace program:",
+                backend.name()
+            )
+        } else {
+            format!("Program run with {}:", backend.name())
+        };
+        let default_path = if backend.name() == "dtrace" {
+            "wachy.d"
+        } else {
+            "wachy.bt"
+        };
+        let text = format!("{}\n\n{}", header, script);
+        let messages = Controller::messages(siv);
+        let edit_script = script.clone();
+        let mut dialog = views::new_text_dialog_view(&messages, &text, "script_view", |siv| {
+            siv.pop_layer();
+        })
+        .button(messages.get("button.edit"), move |siv| {
+            let messages = Controller::messages(siv);
+            siv.add_layer(views::new_text_area_view(
+                &messages,
+                "Edit the trace program",
+                "script_edit_view",
+                &edit_script,
+                messages.get("button.run"),
+                |siv, script| {
+                    let controller = siv
+                        .user_data::<Controller>()
+                        .expect("Bug: Controller does not exist");
+                    match controller
+                        .trace_stack
+                        .set_edited_script(Some(script.to_string()))
+                    {
+                        Ok(()) => {
+                            // Close the editor and the outdated program
+                            siv.pop_layer();
+                            siv.pop_layer();
+                        }
+                        Err(err) => {
+                            siv.add_layer(views::new_dialog(&format!(
+                                "Invalid program\n\n{}",
+                                err
+                            )));
+                        }
+                    }
+                },
+            ));
+        })
+        .button(messages.get("button.save"), move |siv| {
+            let script = script.clone();
+            siv.add_layer(views::new_edit_view(
+                "Save the program to",
+                "script_path_view",
+                Some(default_path),
+                move |siv, path| {
+                    siv.pop_layer();
+                    let text = match std::fs::write(path, format!("{}\n", script)) {
+                        Ok(()) => format!("Saved the program to {}", path),
+                        Err(err) => format!("Failed to save {}: {}", path, err),
+                    };
+                    siv.add_layer(views::new_dialog(&text));
+                },
+            ));
+        });
+        if is_edited {
+            dialog.add_button(messages.get("button.generated"), |siv| {
+                siv.pop_layer();
+                let controller = siv
+                    .user_data::<Controller>()
+                    .expect("Bug: Controller does not exist");
+                if let Err(err) = controller.trace_stack.set_edited_script(None) {
+                    log::warn!("Failed to reset the trace program: {}", err);
+                }
+            });
+        }
+        siv.add_layer(dialog);
+    }
+
+    /// Show how much memory is used by the largest data, with an option to
+    /// drop what can be recomputed
+    fn show_memory_usage(siv: &mut Cursive) {
+        let controller = siv
+            .user_data::<Controller>()
+            .expect("Bug: Controller does not exist");
+        let mut entries = controller.program.memory_usage();
+        entries.push((
+            "Callsites of stack frames",
+            controller.trace_stack.frames_memory_usage(),
+        ));
+        entries.push((
+            "Snapshots (time travel)",
+            controller
+                .snapshots
+                .iter()
+                .map(|s| {
+                    std::mem::size_of::<Snapshot>()
+                        + s.lines.capacity() * std::mem::size_of::<(u32, events::TraceCumulative)>()
+                })
+                .sum(),
+        ));
+        let text = format!("Memory usage\n\n{}", memory::Usage { entries });
+        let messages = Controller::messages(siv);
+        siv.add_layer(
+            views::new_text_dialog_view(&messages, &text, "memory_view", |siv| {
+                siv.pop_layer();
+            })
+            .button(messages.get("button.drop_caches"), |siv| {
+                siv.pop_layer();
+                let controller = siv
+                    .user_data::<Controller>()
+                    .expect("Bug: Controller does not exist");
+                controller.trace_stack.drop_frame_caches();
+                memory::clear_demangle_cache();
+                Controller::show_memory_usage(siv);
+            }),
+        );
+    }
+
+    /// Show whether the trace is running and how it's doing, see
+    /// `TracerStatus`
+    fn show_tracer_status(siv: &mut Cursive) {
+        let text = Controller::tracer_status_text(
+            siv.user_data::<Controller>()
+                .expect("Bug: Controller does not exist"),
+        );
+        let messages = Controller::messages(siv);
+        siv.add_layer(
+            views::new_text_dialog_view(&messages, &text, "tracer_view", |siv| {
+                siv.pop_layer();
+            })
+            .button(
+                messages.get("button.refresh"),
+                Controller::update_tracer_status,
+            ),
+        );
+    }
+
+    /// Refresh the tracer status popup, if open
+    fn update_tracer_status(siv: &mut Cursive) {
+        if siv
+            .find_name::<views::TextDialogView>("tracer_view")
+            .is_none()
+        {
+            return;
+        }
+        let text = Controller::tracer_status_text(
+            siv.user_data::<Controller>()
+                .expect("Bug: Controller does not exist"),
+        );
+        views::set_text_dialog_view(siv, "tracer_view", &text);
+    }
+
+    fn tracer_status_text(controller: &Controller) -> String {
+        let status = controller.tracer.status();
+        let backend = controller.trace_stack.get_backend();
+        let uptime = status.uptime().as_secs_f64();
+        let state = match (&status.exit_status, status.started, status.pid) {
+            (Some(exit_status), _, _) => format!("exited ({})", exit_status),
+            (None, None, _) => "not started".to_string(),
+            (None, Some(_), Some(pid)) => format!("running, pid {}", pid),
+            (None, Some(_), None) => "running in-process".to_string(),
+        };
+        let per_second = |count: u64| {
+            if uptime > 0.0 {
+                format!("{} ({:.1}/s)", count, count as f64 / uptime)
+            } else {
+                count.to_string()
+            }
+        };
+        let rows = [
+            ("Backend", backend.name().to_string()),
+            ("State", state),
+            (
+                "CPU usage",
+                status
+                    .cpu_usage()
+                    .map_or("-".to_string(), |usage| format!("{:.1}%", usage * 100.0)),
+            ),
+            (
+                "Probes attached",
+                status
+                    .probes
+                    .map_or("-".to_string(), |probes| probes.to_string()),
+            ),
+            ("Output records", per_second(status.records)),
+            ("Reports", per_second(status.reports)),
+            (
+                "Last restart",
+                format!("{} ago", views::formatting::format_latency(status.uptime())),
+            ),
+            ("Restarts", status.restarts.to_string()),
+        ];
+        let mut text = vec!["Tracer status".to_string(), String::new()];
+        text.extend(
+            rows.iter()
+                .map(|(label, value)| format!("{:<16} {}", label, value)),
+        );
+        text.push(String::new());
+        if status.warnings.is_empty() {
+            text.push("No output on stderr".to_string());
+        } else {
+            text.push(format!("Last lines of {} stderr:", backend.name()));
+            text.extend(status.warnings.iter().cloned());
+        }
+        text.join("\n")
+    }
+
+    /// Show which parts of the program couldn't be loaded
+    fn show_load_warnings(siv: &mut Cursive, warnings: &[String]) {
+        let text = format!(
+            "Some of the program could not be loaded, continuing without it\n\n{}",
+            warnings.join("\n")
+        );
+        let messages = Controller::messages(siv);
+        siv.add_layer(views::new_text_dialog_view(
+            &messages,
+            &text,
+            "load_warnings_view",
+            |siv| {
+                siv.pop_layer();
+            },
+        ));
+    }
+
+    /// Templates out of the built-in ones and `user_templates` that apply to
+    /// `program`
+    fn applicable_templates(
+        program: &Program,
+        backend: &dyn TraceBackend,
+        user_templates: Vec<templates::Template>,
+    ) -> Vec<templates::Applicable> {
+        if !backend.is_local_bpftrace() {
+            return Vec::new();
+        }
+        let libraries = templates::linked_libraries(&program.file_path).unwrap_or_else(|err| {
+            log::warn!("{}", err);
+            Vec::new()
+        });
+        let mut all_templates = templates::builtin_templates();
+        all_templates.extend(user_templates);
+        templates::applicable(program, &libraries, all_templates)
+    }
+
+    /// Run the program of a trace template in the background, showing its
+    /// histogram once it exits
+    fn run_template(siv: &mut Cursive, applicable: &templates::Applicable) {
+        let controller = siv
+            .user_data::<Controller>()
+            .expect("Bug: Controller does not exist");
+        let name = applicable.template.name.clone();
+        let backend = controller.trace_stack.get_backend();
+        let backend_name = backend.name();
+        let child = backend
+            .run_command(&applicable.program(Controller::TEMPLATE_SECONDS))
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn();
+        let child = match child {
+            Ok(child) => child,
+            Err(err) => {
+                siv.add_layer(views::new_dialog(&format!(
+                    "{} failed to start: {}",
+                    backend_name, err
+                )));
+                return;
+            }
+        };
+        let tx = controller.tx.clone();
+        std::thread::spawn(move || {
+            let result = match child.wait_with_output() {
+                Ok(output) if output.status.success() => Ok(templates::parse_output(
+                    &String::from_utf8_lossy(&output.stdout),
+                )),
+                Ok(output) => Err(format!(
+                    "{} failed, status: {:?}, stderr:\n{}",
+                    backend_name,
+                    output.status,
+                    String::from_utf8_lossy(&output.stderr)
+                )
+                .into()),
+                Err(err) => Err(format!("Failed to wait for {}: {}", backend_name, err).into()),
+            };
+            // Controller may have exited in the meantime
+            let _ = tx.send(Event::Template { name, result });
+        });
+        let messages = Controller::messages(siv);
+        siv.add_layer(views::new_text_dialog_view(
+            &messages,
+            &format!(
+                "Running {} for {} seconds...",
+                applicable.template.name,
+                Controller::TEMPLATE_SECONDS
+            ),
+            "template_view",
+            |siv| {
+                siv.pop_layer();
+            },
+        ));
+    }
+
+    /// Start tracing `ci` on `line`, showing an error if it can't be traced.
+    /// How often it is called is checked first, see `check_call_rate`.
+    fn add_callsite(siv: &mut Cursive, line: u32, ci: CallInstruction) {
+        let callsites = vec![(line, ci)];
+        if !Controller::check_call_rate(siv, &callsites) {
+            Controller::add_callsites_now(siv, callsites);
+        }
+    }
+
+    /// Trace the call on every line of the current function that has one, or
+    /// stop tracing them if they all already are. Lines with several calls
+    /// trace the first one. How often each is called is checked first, like
+    /// with `add_callsite`.
+    fn toggle_all_callsites(siv: &mut Cursive) {
+        let controller = siv
+            .user_data::<Controller>()
+            .expect("Bug: Controller does not exist");
+        let frame = controller.trace_stack.get_current_frame();
+        let mut lines = frame.called_lines();
+        lines.sort_unstable();
+        if lines.is_empty() {
+            siv.add_layer(views::new_dialog(&format!(
+                "No calls found in {}",
+                frame.get_function()
+            )));
+            return;
+        }
+        if lines.iter().all(|&line| frame.is_traced(line)) {
+            for line in lines {
+                Controller::remove_callsite(siv, line);
+            }
+        } else {
+            Controller::trace_all_callsites(siv);
+        }
+    }
+
+    /// Trace the first call on every line of the current function that has
+    /// one and isn't traced yet, see `toggle_all_callsites`. How often they
+    /// are called is checked for all of them at once, and they are all
+    /// traced at once.
+    fn trace_all_callsites(siv: &mut Cursive) {
+        let controller = siv
+            .user_data::<Controller>()
+            .expect("Bug: Controller does not exist");
+        let frame = controller.trace_stack.get_current_frame();
+        let mut lines = frame.called_lines();
+        lines.sort_unstable();
+        let callsites: Vec<(u32, CallInstruction)> = lines
+            .into_iter()
+            .filter(|&line| !frame.is_traced(line))
+            .filter_map(|line| {
+                let ci = controller
+                    .trace_stack
+                    .get_callsites(line)
+                    .into_iter()
+                    .next()?;
+                Some((line, ci))
+            })
+            .collect();
+        if callsites.is_empty() {
+            return;
+        }
+        if !Controller::check_call_rate(siv, &callsites) {
+            Controller::add_callsites_now(siv, callsites);
+        }
+    }
+
+    /// Start drilling down from the current function, `depth` functions deep
+    fn start_auto_profile(siv: &mut Cursive, depth: u32) {
+        let messages = Controller::messages(siv);
+        siv.user_data::<Controller>()
+            .expect("Bug: Controller does not exist")
+            .auto_profile = Some(AutoProfile {
+            remaining_depth: depth,
+            path: Vec::new(),
+            timing: false,
+        });
+        siv.add_layer(views::new_text_dialog_view(
+            &messages,
+            "",
+            "auto_profile_view",
+            |siv| {
+                // Closing the dialog stops drilling down
+                siv.user_data::<Controller>()
+                    .expect("Bug: Controller does not exist")
+                    .auto_profile = None;
+                siv.pop_layer();
+            },
+        ));
+        Controller::auto_profile_step(siv);
+    }
+
+    /// Trace every call of the current function, and drill down into the most
+    /// expensive one once they have been traced for `AUTO_PROFILE_SECONDS`
+    /// (see `auto_profile_timing` and `auto_profile_tick`)
+    fn auto_profile_step(siv: &mut Cursive) {
+        Controller::trace_all_callsites(siv);
+        let controller = siv
+            .user_data::<Controller>()
+            .expect("Bug: Controller does not exist");
+        let function = controller.trace_stack.get_current_function();
+        let path = match &mut controller.auto_profile {
+            Some(auto_profile) => {
+                auto_profile.timing = false;
+                auto_profile.path.clone()
+            }
+            None => return,
+        };
+        let status = format!("Waiting for the calls of {} to be traced...", function);
+        Controller::update_auto_profile(siv, &path, &status);
+    }
+
+    /// Start timing the calls of the current function for the automatic
+    /// drill-down, once their call rates have been checked and the latest
+    /// results are from a trace that includes every one of them. Called
+    /// whenever either may have changed.
+    fn auto_profile_timing(siv: &mut Cursive) {
+        let controller = siv
+            .user_data::<Controller>()
+            .expect("Bug: Controller does not exist");
+        let waiting = controller
+            .auto_profile
+            .as_ref()
+            .map_or(false, |auto_profile| !auto_profile.timing);
+        if !waiting || controller.call_rate_checks > 0 {
+            return;
+        }
+        let frame = controller.trace_stack.get_current_frame();
+        let trace_stack = &controller.trace_stack;
+        let traced = controller
+            .snapshots
+            .last()
+            .filter(|s| trace_stack.is_counter_current(s.counter))
+            .map_or(false, |snapshot| {
+                frame
+                    .traced_lines()
+                    .iter()
+                    .all(|line| snapshot.lines.contains_key(line))
+            });
+        if !traced {
+            return;
+        }
+        let function = frame.get_function();
+        controller.auto_profile_counter += 1;
+        let counter = controller.auto_profile_counter;
+        let tx = controller.tx.clone();
+        std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_secs(Controller::AUTO_PROFILE_SECONDS));
+            // Controller may have exited in the meantime
+            let _ = tx.send(Event::AutoProfileTick { function, counter });
+        });
+        let auto_profile = controller.auto_profile.as_mut().unwrap();
+        auto_profile.timing = true;
+        let path = auto_profile.path.clone();
+        let status = format!(
+            "Timing the calls of {} for {} seconds...",
+            function,
+            Controller::AUTO_PROFILE_SECONDS
+        );
+        Controller::update_auto_profile(siv, &path, &status);
+    }
+
+    /// Enter the most expensive direct call of `function`, which has been
+    /// traced for a while, and carry on drilling down from there. Stops once
+    /// deep enough or there is no such call. `counter` is the
+    /// `auto_profile_counter` the timing started with.
+    fn auto_profile_tick(siv: &mut Cursive, function: FunctionName, counter: u64) {
+        let controller = siv
+            .user_data::<Controller>()
+            .expect("Bug: Controller does not exist");
+        // Put back below if drilling down carries on
+        let mut auto_profile = match controller.auto_profile.take() {
+            Some(auto_profile)
+                if auto_profile.timing && controller.auto_profile_counter == counter =>
+            {
+                auto_profile
+            }
+            // Left over from a drill-down that was closed or restarted
+            auto_profile => {
+                controller.auto_profile = auto_profile;
+                return;
+            }
+        };
+        let frame = controller.trace_stack.get_current_frame();
+        if frame.get_function() != function {
+            Controller::update_auto_profile(
+                siv,
+                &auto_profile.path,
+                "Stopped, another function was entered",
+            );
+            return;
+        }
+        let trace_stack = &controller.trace_stack;
+        let latest = controller
+            .snapshots
+            .last()
+            .filter(|s| trace_stack.is_counter_current(s.counter));
+        let hottest = latest.and_then(|snapshot| {
+            snapshot
+                .lines
+                .iter()
+                .filter_map(
+                    |(&line, info)| match frame.get_traced_callsite(line)?.instruction {
+                        InstructionType::Function(callee) if info.count != 0 => {
+                            Some((line, callee, info.duration))
+                        }
+                        _ => None,
+                    },
+                )
+                .max_by_key(|&(_, _, duration)| duration)
+        });
+        let (line, callee, duration) = match hottest {
+            Some(hottest) => hottest,
+            None => {
+                let status = format!("Done, no direct calls of {} were made", function);
+                Controller::update_auto_profile(siv, &auto_profile.path, &status);
+                return;
+            }
+        };
+        let share = latest
+            .and_then(|snapshot| snapshot.lines.get(&frame.get_source_line()))
+            .filter(|total| total.duration != Duration::ZERO)
+            .map_or(String::new(), |total| {
+                format!(
+                    " ({:.0}% of its time)",
+                    100.0 * duration.as_secs_f64() / total.duration.as_secs_f64()
+                )
+            });
+        auto_profile.path.push(format!(
+            "{}:{} calls {}, {} in total{}",
+            function,
+            line,
+            callee,
+            views::formatting::format_latency(duration),
+            share
+        ));
+        auto_profile.remaining_depth -= 1;
+        if auto_profile.remaining_depth == 0 {
+            Controller::update_auto_profile(siv, &auto_profile.path, "Done");
+            return;
+        }
+        if let Err(err) = Controller::push_function(siv, callee, Some(line)) {
+            let status = format!("Stopped, failed to enter {}: {}", callee, err);
+            Controller::update_auto_profile(siv, &auto_profile.path, &status);
+            return;
+        }
+        let controller = siv
+            .user_data::<Controller>()
+            .expect("Bug: Controller does not exist");
+        if controller.trace_stack.get_current_function() != callee {
+            let status = format!("Stopped, {} is entered once decompiled", callee);
+            Controller::update_auto_profile(siv, &auto_profile.path, &status);
+            return;
+        }
+        controller.auto_profile = Some(auto_profile);
+        Controller::auto_profile_step(siv);
+    }
+
+    /// Text of the trace tree view: each frame of the stack with its traced
+    /// lines, the next frame nested below the line it was entered from, and
+    /// then the lines pinned with `P`. Frames below the current one show their
+    /// results from before the next frame was entered.
+    fn trace_tree_text(controller: &Controller) -> String {
+        let format_stats = |info: Option<&events::TraceCumulative>,
+                            p99: Option<Duration>,
+                            parent: Option<&events::TraceCumulative>|
+         -> String {
+            let info = match info {
+                Some(info) if info.count != 0 => info,
+                _ => return "-".to_string(),
+            };
+            let mut stats = format!(
+                "count {}  avg {}",
+                info.count,
+                views::formatting::format_latency(
+                    info.duration / u32::try_from(info.count).unwrap()
+                )
+            );
+            if let Some(p99) = p99 {
+                stats.push_str(&format!("  p99 {}", views::formatting::format_latency(p99)));
+            }
+            if let Some(parent) = parent.filter(|p| p.duration != Duration::ZERO) {
+                stats.push_str(&format!(
+                    "  {:.0}% of parent",
+                    100.0 * info.duration.as_secs_f64() / parent.duration.as_secs_f64()
+                ));
+            }
+            stats
+        };
+        let callee = |ci: &CallInstruction| -> Option<FunctionName> {
+            match ci.instruction {
+                InstructionType::Function(f) | InstructionType::DynamicSymbol(f) => Some(f),
+                _ => None,
+            }
+        };
+
+        let frames = controller.trace_stack.get_frames();
+        let latest = controller
+            .snapshots
+            .last()
+            .filter(|s| controller.trace_stack.is_counter_current(s.counter));
+        let mut text = String::new();
+        let mut indent = String::new();
+        for (depth, frame) in frames.iter().enumerate() {
+            let (lines, p99s, status) = if depth == frames.len() - 1 {
+                let p99s = latest.map(|s| {
+                    s.tails
+                        .iter()
+                        .map(|(&line, tail)| (line, tail.p99))
+                        .collect::<HashMap<_, _>>()
+                });
+                (latest.map(|s| &s.lines), p99s, "")
+            } else {
+                match frame.get_cached_results() {
+                    Some(results) => (
+                        Some(&results.lines),
+                        Some(results.p99.clone()),
+                        "  (before entering)",
+                    ),
+                    None => (None, None, "  (no results)"),
+                }
+            };
+            let line_stats = |line: u32| lines.and_then(|lines| lines.get(&line));
+            let p99 = |line: u32| p99s.as_ref().and_then(|p99s| p99s.get(&line)).copied();
+            let source_line = frame.get_source_line();
+            text.push_str(&format!(
+                "{}{}  {}{}\n",
+                indent,
+                frame.get_function(),
+                format_stats(line_stats(source_line), p99(source_line), None),
+                status
+            ));
+            // The line the next frame was entered from goes last, whether it
+            // is traced or not, so that the next frame can be nested below it
+            let next = frames.get(depth + 1);
+            let entered_from = next.and_then(FrameInfo::get_entered_from);
+            let mut entries: Vec<String> = frame
+                .traced_lines()
+                .into_iter()
+                .filter(|&line| Some(line) != entered_from)
+                .map(|line| {
+                    let ci = frame.get_traced_callsite(line).unwrap();
+                    format!(
+                        "line {} -> {}  {}",
+                        line,
+                        callee(ci).map_or_else(|| ci.to_string(), |f| f.to_string()),
+                        format_stats(line_stats(line), p99(line), line_stats(source_line))
+                    )
+                })
+                .collect();
+            match (next, entered_from) {
+                (Some(next), Some(line)) => {
+                    let stats = match frame.get_traced_callsite(line) {
+                        Some(_) => {
+                            format_stats(line_stats(line), p99(line), line_stats(source_line))
+                        }
+                        None => "(not traced)".to_string(),
+                    };
+                    entries.push(format!(
+                        "line {} -> {}  {}",
+                        line,
+                        next.get_function(),
+                        stats
+                    ));
+                }
+                (Some(_), None) => entries.push("(entered directly)".to_string()),
+                (None, _) => (),
+            }
+            for (i, entry) in entries.iter().enumerate() {
+                text.push_str(&format!(
+                    "{}{}{}\n",
+                    indent,
+                    if i == entries.len() - 1 {
+                        "└─ "
+                    } else {
+                        "├─ "
+                    },
+                    entry
+                ));
+            }
+            indent.push_str("   ");
+        }
+        if !controller.pinned_traces.is_empty() {
+            text.push_str("\nPinned:\n");
+            for stats in &controller.pinned_traces {
+                let latency = match stats.latency {
+                    TraceState::Traced(l) => views::formatting::format_latency(l),
+                    _ => "-".to_string(),
+                };
+                let frequency = match stats.frequency {
+                    TraceState::Traced(f) => views::formatting::format_frequency(f),
+                    _ => "-".to_string(),
+                };
+                text.push_str(&format!(
+                    "{}:{}  avg {}  {}\n",
+                    stats.function, stats.line, latency, frequency
+                ));
+            }
+        }
+        text
+    }
+
+    /// Show the hot `path` found by the automatic drill-down so far, along
+    /// with its `status`
+    fn update_auto_profile(siv: &mut Cursive, path: &[String], status: &str) {
+        let mut text = format!("{}\n\nHot path:\n", status);
+        for (i, step) in path.iter().enumerate() {
+            text.push_str(&format!("{}. {}\n", i + 1, step));
+        }
+        views::set_text_dialog_view(siv, "auto_profile_view", &text);
+    }
+
+    /// Span of the current function from `start_line` to `end_line`, to be
+    /// traced on `start_line`
+    fn get_line_span(
+        controller: &Controller,
+        start_line: u32,
+        end_line: u32,
+    ) -> Result<CallInstruction, Error> {
+        if end_line <= start_line {
+            return Err(format!(
+                "Span must end after it starts, got lines {}-{}",
+                start_line, end_line
+            )
+            .into());
+        }
+        let frame = controller.trace_stack.get_current_frame();
+        let function = frame.get_function();
+        let span = controller.program.get_line_span(
+            function,
+            frame.get_source_file(),
+            start_line,
+            end_line,
+        )?;
+        Ok(CallInstruction::line_span(&span, end_line))
+    }
+
+    /// Mark the selected line as the start of a span to trace, or trace the
+    /// span from the marked line to the selected one
+    fn mark_span(siv: &mut Cursive) {
+        let line = siv
+            .find_name::<views::SourceView>("source_view")
+            .expect("Bug: source_view does not exist")
+            .row()
+            .unwrap() as u32
+            + 1;
+        let controller = siv
+            .user_data::<Controller>()
+            .expect("Bug: Controller does not exist");
+        let function = controller.trace_stack.get_current_function();
+        let start_line = match controller.span_start.take() {
+            Some((start_function, start_line)) if start_function == function => start_line,
+            // Nothing marked in this function yet
+            _ => {
+                controller.span_start = Some((function, line));
+                siv.add_layer(views::new_dialog(&format!(
+                    "Marked line {} as the start of the span. Select the line it ends on and press z again, or press z on this line to cancel.",
+                    line
+                )));
+                return;
+            }
+        };
+        if start_line == line {
+            return;
+        }
+        match Controller::get_line_span(controller, start_line, line) {
+            Ok(ci) => {
+                // Replaces whatever was traced on the start line
+                Controller::remove_callsite(siv, start_line);
+                Controller::add_callsite(siv, start_line, ci);
+            }
+            Err(err) => siv.add_layer(views::new_dialog(&format!(
+                "Cannot trace lines {}-{}: {}",
+                start_line, line, err
+            ))),
+        }
+    }
+
+    /// Trace the single instruction at `input` (see
+    /// `program::parse_code_location`) of the current function, on the line
+    /// it maps to
+    fn trace_instruction(siv: &mut Cursive, input: &str) -> Result<(), Error> {
+        let location = program::parse_code_location(input)?;
+        let controller = siv
+            .user_data::<Controller>()
+            .expect("Bug: Controller does not exist");
+        let frame = controller.trace_stack.get_current_frame();
+        let current = frame.get_function();
+        let (function, offset) = controller
+            .program
+            .resolve_code_location(&location, current)?;
+        let program = controller.program.object(function);
+        let source = program.get_location(program.get_address(function) + u64::from(offset));
+        let describe = || match &source {
+            Some(l) => format!("{}:{}", l.file.unwrap(), l.line.unwrap()),
+            None => "an unknown line".to_string(),
+        };
+        if function != current {
+            return Err(format!(
+                "{:#x} of {} is at {}. Push {} onto the stack with > to trace it.",
+                offset,
+                function,
+                describe(),
+                function
+            )
+            .into());
+        }
+        let length = program.get_instruction_length(function, offset)?;
+        let line = source
+            .as_ref()
+            .filter(|l| l.file.unwrap() == frame.get_source_file())
+            .and_then(|l| l.line)
+            .ok_or_else(|| {
+                format!(
+                    "{:#x} of {} is at {}, outside of the source shown",
+                    offset,
+                    function,
+                    describe()
+                )
+            })?;
+        Controller::remove_callsite(siv, line);
+        Controller::add_callsite(siv, line, CallInstruction::manual(offset, length));
+        Ok(())
+    }
+
+    fn add_callsites_now(siv: &mut Cursive, callsites: Vec<(u32, CallInstruction)>) {
+        if let Err(err) = Controller::try_add_callsites(siv, callsites) {
+            siv.add_layer(views::new_dialog(&err.to_string()));
+        }
+    }
+
+    /// Count how often each of `callsites` (lines and their calls) is reached
+    /// in the background for a second, all in one program, so that the user
+    /// can be warned before tracing calls so frequent that uprobes would slow
+    /// down the program (see `Event::CallRate`). Returns false if they aren't
+    /// checked, e.g. when the backend doesn't run bpftrace locally.
+    fn check_call_rate(siv: &mut Cursive, callsites: &[(u32, CallInstruction)]) -> bool {
+        let controller = siv
+            .user_data::<Controller>()
+            .expect("Bug: Controller does not exist");
+        let backend = controller.trace_stack.get_backend();
+        if controller.overhead_threshold == 0 || !backend.is_local_bpftrace() {
+            return false;
+        }
+        let function = controller.trace_stack.get_current_function();
+        let backend_name = backend.name();
+        let cis: Vec<CallInstruction> = callsites.iter().map(|(_, ci)| ci.clone()).collect();
+        let child = backend
+            .run_command(&controller.trace_stack.get_call_rate_expr(&cis))
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn();
+        let child = match child {
+            Ok(child) => child,
+            Err(err) => {
+                log::warn!("{} failed to start: {}", backend_name, err);
+                return false;
+            }
+        };
+        controller.call_rate_checks += 1;
+        let tx = controller.tx.clone();
+        let callsites = callsites.to_vec();
+        let lines: Vec<u32> = callsites.iter().map(|&(line, _)| line).collect();
+        std::thread::spawn(move || {
+            let result = match child.wait_with_output() {
+                Ok(output) if output.status.success() => Ok(TraceStack::parse_call_rate(
+                    &String::from_utf8_lossy(&output.stdout),
+                )),
+                Ok(output) => Err(format!(
+                    "{} failed, status: {:?}, stderr:\n{}",
+                    backend_name,
+                    output.status,
+                    String::from_utf8_lossy(&output.stderr)
+                )
+                .into()),
+                Err(err) => Err(format!("Failed to wait for {}: {}", backend_name, err).into()),
+            };
+            // Controller may have exited in the meantime
+            let _ = tx.send(Event::CallRate {
+                function,
+                callsites,
+                result,
+            });
+        });
+        let mut sview = siv
+            .find_name::<views::SourceView>("source_view")
+            .expect("Bug: source_view does not exist");
+        for line in lines {
+            Self::set_line_state(&mut sview, line, TraceState::Pending, TraceState::Pending);
+        }
+        true
+    }
+
+    /// Only time 1 in `rate` calls on the traced `line`, marking it as sampled
+    fn set_callsite_sampling(siv: &mut Cursive, line: u32, rate: u32) -> Result<(), Error> {
+        siv.user_data::<Controller>()
+            .expect("Bug: Controller does not exist")
+            .trace_stack
+            .set_callsite_sampling(line, rate)?;
+        Controller::update_callsite_annotation(siv, line);
+        Ok(())
+    }
+
+    /// Annotate the traced `line` with which of its calls are counted, if not
+    /// all of them are
+    fn update_callsite_annotation(siv: &mut Cursive, line: u32) {
+        let trace_stack = &siv
+            .user_data::<Controller>()
+            .expect("Bug: Controller does not exist")
+            .trace_stack;
+        let mut notes = Vec::new();
+        let rate = trace_stack.get_callsite_sampling(line);
+        if rate > 1 {
+            notes.push(format!("timing 1 in {} calls", rate));
+        }
+        if let Some(min_duration) = trace_stack.get_callsite_threshold(line) {
+            notes.push(format!(
+                "only calls over {}",
+                views::formatting::format_latency(min_duration)
+            ));
+        }
+        let annotation = Some(notes.join(", ")).filter(|notes| !notes.is_empty());
+        let mut sview = siv
+            .find_name::<views::SourceView>("source_view")
+            .expect("Bug: source_view does not exist");
+        Controller::set_line_annotation(&mut sview, line, annotation);
+    }
+
+    /// Warn that each of `frequent` (lines, their calls and how often they
+    /// are called) is called often enough for tracing it to slow down the
+    /// program, in one dialog for all of them
+    fn warn_overhead(siv: &mut Cursive, frequent: Vec<(u32, CallInstruction, u64)>) {
+        let mut sview = siv
+            .find_name::<views::SourceView>("source_view")
+            .expect("Bug: source_view does not exist");
+        for &(line, _, _) in &frequent {
+            Self::set_line_state(&mut sview, line, TraceState::Untraced, TraceState::Untraced);
+        }
+        let calls = if let [(line, ci, rate)] = frequent.as_slice() {
+            format!(
+                "{} on line {} is called at {}. Tracing it runs a uprobe on each call",
+                ci,
+                line,
+                views::formatting::format_frequency(*rate as f32)
+            )
+        } else {
+            let list = frequent
+                .iter()
+                .map(|(line, ci, rate)| {
+                    format!(
+                        "  line {}: {} at {}",
+                        line,
+                        ci,
+                        views::formatting::format_frequency(*rate as f32)
+                    )
+                })
+                .collect::<Vec<_>>()
+                .join("\n");
+            format!(
+                "These calls are made often:\n{}\n\nTracing them runs a uprobe on each call",
+                list
+            )
+        };
+        let text = format!(
+            "{}, which may slow down the traced program noticeably.\n\nSampling CPU time of the current function instead has a fixed overhead, but shows where time is spent rather than latency.",
+            calls
+        );
+        let threshold = siv
+            .user_data::<Controller>()
+            .expect("Bug: Controller does not exist")
+            .overhead_threshold;
+        let callsites: Vec<(u32, CallInstruction)> = frequent
+            .iter()
+            .map(|(line, ci, _)| (*line, ci.clone()))
+            .collect();
+        let messages = Controller::messages(siv);
+        let mut dialog = Dialog::text(text).title("Tracing overhead").button(
+            messages.get("button.trace_anyway"),
+            move |siv| {
+                siv.pop_layer();
+                Controller::add_callsites_now(siv, callsites.clone());
+            },
+        );
+        // Sampling is set per line, so only offered for a single one, which
+        // `n` can be used for otherwise
+        if let [(line, ci, rate)] = frequent.as_slice() {
+            let (line, sampled_ci) = (*line, ci.clone());
+            let sample_rate = u32::try_from((rate + threshold - 1) / threshold).unwrap_or(u32::MAX);
+            dialog.add_button(
+                messages.format("button.trace_sampled", &[("rate", &sample_rate)]),
+                move |siv| {
+                    siv.pop_layer();
+                    let result = Controller::try_add_callsite(siv, line, sampled_ci.clone())
+                        .and_then(|()| Controller::set_callsite_sampling(siv, line, sample_rate));
+                    if let Err(err) = result {
+                        siv.add_layer(views::new_dialog(&err.to_string()));
+                    }
+                },
+            );
+        }
+        siv.add_layer(
+            dialog
+                .button(messages.get("button.sample"), |siv| {
+                    siv.pop_layer();
+                    Controller::sample_cpu(siv);
+                })
+                .button(messages.get("button.cancel"), |siv| {
+                    siv.pop_layer();
+                }),
+        );
+    }
+
+    /// Sample CPU time in the background, showing how much of it is spent on
+    /// each line of the current function once done (see `Event::CpuSamples`)
+    fn sample_cpu(siv: &mut Cursive) {
+        let function = siv
+            .user_data::<Controller>()
+            .expect("Bug: Controller does not exist")
+            .trace_stack
+            .get_current_function();
+        Controller::run_in_background(
+            siv,
+            "cpu_samples_view",
+            &format!(
+                "Sampling CPU time for {} seconds to find where {} spends it...",
+                Controller::CPU_SAMPLE_SECONDS,
+                function
+            ),
+            move || {
+                let result = sampling::sample(
+                    function,
+                    Duration::from_secs(Controller::CPU_SAMPLE_SECONDS),
+                );
+                Event::CpuSamples { function, result }
+            },
+        );
+    }
+
+    /// Run `job` on a background thread, showing `text` in a dialog named
+    /// `view_name` until the event it returns is handled, which replaces the
+    /// text with the results
+    fn run_in_background<F>(siv: &mut Cursive, view_name: &'static str, text: &str, job: F)
+    where
+        F: FnOnce() -> Event + Send + 'static,
+    {
+        let tx = siv
+            .user_data::<Controller>()
+            .expect("Bug: Controller does not exist")
+            .tx
+            .clone();
+        std::thread::spawn(move || {
+            // Controller may have exited in the meantime
+            let _ = tx.send(job());
+        });
+        let messages = Controller::messages(siv);
+        siv.add_layer(views::new_text_dialog_view(
+            &messages,
+            text,
+            view_name,
+            |siv| {
+                siv.pop_layer();
+            },
+        ));
+    }
+
+    /// Run `command` of the tracing backend like `run_in_background`, turning
+    /// its output into the event to send with `to_event`
+    fn run_command_in_background<F>(
+        siv: &mut Cursive,
+        mut command: std::process::Command,
+        view_name: &'static str,
+        text: &str,
+        to_event: F,
+    ) where
+        F: FnOnce(Result<String, Error>) -> Event + Send + 'static,
+    {
+        let backend_name = siv
+            .user_data::<Controller>()
+            .expect("Bug: Controller does not exist")
+            .trace_stack
+            .get_backend()
+            .name();
+        let child = command
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn();
+        let child = match child {
+            Ok(child) => child,
+            Err(err) => {
+                siv.add_layer(views::new_dialog(&format!(
+                    "{} failed to start: {}",
+                    backend_name, err
+                )));
+                return;
+            }
+        };
+        Controller::run_in_background(siv, view_name, text, move || {
+            let output = match child.wait_with_output() {
+                Ok(output) if output.status.success() => {
+                    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+                }
+                Ok(output) => Err(format!(
+                    "{} failed, status: {:?}, stderr:\n{}",
+                    backend_name,
+                    output.status,
+                    String::from_utf8_lossy(&output.stderr)
+                )
+                .into()),
+                Err(err) => Err(format!("Failed to wait for {}: {}", backend_name, err).into()),
+            };
+            to_event(output)
+        });
+    }
+
+    /// Trace lock waits in the background, showing how long each line of the
+    /// current function waited once done (see `Event::LockWaits`)
+    fn trace_lock_waits(siv: &mut Cursive) {
+        let controller = siv
+            .user_data::<Controller>()
+            .expect("Bug: Controller does not exist");
+        if !controller.trace_stack.get_capabilities().stack_traces {
+            siv.add_layer(views::new_dialog(
+                "Lock waits can't be traced without stack traces, which are needed to tell which line they were reached from",
+            ));
+            return;
+        }
+        let function = controller.trace_stack.get_current_function();
+        let mutex_lock = contention::find_mutex_lock(&controller.program.file_path);
+        if mutex_lock.is_none() {
+            log::info!("pthread_mutex_lock not found, only tracing futex waits");
+        }
+        let mut command = controller.trace_stack.get_backend().run_command(
+            &controller
+                .trace_stack
+                .get_lock_waits_expr(mutex_lock, Controller::LOCK_WAIT_SECONDS),
+        );
+        // Stacks are matched against the mangled function name
+        command.env("BPFTRACE_CPP_DEMANGLE", "0");
+        Controller::run_command_in_background(
+            siv,
+            command,
+            "lock_waits_view",
+            &format!(
+                "Tracing lock waits for {} seconds to find where {} waits...",
+                Controller::LOCK_WAIT_SECONDS,
+                function
+            ),
+            move |output| Event::LockWaits {
+                function,
+                result: output
+                    .map(|output| contention::parse_waits(&output, function.unqualified().0)),
+            },
+        );
+    }
+
+    /// Trace the indirect calls on the current line in the background, then
+    /// let the user enter one of the functions they called (see
+    /// `Event::CallTargets`)
+    fn trace_call_targets(siv: &mut Cursive) {
+        let line = siv
+            .find_name::<views::SourceView>("source_view")
+            .expect("Bug: source_view does not exist")
+            .row()
+            .unwrap() as u32
+            + 1;
+        let controller = siv
+            .user_data::<Controller>()
+            .expect("Bug: Controller does not exist");
+        let function = controller.trace_stack.get_current_function();
+        let callsites: Vec<CallInstruction> = controller
+            .trace_stack
+            .get_callsites(line)
+            .into_iter()
+            .filter(|ci| ci.get_target_expr().is_some())
+            .collect();
+        if callsites.is_empty() {
+            siv.add_layer(views::new_dialog(&format!(
+                "No indirect calls found in {} on line {}",
+                function, line
+            )));
+            return;
+        }
+        let function_address = controller.program.get_address(function);
+        let command = controller.trace_stack.get_backend().run_command(
+            &controller.trace_stack.get_call_targets_expr(
+                &callsites,
+                function_address,
+                Controller::CALL_TARGET_SECONDS,
+            ),
+        );
+        Controller::run_command_in_background(
+            siv,
+            command,
+            "call_targets_view",
+            &format!(
+                "Tracing indirect calls on line {} for {} seconds to find where they jump to...",
+                line,
+                Controller::CALL_TARGET_SECONDS
+            ),
+            move |output| Event::CallTargets {
+                function,
+                line,
+                result: output.map(|output| TraceStack::parse_call_targets(&output)),
+            },
+        );
+    }
+
+    /// Trace syscalls in the background, showing which ones the current
+    /// function spent its time in once done (see `Event::Syscalls`)
+    fn trace_syscalls(siv: &mut Cursive) {
+        let controller = siv
+            .user_data::<Controller>()
+            .expect("Bug: Controller does not exist");
+        let function = controller.trace_stack.get_current_function();
+        let command = controller.trace_stack.get_backend().run_command(
+            &controller
+                .trace_stack
+                .get_syscalls_expr(Controller::SYSCALL_SECONDS),
+        );
+        Controller::run_command_in_background(
+            siv,
+            command,
+            "syscalls_view",
+            &format!(
+                "Tracing syscalls for {} seconds to find where {} spends time in the kernel...",
+                Controller::SYSCALL_SECONDS,
+                function
+            ),
+            move |output| Event::Syscalls {
+                function,
+                result: output.map(|output| syscalls::parse_syscalls(&output)),
+            },
+        );
+    }
+
+    /// Count iterations of the loops of the current function in the
+    /// background, showing how many each call makes next to the loop heads
+    /// once done (see `Event::LoopIterations`)
+    fn trace_loop_iterations(siv: &mut Cursive) {
+        let controller = siv
+            .user_data::<Controller>()
+            .expect("Bug: Controller does not exist");
+        let function = controller.trace_stack.get_current_function();
+        let heads: Vec<u32> = controller
+            .program
+            .get_loops(function)
+            .iter()
+            .map(|l| l.head)
+            .collect();
+        if heads.is_empty() {
+            siv.add_layer(views::new_dialog(&format!(
+                "No loops found in the code of {}.",
+                function
+            )));
+            return;
+        }
+        let command = controller.trace_stack.get_backend().run_command(
+            &controller
+                .trace_stack
+                .get_loop_iterations_expr(&heads, Controller::LOOP_SECONDS),
+        );
+        Controller::run_command_in_background(
+            siv,
+            command,
+            "loops_view",
+            &format!(
+                "Counting iterations of {} loops of {} for {} seconds...",
+                heads.len(),
+                function,
+                Controller::LOOP_SECONDS
+            ),
+            move |output| Event::LoopIterations {
+                function,
+                result: output.map(|output| loops::parse_iterations(&output)),
+            },
+        );
+    }
+
+    /// Trace allocations in the background, showing how much each line of the
+    /// current function allocated once done (see `Event::Allocations`)
+    fn trace_allocations(siv: &mut Cursive) {
+        let controller = siv
+            .user_data::<Controller>()
+            .expect("Bug: Controller does not exist");
+        if !controller.trace_stack.get_capabilities().stack_traces {
+            siv.add_layer(views::new_dialog(
+                "Allocations can't be traced without stack traces, which are needed to tell which line they were made from",
+            ));
+            return;
+        }
+        let allocators = allocations::find_allocators(&controller.program.file_path);
+        if allocators.is_empty() {
+            siv.add_layer(views::new_dialog(
+                "No allocator found in the libraries the program links, e.g. it may be statically linked",
+            ));
+            return;
+        }
+        let function = controller.trace_stack.get_current_function();
+        let mut command = controller.trace_stack.get_backend().run_command(
+            &controller
+                .trace_stack
+                .get_allocations_expr(&allocators, Controller::ALLOCATION_SECONDS),
+        );
+        // Stacks are matched against the mangled function name
+        command.env("BPFTRACE_CPP_DEMANGLE", "0");
+        Controller::run_command_in_background(
+            siv,
+            command,
+            "allocations_view",
+            &format!(
+                "Tracing allocations for {} seconds to find where {} allocates...",
+                Controller::ALLOCATION_SECONDS,
+                function
+            ),
+            move |output| Event::Allocations {
+                function,
+                result: output.map(|output| {
+                    allocations::parse_allocations(&output, function.unqualified().0)
+                }),
+            },
+        );
+    }
+
+    fn try_add_callsite(siv: &mut Cursive, line: u32, ci: CallInstruction) -> Result<(), Error> {
+        Controller::try_add_callsites(siv, vec![(line, ci)])
+    }
+
+    /// Start tracing each of `callsites` (lines and their calls) of the
+    /// current function with a single restart of the trace. Those before the
+    /// first that can't be traced stay traced.
+    fn try_add_callsites(
+        siv: &mut Cursive,
+        callsites: Vec<(u32, CallInstruction)>,
+    ) -> Result<(), Error> {
+        let mut sview = siv
+            .find_name::<views::SourceView>("source_view")
+            .expect("Bug: source_view does not exist");
+        for &(line, _) in &callsites {
+            Self::set_line_state(&mut sview, line, TraceState::Pending, TraceState::Pending);
+        }
+        let controller = siv
+            .user_data::<Controller>()
+            .expect("Bug: Controller does not exist");
+        let function = controller.trace_stack.get_current_function();
+        let result = controller.trace_stack.add_callsites(callsites.clone());
+        let frame = controller.trace_stack.get_current_frame();
+        let mut commands = Vec::new();
+        for (line, ci) in callsites {
+            if frame.get_traced_callsite(line) != Some(&ci) {
+                Self::set_line_state(&mut sview, line, TraceState::Untraced, TraceState::Untraced);
+                continue;
+            }
+            let callsite = match ci.instruction {
+                InstructionType::Manual => CallsiteSelector::Manual {
+                    start: ci.get_relative_ip(),
+                    end: ci.get_relative_ip() + ci.get_length(),
+                },
+                InstructionType::Span(end_line) => CallsiteSelector::Span { end_line },
+                _ => CallsiteSelector::Offset(ci.get_relative_ip()),
+            };
+            if let InstructionType::Function(callee) | InstructionType::DynamicSymbol(callee) =
+                ci.instruction
+            {
+                controller.history.record_trace(callee);
+            }
+            commands.push(ControlCommand::AddTrace {
+                function: function.0.to_string(),
+                line,
+                callsite: Some(callsite),
+            });
+        }
+        for command in commands {
+            Controller::record(siv, command);
+        }
+        result
+    }
+
+    /// Stop tracing `line` of the current function, returning true if it was
+    /// traced.
+    fn remove_callsite(siv: &mut Cursive, line: u32) -> bool {
+        let trace_stack = &siv
+            .user_data::<Controller>()
+            .expect("Bug: Controller does not exist")
+            .trace_stack;
+        let function = trace_stack.get_current_function();
+        let annotated = trace_stack.get_callsite_sampling(line) > 1
+            || trace_stack.get_callsite_threshold(line).is_some();
+        if !trace_stack.remove_callsite(line) {
+            return false;
+        }
+        let mut sview = siv
+            .find_name::<views::SourceView>("source_view")
+            .expect("Bug: source_view does not exist");
+        Self::set_line_state(&mut sview, line, TraceState::Untraced, TraceState::Untraced);
+        if annotated {
+            Controller::set_line_annotation(&mut sview, line, None);
+        }
+        Controller::record(
+            siv,
+            ControlCommand::RemoveTrace {
+                function: function.0.to_string(),
+                line,
+            },
+        );
+        true
+    }
+
+    /// Push `function` onto the trace stack and display it. `entered_from` is
+    /// the line of the current function calling it, if any. If `function` has
+    /// to be decompiled first, that is done in the background and it is pushed
+    /// once done.
+    fn push_function(
+        siv: &mut Cursive,
+        function: FunctionName,
+        entered_from: Option<u32>,
+    ) -> Result<(), Error> {
+        if Controller::decompile_in_background(siv, function, AfterDecompiling::Enter(entered_from))
+        {
+            return Ok(());
+        }
+        let mut sview = siv
+            .find_name::<views::SourceView>("source_view")
+            .expect("Bug: source_view does not exist");
+        let mut fview = siv
+            .find_name::<views::FooterView>("footer_view")
+            .expect("Bug: footer_view does not exist");
+        let controller = siv
+            .user_data::<Controller>()
+            .expect("Bug: Controller does not exist");
+        let (mut frame_info, source_found) =
+            Controller::setup_function(&controller.program, function, &mut sview, &mut fview)?;
+        frame_info.set_entered_from(entered_from);
+        controller.cache_current_results();
+        controller.trace_stack.push(frame_info);
+        if memory::over_budget() {
+            let dropped = controller.trace_stack.drop_frame_caches();
+            log::info!(
+                "Over memory budget, dropped callsites of {} frames",
+                dropped
+            );
+        }
+        controller.history.record_visit(function);
+        Controller::update_title(siv);
+        Controller::record(siv, ControlCommand::Enter(function.0.to_string()));
+        if !source_found {
+            Controller::locate_source(siv);
+        }
+        Ok(())
+    }
+
+    /// Run the decompiler on `function` in the background if it has to be
+    /// before `function` can be shown, handling `then` once it is done (see
+    /// `Event::Decompiled`). Returns whether it was started.
+    fn decompile_in_background(
+        siv: &mut Cursive,
+        function: FunctionName,
+        then: AfterDecompiling,
+    ) -> bool {
+        let job = match siv
+            .user_data::<Controller>()
+            .expect("Bug: Controller does not exist")
+            .program
+            .decompile_job(function)
+        {
+            Some(job) => job,
+            None => return false,
+        };
+        Controller::run_in_background(
+            siv,
+            "decompile_view",
+            &format!("Decompiling {}...", function),
+            move || Event::Decompiled {
+                function,
+                then,
+                result: job(),
+            },
+        );
+        true
+    }
+
+    /// Keep the latest results of the current frame, to be shown again once it
+    /// is returned to
+    fn cache_current_results(&mut self) {
+        if let Some(snapshot) = self
+            .snapshots
+            .last()
+            .filter(|s| self.trace_stack.is_counter_current(s.counter))
+        {
+            self.trace_stack.cache_results(CachedResults {
+                time: snapshot.time,
+                lines: snapshot.lines.clone(),
+                p99: snapshot
+                    .tails
+                    .iter()
+                    .map(|(&line, tail)| (line, tail.p99))
+                    .collect(),
+            });
+        }
+        self.stale_footer = None;
+    }
+
+    /// Pick one of the objects the traced processes have mapped, e.g. plugins
+    /// loaded with `dlopen`, and then one of its functions to enter
+    fn pick_mapped_object(siv: &mut Cursive) {
+        let controller = siv
+            .user_data::<Controller>()
+            .expect("Bug: Controller does not exist");
+        if !controller.trace_stack.get_backend().is_local_bpftrace() {
+            siv.add_layer(views::new_dialog(
+                "Loaded libraries are only found with the bpftrace backend",
+            ));
+            return;
+        }
+        let objects = maps::mapped_objects(&controller.program.file_path, &controller.trace_stack);
+        if objects.is_empty() {
+            siv.add_layer(views::new_dialog(
+                "No libraries found, is the program running?",
+            ));
+            return;
+        }
+        let search_view = views::new_simple_search_view(
+            "Select the library",
+            objects,
+            |siv: &mut Cursive, object: &MappedObject| {
+                siv.pop_layer();
+                let controller = siv
+                    .user_data::<Controller>()
+                    .expect("Bug: Controller does not exist");
+                let functions = match controller.program.load_library(&object.path) {
+                    Ok(library) => library.defined_functions(),
+                    Err(err) => {
+                        siv.add_layer(views::new_dialog(&format!(
+                            "Failed to load {}: {}",
+                            object.path, err
+                        )));
+                        return;
+                    }
+                };
+                let search_view = views::new_simple_search_view(
+                    "Select the function to enter",
+                    functions,
+                    |siv: &mut Cursive, symbol: &SymbolInfo| {
+                        siv.pop_layer();
+                        Controller::enter_function(siv, symbol.name, None);
+                    },
+                );
+                siv.add_layer(search_view);
+            },
+        );
+        siv.add_layer(search_view);
+    }
+
+    /// Enter `symbol`, in the shared library defining it if it is a dynamic
+    /// symbol
+    fn enter_symbol(siv: &mut Cursive, symbol: &SymbolInfo, entered_from: Option<u32>) {
+        let controller = siv
+            .user_data::<Controller>()
+            .expect("Bug: Controller does not exist");
+        if !Controller::is_dynamic(&controller.program, symbol) {
+            Controller::enter_function(siv, symbol.name, entered_from);
+            return;
+        }
+        match Controller::load_dynamic_symbol(&mut controller.program, symbol.name) {
+            Ok(resolved) => Controller::enter_function(siv, resolved.name, entered_from),
+            Err(err) => {
+                let text = Controller::messages(siv).format(
+                    "function.setup_error",
+                    &[("function", &symbol.name), ("error", &err)],
+                );
+                siv.add_layer(views::new_dialog(&text));
+            }
+        }
+    }
+
+    /// Whether `symbol` is defined by a shared library rather than `program`,
+    /// either as a PLT stub or as an undefined symbol
+    fn is_dynamic(program: &Program, symbol: &SymbolInfo) -> bool {
+        symbol.address == 0 || program.is_dynamic_symbol(symbol)
+    }
+
+    /// Push `function` onto the trace stack, first offering to skip through it
+    /// if it is a trivial wrapper. Errors are shown to the user.
+    fn enter_function(siv: &mut Cursive, function: FunctionName, entered_from: Option<u32>) {
+        let push = move |siv: &mut Cursive, function: FunctionName| {
+            if let Err(e) = Controller::push_function(siv, function, entered_from) {
+                let text = Controller::messages(siv).format(
+                    "function.setup_error",
+                    &[("function", &function), ("error", &e)],
+                );
+                siv.add_layer(views::new_dialog(&text));
+            }
+        };
+        let callee = siv
+            .user_data::<Controller>()
+            .expect("Bug: Controller does not exist")
+            .program
+            .get_wrapped_function(function);
+        match callee {
+            Some(callee) => siv.add_layer(
+                Dialog::text(format!(
+                    "{} only calls {}. Skip through to it?",
+                    function, callee
+                ))
+                .button("Skip", move |siv| {
+                    siv.pop_layer();
+                    push(siv, callee);
+                })
+                .button("Enter anyway", move |siv| {
+                    siv.pop_layer();
+                    push(siv, function);
+                }),
+            ),
+            None => push(siv, function),
+        }
+    }
+
+    /// Pop the current function off the trace stack and display its parent.
+    /// Returns false if this is the last function, which cannot be popped.
+    fn pop_function(siv: &mut Cursive) -> bool {
+        let controller = siv
+            .user_data::<Controller>()
+            .expect("Bug: Controller does not exist");
+        match controller.trace_stack.pop() {
+            Some(frame_info) => {
+                Controller::show_frame(siv, frame_info);
+                Controller::record(siv, ControlCommand::Pop);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Display `frame_info`, which just became the current frame again, along
+    /// with its cached results until fresh ones arrive
+    fn show_frame(siv: &mut Cursive, frame_info: FrameInfo) {
+        let controller = siv
+            .user_data::<Controller>()
+            .expect("Bug: Controller does not exist");
+        // Callsites of the frame may have been dropped while it was below the
+        // current one
+        let frame_info = if frame_info.callsites_dropped() {
+            match Controller::create_frame_info(&controller.program, frame_info.get_function()) {
+                Ok(frame) => controller.trace_stack.restore_callsites(frame),
+                Err(err) => {
+                    log::warn!("Failed to find callsites again: {}", err);
+                    frame_info
+                }
+            }
+        } else {
+            frame_info
+        };
+        let mut sview = siv
+            .find_name::<views::SourceView>("source_view")
+            .expect("Bug: source_view does not exist");
+        let mut fview = siv
+            .find_name::<views::FooterView>("footer_view")
+            .expect("Bug: footer_view does not exist");
+        let controller = siv
+            .user_data::<Controller>()
+            .expect("Bug: Controller does not exist");
+        Controller::setup_source_view(&controller.program, &frame_info, &mut sview, &mut fview)
+            .unwrap();
+        controller.stale_footer = None;
+        if let Some(results) = frame_info.get_cached_results() {
+            let snapshot = Snapshot {
+                counter: 0,
+                time: results.time,
+                lines: results.lines.clone(),
+                tails: HashMap::new(),
+                off_cpu: HashMap::new(),
+                aborted: HashMap::new(),
+                counters: HashMap::new(),
+            };
+            Controller::show_lines(
+                &mut sview,
+                std::slice::from_ref(&snapshot),
+                0,
+                &frame_info,
+                controller.show_count_delta,
+                controller.latency_statistic,
+                controller.counter_column,
+            );
+            controller.stale_footer = Some(views::get_footer_view(&fview));
+            views::append_footer_warning(
+                &mut fview,
+                "[stale] results from when last shown, updating...",
+            );
+        }
+        Controller::update_title(siv);
+    }
+
+    /// List the workspaces with the latest stats of their current functions,
+    /// to switch between them or start a new one
+    fn show_workspaces(siv: &mut Cursive) {
+        let controller = siv
+            .user_data::<Controller>()
+            .expect("Bug: Controller does not exist");
+        let (functions, current) = controller.trace_stack.get_workspaces();
+        let (time, results) = &controller.workspace_results;
+        let mut items: Vec<WorkspaceItem> = functions
+            .iter()
+            .enumerate()
+            .map(|(i, function)| {
+                let stats = if i == current {
+                    "(current)".to_string()
+                } else {
+                    match results.get(&i) {
+                        Some(info) if info.count != 0 => format!(
+                            "Latency: {}  Frequency: {}",
+                            views::formatting::format_latency(
+                                info.duration / u32::try_from(info.count).unwrap()
+                            ),
+                            views::formatting::format_frequency(
+                                info.count as f32 / time.as_secs_f32()
+                            )
+                        ),
+                        _ => "-".to_string(),
+                    }
+                };
+                WorkspaceItem::Switch(i, format!("{}. {}  {}", i + 1, function, stats))
+            })
+            .collect();
+        items.push(WorkspaceItem::New);
+        if functions.len() > 1 {
+            items.push(WorkspaceItem::Close);
+        }
+        let search_view = views::new_simple_search_view(
+            "Select the workspace",
+            items,
+            |siv: &mut Cursive, item: &WorkspaceItem| {
+                siv.pop_layer();
+                match item {
+                    WorkspaceItem::Switch(index, _) => Controller::switch_workspace(siv, *index),
+                    WorkspaceItem::New => Controller::pick_workspace_function(siv),
+                    WorkspaceItem::Close => Controller::close_workspace(siv),
+                }
+            },
+        );
+        siv.add_layer(search_view);
+    }
+
+    /// Search for the function a new workspace traces
+    fn pick_workspace_function(siv: &mut Cursive) {
+        let controller = siv
+            .user_data::<Controller>()
+            .expect("Bug: Controller does not exist");
+        let initial_results = Controller::empty_search_results(
+            &controller.program,
+            &controller.history,
+            "Type to search",
+        );
+        controller
+            .searcher
+            .setup_search(initial_results.clone(), Vec::new(), true);
+        let search_view = views::new_search_view(
+            "Select the function to trace in the new workspace",
+            initial_results,
+            |siv: &mut Cursive, view_name: &str, search: &str, n_results: usize| {
+                let controller = siv
+                    .user_data::<Controller>()
+                    .expect("Bug: Controller does not exist");
+                controller.searcher.search(view_name, search, n_results);
+            },
+            |siv: &mut Cursive, symbol: &SymbolInfo| {
+                siv.pop_layer();
+                Controller::add_workspace(siv, symbol);
+            },
+        );
+        siv.add_layer(search_view);
+    }
+
+    /// Start a new workspace tracing `symbol`, in the shared library defining
+    /// it if it is a dynamic symbol. Errors are shown to the user.
+    fn add_workspace(siv: &mut Cursive, symbol: &SymbolInfo) {
+        let controller = siv
+            .user_data::<Controller>()
+            .expect("Bug: Controller does not exist");
+        let function = if Controller::is_dynamic(&controller.program, symbol) {
+            Controller::load_dynamic_symbol(&mut controller.program, symbol.name)
+                .map(|resolved| resolved.name)
+        } else {
+            Ok(symbol.name)
+        };
+        if let Ok(function) = function {
+            let then = AfterDecompiling::AddWorkspace(symbol.clone());
+            if Controller::decompile_in_background(siv, function, then) {
+                return;
+            }
+        }
+        let mut sview = siv
+            .find_name::<views::SourceView>("source_view")
+            .expect("Bug: source_view does not exist");
+        let mut fview = siv
+            .find_name::<views::FooterView>("footer_view")
+            .expect("Bug: footer_view does not exist");
+        let controller = siv
+            .user_data::<Controller>()
+            .expect("Bug: Controller does not exist");
+        let result = function.and_then(|function| {
+            Controller::setup_function(&controller.program, function, &mut sview, &mut fview)
+                .map(|(frame_info, source_found)| (function, frame_info, source_found))
+        });
+        match result {
+            Ok((function, frame_info, source_found)) => {
+                controller.cache_current_results();
+                controller.trace_stack.add_workspace(frame_info);
+                controller.history.record_visit(function);
+                Controller::update_title(siv);
+                if !source_found {
+                    Controller::locate_source(siv);
+                }
+            }
+            Err(err) => {
+                let text = Controller::messages(siv).format(
+                    "function.setup_error",
+                    &[("function", &symbol.name), ("error", &err)],
+                );
+                siv.add_layer(views::new_dialog(&text));
+            }
+        }
+    }
+
+    fn switch_workspace(siv: &mut Cursive, index: usize) {
+        let controller = siv
+            .user_data::<Controller>()
+            .expect("Bug: Controller does not exist");
+        controller.cache_current_results();
+        let frame_info = controller.trace_stack.switch_workspace(index);
+        Controller::show_frame(siv, frame_info);
+    }
+
+    /// Close the current workspace and switch to the one before it
+    fn close_workspace(siv: &mut Cursive) {
+        let controller = siv
+            .user_data::<Controller>()
+            .expect("Bug: Controller does not exist");
+        // Indices of the workspaces after it shift down
+        controller.workspace_results.1.clear();
+        match controller.trace_stack.close_workspace() {
+            Some(frame_info) => Controller::show_frame(siv, frame_info),
+            None => siv.add_layer(views::new_dialog("The last workspace can't be closed")),
+        }
+    }
+
+    /// Catalog of user facing strings, shared so that it can be used while
+    /// `siv` is borrowed
+    fn messages(siv: &mut Cursive) -> Rc<Messages> {
+        Rc::clone(
+            &siv.user_data::<Controller>()
+                .expect("Bug: Controller does not exist")
+                .messages,
+        )
+    }
+
+    /// Title of the main view, showing the signature of `function` if it is
+    /// known
+    fn title(program: &Program, function: FunctionName) -> String {
+        let signature = program
+            .get_signature(function)
+            .unwrap_or_else(|| function.to_string());
+        format!(
+            "wachy | {} | {}",
+            program.object(function).file_path,
+            signature
+        )
+    }
+
+    fn update_title(siv: &mut Cursive) {
+        let controller = siv
+            .user_data::<Controller>()
+            .expect("Bug: Controller does not exist");
+        let mut title = Controller::title(
+            &controller.program,
+            controller.trace_stack.get_current_function(),
+        );
+        // Tab bar of the workspaces, see `N`
+        let (functions, current) = controller.trace_stack.get_workspaces();
+        if functions.len() > 1 {
+            let tabs: Vec<String> = functions
+                .iter()
+                .enumerate()
+                .map(|(i, function)| {
+                    if i == current {
+                        format!("[{} {}]", i + 1, function)
+                    } else {
+                        format!("{} {}", i + 1, function)
+                    }
+                })
+                .collect();
+            title = format!("{} | {}", tabs.join(" "), title);
+        }
+        siv.find_name::<Dialog>("main_view")
+            .expect("Bug: main_view does not exist")
+            .set_title(title);
+    }
+
+    /// Add a source map rule for the rest of the session and redisplay the
+    /// current function with it
+    fn add_source_map(siv: &mut Cursive, rule: &str) -> Result<(), Error> {
+        siv.user_data::<Controller>()
+            .expect("Bug: Controller does not exist")
+            .program
+            .add_source_map(rule)?;
+        Controller::refresh_source_view(siv);
+        Ok(())
+    }
+
+    /// Look for the source file of the current function after it wasn't
+    /// found. It is downloaded from debuginfod servers in the background if
+    /// they may have it (see `Event::DebuginfodSource`), and the user is asked
+    /// where it is otherwise.
+    fn locate_source(siv: &mut Cursive) {
+        let controller = siv
+            .user_data::<Controller>()
+            .expect("Bug: Controller does not exist");
+        let frame = controller.trace_stack.get_current_frame();
+        let function = frame.get_function();
+        let source_file = frame.get_source_file().to_string();
+        let build_id = controller
+            .program
+            .get_debuginfod_build_id(function)
+            .filter(|build_id| debuginfod::may_fetch_source(build_id, &source_file));
+        let build_id = match build_id {
+            Some(build_id) => build_id,
+            None => {
+                Controller::ask_source_path(siv);
+                return;
+            }
+        };
+        let tx = controller.tx.clone();
+        let footer_file = source_file.clone();
+        std::thread::spawn(move || {
+            let found = debuginfod::fetch_source(&build_id, &source_file).is_some();
+            // Controller may have exited in the meantime
+            let _ = tx.send(Event::DebuginfodSource {
+                function,
+                source_file,
+                found,
+            });
+        });
+        siv.call_on_name("footer_view", |fview: &mut views::FooterView| {
+            views::set_footer_view_with_warning(
+                fview,
+                &footer_file,
+                "[!] Source not found, downloading it from debuginfod...",
+            );
+        });
+    }
+
+    /// Ask the user where the source file of the current function is, after
+    /// it wasn't found. The tree it is in is remembered for other files, see
+    /// `SourceMap::add_located`.
+    fn ask_source_path(siv: &mut Cursive) {
+        let source_file = siv
+            .user_data::<Controller>()
+            .expect("Bug: Controller does not exist")
+            .trace_stack
+            .get_current_frame()
+            .get_source_file()
+            .to_string();
+        siv.add_layer(views::new_edit_view(
+            &format!("{} not found, enter its path [Esc to skip]", source_file),
+            "locate_source_view",
+            Some(&source_file),
+            move |siv, path| {
+                siv.pop_layer();
+                if !std::path::Path::new(path).is_file() {
+                    siv.add_layer(Dialog::text(format!("{} is not a file", path)).button(
+                        "OK",
+                        |siv| {
+                            siv.pop_layer();
+                            Controller::ask_source_path(siv);
+                        },
+                    ));
+                    return;
+                }
+                siv.user_data::<Controller>()
+                    .expect("Bug: Controller does not exist")
+                    .program
+                    .add_located_source(&source_file, path);
+                Controller::refresh_source_view(siv);
+            },
+        ));
+    }
+
+    /// Set up the source view of the current function again, e.g. after
+    /// changing where sources are read from
+    fn refresh_source_view(siv: &mut Cursive) {
+        let mut sview = siv
+            .find_name::<views::SourceView>("source_view")
+            .expect("Bug: source_view does not exist");
+        let mut fview = siv
+            .find_name::<views::FooterView>("footer_view")
+            .expect("Bug: footer_view does not exist");
+        let controller = siv
+            .user_data::<Controller>()
+            .expect("Bug: Controller does not exist");
+        let frame_info = controller.trace_stack.get_current_frame();
+        Controller::setup_source_view(&controller.program, &frame_info, &mut sview, &mut fview)
+            .unwrap();
+    }
+
+    /// Show aggregates of traced lines from `snapshots[index]` in the source
+    /// view. With `show_count_delta`, the frequency column shows how many
+    /// events arrived since the previous snapshot instead. The own line of
+    /// `frame`'s function is annotated with its self time, the time left once
+    /// its traced callees are taken out (see `FrameInfo::callee_lines`), and
+    /// each line's share of the function's time is set for the share column.
+    fn show_lines(
+        sview: &mut views::SourceView,
+        snapshots: &[Snapshot],
+        index: usize,
+        frame: &FrameInfo,
+        show_count_delta: bool,
+        latency_statistic: LatencyStatistic,
+        counter_column: CounterColumn,
+    ) {
+        let snapshot = &snapshots[index];
+        let previous = index.checked_sub(1).map(|i| &snapshots[i].lines);
+        let source_line = frame.get_source_line();
+        let function_duration = snapshot
+            .lines
+            .get(&source_line)
+            .map(|total| total.duration)
+            .filter(|&duration| duration != Duration::ZERO);
+        for (line, info) in &snapshot.lines {
+            // Tails are pending until the trace restarted with histograms
+            // reports
+            let tail = snapshot.tails.get(line);
+            let latency = if info.count == 0 {
+                TraceState::Untraced
+            } else {
+                match latency_statistic {
+                    LatencyStatistic::Mean => {
+                        TraceState::Traced(info.duration / u32::try_from(info.count).unwrap())
+                    }
+                    LatencyStatistic::P50 => {
+                        tail.map_or(TraceState::Pending, |t| TraceState::Traced(t.p50))
+                    }
+                    LatencyStatistic::P99 => {
+                        tail.map_or(TraceState::Pending, |t| TraceState::Traced(t.p99))
+                    }
+                    LatencyStatistic::Max => {
+                        tail.map_or(TraceState::Pending, |t| TraceState::Traced(t.max))
+                    }
+                }
+            };
+            let frequency = TraceState::Traced(info.count as f32 / snapshot.time.as_secs_f32());
+            Controller::set_line_state(sview, *line, latency, frequency);
+            sview.borrow_items_mut()[*line as usize - 1].off_cpu = snapshot
+                .off_cpu
+                .get(line)
+                .filter(|_| info.duration.as_nanos() > 0)
+                .map(|off_cpu| off_cpu.as_secs_f32() / info.duration.as_secs_f32());
+            sview.borrow_items_mut()[*line as usize - 1].aborted =
+                snapshot.aborted.get(line).copied().unwrap_or(0);
+            sview.borrow_items_mut()[*line as usize - 1].counter = snapshot
+                .counters
+                .get(line)
+                .and_then(|counters| counter_column.value(counters));
+            let count_delta = if show_count_delta {
+                let previous_count = previous
+                    .and_then(|lines| lines.get(line))
+                    .map_or(0, |info| info.count);
+                Some(info.count.saturating_sub(previous_count))
+            } else {
+                None
+            };
+            sview.borrow_items_mut()[*line as usize - 1].count_delta = count_delta;
+            sview.borrow_items_mut()[*line as usize - 1].parent_share =
+                function_duration.map(|total| info.duration.as_secs_f32() / total.as_secs_f32());
+        }
+        let callee_lines = frame.callee_lines();
+        let self_time = snapshot
+            .lines
+            .get(&source_line)
+            .filter(|total| total.count > 0 && total.duration != Duration::ZERO)
+            .filter(|_| !callee_lines.is_empty())
+            .map(|total| {
+                let children: Duration = callee_lines
+                    .iter()
+                    .filter_map(|line| snapshot.lines.get(line))
+                    .map(|info| info.duration)
+                    .sum();
+                // Recursive calls are counted in both, so this can't be
+                // trusted to be positive
+                let own = total.duration.saturating_sub(children);
+                (
+                    own / u32::try_from(total.count).unwrap(),
+                    own.as_secs_f32() / total.duration.as_secs_f32(),
+                )
+            });
+        let items = sview.borrow_items_mut();
+        if let Some(item) = (source_line as usize)
+            .checked_sub(1)
+            .and_then(|i| items.get_mut(i))
+        {
+            item.self_time = self_time;
+        }
+    }
+
+    /// Pin `line` of the current function above the source view, or unpin it
+    /// if it is already pinned
+    fn toggle_pinned(siv: &mut Cursive, line: u32) {
+        let source = siv
+            .find_name::<views::SourceView>("source_view")
+            .expect("Bug: source_view does not exist")
+            .borrow_items()
+            .get(line as usize - 1)
+            .map_or(String::new(), |item| item.line.trim().to_string());
+        let controller = siv
+            .user_data::<Controller>()
+            .expect("Bug: Controller does not exist");
+        let function = controller.trace_stack.get_current_function();
+        let unpin = controller
+            .pinned
+            .as_ref()
+            .map_or(false, |p| p.function == function && p.line == line);
+        controller.pinned = if unpin {
+            None
+        } else {
+            Some(Pinned {
+                function,
+                line,
+                source,
+                last: None,
+                latency: TraceState::Untraced,
+                frequency: TraceState::Untraced,
+                history: Vec::new(),
+            })
+        };
+        Controller::update_pinned(siv);
+    }
+
+    /// Add the latest aggregates of the pinned line to its history and show
+    /// them
+    fn update_pinned(siv: &mut Cursive) {
+        let mut pview = siv
+            .find_name::<views::PinnedView>("pinned_view")
+            .expect("Bug: pinned_view does not exist");
+        let controller = siv
+            .user_data::<Controller>()
+            .expect("Bug: Controller does not exist");
+        let current_function = controller.trace_stack.get_current_function();
+        let pinned = match &mut controller.pinned {
+            Some(pinned) => pinned,
+            None => {
+                views::set_pinned_view(&mut pview, None);
+                return;
+            }
+        };
+        let in_current_function = pinned.function == current_function;
+        let trace_stack = &controller.trace_stack;
+        let latest = controller
+            .snapshots
+            .last()
+            .filter(|s| trace_stack.is_counter_current(s.counter));
+        if let (true, Some(snapshot)) = (in_current_function, latest) {
+            match snapshot.lines.get(&pinned.line) {
+                Some(info) => {
+                    // Counts restart along with the trace
+                    let (previous_duration, previous_count) = match &pinned.last {
+                        Some((counter, last)) if *counter == snapshot.counter => {
+                            (last.duration, last.count)
+                        }
+                        _ => (Duration::ZERO, 0),
+                    };
+                    let count = info.count.saturating_sub(previous_count);
+                    pinned.history.push(if count != 0 {
+                        Some(
+                            info.duration.saturating_sub(previous_duration)
+                                / u32::try_from(count).unwrap(),
+                        )
+                    } else {
+                        None
+                    });
+                    if pinned.history.len() > Controller::PINNED_HISTORY_LEN {
+                        pinned.history.remove(0);
+                    }
+                    pinned.latency = if info.count != 0 {
+                        TraceState::Traced(info.duration / u32::try_from(info.count).unwrap())
+                    } else {
+                        TraceState::Untraced
+                    };
+                    pinned.frequency =
+                        TraceState::Traced(info.count as f32 / snapshot.time.as_secs_f32());
+                    pinned.last = Some((snapshot.counter, info.clone()));
+                }
+                None => {
+                    pinned.last = None;
+                    pinned.latency = TraceState::Untraced;
+                    pinned.frequency = TraceState::Untraced;
+                }
+            }
+        }
+
+        let format_state = |state: String| {
+            if state.is_empty() {
+                "-".to_string()
+            } else {
+                state
+            }
+        };
+        let latency = match pinned.latency {
+            TraceState::Traced(l) => views::formatting::format_latency(l),
+            _ => String::new(),
+        };
+        let frequency = match pinned.frequency {
+            TraceState::Traced(f) => views::formatting::format_frequency(f),
+            _ => String::new(),
+        };
+        let history: Vec<Option<f64>> = pinned
+            .history
+            .iter()
+            .map(|latency| latency.map(|l| l.as_nanos() as f64))
+            .collect();
+        let status = if !in_current_function {
+            "  (not in current function)"
+        } else if let TraceState::Untraced = pinned.frequency {
+            "  (not traced)"
+        } else {
+            ""
+        };
+        let text = format!(
+            "Pinned {}:{}  {}\nLatency: {}  Frequency: {}  {}{}",
+            pinned.function,
+            pinned.line,
+            pinned.source,
+            format_state(latency),
+            format_state(frequency),
+            views::formatting::sparkline(&history),
+            status
+        );
+        views::set_pinned_view(&mut pview, Some(&text));
+    }
+
+    /// Pin `line` of the current function so it stays traced in every frame,
+    /// or unpin it if it is already pinned
+    fn toggle_pinned_trace(siv: &mut Cursive, line: u32) {
+        let source = siv
+            .find_name::<views::SourceView>("source_view")
+            .expect("Bug: source_view does not exist")
+            .borrow_items()
+            .get(line as usize - 1)
+            .map_or(String::new(), |item| item.line.trim().to_string());
+        let controller = siv
+            .user_data::<Controller>()
+            .expect("Bug: Controller does not exist");
+        let function = controller.trace_stack.get_current_function();
+        match controller.trace_stack.toggle_pinned_trace(line) {
+            Ok(true) => controller.pinned_traces.push(PinnedTraceStats {
+                function,
+                line,
+                source,
+                latency: TraceState::Pending,
+                frequency: TraceState::Pending,
+            }),
+            Ok(false) => controller
+                .pinned_traces
+                .retain(|p| p.function != function || p.line != line),
+            Err(err) => {
+                siv.add_layer(views::new_dialog(&format!(
+                    "Cannot pin line {}: {}",
+                    line, err
+                )));
+                return;
+            }
+        }
+        Controller::update_pinned_traces(siv, 0, Duration::ZERO, None);
+    }
+
+    /// Update the stats of the lines pinned with `P` from `results` of the
+    /// trace, if it is still current, and list them above the source view
+    fn update_pinned_traces(
+        siv: &mut Cursive,
+        counter: u64,
+        time: Duration,
+        results: Option<Vec<events::TraceCumulative>>,
+    ) {
+        let mut pview = siv
+            .find_name::<views::PinnedTracesView>("pinned_traces_view")
+            .expect("Bug: pinned_traces_view does not exist");
+        let controller = siv
+            .user_data::<Controller>()
+            .expect("Bug: Controller does not exist");
+        if controller.pinned_traces.is_empty() {
+            views::set_pinned_traces_view(&mut pview, None);
+            return;
+        }
+        if let (true, Some(results)) = (controller.trace_stack.is_counter_current(counter), results)
+        {
+            for (stats, info) in controller.pinned_traces.iter_mut().zip(results) {
+                stats.latency = if info.count != 0 {
+                    TraceState::Traced(info.duration / u32::try_from(info.count).unwrap())
+                } else {
+                    TraceState::Untraced
+                };
+                stats.frequency = TraceState::Traced(info.count as f32 / time.as_secs_f32());
+            }
+        }
+
+        let mut text = if matches!(controller.trace_stack.get_mode(), TraceMode::Line) {
+            "Pinned traces".to_string()
+        } else {
+            "Pinned traces  (paused outside of line mode)".to_string()
+        };
+        for stats in &controller.pinned_traces {
+            let latency = match stats.latency {
+                TraceState::Traced(l) => views::formatting::format_latency(l),
+                _ => "-".to_string(),
+            };
+            let frequency = match stats.frequency {
+                TraceState::Traced(f) => views::formatting::format_frequency(f),
+                _ => "-".to_string(),
+            };
+            text.push_str(&format!(
+                "\n{}:{}  {}  Latency: {}  Frequency: {}",
+                stats.function, stats.line, stats.source, latency, frequency
+            ));
+        }
+        views::set_pinned_traces_view(&mut pview, Some(&text));
+    }
+
+    /// Show or hide the histogram pane, and start or stop collecting the
+    /// histograms shown in it
+    fn toggle_histogram_pane(siv: &mut Cursive) {
+        let mut hview = siv
+            .find_name::<views::HistogramPane>("histogram_pane")
+            .expect("Bug: histogram_pane does not exist");
+        let controller = siv
+            .user_data::<Controller>()
+            .expect("Bug: Controller does not exist");
+        let enabled = controller.line_histograms.is_none();
+        hview.set_visible(enabled);
+        controller.line_histograms = if enabled { Some(HashMap::new()) } else { None };
+        controller.update_line_histograms();
+        drop(hview);
+        Controller::update_histogram_pane(siv);
+    }
+
+    /// Collect line histograms while the histogram pane, a tail latency
+    /// column or the statistics popup needs them
+    fn update_line_histograms(&self) {
+        self.trace_stack.set_line_histograms(
+            self.line_histograms.is_some()
+                || self.latency_statistic != LatencyStatistic::Mean
+                || self.stats_line.is_some(),
+        );
+    }
+
+    /// Tail latencies of each line from its histogram and statistics, if
+    /// they were collected
+    fn line_tails(
+        histograms: Option<&HashMap<u32, String>>,
+        statistics: Option<&HashMap<u32, LineStatistics>>,
+    ) -> HashMap<u32, LineTail> {
+        let (histograms, statistics) = match (histograms, statistics) {
+            (Some(histograms), Some(statistics)) => (histograms, statistics),
+            _ => return HashMap::new(),
+        };
+        histograms
+            .iter()
+            .filter_map(|(line, histogram)| {
+                let tail = LineTail {
+                    p50: trace_structs::histogram_percentile(histogram, 50.0)?,
+                    p99: trace_structs::histogram_percentile(histogram, 99.0)?,
+                    max: statistics.get(line)?.max,
+                };
+                Some((*line, tail))
+            })
+            .collect()
+    }
+
+    /// Show what the latency column is set to show, see `latency_statistic`
+    /// and `show_off_cpu`
+    fn update_latency_column(siv: &mut Cursive) {
+        let controller = siv
+            .user_data::<Controller>()
+            .expect("Bug: Controller does not exist");
+        let mut title = controller
+            .latency_statistic
+            .column_title(&controller.messages)
+            .to_string();
+        let show_off_cpu = controller.show_off_cpu;
+        if show_off_cpu {
+            title = controller
+                .messages
+                .format("column.latency_off_cpu", &[("latency", &title)]);
+        }
+        views::set_latency_column(
+            &mut siv
+                .find_name::<views::SourceView>("source_view")
+                .expect("Bug: source_view does not exist"),
+            &title,
+            show_off_cpu,
+        );
+        let controller = siv
+            .user_data::<Controller>()
+            .expect("Bug: Controller does not exist");
+        if controller.scrubber.is_some() {
+            Controller::show_snapshot(siv);
+        } else {
+            Controller::show_live_lines(siv);
+        }
+    }
+
+    /// Stop collecting what the statistics popup showed, once it is closed
+    fn close_line_stats(siv: &mut Cursive) {
+        let controller = siv
+            .user_data::<Controller>()
+            .expect("Bug: Controller does not exist");
+        controller.stats_line = None;
+        controller.update_line_histograms();
+    }
+
+    /// Show the latest histogram of the selected line, or of the whole
+    /// function if the line isn't traced
+    fn update_histogram_pane(siv: &mut Cursive) {
+        let row = siv
+            .find_name::<views::SourceView>("source_view")
+            .expect("Bug: source_view does not exist")
+            .row();
+        let mut hview = siv
+            .find_name::<views::HistogramPane>("histogram_pane")
+            .expect("Bug: histogram_pane does not exist");
+        let controller = siv
+            .user_data::<Controller>()
+            .expect("Bug: Controller does not exist");
+        let histograms = match &controller.line_histograms {
+            Some(histograms) => histograms,
+            None => return,
+        };
+        let frame = controller.trace_stack.get_current_frame();
+        let line = row
+            .map(|row| row as u32 + 1)
+            .filter(|line| histograms.contains_key(line))
+            .unwrap_or(frame.get_source_line());
+        let messages = &controller.messages;
+        let text = match histograms.get(&line) {
+            Some(hist) if !hist.trim().is_empty() => views::formatting::annotate_percentiles(hist),
+            Some(_) => messages.get("histogram.empty").to_string(),
+            None => messages.get("histogram.line_gathering").to_string(),
+        };
+        views::set_histogram_pane(
+            &mut hview,
+            &messages.format(
+                "histogram.line_result",
+                &[("line", &line), ("histogram", &text)],
+            ),
+        );
+    }
+
+    /// Show the latest aggregates of the current trace, if there are any
+    fn show_live_lines(siv: &mut Cursive) {
+        let mut sview = siv
+            .find_name::<views::SourceView>("source_view")
+            .expect("Bug: source_view does not exist");
+        let controller = siv
+            .user_data::<Controller>()
+            .expect("Bug: Controller does not exist");
+        let snapshots = &controller.snapshots;
+        if snapshots.last().map_or(false, |s| {
+            controller.trace_stack.is_counter_current(s.counter)
+        }) {
+            Controller::show_lines(
+                &mut sview,
+                snapshots,
+                snapshots.len() - 1,
+                &controller.trace_stack.get_current_frame(),
+                controller.show_count_delta,
+                controller.latency_statistic,
+                controller.counter_column,
+            );
+        }
+    }
+
+    /// Show the snapshot selected in time travel mode. Leaves time travel mode
+    /// if the snapshots are from a trace that is no longer current.
+    fn show_snapshot(siv: &mut Cursive) {
+        let controller = siv
+            .user_data::<Controller>()
+            .expect("Bug: Controller does not exist");
+        let is_current = controller.snapshots.last().map_or(false, |s| {
+            controller.trace_stack.is_counter_current(s.counter)
+        });
+        if !is_current {
+            Controller::leave_time_travel(siv);
+            return;
+        }
+
+        let mut sview = siv
+            .find_name::<views::SourceView>("source_view")
+            .expect("Bug: source_view does not exist");
+        let mut fview = siv
+            .find_name::<views::FooterView>("footer_view")
+            .expect("Bug: footer_view does not exist");
+        let controller = siv
+            .user_data::<Controller>()
+            .expect("Bug: Controller does not exist");
+        let scrubber = match &mut controller.scrubber {
+            Some(scrubber) => scrubber,
+            None => return,
+        };
+        scrubber.index = scrubber.index.min(controller.snapshots.len() - 1);
+        let snapshot = &controller.snapshots[scrubber.index];
+        let latest = controller.snapshots.last().unwrap();
+        // Lines may have started being traced after this snapshot
+        for &line in latest.lines.keys() {
+            if !snapshot.lines.contains_key(&line) {
+                Controller::set_line_state(
+                    &mut sview,
+                    line,
+                    TraceState::Untraced,
+                    TraceState::Untraced,
+                );
+            }
+        }
+        Controller::show_lines(
+            &mut sview,
+            &controller.snapshots,
+            scrubber.index,
+            &controller.trace_stack.get_current_frame(),
+            controller.show_count_delta,
+            controller.latency_statistic,
+            controller.counter_column,
+        );
+        views::set_footer_view_with_warning(
+            &mut fview,
+            &format!(
+                "Aggregates as of {}s of {}s",
+                snapshot.time.as_secs(),
+                latest.time.as_secs()
+            ),
+            "[time travel] Left/Right to move, t to return to live",
+        );
+    }
+
+    /// Move the snapshot shown in time travel mode by `step` seconds
+    fn move_scrubber(siv: &mut Cursive, step: isize) {
+        let controller = siv
+            .user_data::<Controller>()
+            .expect("Bug: Controller does not exist");
+        match &mut controller.scrubber {
+            // Moving past the latest snapshot is handled in `show_snapshot`
+            Some(scrubber) => scrubber.index = (scrubber.index as isize + step).max(0) as usize,
+            None => return,
+        }
+        Controller::show_snapshot(siv);
+    }
+
+    /// Leave time travel mode, going back to showing live aggregates
+    fn leave_time_travel(siv: &mut Cursive) {
+        let controller = siv
+            .user_data::<Controller>()
+            .expect("Bug: Controller does not exist");
+        let scrubber = match controller.scrubber.take() {
+            Some(scrubber) => scrubber,
+            None => return,
+        };
+        // The footer was already replaced if the function changed
+        if controller.trace_stack.get_current_function() == scrubber.function {
+            siv.call_on_name("footer_view", |fview: &mut views::FooterView| {
+                views::set_footer_view_styled(fview, scrubber.footer)
+            });
+        }
+        Controller::show_live_lines(siv);
+    }
+
+    /// Stop waiting to capture a single call, if we are
+    fn kill_single_call(&mut self) {
+        if let Some(pid) = self.single_call_pid.take() {
+            unsafe {
+                libc::kill(pid as i32, libc::SIGTERM);
+            }
+        }
+    }
+
+    /// Stop capturing slow calls, if we are
+    fn kill_outliers(&mut self) {
+        if let Some(outliers) = self.outliers.take() {
+            unsafe {
+                libc::kill(outliers.pid as i32, libc::SIGTERM);
+            }
+        }
+    }
+
+    /// Start capturing calls of the current function taking at least
+    /// `threshold`, shown in a popup
+    fn capture_outliers(siv: &mut Cursive, threshold: Duration) {
+        let controller = siv
+            .user_data::<Controller>()
+            .expect("Bug: Controller does not exist");
+        let function = controller.trace_stack.get_current_function();
+        let backend = controller.trace_stack.get_backend();
+        let backend_name = backend.name();
+        let child = backend
+            .run_command(&controller.trace_stack.get_outliers_expr(threshold))
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn();
+        let mut child = match child {
+            Ok(child) => child,
+            Err(err) => {
+                siv.add_layer(views::new_dialog(&format!(
+                    "{} failed to start: {}",
+                    backend_name, err
+                )));
+                return;
+            }
+        };
+        let pid = child.id();
+        let stdout = child.stdout.take().expect("Bug: stdout is not piped");
+        let tx = controller.tx.clone();
+        std::thread::spawn(move || {
+            let mut record: Option<String> = None;
+            for line in std::io::BufReader::new(stdout).lines() {
+                let line = match line {
+                    Ok(line) => line,
+                    Err(_) => break,
+                };
+                if line.starts_with("Outlier ") {
+                    record = Some(String::new());
+                }
+                if let Some(record) = &mut record {
+                    record.push_str(&line);
+                    record.push('\n');
+                }
+                if line == "End" {
+                    let outlier = record
+                        .take()
+                        .and_then(|record| TraceStack::parse_outlier(&record));
+                    if let Some(outlier) = outlier {
+                        // Controller may have exited in the meantime
+                        if tx
+                            .send(Event::Outlier {
+                                pid,
+                                result: Ok(outlier),
+                            })
+                            .is_err()
+                        {
+                            return;
+                        }
+                    }
+                }
+            }
+            let error = match child.wait_with_output() {
+                Ok(output) => format!(
+                    "{} exited, status: {:?}, stderr:\n{}",
+                    backend_name,
+                    output.status,
+                    String::from_utf8_lossy(&output.stderr)
+                ),
+                Err(err) => format!("Failed to wait for {}: {}", backend_name, err),
+            };
+            let _ = tx.send(Event::Outlier {
+                pid,
+                result: Err(error.into()),
+            });
+        });
+        let outliers = Outliers {
+            pid,
+            function,
+            threshold,
+            calls: VecDeque::new(),
+        };
+        let text = Controller::outliers_text(&outliers);
+        controller.outliers = Some(outliers);
+        let messages = Controller::messages(siv);
+        siv.add_layer(views::new_text_dialog_view(
+            &messages,
+            &text,
+            "outliers_view",
+            |siv| {
+                siv.user_data::<Controller>()
+                    .expect("Bug: Controller does not exist")
+                    .kill_outliers();
+                siv.pop_layer();
+            },
+        ));
+    }
+
+    /// Captured slow calls, latest first
+    fn outliers_text(outliers: &Outliers) -> String {
+        let threshold = views::formatting::format_latency(outliers.threshold);
+        if outliers.calls.is_empty() {
+            return format!(
+                "Waiting for calls of {} taking at least {}...",
+                outliers.function, threshold
+            );
+        }
+        let mut text = vec![format!(
+            "Last {} calls of {} taking at least {}, latest first",
+            outliers.calls.len(),
+            outliers.function,
+            threshold
+        )];
+        for outlier in outliers.calls.iter().rev() {
+            text.push(String::new());
+            text.push(format!(
+                "{}  {}  thread {}",
+                outlier.time,
+                views::formatting::format_latency(outlier.duration),
+                outlier.thread
+            ));
+            text.push(format!("  Arguments: {}", outlier.arguments));
+            if let Some(stack) = &outlier.stack {
+                text.push(format!("  Called from:{}", stack));
+            }
+        }
+        text.join("\n")
+    }
+
+    /// Set filter on the current function, see `TraceStack::set_current_filter`
+    fn set_filter(siv: &mut Cursive, filter: String, is_ret_filter: bool) -> Result<(), Error> {
+        siv.user_data::<Controller>()
+            .expect("Bug: Controller does not exist")
+            .trace_stack
+            .set_current_filter(filter.clone(), is_ret_filter)?;
+        let command = if is_ret_filter {
+            ControlCommand::RetFilter(filter)
+        } else {
+            ControlCommand::Filter(filter)
+        };
+        Controller::record(siv, command);
+        Ok(())
+    }
+
+    fn set_process_filter(siv: &mut Cursive, filter: ProcessFilter) -> Result<(), Error> {
+        siv.user_data::<Controller>()
+            .expect("Bug: Controller does not exist")
+            .trace_stack
+            .set_process_filter(filter.clone())?;
+        Controller::record(siv, ControlCommand::ProcessFilter(filter));
+        Ok(())
+    }
+
+    /// Restart trace, clearing current aggregates
+    fn restart(siv: &mut Cursive) {
+        let controller = siv
+            .user_data::<Controller>()
+            .expect("Bug: Controller does not exist");
+        controller.tracer.rerun_tracer();
+        controller.losses = Losses::default();
+        Controller::update_losses(siv);
+        Controller::record(siv, ControlCommand::Restart);
+    }
+
+    /// Show the data dropped while tracing next to the footer, if any
+    fn update_losses(siv: &mut Cursive) {
+        let losses = siv
+            .user_data::<Controller>()
+            .expect("Bug: Controller does not exist")
+            .losses;
+        let mut counts = Vec::new();
+        if losses.lost_events > 0 {
+            counts.push(format!("{} events lost", losses.lost_events));
+        }
+        if losses.map_full > 0 {
+            counts.push(format!("{} map updates failed", losses.map_full));
+        }
+        let warning = if counts.is_empty() {
+            None
+        } else {
+            Some(format!("[!] {}, press E", counts.join(", ")))
+        };
+        siv.call_on_name("losses_view", |lview: &mut views::LossesView| {
+            views::set_losses_view(lview, warning.as_deref())
+        });
+    }
+
+    /// Explain the data dropped while tracing and offer ways to avoid it
+    fn show_losses(siv: &mut Cursive) {
+        let controller = siv
+            .user_data::<Controller>()
+            .expect("Bug: Controller does not exist");
+        let losses = controller.losses;
+        let scale = controller.trace_stack.get_buffer_scale();
+        let can_resize = controller.trace_stack.get_backend().is_local_bpftrace();
+        if losses.is_empty() {
+            siv.add_layer(views::new_dialog(
+                "No data was dropped since tracing was last restarted.",
+            ));
+            return;
+        }
+        let mut text = vec![
+            "bpftrace dropped data while tracing, so results are incomplete:".to_string(),
+            String::new(),
+        ];
+        if losses.lost_events > 0 {
+            text.push(format!(
+                "- {} events were lost because the output buffer was full. Reports go through it, so some intervals are missing.",
+                losses.lost_events
+            ));
+        }
+        if losses.map_full > 0 {
+            text.push(format!(
+                "- {} map updates failed because a map was full. Calls from many threads at once, or grouped by many distinct values, may not be counted.",
+                losses.map_full
+            ));
+        }
+        text.push(String::new());
+        if can_resize {
+            text.push(format!(
+                "Maps and buffers are {}x their default size. Doubling them uses more kernel memory and restarts tracing.",
+                scale
+            ));
+        } else {
+            text.push(
+                "Sizes of maps and buffers can only be changed with local bpftrace.".to_string(),
+            );
+        }
+        text.push(
+            "Tracing fewer calls also helps: trace only 1 in N calls of a busy line (n), or sample CPU time of the current function instead."
+                .to_string(),
+        );
+        let messages = Controller::messages(siv);
+        let mut dialog = Dialog::text(text.join("\n")).title("Dropped data");
+        if can_resize {
+            dialog = dialog.button(messages.get("button.double_sizes"), move |siv| {
+                siv.pop_layer();
+                let controller = siv
+                    .user_data::<Controller>()
+                    .expect("Bug: Controller does not exist");
+                controller.losses = Losses::default();
+                controller
+                    .trace_stack
+                    .set_buffer_scale(scale.saturating_mul(2));
+                Controller::update_losses(siv);
+            });
+        }
+        siv.add_layer(
+            dialog
+                .button(messages.get("button.sample"), |siv| {
+                    siv.pop_layer();
+                    Controller::sample_cpu(siv);
+                })
+                .button(messages.get("button.close"), |siv| {
+                    siv.pop_layer();
+                }),
+        );
+    }
+
+    /// JSON representation of the results currently displayed in the source
+    /// view.
+    fn snapshot(trace_stack: &TraceStack, sview: &mut views::SourceView) -> serde_json::Value {
+        let frame_info = trace_stack.get_current_frame();
+        let lines: serde_json::Map<String, serde_json::Value> = sview
+            .borrow_items()
+            .iter()
+            .filter_map(|item| match (item.latency, item.frequency) {
+                (TraceState::Untraced, TraceState::Untraced) => None,
+                (latency, frequency) => {
+                    let latency = match latency {
+                        TraceState::Traced(l) => serde_json::json!(l.as_nanos() as u64),
+                        _ => serde_json::Value::Null,
+                    };
+                    let frequency = match frequency {
+                        TraceState::Traced(f) => serde_json::json!(f),
+                        _ => serde_json::Value::Null,
+                    };
+                    Some((
+                        item.line_number.to_string(),
+                        serde_json::json!({"latency_ns": latency, "frequency": frequency}),
+                    ))
+                }
+            })
+            .collect();
+        serde_json::json!({
+            "function": trace_stack.get_current_function().to_string(),
+            "source_file": frame_info.get_source_file(),
+            "lines": lines,
+        })
+    }
+
+    fn setup_function(
+        program: &Program,
+        function: FunctionName,
+        sview: &mut views::SourceView,
+        fview: &mut views::FooterView,
+    ) -> Result<(FrameInfo, bool), Error> {
+        let frame_info = Controller::create_frame_info(program, function)?;
+        let source_found = Controller::setup_source_view(program, &frame_info, sview, fview)?;
+        Ok((frame_info, source_found))
+    }
+
+    /// Returns whether source code was found for `frame_info`, blank lines are
+    /// shown otherwise
+    fn setup_source_view(
+        program: &Program,
+        frame_info: &FrameInfo,
+        sview: &mut views::SourceView,
+        fview: &mut views::FooterView,
+    ) -> Result<bool, Error> {
+        let source_file = frame_info.get_source_file();
+        let local_path = program.local_source_path(source_file);
+        let is_local = std::path::Path::new(local_path.as_ref()).is_file();
+        let embedded_source = if is_local {
+            None
+        } else {
+            program.get_embedded_source(frame_info.get_function(), source_file)
+        };
+        let git_source = if is_local || embedded_source.is_some() {
+            None
+        } else {
+            program.get_git_source(source_file)
+        };
+        let downloaded_source = if is_local || embedded_source.is_some() || git_source.is_some() {
+            None
+        } else {
+            program.get_debuginfod_source(frame_info.get_function(), source_file)
+        };
+        let source_text = embedded_source
+            .as_deref()
+            .or(git_source.as_ref().map(|(source, _)| source.as_str()));
+        let source_code = match (source_text, &downloaded_source) {
+            (Some(source), _) => source.lines().map(String::from).collect(),
+            (None, Some(path)) => Controller::read_source_code(path, frame_info.max_line()),
+            (None, None) => Controller::read_source_code(&local_path, frame_info.max_line()),
+        };
+        views::set_source_view(
+            sview,
+            source_code,
+            frame_info.get_source_line(),
+            frame_info.called_lines(),
+            frame_info.inlined_lines(),
+        );
+        if embedded_source.is_some() {
+            views::set_footer_view(fview, &format!("{} [source from debug info]", source_file));
+        } else if let Some((_, rev)) = git_source {
+            views::set_footer_view(
+                fview,
+                &format!("{} [source from git at {}]", source_file, rev),
+            );
+        } else if downloaded_source.is_some() {
+            views::set_footer_view(fview, &format!("{} [source from debuginfod]", source_file));
+        } else if decompiler::is_decompiled(source_file) {
+            let kind = if source_file.ends_with(".s") {
+                "disassembled"
+            } else {
+                "decompiled"
+            };
+            views::set_footer_view(fview, &format!("{} [{}]", frame_info.get_function(), kind));
+        } else if !is_local {
+            views::set_footer_view_with_warning(
+                fview,
+                source_file,
+                "[!] Source not found, press s to remap its path",
+            );
+            return Ok(false);
+        } else {
+            let location = if local_path != source_file {
+                format!("{} [remapped from {}]", local_path, source_file)
+            } else {
+                source_file.to_string()
+            };
+            match program.check_source(frame_info.get_function(), source_file, &local_path) {
+                Some(reason) => views::set_footer_view_with_warning(
+                    fview,
+                    &location,
+                    &format!("[!] Source may not match binary: {}", reason),
+                ),
+                None => views::set_footer_view(fview, &location),
+            }
+        }
+        Ok(true)
+    }
+
+    /// Read lines of `source_file`, falling back to `max_line` empty lines if
+    /// it cannot be read.
+    pub fn read_source_code(source_file: &str, max_line: u32) -> Vec<String> {
+        match std::fs::File::open(source_file) {
+            Ok(file) => {
+                // FIXME we can cache file contents
+                std::io::BufReader::new(file)
+                    .lines()
+                    .map(|l| l.unwrap())
+                    .collect()
+            }
+            Err(_) => {
+                // TODO show error and confirm user wants to display empty lines
+                // instead
+                vec![String::new(); max_line as usize]
+            }
+        }
+    }
+
+    /// Results of a function picker before anything is typed: `hint`, followed
+    /// by suggestions from previous sessions
+    /// Wrap `submit_fn` of a function picker with grouped results (see
+    /// `Searcher::setup_search`), so that submitting a group of overloads
+    /// first picks one of them. Groups submitted along with other functions
+    /// are expanded into all their overloads.
+    fn pick_overload<F>(submit_fn: F) -> impl Fn(&mut Cursive, Vec<SymbolInfo>) + 'static
+    where
+        F: Fn(&mut Cursive, Vec<SymbolInfo>) + 'static,
+    {
+        let submit_fn = Rc::new(submit_fn);
+        move |siv: &mut Cursive, symbols: Vec<SymbolInfo>| {
+            if let [symbol] = symbols.as_slice() {
+                if !symbol.overloads.is_empty() {
+                    let submit_fn = Rc::clone(&submit_fn);
+                    siv.add_layer(views::new_simple_search_view(
+                        "Select the overload",
+                        symbol.clone().expand_overloads(),
+                        move |siv: &mut Cursive, symbol: &SymbolInfo| {
+                            submit_fn(siv, vec![symbol.clone()])
+                        },
+                    ));
+                    return;
+                }
+            }
+            submit_fn(
+                siv,
+                symbols
+                    .into_iter()
+                    .flat_map(SymbolInfo::expand_overloads)
+                    .collect(),
+            );
+        }
+    }
+
+    fn empty_search_results(
+        program: &Program,
+        history: &History,
+        hint: &str,
+    ) -> Vec<(String, Option<SymbolInfo>)> {
+        let suggestions = history.suggestions();
+        let mut symbols: Vec<_> = program
+            .symbols_generator()
+            .into_iter()
+            .filter_map(|s| {
+                let index = suggestions.iter().position(|&name| name == s.name.0)?;
+                Some((index, s.clone()))
+            })
+            .collect();
+        symbols.sort_by_key(|&(index, _)| index);
+        std::iter::once((hint.to_string(), None))
+            .chain(symbols.into_iter().map(|(_, s)| (s.to_string(), Some(s))))
+            .collect()
+    }
+
+    /// Describe how often each caller of `function` reached `line`, as (return
+    /// address, count)
+    fn callers_text(
+        program: &Program,
+        function: FunctionName,
+        line: u32,
+        callers: &[(u64, u64)],
+    ) -> String {
+        let total: u64 = callers.iter().map(|(_, count)| count).sum();
+        let mut text = vec![format!(
+            "Callers of {} reaching line {}: {} distinct, {} calls",
+            function,
+            line,
+            callers.len(),
+            total
+        )];
+        for &(address, count) in callers {
+            let caller = match program.get_function_containing(address) {
+                Some(caller) => {
+                    let offset = address - program.get_address(caller);
+                    // Return address is just past the call instruction
+                    match program.get_location(address - 1) {
+                        Some(l) => format!(
+                            "{}+{:#x} ({}:{})",
+                            caller,
+                            offset,
+                            l.file.unwrap(),
+                            l.line.unwrap()
+                        ),
+                        None => format!("{}+{:#x}", caller, offset),
+                    }
+                }
+                None => format!("{:#x} (outside of program)", address),
+            };
+            text.push(format!(
+                "{:5.1}%  {:>8}  {}",
+                count as f64 / total as f64 * 100.0,
+                count,
+                caller
+            ));
+        }
+        text.join("\n")
+    }
+
+    /// Describe how often each value was returned on `line` of `function`,
+    /// and how the latency of those calls compares, so that error paths stand
+    /// out
+    fn return_values_text(
+        function: FunctionName,
+        line: u32,
+        retvals: &[ReturnValueCount],
+    ) -> String {
+        let total: u64 = retvals.iter().map(|r| r.count).sum();
+        let percent = |count: u64| count as f64 / total as f64 * 100.0;
+        let negative: u64 = retvals
+            .iter()
+            .filter(|r| r.value < 0)
+            .map(|r| r.count)
+            .sum();
+        let zero: u64 = retvals
+            .iter()
+            .filter(|r| r.value == 0)
+            .map(|r| r.count)
+            .sum();
+        let mut text = vec![format!(
+            "Values returned on line {} of {}: {} distinct, {} calls",
+            line,
+            function,
+            retvals.len(),
+            total
+        )];
+        if total > 0 {
+            text.push(format!(
+                "Negative (error codes): {:.1}%, zero (NULL or success): {:.1}%",
+                percent(negative),
+                percent(zero)
+            ));
+        }
+        text.push(String::new());
+        for r in retvals {
+            text.push(format!(
+                "{:5.1}%  {:>8}  {:>10}  {:>20} ({:#x})",
+                percent(r.count),
+                r.count,
+                views::formatting::format_latency(r.duration / u32::try_from(r.count).unwrap()),
+                r.value,
+                r.value
+            ));
+        }
+        text.join("\n")
+    }
+
+    /// Arguments of the call on `line` of the current function (or of the
+    /// function itself on its own line) that can be captured, or the message
+    /// to show if there are none
+    fn argument_columns(&self, line: u32) -> Result<Vec<ArgumentColumn>, String> {
+        let frame = self.trace_stack.get_current_frame();
+        let function = frame.get_function();
+        let callee = if line == frame.get_source_line() {
+            Some(function)
+        } else {
+            match frame.get_traced_callsite(line).map(|ci| &ci.instruction) {
+                Some(InstructionType::Function(callee))
+                | Some(InstructionType::DynamicSymbol(callee)) => Some(*callee),
+                Some(_) => None,
+                None => {
+                    return Err(self.messages.format(
+                        "args.not_traced",
+                        &[("line", &line), ("function", &function)],
+                    ))
+                }
+            }
+        };
+        let columns = match callee.and_then(|callee| self.program.get_parameters(callee)) {
+            Some((return_type, parameters)) => {
+                trace_structs::argument_columns(return_type.as_ref(), &parameters)
+            }
+            None => Vec::new(),
+        };
+        if columns.is_empty() {
+            return Err(self.messages.format(
+                "args.unknown",
+                &[(
+                    "function",
+                    &callee.map_or("the called function".to_string(), |f| f.to_string()),
+                )],
+            ));
+        }
+        Ok(columns)
+    }
+
+    /// Table of traced calls grouped by the value of `column`
+    fn groups_text(
+        function: FunctionName,
+        line: u32,
+        column: &ArgumentColumn,
+        groups: &[GroupCount],
+    ) -> String {
+        let total: u64 = groups.iter().map(|g| g.count).sum();
+        let mut text = vec![
+            format!(
+                "Calls on line {} of {} by {}: {} distinct, {} calls",
+                line,
+                function,
+                column,
+                groups.len(),
+                total
+            ),
+            String::new(),
+        ];
+        for g in groups {
+            let value = match g.value.parse::<u64>() {
+                Ok(address) if column.is_address => format!("{:#x}", address),
+                _ if g.value.is_empty() => "\"\"".to_string(),
+                _ => g.value.clone(),
+            };
+            text.push(format!(
+                "{:5.1}%  {:>8}  {:>10}  {:>10}  {}",
+                g.count as f64 / total as f64 * 100.0,
+                g.count,
+                views::formatting::format_latency(g.duration / u32::try_from(g.count).unwrap()),
+                views::formatting::format_latency(g.duration),
+                value
+            ));
+        }
+        text.join("\n")
+    }
+
+    /// Table of traced calls grouped by the thread that made them, with the
+    /// rate of calls over the `time` in seconds they were traced for
+    fn threads_text(function: FunctionName, line: u32, time: f32, groups: &[GroupCount]) -> String {
+        let total: u64 = groups.iter().map(|g| g.count).sum();
+        let mut text = vec![
+            format!(
+                "Calls on line {} of {} by thread: {} threads, {} calls",
+                line,
+                function,
+                groups.len(),
+                total
+            ),
+            String::new(),
+            format!(
+                "{:>6}  {:>8}  {:>9}  {:>10}  {:>10}  {}",
+                "Calls", "Count", "Calls/s", "Average", "Total", "Thread"
+            ),
+        ];
+        for g in groups {
+            // The thread may have exited since
+            let name = std::fs::read_to_string(format!("/proc/{}/comm", g.value))
+                .map_or(String::new(), |comm| format!(" ({})", comm.trim_end()));
+            text.push(format!(
+                "{:5.1}%  {:>8}  {:>9.1}  {:>10}  {:>10}  {}{}",
+                g.count as f64 / total as f64 * 100.0,
+                g.count,
+                g.count as f32 / time.max(1.0),
+                views::formatting::format_latency(g.duration / u32::try_from(g.count).unwrap()),
+                views::formatting::format_latency(g.duration),
+                g.value,
+                name
+            ));
+        }
+        text.join("\n")
+    }
+
+    /// All statistics of `line`, those from its histogram and `statistics`
+    /// only once they were collected
+    fn line_stats_text(
+        function: FunctionName,
+        line: u32,
+        trace: Option<&events::TraceCumulative>,
+        time: f32,
+        histogram: Option<&String>,
+        statistics: Option<&LineStatistics>,
+    ) -> String {
+        let trace = match trace {
+            Some(trace) if trace.count > 0 => trace,
+            _ => return format!("No calls on line {} of {} yet", line, function),
+        };
+        let format_latency = |latency: Option<Duration>| {
+            latency.map_or("-".to_string(), views::formatting::format_latency)
+        };
+        let percentile = |percentile: f64| {
+            format_latency(
+                histogram.and_then(|h| trace_structs::histogram_percentile(h, percentile)),
+            )
+        };
+        let rows = [
+            ("Count", trace.count.to_string()),
+            (
+                "Frequency",
+                views::formatting::format_frequency(trace.count as f32 / time),
+            ),
+            ("Total", format_latency(Some(trace.duration))),
+            (
+                "Average",
+                format_latency(Some(trace.duration / u32::try_from(trace.count).unwrap())),
+            ),
+            ("Min", format_latency(statistics.map(|s| s.min))),
+            ("Max", format_latency(statistics.map(|s| s.max))),
+            ("Std dev", format_latency(statistics.map(|s| s.std_dev))),
+            ("p50", percentile(50.0)),
+            ("p90", percentile(90.0)),
+            ("p99", percentile(99.0)),
+        ];
+        std::iter::once(format!("Line {} of {}", line, function))
+            .chain(std::iter::once(String::new()))
+            .chain(
+                rows.iter()
+                    .map(|(label, value)| format!("  {:<10} {:>10}", label, value)),
+            )
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+
+    /// Table of the most frequent values of each argument in `columns`
+    fn arguments_text(
+        function: FunctionName,
+        line: u32,
+        columns: &[ArgumentColumn],
+        values: &[Vec<(String, u64)>],
+    ) -> String {
+        let mut text = vec![format!("Arguments on line {} of {}", line, function)];
+        for (column, values) in columns.iter().zip(values) {
+            let total: u64 = values.iter().map(|(_, count)| count).sum();
+            text.push(String::new());
+            text.push(column.to_string());
+            for (value, count) in values {
+                let value = match value.parse::<u64>() {
+                    Ok(address) if column.is_address => format!("{:#x}", address),
+                    _ if value.is_empty() => "\"\"".to_string(),
+                    _ => value.clone(),
+                };
+                text.push(format!(
+                    "  {:5.1}%  {:>8}  {}",
+                    *count as f64 / total as f64 * 100.0,
+                    count,
+                    value
+                ));
+            }
+        }
+        text.join("\n")
+    }
+
+    /// Show `symbols` side by side, e.g. to pick between overloads
+    fn show_comparison(
+        siv: &mut Cursive,
+        program: &Program,
+        messages: &Messages,
+        symbols: &[SymbolInfo],
+    ) {
+        let text = Controller::comparison_text(program, symbols);
+        siv.add_layer(views::new_text_dialog_view(
+            messages,
+            &text,
+            "compare_view",
+            |siv| {
+                siv.pop_layer();
+            },
+        ));
+    }
+
+    fn comparison_text(program: &Program, symbols: &[SymbolInfo]) -> String {
+        symbols
+            .iter()
+            .map(|symbol| {
+                let address = program.get_address(symbol.name);
+                let source = match program.get_location(address) {
+                    Some(location) => {
+                        format!("{}:{}", location.file.unwrap(), location.line.unwrap())
+                    }
+                    None => "unknown".to_string(),
+                };
+                format!(
+                    "{}\n  symbol: {}\n  address: {:#x}\n  source: {}",
+                    symbol, symbol.name.0, address, source
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\n\n")
+    }
+
+    /// Symbol of the dynamic symbol `function` in the shared library defining
+    /// it, which is loaded if needed
+    fn load_dynamic_symbol(
+        program: &mut Program,
+        function: FunctionName,
+    ) -> Result<SymbolInfo, Error> {
+        let libraries = match templates::linked_libraries(&program.file_path) {
+            Ok(libraries) if !libraries.is_empty() => libraries,
+            result => {
+                if let Err(err) = result {
+                    log::warn!("{}", err);
+                }
+                // ldd can't resolve them, e.g. when it's missing
+                program
+                    .needed_libraries()
+                    .iter()
+                    .filter_map(|name| program::find_shared_library(name))
+                    .collect()
+            }
+        };
+        program.load_dynamic_symbol(function, &libraries)
+    }
+
+    fn create_frame_info(program: &Program, function: FunctionName) -> Result<FrameInfo, Error> {
+        // Addresses of calls are relative to the shared library the function
+        // is in, if any
+        let program = program.object(function);
+        let location = program.get_location(program.get_address(function));
+        // Fall back to pseudo-code when there is no source information (e.g.
+        // closed third-party libraries)
+        let decompiled = match location {
+            Some(_) => None,
+            None => program.decompile(function).transpose()?,
+        };
+        let (source_file, source_line) = match (&location, &decompiled) {
+            (Some(location), _) => (location.file.unwrap(), location.line.unwrap()),
+            (None, Some(decompiled)) => (decompiled.path.as_str(), 1),
+            (None, None) => return Err(format!("Failed to get source information corresponding to function {}, please ensure {} has appropriate debugging symbols (or pass --decompiler)", function, program.file_path).into()),
+        };
+        log::info!(
+            "Function {} is at {}:{}",
+            function,
+            source_file,
+            source_line
+        );
+
+        // TODO
+        let (start_address, _) = program.get_data(function).unwrap();
+
+        let mut line_to_callsites = HashMap::<u32, Vec<CallInstruction>>::new();
+        let mut unattached_callsites = Vec::<CallInstruction>::new();
+
+        // Source is still shown if calls can't be found
+        let mut calls = Vec::<(u64, CallInstruction)>::new();
+        for call in program.get_calls(function) {
+            let relative_ip = u32::try_from(call.ip - start_address).unwrap();
+            let call_instruction = match call.target {
+                CallTarget::Address(address, mode) => {
+                    match program.get_function_for_address(address) {
+                        Some(callee) if program.is_dynamic_symbol_address(address) => {
+                            CallInstruction::dynamic_symbol(relative_ip, call.length, callee)
+                        }
+                        // A call continuing in a different mode than the callee
+                        // is in can't be to it, e.g. a BL to a Thumb function
+                        // from ARM code
+                        Some(callee) if program.get_isa_mode(callee) == mode => {
+                            CallInstruction::function(relative_ip, call.length, callee)
+                        }
+                        _ if call.tail_call => continue,
+                        _ => CallInstruction::unknown(relative_ip, call.length),
+                    }
+                }
+                CallTarget::Register(register, displacement) => {
+                    // TODO convert register string to bpftrace register
+                    CallInstruction::register(relative_ip, call.length, register, displacement)
+                }
+                CallTarget::Unknown => CallInstruction::unknown(relative_ip, call.length),
+            };
+            let call_instruction = if call.tail_call {
+                call_instruction.as_tail_call()
+            } else {
+                call_instruction
+            };
+            calls.push((call.ip, call_instruction));
+        }
+
+        for (ip, call_instruction) in calls {
+            let (line, call_instruction) = match &decompiled {
+                Some(decompiled) => (decompiled.get_line(ip), call_instruction),
+                None => {
+                    let location = program.get_location(ip).unwrap();
+                    if location.file.unwrap() == source_file {
+                        (location.line, call_instruction.with_column(location.column))
+                    } else {
+                        // This is an inlined call. We don't know which line it
+                        // corresponds to in the source file we are displaying.
+                        log::trace!(
+                            "Not displaying function call {} from {}:{} because it is not in current source file {}",
+                            call_instruction,
+                            location.file.unwrap(),
+                            location.line.unwrap(),
+                            source_file
+                        );
+                        (None, call_instruction)
+                    }
+                }
+            };
+            match line {
+                Some(line) => line_to_callsites
+                    .entry(line)
+                    .or_default()
+                    .push(call_instruction),
+                None => unattached_callsites.push(call_instruction),
+            }
+        }
+
+        // Inlined calls have no call instruction, but can be traced as the
+        // span of code they were inlined as
+        if decompiled.is_none() {
+            for span in program.get_inlined_spans(function) {
+                let call_instruction = CallInstruction::inlined(&span);
+                if span.call_file == source_file {
+                    line_to_callsites
+                        .entry(span.call_line)
+                        .or_default()
+                        .push(call_instruction);
+                } else {
+                    unattached_callsites.push(call_instruction);
+                }
+            }
+        }
+
+        // List calls in the order they appear on the line rather than the
+        // order the compiler emitted them in
+        for callsites in line_to_callsites.values_mut() {
+            callsites.sort_by_key(|ci| (ci.get_column(), ci.get_relative_ip()));
+        }
+
+        log::trace!("{:?}", line_to_callsites);
+        let frame_info = FrameInfo::new(
             function,
             String::from(source_file),
             source_line,
             line_to_callsites,
             unattached_callsites,
+            program.get_return_offsets(function),
+        );
+
+        Ok(frame_info)
+    }
+
+    pub fn set_line_state(
+        sview: &mut views::SourceView,
+        line: u32,
+        latency: TraceState<std::time::Duration>,
+        frequency: TraceState<f32>,
+    ) {
+        let item = sview.borrow_items_mut().get_mut(line as usize - 1).unwrap();
+        item.latency = latency;
+        item.frequency = frequency;
+    }
+
+    /// Add up `values` at offsets within the function of `frame` by the line
+    /// they are on, along with what was inlined from other files. Offsets of
+    /// `return_addresses` are looked up one byte earlier, since the return
+    /// address may be on the line after the call.
+    fn group_by_line<T: Default>(
+        &self,
+        frame: &FrameInfo,
+        values: impl IntoIterator<Item = (u64, T)>,
+        return_addresses: bool,
+        add: impl Fn(&mut T, T),
+    ) -> (BTreeMap<u32, T>, T) {
+        let function = frame.get_function();
+        let program = self.program.object(function);
+        let address = program.get_address(function);
+        let mut lines = BTreeMap::<u32, T>::new();
+        let mut elsewhere = T::default();
+        for (offset, value) in values {
+            let offset = if return_addresses { offset - 1 } else { offset };
+            let line = program
+                .get_location(address + offset)
+                .filter(|l| l.file.unwrap() == frame.get_source_file())
+                .and_then(|l| l.line);
+            match line {
+                Some(line) => add(lines.entry(line).or_default(), value),
+                // Inlined from another file
+                None => add(&mut elsewhere, value),
+            }
+        }
+        (lines, elsewhere)
+    }
+
+    /// Annotate lines with the results of an analysis, e.g. branches sampled
+    /// with `B`, replacing the annotations of the previous one
+    fn annotate_lines(siv: &mut Cursive, annotations: Vec<(u32, String)>) {
+        Controller::clear_line_annotations(siv);
+        let mut sview = siv
+            .find_name::<views::SourceView>("source_view")
+            .expect("Bug: source_view does not exist");
+        let mut annotated_lines = Vec::new();
+        for (line, annotation) in annotations {
+            Controller::set_line_annotation(&mut sview, line, Some(annotation));
+            annotated_lines.push(line);
+        }
+        siv.user_data::<Controller>()
+            .expect("Bug: Controller does not exist")
+            .annotated_lines = annotated_lines;
+    }
+
+    /// Clear the annotations of `annotated_lines`, leaving just the notes on
+    /// which of their calls are counted
+    fn clear_line_annotations(siv: &mut Cursive) {
+        let lines = std::mem::take(
+            &mut siv
+                .user_data::<Controller>()
+                .expect("Bug: Controller does not exist")
+                .annotated_lines,
         );
+        for line in lines {
+            Controller::update_callsite_annotation(siv, line);
+        }
+    }
+
+    fn set_line_annotation(sview: &mut views::SourceView, line: u32, annotation: Option<String>) {
+        if let Some(item) = sview.borrow_items_mut().get_mut(line as usize - 1) {
+            item.annotation = annotation;
+        }
+    }
+
+    /// Annotation for a line with branches that mispredict heavily, if enough
+    /// of them were sampled to tell
+    fn mispredict_annotation(counts: &BranchCounts) -> Option<String> {
+        let rate = counts.mispredicted as f64 / counts.taken as f64;
+        if counts.taken >= Controller::MIN_BRANCH_SAMPLES
+            && rate >= Controller::HEAVY_MISPREDICT_RATE
+        {
+            Some(format!(
+                "{:.0}% of {} branches mispredicted",
+                rate * 100.0,
+                counts.taken
+            ))
+        } else {
+            None
+        }
+    }
+
+    /// Summary of sampled branches of `function` by line, worst first
+    fn branch_text(
+        function: FunctionName,
+        lines: &BTreeMap<u32, BranchCounts>,
+        elsewhere: &BranchCounts,
+    ) -> String {
+        let total: u64 = lines.values().map(|c| c.taken).sum::<u64>() + elsewhere.taken;
+        if total == 0 {
+            return format!(
+                "No branches sampled in {}. It may not have run while sampling.",
+                function
+            );
+        }
+        let format_counts = |counts: &BranchCounts| {
+            format!(
+                "{:5.1}% mispredicted ({} of {})",
+                counts.mispredicted as f64 / counts.taken as f64 * 100.0,
+                counts.mispredicted,
+                counts.taken
+            )
+        };
+        let mut sorted_lines: Vec<_> = lines.iter().collect();
+        sorted_lines.sort_by_key(|(line, counts)| (std::cmp::Reverse(counts.mispredicted), **line));
+        Controller::by_line_text(
+            format!(
+                "Branches sampled in {}: {}, by line with most mispredictions first",
+                function, total
+            ),
+            sorted_lines
+                .into_iter()
+                .map(|(&line, counts)| (line, format_counts(counts)))
+                .collect(),
+            (elsewhere.taken > 0).then(|| format_counts(elsewhere)),
+        )
+    }
+
+    /// Text of `header` followed by a row for each of `lines`, in the order
+    /// given, and one for what was inlined from other files if anything was
+    fn by_line_text(header: String, lines: Vec<(u32, String)>, inlined: Option<String>) -> String {
+        let mut text = vec![header];
+        for (line, row) in lines {
+            text.push(format!("Line {:>5}: {}", line, row));
+        }
+        if let Some(inlined) = inlined {
+            text.push(format!("Inlined:    {}", inlined));
+        }
+        text.join("\n")
+    }
+
+    /// Summary of sampled CPU time of `function` by line, most first
+    fn cpu_samples_text(
+        function: FunctionName,
+        lines: &BTreeMap<u32, u64>,
+        elsewhere: u64,
+    ) -> String {
+        let total: u64 = lines.values().sum::<u64>() + elsewhere;
+        if total == 0 {
+            return format!(
+                "No CPU time sampled in {}. It may not have run while sampling.",
+                function
+            );
+        }
+        let format_count = |count: u64| {
+            format!(
+                "{:5.1}% ({} samples)",
+                count as f64 / total as f64 * 100.0,
+                count
+            )
+        };
+        let mut sorted_lines: Vec<_> = lines.iter().collect();
+        sorted_lines.sort_by_key(|(line, count)| (std::cmp::Reverse(**count), **line));
+        Controller::by_line_text(
+            format!(
+                "CPU time sampled in {}: {} samples, by line with most first",
+                function, total
+            ),
+            sorted_lines
+                .into_iter()
+                .map(|(&line, &count)| (line, format_count(count)))
+                .collect(),
+            (elsewhere > 0).then(|| format_count(elsewhere)),
+        )
+    }
+
+    fn lock_waits_text(
+        function: FunctionName,
+        lines: &BTreeMap<u32, LockWaits>,
+        elsewhere: LockWaits,
+    ) -> String {
+        let mut total = elsewhere;
+        for waits in lines.values() {
+            total.add(*waits);
+        }
+        if total.count == 0 {
+            return format!(
+                "No lock waits traced in {}. It may not have run, or not contended for locks.",
+                function
+            );
+        }
+        let format_waits = |waits: &LockWaits| {
+            format!(
+                "{:5.1}% {:>8} ({} waits)",
+                waits.duration.as_secs_f64() / total.duration.as_secs_f64() * 100.0,
+                views::formatting::format_latency(waits.duration),
+                waits.count
+            )
+        };
+        let mut sorted_lines: Vec<_> = lines.iter().collect();
+        sorted_lines.sort_by_key(|(line, waits)| (std::cmp::Reverse(waits.duration), **line));
+        Controller::by_line_text(
+            format!(
+                "Lock waits traced in {}: {} in {} waits, by line with most first",
+                function,
+                views::formatting::format_latency(total.duration).trim(),
+                total.count
+            ),
+            sorted_lines
+                .into_iter()
+                .map(|(&line, waits)| (line, format_waits(waits)))
+                .collect(),
+            (elsewhere.count > 0).then(|| format_waits(&elsewhere)),
+        )
+    }
+
+    fn syscalls_text(function: FunctionName, syscalls: &Syscalls) -> String {
+        if syscalls.function_calls == 0 {
+            return format!(
+                "No calls of {} returned while tracing. It may not have run, or its calls may take longer than {} seconds.",
+                function,
+                Controller::SYSCALL_SECONDS
+            );
+        }
+        let mut total = SyscallTimes::default();
+        for times in syscalls.syscalls.values() {
+            total.duration += times.duration;
+            total.count += times.count;
+            total.errors += times.errors;
+        }
+        let function_time = syscalls.function_time.as_secs_f64();
+        let mut text = vec![format!(
+            "{} calls of {} took {}, {:.1}% of which in {} syscalls. Syscalls with most time first:",
+            syscalls.function_calls,
+            function,
+            views::formatting::format_latency(syscalls.function_time),
+            total.duration.as_secs_f64() / function_time.max(f64::MIN_POSITIVE) * 100.0,
+            total.count
+        )];
+        let names = syscalls::syscall_names();
+        let mut sorted: Vec<_> = syscalls.syscalls.iter().collect();
+        sorted.sort_by_key(|(id, times)| (std::cmp::Reverse(times.duration), **id));
+        for (id, times) in sorted {
+            let name = names
+                .get(id)
+                .cloned()
+                .unwrap_or_else(|| format!("syscall {}", id));
+            let mut line = format!(
+                "{:<16} {:5.1}% {:>8} in {} calls, {} each",
+                name,
+                times.duration.as_secs_f64() / function_time.max(f64::MIN_POSITIVE) * 100.0,
+                views::formatting::format_latency(times.duration),
+                times.count,
+                views::formatting::format_latency(times.duration / times.count.max(1) as u32)
+            );
+            if times.errors > 0 {
+                line += &format!(", {} failed", times.errors);
+            }
+            text.push(line);
+        }
+        text.join("\n")
+    }
 
-        Ok(frame_info)
+    /// `heads` are the offsets of the loop heads of `function`, along with
+    /// their lines
+    fn loops_text(
+        function: FunctionName,
+        heads: &[(u32, Option<u32>)],
+        iterations: &LoopIterations,
+    ) -> String {
+        if iterations.function_calls == 0 {
+            return format!(
+                "No calls of {} returned while tracing. It may not have run, or its calls may take longer than {} seconds.",
+                function,
+                Controller::LOOP_SECONDS
+            );
+        }
+        let mut text = vec![format!(
+            "Iterations per call of the loops of {}, over {} calls:",
+            function, iterations.function_calls
+        )];
+        for &(head, line) in heads {
+            let location = match line {
+                Some(line) => format!("line {}", line),
+                None => "inlined".to_string(),
+            };
+            text.push(format!(
+                "{:<12} offset {:<6} {:>10.1} ({} in total)",
+                location,
+                head,
+                iterations.per_call(head),
+                iterations.iterations.get(&head).copied().unwrap_or(0)
+            ));
+        }
+        text.push(String::new());
+        text.push(
+            "Loops that check their condition before the first iteration count one more iteration than they run."
+                .to_string(),
+        );
+        text.join("\n")
     }
 
-    fn set_line_state(
-        sview: &mut views::SourceView,
-        line: u32,
-        latency: TraceState<std::time::Duration>,
-        frequency: TraceState<f32>,
-    ) {
-        let item = sview.borrow_items_mut().get_mut(line as usize - 1).unwrap();
-        item.latency = latency;
-        item.frequency = frequency;
+    fn allocations_text(
+        function: FunctionName,
+        lines: &BTreeMap<u32, Allocations>,
+        elsewhere: Allocations,
+    ) -> String {
+        let mut total = elsewhere;
+        for allocations in lines.values() {
+            total.add(*allocations);
+        }
+        if total.count == 0 && total.frees == 0 {
+            return format!(
+                "No allocations traced in {}. It may not have run while tracing.",
+                function
+            );
+        }
+        let format_allocations = |allocations: &Allocations| {
+            format!(
+                "{:5.1}% {:>10} in {} allocations, {} frees",
+                allocations.bytes as f64 / total.bytes.max(1) as f64 * 100.0,
+                memory::format_bytes(allocations.bytes as usize),
+                allocations.count,
+                allocations.frees
+            )
+        };
+        let mut sorted_lines: Vec<_> = lines.iter().collect();
+        sorted_lines.sort_by_key(|(line, allocations)| {
+            (
+                std::cmp::Reverse(allocations.bytes),
+                std::cmp::Reverse(allocations.count),
+                **line,
+            )
+        });
+        Controller::by_line_text(
+            format!(
+                "Allocations traced in {} over {} seconds: {} in {} allocations, {} frees, by line with most bytes first",
+                function,
+                Controller::ALLOCATION_SECONDS,
+                memory::format_bytes(total.bytes as usize),
+                total.count,
+                total.frees
+            ),
+            sorted_lines
+                .into_iter()
+                .map(|(&line, allocations)| (line, format_allocations(allocations)))
+                .collect(),
+            (elsewhere.count > 0 || elsewhere.frees > 0).then(|| format_allocations(&elsewhere)),
+        )
     }
 
     /// Request user to input a filter. If it fails validation, the user is
@@ -509,11 +5634,7 @@ impl Controller {
             initial_filter.as_deref(),
             move |siv, filter| {
                 siv.pop_layer();
-                if let Err(message) = siv
-                    .user_data::<Controller>()
-                    .expect("Bug: Controller does not exist")
-                    .trace_stack
-                    .set_current_filter(filter.to_string(), is_ret_filter)
+                if let Err(message) = Controller::set_filter(siv, filter.to_string(), is_ret_filter)
                 {
                     let message = format!("Invalid filter:\n{}", message);
                     let filter = filter.to_string();
@@ -527,6 +5648,110 @@ impl Controller {
         ));
     }
 
+    /// Show the filters applied on entry of the current function: the bpftrace
+    /// filter of the function, and which processes and threads are traced.
+    /// `process_fields` are as in `ProcessFilter::fields`.
+    fn setup_entry_filters(
+        siv: &mut Cursive,
+        filter: String,
+        process_fields: [String; 5],
+        follow_forks: bool,
+    ) {
+        let function = siv
+            .user_data::<Controller>()
+            .expect("Bug: Controller does not exist")
+            .trace_stack
+            .get_current_function();
+        let mut layout = LinearLayout::vertical()
+            .child(TextView::new(format!(
+                "bpftrace filter on entry of {}",
+                function
+            )))
+            .child(
+                EditView::new()
+                    .filler(" ")
+                    .content(filter)
+                    .with_name("filter_view"),
+            )
+            .child(TextView::new(
+                "\nOnly trace (for the whole stack, empty to not filter):",
+            ));
+        for ((label, name), content) in Controller::PROCESS_FILTER_FIELDS.iter().zip(process_fields)
+        {
+            layout.add_child(
+                LinearLayout::horizontal()
+                    .child(TextView::new(format!("{:14}", label)))
+                    .child(
+                        EditView::new()
+                            .filler(" ")
+                            .content(content)
+                            .with_name(*name)
+                            .full_width(),
+                    ),
+            );
+        }
+        let mut checkbox = Checkbox::new();
+        checkbox.set_checked(follow_forks);
+        layout.add_child(
+            LinearLayout::horizontal()
+                .child(checkbox.with_name("filter_follow_forks"))
+                .child(TextView::new(" Also trace descendants of the PIDs")),
+        );
+        let messages = Controller::messages(siv);
+        siv.add_layer(
+            Dialog::around(layout)
+                .title("Filters [empty to clear]")
+                .button("Apply", |siv| {
+                    let content = |siv: &mut Cursive, name: &str| {
+                        siv.find_name::<EditView>(name)
+                            .expect("Bug: filter field does not exist")
+                            .get_content()
+                            .to_string()
+                    };
+                    let filter = content(siv, "filter_view");
+                    let mut fields: [String; 5] = Default::default();
+                    for (field, (_, name)) in
+                        fields.iter_mut().zip(&Controller::PROCESS_FILTER_FIELDS)
+                    {
+                        *field = content(siv, name);
+                    }
+                    let follow_forks = siv
+                        .find_name::<Checkbox>("filter_follow_forks")
+                        .expect("Bug: filter_follow_forks does not exist")
+                        .is_checked();
+                    siv.pop_layer();
+                    let result = ProcessFilter::from_fields(
+                        &fields[0],
+                        &fields[1],
+                        &fields[2],
+                        &fields[3],
+                        &fields[4],
+                        follow_forks,
+                    )
+                    .and_then(|process_filter| {
+                        Controller::set_process_filter(siv, process_filter)?;
+                        Controller::set_filter(siv, filter.clone(), false)
+                    });
+                    if let Err(message) = result {
+                        let message = format!("Invalid filter:\n{}", message);
+                        siv.add_layer(Dialog::text(message).button("OK", move |siv| {
+                            siv.pop_layer();
+                            // Ask user to edit filters again
+                            Controller::setup_entry_filters(
+                                siv,
+                                filter.clone(),
+                                fields.clone(),
+                                follow_forks,
+                            );
+                        }));
+                    }
+                })
+                .button(messages.get("button.cancel"), |siv| {
+                    siv.pop_layer();
+                }),
+        );
+    }
+
     fn add_callbacks(siv: &mut Cursive) {
         siv.add_global_callback(cursive::event::Event::CtrlChar('t'), |siv| {
             siv.user_data::<Controller>()
@@ -543,33 +5768,30 @@ impl Controller {
                 // times, for all of the callbacks.
                 //
                 // Normal trace
-                let mut sview = siv
+                let line = siv
                     .find_name::<views::SourceView>("source_view")
-                    .expect("Bug: source_view does not exist");
-                let line = sview.row().unwrap() as u32 + 1;
-                let trace_stack = &siv
-                    .user_data::<Controller>()
-                    .expect("Bug: Controller does not exist")
-                    .trace_stack;
+                    .expect("Bug: source_view does not exist")
+                    .row()
+                    .unwrap() as u32
+                    + 1;
                 // We want to toggle tracing at this line - try to remove if it
                 // exists, otherwise proceed to add callsite.
-                if trace_stack.remove_callsite(line) {
-                    Self::set_line_state(
-                        &mut *sview,
-                        line,
-                        TraceState::Untraced,
-                        TraceState::Untraced,
-                    );
+                if Controller::remove_callsite(siv, line) {
                     return;
                 }
 
+                let trace_stack = &siv
+                    .user_data::<Controller>()
+                    .expect("Bug: Controller does not exist")
+                    .trace_stack;
                 let callsites = trace_stack.get_callsites(line);
                 if callsites.is_empty() {
                     let function = trace_stack.get_current_function();
-                    siv.add_layer(views::new_dialog(&format!(
-                        "No calls found in {} on line {}. Note the call may have been inlined.",
-                        function, line
-                    )));
+                    let text = Controller::messages(siv).format(
+                        "enter.no_calls",
+                        &[("function", &function), ("line", &line)],
+                    );
+                    siv.add_layer(views::new_dialog(&text));
                     return;
                 }
                 if callsites.len() > 1 {
@@ -577,51 +5799,25 @@ impl Controller {
                         "Select the call to trace",
                         callsites,
                         move |siv: &mut Cursive, ci: &CallInstruction| {
-                            let mut sview = siv
-                                .find_name::<views::SourceView>("source_view")
-                                .expect("Bug: source_view does not exist");
-                            Self::set_line_state(
-                                &mut *sview,
-                                line,
-                                TraceState::Pending,
-                                TraceState::Pending,
-                            );
-                            let controller = siv
-                                .user_data::<Controller>()
-                                .expect("Bug: Controller does not exist");
-                            controller.trace_stack.add_callsite(line, ci.clone());
+                            Controller::add_callsite(siv, line, ci.clone());
                         },
                     );
                     siv.add_layer(search_view);
                 } else {
-                    Self::set_line_state(
-                        &mut *sview,
-                        line,
-                        TraceState::Pending,
-                        TraceState::Pending,
-                    );
-                    trace_stack.add_callsite(line, callsites.into_iter().nth(0).unwrap());
+                    Controller::add_callsite(siv, line, callsites.into_iter().nth(0).unwrap());
                 }
             },
             |siv| {
                 // Advanced mode - allow specifying exact addresses to trace
-                let mut sview = siv
+                let line = siv
                     .find_name::<views::SourceView>("source_view")
-                    .expect("Bug: source_view does not exist");
-                let line = sview.row().unwrap() as u32 + 1;
-                let trace_stack = &siv
-                    .user_data::<Controller>()
-                    .expect("Bug: Controller does not exist")
-                    .trace_stack;
+                    .expect("Bug: source_view does not exist")
+                    .row()
+                    .unwrap() as u32
+                    + 1;
                 // We want to toggle tracing at this line - try to remove if it
                 // exists, otherwise proceed to add callsite.
-                if trace_stack.remove_callsite(line) {
-                    Self::set_line_state(
-                        &mut *sview,
-                        line,
-                        TraceState::Untraced,
-                        TraceState::Untraced,
-                    );
+                if Controller::remove_callsite(siv, line) {
                     return;
                 }
 
@@ -643,15 +5839,7 @@ impl Controller {
                                 let end_ip = unwrap::unwrap!(end_offset.parse::<u32>(), "Could not parse {} as number", end_offset);
                                 assert!(end_ip > start_ip);
                                 let ci = CallInstruction::manual(start_ip, end_ip - start_ip);
-                                let mut sview = siv.find_name::<views::SourceView>("source_view").expect("Bug: source_view does not exist");
-                                Self::set_line_state(
-                                    &mut *sview,
-                                    line,
-                                    TraceState::Pending,
-                                    TraceState::Pending,
-                                );
-                                let trace_stack = &siv.user_data::<Controller>().expect("Bug: Controller does not exist").trace_stack;
-                                trace_stack.add_callsite(line, ci);
+                                Controller::add_callsite(siv, line, ci);
                             },
                         ));
                     },
@@ -659,65 +5847,243 @@ impl Controller {
             },
         );
 
-        KeyHandler::add_global_callback(siv, 'X', |siv| {
-            let mut sview = siv
+        KeyHandler::add_global_callback(siv, 'z', Controller::mark_span);
+
+        KeyHandler::add_global_callback(
+            siv,
+            cursive::event::Event::CtrlChar('x'),
+            Controller::toggle_all_callsites,
+        );
+
+        KeyHandler::add_global_callbacks(
+            siv,
+            'X',
+            |siv| {
+                let line = siv
+                    .find_name::<views::SourceView>("source_view")
+                    .expect("Bug: source_view does not exist")
+                    .row()
+                    .unwrap() as u32
+                    + 1;
+                if Controller::remove_callsite(siv, line) {
+                    return;
+                }
+
+                let trace_stack = &siv
+                    .user_data::<Controller>()
+                    .expect("Bug: Controller does not exist")
+                    .trace_stack;
+                let callsites = trace_stack.get_unattached_callsites();
+                if callsites.is_empty() {
+                    let function = trace_stack.get_current_function();
+                    siv.add_layer(views::new_dialog(&format!(
+                        "No unattached calls found in {}",
+                        function
+                    )));
+                    return;
+                }
+                let search_view = views::new_simple_search_view(
+                    "Select the call to trace",
+                    callsites,
+                    move |siv: &mut Cursive, ci: &CallInstruction| {
+                        Controller::add_callsite(siv, line, ci.clone());
+                    },
+                );
+                siv.add_layer(search_view);
+            },
+            |siv| {
+                // Advanced mode - trace any instruction, for calls that
+                // weren't found
+                siv.add_layer(views::new_edit_view(
+                    "Enter instruction to trace, as function+offset, +offset in the current function, or address",
+                    "instruction_view",
+                    None,
+                    |siv, input| {
+                        siv.pop_layer();
+                        if let Err(err) = Controller::trace_instruction(siv, input) {
+                            siv.add_layer(views::new_dialog(&err.to_string()));
+                        }
+                    },
+                ));
+            },
+        );
+
+        KeyHandler::add_global_callback(siv, 'n', |siv| {
+            let line = siv
                 .find_name::<views::SourceView>("source_view")
-                .expect("Bug: source_view does not exist");
+                .expect("Bug: source_view does not exist")
+                .row()
+                .unwrap() as u32
+                + 1;
             let trace_stack = &siv
                 .user_data::<Controller>()
                 .expect("Bug: Controller does not exist")
                 .trace_stack;
-            let line = sview.row().unwrap() as u32 + 1;
-            if trace_stack.remove_callsite(line) {
-                Self::set_line_state(
-                    &mut *sview,
-                    line,
-                    TraceState::Untraced,
-                    TraceState::Untraced,
-                );
+            if !trace_stack.get_current_frame().is_traced(line) {
+                siv.add_layer(views::new_dialog(&format!(
+                    "No call is traced on line {}, press x to trace one first",
+                    line
+                )));
                 return;
             }
+            let rate = trace_stack.get_callsite_sampling(line).to_string();
+            siv.add_layer(views::new_edit_view(
+                &format!("Time 1 in how many calls on line {}? [1 to time all]", line),
+                "sampling_view",
+                Some(&rate),
+                move |siv, rate| {
+                    siv.pop_layer();
+                    let result = match rate.trim().parse::<u32>() {
+                        Ok(rate) if rate > 0 => Controller::set_callsite_sampling(siv, line, rate),
+                        _ => Err(format!("Invalid number of calls {}", rate).into()),
+                    };
+                    if let Err(err) = result {
+                        siv.add_layer(views::new_dialog(&err.to_string()));
+                    }
+                },
+            ));
+        });
 
-            let callsites = trace_stack.get_unattached_callsites();
-            if callsites.is_empty() {
-                let function = trace_stack.get_current_function();
+        KeyHandler::add_global_callback(siv, 'V', |siv| {
+            let interval = trace_structs::format_interval(
+                siv.user_data::<Controller>()
+                    .expect("Bug: Controller does not exist")
+                    .trace_stack
+                    .get_report_interval(),
+            );
+            siv.add_layer(views::new_edit_view(
+                "How often to refresh results? [e.g. 500ms or 5s, longer lowers overhead]",
+                "interval_view",
+                Some(&interval),
+                |siv, interval| {
+                    siv.pop_layer();
+                    match trace_structs::parse_interval(interval) {
+                        Ok(interval) => siv
+                            .user_data::<Controller>()
+                            .expect("Bug: Controller does not exist")
+                            .trace_stack
+                            .set_report_interval(interval),
+                        Err(err) => siv.add_layer(views::new_dialog(&err.to_string())),
+                    }
+                },
+            ));
+        });
+
+        KeyHandler::add_global_callback(siv, 'F', |siv| {
+            let line = siv
+                .find_name::<views::SourceView>("source_view")
+                .expect("Bug: source_view does not exist")
+                .row()
+                .unwrap() as u32
+                + 1;
+            let trace_stack = &siv
+                .user_data::<Controller>()
+                .expect("Bug: Controller does not exist")
+                .trace_stack;
+            if !trace_stack.get_current_frame().is_traced(line) {
                 siv.add_layer(views::new_dialog(&format!(
-                    "No unattached calls found in {}",
-                    function
+                    "No call is traced on line {}, press x to trace one first. To only count slow calls of the whole function, filter on $duration with g.",
+                    line
                 )));
                 return;
             }
-            let search_view = views::new_simple_search_view(
-                "Select the call to trace",
-                callsites,
-                move |siv: &mut Cursive, ci: &CallInstruction| {
-                    let mut sview = siv
-                        .find_name::<views::SourceView>("source_view")
-                        .expect("Bug: source_view does not exist");
-                    Self::set_line_state(
-                        &mut *sview,
-                        line,
-                        TraceState::Pending,
-                        TraceState::Pending,
-                    );
-                    let controller = siv
-                        .user_data::<Controller>()
-                        .expect("Bug: Controller does not exist");
-                    controller.trace_stack.add_callsite(line, ci.clone());
+            let min_duration = trace_stack
+                .get_callsite_threshold(line)
+                .map(|min_duration| (min_duration.as_secs_f64() * 1e6).to_string());
+            siv.add_layer(views::new_edit_view(
+                &format!(
+                    "Only count calls on line {} taking at least how many microseconds? [empty to count all]",
+                    line
+                ),
+                "threshold_view",
+                min_duration.as_deref(),
+                move |siv, micros| {
+                    siv.pop_layer();
+                    let min_duration = match micros.trim() {
+                        "" => Ok(None),
+                        micros => match micros.parse::<f64>() {
+                            Ok(micros) if micros > 0.0 => {
+                                Ok(Some(Duration::from_secs_f64(micros / 1e6)))
+                            }
+                            _ => Err(Error::from(format!("Invalid duration {}", micros))),
+                        },
+                    };
+                    let result = min_duration.and_then(|min_duration| {
+                        siv.user_data::<Controller>()
+                            .expect("Bug: Controller does not exist")
+                            .trace_stack
+                            .set_callsite_threshold(line, min_duration)
+                    });
+                    match result {
+                        Ok(()) => Controller::update_callsite_annotation(siv, line),
+                        Err(err) => siv.add_layer(views::new_dialog(&err.to_string())),
+                    }
                 },
-            );
-            siv.add_layer(search_view);
+            ));
+        });
+
+        KeyHandler::add_global_callback(siv, 'I', |siv| {
+            let line = siv
+                .find_name::<views::SourceView>("source_view")
+                .expect("Bug: source_view does not exist")
+                .row()
+                .unwrap() as u32
+                + 1;
+            let trace_stack = &siv
+                .user_data::<Controller>()
+                .expect("Bug: Controller does not exist")
+                .trace_stack;
+            let inlined: Vec<String> = trace_stack
+                .get_callsites(line)
+                .iter()
+                .filter(|ci| matches!(ci.instruction, InstructionType::Inlined(_)))
+                .map(|ci| {
+                    format!(
+                        "  {} (offsets {}-{})",
+                        ci,
+                        ci.get_relative_ip(),
+                        ci.get_relative_ip() + ci.get_length()
+                    )
+                })
+                .collect();
+            let text = if inlined.is_empty() {
+                format!(
+                    "No inlined calls found on line {} of {}",
+                    line,
+                    trace_stack.get_current_function()
+                )
+            } else {
+                format!(
+                    "Calls inlined on line {} of {}, which have no call instruction of their own:\n\n{}",
+                    line,
+                    trace_stack.get_current_function(),
+                    inlined.join("\n")
+                )
+            };
+            let messages = Controller::messages(siv);
+            siv.add_layer(views::new_text_dialog_view(
+                &messages,
+                &text,
+                "inlined_view",
+                |siv| {
+                    siv.pop_layer();
+                },
+            ));
         });
 
         KeyHandler::add_global_callback(siv, '>', |siv| {
             let controller = siv
                 .user_data::<Controller>()
                 .expect("Bug: Controller does not exist");
-            let initial_results = vec![("Type to search".to_string(), None)];
+            let initial_results = Controller::empty_search_results(
+                &controller.program,
+                &controller.history,
+                "Type to search",
+            );
             controller
                 .searcher
-                .setup_search(initial_results.clone(), Vec::new());
-            let search_view = views::new_search_view(
+                .setup_search(initial_results.clone(), Vec::new(), true);
+            let search_view = views::new_multi_search_view(
                 "Select the function to enter",
                 initial_results,
                 move |siv: &mut Cursive, view_name: &str, search: &str, n_results: usize| {
@@ -726,51 +6092,33 @@ impl Controller {
                         .expect("Bug: Controller does not exist");
                     controller.searcher.search(view_name, search, n_results);
                 },
-                move |siv: &mut Cursive, symbol: &SymbolInfo| {
-                    let controller = siv
-                        .user_data::<Controller>()
-                        .expect("Bug: Controller does not exist");
-                    // TODO cancel any pending searches
-                    if controller.program.is_dynamic_symbol(symbol) {
-                        // TODO show error for dyn fn
-                    } else {
-                        let mut sview = siv
-                            .find_name::<views::SourceView>("source_view")
-                            .expect("Bug: source_view does not exist");
-                        let mut fview = siv
-                            .find_name::<views::FooterView>("footer_view")
-                            .expect("Bug: footer_view does not exist");
-                        // Reset lifetime of `controller` to avoid overlapping
-                        // mutable borrows of `siv`.
-                        let controller = siv
+                Controller::pick_overload(move |siv: &mut Cursive, symbols: Vec<SymbolInfo>| {
+                    if symbols.len() > 1 {
+                        let messages = Controller::messages(siv);
+                        let program = &siv
                             .user_data::<Controller>()
-                            .expect("Bug: Controller does not exist");
-                        match Controller::setup_function(
-                            &controller.program,
-                            symbol.name,
-                            &mut *sview,
-                            &mut *fview,
-                        ) {
-                            Err(e) => siv.add_layer(views::new_dialog(&format!(
-                                "Error setting up function {}: {}",
-                                symbol.name, e
-                            ))),
-                            Ok(frame_info) => {
-                                controller.trace_stack.push(frame_info);
-                            }
-                        };
+                            .expect("Bug: Controller does not exist")
+                            .program;
+                        let text = Controller::comparison_text(program, &symbols);
+                        siv.add_layer(views::new_text_dialog_view(
+                            &messages,
+                            &text,
+                            "compare_view",
+                            |siv| {
+                                siv.pop_layer();
+                            },
+                        ));
+                        return;
                     }
-                },
+                    siv.pop_layer();
+                    // TODO cancel any pending searches
+                    Controller::enter_symbol(siv, &symbols[0], None);
+                }),
             );
             siv.add_layer(search_view);
         });
 
-        KeyHandler::add_global_callback(siv, 'r', |siv| {
-            siv.user_data::<Controller>()
-                .expect("Bug: Controller does not exist")
-                .tracer
-                .rerun_tracer();
-        });
+        KeyHandler::add_global_callback(siv, 'r', Controller::restart);
 
         KeyHandler::add_global_callback(
             siv,
@@ -786,79 +6134,75 @@ impl Controller {
                     .user_data::<Controller>()
                     .expect("Bug: Controller does not exist");
                 let trace_stack = &controller.trace_stack;
-                let callsites = trace_stack.get_callsites(line);
+                // Inlined calls have no function to enter
+                let callsites: Vec<CallInstruction> = trace_stack
+                    .get_callsites(line)
+                    .into_iter()
+                    .filter(|ci| !matches!(ci.instruction, InstructionType::Inlined(_)))
+                    .collect();
                 if callsites.is_empty() {
                     let function = trace_stack.get_current_function();
-                    siv.add_layer(views::new_dialog(&format!(
-                        "No calls found in {} on line {}. Note the call may have been inlined.",
-                        function, line
-                    )));
+                    let text = Controller::messages(siv).format(
+                        "enter.no_calls",
+                        &[("function", &function), ("line", &line)],
+                    );
+                    siv.add_layer(views::new_dialog(&text));
                     return;
                 }
 
                 let num_callsites = callsites.len();
+                let function = trace_stack.get_current_function();
+                // Slots of calls that may be virtual, e.g. `call [rax+0x10]`
+                let mut vtable_displacements: Vec<i64> = callsites
+                    .iter()
+                    .filter_map(|ci| match &ci.instruction {
+                        InstructionType::Register(register, Some(displacement))
+                            if register != "rip" =>
+                        {
+                            Some(*displacement)
+                        }
+                        _ => None,
+                    })
+                    .collect();
+                vtable_displacements.sort_unstable();
+                vtable_displacements.dedup();
                 let direct_calls: Vec<SymbolInfo> = callsites
                     .into_iter()
                     .filter_map(|ci| match ci.instruction {
                         InstructionType::Unknown => None,
                         InstructionType::Manual => None,
+                        InstructionType::Span(_) => None,
+                        InstructionType::Inlined(_) => None,
                         InstructionType::Register(_, _) => None,
+                        // Entered in the shared library defining it
                         InstructionType::DynamicSymbol(function) => {
-                            controller.program.get_symbol(function).or_else(|| {
-                                log::warn!("Could not get symbol information for {}", function);
-                                None
-                            })
+                            match Controller::load_dynamic_symbol(&mut controller.program, function)
+                            {
+                                Ok(symbol) => Some(symbol),
+                                Err(err) => {
+                                    log::warn!("Failed to load {}: {}", function, err);
+                                    controller.program.get_symbol(function).cloned()
+                                }
+                            }
                         }
-                        InstructionType::Function(function) => {
-                            controller.program.get_symbol(function).or_else(|| {
+                        InstructionType::Function(function) => controller
+                            .program
+                            .get_symbol_with_signature(function)
+                            .or_else(|| {
                                 log::warn!("Could not get symbol information for {}", function);
                                 None
-                            })
-                        }
+                            }),
                     })
-                    .map(|si| si.clone())
                     .collect();
                 let num_indirect_calls = num_callsites - direct_calls.len();
 
                 let submit_fn = move |siv: &mut Cursive, symbol: &SymbolInfo| {
-                    let controller = siv
-                        .user_data::<Controller>()
-                        .expect("Bug: Controller does not exist");
-                    // TODO cancel any pending searches
-                    if controller.program.is_dynamic_symbol(symbol) {
-                        // TODO show error for dyn fn
-                    } else {
-                        let mut sview = siv
-                            .find_name::<views::SourceView>("source_view")
-                            .expect("Bug: source_view does not exist");
-                        let mut fview = siv
-                            .find_name::<views::FooterView>("footer_view")
-                            .expect("Bug: footer_view does not exist");
-                        // Reset lifetime of `controller` to avoid overlapping
-                        // mutable borrows of `siv`.
-                        let controller = siv
-                            .user_data::<Controller>()
-                            .expect("Bug: Controller does not exist");
-                        match Controller::setup_function(
-                            &controller.program,
-                            symbol.name,
-                            &mut *sview,
-                            &mut *fview,
-                        ) {
-                            Err(e) => siv.add_layer(views::new_dialog(&format!(
-                                "Error setting up function {}: {}",
-                                symbol.name, e
-                            ))),
-                            Ok(frame_info) => {
-                                controller.trace_stack.push(frame_info);
-                            }
-                        };
-                    }
-                    // TODO show error for dyn fn
+                    // TODO cancel any pending searches
+                    Controller::enter_symbol(siv, symbol, Some(line));
                 };
 
                 if num_callsites > 1 || num_indirect_calls > 0 {
-                    let title = "Select the call to enter";
+                    let title = &controller.messages.get("enter.select_call").to_string();
                     let search_view = if num_indirect_calls == 0 {
                         views::new_simple_search_view(title, direct_calls, submit_fn)
                     } else {
@@ -869,11 +6213,44 @@ impl Controller {
                         } else {
                             format!("{} indirect calls", num_indirect_calls)
                         };
-                        initial_results
-                            .insert(0, (format!("{} (type to search)", call_string), None));
-                        controller
-                            .searcher
-                            .setup_search(initial_results.clone(), direct_calls);
+                        // Implementations in the vtables of the program are
+                        // likely targets of virtual calls, without tracing
+                        let program = controller.program.object(function);
+                        let mut virtual_calls = Vec::new();
+                        for displacement in vtable_displacements {
+                            for (callee, vtables) in
+                                program.get_virtual_call_candidates(displacement)
+                            {
+                                if let Some(symbol) =
+                                    controller.program.get_symbol_with_signature(callee)
+                                {
+                                    let label = format!("(V) {} ({} vtables)", symbol, vtables);
+                                    virtual_calls.push((label, symbol));
+                                }
+                            }
+                        }
+                        let mut fixed_items = direct_calls;
+                        fixed_items.extend(virtual_calls.iter().map(|(_, symbol)| symbol.clone()));
+                        initial_results.splice(
+                            0..0,
+                            std::iter::once((
+                                format!(
+                                    "{} (type to search, or press j on the line to trace targets)",
+                                    call_string
+                                ),
+                                None,
+                            ))
+                            .chain(
+                                virtual_calls
+                                    .into_iter()
+                                    .map(|(label, symbol)| (label, Some(symbol))),
+                            ),
+                        );
+                        controller.searcher.setup_search(
+                            initial_results.clone(),
+                            fixed_items,
+                            false,
+                        );
                         views::new_search_view(
                             title,
                             initial_results,
@@ -902,80 +6279,882 @@ impl Controller {
             |siv| {
                 if siv.screen().len() > 1 {
                     // Pop anything on top of source view
-                    let view = siv
+                    let mut view = siv
                         .pop_layer()
                         .expect("Pop unexpectedly empty despite len > 1");
 
-                    // Check if this is histogram or breakdown view - we need to
+                    // Check if this is histogram, breakdown, callers, return
+                    // values, arguments, groups or threads view - we need to
                     // reset mode if so.
-                    if views::is_text_dialog_view(&view, "histogram_view")
-                        || views::is_text_dialog_view(&view, "breakdown_view")
+                    if views::is_text_dialog_view(&mut view, "histogram_view")
+                        || views::is_text_dialog_view(&mut view, "breakdown_view")
+                        || views::is_text_dialog_view(&mut view, "callers_view")
+                        || views::is_text_dialog_view(&mut view, "retvals_view")
+                        || views::is_text_dialog_view(&mut view, "args_view")
+                        || views::is_text_dialog_view(&mut view, "groups_view")
+                        || views::is_text_dialog_view(&mut view, "threads_view")
                     {
                         siv.user_data::<Controller>()
                             .expect("Bug: Controller does not exist")
                             .trace_stack
                             .set_mode(TraceMode::Line);
+                    } else if views::is_text_dialog_view(&mut view, "line_stats_view") {
+                        Controller::close_line_stats(siv);
+                    } else if views::is_text_dialog_view(&mut view, "single_call_view") {
+                        siv.user_data::<Controller>()
+                            .expect("Bug: Controller does not exist")
+                            .kill_single_call();
+                    } else if views::is_text_dialog_view(&mut view, "outliers_view") {
+                        siv.user_data::<Controller>()
+                            .expect("Bug: Controller does not exist")
+                            .kill_outliers();
+                    } else if views::is_text_dialog_view(&mut view, "auto_profile_view") {
+                        // Closing the dialog stops drilling down
+                        siv.user_data::<Controller>()
+                            .expect("Bug: Controller does not exist")
+                            .auto_profile = None;
                     }
 
                     return;
                 }
-                let controller = siv
-                    .user_data::<Controller>()
-                    .expect("Bug: Controller does not exist");
-                match controller.trace_stack.pop() {
-                    Some(frame_info) => {
-                        let mut sview = siv
-                            .find_name::<views::SourceView>("source_view")
-                            .expect("Bug: source_view does not exist");
-                        let mut fview = siv
-                            .find_name::<views::FooterView>("footer_view")
-                            .expect("Bug: footer_view does not exist");
-                        Controller::setup_source_view(&frame_info, &mut *sview, &mut *fview)
-                            .unwrap();
-                    }
-                    None => siv.add_layer(views::new_quit_dialog("Are you sure you want to quit?")),
+                if !Controller::pop_function(siv) {
+                    let messages = Controller::messages(siv);
+                    siv.add_layer(views::new_quit_dialog(
+                        &messages,
+                        messages.get("quit.confirm"),
+                    ));
                 }
             },
         );
 
-        KeyHandler::add_global_callback(siv, 'h', |siv| {
-            if let Some(_) = siv.find_name::<views::TextDialogView>("histogram_view") {
+        KeyHandler::add_global_callback(siv, 'h', |siv| {
+            if let Some(_) = siv.find_name::<views::TextDialogView>("histogram_view") {
+                // View is already open, make it no-op
+                return;
+            }
+
+            let row = siv
+                .find_name::<views::SourceView>("source_view")
+                .expect("Bug: source_view does not exist")
+                .row();
+            let trace_stack = &siv
+                .user_data::<Controller>()
+                .expect("Bug: Controller does not exist")
+                .trace_stack;
+            let frame = trace_stack.get_current_frame();
+            let function = frame.get_function();
+            // Lines other than traced calls show the whole function
+            let line = row
+                .map(|row| row as u32 + 1)
+                .filter(|line| frame.is_traced(*line))
+                .unwrap_or(frame.get_source_line());
+            trace_stack.set_mode(TraceMode::Histogram { line });
+            let messages = Controller::messages(siv);
+            let text = if line == frame.get_source_line() {
+                messages.format("histogram.gathering", &[("function", &function)])
+            } else {
+                messages.format(
+                    "histogram.line_gathering_of",
+                    &[("function", &function), ("line", &line)],
+                )
+            };
+            siv.add_layer(views::new_text_dialog_view(
+                &messages,
+                &text,
+                "histogram_view",
+                |siv| {
+                    let trace_stack = &siv
+                        .user_data::<Controller>()
+                        .expect("Bug: Controller does not exist")
+                        .trace_stack;
+                    trace_stack.set_mode(TraceMode::Line);
+                    siv.pop_layer();
+                },
+            ));
+        });
+
+        KeyHandler::add_global_callback(siv, 'H', |siv| {
+            Controller::toggle_histogram_pane(siv);
+        });
+
+        KeyHandler::add_global_callback(siv, 'D', |siv| {
+            if let Some(_) = siv.find_name::<views::TextDialogView>("line_stats_view") {
+                // View is already open, make it no-op
+                return;
+            }
+
+            let line = siv
+                .find_name::<views::SourceView>("source_view")
+                .expect("Bug: source_view does not exist")
+                .row()
+                .unwrap() as u32
+                + 1;
+            let controller = siv
+                .user_data::<Controller>()
+                .expect("Bug: Controller does not exist");
+            let frame = controller.trace_stack.get_current_frame();
+            let function = frame.get_function();
+            if line != frame.get_source_line() && !frame.is_traced(line) {
+                let text = controller.messages.format(
+                    "line_stats.not_traced",
+                    &[("function", &function), ("line", &line)],
+                );
+                siv.add_layer(views::new_dialog(&text));
+                return;
+            }
+            controller.stats_line = Some(line);
+            controller.update_line_histograms();
+            let messages = Controller::messages(siv);
+            siv.add_layer(views::new_text_dialog_view(
+                &messages,
+                &messages.format(
+                    "line_stats.gathering",
+                    &[("function", &function), ("line", &line)],
+                ),
+                "line_stats_view",
+                |siv| {
+                    Controller::close_line_stats(siv);
+                    siv.pop_layer();
+                },
+            ));
+        });
+
+        KeyHandler::add_global_callback(siv, 'c', |siv| {
+            if let Some(_) = siv.find_name::<views::TextDialogView>("callers_view") {
+                // View is already open, make it no-op
+                return;
+            }
+
+            let line = siv
+                .find_name::<views::SourceView>("source_view")
+                .expect("Bug: source_view does not exist")
+                .row()
+                .unwrap() as u32
+                + 1;
+            let controller = siv
+                .user_data::<Controller>()
+                .expect("Bug: Controller does not exist");
+            let frame = controller.trace_stack.get_current_frame();
+            let function = frame.get_function();
+            if line != frame.get_source_line() && !frame.is_traced(line) {
+                let text = controller.messages.format(
+                    "callers.not_traced",
+                    &[("line", &line), ("function", &function)],
+                );
+                siv.add_layer(views::new_dialog(&text));
+                return;
+            }
+            controller.trace_stack.set_mode(TraceMode::Callers {
+                line,
+                function_address: controller.program.get_address(function),
+            });
+            let messages = Controller::messages(siv);
+            siv.add_layer(views::new_text_dialog_view(
+                &messages,
+                &messages.format(
+                    "callers.gathering",
+                    &[("function", &function), ("line", &line)],
+                ),
+                "callers_view",
+                |siv| {
+                    let trace_stack = &siv
+                        .user_data::<Controller>()
+                        .expect("Bug: Controller does not exist")
+                        .trace_stack;
+                    trace_stack.set_mode(TraceMode::Line);
+                    siv.pop_layer();
+                },
+            ));
+        });
+
+        KeyHandler::add_global_callback(siv, 'v', |siv| {
+            if let Some(_) = siv.find_name::<views::TextDialogView>("retvals_view") {
+                // View is already open, make it no-op
+                return;
+            }
+
+            let line = siv
+                .find_name::<views::SourceView>("source_view")
+                .expect("Bug: source_view does not exist")
+                .row()
+                .unwrap() as u32
+                + 1;
+            let controller = siv
+                .user_data::<Controller>()
+                .expect("Bug: Controller does not exist");
+            let frame = controller.trace_stack.get_current_frame();
+            let function = frame.get_function();
+            if line != frame.get_source_line() && !frame.is_traced(line) {
+                let text = controller.messages.format(
+                    "retvals.not_traced",
+                    &[("line", &line), ("function", &function)],
+                );
+                siv.add_layer(views::new_dialog(&text));
+                return;
+            }
+            controller
+                .trace_stack
+                .set_mode(TraceMode::ReturnValues { line });
+            let messages = Controller::messages(siv);
+            siv.add_layer(views::new_text_dialog_view(
+                &messages,
+                &messages.format(
+                    "retvals.gathering",
+                    &[("function", &function), ("line", &line)],
+                ),
+                "retvals_view",
+                |siv| {
+                    let trace_stack = &siv
+                        .user_data::<Controller>()
+                        .expect("Bug: Controller does not exist")
+                        .trace_stack;
+                    trace_stack.set_mode(TraceMode::Line);
+                    siv.pop_layer();
+                },
+            ));
+        });
+
+        KeyHandler::add_global_callback(siv, 'a', |siv| {
+            if let Some(_) = siv.find_name::<views::TextDialogView>("args_view") {
+                // View is already open, make it no-op
+                return;
+            }
+
+            let line = siv
+                .find_name::<views::SourceView>("source_view")
+                .expect("Bug: source_view does not exist")
+                .row()
+                .unwrap() as u32
+                + 1;
+            let controller = siv
+                .user_data::<Controller>()
+                .expect("Bug: Controller does not exist");
+            let function = controller.trace_stack.get_current_function();
+            let columns = match controller.argument_columns(line) {
+                Ok(columns) => columns,
+                Err(text) => {
+                    siv.add_layer(views::new_dialog(&text));
+                    return;
+                }
+            };
+            controller.trace_stack.set_argument_columns(columns);
+            controller
+                .trace_stack
+                .set_mode(TraceMode::Arguments { line });
+            let messages = Controller::messages(siv);
+            siv.add_layer(views::new_text_dialog_view(
+                &messages,
+                &messages.format(
+                    "args.gathering",
+                    &[("function", &function), ("line", &line)],
+                ),
+                "args_view",
+                |siv| {
+                    let trace_stack = &siv
+                        .user_data::<Controller>()
+                        .expect("Bug: Controller does not exist")
+                        .trace_stack;
+                    trace_stack.set_mode(TraceMode::Line);
+                    siv.pop_layer();
+                },
+            ));
+        });
+
+        KeyHandler::add_global_callback(siv, 'k', |siv| {
+            if let Some(_) = siv.find_name::<views::TextDialogView>("groups_view") {
+                // View is already open, make it no-op
+                return;
+            }
+
+            let line = siv
+                .find_name::<views::SourceView>("source_view")
+                .expect("Bug: source_view does not exist")
+                .row()
+                .unwrap() as u32
+                + 1;
+            let controller = siv
+                .user_data::<Controller>()
+                .expect("Bug: Controller does not exist");
+            let columns = match controller.argument_columns(line) {
+                Ok(columns) => columns,
+                Err(text) => {
+                    siv.add_layer(views::new_dialog(&text));
+                    return;
+                }
+            };
+            let search_view = views::new_simple_search_view(
+                "Select the argument to group by",
+                columns,
+                move |siv: &mut Cursive, column: &ArgumentColumn| {
+                    siv.pop_layer();
+                    let controller = siv
+                        .user_data::<Controller>()
+                        .expect("Bug: Controller does not exist");
+                    let function = controller.trace_stack.get_current_function();
+                    controller.trace_stack.set_group_by(column.clone());
+                    controller.trace_stack.set_mode(TraceMode::GroupBy { line });
+                    let messages = Controller::messages(siv);
+                    siv.add_layer(views::new_text_dialog_view(
+                        &messages,
+                        &messages.format(
+                            "groups.gathering",
+                            &[
+                                ("function", &function),
+                                ("line", &line),
+                                ("argument", &column.label),
+                            ],
+                        ),
+                        "groups_view",
+                        |siv| {
+                            let trace_stack = &siv
+                                .user_data::<Controller>()
+                                .expect("Bug: Controller does not exist")
+                                .trace_stack;
+                            trace_stack.set_mode(TraceMode::Line);
+                            siv.pop_layer();
+                        },
+                    ));
+                },
+            );
+            siv.add_layer(search_view);
+        });
+
+        KeyHandler::add_global_callback(siv, 'W', |siv| {
+            if let Some(_) = siv.find_name::<views::TextDialogView>("threads_view") {
+                // View is already open, make it no-op
+                return;
+            }
+
+            let line = siv
+                .find_name::<views::SourceView>("source_view")
+                .expect("Bug: source_view does not exist")
+                .row()
+                .unwrap() as u32
+                + 1;
+            let controller = siv
+                .user_data::<Controller>()
+                .expect("Bug: Controller does not exist");
+            let frame = controller.trace_stack.get_current_frame();
+            let function = frame.get_function();
+            if line != frame.get_source_line() && !frame.is_traced(line) {
+                let text = controller.messages.format(
+                    "threads.not_traced",
+                    &[("line", &line), ("function", &function)],
+                );
+                siv.add_layer(views::new_dialog(&text));
+                return;
+            }
+            controller
+                .trace_stack
+                .set_group_by(ArgumentColumn::thread());
+            controller.trace_stack.set_mode(TraceMode::GroupBy { line });
+            let messages = Controller::messages(siv);
+            siv.add_layer(views::new_text_dialog_view(
+                &messages,
+                &messages.format(
+                    "threads.gathering",
+                    &[("function", &function), ("line", &line)],
+                ),
+                "threads_view",
+                |siv| {
+                    let trace_stack = &siv
+                        .user_data::<Controller>()
+                        .expect("Bug: Controller does not exist")
+                        .trace_stack;
+                    trace_stack.set_mode(TraceMode::Line);
+                    siv.pop_layer();
+                },
+            ));
+        });
+
+        KeyHandler::add_global_callback(siv, 'B', |siv| {
+            if let Some(_) = siv.find_name::<views::TextDialogView>("branch_view") {
+                // View is already open, make it no-op
+                return;
+            }
+
+            let controller = siv
+                .user_data::<Controller>()
+                .expect("Bug: Controller does not exist");
+            let function = controller.trace_stack.get_current_function();
+            let object_path = controller.program.object(function).file_path.clone();
+            let pids = controller.trace_stack.get_process_filter().pids;
+            Controller::run_in_background(
+                siv,
+                "branch_view",
+                &format!(
+                    "Sampling last branch records for {} seconds to find mispredicted branches in {}...",
+                    Controller::BRANCH_SAMPLE_SECONDS,
+                    function
+                ),
+                move || {
+                    let result = lbr::sample(
+                        function,
+                        &object_path,
+                        &pids,
+                        Duration::from_secs(Controller::BRANCH_SAMPLE_SECONDS),
+                    );
+                    Event::BranchSamples { function, result }
+                },
+            );
+        });
+
+        KeyHandler::add_global_callback(siv, 'o', |siv| {
+            if let Some(_) = siv.find_name::<views::TextDialogView>("single_call_view") {
+                // View is already open, make it no-op
+                return;
+            }
+
+            let controller = siv
+                .user_data::<Controller>()
+                .expect("Bug: Controller does not exist");
+            let function = controller.trace_stack.get_current_function();
+            let backend = controller.trace_stack.get_backend();
+            let backend_name = backend.name();
+            let child = backend
+                .run_command(&controller.trace_stack.get_single_call_expr())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .spawn();
+            let child = match child {
+                Ok(child) => child,
+                Err(err) => {
+                    siv.add_layer(views::new_dialog(&format!(
+                        "{} failed to start: {}",
+                        backend_name, err
+                    )));
+                    return;
+                }
+            };
+            let pid = child.id();
+            controller.single_call_pid = Some(pid);
+            let tx = controller.tx.clone();
+            std::thread::spawn(move || {
+                let result = match child.wait_with_output() {
+                    Ok(output) if output.status.success() => Ok(TraceStack::parse_single_call(
+                        &String::from_utf8_lossy(&output.stdout),
+                    )),
+                    Ok(output) => Err(format!(
+                        "{} failed, status: {:?}, stderr:\n{}",
+                        backend_name,
+                        output.status,
+                        String::from_utf8_lossy(&output.stderr)
+                    )
+                    .into()),
+                    Err(err) => Err(format!("Failed to wait for {}: {}", backend_name, err).into()),
+                };
+                // Controller may have exited in the meantime
+                let _ = tx.send(Event::SingleCall {
+                    pid,
+                    function,
+                    result,
+                });
+            });
+            let messages = Controller::messages(siv);
+            siv.add_layer(views::new_text_dialog_view(
+                &messages,
+                &format!("Waiting for the next call of {}...", function),
+                "single_call_view",
+                |siv| {
+                    siv.user_data::<Controller>()
+                        .expect("Bug: Controller does not exist")
+                        .kill_single_call();
+                    siv.pop_layer();
+                },
+            ));
+        });
+
+        KeyHandler::add_global_callback(siv, 'u', |siv| {
+            if let Some(_) = siv.find_name::<views::TextDialogView>("lock_waits_view") {
+                // View is already open, make it no-op
+                return;
+            }
+            Controller::trace_lock_waits(siv);
+        });
+
+        KeyHandler::add_global_callback(siv, 'j', |siv| {
+            if let Some(_) = siv.find_name::<views::TextDialogView>("call_targets_view") {
+                // View is already open, make it no-op
+                return;
+            }
+            Controller::trace_call_targets(siv);
+        });
+
+        KeyHandler::add_global_callback(siv, 'y', |siv| {
+            if let Some(_) = siv.find_name::<views::TextDialogView>("syscalls_view") {
+                // View is already open, make it no-op
+                return;
+            }
+            Controller::trace_syscalls(siv);
+        });
+
+        KeyHandler::add_global_callback(siv, 'C', |siv| {
+            if let Some(_) = siv.find_name::<views::TextDialogView>("loops_view") {
+                // View is already open, make it no-op
+                return;
+            }
+            Controller::trace_loop_iterations(siv);
+        });
+
+        KeyHandler::add_global_callback(siv, 'A', |siv| {
+            if let Some(_) = siv.find_name::<views::TextDialogView>("allocations_view") {
+                // View is already open, make it no-op
+                return;
+            }
+            Controller::trace_allocations(siv);
+        });
+
+        KeyHandler::add_global_callback(siv, 'O', |siv| {
+            if let Some(_) = siv.find_name::<views::TextDialogView>("outliers_view") {
+                // View is already open, make it no-op
+                return;
+            }
+
+            let function = siv
+                .user_data::<Controller>()
+                .expect("Bug: Controller does not exist")
+                .trace_stack
+                .get_current_function();
+            siv.add_layer(views::new_edit_view(
+                &format!(
+                    "Capture calls of {} taking at least how many microseconds?",
+                    function
+                ),
+                "outlier_threshold_view",
+                None,
+                |siv, micros| {
+                    siv.pop_layer();
+                    match micros.trim().parse::<f64>() {
+                        Ok(micros) if micros > 0.0 => {
+                            Controller::capture_outliers(siv, Duration::from_secs_f64(micros / 1e6))
+                        }
+                        _ => siv
+                            .add_layer(views::new_dialog(&format!("Invalid duration {}", micros))),
+                    }
+                },
+            ));
+        });
+
+        KeyHandler::add_global_callback(siv, 'T', |siv| {
+            if let Some(_) = siv.find_name::<views::TextDialogView>("template_view") {
                 // View is already open, make it no-op
                 return;
             }
 
-            let trace_stack = &siv
+            let controller = siv
+                .user_data::<Controller>()
+                .expect("Bug: Controller does not exist");
+            if !controller.trace_stack.get_backend().is_local_bpftrace() {
+                siv.add_layer(views::new_dialog(
+                    "Trace templates are only supported with the bpftrace backend",
+                ));
+                return;
+            }
+            if controller.templates.is_empty() {
+                siv.add_layer(views::new_dialog(
+                    "No trace templates apply, the program doesn't use OpenSSL, zlib or jemalloc and no --templates match it",
+                ));
+                return;
+            }
+            let search_view = views::new_simple_search_view(
+                "Select a trace template",
+                controller.templates.clone(),
+                |siv: &mut Cursive, applicable: &templates::Applicable| {
+                    Controller::run_template(siv, applicable);
+                },
+            );
+            siv.add_layer(search_view);
+        });
+
+        KeyHandler::add_global_callback(siv, 's', |siv| {
+            if let Some(_) = siv.find_name::<cursive::views::EditView>("source_map_view") {
+                // View is already open, make it no-op
+                return;
+            }
+
+            let frame = siv
                 .user_data::<Controller>()
                 .expect("Bug: Controller does not exist")
-                .trace_stack;
-            trace_stack.set_mode(TraceMode::Histogram);
-            let function = trace_stack.get_current_function();
+                .trace_stack
+                .get_current_frame();
+            let source_file = frame.get_source_file();
+            // Suggest remapping the directory of the current file
+            let initial_rule = match source_file.rfind('/') {
+                Some(i) if i > 0 => format!("{}=", &source_file[..i]),
+                _ => String::new(),
+            };
+            siv.add_layer(views::new_edit_view(
+                &format!(
+                    "Remap sources under a path prefix as OLD=NEW (for {})",
+                    source_file
+                ),
+                "source_map_view",
+                Some(&initial_rule),
+                |siv, rule| {
+                    siv.pop_layer();
+                    if let Err(err) = Controller::add_source_map(siv, rule) {
+                        siv.add_layer(views::new_dialog(&format!(
+                            "Failed to remap sources: {}",
+                            err
+                        )));
+                    }
+                },
+            ));
+        });
+
+        KeyHandler::add_global_callback(siv, 'S', Controller::show_script);
+
+        KeyHandler::add_global_callback(siv, 't', |siv| {
+            let footer = views::get_footer_view(
+                &siv.find_name::<views::FooterView>("footer_view")
+                    .expect("Bug: footer_view does not exist"),
+            );
+            let controller = siv
+                .user_data::<Controller>()
+                .expect("Bug: Controller does not exist");
+            if controller.scrubber.is_some() {
+                Controller::leave_time_travel(siv);
+                return;
+            }
+            let is_current = controller.snapshots.last().map_or(false, |s| {
+                controller.trace_stack.is_counter_current(s.counter)
+            });
+            if !is_current {
+                siv.add_layer(views::new_dialog(
+                    "No trace data for the current function yet",
+                ));
+                return;
+            }
+            controller.scrubber = Some(Scrubber {
+                index: controller.snapshots.len() - 1,
+                function: controller.trace_stack.get_current_function(),
+                footer,
+            });
+            Controller::show_snapshot(siv);
+        });
+
+        KeyHandler::add_global_callback(siv, 'p', |siv| {
+            let line = siv
+                .find_name::<views::SourceView>("source_view")
+                .expect("Bug: source_view does not exist")
+                .row()
+                .unwrap() as u32
+                + 1;
+            Controller::toggle_pinned(siv, line);
+        });
+
+        KeyHandler::add_global_callback(siv, 'N', Controller::show_workspaces);
+
+        KeyHandler::add_global_callback(siv, 'Y', |siv| {
+            if siv
+                .find_name::<views::TextDialogView>("trace_tree_view")
+                .is_some()
+            {
+                // View is already open, make it no-op
+                return;
+            }
+            let messages = Controller::messages(siv);
+            let text = Controller::trace_tree_text(
+                siv.user_data::<Controller>()
+                    .expect("Bug: Controller does not exist"),
+            );
             siv.add_layer(views::new_text_dialog_view(
-                &format!("Gathering latency histogram for {}...", function),
-                "histogram_view",
+                &messages,
+                &text,
+                "trace_tree_view",
                 |siv| {
-                    let trace_stack = &siv
-                        .user_data::<Controller>()
-                        .expect("Bug: Controller does not exist")
-                        .trace_stack;
-                    trace_stack.set_mode(TraceMode::Line);
                     siv.pop_layer();
                 },
             ));
         });
 
+        KeyHandler::add_global_callback(siv, 'G', |siv| {
+            if siv
+                .find_name::<views::TextDialogView>("auto_profile_view")
+                .is_some()
+            {
+                // View is already open, make it no-op
+                return;
+            }
+            siv.add_layer(views::new_edit_view(
+                "How many functions deep to drill down?",
+                "auto_profile_depth_view",
+                Some(&Controller::AUTO_PROFILE_DEPTH.to_string()),
+                |siv, depth| match depth.trim().parse::<u32>() {
+                    Ok(depth) if depth > 0 => {
+                        siv.pop_layer();
+                        Controller::start_auto_profile(siv, depth);
+                    }
+                    _ => siv.add_layer(views::new_dialog(&format!(
+                        "Depth must be a positive number, got {}",
+                        depth
+                    ))),
+                },
+            ));
+        });
+
+        KeyHandler::add_global_callback(siv, 'P', |siv| {
+            let line = siv
+                .find_name::<views::SourceView>("source_view")
+                .expect("Bug: source_view does not exist")
+                .row()
+                .unwrap() as u32
+                + 1;
+            Controller::toggle_pinned_trace(siv, line);
+        });
+
+        KeyHandler::add_global_callback(siv, 'l', |siv| {
+            let controller = siv
+                .user_data::<Controller>()
+                .expect("Bug: Controller does not exist");
+            controller.latency_statistic = controller.latency_statistic.next();
+            controller.update_line_histograms();
+            Controller::update_latency_column(siv);
+        });
+
+        KeyHandler::add_global_callback(siv, 'w', |siv| {
+            let controller = siv
+                .user_data::<Controller>()
+                .expect("Bug: Controller does not exist");
+            controller.show_off_cpu = !controller.show_off_cpu;
+            controller.trace_stack.set_off_cpu(controller.show_off_cpu);
+            Controller::update_latency_column(siv);
+        });
+
+        KeyHandler::add_global_callback(siv, 'J', |siv| {
+            let controller = siv
+                .user_data::<Controller>()
+                .expect("Bug: Controller does not exist");
+            controller.show_parent_share = !controller.show_parent_share;
+            let show = controller.show_parent_share;
+            let counter_shown = controller.counter_column != CounterColumn::Hidden;
+            let title = controller.messages.get("column.parent_share").to_string();
+            views::set_parent_share_column(
+                &mut siv
+                    .find_name::<views::SourceView>("source_view")
+                    .expect("Bug: source_view does not exist"),
+                &title,
+                show,
+                counter_shown,
+            );
+        });
+
+        KeyHandler::add_global_callback(siv, 'R', |siv| {
+            let controller = siv
+                .user_data::<Controller>()
+                .expect("Bug: Controller does not exist");
+            if controller.program.is_go() {
+                siv.add_layer(views::new_dialog(
+                    "Recursive calls can't be told apart in Go programs, whose stacks move",
+                ));
+                return;
+            }
+            controller.outermost_calls_only = !controller.outermost_calls_only;
+            controller
+                .trace_stack
+                .set_outermost_calls_only(controller.outermost_calls_only);
+            let text = if controller.outermost_calls_only {
+                "Only counting the outermost calls of recursive functions"
+            } else {
+                "Counting every call of recursive functions"
+            };
+            siv.add_layer(views::new_dialog(text));
+        });
+
+        KeyHandler::add_global_callback(siv, 'e', |siv| {
+            let controller = siv
+                .user_data::<Controller>()
+                .expect("Bug: Controller does not exist");
+            let backend = controller.trace_stack.get_backend();
+            if !backend.is_native() {
+                let text = format!(
+                    "Hardware counters are only read by the libbpf backend, not {}",
+                    backend.name()
+                );
+                siv.add_layer(views::new_dialog(&text));
+                return;
+            }
+            let shown = controller.counter_column != CounterColumn::Hidden;
+            controller.counter_column = controller.counter_column.next();
+            let show = controller.counter_column != CounterColumn::Hidden;
+            if show != shown {
+                controller.trace_stack.set_counters(show);
+            }
+            let title = controller
+                .counter_column
+                .column_title(&controller.messages)
+                .map(|title| title.to_string());
+            // Traces the libbpf backend can't run in-process fall back to
+            // bpftrace, which can't read counters
+            let unsupported = if show && !shown {
+                controller.trace_stack.get_native_plan().err()
+            } else {
+                None
+            };
+            views::set_counter_column(
+                &mut siv
+                    .find_name::<views::SourceView>("source_view")
+                    .expect("Bug: source_view does not exist"),
+                title.as_deref(),
+                shown,
+            );
+            let controller = siv
+                .user_data::<Controller>()
+                .expect("Bug: Controller does not exist");
+            if controller.scrubber.is_some() {
+                Controller::show_snapshot(siv);
+            } else {
+                Controller::show_live_lines(siv);
+            }
+            if let Some(err) = unsupported {
+                siv.add_layer(views::new_dialog(&format!(
+                    "Hardware counters will only be read once the trace can run in-process: {}",
+                    err
+                )));
+            }
+        });
+
+        KeyHandler::add_global_callback(siv, 'd', |siv| {
+            let controller = siv
+                .user_data::<Controller>()
+                .expect("Bug: Controller does not exist");
+            controller.show_count_delta = !controller.show_count_delta;
+            if controller.scrubber.is_some() {
+                Controller::show_snapshot(siv);
+            } else {
+                Controller::show_live_lines(siv);
+            }
+        });
+
+        KeyHandler::add_global_callback(
+            siv,
+            cursive::event::Event::Key(cursive::event::Key::Left),
+            |siv| Controller::move_scrubber(siv, -1),
+        );
+        KeyHandler::add_global_callback(
+            siv,
+            cursive::event::Event::Key(cursive::event::Key::Right),
+            |siv| Controller::move_scrubber(siv, 1),
+        );
+
         KeyHandler::add_global_callback(siv, 'f', |siv| {
             if let Some(_) = siv.find_name::<cursive::views::EditView>("filter_view") {
                 // View is already open, make it no-op
                 return;
             }
 
-            let initial_filter = siv
+            let trace_stack = &siv
                 .user_data::<Controller>()
                 .expect("Bug: Controller does not exist")
-                .trace_stack
-                .get_current_filter(false);
-            Controller::setup_user_filter(siv, initial_filter, false);
+                .trace_stack;
+            let initial_filter = trace_stack.get_current_filter(false);
+            let process_filter = trace_stack.get_process_filter();
+            Controller::setup_entry_filters(
+                siv,
+                initial_filter.unwrap_or_default(),
+                process_filter.fields(),
+                process_filter.follow_forks,
+            );
         });
         KeyHandler::add_global_callback(siv, 'g', |siv| {
             if let Some(_) = siv.find_name::<cursive::views::EditView>("filter_view") {
@@ -995,11 +7174,15 @@ impl Controller {
             let controller = siv
                 .user_data::<Controller>()
                 .expect("Bug: Controller does not exist");
-            let initial_results = vec![("Type to search".to_string(), None)];
+            let initial_results = Controller::empty_search_results(
+                &controller.program,
+                &controller.history,
+                "Type to search",
+            );
             controller
                 .searcher
-                .setup_search(initial_results.clone(), Vec::new());
-            let search_view = views::new_search_view(
+                .setup_search(initial_results.clone(), Vec::new(), true);
+            let search_view = views::new_multi_search_view(
                 "Select the functions to trace",
                 initial_results,
                 move |siv: &mut Cursive, view_name: &str, search: &str, n_results: usize| {
@@ -1008,39 +7191,106 @@ impl Controller {
                         .expect("Bug: Controller does not exist");
                     controller.searcher.search(view_name, search, n_results);
                 },
-                move |siv: &mut Cursive, symbol: &SymbolInfo| {
+                Controller::pick_overload(move |siv: &mut Cursive, symbols: Vec<SymbolInfo>| {
+                    siv.pop_layer();
                     let controller = siv
                         .user_data::<Controller>()
                         .expect("Bug: Controller does not exist");
                     // TODO cancel any pending searches
-                    if controller.program.is_dynamic_symbol(symbol) {
-                        // TODO show error for dyn fn
-                    } else {
-                        // TODO need way better layout, way to exit, remove fns etc
+                    let mut start_breakdown = false;
+                    for symbol in &symbols {
+                        let symbol = if Controller::is_dynamic(&controller.program, symbol) {
+                            match Controller::load_dynamic_symbol(
+                                &mut controller.program,
+                                symbol.name,
+                            ) {
+                                Ok(symbol) => symbol,
+                                Err(err) => {
+                                    log::warn!("Not tracing {}: {}", symbol.name, err);
+                                    continue;
+                                }
+                            }
+                        } else {
+                            symbol.clone()
+                        };
                         if symbol.name.0 == "main" {
-                            controller.trace_stack.set_mode(TraceMode::Breakdown);
-                            let current_function = controller.trace_stack.get_current_function();
-                            siv.add_layer(views::new_text_dialog_view(
-                                &format!("Gathering latency breakdown for {}...", current_function),
-                                "breakdown_view",
-                                |siv| {
-                                    let trace_stack = &siv
-                                        .user_data::<Controller>()
-                                        .expect("Bug: Controller does not exist")
-                                        .trace_stack;
-                                    trace_stack.set_mode(TraceMode::Line);
-                                    siv.pop_layer();
-                                },
-                            ));
+                            // TODO need way better layout, way to exit, remove fns etc
+                            start_breakdown = true;
                         } else {
-                            controller.trace_stack.add_breakdown_function(symbol.name);
+                            controller.trace_stack.add_breakdown_function(
+                                symbol.name,
+                                controller.program.get_return_offsets(symbol.name),
+                            );
+                            controller.history.record_trace(symbol.name);
                         }
                     }
-                },
+                    if start_breakdown {
+                        controller.trace_stack.set_mode(TraceMode::Breakdown);
+                        let current_function = controller.trace_stack.get_current_function();
+                        let messages = Controller::messages(siv);
+                        siv.add_layer(views::new_text_dialog_view(
+                            &messages,
+                            &messages
+                                .format("breakdown.gathering", &[("function", &current_function)]),
+                            "breakdown_view",
+                            |siv| {
+                                let trace_stack = &siv
+                                    .user_data::<Controller>()
+                                    .expect("Bug: Controller does not exist")
+                                    .trace_stack;
+                                trace_stack.set_mode(TraceMode::Line);
+                                siv.pop_layer();
+                            },
+                        ));
+                    }
+                }),
             );
             siv.add_layer(search_view);
         });
 
+        KeyHandler::add_global_callback(siv, 'i', |siv| {
+            let program = &siv
+                .user_data::<Controller>()
+                .expect("Bug: Controller does not exist")
+                .program;
+            let kind = if program.is_shared_library() {
+                " (shared library)"
+            } else {
+                ""
+            };
+            let text = format!(
+                "About {}{}\n\n{}",
+                program.file_path,
+                kind,
+                program.get_build_info()
+            );
+            let messages = Controller::messages(siv);
+            siv.add_layer(views::new_text_dialog_view(
+                &messages,
+                &text,
+                "info_view",
+                |siv| {
+                    siv.pop_layer();
+                },
+            ));
+        });
+
+        KeyHandler::add_global_callback(siv, 'K', Controller::show_capabilities);
+
+        KeyHandler::add_global_callback(siv, 'E', Controller::show_losses);
+
+        KeyHandler::add_global_callback(siv, 'U', |siv| {
+            if let Some(_) = siv.find_name::<views::TextDialogView>("tracer_view") {
+                // View is already open, make it no-op
+                return;
+            }
+            Controller::show_tracer_status(siv);
+        });
+
+        KeyHandler::add_global_callback(siv, 'M', Controller::show_memory_usage);
+
+        KeyHandler::add_global_callback(siv, 'L', Controller::pick_mapped_object);
+
         KeyHandler::add_global_callback(siv, 'm', |siv| {
             let controller = siv
                 .user_data::<Controller>()
@@ -1048,7 +7298,7 @@ impl Controller {
             let initial_results = vec![("Type to search".to_string(), None)];
             controller
                 .searcher
-                .setup_search(initial_results.clone(), Vec::new());
+                .setup_search(initial_results.clone(), Vec::new(), false);
             let search_view = views::new_search_view(
                 "Select a function to get its mangled name",
                 initial_results,
@@ -1068,6 +7318,54 @@ impl Controller {
             );
             siv.add_layer(search_view);
         });
+
+        KeyHandler::add_global_callback(siv, cursive::event::Event::CtrlChar('p'), |siv| {
+            // Templates can be run from the palette directly
+            let templates = siv
+                .user_data::<Controller>()
+                .expect("Bug: Controller does not exist")
+                .templates
+                .iter()
+                .cloned()
+                .map(PaletteItem::Template);
+            let items = PALETTE_COMMANDS
+                .iter()
+                .cloned()
+                .map(PaletteItem::Command)
+                .chain(templates)
+                .collect();
+            let search_view = views::new_simple_search_view(
+                "Run a command",
+                items,
+                |siv: &mut Cursive, item: &PaletteItem| match item {
+                    PaletteItem::Command(command) => {
+                        if command.advanced {
+                            siv.user_data::<Controller>()
+                                .expect("Bug: Controller does not exist")
+                                .key_handler
+                                .advanced_mode_key_pressed();
+                        }
+                        siv.on_event(command.event.clone());
+                    }
+                    PaletteItem::Template(applicable) => {
+                        if siv
+                            .find_name::<views::TextDialogView>("template_view")
+                            .is_none()
+                        {
+                            Controller::run_template(siv, applicable);
+                        }
+                    }
+                },
+            );
+            siv.add_layer(search_view);
+        });
+    }
+}
+
+impl Drop for Controller {
+    fn drop(&mut self) {
+        self.kill_single_call();
+        self.kill_outliers();
     }
 }
 
@@ -1133,13 +7431,251 @@ impl KeyHandler {
     }
 }
 
+/// Command listed in the command palette (`Ctrl-p`). Commands are run by
+/// simulating their key press, so they behave exactly like the key.
+#[derive(Clone)]
+struct PaletteCommand {
+    description: &'static str,
+    event: cursive::event::Event,
+    /// Whether the key is pressed in advanced mode, see `KeyHandler`
+    advanced: bool,
+}
+
+impl PaletteCommand {
+    const fn new(description: &'static str, c: char) -> PaletteCommand {
+        PaletteCommand {
+            description,
+            event: cursive::event::Event::Char(c),
+            advanced: false,
+        }
+    }
+
+    const fn advanced(description: &'static str, c: char) -> PaletteCommand {
+        PaletteCommand {
+            description,
+            event: cursive::event::Event::Char(c),
+            advanced: true,
+        }
+    }
+
+    const fn ctrl(description: &'static str, c: char) -> PaletteCommand {
+        PaletteCommand {
+            description,
+            event: cursive::event::Event::CtrlChar(c),
+            advanced: false,
+        }
+    }
+
+    const fn key(description: &'static str, key: cursive::event::Key) -> PaletteCommand {
+        PaletteCommand {
+            description,
+            event: cursive::event::Event::Key(key),
+            advanced: false,
+        }
+    }
+}
+
+/// Entry of the workspace list shown with `N`
+#[derive(Clone)]
+enum WorkspaceItem {
+    /// Switch to the workspace at this index, with its label
+    Switch(usize, String),
+    New,
+    Close,
+}
+
+impl fmt::Display for WorkspaceItem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WorkspaceItem::Switch(_, label) => f.write_str(label),
+            WorkspaceItem::New => f.write_str("+ New workspace"),
+            WorkspaceItem::Close => f.write_str("- Close current workspace"),
+        }
+    }
+}
+
+impl search::Label for WorkspaceItem {
+    fn label(&self) -> Cow<str> {
+        Cow::Owned(self.to_string())
+    }
+}
+
+/// Entry of the command palette
+#[derive(Clone)]
+enum PaletteItem {
+    Command(PaletteCommand),
+    Template(templates::Applicable),
+}
+
+const PALETTE_COMMANDS: &[PaletteCommand] = &[
+    PaletteCommand::new("Toggle tracing the call on the current line", 'x'),
+    PaletteCommand::ctrl("Toggle tracing the calls on every line", 'x'),
+    PaletteCommand::advanced("Toggle tracing an address range on the current line", 'x'),
+    PaletteCommand::new(
+        "Toggle tracing an inlined function on the current line",
+        'X',
+    ),
+    PaletteCommand::advanced("Trace the instruction at a function offset or address", 'X'),
+    PaletteCommand::new("Mark the start or end of a span of lines to trace", 'z'),
+    PaletteCommand::key(
+        "Push the call on the current line onto the trace stack",
+        cursive::event::Key::Enter,
+    ),
+    PaletteCommand::new("Push any function onto the trace stack", '>'),
+    PaletteCommand::new("List calls inlined on the current line", 'I'),
+    PaletteCommand::new("Only time 1 in N calls on the current line", 'n'),
+    PaletteCommand::new("Set how often results refresh", 'V'),
+    PaletteCommand::new(
+        "Only count calls on the current line slower than a threshold",
+        'F',
+    ),
+    PaletteCommand::key(
+        "Pop the current function off the trace stack",
+        cursive::event::Key::Esc,
+    ),
+    PaletteCommand::new("Restart trace", 'r'),
+    PaletteCommand::new("Time travel through aggregates", 't'),
+    PaletteCommand::new(
+        "Toggle showing event counts of the last interval instead of rates",
+        'd',
+    ),
+    PaletteCommand::new(
+        "Cycle the latency column between average, p50, p99 and max",
+        'l',
+    ),
+    PaletteCommand::new("Toggle showing the share of latency spent off-CPU", 'w'),
+    PaletteCommand::new(
+        "Toggle a column with each line's share of the function's time",
+        'J',
+    ),
+    PaletteCommand::new(
+        "Toggle counting only the outermost calls of a recursive function",
+        'R',
+    ),
+    PaletteCommand::new(
+        "Cycle the counter column between IPC, cache misses per call and hidden",
+        'e',
+    ),
+    PaletteCommand::new("Show latency histogram", 'h'),
+    PaletteCommand::new(
+        "Toggle live histogram of the current line beside the source",
+        'H',
+    ),
+    PaletteCommand::new(
+        "Show min, max, standard deviation and percentiles of the current line",
+        'D',
+    ),
+    PaletteCommand::new("Pin the current line's stats above the source", 'p'),
+    PaletteCommand::new("Pin the current line's trace across all functions", 'P'),
+    PaletteCommand::new("Switch workspace or trace another function alongside", 'N'),
+    PaletteCommand::new(
+        "Drill down into the most expensive calls automatically",
+        'G',
+    ),
+    PaletteCommand::new("Show the whole trace stack as a tree", 'Y'),
+    PaletteCommand::new("Count callers reaching the current line", 'c'),
+    PaletteCommand::new("Count values returned on the current line", 'v'),
+    PaletteCommand::new("Count argument values of the current line", 'a'),
+    PaletteCommand::new("Group calls on the current line by an argument", 'k'),
+    PaletteCommand::new("Split calls on the current line by thread", 'W'),
+    PaletteCommand::new("Sample branch mispredictions", 'B'),
+    PaletteCommand::new("Capture the next call in detail", 'o'),
+    PaletteCommand::new("Capture calls slower than a threshold", 'O'),
+    PaletteCommand::new("Find where the current function waits for locks", 'u'),
+    PaletteCommand::new("Find where the current function allocates memory", 'A'),
+    PaletteCommand::new(
+        "Find which syscalls the current function spends time in",
+        'y',
+    ),
+    PaletteCommand::new("Count iterations of the loops of the current function", 'C'),
+    PaletteCommand::new(
+        "Trace where indirect calls on the current line jump to",
+        'j',
+    ),
+    PaletteCommand::new(
+        "Set filter on function entry, or on processes and threads",
+        'f',
+    ),
+    PaletteCommand::new("Set filter on function exit", 'g'),
+    PaletteCommand::new("Show latency breakdown by function", 'b'),
+    PaletteCommand::new("Show binary info", 'i'),
+    PaletteCommand::new("Show tracing capabilities", 'K'),
+    PaletteCommand::new("Show memory usage", 'M'),
+    PaletteCommand::new("Show data dropped while tracing", 'E'),
+    PaletteCommand::new("Show tracer status", 'U'),
+    PaletteCommand::new("Run a trace template for a common library", 'T'),
+    PaletteCommand::new("Trace a function of a loaded library", 'L'),
+    PaletteCommand::new("Remap source path", 's'),
+    PaletteCommand::new("Show the generated trace program", 'S'),
+    PaletteCommand::new("Get mangled function name", 'm'),
+];
+
+impl fmt::Display for PaletteCommand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}  [", self.description)?;
+        if self.advanced {
+            write!(f, "Ctrl-t ")?;
+        }
+        match &self.event {
+            cursive::event::Event::Char(c) => write!(f, "{}]", c),
+            cursive::event::Event::Key(key) => write!(f, "{:?}]", key),
+            cursive::event::Event::CtrlChar(c) => write!(f, "Ctrl-{}]", c),
+            event => write!(f, "{:?}]", event),
+        }
+    }
+}
+
+impl fmt::Display for PaletteItem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PaletteItem::Command(command) => command.fmt(f),
+            PaletteItem::Template(applicable) => write!(f, "Run template: {}", applicable),
+        }
+    }
+}
+
+impl search::Label for PaletteItem {
+    fn label(&self) -> Cow<str> {
+        match self {
+            PaletteItem::Command(command) => Cow::Borrowed(command.description),
+            PaletteItem::Template(_) => Cow::Owned(self.to_string()),
+        }
+    }
+}
+
+impl search::Label for ArgumentColumn {
+    fn label(&self) -> Cow<str> {
+        Cow::Owned(self.to_string())
+    }
+}
+
 impl search::Label for CallInstruction {
     fn label(&self) -> Cow<str> {
         Cow::Owned(self.to_string())
     }
 }
+
+impl search::Label for templates::Applicable {
+    fn label(&self) -> Cow<str> {
+        Cow::Owned(self.to_string())
+    }
+}
+
 impl search::Label for program::SymbolInfo {
     fn label(&self) -> Cow<str> {
-        Cow::Borrowed(self.as_ref())
+        // Aliases are only listed under their canonical symbol, so should match
+        // the search too
+        if self.aliases.is_empty() {
+            self.display_name()
+        } else {
+            let aliases: Vec<String> = self.aliases.iter().map(|a| a.to_string()).collect();
+            Cow::Owned(format!("{} {}", self.display_name(), aliases.join(" ")))
+        }
+    }
+}
+
+impl search::Label for MappedObject {
+    fn label(&self) -> Cow<str> {
+        Cow::Borrowed(&self.path)
     }
 }
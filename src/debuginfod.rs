@@ -0,0 +1,166 @@
+use crate::store;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::Mutex;
+
+/// Seconds to wait for each debuginfod server before giving up
+const TIMEOUT_SECONDS: u32 = 60;
+
+lazy_static::lazy_static! {
+    /// Artifacts that no server had, so we don't keep asking for them
+    static ref MISSING: Mutex<HashSet<String>> = Mutex::new(HashSet::new());
+}
+
+/// Path to the debug info of the binary with `build_id`, downloaded from the
+/// debuginfod servers in `DEBUGINFOD_URLS` (or previously cached). Returns
+/// `None` if no servers are configured or none of them have it.
+pub fn fetch_debuginfo(build_id: &[u8]) -> Option<PathBuf> {
+    fetch(build_id, "debuginfo", Path::new("debuginfo"))
+}
+
+/// Path to a copy of `source_file` (as recorded in the debug info) of the
+/// binary with `build_id`, see `fetch_debuginfo`. Blocks while downloading,
+/// so shouldn't be called from the UI thread.
+pub fn fetch_source(build_id: &[u8], source_file: &str) -> Option<PathBuf> {
+    let (artifact, cache_path) = source_artifact(source_file)?;
+    fetch(build_id, &artifact, &cache_path)
+}
+
+/// Path to the copy of `source_file` of the binary with `build_id` if
+/// `fetch_source` already downloaded it, without asking any server
+pub fn cached_source(build_id: &[u8], source_file: &str) -> Option<PathBuf> {
+    let (_, cache_path) = source_artifact(source_file)?;
+    local_path(&hex(build_id), &cache_path).filter(|path| path.is_file())
+}
+
+/// Whether `fetch_source` may find `source_file` of the binary with
+/// `build_id`, i.e. servers are configured and haven't been asked for it yet
+pub fn may_fetch_source(build_id: &[u8], source_file: &str) -> bool {
+    match source_artifact(source_file) {
+        Some((artifact, _)) => {
+            std::env::var_os("DEBUGINFOD_URLS").is_some()
+                && !MISSING
+                    .lock()
+                    .unwrap()
+                    .contains(&format!("{}/{}", hex(build_id), artifact))
+        }
+        None => false,
+    }
+}
+
+/// Name of the artifact of `source_file` on servers, and where it is cached
+/// relative to the cache directory for its build id
+fn source_artifact(source_file: &str) -> Option<(String, PathBuf)> {
+    // Servers can only look up absolute paths. The path is also used within
+    // the cache, so must not escape it.
+    if !source_file.starts_with('/') || source_file.split('/').any(|c| c == "..") {
+        return None;
+    }
+    Some((
+        format!("source{}", url_escape(source_file)),
+        Path::new("source").join(&source_file[1..]),
+    ))
+}
+
+/// Where an artifact is cached, see `fetch`
+fn local_path(build_id: &str, cache_path: &Path) -> Option<PathBuf> {
+    Some(
+        store::cache_dir()?
+            .join("debuginfod")
+            .join(build_id)
+            .join(cache_path),
+    )
+}
+
+/// Download `artifact` of `build_id`, caching it at `cache_path` (relative to
+/// the cache directory for `build_id`)
+fn fetch(build_id: &[u8], artifact: &str, cache_path: &Path) -> Option<PathBuf> {
+    let build_id = hex(build_id);
+    let path = local_path(&build_id, cache_path)?;
+    if path.is_file() {
+        return Some(path);
+    }
+    let urls = std::env::var("DEBUGINFOD_URLS").ok()?;
+    let key = format!("{}/{}", build_id, artifact);
+    if MISSING.lock().unwrap().contains(&key) {
+        return None;
+    }
+    let dir = path.parent()?;
+    if let Err(err) = std::fs::create_dir_all(dir) {
+        log::warn!("Failed to create {}: {}", dir.display(), err);
+        return None;
+    }
+
+    // Download to a temporary file so an interrupted download doesn't end up
+    // in the cache
+    let tmp_path = PathBuf::from(format!("{}.tmp{}", path.display(), std::process::id()));
+    for url in urls.split_whitespace() {
+        let url = format!(
+            "{}/buildid/{}/{}",
+            url.trim_end_matches('/'),
+            build_id,
+            artifact
+        );
+        log::info!("Fetching {}", url);
+        let status = Command::new("curl")
+            .args(["--fail", "--silent", "--show-error", "--location"])
+            .arg("--max-time")
+            .arg(TIMEOUT_SECONDS.to_string())
+            .arg("--output")
+            .arg(&tmp_path)
+            .arg(&url)
+            .status();
+        match status {
+            Ok(status) if status.success() => match std::fs::rename(&tmp_path, &path) {
+                Ok(()) => {
+                    log::info!("Saved {} to {}", url, path.display());
+                    return Some(path);
+                }
+                Err(err) => {
+                    log::warn!("Failed to save {}: {}", path.display(), err);
+                    break;
+                }
+            },
+            Ok(status) => log::info!("Failed to fetch {}: {}", url, status),
+            Err(err) => {
+                log::warn!("Failed to run curl: {}", err);
+                break;
+            }
+        }
+    }
+    let _ = std::fs::remove_file(&tmp_path);
+    MISSING.lock().unwrap().insert(key);
+    None
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Percent-encode everything in `path` other than unreserved characters and
+/// `/`
+fn url_escape(path: &str) -> String {
+    path.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_url_escape() {
+        assert_eq!(url_escape("/usr/src/foo.c"), "/usr/src/foo.c");
+        assert_eq!(
+            url_escape("/build/my dir/a+b.cc"),
+            "/build/my%20dir/a%2Bb.cc"
+        );
+    }
+}
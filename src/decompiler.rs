@@ -0,0 +1,187 @@
+use crate::error::Error;
+use crate::program::FunctionName;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::{Arc, Mutex};
+
+/// Runs an external decompiler (e.g. a wrapper around Ghidra's headless
+/// analyzer or retdec) to show pseudo-code for functions without source
+/// information.
+///
+/// The command is run by the shell with the program path, mangled function
+/// name and function address (in hex) appended as arguments, and should print
+/// pseudo-code on stdout. A line can be prefixed with `@<hex address>` and a
+/// tab to mark the instruction address it starts at, which is used to map calls
+/// to lines.
+///
+/// Decompilers can take a while, so `decompile` is run in the background and
+/// its results are kept for `get_decompiled`. Clones share them.
+#[derive(Clone)]
+pub struct Decompiler {
+    command: String,
+    /// Functions decompiled so far, keyed by program path and address
+    decompiled: Arc<Mutex<HashMap<(String, u64), Decompiled>>>,
+}
+
+/// Pseudo-code for a single function
+#[derive(Clone)]
+pub struct Decompiled {
+    /// File the pseudo-code was written to, displayed as the source file
+    pub path: String,
+    /// (address, line) of marked lines, sorted by address
+    line_addresses: Vec<(u64, u32)>,
+}
+
+impl Decompiled {
+    /// Write `output` (in the format printed by decompilers, see `Decompiler`)
+    /// to `file_name` in the output directory
+    pub fn write(output: &str, file_name: &str) -> Result<Decompiled, Error> {
+        let (text, line_addresses) = parse_output(output);
+        let dir = output_dir();
+        let path = dir.join(file_name);
+        std::fs::create_dir_all(&dir)
+            .and_then(|_| std::fs::write(&path, text))
+            .map_err(|err| format!("Failed to write {}: {}", path.display(), err))?;
+        Ok(Decompiled {
+            path: path.to_string_lossy().into_owned(),
+            line_addresses,
+        })
+    }
+
+    /// Line containing the instruction at `address`, i.e. the closest marked
+    /// line at or before it
+    pub fn get_line(&self, address: u64) -> Option<u32> {
+        match self
+            .line_addresses
+            .binary_search_by_key(&address, |&(line_address, _)| line_address)
+        {
+            Ok(index) => Some(self.line_addresses[index].1),
+            Err(0) => None,
+            Err(index) => Some(self.line_addresses[index - 1].1),
+        }
+    }
+}
+
+impl Decompiler {
+    pub fn new(command: String) -> Decompiler {
+        Decompiler {
+            command,
+            decompiled: Arc::default(),
+        }
+    }
+
+    /// Pseudo-code of the function at `address` in `program_path`, if it was
+    /// decompiled already
+    pub fn get_decompiled(&self, program_path: &str, address: u64) -> Option<Decompiled> {
+        self.decompiled
+            .lock()
+            .unwrap()
+            .get(&(program_path.to_string(), address))
+            .cloned()
+    }
+
+    /// Run the decompiler on `function`, blocking until it is done. The
+    /// pseudo-code is then returned by `get_decompiled`.
+    pub fn decompile(
+        &self,
+        program_path: &str,
+        function: FunctionName,
+        address: u64,
+    ) -> Result<(), Error> {
+        log::info!("Decompiling {} with '{}'", function, self.command);
+        let output = Command::new("sh")
+            .arg("-c")
+            .arg(format!("{} \"$@\"", self.command))
+            .arg("sh")
+            .args([program_path, function.0, &format!("{:#x}", address)])
+            .output()
+            .map_err(|err| format!("Failed to run decompiler: {}", err))?;
+        if !output.status.success() {
+            return Err(format!(
+                "Decompiler failed on {}, status: {:?}, stderr:\n{}",
+                function,
+                output.status,
+                String::from_utf8_lossy(&output.stderr)
+            )
+            .into());
+        }
+        // Functions of different libraries may have the same address
+        let program_name = Path::new(program_path)
+            .file_name()
+            .map_or_else(|| program_path.into(), |name| name.to_string_lossy());
+        let decompiled = Decompiled::write(
+            &String::from_utf8_lossy(&output.stdout),
+            &format!("{}-{:x}.c", program_name, address),
+        )?;
+        self.decompiled
+            .lock()
+            .unwrap()
+            .insert((program_path.to_string(), address), decompiled);
+        Ok(())
+    }
+}
+
+/// Whether `path` is pseudo-code written by `Decompiler`
+pub fn is_decompiled(path: &str) -> bool {
+    Path::new(path).starts_with(output_dir())
+}
+
+/// Remove the pseudo-code written by this process, on exit
+pub fn remove_output_dir() {
+    let _ = std::fs::remove_dir_all(output_dir());
+}
+
+fn output_dir() -> PathBuf {
+    std::env::temp_dir().join(format!("wachy-decompiled-{}", std::process::id()))
+}
+
+/// Split decompiler output into pseudo-code with address markers removed and
+/// the (address, line) of marked lines
+fn parse_output(output: &str) -> (String, Vec<(u64, u32)>) {
+    let mut text = String::new();
+    let mut line_addresses = Vec::new();
+    for (i, line) in output.lines().enumerate() {
+        let marked = line
+            .strip_prefix('@')
+            .and_then(|l| l.split_once('\t'))
+            .and_then(|(address, rest)| {
+                let address = address.trim_start_matches("0x");
+                u64::from_str_radix(address, 16)
+                    .ok()
+                    .map(|address| (address, rest))
+            });
+        let line = match marked {
+            Some((address, rest)) => {
+                line_addresses.push((address, i as u32 + 1));
+                rest
+            }
+            None => line,
+        };
+        text += line;
+        text += "\n";
+    }
+    line_addresses.sort_unstable();
+    (text, line_addresses)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_output() {
+        let (text, line_addresses) =
+            parse_output("int foo(void) {\n@0x1010\tbar();\n@1020\treturn baz();\n}\n");
+        assert_eq!(text, "int foo(void) {\nbar();\nreturn baz();\n}\n");
+        assert_eq!(line_addresses, vec![(0x1010, 2), (0x1020, 3)]);
+
+        let decompiled = Decompiled {
+            path: String::new(),
+            line_addresses,
+        };
+        assert_eq!(decompiled.get_line(0x1000), None);
+        assert_eq!(decompiled.get_line(0x1018), Some(2));
+        assert_eq!(decompiled.get_line(0x1030), Some(3));
+    }
+}
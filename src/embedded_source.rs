@@ -0,0 +1,155 @@
+use crate::program::DwarfReader;
+use gimli::constants;
+use gimli::{AttributeValue, Reader, Section};
+
+/// Content type used by clang's `-gembed-source` to embed source text in
+/// DWARF 5 line tables. gimli drops unknown content types, so file entries are
+/// parsed again here to get it.
+const DW_LNCT_LLVM_SOURCE: constants::DwLnct = constants::DwLnct(0x2001);
+
+/// Source text embedded for each file of the line program of `unit`, indexed
+/// the same as `LineProgramHeader::file_names`. Empty if nothing is embedded.
+pub fn get_embedded_sources(
+    dwarf: &gimli::Dwarf<DwarfReader>,
+    unit: &gimli::Unit<DwarfReader>,
+) -> gimli::Result<Vec<Option<String>>> {
+    let header = match &unit.line_program {
+        Some(line_program) => line_program.header(),
+        None => return Ok(Vec::new()),
+    };
+    if header.version() < 5
+        || !header
+            .file_name_entry_format()
+            .iter()
+            .any(|f| f.content_type == DW_LNCT_LLVM_SOURCE)
+    {
+        return Ok(Vec::new());
+    }
+
+    let format = header.format();
+    let mut reader = dwarf.debug_line.reader().clone();
+    reader.skip(header.offset().0)?;
+    reader.read_initial_length()?;
+    // version, address_size, segment_selector_size
+    reader.skip(4)?;
+    // header_length
+    reader.read_offset(format)?;
+    // minimum_instruction_length, maximum_operations_per_instruction,
+    // default_is_stmt, line_base, line_range, opcode_base
+    reader.skip(6)?;
+    reader.skip((header.opcode_base() as usize).saturating_sub(1))?;
+
+    let directory_formats = read_formats(&mut reader)?;
+    for _ in 0..reader.read_uleb128()? {
+        for &(_, form) in &directory_formats {
+            skip_form(&mut reader, form, format)?;
+        }
+    }
+
+    let file_formats = read_formats(&mut reader)?;
+    let mut sources = Vec::new();
+    for _ in 0..reader.read_uleb128()? {
+        let mut source = None;
+        for &(content_type, form) in &file_formats {
+            if content_type == DW_LNCT_LLVM_SOURCE {
+                source = read_string(dwarf, unit, &mut reader, form, format)?;
+            } else {
+                skip_form(&mut reader, form, format)?;
+            }
+        }
+        sources.push(source);
+    }
+    Ok(sources)
+}
+
+fn read_formats(
+    reader: &mut DwarfReader,
+) -> gimli::Result<Vec<(constants::DwLnct, constants::DwForm)>> {
+    let count = reader.read_u8()?;
+    (0..count)
+        .map(|_| {
+            let content_type = constants::DwLnct(reader.read_uleb128_u16()?);
+            let form = constants::DwForm(reader.read_uleb128_u16()?);
+            Ok((content_type, form))
+        })
+        .collect()
+}
+
+/// Read a string that is inline, in a string section, or indexed through the
+/// string offsets of `unit` (`DW_FORM_strx*`, as emitted with
+/// `-gsplit-dwarf` or by default for DWARF 5 by recent clang). Returns `None`
+/// for other forms.
+fn read_string(
+    dwarf: &gimli::Dwarf<DwarfReader>,
+    unit: &gimli::Unit<DwarfReader>,
+    reader: &mut DwarfReader,
+    form: constants::DwForm,
+    format: gimli::Format,
+) -> gimli::Result<Option<String>> {
+    let value = match form {
+        constants::DW_FORM_string => AttributeValue::String(reader.read_null_terminated_slice()?),
+        constants::DW_FORM_line_strp => {
+            AttributeValue::DebugLineStrRef(gimli::DebugLineStrOffset(reader.read_offset(format)?))
+        }
+        constants::DW_FORM_strp => {
+            AttributeValue::DebugStrRef(gimli::DebugStrOffset(reader.read_offset(format)?))
+        }
+        constants::DW_FORM_strx
+        | constants::DW_FORM_strx1
+        | constants::DW_FORM_strx2
+        | constants::DW_FORM_strx3
+        | constants::DW_FORM_strx4 => {
+            let index = match form {
+                constants::DW_FORM_strx => reader.read_uleb128()?,
+                constants::DW_FORM_strx1 => reader.read_u8()?.into(),
+                constants::DW_FORM_strx2 => reader.read_u16()?.into(),
+                constants::DW_FORM_strx3 => reader.read_uint(3)?,
+                _ => reader.read_u32()?.into(),
+            };
+            AttributeValue::DebugStrOffsetsIndex(gimli::DebugStrOffsetsIndex(index as usize))
+        }
+        _ => {
+            skip_form(reader, form, format)?;
+            return Ok(None);
+        }
+    };
+    let string = dwarf.attr_string(unit, value)?;
+    Ok(Some(string.to_string_lossy()?.into_owned()))
+}
+
+/// Skip over a value of the forms allowed in line table entry formats
+fn skip_form(
+    reader: &mut DwarfReader,
+    form: constants::DwForm,
+    format: gimli::Format,
+) -> gimli::Result<()> {
+    let len = match form {
+        constants::DW_FORM_string => {
+            reader.read_null_terminated_slice()?;
+            return Ok(());
+        }
+        constants::DW_FORM_udata | constants::DW_FORM_strx => {
+            reader.read_uleb128()?;
+            return Ok(());
+        }
+        constants::DW_FORM_sdata => {
+            reader.read_sleb128()?;
+            return Ok(());
+        }
+        constants::DW_FORM_line_strp | constants::DW_FORM_strp | constants::DW_FORM_sec_offset => {
+            format.word_size() as usize
+        }
+        constants::DW_FORM_data1 | constants::DW_FORM_strx1 => 1,
+        constants::DW_FORM_data2 | constants::DW_FORM_strx2 => 2,
+        constants::DW_FORM_strx3 => 3,
+        constants::DW_FORM_data4 | constants::DW_FORM_strx4 => 4,
+        constants::DW_FORM_data8 => 8,
+        constants::DW_FORM_data16 => 16,
+        constants::DW_FORM_block => reader.read_uleb128()? as usize,
+        constants::DW_FORM_block1 => reader.read_u8()? as usize,
+        constants::DW_FORM_block2 => reader.read_u16()? as usize,
+        constants::DW_FORM_block4 => reader.read_u32()? as usize,
+        _ => return Err(gimli::Error::UnknownForm),
+    };
+    reader.skip(len)
+}
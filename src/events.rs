@@ -1,6 +1,15 @@
+use crate::allocations::Allocations;
+use crate::contention::LockWaits;
+use crate::error::Error;
+use crate::lbr::BranchCounts;
+use crate::loops::LoopIterations;
+use crate::losses::Losses;
 use crate::program::FunctionName;
 use crate::program::SymbolInfo;
+use crate::syscalls::Syscalls;
+use crate::trace_structs::CallInstruction;
 use std::collections::HashMap;
+use std::sync::mpsc;
 use std::time::Duration;
 
 /// Events communicated to the controller
@@ -10,6 +19,8 @@ pub enum Event {
         error_message: String,
     },
     TraceData(TraceInfo),
+    /// bpftrace reported dropping output or map updates while tracing
+    TraceLosses(Losses),
     TraceCommandModified,
     SearchResults {
         counter: u64,
@@ -17,6 +28,118 @@ pub enum Event {
         results: Vec<(String, Option<SymbolInfo>)>,
     },
     SelectedFunction(FunctionName),
+    /// Multiple functions were marked in the initial function search, show
+    /// them side by side
+    CompareFunctions(Vec<SymbolInfo>),
+    /// Last branch records of `function` were sampled, with counts keyed by
+    /// offset within the function
+    BranchSamples {
+        function: FunctionName,
+        result: Result<HashMap<u64, BranchCounts>, Error>,
+    },
+    /// Lock waits of `function` were traced, keyed by the offset within it
+    /// they were reached from (see `contention::parse_waits`)
+    LockWaits {
+        function: FunctionName,
+        result: Result<HashMap<u64, LockWaits>, Error>,
+    },
+    /// Indirect calls on `line` of `function` were traced, with how often
+    /// each address in the program was called, most called first (see
+    /// `TraceStack::parse_call_targets`)
+    CallTargets {
+        function: FunctionName,
+        line: u32,
+        result: Result<Vec<(u64, u64)>, Error>,
+    },
+    /// Syscalls made by `function` were traced (see `syscalls::parse_syscalls`)
+    Syscalls {
+        function: FunctionName,
+        result: Result<Syscalls, Error>,
+    },
+    /// Iterations of the loops of `function` were counted (see
+    /// `loops::parse_iterations`)
+    LoopIterations {
+        function: FunctionName,
+        result: Result<LoopIterations, Error>,
+    },
+    /// Allocations made by `function` were traced, keyed by the offset within
+    /// it they were made from (see `allocations::parse_allocations`)
+    Allocations {
+        function: FunctionName,
+        result: Result<HashMap<u64, Allocations>, Error>,
+    },
+    /// The program capturing a single call of `function` (with process id
+    /// `pid`) exited, with its parsed output if successful
+    SingleCall {
+        pid: u32,
+        function: FunctionName,
+        result: Result<String, Error>,
+    },
+    /// The program capturing slow calls (with process id `pid`) printed one,
+    /// or failed
+    Outlier {
+        pid: u32,
+        result: Result<Outlier, Error>,
+    },
+    /// The program estimating how often each of `callsites` (lines of
+    /// `function` and their calls) is called exited, with the calls per
+    /// second keyed by offset within the function if successful
+    CallRate {
+        function: FunctionName,
+        callsites: Vec<(u32, CallInstruction)>,
+        result: Result<HashMap<u32, u64>, Error>,
+    },
+    /// Downloading `source_file` of `function` from debuginfod servers
+    /// finished, `found` if a server had it
+    DebuginfodSource {
+        function: FunctionName,
+        source_file: String,
+        found: bool,
+    },
+    /// CPU time of `function` was sampled, with sample counts keyed by offset
+    /// within the function
+    CpuSamples {
+        function: FunctionName,
+        result: Result<HashMap<u64, u64>, Error>,
+    },
+    /// The program of the template named `name` exited, with its parsed
+    /// output if successful
+    Template {
+        name: String,
+        result: Result<String, Error>,
+    },
+    /// The calls of `function` were traced for long enough to drill down into
+    /// the most expensive one, see `Controller::auto_profile_step`
+    AutoProfileTick {
+        function: FunctionName,
+        /// See `Controller::auto_profile_counter`
+        counter: u64,
+    },
+    /// The decompiler finished running on `function`, which is then shown as
+    /// given by `then` if successful
+    Decompiled {
+        function: FunctionName,
+        then: AfterDecompiling,
+        result: Result<(), Error>,
+    },
+    /// The traced processes mapped objects the program doesn't link, e.g.
+    /// plugins loaded with `dlopen`, given by path
+    ObjectsMapped(Vec<String>),
+    /// Command received over the control socket. Reply must be sent exactly
+    /// once.
+    ControlCommand {
+        command: String,
+        reply_tx: mpsc::Sender<String>,
+    },
+}
+
+/// How to show a function once it is decompiled, see `Event::Decompiled`
+pub enum AfterDecompiling {
+    /// Push it onto the trace stack, entered from the given line of the
+    /// current function if any
+    Enter(Option<u32>),
+    /// Start a new workspace tracing the symbol it was resolved from
+    AddWorkspace(SymbolInfo),
 }
 
 /// Format in which trace data is passed back
@@ -26,6 +149,92 @@ pub struct TraceInfo {
     /// Time for which current trace has been running
     pub time: Duration,
     pub traces: TraceInfoMode,
+    /// Latency histogram of each traced line, only collected in Line mode
+    /// while the histogram pane is shown
+    pub histograms: Option<HashMap<u32, String>>,
+    /// Latency statistics of each traced line, collected along with
+    /// `histograms`
+    pub statistics: Option<HashMap<u32, LineStatistics>>,
+    /// Time each traced line spent off-CPU, only collected in Line mode while
+    /// off-CPU time is shown
+    pub off_cpu: Option<HashMap<u32, Duration>>,
+    /// Calls of each traced line that never returned, because they were
+    /// unwound by an exception or longjmp. Only counted in Line mode.
+    pub aborted: Option<HashMap<u32, u64>>,
+    /// Hardware counters of each traced line, only read by the libbpf backend
+    /// while the counter column is shown
+    pub counters: Option<HashMap<u32, LineCounters>>,
+    /// Cumulative value of each line pinned with
+    /// `TraceStack::toggle_pinned_trace`, in the order they were pinned. Only
+    /// traced in Line mode.
+    pub pinned: Option<Vec<TraceCumulative>>,
+    /// Cumulative value of the calls of the current function of each other
+    /// workspace, by index (see `TraceStack::get_workspaces`). Only traced in
+    /// Line mode.
+    pub workspaces: Option<HashMap<usize, TraceCumulative>>,
+}
+
+/// Hardware counters of a traced line, summed over the calls it stayed on one
+/// CPU for. Counters are per CPU, so calls that migrate can't be measured.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct LineCounters {
+    /// Calls the counters were read for
+    pub calls: u64,
+    pub cycles: u64,
+    pub instructions: u64,
+    /// Last level cache misses
+    pub cache_misses: u64,
+}
+
+impl LineCounters {
+    pub fn add(&mut self, other: LineCounters) {
+        self.calls += other.calls;
+        self.cycles += other.cycles;
+        self.instructions += other.instructions;
+        self.cache_misses += other.cache_misses;
+    }
+
+    /// Instructions per cycle, if any cycles were counted
+    pub fn ipc(&self) -> Option<f32> {
+        (self.cycles > 0).then(|| self.instructions as f32 / self.cycles as f32)
+    }
+
+    /// Cache misses per call, if any calls were counted
+    pub fn cache_misses_per_call(&self) -> Option<f32> {
+        (self.calls > 0).then(|| self.cache_misses as f32 / self.calls as f32)
+    }
+}
+
+/// Latency statistics of a traced line, over calls of the outermost traced
+/// function it ran in
+#[derive(Clone, Copy)]
+pub struct LineStatistics {
+    pub calls: u64,
+    pub min: Duration,
+    pub max: Duration,
+    pub std_dev: Duration,
+}
+
+impl LineStatistics {
+    /// From the `duration` of all calls in nanoseconds, and the
+    /// `sum_squares` of their durations in microseconds
+    pub fn new(min: u64, max: u64, duration: u64, sum_squares: u64, calls: u64) -> LineStatistics {
+        let std_dev = if calls == 0 {
+            0.0
+        } else {
+            let mean = duration as f64 / 1000.0 / calls as f64;
+            // Clamped, as the squares are of truncated durations
+            (sum_squares as f64 / calls as f64 - mean * mean)
+                .max(0.0)
+                .sqrt()
+        };
+        LineStatistics {
+            calls,
+            min: Duration::from_nanos(min),
+            max: Duration::from_nanos(max),
+            std_dev: Duration::from_secs_f64(std_dev / 1_000_000.0),
+        }
+    }
 }
 
 pub enum TraceInfoMode {
@@ -39,11 +248,54 @@ pub enum TraceInfoMode {
         /// `TraceStack.breakdown_functions`.
         breakdown_traces: Vec<TraceCumulative>,
     },
+    /// (return address in the program, count) of each caller, most frequent
+    /// first
+    Callers(Vec<(u64, u64)>),
+    /// Values returned by the traced call, most frequent first
+    ReturnValues(Vec<ReturnValueCount>),
+    /// (value, count) of the most frequent values of each captured argument,
+    /// most frequent first. Values are as printed by bpftrace.
+    Arguments(Vec<Vec<(String, u64)>>),
+    /// Traced calls grouped by the value of an argument, most frequent first
+    Groups(Vec<GroupCount>),
 }
 
+#[derive(Clone, Debug)]
 pub struct TraceCumulative {
     /// Cumulative time spent
     pub duration: Duration,
     /// Cumulative count
     pub count: u64,
 }
+
+/// How often a traced call was made with an argument of `value` (as printed by
+/// bpftrace), and the total time those calls took
+#[derive(Debug, PartialEq)]
+pub struct GroupCount {
+    pub value: String,
+    pub count: u64,
+    pub duration: Duration,
+}
+
+/// Call that took at least the outlier threshold, see
+/// `TraceStack::get_outliers_expr`
+pub struct Outlier {
+    /// Wall clock time it returned at
+    pub time: String,
+    pub duration: Duration,
+    /// Thread id and name
+    pub thread: String,
+    /// Values of the argument registers, in hex
+    pub arguments: String,
+    /// Stack of the caller, if stack traces are supported
+    pub stack: Option<String>,
+}
+
+/// How often a traced call returned `value`, and the total time those calls
+/// took
+#[derive(Debug, PartialEq)]
+pub struct ReturnValueCount {
+    pub value: i64,
+    pub count: u64,
+    pub duration: Duration,
+}
@@ -0,0 +1,129 @@
+use crate::error::Error;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Reads sources from a local git repository at the revision the program was
+/// built from, for when the paths in debug info don't exist locally (e.g. for
+/// production binaries)
+pub struct GitSource {
+    repo: PathBuf,
+    rev: String,
+    /// Paths of all files at `rev`, relative to the root of `repo`
+    files: Vec<String>,
+}
+
+impl GitSource {
+    pub fn new(repo: &str, rev: &str) -> Result<GitSource, Error> {
+        let files = git(
+            Path::new(repo),
+            &["ls-tree", "-r", "-z", "--name-only", rev],
+        )?
+        .split('\0')
+        .filter(|f| !f.is_empty())
+        .map(String::from)
+        .collect();
+        Ok(GitSource {
+            repo: PathBuf::from(repo),
+            rev: rev.to_string(),
+            files,
+        })
+    }
+
+    pub fn rev(&self) -> &str {
+        &self.rev
+    }
+
+    /// Contents of `source_file` (as recorded in debug info) at the revision,
+    /// if it is in the repository
+    pub fn read(&self, source_file: &str) -> Option<String> {
+        let path = find_file(&self.files, source_file)?;
+        match git(&self.repo, &["show", &format!("{}:{}", self.rev, path)]) {
+            Ok(contents) => Some(contents),
+            Err(err) => {
+                log::warn!("Failed to read {} from git: {}", path, err);
+                None
+            }
+        }
+    }
+}
+
+/// Look up the revision of the binary with `build_id` (hex encoded) in the
+/// file at `map_path`, each line of which is a build ID followed by the
+/// revision it was built from. Lines starting with `#` are ignored.
+pub fn lookup_rev(map_path: &str, build_id: &str) -> Result<String, Error> {
+    let map = std::fs::read_to_string(map_path)
+        .map_err(|err| format!("Failed to read {}: {}", map_path, err))?;
+    map.lines()
+        .filter(|line| !line.starts_with('#'))
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            Some((fields.next()?, fields.next()?))
+        })
+        .find(|(id, _)| id.eq_ignore_ascii_case(build_id))
+        .map(|(_, rev)| rev.to_string())
+        .ok_or_else(|| format!("Build ID {} not found in {}", build_id, map_path).into())
+}
+
+/// The file in `files` that has the most trailing path components in common
+/// with `source_file`, since the repository may have been checked out
+/// anywhere when building
+fn find_file<'a>(files: &'a [String], source_file: &str) -> Option<&'a str> {
+    let components: Vec<&str> = source_file
+        .split('/')
+        .filter(|c| !c.is_empty() && *c != ".")
+        .collect();
+    files
+        .iter()
+        .map(|file| {
+            let common = file
+                .split('/')
+                .rev()
+                .zip(components.iter().rev())
+                .take_while(|(a, b)| a == *b)
+                .count();
+            (common, file.as_str())
+        })
+        .filter(|(common, _)| *common > 0)
+        .max_by_key(|(common, _)| *common)
+        .map(|(_, file)| file)
+}
+
+fn git(repo: &Path, args: &[&str]) -> Result<String, Error> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(repo)
+        .args(args)
+        .output()
+        .map_err(|err| format!("Failed to run git: {}", err))?;
+    if !output.status.success() {
+        return Err(format!(
+            "git {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )
+        .into());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_file() {
+        let files: Vec<String> = vec!["src/foo.cc", "src/util/foo.cc", "README.md"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        assert_eq!(
+            find_file(&files, "/build/repo/src/util/foo.cc"),
+            Some("src/util/foo.cc")
+        );
+        assert_eq!(
+            find_file(&files, "/build/repo/./src/foo.cc"),
+            Some("src/foo.cc")
+        );
+        assert_eq!(find_file(&files, "/build/repo/src/bar.cc"), None);
+    }
+}
@@ -0,0 +1,322 @@
+use crate::error::Error;
+use addr2line::Location;
+use object::read::File;
+use object::{Object, ObjectSection};
+use std::convert::TryFrom;
+
+/// Function symbols and address to line mappings of a Go program, read from
+/// its pclntab. This is the table the Go runtime itself uses for stack traces,
+/// so unlike DWARF it is present even in binaries built with `-ldflags=-s -w`.
+///
+/// Only the layout used since Go 1.16 is supported.
+pub struct GoPclntab {
+    data: &'static [u8],
+    little_endian: bool,
+    /// Instruction size quantum, pc deltas are in multiples of this
+    quantum: u64,
+    /// Size of the entry field at the start of `_func`
+    entry_size: usize,
+    funcname_offset: usize,
+    cu_offset: usize,
+    filetab_offset: usize,
+    pctab_offset: usize,
+    /// (entry address, offset of `_func` within `data`) of each function,
+    /// sorted by address. The last entry only marks the end of the last
+    /// function.
+    functions: Vec<(u64, usize)>,
+}
+
+const GO_1_16_MAGIC: u32 = 0xfffffffa;
+const GO_1_18_MAGIC: u32 = 0xfffffff0;
+const GO_1_20_MAGIC: u32 = 0xfffffff1;
+
+// Offsets of `_func` fields, following the entry field
+const FUNC_NAME_OFFSET: usize = 0;
+const FUNC_PCFILE_OFFSET: usize = 16;
+const FUNC_PCLN_OFFSET: usize = 20;
+const FUNC_CU_OFFSET: usize = 28;
+
+/// Contents of the pclntab section, if `file` is a Go program
+pub fn find_data(file: &File<'static>) -> Option<&'static [u8]> {
+    [".gopclntab", "__gopclntab"]
+        .iter()
+        .find_map(|name| file.section_by_name(name))
+        .and_then(|section| section.data().ok())
+}
+
+impl GoPclntab {
+    pub fn load(file: &File<'static>, file_path: &str) -> Result<GoPclntab, Error> {
+        let data = find_data(file)
+            .ok_or_else(|| format!("Program {} is missing the Go pclntab", file_path))?;
+        let pclntab = GoPclntab::parse(data, file.is_little_endian()).ok_or_else(|| {
+            format!(
+                "Failed to parse Go pclntab of {} (only Go 1.16+ is supported)",
+                file_path
+            )
+        })?;
+        log::info!("Using Go pclntab for symbols and address to line mappings");
+        Ok(pclntab)
+    }
+
+    fn parse(data: &'static [u8], little_endian: bool) -> Option<GoPclntab> {
+        let quantum = *data.get(6)? as u64;
+        let ptr_size = *data.get(7)? as usize;
+        if data[4] != 0 || data[5] != 0 || ![4, 8].contains(&ptr_size) {
+            return None;
+        }
+        let read = |offset, size| read_uint(data, little_endian, offset, size);
+        let word = |index: usize| read(8 + index * ptr_size, ptr_size);
+
+        // Header is magic, padding, quantum, pointer size, then words for
+        // number of functions, number of files, (since 1.18) text start and
+        // offsets of each table
+        let (text_start, first_offset) = match read(0, 4)? as u32 {
+            GO_1_16_MAGIC => (None, 2),
+            GO_1_18_MAGIC | GO_1_20_MAGIC => (Some(word(2)?), 3),
+            _ => return None,
+        };
+        let num_functions = word(0)? as usize;
+        let offset = |index: usize| word(first_offset + index).map(|o| o as usize);
+        let functab_offset = offset(4)?;
+
+        // Before 1.18 functab entries are absolute addresses and offsets,
+        // afterwards both are 32-bit with addresses relative to text start
+        let field_size = match text_start {
+            Some(_) => 4,
+            None => ptr_size,
+        };
+        let mut functions = Vec::with_capacity(num_functions + 1);
+        for i in 0..=num_functions {
+            let entry_offset = functab_offset + i * 2 * field_size;
+            let entry = read(entry_offset, field_size)? + text_start.unwrap_or(0);
+            let func_offset = if i == num_functions {
+                0
+            } else {
+                functab_offset + read(entry_offset + field_size, field_size)? as usize
+            };
+            functions.push((entry, func_offset));
+        }
+
+        Some(GoPclntab {
+            data,
+            little_endian,
+            quantum,
+            entry_size: field_size,
+            funcname_offset: offset(0)?,
+            cu_offset: offset(1)?,
+            filetab_offset: offset(2)?,
+            pctab_offset: offset(3)?,
+            functions,
+        })
+    }
+
+    /// (name, address, size) of each function
+    pub fn functions(&self) -> impl Iterator<Item = (&'static str, u64, u64)> + '_ {
+        self.functions.windows(2).filter_map(move |window| {
+            let (address, func_offset) = window[0];
+            let name = self.func_name(func_offset)?;
+            Some((name, address, window[1].0 - address))
+        })
+    }
+
+    pub fn find_location(&self, address: u64) -> Option<Location<'static>> {
+        let index = match self
+            .functions
+            .binary_search_by_key(&address, |&(entry, _)| entry)
+        {
+            Ok(index) => index,
+            Err(0) => return None,
+            Err(index) => index - 1,
+        };
+        if index + 1 >= self.functions.len() {
+            return None;
+        }
+        let (entry, func_offset) = self.functions[index];
+
+        let file_index = self.pc_value(
+            self.func_field(func_offset, FUNC_PCFILE_OFFSET)?,
+            entry,
+            address,
+        )?;
+        let cu_index = self.func_field(func_offset, FUNC_CU_OFFSET)?;
+        let file_offset = self.read_uint(
+            self.cu_offset + (cu_index as usize + file_index as usize) * 4,
+            4,
+        )? as u32;
+        if file_offset == u32::MAX {
+            return None;
+        }
+        let file = self.read_str(self.filetab_offset + file_offset as usize)?;
+        let line = self.pc_value(
+            self.func_field(func_offset, FUNC_PCLN_OFFSET)?,
+            entry,
+            address,
+        )?;
+        Some(Location {
+            file: Some(file),
+            line: Some(line),
+            column: None,
+        })
+    }
+
+    fn func_name(&self, func_offset: usize) -> Option<&'static str> {
+        let name_offset = self.func_field(func_offset, FUNC_NAME_OFFSET)?;
+        self.read_str(self.funcname_offset + name_offset as usize)
+    }
+
+    fn func_field(&self, func_offset: usize, field_offset: usize) -> Option<u32> {
+        self.read_uint(func_offset + self.entry_size + field_offset, 4)
+            .map(|value| value as u32)
+    }
+
+    /// Look up `address` in the pc-value table at `table_offset` (relative to
+    /// the pctab) for a function starting at `entry`. Tables are a sequence of
+    /// (zig-zag encoded value delta, pc delta) varint pairs.
+    fn pc_value(&self, table_offset: u32, entry: u64, address: u64) -> Option<u32> {
+        if table_offset == 0 {
+            return None;
+        }
+        let mut table = self.data.get(self.pctab_offset + table_offset as usize..)?;
+        let mut value: i32 = -1;
+        let mut pc = entry;
+        let mut first = true;
+        loop {
+            let value_delta = read_varint(&mut table)?;
+            if value_delta == 0 && !first {
+                return None;
+            }
+            first = false;
+            let value_delta = if value_delta & 1 != 0 {
+                !(value_delta >> 1)
+            } else {
+                value_delta >> 1
+            };
+            value = value.wrapping_add(value_delta as i32);
+            pc += read_varint(&mut table)? as u64 * self.quantum;
+            if address < pc {
+                return u32::try_from(value).ok();
+            }
+        }
+    }
+
+    fn read_uint(&self, offset: usize, size: usize) -> Option<u64> {
+        read_uint(self.data, self.little_endian, offset, size)
+    }
+
+    fn read_str(&self, offset: usize) -> Option<&'static str> {
+        let bytes = self.data.get(offset..)?;
+        let len = bytes.iter().position(|&b| b == 0)?;
+        std::str::from_utf8(&bytes[..len]).ok()
+    }
+}
+
+pub fn read_uint(data: &[u8], little_endian: bool, offset: usize, size: usize) -> Option<u64> {
+    let bytes = data.get(offset..offset + size)?;
+    let fold = |value, &byte| value << 8 | byte as u64;
+    Some(if little_endian {
+        bytes.iter().rev().fold(0, fold)
+    } else {
+        bytes.iter().fold(0, fold)
+    })
+}
+
+fn read_varint(data: &mut &[u8]) -> Option<u32> {
+    let mut value = 0u32;
+    let mut shift = 0;
+    loop {
+        let (&byte, rest) = data.split_first()?;
+        *data = rest;
+        value |= ((byte & 0x7f) as u32).checked_shl(shift)?;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+        shift += 7;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Little-endian 64-bit pclntab with `main.main` at [0x401000, 0x401020),
+    /// laid out as the Go linker does: header words, the function name table
+    /// and then the functab followed by the `_func`s it points to
+    fn pclntab_data(magic: u32) -> &'static [u8] {
+        let go_1_18 = magic != GO_1_16_MAGIC;
+        let header_words: u64 = if go_1_18 { 8 } else { 7 };
+        let funcname_offset = 8 + header_words * 8;
+        let functab_offset = funcname_offset + 16;
+        // Functab entries are absolute uintptrs before 1.18, 32-bit and
+        // relative to text start since
+        let field_size = if go_1_18 { 4 } else { 8 };
+        let func_offset = 4 * field_size as u64;
+
+        let mut data = Vec::new();
+        let push = |data: &mut Vec<u8>, value: u64, size: usize| {
+            data.extend_from_slice(&value.to_le_bytes()[..size])
+        };
+        push(&mut data, magic.into(), 4);
+        data.extend_from_slice(&[0, 0, 1, 8]);
+        // Number of functions and files
+        push(&mut data, 1, 8);
+        push(&mut data, 1, 8);
+        if go_1_18 {
+            // Text start
+            push(&mut data, 0x401000, 8);
+        }
+        // Function name, cu, file and pc tables, then the functab
+        push(&mut data, funcname_offset, 8);
+        for _ in 0..3 {
+            push(&mut data, functab_offset, 8);
+        }
+        push(&mut data, functab_offset, 8);
+        assert_eq!(data.len() as u64, funcname_offset);
+        data.extend_from_slice(b"main.main\0\0\0\0\0\0\0");
+
+        let text_start = if go_1_18 { 0x401000 } else { 0 };
+        push(&mut data, 0x401000 - text_start, field_size);
+        push(&mut data, func_offset, field_size);
+        push(&mut data, 0x401020 - text_start, field_size);
+        push(&mut data, 0, field_size);
+        // `_func`: entry, then the offset of its name
+        push(&mut data, 0x401000 - text_start, field_size);
+        push(&mut data, 0, 4);
+        Box::leak(data.into_boxed_slice())
+    }
+
+    #[test]
+    fn test_parse_header() {
+        for &magic in &[GO_1_16_MAGIC, GO_1_18_MAGIC, GO_1_20_MAGIC] {
+            let pclntab = GoPclntab::parse(pclntab_data(magic), true).unwrap();
+            assert_eq!(
+                pclntab.functions().collect::<Vec<_>>(),
+                vec![("main.main", 0x401000, 0x20)],
+                "magic {:#x}",
+                magic
+            );
+        }
+        assert!(GoPclntab::parse(pclntab_data(0xfffffffb), true).is_none());
+    }
+
+    #[test]
+    fn test_pc_value() {
+        // Values 10, 12 then 11 over pcs [0x1000, 0x1004), [0x1004, 0x100c)
+        // and [0x100c, 0x1010)
+        let data: &'static [u8] = &[0, 0x16, 0x04, 0x04, 0x08, 0x01, 0x04, 0x00];
+        let pclntab = GoPclntab {
+            data,
+            little_endian: true,
+            quantum: 1,
+            entry_size: 4,
+            funcname_offset: 0,
+            cu_offset: 0,
+            filetab_offset: 0,
+            pctab_offset: 0,
+            functions: Vec::new(),
+        };
+        assert_eq!(pclntab.pc_value(1, 0x1000, 0x1000), Some(10));
+        assert_eq!(pclntab.pc_value(1, 0x1000, 0x1008), Some(12));
+        assert_eq!(pclntab.pc_value(1, 0x1000, 0x100c), Some(11));
+        assert_eq!(pclntab.pc_value(1, 0x1000, 0x1010), None);
+    }
+}
@@ -0,0 +1,128 @@
+use crate::error::Error;
+use crate::program::FunctionName;
+use itertools::Itertools;
+use std::collections::HashMap;
+use std::process::Command;
+use std::time::Duration;
+
+/// How often a branch instruction was taken and mispredicted, according to the
+/// last branch records (LBR) sampled
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct BranchCounts {
+    pub taken: u64,
+    pub mispredicted: u64,
+}
+
+/// Sample last branch records of user space code for `duration` with `perf`,
+/// returning the branches taken from `function` keyed by their offset within
+/// it. Only the processes in `pids` are sampled, or all of them if empty, and
+/// only samples in `object_path` (the binary or library defining `function`)
+/// are counted, so that functions of the same name elsewhere aren't. Requires
+/// a CPU with LBR support (or an equivalent like AMD's BRS).
+pub fn sample(
+    function: FunctionName,
+    object_path: &str,
+    pids: &[u32],
+    duration: Duration,
+) -> Result<HashMap<u64, BranchCounts>, Error> {
+    let data_path = std::env::temp_dir().join(format!("wachy-lbr-{}.data", std::process::id()));
+    log::info!("Sampling last branch records for {:?}", duration);
+    let mut command = Command::new("perf");
+    command.args(["record", "--branch-filter", "any,u"]);
+    if pids.is_empty() {
+        command.arg("--all-cpus");
+    } else {
+        command.arg("--pid").arg(pids.iter().join(","));
+    }
+    let output = command
+        .args(["--event", "cycles:u", "--output"])
+        .arg(&data_path)
+        .args(["--", "sleep"])
+        .arg(duration.as_secs().to_string())
+        .output()
+        .map_err(|err| format!("Failed to run perf: {}", err))?;
+    if !output.status.success() {
+        let _ = std::fs::remove_file(&data_path);
+        return Err(format!(
+            "perf record failed (the CPU may not support branch sampling), status: {:?}, stderr:\n{}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr)
+        )
+        .into());
+    }
+
+    let output = Command::new("perf")
+        .args(["script", "--input"])
+        .arg(&data_path)
+        .args(["--no-demangle", "--fields", "brstacksym", "--dsos"])
+        .arg(object_path)
+        .output();
+    let _ = std::fs::remove_file(&data_path);
+    let output = output.map_err(|err| format!("Failed to run perf: {}", err))?;
+    if !output.status.success() {
+        return Err(format!(
+            "perf script failed, status: {:?}, stderr:\n{}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr)
+        )
+        .into());
+    }
+    Ok(parse_brstacksym(
+        &String::from_utf8_lossy(&output.stdout),
+        function.unqualified().0,
+    ))
+}
+
+/// Count branches from `function` in `perf script` brstacksym output. Each
+/// branch is printed as `from/to/flag/...`, where `from` and `to` are
+/// `symbol+offset` and `flag` is `M` if it was mispredicted.
+fn parse_brstacksym(output: &str, function: &str) -> HashMap<u64, BranchCounts> {
+    let mut counts: HashMap<u64, BranchCounts> = HashMap::new();
+    for branch in output.split_whitespace() {
+        let mut fields = branch.split('/');
+        let (from, flag) = match (fields.next(), fields.nth(1)) {
+            (Some(from), Some(flag)) => (from, flag),
+            _ => continue,
+        };
+        let offset = match from.rsplit_once('+') {
+            Some((symbol, offset)) if symbol == function => offset,
+            _ => continue,
+        };
+        let offset = match u64::from_str_radix(offset.trim_start_matches("0x"), 16) {
+            Ok(offset) => offset,
+            Err(_) => continue,
+        };
+        let branch_counts = counts.entry(offset).or_default();
+        branch_counts.taken += 1;
+        if flag == "M" {
+            branch_counts.mispredicted += 1;
+        }
+    }
+    counts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_brstacksym() {
+        let output = " foo+0x1a/foo+0x40/M/-/-/0  foo+0x1a/foo+0x40/P/-/-/0  bar+0x8/foo+0x0/P/-/-/0\n foo+0x52/baz+0x0/-/-/-/0 \n";
+        let counts = parse_brstacksym(output, "foo");
+        assert_eq!(counts.len(), 2);
+        assert_eq!(
+            counts[&0x1a],
+            BranchCounts {
+                taken: 2,
+                mispredicted: 1
+            }
+        );
+        assert_eq!(
+            counts[&0x52],
+            BranchCounts {
+                taken: 1,
+                mispredicted: 0
+            }
+        );
+    }
+}
@@ -0,0 +1,567 @@
+use crate::backend::{BpftraceBackend, TraceBackend};
+use crate::bpftrace_compiler::BpftraceProgram;
+use crate::capabilities::Capabilities;
+use crate::error::Error;
+use crate::events::{Event, LineCounters, TraceCumulative, TraceInfo, TraceInfoMode};
+use crate::native::{NativePlan, NativeProbe};
+use crate::program::FunctionName;
+use libbpf_rs::skel::{OpenSkel, SkelBuilder};
+use libbpf_rs::{MapFlags, RingBufferBuilder, UprobeOpts};
+use object::{Object, ObjectSegment, ObjectSymbol};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::convert::TryInto;
+use std::ffi::CString;
+use std::os::raw::c_ulong;
+use std::process::Command;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+mod skel {
+    include!(concat!(env!("OUT_DIR"), "/wachy.skel.rs"));
+}
+
+/// Longest the ring buffer is waited on, bounding how long stopping takes
+const POLL_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// `PERF_TYPE_HARDWARE` and its `PERF_COUNT_HW_*` events, from
+/// `linux/perf_event.h`
+const PERF_TYPE_HARDWARE: u32 = 0;
+const PERF_COUNT_HW_CPU_CYCLES: u64 = 0;
+const PERF_COUNT_HW_INSTRUCTIONS: u64 = 1;
+const PERF_COUNT_HW_CACHE_MISSES: u64 = 3;
+const PERF_FLAG_FD_CLOEXEC: c_ulong = 1 << 3;
+
+/// Loads the BPF program in `bpf/wachy.bpf.c` in-process to trace lines,
+/// attaching probes directly instead of starting bpftrace each time the trace
+/// stack changes. Traces it doesn't support yet (filters, histograms,
+/// breakdowns, callers, templates and captured calls) are still run with
+/// bpftrace.
+pub struct LibbpfBackend {
+    program_path: String,
+    bpftrace: BpftraceBackend,
+}
+
+impl LibbpfBackend {
+    pub fn new(program_path: String, bpftrace: BpftraceBackend) -> LibbpfBackend {
+        LibbpfBackend {
+            program_path,
+            bpftrace,
+        }
+    }
+}
+
+impl TraceBackend for LibbpfBackend {
+    fn name(&self) -> &'static str {
+        "libbpf"
+    }
+
+    fn run_prechecks(&self) -> Result<(), Error> {
+        if unsafe { libc::geteuid() } != 0 {
+            return Err("The libbpf backend must be run as root".into());
+        }
+        if !self.capabilities().bpf_cookies {
+            return Err(
+                "The libbpf backend needs BPF cookies (Linux 5.15+), pass --backend bpftrace instead"
+                    .into(),
+            );
+        }
+        if let Err(err) = self.bpftrace.run_prechecks() {
+            log::warn!(
+                "{} Traces the libbpf backend doesn't support will fail.",
+                err
+            );
+        }
+        Ok(())
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities::probe_local()
+    }
+
+    fn is_local_bpftrace(&self) -> bool {
+        true
+    }
+
+    fn bpftrace_command(&self) -> Option<Command> {
+        self.bpftrace.bpftrace_command()
+    }
+
+    fn compile(&self, program: &BpftraceProgram) -> String {
+        self.bpftrace.compile(program)
+    }
+
+    fn run_command(&self, script: &str) -> Command {
+        self.bpftrace.run_command(script)
+    }
+
+    fn check_command(&self, script: &str) -> Command {
+        self.bpftrace.check_command(script)
+    }
+
+    fn json_command(&self, script: &str) -> Option<Command> {
+        self.bpftrace.json_command(script)
+    }
+
+    fn is_native(&self) -> bool {
+        true
+    }
+
+    fn run_native(
+        &self,
+        plan: &NativePlan,
+        counter: u64,
+        tx: &mpsc::Sender<Event>,
+        stop: &AtomicBool,
+    ) -> Result<(), Error> {
+        let uprobe_multi = self.capabilities().uprobe_multi;
+        let mut open_skel = skel::WachySkelBuilder::default()
+            .open()
+            .map_err(|err| format!("Failed to open BPF program: {}", err))?;
+        if !uprobe_multi {
+            // Its attach type fails to load on older kernels
+            open_skel
+                .progs_mut()
+                .probe_multi()
+                .set_autoload(false)
+                .map_err(|err| format!("Failed to open BPF program: {}", err))?;
+        }
+        let mut skel = open_skel
+            .load()
+            .map_err(|err| format!("Failed to load BPF program: {}", err))?;
+
+        // -1 attaches to all processes
+        let pids: Vec<i32> = if plan.pids.is_empty() {
+            vec![-1]
+        } else {
+            plan.pids.iter().map(|&pid| pid as i32).collect()
+        };
+        // Probes are detached when their links are dropped
+        let mut links = Vec::new();
+        let mut multi_links = Vec::new();
+        if uprobe_multi {
+            multi_links =
+                self.attach_multi(skel.progs().probe_multi().fd(), &plan.probes, &pids)?;
+        } else {
+            let path_of = |probe: &NativeProbe| {
+                probe
+                    .function
+                    .object()
+                    .unwrap_or_else(|| self.program_path.as_str())
+            };
+            // Synthesized names aren't symbols libbpf could resolve, so those
+            // functions are attached to by file offset instead
+            let mut synthesized: HashMap<&str, HashSet<FunctionName>> = HashMap::new();
+            for probe in &plan.probes {
+                if probe.function.synthesized_address().is_some() {
+                    synthesized
+                        .entry(path_of(probe))
+                        .or_default()
+                        .insert(probe.function);
+                }
+            }
+            let mut file_offsets = HashMap::new();
+            for (path, functions) in &synthesized {
+                file_offsets.insert(*path, function_file_offsets(path, functions)?);
+            }
+            for probe in &plan.probes {
+                let path = path_of(probe);
+                let (func_name, offset) = match file_offsets.get(path) {
+                    Some(offsets) if probe.function.synthesized_address().is_some() => {
+                        let function_offset = offsets.get(&probe.function).ok_or_else(|| {
+                            format!("Failed to find {} in {}", probe.function, path)
+                        })?;
+                        (String::new(), function_offset + u64::from(probe.offset))
+                    }
+                    // Resolved by libbpf, `offset` is relative to it
+                    _ => (
+                        probe.function.unqualified().0.to_string(),
+                        u64::from(probe.offset),
+                    ),
+                };
+                for &pid in &pids {
+                    let opts = UprobeOpts {
+                        cookie: probe.cookie(),
+                        retprobe: probe.retprobe,
+                        func_name: func_name.clone(),
+                        ..Default::default()
+                    };
+                    let link = skel
+                        .progs_mut()
+                        .probe()
+                        .attach_uprobe_with_opts(pid, path, offset as usize, opts)
+                        .map_err(|err| {
+                            format!(
+                                "Failed to attach to {}+{}: {}",
+                                probe.function, probe.offset, err
+                            )
+                        })?;
+                    links.push(link);
+                }
+            }
+        }
+        log::info!(
+            "Attached {} probes with {} links",
+            plan.probes.len() * pids.len(),
+            links.len() + multi_links.len()
+        );
+        // Counters are closed when dropped. Lines are still timed without them.
+        let mut perf_counters = Vec::new();
+        if plan.counters {
+            match open_counters(&skel) {
+                Ok(counters) => perf_counters = counters,
+                Err(err) => log::warn!("{}", err),
+            }
+        }
+        let read_counters = !perf_counters.is_empty();
+
+        let totals: Rc<RefCell<HashMap<u32, TraceCumulative>>> = Rc::new(RefCell::new(
+            plan.lines
+                .iter()
+                .map(|&line| {
+                    (
+                        line,
+                        TraceCumulative {
+                            duration: Duration::from_nanos(0),
+                            count: 0,
+                        },
+                    )
+                })
+                .collect(),
+        ));
+        let mut builder = RingBufferBuilder::new();
+        let lines = plan.lines.clone();
+        let event_totals = Rc::clone(&totals);
+        let line_counters: Rc<RefCell<HashMap<u32, LineCounters>>> =
+            Rc::new(RefCell::new(HashMap::new()));
+        let event_counters = Rc::clone(&line_counters);
+        let aborted: Rc<RefCell<HashMap<u32, u64>>> = Rc::new(RefCell::new(HashMap::new()));
+        let event_aborted = Rc::clone(&aborted);
+        builder
+            .add(skel.maps().events(), move |data: &[u8]| {
+                match LineEvent::parse(data) {
+                    Some(event) => match lines.get(event.slot as usize) {
+                        Some(line) => {
+                            let mut totals = event_totals.borrow_mut();
+                            let total = totals.get_mut(line).unwrap();
+                            total.duration += Duration::from_nanos(event.duration);
+                            total.count += event.count;
+                            event_counters
+                                .borrow_mut()
+                                .entry(*line)
+                                .or_default()
+                                .add(event.counters);
+                            *event_aborted.borrow_mut().entry(*line).or_default() += event.aborted;
+                        }
+                        None => log::warn!("Event for unknown slot {}", event.slot),
+                    },
+                    None => log::warn!("Unexpected event of {} bytes", data.len()),
+                }
+                0
+            })
+            .map_err(|err| format!("Failed to set up ring buffer: {}", err))?;
+        let ring_buffer = builder
+            .build()
+            .map_err(|err| format!("Failed to set up ring buffer: {}", err))?;
+
+        let start = Instant::now();
+        let mut last_report = start;
+        while !stop.load(Ordering::Acquire) {
+            ring_buffer
+                .poll(POLL_TIMEOUT)
+                .map_err(|err| format!("Failed to poll ring buffer: {}", err))?;
+            if last_report.elapsed() >= plan.report_interval {
+                last_report = Instant::now();
+                let info = TraceInfo {
+                    counter,
+                    time: start.elapsed(),
+                    traces: TraceInfoMode::Lines(totals.borrow().clone()),
+                    histograms: None,
+                    statistics: None,
+                    off_cpu: None,
+                    aborted: Some(aborted.borrow().clone()),
+                    counters: read_counters.then(|| line_counters.borrow().clone()),
+                    pinned: None,
+                    workspaces: None,
+                };
+                if tx.send(Event::TraceData(info)).is_err() {
+                    // Controller has exited
+                    return Ok(());
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl LibbpfBackend {
+    /// Attach `probes` with one uprobe_multi link per binary, kind of probe and
+    /// process rather than one link per probe, which takes seconds with many
+    /// probes
+    fn attach_multi(
+        &self,
+        prog_fd: i32,
+        probes: &[NativeProbe],
+        pids: &[i32],
+    ) -> Result<Vec<MultiLink>, Error> {
+        let path_of = |probe: &NativeProbe| {
+            probe
+                .function
+                .object()
+                .unwrap_or_else(|| self.program_path.as_str())
+        };
+        let mut functions: HashMap<&str, HashSet<FunctionName>> = HashMap::new();
+        for probe in probes {
+            functions
+                .entry(path_of(probe))
+                .or_default()
+                .insert(probe.function);
+        }
+        let mut file_offsets = HashMap::new();
+        for (path, functions) in &functions {
+            file_offsets.insert(*path, function_file_offsets(path, functions)?);
+        }
+
+        // Offsets and cookies of the probes of each link
+        let mut groups: BTreeMap<(&str, bool), (Vec<c_ulong>, Vec<u64>)> = BTreeMap::new();
+        for probe in probes {
+            let path = path_of(probe);
+            let function_offset = file_offsets[path]
+                .get(&probe.function)
+                .ok_or_else(|| format!("Failed to find {} in {}", probe.function, path))?;
+            let (offsets, cookies) = groups.entry((path, probe.retprobe)).or_default();
+            offsets.push((function_offset + u64::from(probe.offset)) as c_ulong);
+            cookies.push(probe.cookie());
+        }
+        let mut links = Vec::new();
+        for ((path, retprobe), (offsets, cookies)) in &groups {
+            for &pid in pids {
+                let link = MultiLink::attach(prog_fd, path, offsets, cookies, *retprobe, pid)
+                    .map_err(|err| format!("Failed to attach to {}: {}", path, err))?;
+                links.push(link);
+            }
+        }
+        Ok(links)
+    }
+}
+
+/// A uprobe_multi link, detached when dropped
+struct MultiLink {
+    fd: i32,
+}
+
+impl MultiLink {
+    /// Attach `prog_fd` at `offsets` in the file at `path`, with a cookie for
+    /// each. `pid` -1 attaches to all processes, like for single uprobes.
+    fn attach(
+        prog_fd: i32,
+        path: &str,
+        offsets: &[c_ulong],
+        cookies: &[u64],
+        retprobe: bool,
+        pid: i32,
+    ) -> std::io::Result<MultiLink> {
+        let path = CString::new(path).unwrap();
+        let mut opts: libbpf_sys::bpf_link_create_opts = unsafe { std::mem::zeroed() };
+        opts.sz = std::mem::size_of::<libbpf_sys::bpf_link_create_opts>() as _;
+        let fd = unsafe {
+            let uprobe_multi = &mut opts.__bindgen_anon_1.uprobe_multi;
+            uprobe_multi.path = path.as_ptr();
+            uprobe_multi.offsets = offsets.as_ptr();
+            uprobe_multi.cookies = cookies.as_ptr();
+            uprobe_multi.cnt = offsets.len() as u32;
+            uprobe_multi.flags = if retprobe {
+                libbpf_sys::BPF_F_UPROBE_MULTI_RETURN
+            } else {
+                0
+            };
+            // 0 rather than -1 for all processes
+            uprobe_multi.pid = pid.max(0) as u32;
+            libbpf_sys::bpf_link_create(prog_fd, 0, libbpf_sys::BPF_TRACE_UPROBE_MULTI, &opts)
+        };
+        if fd < 0 {
+            return Err(std::io::Error::from_raw_os_error(-fd));
+        }
+        Ok(MultiLink { fd })
+    }
+}
+
+impl Drop for MultiLink {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+/// A hardware counter opened with `perf_event_open`, closed when dropped
+struct PerfCounter {
+    fd: i32,
+}
+
+impl PerfCounter {
+    /// Count `config` events of type `PERF_TYPE_HARDWARE` on `cpu`, for all
+    /// processes. Only user space is counted, so that the work of the probes
+    /// reading the counter isn't.
+    fn open(config: u64, cpu: i32) -> std::io::Result<PerfCounter> {
+        let attr = PerfEventAttr {
+            type_: PERF_TYPE_HARDWARE,
+            size: std::mem::size_of::<PerfEventAttr>() as u32,
+            config,
+            // exclude_kernel and exclude_hv
+            flags: 1 << 5 | 1 << 6,
+            ..Default::default()
+        };
+        let fd = unsafe {
+            libc::syscall(
+                libc::SYS_perf_event_open,
+                &attr as *const PerfEventAttr,
+                -1,
+                cpu,
+                -1,
+                PERF_FLAG_FD_CLOEXEC,
+            )
+        };
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(PerfCounter { fd: fd as i32 })
+    }
+}
+
+impl Drop for PerfCounter {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+/// The first version of `struct perf_event_attr`, which is all that is needed
+/// to count events. The kernel accepts it as long as `size` says so.
+#[repr(C)]
+#[derive(Default)]
+struct PerfEventAttr {
+    type_: u32,
+    size: u32,
+    config: u64,
+    sample_period: u64,
+    sample_type: u64,
+    read_format: u64,
+    flags: u64,
+    wakeup_events: u32,
+    bp_type: u32,
+    config1: u64,
+}
+
+/// Open the cycles, instructions and cache misses counters of each CPU, and
+/// add them to the maps the BPF program reads them from
+fn open_counters(skel: &skel::WachySkel) -> Result<Vec<PerfCounter>, Error> {
+    let cpus = libbpf_rs::num_possible_cpus()
+        .map_err(|err| format!("Failed to get the number of CPUs: {}", err))?;
+    let maps = skel.maps();
+    let events = [
+        (maps.cycles(), PERF_COUNT_HW_CPU_CYCLES),
+        (maps.instructions(), PERF_COUNT_HW_INSTRUCTIONS),
+        (maps.cache_misses(), PERF_COUNT_HW_CACHE_MISSES),
+    ];
+    let mut counters = Vec::new();
+    for cpu in 0..cpus as i32 {
+        for (map, config) in &events {
+            let counter = match PerfCounter::open(*config, cpu) {
+                Ok(counter) => counter,
+                // Possible CPUs may not be online
+                Err(err) if err.raw_os_error() == Some(libc::ENODEV) => break,
+                Err(err) => {
+                    return Err(format!(
+                    "Failed to open hardware counters, they may not be exposed (e.g. in a VM): {}",
+                    err
+                )
+                    .into())
+                }
+            };
+            map.update(
+                &(cpu as u32).to_ne_bytes(),
+                &(counter.fd as u32).to_ne_bytes(),
+                MapFlags::ANY,
+            )
+            .map_err(|err| format!("Failed to add hardware counter: {}", err))?;
+            counters.push(counter);
+        }
+    }
+    Ok(counters)
+}
+
+/// File offsets of `functions` in the binary at `path`, which uprobe_multi
+/// links are attached at rather than symbols
+fn function_file_offsets(
+    path: &str,
+    functions: &HashSet<FunctionName>,
+) -> Result<HashMap<FunctionName, u64>, Error> {
+    let file =
+        std::fs::File::open(path).map_err(|err| format!("Failed to open {}: {}", path, err))?;
+    let mmap = unsafe { memmap2::Mmap::map(&file) }
+        .map_err(|err| format!("Failed to map {}: {}", path, err))?;
+    let object =
+        object::File::parse(&*mmap).map_err(|err| format!("Failed to parse {}: {}", path, err))?;
+    let to_file_offset = |address: u64| {
+        object.segments().find_map(|segment| {
+            let (file_start, file_size) = segment.file_range();
+            (segment.address()..segment.address() + file_size)
+                .contains(&address)
+                .then(|| address - segment.address() + file_start)
+        })
+    };
+
+    let mut addresses: HashMap<FunctionName, u64> = functions
+        .iter()
+        .filter_map(|function| Some((*function, function.synthesized_address()?)))
+        .collect();
+    let by_name: HashMap<&str, FunctionName> = functions
+        .iter()
+        .filter(|function| !addresses.contains_key(function))
+        .map(|function| (function.unqualified().0, *function))
+        .collect();
+    for symbol in object.symbols().chain(object.dynamic_symbols()) {
+        if let Some(function) = symbol.name().ok().and_then(|name| by_name.get(name)) {
+            addresses
+                .entry(*function)
+                .or_insert_with(|| symbol.address());
+        }
+    }
+    Ok(addresses
+        .into_iter()
+        .filter_map(|(function, address)| Some((function, to_file_offset(address)?)))
+        .collect())
+}
+
+/// Times of a line during a call of the outermost function, as sent by the
+/// BPF program (`struct line_event`)
+struct LineEvent {
+    slot: u32,
+    duration: u64,
+    count: u64,
+    /// Zero unless the plan reads hardware counters
+    counters: LineCounters,
+    /// Calls that never returned
+    aborted: u64,
+}
+
+impl LineEvent {
+    fn parse(data: &[u8]) -> Option<LineEvent> {
+        if data.len() < 64 {
+            return None;
+        }
+        let u64_at = |i: usize| u64::from_ne_bytes(data[i..i + 8].try_into().unwrap());
+        Some(LineEvent {
+            slot: u32::from_ne_bytes(data[0..4].try_into().unwrap()),
+            duration: u64_at(8),
+            count: u64_at(16),
+            counters: LineCounters {
+                cycles: u64_at(24),
+                instructions: u64_at(32),
+                cache_misses: u64_at(40),
+                calls: u64_at(48),
+            },
+            aborted: u64_at(56),
+        })
+    }
+}
@@ -0,0 +1,61 @@
+use std::collections::HashMap;
+
+/// How many times the loops of a function were iterated, see
+/// `parse_iterations`
+#[derive(Debug, Default, PartialEq)]
+pub struct LoopIterations {
+    pub function_calls: u64,
+    /// Times the head of each loop was reached, keyed by its offset
+    pub iterations: HashMap<u32, u64>,
+}
+
+impl LoopIterations {
+    /// Average iterations of the loop with its head at `head` per call of the
+    /// function
+    pub fn per_call(&self, head: u32) -> f64 {
+        let iterations = self.iterations.get(&head).copied().unwrap_or(0);
+        iterations as f64 / self.function_calls.max(1) as f64
+    }
+}
+
+/// Parse the maps printed by the program from
+/// `TraceStack::get_loop_iterations_expr`, which look like
+/// `@iterations[16]: 1234` or `@function_calls: 56`
+pub fn parse_iterations(output: &str) -> LoopIterations {
+    let mut result = LoopIterations::default();
+    for line in output.lines() {
+        let (name, value) = match line.split_once(": ") {
+            Some((name, value)) => match value.trim().parse::<u64>() {
+                Ok(value) => (name, value),
+                Err(_) => continue,
+            },
+            None => continue,
+        };
+        if name == "@function_calls" {
+            result.function_calls = value;
+        } else if let Some(head) = name
+            .strip_prefix("@iterations[")
+            .and_then(|key| key.strip_suffix(']'))
+            .and_then(|head| head.parse::<u32>().ok())
+        {
+            result.iterations.insert(head, value);
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_iterations() {
+        let output = "Attaching 4 probes...\n\n\n@depth[42]: 1\n@function_calls: 4\n\n@iterations[16]: 40\n@iterations[72]: 2\n";
+        let iterations = parse_iterations(output);
+        assert_eq!(iterations.function_calls, 4);
+        assert_eq!(iterations.iterations.len(), 2);
+        assert_eq!(iterations.per_call(16), 10.0);
+        assert_eq!(iterations.per_call(72), 0.5);
+        assert_eq!(iterations.per_call(100), 0.0);
+    }
+}
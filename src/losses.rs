@@ -0,0 +1,149 @@
+/// Default of bpftrace's `max_map_keys`, the most elements a map can hold
+const DEFAULT_MAP_KEYS: u32 = 4096;
+/// Default of bpftrace's `perf_rb_pages`, the pages of the buffer per CPU that
+/// printed output goes through
+const DEFAULT_PERF_RB_PAGES: u32 = 64;
+/// `E2BIG`, which map updates fail with when the map is full
+const MAP_FULL_ERRNO: i64 = 7;
+
+/// Data dropped while tracing, which bpftrace reports but otherwise leaves the
+/// results silently incomplete
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Losses {
+    /// Output lost because the perf buffer was full, e.g. reports
+    pub lost_events: u64,
+    /// Map updates that failed because the map was full, e.g. calls that
+    /// couldn't be timed
+    pub map_full: u64,
+}
+
+impl Losses {
+    pub fn is_empty(&self) -> bool {
+        *self == Losses::default()
+    }
+
+    pub fn add(&mut self, other: Losses) {
+        self.lost_events += other.lost_events;
+        self.map_full += other.map_full;
+    }
+}
+
+/// Parse a line of bpftrace's text output or stderr, like `Lost 12 events` or
+/// `stdin:1:20-45: WARNING: Map full; can't update element`
+pub fn parse_line(line: &str) -> Option<Losses> {
+    let line = line.trim();
+    if let Some(count) = line
+        .strip_prefix("Lost ")
+        .and_then(|rest| rest.strip_suffix(" events"))
+    {
+        return Some(Losses {
+            lost_events: count.parse().ok()?,
+            map_full: 0,
+        });
+    }
+    if line.contains("Map full") {
+        return Some(Losses {
+            lost_events: 0,
+            map_full: 1,
+        });
+    }
+    None
+}
+
+/// Parse a record of bpftrace's JSON output, like `{"type": "lost_events",
+/// "data": {"events": 12}}` or a `helper_error` of a full map
+pub fn parse_json(line: &str) -> Option<Losses> {
+    // Most records are neither, skip parsing them twice
+    if !line.contains("\"lost_events\"") && !line.contains("\"helper_error\"") {
+        return None;
+    }
+    let record: serde_json::Value = serde_json::from_str(line).ok()?;
+    match record["type"].as_str()? {
+        "lost_events" => Some(Losses {
+            lost_events: record["data"]["events"].as_u64()?,
+            map_full: 0,
+        }),
+        "helper_error"
+            if record["retcode"].as_i64() == Some(-MAP_FULL_ERRNO)
+                || record["msg"]
+                    .as_str()
+                    .map_or(false, |msg| msg.contains("Map full")) =>
+        {
+            Some(Losses {
+                lost_events: 0,
+                map_full: 1,
+            })
+        }
+        _ => None,
+    }
+}
+
+/// Environment that makes bpftrace's maps and perf buffers `scale` times
+/// their default size. Both names of the map setting are set, since bpftrace
+/// renamed it.
+pub fn scaled_env(scale: u32) -> Vec<(&'static str, String)> {
+    let map_keys = DEFAULT_MAP_KEYS.saturating_mul(scale).to_string();
+    vec![
+        ("BPFTRACE_MAX_MAP_KEYS", map_keys.clone()),
+        ("BPFTRACE_MAP_KEYS_MAX", map_keys),
+        (
+            "BPFTRACE_PERF_RB_PAGES",
+            DEFAULT_PERF_RB_PAGES.saturating_mul(scale).to_string(),
+        ),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_line() {
+        assert_eq!(
+            parse_line("Lost 12 events"),
+            Some(Losses {
+                lost_events: 12,
+                map_full: 0
+            })
+        );
+        assert_eq!(
+            parse_line("stdin:1:20-45: WARNING: Map full; can't update element. Try increasing max_map_keys config"),
+            Some(Losses {
+                lost_events: 0,
+                map_full: 1
+            })
+        );
+        assert_eq!(parse_line("Attaching 4 probes..."), None);
+        assert_eq!(parse_line("Lost many events"), None);
+    }
+
+    #[test]
+    fn test_parse_json() {
+        assert_eq!(
+            parse_json(r#"{"type": "lost_events", "data": {"events": 3}}"#),
+            Some(Losses {
+                lost_events: 3,
+                map_full: 0
+            })
+        );
+        assert_eq!(
+            parse_json(
+                r#"{"type": "helper_error", "msg": "Failed to update map", "helper": "map_update_elem", "retcode": -7, "line": 1, "col": 20}"#
+            ),
+            Some(Losses {
+                lost_events: 0,
+                map_full: 1
+            })
+        );
+        assert_eq!(
+            parse_json(
+                r#"{"type": "helper_error", "msg": "Failed to read", "helper": "probe_read", "retcode": -14}"#
+            ),
+            None
+        );
+        assert_eq!(
+            parse_json(r#"{"type": "printf", "data": "{\"time\": 1}\n"}"#),
+            None
+        );
+    }
+}
@@ -1,14 +1,45 @@
+mod allocations;
+mod arm;
+mod backend;
 mod bpftrace_compiler;
+mod capabilities;
+mod checks;
+mod container;
+mod contention;
+mod control;
 mod controller;
+mod debuginfod;
+mod decompiler;
+mod embedded_source;
 mod error;
 mod events;
+mod git_source;
+mod gopclntab;
+mod lbr;
+#[cfg(feature = "libbpf")]
+mod libbpf_backend;
+mod loops;
+mod losses;
+mod maps;
+mod memory;
+mod messages;
+mod native;
+mod pe;
 mod program;
+mod recording;
+mod runtime;
+mod sampling;
 mod search;
+mod share;
+mod source_map;
+mod store;
+mod syscalls;
+mod templates;
 mod trace_structs;
 mod tracer;
 mod views;
 
-use clap::{App, Arg};
+use clap::{App, AppSettings, Arg, SubCommand};
 use error::Error;
 use flexi_logger::{opt_format, FileSpec, Logger, LoggerHandle};
 use std::env;
@@ -23,16 +54,61 @@ Uses eBPF to trace arbitrary binaries and compiled functions at runtime.
 
 Keyboard shortcuts:
 x - toggle tracing on current line
+ctrl+x - toggle tracing on every line with a call in the current function
 X - toggle tracing of an inlined function on current line
+ctrl+t X - trace the instruction at a function offset or address, on its line
+z - mark current line as start of a span, then press on its end line to trace it
+I - list calls inlined on current line
+n - only time 1 in N calls on current line, to bound the overhead of hot calls
+V - set how often results refresh (--interval), longer lowers overhead
+F - only count calls on current line slower than a threshold, for tail latency
 <enter> - push current call onto trace stack
 > (shift+.) - specify arbitrary function to push onto trace stack
 <esc> - pop function off of trace stack
 r - restart trace, clear current aggregates
-h - get histogram of current function's latency
-f - add filter on function entry
+t - time travel through past aggregates, with left and right keys
+d - toggle showing event counts of the last second instead of frequency
+l - cycle latency column between average, p50, p99 and max
+w - toggle showing the share of latency spent off-CPU (blocked) in latency column
+R - toggle counting only the outermost calls of a recursive current function
+e - cycle hardware counter column between IPC, cache misses per call and hidden
+J - toggle column with each traced line's share of current function's time
+p - pin current line's stats above the source, to compare with other lines
+P - keep tracing current line in every function, listed above the source
+N - switch workspace, or start a new one tracing another function alongside
+G - drill down automatically, entering the most expensive call repeatedly
+Y - show the trace stack as a tree of functions, traced lines and their callees
+h - get histogram of current function's latency, or of the selected traced line
+H - toggle pane with live histogram of the selected line's latency
+D - show min, max, standard deviation and percentiles of current line's latency
+c - count distinct callers of current function reaching current line
+v - count values returned by the call on current line, with their latency
+a - count the most frequent argument values of the call on current line
+k - group calls on current line by the value of an argument, with their latency
+W - split calls on current line by thread, with their frequency and latency
+B - sample branch mispredictions of current function (needs LBR support)
+o - capture arguments, stack and latency of the next call of current function
+O - capture arguments and stacks of the latest calls of current function over a latency threshold
+u - trace how long each line of current function waits for locks
+A - trace how much each line of current function allocates
+y - trace which syscalls current function spends its time in
+C - count iterations per call of the loops of current function
+j - trace where indirect calls on current line jump to, and pick one to enter
+f - add filter on function entry, or only trace some processes or threads
 g - add filter on function exit. `$duration` can be used to refer to
     function latency.
 m - get mangled function name
+i - show compiler and optimization flags the binary was built with
+K - show what the kernel supports and features limited because of it, and the
+    results of the environment checks run on startup
+M - show memory usage, and drop caches that can be recomputed
+E - show data dropped while tracing (lost events, full maps) and how to avoid it
+U - show tracer status: backend process, CPU usage, probes, output rate, stderr
+T - run a trace template for a common library or from --templates
+L - trace a function of a library the program has loaded, e.g. with dlopen
+s - remap the path prefix of current source file, when it is not found
+S - show the trace program currently run, to save it or edit it
+ctrl+p - search for and run any of the above commands
 "#;
 
 lazy_static::lazy_static! {
@@ -56,28 +132,330 @@ fn main() {
         let args = App::new("wachy")
             .version(VERSION)
             .long_about(ABOUT)
+            .setting(AppSettings::SubcommandsNegateReqs)
             .arg(
                 Arg::with_name("PROGRAM")
                     .help("Path of binary to trace")
-                    .required(true),
+                    .required_unless_one(&["watch", "print-messages"]),
             )
             .arg(
                 Arg::with_name("FUNCTION")
                     .help("Function to trace")
-                    .required(true),
+                    .required_unless_one(&["watch", "print-messages"]),
+            )
+            .arg(
+                Arg::with_name("backend")
+                    .long("backend")
+                    .value_name("BACKEND")
+                    .help("Tracing tool to use")
+                    .possible_values(backend::BACKEND_NAMES)
+                    .default_value(backend::DEFAULT_BACKEND)
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("pid")
+                    .long("pid")
+                    .value_name("PID")
+                    .help("Process to trace, required with the dtrace backend")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("container")
+                    .long("container")
+                    .value_name("CONTAINER")
+                    .help("Trace a program in a docker or podman container, given its id or name. PROGRAM is then its absolute path in the container.")
+                    .takes_value(true)
+                    .conflicts_with_all(&["pid", "follow-forks", "device-path"]),
+            )
+            .arg(
+                Arg::with_name("follow-forks")
+                    .long("follow-forks")
+                    .help("Only trace --pid and its descendants, including workers it forks while tracing")
+                    .requires("pid"),
+            )
+            .arg(
+                Arg::with_name("device-path")
+                    .long("device-path")
+                    .value_name("PATH")
+                    .help("Path of PROGRAM on the device, required with the android backend. PROGRAM should be a local copy of it.")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("bundled-runtime")
+                    .long("bundled-runtime")
+                    .help("Use a pinned static build of bpftrace, downloaded on first use, for hosts where bpftrace can't be installed"),
+            )
+            .arg(
+                Arg::with_name("memory-budget")
+                    .long("memory-budget")
+                    .value_name("SIZE")
+                    .help("Keep memory usage down for hosts with little memory, e.g. 512M or 2G. Symbol names are demangled on demand and caches are dropped when over budget.")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("overhead-threshold")
+                    .long("overhead-threshold")
+                    .value_name("CALLS")
+                    .help("Before tracing a call, check how often it is reached and warn if it is more than this many times a second, offering to sample CPU time instead. 0 disables the check.")
+                    .default_value("500000"),
+            )
+            .arg(
+                Arg::with_name("interval")
+                    .long("interval")
+                    .value_name("INTERVAL")
+                    .help("How often trace results are reported and the display refreshed, e.g. 500ms or 5s. Longer intervals lower the overhead on busy hosts. Can be changed at runtime with V.")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("decompiler")
+                    .long("decompiler")
+                    .value_name("COMMAND")
+                    .help("Command to show pseudo-code for functions without source information. Run with the program, mangled function name and address appended.")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("source-map")
+                    .long("source-map")
+                    .value_name("OLD=NEW")
+                    .help("Look for sources recorded under the OLD path prefix under NEW instead. Can be given multiple times.")
+                    .takes_value(true)
+                    .multiple(true)
+                    .number_of_values(1),
+            )
+            .arg(
+                Arg::with_name("git-repo")
+                    .long("git-repo")
+                    .value_name("PATH")
+                    .help("Local git repository to read sources from when they aren't found, at the revision given by --git-rev or --git-rev-map")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("git-rev")
+                    .long("git-rev")
+                    .value_name("REV")
+                    .help("Revision PROGRAM was built from")
+                    .takes_value(true)
+                    .requires("git-repo")
+                    .conflicts_with("git-rev-map"),
+            )
+            .arg(
+                Arg::with_name("git-rev-map")
+                    .long("git-rev-map")
+                    .value_name("FILE")
+                    .help("File mapping build IDs to the revisions they were built from, one `BUILD_ID REV` per line")
+                    .takes_value(true)
+                    .requires("git-repo"),
+            )
+            .arg(
+                Arg::with_name("templates")
+                    .long("templates")
+                    .value_name("DIR")
+                    .help("Directory of trace template files (*.toml) to offer alongside the built-in ones. Defaults to ~/.config/wachy/templates.")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("share")
+                    .long("share")
+                    .value_name("SOCKET")
+                    .help("Share this session read-only over a unix socket at the given path")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("control")
+                    .long("control")
+                    .value_name("SOCKET")
+                    .help("Accept automation commands over a unix socket at the given path")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("record")
+                    .long("record")
+                    .value_name("FILE")
+                    .help("Record user actions to a file that can be replayed with --replay")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("replay")
+                    .long("replay")
+                    .value_name("FILE")
+                    .help("Replay user actions from a file created with --record")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("watch")
+                    .long("watch")
+                    .value_name("SOCKET")
+                    .help("Watch a session shared with --share (read-only)")
+                    .takes_value(true)
+                    .conflicts_with_all(&[
+                        "PROGRAM", "FUNCTION", "pid", "share", "control", "record", "replay",
+                    ]),
+            )
+            .arg(
+                Arg::with_name("print-messages")
+                    .long("print-messages")
+                    .help("Print the English catalog of UI strings, as a starting point for a translation in ~/.config/wachy/locales")
+                    .conflicts_with_all(&["PROGRAM", "FUNCTION", "watch"]),
+            )
+            .subcommand(
+                SubCommand::with_name("k8s")
+                    .about("Trace a program in a Kubernetes pod, found through the node's CRI runtime with crictl")
+                    .arg(
+                        Arg::with_name("POD")
+                            .help("Pod to trace, as <namespace>/<pod>[/<container>]")
+                            .required(true),
+                    )
+                    .arg(
+                        Arg::with_name("FUNCTION")
+                            .help("Function to trace")
+                            .required(true),
+                    )
+                    .arg(
+                        Arg::with_name("program")
+                            .long("program")
+                            .value_name("PATH")
+                            .help("Path of binary to trace in the container, defaults to the binary of its main process")
+                            .takes_value(true),
+                    ),
             )
             .get_matches();
 
+        if args.is_present("print-messages") {
+            print!("{}", messages::default_catalog());
+            return Ok(());
+        }
+        let messages = messages::Messages::load()?;
+        if let Some(socket_path) = args.value_of("watch") {
+            return share::Watcher::run(socket_path, messages);
+        }
+
+        let k8s_args = args.subcommand_matches("k8s");
+        let container = match (k8s_args, args.value_of("container")) {
+            (Some(_), Some(_)) => return Err("--container can't be used with k8s".into()),
+            (Some(k8s_args), None) => Some(container::Container::resolve_pod(
+                k8s_args.value_of("POD").unwrap(),
+            )?),
+            (None, Some(name)) => Some(container::Container::resolve(name)?),
+            (None, None) => None,
+        };
         // TODO make absolute
-        let file_arg = args.value_of("PROGRAM").unwrap();
-        let file_path = match std::fs::canonicalize(file_arg) {
-            Ok(path) => path.to_string_lossy().into_owned(),
-            Err(err) => return Err(format!("Failed to find file {}: {}", file_arg, err).into()),
+        let file_arg = match (k8s_args, &container) {
+            (Some(k8s_args), Some(container)) => match k8s_args.value_of("program") {
+                Some(program) => program.to_string(),
+                None => container.executable()?,
+            },
+            _ => args.value_of("PROGRAM").unwrap().to_string(),
+        };
+        let file_path = match &container {
+            Some(container) => container.host_path(&file_arg)?,
+            None => match std::fs::canonicalize(&file_arg) {
+                Ok(path) => path.to_string_lossy().into_owned(),
+                // Shared libraries can also be given by name, like with bpftrace
+                Err(err) => match program::find_shared_library(&file_arg) {
+                    Some(path) => path,
+                    None => return Err(format!("Failed to find file {}: {}", file_arg, err).into()),
+                },
+            },
         };
-        let function_name = args.value_of("FUNCTION").unwrap();
+        let function_name = k8s_args.unwrap_or(&args).value_of("FUNCTION").unwrap();
 
-        let program = program::Program::new(file_path)?;
-        controller::Controller::run(program, function_name)?;
+        let pid = match args.value_of("pid").map(|p| p.parse::<u32>()) {
+            Some(Ok(pid)) => Some(pid),
+            Some(Err(err)) => return Err(format!("Invalid pid: {}", err).into()),
+            None => None,
+        };
+        let overhead_threshold = args
+            .value_of("overhead-threshold")
+            .unwrap()
+            .parse::<u64>()
+            .map_err(|err| format!("Invalid overhead threshold: {}", err))?;
+        let report_interval = args
+            .value_of("interval")
+            .map(trace_structs::parse_interval)
+            .transpose()?;
+        if let Some(budget) = args.value_of("memory-budget") {
+            memory::set_budget(memory::parse_budget(budget)?);
+        }
+        let mut program = program::Program::new(file_path.clone())?;
+        if let Some(command) = args.value_of("decompiler") {
+            program.set_decompiler(decompiler::Decompiler::new(command.to_string()));
+        }
+        for rule in args.values_of("source-map").into_iter().flatten() {
+            program.add_source_map(rule)?;
+        }
+        if let Some(repo) = args.value_of("git-repo") {
+            let rev = match (args.value_of("git-rev"), args.value_of("git-rev-map")) {
+                (Some(rev), _) => rev.to_string(),
+                (None, Some(map_path)) => match program.get_build_id() {
+                    Some(build_id) => git_source::lookup_rev(map_path, &build_id)?,
+                    None => return Err(format!("{} has no build ID", file_path).into()),
+                },
+                (None, None) => {
+                    return Err("--git-rev or --git-rev-map is required with --git-repo".into())
+                }
+            };
+            program.set_git_source(git_source::GitSource::new(repo, &rev)?);
+        }
+        let backend_name = if program.is_pe() {
+            // No tracing tools for Windows binaries, they can only be browsed
+            "none"
+        } else {
+            args.value_of("backend").unwrap()
+        };
+        let bpftrace_path = if args.is_present("bundled-runtime") {
+            Some(runtime::bundled_bpftrace()?)
+        } else {
+            None
+        };
+        let templates = templates::load_user_templates(args.value_of("templates"))?;
+        let process_filter = match (&container, pid) {
+            (
+                Some(container::Container {
+                    cgroup: Some(cgroup),
+                    ..
+                }),
+                _,
+            ) => trace_structs::ProcessFilter {
+                cgroup: Some(cgroup.clone()),
+                ..Default::default()
+            },
+            // Without cgroup v2, all processes in the container descend from
+            // its init process
+            (Some(container), _) => trace_structs::ProcessFilter {
+                pids: vec![container.pid],
+                follow_forks: true,
+                ..Default::default()
+            },
+            (None, Some(pid)) if args.is_present("follow-forks") => trace_structs::ProcessFilter {
+                pids: vec![pid],
+                follow_forks: true,
+                ..Default::default()
+            },
+            _ => Default::default(),
+        };
+        let backend = backend::new_backend(
+            backend_name,
+            &file_path,
+            pid,
+            args.value_of("device-path"),
+            bpftrace_path,
+        )?;
+        controller::Controller::run(
+            program,
+            backend,
+            function_name,
+            controller::SessionOptions {
+                share_socket: args.value_of("share"),
+                control_socket: args.value_of("control"),
+                record_path: args.value_of("record"),
+                replay_path: args.value_of("replay"),
+                templates,
+                messages,
+                process_filter,
+                overhead_threshold,
+                report_interval,
+            },
+        )?;
         Ok(())
     };
 
@@ -106,6 +484,7 @@ fn main() {
     // catch_unwind doesn't give us stacktrace, that's why we use a panic hook
     // too.
     let ret = std::panic::catch_unwind(|| run());
+    decompiler::remove_output_dir();
     if let Some(msg) = PANIC_MESSAGE.lock().unwrap().clone() {
         log::error!("{}", msg);
         eprintln!("Error: {}", msg);
@@ -0,0 +1,176 @@
+use crate::error::Error;
+use crate::events::Event;
+use crate::templates;
+use crate::trace_structs::TraceStack;
+use itertools::Itertools;
+use std::collections::HashSet;
+use std::fmt;
+use std::sync::{mpsc, Arc};
+use std::time::Duration;
+
+/// How often the traced processes' mappings are checked for newly loaded
+/// objects
+const WATCH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// An object file mapped into the traced processes
+#[derive(Clone)]
+pub struct MappedObject {
+    pub path: String,
+    /// Whether the program links it, rather than it being loaded at runtime
+    /// (e.g. with `dlopen`)
+    pub linked: bool,
+}
+
+impl fmt::Display for MappedObject {
+    // This is used to display the object in the library picker
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.linked {
+            write!(f, "{}", self.path)
+        } else {
+            write!(f, "{} (dlopen)", self.path)
+        }
+    }
+}
+
+/// Objects mapped by the traced processes other than the program at
+/// `program_path`, the ones it doesn't link first
+pub fn mapped_objects(program_path: &str, trace_stack: &TraceStack) -> Vec<MappedObject> {
+    let linked = linked_libraries(program_path);
+    let mut objects: Vec<MappedObject> = scan(program_path, trace_stack)
+        .into_iter()
+        .map(|path| MappedObject {
+            linked: linked.contains(&path),
+            path,
+        })
+        .collect();
+    objects.sort_by_key(|object| object.linked);
+    objects
+}
+
+/// Watch the traced processes in the background, sending
+/// `Event::ObjectsMapped` whenever they map objects the program doesn't link
+/// that weren't mapped before
+pub fn watch(program_path: String, trace_stack: Arc<TraceStack>, tx: mpsc::Sender<Event>) {
+    std::thread::spawn(move || {
+        let linked = linked_libraries(&program_path);
+        let mut seen: HashSet<String> = scan(&program_path, &trace_stack).into_iter().collect();
+        loop {
+            std::thread::sleep(WATCH_INTERVAL);
+            let new_objects: Vec<String> = scan(&program_path, &trace_stack)
+                .into_iter()
+                .filter(|path| seen.insert(path.clone()) && !linked.contains(path))
+                .collect();
+            if new_objects.is_empty() {
+                continue;
+            }
+            log::info!("Objects mapped at runtime: {:?}", new_objects);
+            if tx.send(Event::ObjectsMapped(new_objects)).is_err() {
+                // Controller has exited
+                return;
+            }
+        }
+    });
+}
+
+/// Paths of the objects mapped by the processes the trace is filtered to, or
+/// by all processes running the program if it isn't filtered by pid
+fn scan(program_path: &str, trace_stack: &TraceStack) -> Vec<String> {
+    let mut pids = trace_stack.get_process_filter().pids;
+    if pids.is_empty() {
+        pids = processes_mapping(program_path);
+    }
+    pids.iter()
+        .flat_map(|&pid| {
+            mapped_files(pid).unwrap_or_else(|err| {
+                log::warn!("{}", err);
+                Vec::new()
+            })
+        })
+        .filter(|path| path != program_path)
+        .unique()
+        .collect()
+}
+
+/// Canonical paths of the libraries the program links. Paths in
+/// `/proc/<pid>/maps` are canonical, unlike the ones `ldd` prints (e.g.
+/// `/lib` may be a link to `/usr/lib`).
+fn linked_libraries(program_path: &str) -> Vec<String> {
+    let libraries = templates::linked_libraries(program_path).unwrap_or_else(|err| {
+        log::warn!("{}", err);
+        Vec::new()
+    });
+    libraries
+        .into_iter()
+        .map(|path| match std::fs::canonicalize(&path) {
+            Ok(canonical) => canonical.to_string_lossy().into_owned(),
+            Err(_) => path,
+        })
+        .collect()
+}
+
+/// Paths of the files `pid` has mapped executable, in the order they are
+/// mapped at
+fn mapped_files(pid: u32) -> Result<Vec<String>, Error> {
+    let maps = std::fs::read_to_string(format!("/proc/{}/maps", pid))
+        .map_err(|err| format!("Failed to read mappings of process {}: {}", pid, err))?;
+    Ok(parse_maps(&maps))
+}
+
+/// Processes with the file at `path` mapped
+fn processes_mapping(path: &str) -> Vec<u32> {
+    let entries = match std::fs::read_dir("/proc") {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+    entries
+        .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse::<u32>().ok())
+        // Processes may exit or not be readable by us
+        .filter(|&pid| mapped_files(pid).map_or(false, |files| files.iter().any(|f| f == path)))
+        .collect()
+}
+
+/// Lines of `/proc/<pid>/maps` look like
+/// `7f1c2a000000-7f1c2a1b0000 r-xp 00000000 08:01 1234   /usr/lib/libz.so.1`.
+/// Anonymous and special mappings (e.g. `[vdso]`) have no path, and files
+/// deleted since they were mapped can't be probed.
+fn parse_maps(maps: &str) -> Vec<String> {
+    maps.lines()
+        .filter(|line| {
+            line.split_whitespace()
+                .nth(1)
+                .map_or(false, |perms| perms.contains('x'))
+        })
+        // Only the path contains slashes
+        .filter_map(|line| line.find('/').map(|i| &line[i..]))
+        .filter(|path| !path.ends_with(" (deleted)"))
+        .unique()
+        .map(|path| path.to_string())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_maps() {
+        let maps = "\
+55d0c4a00000-55d0c4a02000 r--p 00000000 08:01 1001                       /usr/bin/server
+55d0c4a02000-55d0c4a10000 r-xp 00002000 08:01 1001                       /usr/bin/server
+7f1c2a000000-7f1c2a1b0000 r-xp 00000000 08:01 1234                       /usr/lib/libz.so.1
+7f1c2a1b0000-7f1c2a1c0000 r--p 001b0000 08:01 1234                       /usr/lib/libz.so.1
+7f1c2b000000-7f1c2b010000 r-xp 00000000 08:01 1300                       /opt/app/plugins/my plugin.so
+7f1c2c000000-7f1c2c010000 r-xp 00000000 08:01 1301                       /tmp/old.so (deleted)
+7f1c2d000000-7f1c2d021000 rw-p 00000000 00:00 0                          [heap]
+7ffd1a5f0000-7ffd1a5f2000 r-xp 00000000 00:00 0                          [vdso]
+";
+        assert_eq!(
+            parse_maps(maps),
+            vec![
+                "/usr/bin/server",
+                "/usr/lib/libz.so.1",
+                "/opt/app/plugins/my plugin.so"
+            ]
+        );
+    }
+}
@@ -0,0 +1,255 @@
+use crate::error::Error;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+/// Bytes of demangled names kept in the cache when there is no budget
+const DEFAULT_DEMANGLE_CACHE_BYTES: usize = 4 << 20;
+
+/// Share of the budget the demangle cache may use, as a divisor
+const DEMANGLE_CACHE_SHARE: usize = 16;
+
+/// Memory budget in bytes set with `--memory-budget`, 0 if there is none
+static BUDGET: AtomicUsize = AtomicUsize::new(0);
+
+/// Bytes of files mapped into memory, which are never unmapped (see
+/// `Program::parse`)
+static MAPPED: AtomicUsize = AtomicUsize::new(0);
+
+lazy_static::lazy_static! {
+    static ref DEMANGLE_CACHE: Mutex<LruCache> = Mutex::new(LruCache::new(DEFAULT_DEMANGLE_CACHE_BYTES));
+}
+
+/// Set the memory budget. With a budget symbol names are demangled on demand
+/// rather than all up front, so it must be set before loading the program.
+pub fn set_budget(bytes: usize) {
+    BUDGET.store(bytes, Ordering::Relaxed);
+    DEMANGLE_CACHE.lock().unwrap().capacity = bytes / DEMANGLE_CACHE_SHARE;
+}
+
+pub fn budget() -> Option<usize> {
+    match BUDGET.load(Ordering::Relaxed) {
+        0 => None,
+        bytes => Some(bytes),
+    }
+}
+
+/// Whether usage should be kept down, i.e. a budget is set and the resident
+/// size is over it
+pub fn over_budget() -> bool {
+    match (budget(), resident_bytes()) {
+        (Some(budget), Some(resident)) => resident > budget,
+        _ => false,
+    }
+}
+
+/// Parse a budget like `512M` or `2G` into bytes. A plain number is in MiB.
+pub fn parse_budget(budget: &str) -> Result<usize, Error> {
+    let (number, shift) = match budget.char_indices().last() {
+        Some((i, 'K')) | Some((i, 'k')) => (&budget[..i], 10),
+        Some((i, 'M')) | Some((i, 'm')) => (&budget[..i], 20),
+        Some((i, 'G')) | Some((i, 'g')) => (&budget[..i], 30),
+        _ => (budget, 20),
+    };
+    match number.parse::<usize>() {
+        Ok(n) if n > 0 => n
+            .checked_mul(1 << shift)
+            .ok_or_else(|| format!("Memory budget {} is too large", budget).into()),
+        _ => Err(format!("Invalid memory budget {}, expected e.g. 512M or 2G", budget).into()),
+    }
+}
+
+/// Record that `bytes` of a file were mapped into memory
+pub fn record_mapped(bytes: usize) {
+    MAPPED.fetch_add(bytes, Ordering::Relaxed);
+}
+
+pub fn mapped_bytes() -> usize {
+    MAPPED.load(Ordering::Relaxed)
+}
+
+/// Resident set size of the process, from `/proc/self/statm`
+pub fn resident_bytes() -> Option<usize> {
+    let statm = std::fs::read_to_string("/proc/self/statm").ok()?;
+    let pages: usize = statm.split_whitespace().nth(1)?.parse().ok()?;
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    if page_size <= 0 {
+        return None;
+    }
+    Some(pages * page_size as usize)
+}
+
+/// Demangled `name`, computed with `demangle_fn` unless it is in the cache
+pub fn demangle_cached<F>(name: &'static str, demangle_fn: F) -> Option<String>
+where
+    F: FnOnce(&str) -> Option<String>,
+{
+    if let Some(demangled) = DEMANGLE_CACHE.lock().unwrap().get(name) {
+        return demangled;
+    }
+    // Demangle without holding the lock, the search thread demangles too
+    let demangled = demangle_fn(name);
+    DEMANGLE_CACHE
+        .lock()
+        .unwrap()
+        .insert(name, demangled.clone());
+    demangled
+}
+
+pub fn demangle_cache_bytes() -> usize {
+    DEMANGLE_CACHE.lock().unwrap().bytes
+}
+
+pub fn clear_demangle_cache() {
+    DEMANGLE_CACHE.lock().unwrap().clear();
+}
+
+/// Demangled names by mangled name, evicting the least recently used once
+/// they take up more than `capacity` bytes
+struct LruCache {
+    capacity: usize,
+    /// Estimated bytes used by the entries
+    bytes: usize,
+    /// Incremented on each access, to order entries by recency
+    tick: u64,
+    entries: HashMap<&'static str, (Option<String>, u64)>,
+    /// Keys of `entries` by the tick they were last used at
+    recency: BTreeMap<u64, &'static str>,
+}
+
+impl LruCache {
+    fn new(capacity: usize) -> LruCache {
+        LruCache {
+            capacity,
+            bytes: 0,
+            tick: 0,
+            entries: HashMap::new(),
+            recency: BTreeMap::new(),
+        }
+    }
+
+    fn get(&mut self, name: &'static str) -> Option<Option<String>> {
+        self.tick += 1;
+        let (demangled, tick) = self.entries.get_mut(name)?;
+        self.recency.remove(tick);
+        *tick = self.tick;
+        self.recency.insert(self.tick, name);
+        Some(demangled.clone())
+    }
+
+    fn insert(&mut self, name: &'static str, demangled: Option<String>) {
+        self.tick += 1;
+        let size = entry_bytes(&demangled);
+        if let Some((old, tick)) = self.entries.insert(name, (demangled, self.tick)) {
+            self.recency.remove(&tick);
+            self.bytes -= entry_bytes(&old);
+        }
+        self.recency.insert(self.tick, name);
+        self.bytes += size;
+        while self.bytes > self.capacity {
+            let (tick, name) = match self.recency.iter().next() {
+                Some((&tick, &name)) => (tick, name),
+                None => break,
+            };
+            self.recency.remove(&tick);
+            if let Some((old, _)) = self.entries.remove(name) {
+                self.bytes -= entry_bytes(&old);
+            }
+        }
+    }
+
+    fn clear(&mut self) {
+        self.entries = HashMap::new();
+        self.recency.clear();
+        self.bytes = 0;
+    }
+}
+
+/// Rough size of a cache entry, including the map overhead
+fn entry_bytes(demangled: &Option<String>) -> usize {
+    std::mem::size_of::<(&str, (Option<String>, u64))>()
+        + std::mem::size_of::<(u64, &str)>()
+        + demangled.as_ref().map_or(0, |d| d.capacity())
+}
+
+/// Memory used by the largest data wachy keeps, as shown with `M`
+pub struct Usage {
+    /// Description and estimated bytes of each kind of data
+    pub entries: Vec<(&'static str, usize)>,
+}
+
+impl fmt::Display for Usage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match resident_bytes() {
+            Some(resident) => writeln!(f, "Resident: {}", format_bytes(resident))?,
+            None => writeln!(f, "Resident: unknown")?,
+        }
+        match budget() {
+            Some(budget) => writeln!(f, "Budget: {}", format_bytes(budget))?,
+            None => writeln!(f, "Budget: none (set with --memory-budget)")?,
+        }
+        writeln!(f)?;
+        let width = self.entries.iter().map(|(name, _)| name.len()).max();
+        for (name, bytes) in &self.entries {
+            writeln!(
+                f,
+                "{:width$}  {}",
+                name,
+                format_bytes(*bytes),
+                width = width.unwrap_or(0)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// `bytes` in the largest unit that keeps it at least 1, e.g. `1.5 MiB`
+pub fn format_bytes(bytes: usize) -> String {
+    const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit + 1 < UNITS.len() {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_budget() {
+        assert_eq!(parse_budget("512").unwrap(), 512 << 20);
+        assert_eq!(parse_budget("512M").unwrap(), 512 << 20);
+        assert_eq!(parse_budget("2G").unwrap(), 2 << 30);
+        assert_eq!(parse_budget("64k").unwrap(), 64 << 10);
+        assert!(parse_budget("0").is_err());
+        assert!(parse_budget("lots").is_err());
+        assert_eq!(format_bytes(512), "512 B");
+        assert_eq!(format_bytes(3 << 19), "1.5 MiB");
+    }
+
+    #[test]
+    fn test_lru_cache() {
+        let entry = entry_bytes(&Some("a()".to_string()));
+        let mut cache = LruCache::new(2 * entry);
+        cache.insert("_Z1av", Some("a()".to_string()));
+        cache.insert("_Z1bv", Some("b()".to_string()));
+        // Makes `_Z1av` the most recently used, so `_Z1bv` is evicted
+        assert_eq!(cache.get("_Z1av"), Some(Some("a()".to_string())));
+        cache.insert("_Z1cv", Some("c()".to_string()));
+        assert_eq!(cache.get("_Z1bv"), None);
+        assert_eq!(cache.get("_Z1av"), Some(Some("a()".to_string())));
+        assert_eq!(cache.get("_Z1cv"), Some(Some("c()".to_string())));
+        assert_eq!(cache.bytes, 2 * entry);
+        cache.clear();
+        assert_eq!(cache.get("_Z1av"), None);
+    }
+}
@@ -0,0 +1,282 @@
+use crate::error::Error;
+use crate::store;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/// English text of every user facing string, by key. Placeholders like
+/// `{function}` are filled in by `Messages::format`.
+const DEFAULT_MESSAGES: &[(&str, &str)] = &[
+    ("button.cancel", "Cancel"),
+    ("button.close", "Close"),
+    ("button.double_sizes", "Double sizes"),
+    ("button.drop_caches", "Drop caches"),
+    ("button.edit", "Edit"),
+    ("button.generated", "Use generated"),
+    ("button.quit", "Quit"),
+    ("button.refresh", "Refresh"),
+    ("button.run", "Run"),
+    ("button.sample", "Sample instead"),
+    ("button.save", "Save"),
+    ("button.trace_anyway", "Trace anyway"),
+    ("button.trace_sampled", "Trace 1 in {rate} calls"),
+    ("column.frequency", "Frequency"),
+    ("column.latency", "Latency"),
+    ("column.latency_p50", "p50"),
+    ("column.latency_p99", "p99"),
+    ("column.latency_max", "Max"),
+    ("column.latency_off_cpu", "{latency} off%"),
+    ("column.ipc", "IPC"),
+    ("column.cache_misses", "Miss/call"),
+    ("column.parent_share", "% parent"),
+    ("search.prompt", "Search: "),
+    ("quit.confirm", "Are you sure you want to quit?"),
+    ("share.ended", "Shared session has ended."),
+    (
+        "function.setup_error",
+        "Error setting up function {function}: {error}",
+    ),
+    (
+        "enter.no_calls",
+        "No calls found in {function} on line {line}. Note the call may have been inlined.",
+    ),
+    ("enter.select_call", "Select the call to enter"),
+    (
+        "histogram.gathering",
+        "Gathering latency histogram for {function}...",
+    ),
+    (
+        "histogram.result",
+        "Latency histogram in nanoseconds for {function}:\n{histogram}",
+    ),
+    (
+        "histogram.line_result",
+        "Latency histogram in nanoseconds for line {line}:\n{histogram}",
+    ),
+    ("histogram.line_gathering", "Gathering..."),
+    (
+        "histogram.line_gathering_of",
+        "Gathering latency histogram for line {line} of {function}...",
+    ),
+    ("histogram.empty", "<Empty>"),
+    (
+        "line_stats.not_traced",
+        "Line {line} is not traced. Statistics can be shown for the line of {function} or a traced call.",
+    ),
+    (
+        "line_stats.gathering",
+        "Gathering statistics for line {line} of {function}...",
+    ),
+    (
+        "callers.not_traced",
+        "Line {line} is not traced. Callers can be counted for the line of {function} or a traced call.",
+    ),
+    (
+        "callers.gathering",
+        "Gathering callers of {function} reaching line {line}...",
+    ),
+    (
+        "args.not_traced",
+        "Line {line} is not traced. Arguments can be captured for the line of {function} or a traced call.",
+    ),
+    (
+        "args.unknown",
+        "No arguments of {function} can be captured. Their types come from debug info, and only integers, pointers, strings and small structs passed in registers are captured.",
+    ),
+    (
+        "args.gathering",
+        "Gathering arguments on line {line} of {function}...",
+    ),
+    (
+        "groups.gathering",
+        "Gathering calls on line {line} of {function} by {argument}...",
+    ),
+    (
+        "threads.not_traced",
+        "Line {line} is not traced. Calls can be split by thread for the line of {function} or a traced call.",
+    ),
+    (
+        "threads.gathering",
+        "Gathering calls on line {line} of {function} by thread...",
+    ),
+    (
+        "retvals.not_traced",
+        "Line {line} is not traced. Return values can be counted for the line of {function} or a traced call.",
+    ),
+    (
+        "retvals.gathering",
+        "Gathering values returned on line {line} of {function}...",
+    ),
+    (
+        "breakdown.gathering",
+        "Gathering latency breakdown for {function}...",
+    ),
+];
+
+/// Settings read from `config.toml` in the config directory
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    /// Name of the message catalog to use, e.g. `de`
+    locale: Option<String>,
+}
+
+/// Catalog of user facing strings in the selected locale. Strings missing
+/// from the locale's catalog fall back to English, the default catalog is
+/// entirely English.
+#[derive(Default)]
+pub struct Messages {
+    translations: HashMap<String, String>,
+}
+
+impl Messages {
+    /// Load the catalog of the locale set in `config.toml`, or if none is set
+    /// of the language in the environment (e.g. `LANG=de_DE.UTF-8`) if there
+    /// is a catalog for it. Catalogs are read from the `locales` directory of
+    /// the config directory, e.g. `~/.config/wachy/locales/de.toml`.
+    pub fn load() -> Result<Messages, Error> {
+        let config_dir = match store::config_dir() {
+            Some(dir) => dir,
+            None => return Ok(Messages::default()),
+        };
+        let config = read_config(&config_dir.join("config.toml"))?;
+        let path = |locale: &str| -> PathBuf {
+            config_dir.join("locales").join(format!("{}.toml", locale))
+        };
+        match config.locale {
+            Some(locale) => Messages::from_file(&path(&locale)),
+            None => match env_language() {
+                Some(language) if path(&language).is_file() => {
+                    Messages::from_file(&path(&language))
+                }
+                _ => Ok(Messages::default()),
+            },
+        }
+    }
+
+    fn from_file(path: &Path) -> Result<Messages, Error> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|err| format!("Failed to read {}: {}", path.display(), err))?;
+        Messages::parse(&contents).map_err(|err| format!("{}: {}", path.display(), err).into())
+    }
+
+    /// Parse a catalog, a TOML table of keys to translated strings
+    fn parse(contents: &str) -> Result<Messages, Error> {
+        let translations: HashMap<String, String> =
+            toml::from_str(contents).map_err(|err| err.to_string())?;
+        for (key, translation) in &translations {
+            let default =
+                default_message(key).ok_or_else(|| format!("Unknown message key \"{}\"", key))?;
+            // A translation that drops or invents a placeholder would silently
+            // show the wrong text
+            if placeholders(translation) != placeholders(default) {
+                return Err(format!(
+                    "Translation of \"{}\" must use the placeholders of \"{}\"",
+                    key, default
+                )
+                .into());
+            }
+        }
+        Ok(Messages { translations })
+    }
+
+    /// The string with `key`, which must be in `DEFAULT_MESSAGES`
+    pub fn get(&self, key: &str) -> &str {
+        match self.translations.get(key) {
+            Some(translation) => translation,
+            None => default_message(key).expect("Bug: unknown message key"),
+        }
+    }
+
+    /// The string with `key`, with each `{name}` placeholder replaced by the
+    /// corresponding value in `args`
+    pub fn format(&self, key: &str, args: &[(&str, &dyn fmt::Display)]) -> String {
+        let mut message = self.get(key).to_string();
+        for (name, value) in args {
+            message = message.replace(&format!("{{{}}}", name), &value.to_string());
+        }
+        message
+    }
+}
+
+/// The English catalog, as a starting point for translations
+pub fn default_catalog() -> String {
+    DEFAULT_MESSAGES
+        .iter()
+        .map(|(key, message)| format!("\"{}\" = {:?}\n", key, message))
+        .collect()
+}
+
+fn default_message(key: &str) -> Option<&'static str> {
+    DEFAULT_MESSAGES
+        .iter()
+        .find(|(k, _)| *k == key)
+        .map(|(_, message)| *message)
+}
+
+fn read_config(path: &Path) -> Result<Config, Error> {
+    match std::fs::read_to_string(path) {
+        Ok(contents) => toml::from_str(&contents)
+            .map_err(|err| format!("Invalid config {}: {}", path.display(), err).into()),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(Config::default()),
+        Err(err) => Err(format!("Failed to read {}: {}", path.display(), err).into()),
+    }
+}
+
+/// Language of the environment's locale, e.g. `de` for `de_DE.UTF-8`
+fn env_language() -> Option<String> {
+    let locale = ["LC_ALL", "LC_MESSAGES", "LANG"]
+        .iter()
+        .filter_map(|var| std::env::var(var).ok())
+        .find(|value| !value.is_empty())?;
+    let language = locale.split(|c| c == '_' || c == '.' || c == '@').next()?;
+    match language {
+        "" | "C" | "POSIX" => None,
+        _ => Some(language.to_string()),
+    }
+}
+
+/// Names of the `{name}` placeholders in `message`, sorted
+fn placeholders(message: &str) -> Vec<&str> {
+    let mut names: Vec<&str> = message
+        .split('{')
+        .skip(1)
+        .filter_map(|part| part.split_once('}').map(|(name, _)| name))
+        .collect();
+    names.sort_unstable();
+    names
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let messages = Messages::parse(
+            r#"
+"button.quit" = "Beenden"
+"enter.no_calls" = "Keine Aufrufe in Zeile {line} von {function}."
+"#,
+        )
+        .unwrap();
+        assert_eq!(messages.get("button.quit"), "Beenden");
+        assert_eq!(messages.get("button.cancel"), "Cancel");
+        assert_eq!(
+            messages.format("enter.no_calls", &[("function", &"foo"), ("line", &12)]),
+            "Keine Aufrufe in Zeile 12 von foo."
+        );
+
+        assert!(Messages::parse(r#""button.qiut" = "Beenden""#).is_err());
+        assert!(Messages::parse(r#""enter.no_calls" = "Keine Aufrufe in {function}""#).is_err());
+    }
+
+    #[test]
+    fn test_default_catalog() {
+        let messages = Messages::parse(&default_catalog()).unwrap();
+        for (key, message) in DEFAULT_MESSAGES {
+            assert_eq!(messages.get(key), *message);
+        }
+    }
+}
@@ -0,0 +1,177 @@
+use crate::program::FunctionName;
+use std::time::Duration;
+
+/// Most lines a plan can time, the number of slots the BPF program commits
+/// (`MAX_SLOTS` in `bpf/wachy.bpf.c`)
+pub const MAX_SLOTS: usize = 64;
+
+/// Slot of probes that don't time a line (`NO_SLOT` in `bpf/wachy.bpf.c`)
+const NO_SLOT: u64 = 0xffff;
+
+/// Cookie flag of probes that also time recursive calls of the last frame's
+/// function (`RECURSIVE_CALLS` in `bpf/wachy.bpf.c`)
+const RECURSIVE_CALLS: u64 = 1 << 32;
+
+/// What a probe does when its thread is at its depth of the trace stack
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ProbeKind {
+    /// Go one frame deeper, starting to time the frame's line if it has one
+    FrameEntry = 0,
+    /// Go back one frame, adding the time since entry to the frame's line if
+    /// it has one. Returning from the outermost frame commits the times
+    /// gathered during the call.
+    FrameExit = 1,
+    /// Start timing a call
+    LineStart = 2,
+    /// Add the time since the call started to its line
+    LineEnd = 3,
+}
+
+/// A probe of a `NativePlan`. All probes run the same BPF program, which
+/// tells them apart by their cookie.
+#[derive(Debug, PartialEq)]
+pub struct NativeProbe {
+    pub function: FunctionName,
+    /// Offset within `function`
+    pub offset: u32,
+    /// Whether this is a uretprobe, rather than a uprobe at `offset`
+    pub retprobe: bool,
+    pub kind: ProbeKind,
+    /// Depth the thread must be at for the probe to fire. Frame exits fire
+    /// one deeper, i.e. while still in the frame.
+    pub depth: u8,
+    /// Index in `NativePlan::lines` of the line the probe times, if any
+    pub slot: Option<u16>,
+    /// Whether recursive calls of the frame's function are timed on its line
+    /// too, see `NativePlan::recursive_calls`
+    pub recursive_calls: bool,
+}
+
+impl NativeProbe {
+    /// Cookie the probe is attached with, which the BPF program decodes
+    #[cfg_attr(not(feature = "libbpf"), allow(dead_code))]
+    pub fn cookie(&self) -> u64 {
+        let slot = self.slot.map_or(NO_SLOT, u64::from);
+        let flags = if self.recursive_calls {
+            RECURSIVE_CALLS
+        } else {
+            0
+        };
+        flags | (self.kind as u64) << 24 | u64::from(self.depth) << 16 | slot
+    }
+}
+
+/// Probes tracing the lines of the current function in-process, the native
+/// equivalent of the bpftrace program for `TraceMode::Line`. See
+/// `TraceStack::get_native_plan`.
+#[derive(Debug, Default)]
+pub struct NativePlan {
+    pub probes: Vec<NativeProbe>,
+    /// Traced lines, by slot
+    pub lines: Vec<u32>,
+    /// Only trace these processes, or all if empty
+    pub pids: Vec<u32>,
+    /// Also read hardware counters while timing lines, see `LineCounters`
+    pub counters: bool,
+    /// Also time recursive calls of the current function on its line, rather
+    /// than only the outermost ones. Lines within it are only ever timed in
+    /// the outermost call.
+    pub recursive_calls: bool,
+    /// How often trace data is sent
+    pub report_interval: Duration,
+}
+
+impl NativePlan {
+    /// Probe the entry and returns of `function`, the frame at `depth`. Returns
+    /// are probed at `return_offsets` if set, see `FrameInfo::return_offsets`.
+    pub fn add_frame(
+        &mut self,
+        function: FunctionName,
+        depth: u8,
+        return_offsets: &Option<Vec<u32>>,
+        slot: Option<u16>,
+    ) {
+        let recursive_calls = slot.is_some() && self.recursive_calls;
+        let probe = |offset, retprobe, kind| NativeProbe {
+            function,
+            offset,
+            retprobe,
+            kind,
+            depth,
+            slot,
+            recursive_calls,
+        };
+        self.probes.push(probe(0, false, ProbeKind::FrameEntry));
+        match return_offsets {
+            Some(offsets) => self.probes.extend(
+                offsets
+                    .iter()
+                    .map(|&offset| probe(offset, false, ProbeKind::FrameExit)),
+            ),
+            None => self.probes.push(probe(0, true, ProbeKind::FrameExit)),
+        }
+    }
+
+    /// Time the call in `function` at `start_offset`, which is done at any of
+    /// `end_offsets`. `depth` is the depth inside `function`.
+    pub fn add_call(
+        &mut self,
+        function: FunctionName,
+        depth: u8,
+        start_offset: u32,
+        end_offsets: Vec<u32>,
+        slot: u16,
+    ) {
+        let probe = |offset, kind| NativeProbe {
+            function,
+            offset,
+            retprobe: false,
+            kind,
+            depth,
+            slot: Some(slot),
+            recursive_calls: false,
+        };
+        self.probes.push(probe(start_offset, ProbeKind::LineStart));
+        self.probes.extend(
+            end_offsets
+                .into_iter()
+                .map(|offset| probe(offset, ProbeKind::LineEnd)),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_plan() {
+        let mut plan = NativePlan {
+            recursive_calls: true,
+            ..NativePlan::default()
+        };
+        plan.add_frame(FunctionName("main"), 0, &None, None);
+        plan.add_frame(FunctionName("handle"), 1, &Some(vec![0x20, 0x48]), Some(0));
+        plan.add_call(FunctionName("handle"), 2, 0x10, vec![0x15], 1);
+        let kinds: Vec<(u32, bool, ProbeKind)> = plan
+            .probes
+            .iter()
+            .map(|probe| (probe.offset, probe.retprobe, probe.kind))
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                (0, false, ProbeKind::FrameEntry),
+                (0, true, ProbeKind::FrameExit),
+                (0, false, ProbeKind::FrameEntry),
+                (0x20, false, ProbeKind::FrameExit),
+                (0x48, false, ProbeKind::FrameExit),
+                (0x10, false, ProbeKind::LineStart),
+                (0x15, false, ProbeKind::LineEnd),
+            ]
+        );
+        assert_eq!(plan.probes[0].cookie(), 0x00_00_ffff);
+        assert_eq!(plan.probes[3].cookie(), 0x1_01_01_0000);
+        assert_eq!(plan.probes[6].cookie(), 0x03_02_0001);
+    }
+}
@@ -0,0 +1,140 @@
+use crate::error::Error;
+use addr2line::Location;
+use object::read::File;
+use object::Object;
+use pdb::FallibleIterator;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// Function symbols and address to line mappings of a PE binary, read from its
+/// PDB. MSVC binaries usually don't carry symbols themselves.
+pub struct PdbInfo {
+    /// (name, address, size) of each function
+    functions: Vec<(&'static str, u64, u64)>,
+    /// (address, index into `files`, line), sorted by address. Each entry
+    /// covers addresses up to the next one.
+    lines: Vec<(u64, usize, u32)>,
+    files: Vec<String>,
+}
+
+impl PdbInfo {
+    pub fn load(file: &File<'static>, file_path: &str) -> Result<PdbInfo, Error> {
+        let pdb_path = find_pdb(file, file_path).ok_or_else(|| {
+            format!(
+                "Program {} is missing debug symbols (PDB file not found)",
+                file_path
+            )
+        })?;
+        // TODO should we be checking the GUID/age from CodeView?
+        let pdb_file = match std::fs::File::open(&pdb_path) {
+            Ok(pdb_file) => pdb_file,
+            Err(err) => return Err(format!("Failed to open file {}: {}", pdb_path, err).into()),
+        };
+        let info = PdbInfo::parse(pdb_file, file.relative_address_base())
+            .map_err(|err| format!("Failed to parse PDB file {}: {}", pdb_path, err))?;
+        log::info!(
+            "Using PDB file {} for symbols and address to line mappings",
+            pdb_path
+        );
+        Ok(info)
+    }
+
+    fn parse(pdb_file: std::fs::File, image_base: u64) -> pdb::Result<PdbInfo> {
+        let mut pdb = pdb::PDB::open(pdb_file)?;
+        let address_map = pdb.address_map()?;
+        let string_table = pdb.string_table()?;
+        let debug_information = pdb.debug_information()?;
+
+        let mut functions = Vec::new();
+        let mut lines = Vec::new();
+        let mut files = Vec::new();
+        let mut file_indices = HashMap::new();
+        let mut modules = debug_information.modules()?;
+        while let Some(module) = modules.next()? {
+            let info = match pdb.module_info(&module)? {
+                Some(info) => info,
+                None => continue,
+            };
+
+            let mut symbols = info.symbols()?;
+            while let Some(symbol) = symbols.next()? {
+                if let Ok(pdb::SymbolData::Procedure(procedure)) = symbol.parse() {
+                    if let Some(rva) = procedure.offset.to_rva(&address_map) {
+                        // Leaked for the same reason as the mmap in
+                        // `Program::parse` - `FunctionName` is static.
+                        let name: &'static str =
+                            Box::leak(procedure.name.to_string().into_owned().into_boxed_str());
+                        functions.push((name, image_base + rva.0 as u64, procedure.len as u64));
+                    }
+                }
+            }
+
+            let program = info.line_program()?;
+            let mut module_lines = program.lines();
+            while let Some(line) = module_lines.next()? {
+                let rva = match line.offset.to_rva(&address_map) {
+                    Some(rva) => rva,
+                    None => continue,
+                };
+                let name = program
+                    .get_file_info(line.file_index)?
+                    .name
+                    .to_string_lossy(&string_table)?;
+                let index = *file_indices.entry(name.to_string()).or_insert_with(|| {
+                    files.push(name.to_string());
+                    files.len() - 1
+                });
+                lines.push((image_base + rva.0 as u64, index, line.line_start));
+            }
+        }
+        lines.sort_unstable_by_key(|&(address, _, _)| address);
+
+        Ok(PdbInfo {
+            functions,
+            lines,
+            files,
+        })
+    }
+
+    /// (name, address, size) of each function
+    pub fn functions(&self) -> impl Iterator<Item = (&'static str, u64, u64)> + '_ {
+        self.functions.iter().copied()
+    }
+
+    pub fn find_location(&self, address: u64) -> Option<Location> {
+        let index = match self
+            .lines
+            .binary_search_by_key(&address, |&(line_address, _, _)| line_address)
+        {
+            Ok(index) => index,
+            Err(0) => return None,
+            Err(index) => index - 1,
+        };
+        let (_, file_index, line) = self.lines[index];
+        Some(Location {
+            file: Some(&self.files[file_index]),
+            line: Some(line),
+            column: None,
+        })
+    }
+}
+
+/// Look for the PDB recorded in the binary's CodeView entry. That path is
+/// usually from the build machine, so also check for a file with the same name
+/// next to the binary, and finally `<binary>.pdb`.
+fn find_pdb(file: &File<'static>, file_path: &str) -> Option<String> {
+    let path = Path::new(file_path);
+    let mut candidates = Vec::new();
+    if let Ok(Some(code_view)) = file.pdb_info() {
+        let pdb_path = String::from_utf8_lossy(code_view.path()).into_owned();
+        if let (Some(dir), Some(name)) = (path.parent(), pdb_path.rsplit(&['\\', '/'][..]).next()) {
+            candidates.push(dir.join(name));
+        }
+        candidates.push(PathBuf::from(pdb_path));
+    }
+    candidates.push(path.with_extension("pdb"));
+    candidates
+        .into_iter()
+        .find(|candidate| candidate.is_file())
+        .map(|candidate| candidate.to_string_lossy().into_owned())
+}
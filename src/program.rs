@@ -1,21 +1,33 @@
+use crate::arm;
+use crate::debuginfod;
+use crate::decompiler::{Decompiled, Decompiler};
+use crate::embedded_source;
 use crate::error::Error;
+use crate::git_source::GitSource;
+use crate::gopclntab::{self, GoPclntab};
+use crate::memory;
+use crate::pe::PdbInfo;
+use crate::source_map::SourceMap;
 use addr2line::fallible_iterator::FallibleIterator;
 use addr2line::Location;
-use object::read::File;
+use itertools::Itertools;
+use object::read::{File, Section};
 use object::Object;
 use object::ObjectSection;
 use object::ObjectSymbol;
 use object::ObjectSymbolTable;
 use std::borrow::Cow;
-use std::collections::{hash_map, HashMap};
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
 use std::fmt;
 use std::io::ErrorKind;
 use std::io::Read;
+use std::process::Command;
 use std::sync::Arc;
 use zydis::ffi::Decoder;
 use zydis::formatter::{Formatter, OutputBuffer};
 use zydis::{
-    enums::generated::{AddressWidth, FormatterStyle, MachineMode, Mnemonic},
+    enums::generated::{AddressWidth, FormatterStyle, MachineMode, Mnemonic, Register},
     DecodedInstruction,
 };
 
@@ -25,7 +37,23 @@ pub struct FunctionName(pub &'static str);
 
 impl fmt::Display for FunctionName {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let demangled = cplus_demangle::demangle(self.0).unwrap_or(String::from(self.0));
+        let function = self.unqualified();
+        // Keep the address of a disambiguated local symbol as is
+        let (name, suffix) = match function.0.rfind(ADDRESS_SEPARATOR) {
+            Some(index) if function.synthesized_address().is_some() => function.0.split_at(index),
+            _ => (function.0, ""),
+        };
+        // Functions in shared libraries are shown like `libz.so.1`deflate`
+        let object = match self.object() {
+            Some(path) => match std::path::Path::new(path).file_name() {
+                Some(file_name) => format!("{}{}", file_name.to_string_lossy(), OBJECT_SEPARATOR),
+                None => format!("{}{}", path, OBJECT_SEPARATOR),
+            },
+            None => String::new(),
+        };
+        let demangled = object
+            + &memory::demangle_cached(name, demangle).unwrap_or(String::from(name))
+            + suffix;
         fmt::Display::fmt(&demangled, f)
     }
 }
@@ -36,49 +64,256 @@ impl fmt::Debug for FunctionName {
     }
 }
 
+/// Prefix of the names given to functions found in `.eh_frame` of stripped
+/// binaries, followed by their address in hex
+const SYNTHESIZED_PREFIX: &str = "fn_0x";
+
+/// Separates the name of a local function that shares it with others (e.g.
+/// static functions in different files) from its address in hex
+const ADDRESS_SEPARATOR: &str = "@0x";
+
+/// Separates the path of the shared library a function is in from its name,
+/// like in DTrace probe descriptions (`/lib/libz.so.1`deflate`)
+pub const OBJECT_SEPARATOR: char = '`';
+
+impl FunctionName {
+    /// Address of a function that has no unique symbol to refer to it by -
+    /// either found in `.eh_frame` of a stripped binary (`fn_0x401230`), or a
+    /// local function with the same name as others (`helper@0x401230`)
+    pub fn synthesized_address(&self) -> Option<u64> {
+        let name = self.unqualified().0;
+        let address = match name.strip_prefix(SYNTHESIZED_PREFIX) {
+            Some(address) => address,
+            None => name.rsplit_once(ADDRESS_SEPARATOR)?.1,
+        };
+        u64::from_str_radix(address, 16).ok()
+    }
+
+    /// Path of the shared library the function is in, if it was loaded from
+    /// one rather than the program (see `Program::load_library`)
+    pub fn object(&self) -> Option<&'static str> {
+        self.0
+            .split_once(OBJECT_SEPARATOR)
+            .map(|(object, _)| object)
+    }
+
+    /// Name of the function within its object, i.e. without the path of the
+    /// shared library it is in
+    pub fn unqualified(&self) -> FunctionName {
+        match self.0.split_once(OBJECT_SEPARATOR) {
+            Some((_, name)) => FunctionName(name),
+            None => *self,
+        }
+    }
+}
+
 pub struct Program {
     /// Only used when printing error messages
     pub file_path: String,
     file: File<'static>,
     name_to_symbol: Arc<HashMap<FunctionName, SymbolInfo>>,
     address_to_name: HashMap<u64, FunctionName>,
-    context: addr2line::Context<gimli::EndianArcSlice<gimli::RunTimeEndian>>,
+    lines: LineMapper,
     // (start_address, size) of runtime addresses for dynamic symbols (functions
     // loaded from shared libraries)
     dynamic_symbols_ranges: Vec<std::ops::Range<u64>>,
     dynamic_symbols_map: HashMap<u64, FunctionName>,
+    /// Used for functions without source information, if set
+    decompiler: Option<Decompiler>,
+    source_map: SourceMap,
+    /// Used for sources that aren't available locally, if set
+    git_source: Option<GitSource>,
+    /// Trace returns by probing return instructions even if this isn't a Go
+    /// program, for kernels without uretprobes
+    probe_return_instructions: bool,
+    /// Parts of the file that couldn't be used, and how that limits what is
+    /// shown
+    load_warnings: Vec<String>,
+    /// Shared libraries the program links that were loaded to enter functions
+    /// they define, see `load_dynamic_symbol`
+    libraries: Vec<Program>,
+}
+
+pub type DwarfReader = gimli::EndianArcSlice<gimli::RunTimeEndian>;
+
+/// Source of address to line mappings
+enum LineMapper {
+    Dwarf(addr2line::Context<DwarfReader>),
+    Pdb(PdbInfo),
+    Go(GoPclntab),
+    /// Stripped binaries have no line information, their disassembly is shown
+    /// instead
+    None,
 }
 
 pub struct SymbolsGenerator {
     name_to_symbol: Arc<HashMap<FunctionName, SymbolInfo>>,
 }
 
+/// Iterates over canonical symbols, aliases are skipped
 impl<'a> IntoIterator for &'a SymbolsGenerator {
     type Item = &'a SymbolInfo;
-    type IntoIter = hash_map::Values<'a, FunctionName, SymbolInfo>;
+    type IntoIter = Box<dyn Iterator<Item = &'a SymbolInfo> + 'a>;
     fn into_iter(self) -> Self::IntoIter {
-        self.name_to_symbol.values()
+        Box::new(
+            self.name_to_symbol
+                .values()
+                .filter(|symbol| symbol.alias_of.is_none()),
+        )
+    }
+}
+
+/// Instruction set a function is encoded in. ARM32 binaries can mix ARM and
+/// Thumb code (interworking), so this is tracked per function rather than per
+/// program.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum IsaMode {
+    X86_64,
+    /// 32-bit ARM (A32) instructions
+    Arm,
+    /// Thumb (T32) instructions
+    Thumb,
+    /// Any other architecture
+    Unknown,
+}
+
+impl IsaMode {
+    /// Mode of a function whose symbol has the given `address`, along with the
+    /// actual start address of its code.
+    fn from_symbol(architecture: object::Architecture, address: u64) -> (IsaMode, u64) {
+        match architecture {
+            object::Architecture::X86_64 => (IsaMode::X86_64, address),
+            // Thumb function symbols have the lowest address bit set. It must
+            // be cleared for the code address, which is what call instructions
+            // (including mode switching BLX) resolve to.
+            object::Architecture::Arm if address & 1 == 1 => (IsaMode::Thumb, address & !1),
+            object::Architecture::Arm => (IsaMode::Arm, address),
+            _ => (IsaMode::Unknown, address),
+        }
     }
+
+    /// Whether all instructions of functions in this mode can be decoded, for
+    /// analyses other than finding calls. The decoder only handles x86-64,
+    /// decoding anything else would produce garbage.
+    pub fn can_decode(self) -> bool {
+        self == IsaMode::X86_64
+    }
+
+    /// Whether calls in functions of this mode can be found, see `find_calls`
+    pub fn can_find_calls(self) -> bool {
+        self != IsaMode::Unknown
+    }
+}
+
+/// Call (or tail call) instruction, see `Program::get_calls`
+#[derive(Debug)]
+pub struct Call {
+    /// Address of the instruction
+    pub ip: u64,
+    pub length: u8,
+    pub target: CallTarget,
+    /// Direct jump to another function, which then returns to the caller
+    pub tail_call: bool,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum CallTarget {
+    /// Direct call to `address`, continuing in the given mode. Only ARM's BLX
+    /// switches modes.
+    Address(u64, IsaMode),
+    /// Indirect call through a register, or memory at the register plus a
+    /// displacement
+    Register(String, Option<i64>),
+    /// Direct call whose target couldn't be computed
+    Unknown,
+}
+
+/// How a parameter (or return value) is passed under the x86-64 System V
+/// calling convention, from its DWARF type
+#[derive(Clone, Debug, PartialEq)]
+pub enum ValueType {
+    /// Signed or unsigned integer of `size` bytes, including `bool`, `char`
+    /// and enums
+    Integer { size: u64, signed: bool },
+    /// Pointer or reference
+    Pointer,
+    /// Pointer to `char`, assumed to be a NUL-terminated string
+    CString,
+    /// Struct of at most 16 bytes passed in integer registers, along with the
+    /// offset of each of its fields (which are all integers or pointers)
+    Struct {
+        size: u64,
+        fields: Vec<(u64, Parameter)>,
+    },
+    /// Passed in vector registers
+    Float,
+    /// Anything else of `size` bytes, e.g. unions or large structs. Passed on
+    /// the stack if over 16 bytes, otherwise assumed to be passed in integer
+    /// registers.
+    Other { size: u64 },
+    /// Unknown, e.g. types defined in other units or structs holding floating
+    /// point values
+    Unknown,
+}
+
+/// Parameter of a function or field of a struct, from debug info
+#[derive(Clone, Debug, PartialEq)]
+pub struct Parameter {
+    /// Empty if unnamed
+    pub name: String,
+    /// C-like name of its type, e.g. `const char*`
+    pub type_name: String,
+    pub value_type: ValueType,
 }
 
 #[derive(Clone, Debug)]
 pub struct SymbolInfo {
     pub name: FunctionName,
+    /// Only set when names are demangled up front, see `display_name`
     demangled_name: Option<String>,
     section_index: Option<object::SectionIndex>,
+    /// Start address of the function's code, see `IsaMode::from_symbol`
     address: u64,
     size: u64,
+    isa_mode: IsaMode,
+    /// Other symbols at the same address (e.g. weak aliases, or C1/C2
+    /// constructors that share code). Only set on the canonical symbol, see
+    /// `Program::address_to_name`.
+    pub aliases: Vec<FunctionName>,
+    /// Canonical symbol at the same address, if this is an alias of it
+    alias_of: Option<FunctionName>,
+    /// Signature from debug info, only looked up when the symbol is shown
+    /// as a callee, see `Program::get_signature`
+    signature: Option<String>,
+    /// Other overloads or template instantiations collapsed into this symbol
+    /// in search results, see `search::group_overloads`
+    pub overloads: Vec<SymbolInfo>,
+    /// Code of the function, `None` if it is not available (e.g. dynamically
+    /// linked symbols) or can't be decoded. See `num_calls`.
+    code: Option<FunctionCode>,
 }
 
-impl AsRef<str> for SymbolInfo {
-    fn as_ref(&self) -> &str {
-        match &self.demangled_name {
-            Some(dn) => &dn,
-            None => self.name.0,
-        }
+/// Code of a function, which is only decoded when needed
+#[derive(Clone, Copy)]
+struct FunctionCode {
+    code: &'static [u8],
+    /// See `instructions_big_endian`
+    big_endian: bool,
+}
+
+impl fmt::Debug for FunctionCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "<{} bytes>", self.code.len())
     }
 }
 
+lazy_static::lazy_static! {
+    /// Number of call instructions in functions whose calls were counted,
+    /// keyed by the address of their code in memory, see
+    /// `SymbolInfo::num_calls`
+    static ref NUM_CALLS: std::sync::Mutex<HashMap<usize, u32>> = Default::default();
+}
+
 impl fmt::Display for SymbolInfo {
     // This is used to display the symbol in search results
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -86,10 +321,234 @@ impl fmt::Display for SymbolInfo {
             // Undefined symbol
             fmt::Display::fmt("(D) ", f)?
         }
-        fmt::Display::fmt(self.as_ref(), f)
+        match &self.signature {
+            Some(signature) => fmt::Display::fmt(signature, f)?,
+            None => fmt::Display::fmt(&self.display_name(), f)?,
+        }
+        if let Some(num_calls) = self.num_calls() {
+            // Hints to help pick the meaty implementation over a trivial
+            // wrapper with a similar name
+            let calls = if num_calls == 1 { "call" } else { "calls" };
+            write!(f, "  [{} B, {} {}]", self.size, num_calls, calls)?;
+        }
+        if !self.aliases.is_empty() {
+            write!(f, "  (aliases: {})", self.aliases.iter().join(", "))?;
+        }
+        if !self.overloads.is_empty() {
+            write!(f, "  (+{} overloads)", self.overloads.len())?;
+        }
+        Ok(())
+    }
+}
+
+impl SymbolInfo {
+    /// Symbol of a function starting at `address`, not yet grouped with its
+    /// aliases (see `Program::address_to_name`)
+    fn new(
+        name: FunctionName,
+        demangled_name: Option<String>,
+        section_index: Option<object::SectionIndex>,
+        address: u64,
+        size: u64,
+        isa_mode: IsaMode,
+        code: Option<FunctionCode>,
+    ) -> SymbolInfo {
+        SymbolInfo {
+            name,
+            demangled_name,
+            section_index,
+            address,
+            size,
+            isa_mode,
+            aliases: Vec::new(),
+            alias_of: None,
+            signature: None,
+            overloads: Vec::new(),
+            code,
+        }
+    }
+
+    /// Number of call instructions in the function, used as a cheap complexity
+    /// metric. `None` if its code is not available. Only counted the first time
+    /// it is shown, decoding every function up front slows down loading large
+    /// programs.
+    pub fn num_calls(&self) -> Option<u32> {
+        let FunctionCode { code, big_endian } = self.code?;
+        let key = code.as_ptr() as usize;
+        if let Some(&num_calls) = NUM_CALLS.lock().unwrap().get(&key) {
+            return Some(num_calls);
+        }
+        let num_calls = find_calls(self.isa_mode, code, self.address, big_endian)
+            .iter()
+            .filter(|call| !call.tail_call)
+            .count() as u32;
+        NUM_CALLS.lock().unwrap().insert(key, num_calls);
+        Some(num_calls)
+    }
+
+    /// Demangled name if the symbol is mangled. With a memory budget names
+    /// are demangled on demand rather than stored, see `memory::set_budget`.
+    pub fn display_name(&self) -> Cow<str> {
+        match &self.demangled_name {
+            Some(dn) => Cow::Borrowed(dn),
+            None if memory::budget().is_some() => {
+                match memory::demangle_cached(self.name.unqualified().0, demangle) {
+                    Some(dn) => Cow::Owned(dn),
+                    None => Cow::Borrowed(self.name.unqualified().0),
+                }
+            }
+            None => Cow::Borrowed(self.name.unqualified().0),
+        }
+    }
+
+    /// Rough number of bytes used by the symbol
+    fn memory_usage(&self) -> usize {
+        std::mem::size_of::<SymbolInfo>()
+            + self.demangled_name.as_ref().map_or(0, |dn| dn.capacity())
+            + self.aliases.capacity() * std::mem::size_of::<FunctionName>()
+    }
+
+    /// This symbol followed by the overloads collapsed into it
+    pub fn expand_overloads(mut self) -> Vec<SymbolInfo> {
+        let overloads = std::mem::take(&mut self.overloads);
+        std::iter::once(self).chain(overloads).collect()
+    }
+}
+
+/// Call of a function that was inlined into another, as a span of code that
+/// can be traced like a call: from its first instruction to wherever control
+/// leaves it
+#[derive(Debug, Clone, PartialEq)]
+pub struct InlinedSpan {
+    /// Demangled name of the inlined function
+    pub name: String,
+    /// Location of the inlined call in the caller's source
+    pub call_file: String,
+    pub call_line: u32,
+    pub call_column: Option<u32>,
+    /// Offset of the first instruction, relative to the start of the caller
+    pub start: u32,
+    /// Offset just past the last instruction
+    pub end: u32,
+    /// Offsets at which control leaves the span: `end` and the targets of
+    /// jumps out of it within the caller
+    pub exits: Vec<u32>,
+}
+
+/// Span of code from one source line of a function to another, which can be
+/// traced like a call
+#[derive(Debug, Clone, PartialEq)]
+pub struct LineSpan {
+    /// Offset of the first instruction of the start line, relative to the
+    /// start of the function
+    pub start: u32,
+    /// Offset just past the last instruction of the end line
+    pub end: u32,
+    /// Offsets at which control leaves the span: `end` and the targets of
+    /// jumps out of it within the function
+    pub exits: Vec<u32>,
+}
+
+/// Loop in the code of a function, found from the jumps back to its first
+/// instruction
+#[derive(Debug, Clone, PartialEq)]
+pub struct Loop {
+    /// Offset of the first instruction of the loop, relative to the start of
+    /// the function. It is reached once per iteration.
+    pub head: u32,
+    /// Offsets of the jumps back to `head`
+    pub back_edges: Vec<u32>,
+}
+
+/// Instruction entered by the user, see `parse_code_location`
+#[derive(Debug, Clone, PartialEq)]
+pub enum CodeLocation {
+    /// Address in the program, as in its symbol table
+    Address(u64),
+    /// Offset within the function with the given mangled name, `None` for
+    /// the current function
+    Offset(Option<String>, u64),
+}
+
+/// Metadata on how the program was built
+pub struct BuildInfo {
+    pub architecture: object::Architecture,
+    pub endianness: object::Endianness,
+    /// Whether calls can be found in the program's code, see `can_find_calls`
+    pub can_decode: bool,
+    /// Contents of the `.comment` section, usually compiler versions
+    pub comments: Vec<String>,
+    /// DW_AT_producer of compilation units, along with the number of units
+    /// with that producer. For GCC these include the compiler flags.
+    pub producers: BTreeMap<String, usize>,
+}
+
+impl BuildInfo {
+    /// Distinct optimization flags (e.g. `-O2`) in producers. Empty if the
+    /// compiler doesn't record flags (e.g. clang without
+    /// `-grecord-command-line`).
+    pub fn optimization_levels(&self) -> Vec<&str> {
+        let mut levels: Vec<&str> = self
+            .producers
+            .keys()
+            .flat_map(|producer| producer.split_whitespace())
+            .filter(|flag| flag.starts_with("-O"))
+            .collect();
+        levels.sort_unstable();
+        levels.dedup();
+        levels
+    }
+}
+
+impl fmt::Display for BuildInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let endianness = match self.endianness {
+            object::Endianness::Little => "little",
+            object::Endianness::Big => "big",
+        };
+        writeln!(
+            f,
+            "Architecture: {:?} ({} endian)",
+            self.architecture, endianness
+        )?;
+        if !self.can_decode {
+            writeln!(
+                f,
+                "Callsite analysis: unavailable, instructions of this architecture can't be decoded, so calls are not shown and can't be traced or entered"
+            )?;
+        }
+        let levels = self.optimization_levels();
+        if levels.is_empty() {
+            writeln!(f, "Optimization: unknown (not recorded by compiler)")?;
+        } else {
+            writeln!(f, "Optimization: {}", levels.join(", "))?;
+        }
+        if !self.comments.is_empty() {
+            writeln!(f, "\nCompilers (.comment):")?;
+            for comment in &self.comments {
+                writeln!(f, "  {}", comment)?;
+            }
+        }
+        if !self.producers.is_empty() {
+            writeln!(f, "\nProducers (compilation units):")?;
+            for (producer, count) in &self.producers {
+                writeln!(f, "  {} ({})", producer, count)?;
+            }
+        }
+        Ok(())
     }
 }
 
+fn demangle(name: &str) -> Option<String> {
+    cplus_demangle::demangle(name)
+        .ok()
+        .or_else(|| match name.strip_prefix('_') {
+            // Mach-O symbols have an extra leading underscore
+            Some(name) if name.starts_with("_Z") => cplus_demangle::demangle(name).ok(),
+            _ => None,
+        })
+}
+
 fn should_log_verbose() -> bool {
     std::env::var("WACHY_PROGRAM_TRACE").unwrap_or(String::new()) == "1"
 }
@@ -97,72 +556,111 @@ fn should_log_verbose() -> bool {
 impl Program {
     pub fn new(file_path: String) -> Result<Self, Error> {
         let file = Program::parse(&file_path)?;
+        Program::from_file(file_path, file)
+    }
 
-        // TODO fixup unwraps
-        let dynamic_symbols_ranges = file
-            .sections()
-            .filter(|s| s.name().unwrap().starts_with(".plt")) // Include .plt and .plt.got
-            .map(|s| std::ops::Range {
-                start: s.address(),
-                end: s.address() + s.size(),
-            })
-            .collect();
+    /// Load the program parsed into `file`, which was read from `file_path`.
+    /// Damaged files load in a degraded mode with `load_warnings` rather than
+    /// failing where possible, which `fuzz/fuzz_targets/load.rs` checks.
+    pub fn from_file(file_path: String, file: File<'static>) -> Result<Self, Error> {
+        if file.format() == object::BinaryFormat::Pe {
+            return Program::new_pe(file_path, file);
+        }
+        if file.section_by_name(".debug_line").is_none() && gopclntab::find_data(&file).is_some() {
+            return Program::new_go(file_path, file);
+        }
+        let architecture = file.architecture();
+
+        let dynamic_symbols_ranges = Program::dynamic_symbols_ranges(&file);
 
         let mut versioned_symbols_map: HashMap<String, FunctionName> = HashMap::new();
+        let mut load_warnings = Vec::new();
 
         // Try to find file containing `.debug_line` section - if it's not in
-        // the passed in binary, check debuglink.
+        // the passed in binary, check debuglink. Without one, functions from
+        // the symbol table can still be browsed as disassembly.
         let debug_file;
         let debug_file_ref = match file.section_by_name(".debug_line") {
-            Some(_) => &file,
-            None => match Program::get_debug_file(&file, &file_path) {
+            Some(_) => Some(&file),
+            None => match Program::get_debug_file(&file, &file_path)
+                .or_else(|| Program::get_dsym_file(&file, &file_path))
+                .or_else(|| Program::get_debuginfod_file(&file))
+            {
+                None if !has_text_symbols(file.symbols())
+                    && !has_text_symbols(file.dynamic_symbols()) =>
+                {
+                    return Program::new_stripped(file_path, file)
+                }
                 None => {
-                    return Err(Error::from(format!(
-                        "Program {} is missing debug symbols (section .debug_line not found)",
+                    load_warnings.push(format!(
+                        "{} is missing debug info (section .debug_line not found), disassembly is shown instead of source",
                         file_path
-                    )))
+                    ));
+                    None
+                }
+                Some(Ok(df)) => {
+                    debug_file = df;
+                    Some(&debug_file)
+                }
+                Some(Err(err)) => {
+                    load_warnings.push(format!(
+                        "Failed to get debug file for {}, disassembly is shown instead of source: {}",
+                        file_path, err
+                    ));
+                    None
                 }
-                Some(r) => match r {
-                    Ok(df) => {
-                        debug_file = df;
-                        &debug_file
-                    }
-                    Err(err) => {
-                        return Err(Error::from(format!(
-                            "Failed to get debug file for program {}: {}",
-                            file_path, err
-                        )))
-                    }
-                },
             },
         };
 
-        let symbols_file = if file.has_debug_symbols() {
-            &file
+        let symbols_file = match debug_file_ref {
+            Some(debug_file_ref) if !file.has_debug_symbols() => {
+                log::info!("binary does not have debug symbols, using debug info file");
+                debug_file_ref
+            }
+            _ => &file,
+        };
+
+        // Shared libraries are usually stripped of their symbol table, but
+        // still name the functions they export in the dynamic one
+        let symbol_table = if has_text_symbols(symbols_file.symbols()) {
+            symbols_file.symbols()
         } else {
-            log::info!("binary does not have debug symbols, using debug info file");
-            debug_file_ref
+            file.dynamic_symbols()
         };
 
         // if binary contains symbols, use those - if not, get them from the debuginfo file
-        let symbols: Vec<SymbolInfo> = symbols_file
-            .symbols()
+        let mut symbols: Vec<(SymbolInfo, bool)> = symbol_table
             .filter(|symbol| symbol.kind() == object::SymbolKind::Text) // Filter to functions
             .map(|symbol| {
                 symbol.name().map(|name| {
-                    let demangled_name = cplus_demangle::demangle(name).ok();
+                    let (isa_mode, address) = IsaMode::from_symbol(architecture, symbol.address());
+                    // Demangling everything up front takes a lot of memory
+                    // for large C++ programs
+                    let demangled_name = match memory::budget() {
+                        Some(_) => None,
+                        None => demangle(name),
+                    };
                     let function = FunctionName(name);
                     if name.contains("@@") {
                         versioned_symbols_map
                             .insert(name.split("@@").next().unwrap().to_string(), function);
                     }
-                    SymbolInfo {
-                        name: function,
+                    let symbol_info = SymbolInfo::new(
+                        function,
                         demangled_name,
-                        section_index: symbol.section_index(),
-                        address: symbol.address(),
-                        size: symbol.size(),
-                    }
+                        symbol.section_index(),
+                        address,
+                        symbol.size(),
+                        isa_mode,
+                        function_code(
+                            &file,
+                            isa_mode,
+                            symbol.section_index(),
+                            address,
+                            symbol.size(),
+                        ),
+                    );
+                    (symbol_info, symbol.is_local())
                 })
             })
             .flat_map(|x| {
@@ -173,27 +671,448 @@ impl Program {
             })
             .collect();
 
+        // Local functions in different files can share a name, in which case
+        // only one of them would be found by name. Those are given unique
+        // names including their address instead, and probed by address.
+        let mut name_counts: HashMap<FunctionName, usize> = HashMap::new();
+        for (symbol, _) in &symbols {
+            *name_counts.entry(symbol.name).or_default() += 1;
+        }
+        for (symbol, is_local) in &mut symbols {
+            if *is_local && name_counts[&symbol.name] > 1 {
+                let name = format!("{}{}{:x}", symbol.name.0, ADDRESS_SEPARATOR, symbol.address);
+                symbol.name = FunctionName(Box::leak(name.into_boxed_str()));
+            }
+        }
+
         let dynamic_symbols_map = Program::dynamic_symbols_map(&file, &versioned_symbols_map);
 
-        let name_to_symbol: HashMap<_, _> = symbols.into_iter().map(|si| (si.name, si)).collect();
+        let name_to_symbol: HashMap<_, _> =
+            symbols.into_iter().map(|(si, _)| (si.name, si)).collect();
 
-        let address_to_name: HashMap<_, _> = name_to_symbol
-            .iter()
-            .filter(|(_, s)| s.address != 0)
-            .map(|(n, s)| (s.address, n.clone()))
-            .collect();
+        let lines = match debug_file_ref.map(new_context) {
+            Some(Ok(context)) => LineMapper::Dwarf(context),
+            Some(Err(err)) => {
+                load_warnings.push(format!(
+                    "Failed to load debug info, disassembly is shown instead of source: {}",
+                    err
+                ));
+                LineMapper::None
+            }
+            None => LineMapper::None,
+        };
 
-        let context = new_context(debug_file_ref).unwrap();
+        Ok(Program::from_parts(
+            file_path,
+            file,
+            name_to_symbol,
+            lines,
+            dynamic_symbols_ranges,
+            dynamic_symbols_map,
+            load_warnings,
+        ))
+    }
 
-        Ok(Program {
+    /// Program with the symbols and line mappings found by one of the loaders
+    /// above, and everything set up on demand left empty
+    fn from_parts(
+        file_path: String,
+        file: File<'static>,
+        mut name_to_symbol: HashMap<FunctionName, SymbolInfo>,
+        lines: LineMapper,
+        dynamic_symbols_ranges: Vec<std::ops::Range<u64>>,
+        dynamic_symbols_map: HashMap<u64, FunctionName>,
+        mut load_warnings: Vec<String>,
+    ) -> Program {
+        if !can_find_calls(&file) {
+            load_warnings.push(format!(
+                "Instructions of {:?} ({:?} endian) binaries can't be decoded, callsite analysis is unavailable: calls are not shown and can't be traced or entered",
+                file.architecture(),
+                file.endianness()
+            ));
+        }
+        for warning in &load_warnings {
+            log::warn!("{}", warning);
+        }
+        let address_to_name = Program::address_to_name(&mut name_to_symbol);
+        Program {
             file_path,
             file,
             name_to_symbol: Arc::new(name_to_symbol),
             address_to_name,
-            context,
+            lines,
             dynamic_symbols_ranges,
             dynamic_symbols_map,
-        })
+            decompiler: None,
+            source_map: SourceMap::default(),
+            git_source: None,
+            probe_return_instructions: false,
+            load_warnings,
+            libraries: Vec::new(),
+        }
+    }
+
+    /// PE binaries get symbols and line mappings from their PDB instead of
+    /// DWARF. They can only be browsed, not traced.
+    fn new_pe(file_path: String, file: File<'static>) -> Result<Self, Error> {
+        let pdb_info = PdbInfo::load(&file, &file_path)?;
+        let name_to_symbol: HashMap<_, _> = pdb_info
+            .functions()
+            .map(|(name, address, size)| {
+                let (isa_mode, address) = IsaMode::from_symbol(file.architecture(), address);
+                let section_index = file
+                    .sections()
+                    .find(|s| s.address() <= address && address - s.address() < s.size())
+                    .map(|s| s.index());
+                let symbol = SymbolInfo::new(
+                    FunctionName(name),
+                    // PDB names are already demangled
+                    None,
+                    section_index,
+                    address,
+                    size,
+                    isa_mode,
+                    function_code(&file, isa_mode, section_index, address, size),
+                );
+                (symbol.name, symbol)
+            })
+            .collect();
+
+        Ok(Program::from_parts(
+            file_path,
+            file,
+            name_to_symbol,
+            LineMapper::Pdb(pdb_info),
+            // Imports are called indirectly through the IAT, so show up as
+            // register calls
+            Vec::new(),
+            HashMap::new(),
+            Vec::new(),
+        ))
+    }
+
+    /// Go programs built without DWARF (e.g. with `-ldflags=-w`) get symbols
+    /// and line mappings from the pclntab instead
+    fn new_go(file_path: String, file: File<'static>) -> Result<Self, Error> {
+        let pclntab = GoPclntab::load(&file, &file_path)?;
+        let name_to_symbol: HashMap<_, _> = pclntab
+            .functions()
+            .map(|(name, address, size)| {
+                let (isa_mode, address) = IsaMode::from_symbol(file.architecture(), address);
+                let section_index = file
+                    .sections()
+                    .find(|s| s.address() <= address && address - s.address() < s.size())
+                    .map(|s| s.index());
+                let symbol = SymbolInfo::new(
+                    FunctionName(name),
+                    // Go symbols aren't mangled
+                    None,
+                    section_index,
+                    address,
+                    size,
+                    isa_mode,
+                    function_code(&file, isa_mode, section_index, address, size),
+                );
+                (symbol.name, symbol)
+            })
+            .collect();
+        let dynamic_symbols_ranges = Program::dynamic_symbols_ranges(&file);
+        let dynamic_symbols_map = Program::dynamic_symbols_map(&file, &HashMap::new());
+
+        Ok(Program::from_parts(
+            file_path,
+            file,
+            name_to_symbol,
+            LineMapper::Go(pclntab),
+            dynamic_symbols_ranges,
+            dynamic_symbols_map,
+            Vec::new(),
+        ))
+    }
+
+    /// Stripped binaries without a debug file get function boundaries from
+    /// the FDEs in `.eh_frame`, which is kept since it's needed for unwinding.
+    /// Functions are named after their address, and their disassembly is shown
+    /// in place of source.
+    fn new_stripped(file_path: String, file: File<'static>) -> Result<Self, Error> {
+        let functions = eh_frame_functions(&file)?;
+        if functions.is_empty() {
+            return Err(Error::from(format!(
+                "Program {} is missing debug symbols and has no symbol table or .eh_frame to find functions with",
+                file_path
+            )));
+        }
+        log::info!(
+            "{} is stripped, found {} functions in .eh_frame",
+            file_path,
+            functions.len()
+        );
+        let name_to_symbol: HashMap<_, _> = functions
+            .into_iter()
+            .filter_map(|(address, size)| {
+                let (isa_mode, address) = IsaMode::from_symbol(file.architecture(), address);
+                let section_index = file
+                    .sections()
+                    .find(|s| s.address() <= address && address - s.address() < s.size())
+                    .map(|s| s.index())?;
+                let name: &'static str =
+                    Box::leak(format!("{}{:x}", SYNTHESIZED_PREFIX, address).into_boxed_str());
+                let symbol = SymbolInfo::new(
+                    FunctionName(name),
+                    None,
+                    Some(section_index),
+                    address,
+                    size,
+                    isa_mode,
+                    function_code(&file, isa_mode, Some(section_index), address, size),
+                );
+                Some((symbol.name, symbol))
+            })
+            .collect();
+        let dynamic_symbols_ranges = Program::dynamic_symbols_ranges(&file);
+        let dynamic_symbols_map = Program::dynamic_symbols_map(&file, &HashMap::new());
+
+        Ok(Program::from_parts(
+            file_path,
+            file,
+            name_to_symbol,
+            LineMapper::None,
+            dynamic_symbols_ranges,
+            dynamic_symbols_map,
+            Vec::new(),
+        ))
+    }
+
+    /// Group symbols at the same address (e.g. weak aliases, or C1/C2
+    /// constructors that share code) under a canonical name for the address:
+    /// the one with the shortest demangled name, ties broken by mangled name.
+    /// Aliases can still be looked up by name, but are otherwise shown
+    /// through their canonical symbol.
+    fn address_to_name(
+        name_to_symbol: &mut HashMap<FunctionName, SymbolInfo>,
+    ) -> HashMap<u64, FunctionName> {
+        let mut address_to_names: HashMap<u64, Vec<FunctionName>> = HashMap::new();
+        for (name, symbol) in name_to_symbol.iter() {
+            if symbol.address != 0 {
+                address_to_names
+                    .entry(symbol.address)
+                    .or_default()
+                    .push(*name);
+            }
+        }
+        let mut address_to_name = HashMap::new();
+        for (address, mut names) in address_to_names {
+            names.sort_by_key(|name| (name_to_symbol[name].display_name().len(), name.0));
+            let canonical = names.remove(0);
+            for alias in &names {
+                name_to_symbol.get_mut(alias).unwrap().alias_of = Some(canonical);
+            }
+            name_to_symbol.get_mut(&canonical).unwrap().aliases = names;
+            address_to_name.insert(address, canonical);
+        }
+        address_to_name
+    }
+
+    pub fn set_decompiler(&mut self, decompiler: Decompiler) {
+        self.decompiler = Some(decompiler);
+    }
+
+    pub fn set_git_source(&mut self, git_source: GitSource) {
+        self.git_source = Some(git_source);
+    }
+
+    /// Trace returns of all functions by probing their return instructions,
+    /// for kernels without uretprobes
+    pub fn set_probe_return_instructions(&mut self) {
+        self.probe_return_instructions = true;
+    }
+
+    /// Contents of `source_file` at the revision the program was built from,
+    /// along with that revision. `None` if no git repository is set.
+    pub fn get_git_source(&self, source_file: &str) -> Option<(String, &str)> {
+        let git_source = self.git_source.as_ref()?;
+        Some((git_source.read(source_file)?, git_source.rev()))
+    }
+
+    /// Hex encoded build ID of the program, if it has one
+    pub fn get_build_id(&self) -> Option<String> {
+        let build_id = self.file.build_id().ok()??;
+        Some(build_id.iter().map(|b| format!("{:02x}", b)).collect())
+    }
+
+    /// Add a rule for finding sources locally, see `SourceMap::add`
+    pub fn add_source_map(&mut self, rule: &str) -> Result<(), Error> {
+        self.source_map.add(rule)
+    }
+
+    /// Remember that `source_file` was found at `local_path`, see
+    /// `SourceMap::add_located`
+    pub fn add_located_source(&mut self, source_file: &str, local_path: &str) {
+        self.source_map.add_located(source_file, local_path);
+    }
+
+    /// Where to read `source_file` (as recorded in debug info) from locally
+    pub fn local_source_path<'a>(&self, source_file: &'a str) -> Cow<'a, str> {
+        self.source_map.remap(source_file)
+    }
+
+    /// Pseudo-code for `function` (disassembly for stripped binaries), or
+    /// `None` if no decompiler is set or it hasn't been run on `function` yet
+    /// (see `decompile_job`)
+    pub fn decompile(&self, function: FunctionName) -> Option<Result<Decompiled, Error>> {
+        if let Some(library) = self.library_of(function) {
+            return library.decompile(function);
+        }
+        match &self.decompiler {
+            Some(decompiler) => decompiler
+                .get_decompiled(&self.file_path, self.get_address(function))
+                .map(Ok),
+            None if matches!(self.lines, LineMapper::None) => Some(self.disassemble(function)),
+            None => None,
+        }
+    }
+
+    /// Job running the decompiler on `function`, if it has to be before
+    /// `function` can be shown, i.e. it has no source information and wasn't
+    /// decompiled yet. It can take a while, so is meant to run in the
+    /// background.
+    pub fn decompile_job(
+        &self,
+        function: FunctionName,
+    ) -> Option<Box<dyn FnOnce() -> Result<(), Error> + Send>> {
+        if let Some(library) = self.library_of(function) {
+            return library.decompile_job(function);
+        }
+        let decompiler = self.decompiler.clone()?;
+        let address = self.get_address(function);
+        if self.get_location(address).is_some()
+            || decompiler
+                .get_decompiled(&self.file_path, address)
+                .is_some()
+        {
+            return None;
+        }
+        let program_path = self.file_path.clone();
+        let function = function.unqualified();
+        Some(Box::new(move || {
+            decompiler.decompile(&program_path, function, address)
+        }))
+    }
+
+    /// Disassembly of `function`, with each instruction marked with its
+    /// address like decompiler output
+    fn disassemble(&self, function: FunctionName) -> Result<Decompiled, Error> {
+        let (address, code) = self.get_decodable_data(function)?;
+        let decoder = create_decoder();
+        let formatter = Formatter::new(FormatterStyle::INTEL)
+            .map_err(|err| format!("Could not create zydis Formatter: {:?}", err))?;
+        let mut output = format!("{}:\n", function);
+        let mut buffer = [0u8; 200];
+        for (instruction, ip) in decoder.instruction_iterator(&code, address) {
+            let mut buffer = OutputBuffer::new(&mut buffer[..]);
+            formatter
+                .format_instruction(&instruction, &mut buffer, Some(ip), None)
+                .map_err(|err| format!("Failed to format instruction at {:#x}: {:?}", ip, err))?;
+            output += &format!("@{:x}\t  {:#x}:  {}\n", ip, ip, buffer);
+        }
+        Decompiled::write(&output, &format!("{:x}.s", address))
+    }
+
+    /// Instruction set `function` is encoded in
+    pub fn get_isa_mode(&self, function: FunctionName) -> IsaMode {
+        if let Some(library) = self.library_of(function) {
+            return library.get_isa_mode(function);
+        }
+        self.name_to_symbol
+            .get(&function)
+            .map_or(IsaMode::Unknown, |symbol| symbol.isa_mode)
+    }
+
+    /// Address and code of `function`, for analyses that decode all of its
+    /// instructions. Fails if they can't be, see `IsaMode::can_decode`.
+    fn get_decodable_data(
+        &self,
+        function: FunctionName,
+    ) -> Result<(u64, Cow<'static, [u8]>), Error> {
+        let isa_mode = self.get_isa_mode(function);
+        if !isa_mode.can_decode() {
+            return Err(format!(
+                "Cannot decode instructions of {} ({:?} code)",
+                function, isa_mode
+            )
+            .into());
+        }
+        self.get_data(function)
+    }
+
+    /// Calls and tail calls made by `function`, in order. Empty if they can't
+    /// be found, see `IsaMode::can_find_calls`.
+    pub fn get_calls(&self, function: FunctionName) -> Vec<Call> {
+        let isa_mode = self.get_isa_mode(function);
+        if !isa_mode.can_find_calls() {
+            return Vec::new();
+        }
+        match self.get_data(function) {
+            Ok((address, code)) => find_calls(
+                isa_mode,
+                &code,
+                address,
+                instructions_big_endian(&self.file),
+            ),
+            Err(_) => Vec::new(),
+        }
+    }
+
+    /// Whether this is a Go program. uretprobes can crash Go programs - they
+    /// replace the return address on the stack, and the Go runtime walks and
+    /// copies goroutine stacks (e.g. to grow them) - so returns are traced with
+    /// `get_return_offsets` instead.
+    pub fn is_go(&self) -> bool {
+        gopclntab::find_data(&self.file).is_some()
+    }
+
+    /// Offsets of return instructions in `function` if it should be traced by
+    /// probing those rather than with a uretprobe, see `is_go` and
+    /// `set_probe_return_instructions`
+    pub fn get_return_offsets(&self, function: FunctionName) -> Option<Vec<u32>> {
+        if let Some(library) = self.library_of(function) {
+            return library.get_return_offsets(function);
+        }
+        if !(self.is_go() || self.probe_return_instructions) {
+            return None;
+        }
+        let (address, code) = self.get_decodable_data(function).ok()?;
+        let decoder = create_decoder();
+        // Calls ending in a tail call return from the callee rather than
+        // through a return instruction of `function`, so they are considered
+        // to return at the jump. Otherwise the thread would never leave the
+        // frame.
+        let offsets: Vec<u32> =
+            get_instructions_with_mnemonic(&decoder, address, &code, Mnemonic::RET)
+                .map(|(_, ip)| (ip - address) as u32)
+                .chain(tail_call_offsets(&code, address))
+                .sorted()
+                .collect();
+        if offsets.is_empty() {
+            log::warn!(
+                "No return instructions found in {}, falling back to uretprobe",
+                function
+            );
+            return None;
+        }
+        Some(offsets)
+    }
+
+    /// Whether this is a shared library rather than an executable. PIE
+    /// executables are shared objects too, but have an interpreter.
+    pub fn is_shared_library(&self) -> bool {
+        self.file.kind() == object::ObjectKind::Dynamic
+            && self.file.section_by_name(".interp").is_none()
+    }
+
+    /// Whether this is a Windows binary, which can only be browsed
+    pub fn is_pe(&self) -> bool {
+        self.file.format() == object::BinaryFormat::Pe
     }
 
     fn parse(file_path: &String) -> Result<File<'static>, Error> {
@@ -210,6 +1129,7 @@ impl Program {
         // this mmap static or some other struct, and doing it here simplifies
         // LOTS of annotations.
         let mmap = Box::leak(Box::new(mmap));
+        memory::record_mapped(mmap.len());
 
         match object::File::parse(&**mmap) {
             Ok(file) => Ok(file),
@@ -217,6 +1137,16 @@ impl Program {
         }
     }
 
+    fn dynamic_symbols_ranges(file: &File<'static>) -> Vec<std::ops::Range<u64>> {
+        file.sections()
+            .filter(|s| s.name().map_or(false, is_stubs_section))
+            .map(|s| std::ops::Range {
+                start: s.address(),
+                end: s.address().saturating_add(s.size()),
+            })
+            .collect()
+    }
+
     // `versioned_symbols_map` is a map from unversioned symbol name to the
     // versioned one. The dynamic symbols section seems to contain unversioned
     // symbol names.
@@ -224,21 +1154,52 @@ impl Program {
         file: &File<'static>,
         versioned_symbols_map: &HashMap<String, FunctionName>,
     ) -> HashMap<u64, FunctionName> {
+        if !can_decode(file) {
+            // PLT entries can't be decoded
+            return HashMap::new();
+        }
         let mut relocations = HashMap::new();
-        let dynamic_symbols = file.dynamic_symbol_table().unwrap();
-        let reloc_iter = file.dynamic_relocations().unwrap();
+        // Mach-O binaries don't expose dynamic symbols/relocations this way,
+        // calls through `__stubs` are treated as unknown dynamic symbols.
+        let reloc_iter = match file.dynamic_relocations() {
+            Some(reloc_iter) => reloc_iter,
+            None => return HashMap::new(),
+        };
+        let dynamic_symbols = file.dynamic_symbol_table();
+        // GNU IFUNCs (e.g. memcpy in static binaries) are called through PLT
+        // entries with IRELATIVE relocations, which refer to the resolver by
+        // address rather than by symbol
+        let ifunc_resolvers = ifunc_resolvers(file);
         for (address, relocation) in reloc_iter {
-            if let object::RelocationTarget::Symbol(index) = relocation.target() {
-                let symbol = dynamic_symbols.symbol_by_index(index).unwrap();
-                if symbol.kind() == object::SymbolKind::Text {
-                    if let Ok(name) = symbol.name() {
-                        if should_log_verbose() {
-                            log::trace!("Relocation {:x} = {}", address, name);
-                        }
-                        relocations.insert(address, name);
+            let name = match (relocation.target(), &dynamic_symbols) {
+                (object::RelocationTarget::Symbol(index), Some(dynamic_symbols)) => {
+                    let symbol = match dynamic_symbols.symbol_by_index(index) {
+                        Ok(symbol) => symbol,
+                        Err(_) => continue,
+                    };
+                    if symbol.kind() != object::SymbolKind::Text {
+                        continue;
+                    }
+                    match symbol.name() {
+                        Ok(name) => name,
+                        Err(_) => continue,
                     }
                 }
+                (object::RelocationTarget::Absolute, _)
+                    if relocation.kind()
+                        == object::RelocationKind::Elf(object::elf::R_X86_64_IRELATIVE) =>
+                {
+                    match ifunc_resolvers.get(&(relocation.addend() as u64)) {
+                        Some(name) => *name,
+                        None => continue,
+                    }
+                }
+                _ => continue,
+            };
+            if should_log_verbose() {
+                log::trace!("Relocation {:x} = {}", address, name);
             }
+            relocations.insert(address, name);
         }
 
         let mut map = HashMap::new();
@@ -247,14 +1208,24 @@ impl Program {
             if let (Ok(name), address) = (section.name(), section.address()) {
                 // Include .plt and .plt.got
                 if name.starts_with(".plt") {
-                    let code = section.uncompressed_data().unwrap();
+                    let code = match section_data(file, &section) {
+                        Ok(code) => code,
+                        Err(err) => {
+                            log::warn!("Skipping dynamic symbols in {}: {}", name, err);
+                            continue;
+                        }
+                    };
                     for (instruction, ip) in
                         get_instructions_with_mnemonic(&decoder, address, &code, Mnemonic::JMP)
                     {
-                        assert!(instruction.operand_count > 0);
-                        let jump_address = instruction
-                            .calc_absolute_address(ip, &instruction.operands[0])
-                            .unwrap();
+                        if instruction.operand_count == 0 {
+                            continue;
+                        }
+                        let jump_address =
+                            match instruction.calc_absolute_address(ip, &instruction.operands[0]) {
+                                Ok(jump_address) => jump_address,
+                                Err(_) => continue,
+                            };
                         if should_log_verbose() {
                             log::trace!("PLT {:#x?} -> GOT {:#x?}", ip, jump_address);
                         }
@@ -277,107 +1248,219 @@ impl Program {
         map
     }
 
-    // If .gnu_debuglink not found, returns None, else valid file/error
+    /// Look for a separate debug file in the standard locations (same as gdb):
+    /// by build ID under `/usr/lib/debug/.build-id`, by `.gnu_debuglink` name
+    /// in the current directory, next to the binary (and in its `.debug`
+    /// directory) or its mirror under `/usr/lib/debug`, and finally as
+    /// `/usr/lib/debug/<binary path>.debug`. Returns None if none were found,
+    /// else valid file/error.
     fn get_debug_file(
         program_file: &File<'static>,
         program_file_path: &String,
     ) -> Option<Result<File<'static>, Error>> {
-        let debuglink_filename = match program_file.gnu_debuglink() {
-            Ok(link_opt) => match link_opt {
-                Some(link) => {
-                    let filename = std::str::from_utf8(link.0).unwrap().to_string();
-                    // TODO if file doesn't exist in cwd we should probably
-                    // check in original file_path's folder.
-                    let mut file = match std::fs::File::open(&filename) {
-                        Ok(file) => file,
-                        Err(err) => {
-                            return Some(Err(
-                                format!("Failed to open file {}: {}", filename, err).into()
-                            ))
-                        }
-                    };
+        let build_id = program_file.build_id().ok().flatten();
+        let path = std::path::Path::new(program_file_path);
+        let canonical_path = std::fs::canonicalize(path).ok();
 
-                    // Validate checksum
-                    let mut hash_fn = || {
-                        const READ_SIZE: usize = 1 << 20; // 1 MB
-                        let mut buf = vec![0; READ_SIZE];
-                        let mut hasher = crc32fast::Hasher::new();
-                        loop {
-                            match file.read(&mut buf) {
-                                Ok(0) => break,
-                                Ok(n) => hasher.update(&buf[0..n]),
-                                Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
-                                Err(e) => {
-                                    return Err(Error::from(format!(
-                                        "Failed to read file {}: {}",
-                                        filename, e
-                                    )))
-                                }
-                            }
-                        }
-                        Ok(hasher.finalize())
-                    };
+        if let Some(build_id) = build_id.filter(|id| id.len() >= 2) {
+            let hex: String = build_id.iter().map(|b| format!("{:02x}", b)).collect();
+            let filename = format!("{}/.build-id/{}/{}.debug", DEBUG_DIR, &hex[..2], &hex[2..]);
+            if std::path::Path::new(&filename).is_file() {
+                return Some(Program::parse_debug_file(filename));
+            }
+        }
 
-                    match hash_fn() {
-                        Ok(hash) => {
-                            if hash != link.1 {
-                                log::info!(
-                                    "Expected hash {:x}, but actual debug file {} has hash {:x}",
-                                    link.1,
-                                    filename,
-                                    hash
-                                );
-                                return Some(Err(format!(
-                                    "Debug file {} does not correspond to {} (CRC mismatch)",
-                                    filename, program_file_path
-                                )
-                                .into()));
-                            }
-                        }
-                        Err(err) => return Some(Err(err)),
-                    }
-                    // TODO should we be checking BuildID too?
-                    filename
-                }
-                None => return None,
-            },
+        let debuglink = match program_file.gnu_debuglink() {
+            Ok(debuglink) => debuglink,
             Err(err) => return Some(Err(format!("Failed to get .gnu_debuglink: {}", err).into())),
         };
-        let df = Program::parse(&debuglink_filename);
-        if df.is_ok() {
-            log::info!(
-                "Using debug file {} for address to line mappings",
-                debuglink_filename
+        if let Some(link) = debuglink {
+            let name = match std::str::from_utf8(link.0) {
+                Ok(name) => name,
+                Err(_) => return Some(Err("Invalid .gnu_debuglink file name".into())),
+            };
+            let mut candidates = vec![std::path::PathBuf::from(name)];
+            if let Some(dir) = canonical_path.as_ref().and_then(|p| p.parent()) {
+                candidates.push(dir.join(name));
+                candidates.push(dir.join(".debug").join(name));
+                candidates.push(
+                    std::path::Path::new(DEBUG_DIR)
+                        .join(dir.strip_prefix("/").unwrap_or(dir))
+                        .join(name),
+                );
+            }
+            if let Some(candidate) = candidates.iter().find(|c| c.is_file()) {
+                let filename = candidate.to_string_lossy().into_owned();
+                if let Err(err) =
+                    Program::check_debug_file_crc(&filename, link.1, program_file_path)
+                {
+                    return Some(Err(err));
+                }
+                return Some(Program::parse_debug_file(filename));
+            }
+            log::info!("Debug file {} not found in any of {:?}", name, candidates);
+        }
+
+        let canonical_path = canonical_path?;
+        let filename = format!("{}{}.debug", DEBUG_DIR, canonical_path.display());
+        if !std::path::Path::new(&filename).is_file() {
+            return None;
+        }
+        let df = Program::parse_debug_file(filename.clone());
+        if let Ok(df) = &df {
+            if build_id.is_some() && df.build_id().ok().flatten() != build_id {
+                return Some(Err(format!(
+                    "Debug file {} does not correspond to {} (build ID mismatch)",
+                    filename, program_file_path
+                )
+                .into()));
+            }
+        }
+        Some(df)
+    }
+
+    fn parse_debug_file(filename: String) -> Result<File<'static>, Error> {
+        let df = Program::parse(&filename);
+        if df.is_ok() {
+            log::info!("Using debug file {} for address to line mappings", filename);
+        }
+        df
+    }
+
+    /// Validate `filename` against the checksum recorded in the program's
+    /// `.gnu_debuglink`
+    fn check_debug_file_crc(
+        filename: &str,
+        expected: u32,
+        program_file_path: &str,
+    ) -> Result<(), Error> {
+        let mut file = match std::fs::File::open(filename) {
+            Ok(file) => file,
+            Err(err) => return Err(format!("Failed to open file {}: {}", filename, err).into()),
+        };
+        const READ_SIZE: usize = 1 << 20; // 1 MB
+        let mut buf = vec![0; READ_SIZE];
+        let mut hasher = crc32fast::Hasher::new();
+        loop {
+            match file.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => hasher.update(&buf[0..n]),
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => {
+                    return Err(Error::from(format!(
+                        "Failed to read file {}: {}",
+                        filename, e
+                    )))
+                }
+            }
+        }
+        let hash = hasher.finalize();
+        if hash != expected {
+            log::info!(
+                "Expected hash {:x}, but actual debug file {} has hash {:x}",
+                expected,
+                filename,
+                hash
             );
+            return Err(format!(
+                "Debug file {} does not correspond to {} (CRC mismatch)",
+                filename, program_file_path
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    /// On macOS debug info is kept in a separate dSYM bundle next to the
+    /// binary. Returns None if `program_file` is not Mach-O or there is no
+    /// bundle.
+    fn get_dsym_file(
+        program_file: &File<'static>,
+        program_file_path: &String,
+    ) -> Option<Result<File<'static>, Error>> {
+        if program_file.format() != object::BinaryFormat::MachO {
+            return None;
+        }
+        let path = std::path::Path::new(program_file_path);
+        let dsym_path = format!(
+            "{}.dSYM/Contents/Resources/DWARF/{}",
+            program_file_path,
+            path.file_name()?.to_string_lossy()
+        );
+        if !std::path::Path::new(&dsym_path).exists() {
+            return None;
+        }
+        let df = Program::parse(&dsym_path);
+        if df.is_ok() {
+            log::info!("Using dSYM file {} for address to line mappings", dsym_path);
+        }
+        Some(df)
+    }
+
+    /// Debug info downloaded from debuginfod servers, see
+    /// `debuginfod::fetch_debuginfo`
+    fn get_debuginfod_file(program_file: &File<'static>) -> Option<Result<File<'static>, Error>> {
+        let build_id = program_file.build_id().ok()??;
+        let path = debuginfod::fetch_debuginfo(build_id)?;
+        let path = path.to_string_lossy().into_owned();
+        let df = Program::parse(&path);
+        if df.is_ok() {
+            log::info!("Using debug file {} from debuginfod", path);
         }
         Some(df)
     }
 
+    /// Local copy of `source_file` of `function` if it was already downloaded
+    /// from debuginfod servers, see `get_debuginfod_build_id`
+    pub fn get_debuginfod_source(
+        &self,
+        function: FunctionName,
+        source_file: &str,
+    ) -> Option<String> {
+        let build_id = self.get_debuginfod_build_id(function)?;
+        let path = debuginfod::cached_source(&build_id, source_file)?;
+        Some(path.to_string_lossy().into_owned())
+    }
+
+    /// Build id of the binary `function` is in, to download its sources from
+    /// debuginfod servers with
+    pub fn get_debuginfod_build_id(&self, function: FunctionName) -> Option<Vec<u8>> {
+        if let Some(library) = self.library_of(function) {
+            return library.get_debuginfod_build_id(function);
+        }
+        Some(self.file.build_id().ok()??.to_vec())
+    }
+
     pub fn get_address(&self, function: FunctionName) -> u64 {
+        if let Some(library) = self.library_of(function) {
+            return library.get_address(function);
+        }
         self.name_to_symbol.get(&function).unwrap().address
     }
 
     /// If something is returned, it is guaranteed to have file and line number
     /// set.
     pub fn get_location(&self, address: u64) -> Option<Location> {
-        match self.context.find_location(address) {
-            Ok(l) => match l {
-                Some(l) => {
-                    l.file?;
-                    l.line?;
-                    Some(l)
-                }
-                None => None,
-            },
-            Err(_) => None,
-        }
+        let l = match &self.lines {
+            LineMapper::Dwarf(context) => context.find_location(address).ok()??,
+            LineMapper::Pdb(pdb_info) => pdb_info.find_location(address)?,
+            LineMapper::Go(pclntab) => pclntab.find_location(address)?,
+            LineMapper::None => return None,
+        };
+        l.file?;
+        l.line?;
+        Some(l)
     }
 
     #[allow(dead_code)]
     fn print_frames(&self, address: u64) {
+        let context = match &self.lines {
+            LineMapper::Dwarf(context) => context,
+            LineMapper::Pdb(_) | LineMapper::Go(_) | LineMapper::None => return,
+        };
         log::info!(
             "{:#?}",
-            self.context
+            context
                 .find_frames(address)
                 .unwrap()
                 .collect::<Vec<addr2line::Frame<_>>>()
@@ -389,7 +1472,10 @@ impl Program {
     }
 
     // Returns (address, data) for given function
-    pub fn get_data(&self, function: FunctionName) -> Result<(u64, &[u8]), Error> {
+    pub fn get_data(&self, function: FunctionName) -> Result<(u64, Cow<'static, [u8]>), Error> {
+        if let Some(library) = self.library_of(function) {
+            return library.get_data(function);
+        }
         let symbol = &self.name_to_symbol.get(&function).unwrap();
         let address = symbol.address;
         if address == 0 {
@@ -397,23 +1483,481 @@ impl Program {
                 format!("Cannot get data for dynamically linked symbol {}", function).into(),
             );
         }
-        let size = symbol.size;
-        let index = symbol.section_index.unwrap();
-        Ok((
-            address,
-            self.file
-                .section_by_index(index)
-                .unwrap()
-                .data_range(address, size)
-                .unwrap()
-                .unwrap(),
-        ))
+        let section = symbol
+            .section_index
+            .and_then(|index| self.file.section_by_index(index).ok())
+            .ok_or_else(|| format!("Failed to find section containing {}", function))?;
+        let code = section_data_range(&self.file, &section, address, symbol.size)
+            .ok_or_else(|| format!("Failed to read code of {}", function))?;
+        Ok((address, code))
+    }
+
+    /// If `function` is a trivial wrapper - its body is just a single call or
+    /// tail call to another function, like a forwarding shim - returns the
+    /// function it calls.
+    pub fn get_wrapped_function(&self, function: FunctionName) -> Option<FunctionName> {
+        if let Some(library) = self.library_of(function) {
+            return library.get_wrapped_function(function);
+        }
+        let (address, code) = self.get_decodable_data(function).ok()?;
+        let end_address = address + code.len() as u64;
+        let decoder = create_decoder();
+        let mut callee = None;
+        for (i, (instruction, ip)) in decoder.instruction_iterator(&code, address).enumerate() {
+            if i >= MAX_WRAPPER_INSTRUCTIONS {
+                return None;
+            }
+            if instruction.mnemonic != Mnemonic::CALL && instruction.mnemonic != Mnemonic::JMP {
+                continue;
+            }
+            // Indirect calls can't be followed
+            let target = instruction
+                .calc_absolute_address(ip, &instruction.operands[0])
+                .ok()?;
+            if instruction.mnemonic == Mnemonic::JMP && (address..end_address).contains(&target) {
+                // Regular control flow within the function
+                continue;
+            }
+            if callee.is_some() {
+                return None;
+            }
+            callee = Some(*self.address_to_name.get(&target)?);
+        }
+        callee.filter(|&callee| callee != function)
+    }
+
+    /// Functions inlined directly into `function`, according to
+    /// DW_TAG_inlined_subroutine entries. Each contiguous range of an inlined
+    /// call is a separate span.
+    pub fn get_inlined_spans(&self, function: FunctionName) -> Vec<InlinedSpan> {
+        if let Some(library) = self.library_of(function) {
+            return library.get_inlined_spans(function);
+        }
+        let context = match &self.lines {
+            LineMapper::Dwarf(context) => context,
+            LineMapper::Pdb(_) | LineMapper::Go(_) | LineMapper::None => return Vec::new(),
+        };
+        let (address, code) = match self.get_decodable_data(function) {
+            Ok(data) => data,
+            Err(_) => return Vec::new(),
+        };
+        let decoder = create_decoder();
+        let mut spans: Vec<InlinedSpan> = Vec::new();
+        // (offset of jump, offset of target) of jumps within the function
+        let mut jumps = Vec::new();
+        for (instruction, ip) in decoder.instruction_iterator(&code, address) {
+            let offset = (ip - address) as u32;
+            let next_offset = offset + instruction.length as u32;
+            if JUMP_MNEMONICS.contains(&instruction.mnemonic) {
+                if let Ok(target) = instruction.calc_absolute_address(ip, &instruction.operands[0])
+                {
+                    if (address..address + code.len() as u64).contains(&target) {
+                        jumps.push((offset, (target - address) as u32));
+                    }
+                }
+            }
+
+            let frames: Vec<addr2line::Frame<_>> = match context.find_frames(ip) {
+                Ok(frames) => frames.collect().unwrap_or_default(),
+                Err(_) => continue,
+            };
+            // Frames are innermost first, the last is `function` itself and
+            // its location is that of the call inlined directly into it
+            let (inlined, caller) = match frames.as_slice() {
+                [.., inlined, caller] => (inlined, caller),
+                _ => continue,
+            };
+            let name = match inlined.function.as_ref().and_then(|f| f.raw_name().ok()) {
+                Some(name) => demangle(&name).unwrap_or_else(|| name.into_owned()),
+                None => continue,
+            };
+            let (call_file, call_line) = match &caller.location {
+                Some(addr2line::Location {
+                    file: Some(file),
+                    line: Some(line),
+                    ..
+                }) => (*file, *line),
+                _ => continue,
+            };
+            let call_column = caller.location.as_ref().and_then(|l| l.column);
+            match spans.last_mut() {
+                Some(span)
+                    if span.end == offset
+                        && span.name == name
+                        && span.call_file == call_file
+                        && span.call_line == call_line
+                        && span.call_column == call_column =>
+                {
+                    span.end = next_offset;
+                }
+                _ => spans.push(InlinedSpan {
+                    name,
+                    call_file: call_file.to_string(),
+                    call_line,
+                    call_column,
+                    start: offset,
+                    end: next_offset,
+                    exits: Vec::new(),
+                }),
+            }
+        }
+        for span in &mut spans {
+            span.exits = span_exits(span.start, span.end, &jumps);
+        }
+        spans
+    }
+
+    /// Span of `function` from the first instruction of `start_line` to the
+    /// last one of `end_line`, both lines of `source_file`. Instructions of the
+    /// lines are usually interleaved with others by optimization, so the span
+    /// also covers whatever the compiler placed between them.
+    pub fn get_line_span(
+        &self,
+        function: FunctionName,
+        source_file: &str,
+        start_line: u32,
+        end_line: u32,
+    ) -> Result<LineSpan, Error> {
+        if let Some(library) = self.library_of(function) {
+            return library.get_line_span(function, source_file, start_line, end_line);
+        }
+        let (address, code) = self.get_decodable_data(function)?;
+        let decoder = create_decoder();
+        let mut start = None;
+        let mut end = None;
+        for (instruction, ip) in decoder.instruction_iterator(&code, address) {
+            let offset = (ip - address) as u32;
+            let line = self
+                .get_location(ip)
+                .filter(|l| l.file.unwrap() == source_file)
+                .and_then(|l| l.line);
+            if line == Some(start_line) {
+                start = Some(start.map_or(offset, |start: u32| start.min(offset)));
+            }
+            if line == Some(end_line) {
+                end = Some(offset + instruction.length as u32);
+            }
+        }
+        let start =
+            start.ok_or_else(|| format!("No instructions found for line {}", start_line))?;
+        let end = end.ok_or_else(|| format!("No instructions found for line {}", end_line))?;
+        if end <= start {
+            return Err(format!(
+                "Code of line {} comes before that of line {}",
+                end_line, start_line
+            )
+            .into());
+        }
+        Ok(LineSpan {
+            start,
+            end,
+            exits: span_exits(start, end, &internal_jumps(&code, address)),
+        })
+    }
+
+    /// Function containing `location` and the offset of it within the
+    /// function. `current` is the function an offset without one refers to.
+    pub fn resolve_code_location(
+        &self,
+        location: &CodeLocation,
+        current: FunctionName,
+    ) -> Result<(FunctionName, u32), Error> {
+        let (function, offset) = match location {
+            CodeLocation::Address(address) => {
+                let function = self
+                    .get_function_containing(*address)
+                    .ok_or_else(|| format!("No function contains address {:#x}", address))?;
+                (function, address - self.get_address(function))
+            }
+            CodeLocation::Offset(None, offset) => (current, *offset),
+            CodeLocation::Offset(Some(name), offset) => {
+                let symbol = self
+                    .find_symbol(name)
+                    .ok_or_else(|| format!("Function {} not found", name))?;
+                if *offset >= symbol.size {
+                    return Err(format!(
+                        "Offset {:#x} is past the end of {}, which is {:#x} bytes long",
+                        offset, name, symbol.size
+                    )
+                    .into());
+                }
+                (symbol.name, *offset)
+            }
+        };
+        Ok((function, offset as u32))
+    }
+
+    /// Length of the instruction at `offset` in `function`. Fails if no
+    /// instruction starts there, since a probe placed in the middle of one
+    /// would corrupt it.
+    pub fn get_instruction_length(
+        &self,
+        function: FunctionName,
+        offset: u32,
+    ) -> Result<u32, Error> {
+        if let Some(library) = self.library_of(function) {
+            return library.get_instruction_length(function, offset);
+        }
+        let (address, code) = self.get_decodable_data(function)?;
+        let decoder = create_decoder();
+        for (instruction, ip) in decoder.instruction_iterator(&code, address) {
+            let instruction_offset = (ip - address) as u32;
+            let next_offset = instruction_offset + instruction.length as u32;
+            if instruction_offset == offset {
+                return Ok(instruction.length as u32);
+            }
+            if next_offset > offset {
+                return Err(format!(
+                    "Offset {:#x} is in the middle of the instruction at {:#x}",
+                    offset, instruction_offset
+                )
+                .into());
+            }
+        }
+        Err(format!("Offset {:#x} is past the end of {}", offset, function).into())
+    }
+
+    /// Loops in `function`, ordered by the offset of their head. Empty if its
+    /// instructions can't be decoded.
+    pub fn get_loops(&self, function: FunctionName) -> Vec<Loop> {
+        if let Some(library) = self.library_of(function) {
+            return library.get_loops(function);
+        }
+        match self.get_decodable_data(function) {
+            Ok((address, code)) => find_loops(&internal_jumps(&code, address)),
+            Err(_) => Vec::new(),
+        }
+    }
+
+    /// Reason why `source_file` (read from `local_path`) may not be what
+    /// `function` was built from, if any. Uses the MD5 checksum in debug info when available (DWARF 5),
+    /// otherwise checks if the source was modified after the program.
+    pub fn check_source(
+        &self,
+        function: FunctionName,
+        source_file: &str,
+        local_path: &str,
+    ) -> Option<String> {
+        if let Some(library) = self.library_of(function) {
+            return library.check_source(function, source_file, local_path);
+        }
+        // Missing sources are handled separately
+        let contents = std::fs::read(local_path).ok()?;
+        if let Some(md5) = self.get_source_md5(self.get_address(function), source_file) {
+            if md5::compute(&contents).0 != md5 {
+                return Some("checksum differs from debug info".to_string());
+            }
+            return None;
+        }
+        let modified = |path: &str| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+        if modified(local_path)? > modified(&self.file_path)? {
+            Some("modified after the binary was built".to_string())
+        } else {
+            None
+        }
+    }
+
+    /// MD5 checksum of `source_file` recorded in the line table of the unit
+    /// containing `address`
+    fn get_source_md5(&self, address: u64, source_file: &str) -> Option<[u8; 16]> {
+        let (_, unit, index) = self.find_file_entry(address, source_file)?;
+        let header = unit.line_program.as_ref()?.header();
+        if !header.file_has_md5() {
+            return None;
+        }
+        Some(*header.file_names()[index].md5())
+    }
+
+    /// Source text of `source_file` embedded in debug info (with clang's
+    /// `-gembed-source`), for when it isn't available locally
+    pub fn get_embedded_source(&self, function: FunctionName, source_file: &str) -> Option<String> {
+        if let Some(library) = self.library_of(function) {
+            return library.get_embedded_source(function, source_file);
+        }
+        let (dwarf, unit, index) = self.find_file_entry(self.get_address(function), source_file)?;
+        match embedded_source::get_embedded_sources(dwarf, unit) {
+            Ok(mut sources) if index < sources.len() => sources.swap_remove(index),
+            Ok(_) => None,
+            Err(err) => {
+                log::warn!("Failed to read embedded source: {}", err);
+                None
+            }
+        }
+    }
+
+    /// Find `source_file` in the line table of the unit containing `address`,
+    /// returning the unit and index of the file within its line table
+    fn find_file_entry(
+        &self,
+        address: u64,
+        source_file: &str,
+    ) -> Option<(&gimli::Dwarf<DwarfReader>, &gimli::Unit<DwarfReader>, usize)> {
+        use gimli::Reader;
+        let context = match &self.lines {
+            LineMapper::Dwarf(context) => context,
+            LineMapper::Pdb(_) | LineMapper::Go(_) | LineMapper::None => return None,
+        };
+        let unit = context.find_dwarf_unit(address)?;
+        let header = unit.line_program.as_ref()?.header();
+        let to_string = |value| -> Option<String> {
+            let value = context.dwarf().attr_string(unit, value).ok()?;
+            Some(value.to_string_lossy().ok()?.into_owned())
+        };
+        let source_file = std::path::Path::new(source_file);
+        let index = header.file_names().iter().position(|entry| {
+            // Same as how addr2line builds paths, pushing an absolute path
+            // replaces everything before it
+            let mut path = std::path::PathBuf::new();
+            if let Some(comp_dir) = &unit.comp_dir {
+                if let Ok(comp_dir) = comp_dir.to_string_lossy() {
+                    path.push(&*comp_dir);
+                }
+            }
+            if let Some(directory) = entry.directory(header).and_then(to_string) {
+                path.push(directory);
+            }
+            match to_string(entry.path_name()) {
+                Some(name) => path.push(name),
+                None => return false,
+            }
+            path == source_file
+        })?;
+        Some((context.dwarf(), unit, index))
+    }
+
+    /// Compiler and flags used to build the program. This reads every
+    /// compilation unit, so is only computed on demand.
+    pub fn get_build_info(&self) -> BuildInfo {
+        let comments = self
+            .file
+            .section_by_name(".comment")
+            .and_then(|section| section_data(&self.file, &section).ok())
+            .map(|data| {
+                data.split(|&b| b == 0)
+                    .filter(|comment| !comment.is_empty())
+                    .map(|comment| String::from_utf8_lossy(comment).into_owned())
+                    .collect()
+            })
+            .unwrap_or_default();
+        let producers = match &self.lines {
+            LineMapper::Dwarf(context) => get_producers(context.dwarf()).unwrap_or_else(|err| {
+                log::warn!("Failed to read DW_AT_producer: {}", err);
+                BTreeMap::new()
+            }),
+            LineMapper::Pdb(_) | LineMapper::Go(_) | LineMapper::None => BTreeMap::new(),
+        };
+        BuildInfo {
+            architecture: self.file.architecture(),
+            endianness: self.file.endianness(),
+            can_decode: can_find_calls(&self.file),
+            comments,
+            producers,
+        }
     }
 
     pub fn get_symbol(&self, function: FunctionName) -> Option<&SymbolInfo> {
+        if let Some(library) = self.library_of(function) {
+            return library.get_symbol(function);
+        }
         self.name_to_symbol.get(&function)
     }
 
+    /// Functions called `name`, either exactly (mangled or demangled) or
+    /// ignoring parameters, template arguments and enclosing scopes. Several
+    /// match if the function is overloaded or a template.
+    pub fn get_matches(&self, name: &str) -> Vec<FunctionName> {
+        let scoped_name = format!("::{}", name);
+        let mut matches: Vec<FunctionName> = self
+            .symbols_generator()
+            .into_iter()
+            .filter(|s| {
+                let display_name = s.display_name();
+                let base = base_name(&display_name);
+                s.name.0 == name
+                    || display_name == name
+                    || base == name
+                    || base.ends_with(&scoped_name)
+            })
+            .map(|s| s.name)
+            .collect();
+        matches.sort_by_key(|f| f.0);
+        matches
+    }
+
+    /// Symbol of `function`, displayed with its signature if it is known
+    pub fn get_symbol_with_signature(&self, function: FunctionName) -> Option<SymbolInfo> {
+        let mut symbol = self.get_symbol(function)?.clone();
+        symbol.signature = self.get_signature(function);
+        Some(symbol)
+    }
+
+    /// Full signature of `function` (e.g. `int foo(const char* name, int
+    /// flags)`), from the parameters and return type recorded in its
+    /// `DW_TAG_subprogram`
+    pub fn get_signature(&self, function: FunctionName) -> Option<String> {
+        if let Some(library) = self.library_of(function) {
+            return library.get_signature(function);
+        }
+        let context = match &self.lines {
+            LineMapper::Dwarf(context) => context,
+            LineMapper::Pdb(_) | LineMapper::Go(_) | LineMapper::None => return None,
+        };
+        let address = self.get_address(function);
+        let unit = context.find_dwarf_unit(address)?;
+        let (return_type, parameters) = match get_subprogram_types(context.dwarf(), unit, address) {
+            Ok(types) => types?,
+            Err(err) => {
+                log::warn!("Failed to read signature of {}: {}", function, err);
+                return None;
+            }
+        };
+        let (name, qualifiers) = split_parameters(&function.to_string());
+        Some(format!(
+            "{} {}({}){}",
+            return_type,
+            name,
+            parameters.join(", "),
+            qualifiers
+        ))
+    }
+
+    /// Return type (`None` if `void`) and parameters of `function`, to capture
+    /// its arguments. Unlike `get_signature`, implicit parameters like `this`
+    /// are included, as they take up registers too.
+    pub fn get_parameters(
+        &self,
+        function: FunctionName,
+    ) -> Option<(Option<ValueType>, Vec<Parameter>)> {
+        if let Some(library) = self.library_of(function) {
+            return library.get_parameters(function);
+        }
+        let context = match &self.lines {
+            LineMapper::Dwarf(context) => context,
+            LineMapper::Pdb(_) | LineMapper::Go(_) | LineMapper::None => return None,
+        };
+        let address = self.get_address(function);
+        let unit = context.find_dwarf_unit(address)?;
+        match get_subprogram_parameters(context.dwarf(), unit, address) {
+            Ok(parameters) => parameters,
+            Err(err) => {
+                log::warn!("Failed to read parameters of {}: {}", function, err);
+                None
+            }
+        }
+    }
+
+    /// Symbols of the functions defined in the program, sorted by name
+    pub fn defined_functions(&self) -> Vec<SymbolInfo> {
+        let mut functions: Vec<SymbolInfo> = self
+            .name_to_symbol
+            .values()
+            .filter(|symbol| symbol.address != 0)
+            .cloned()
+            .collect();
+        functions.sort_by_key(|symbol| symbol.name);
+        functions
+    }
+
     pub fn symbols_generator(&self) -> SymbolsGenerator {
         SymbolsGenerator {
             name_to_symbol: Arc::clone(&self.name_to_symbol),
@@ -428,6 +1972,101 @@ impl Program {
         }
     }
 
+    /// Function whose code contains `address`
+    pub fn get_function_containing(&self, address: u64) -> Option<FunctionName> {
+        self.name_to_symbol
+            .values()
+            .filter(|s| s.alias_of.is_none())
+            .find(|s| s.address != 0 && s.address <= address && address - s.address < s.size)
+            .map(|s| s.name)
+    }
+
+    /// Functions a C++ virtual call through `displacement` (e.g. `call
+    /// [rax+0x10]`) may call: the function at that offset in each vtable of
+    /// the program, with how many vtables have it there. Only the primary
+    /// vtable of each class is considered, secondary ones of classes with
+    /// multiple bases aren't. Functions in most vtables come first.
+    pub fn get_virtual_call_candidates(&self, displacement: i64) -> Vec<(FunctionName, usize)> {
+        if !self.file.is_64() || displacement < 0 || displacement % 8 != 0 {
+            return Vec::new();
+        }
+        // `_ZTV<class>` vtables start with the offset to the top of the object
+        // and the address of its typeinfo, objects point just past them
+        let slot_offset = 16 + displacement as u64;
+        let slots: Vec<u64> = self
+            .file
+            .symbols()
+            .chain(self.file.dynamic_symbols())
+            .filter(|symbol| {
+                symbol.kind() == object::SymbolKind::Data
+                    && symbol.size() >= slot_offset + 8
+                    && symbol.name().map_or(false, |name| name.starts_with("_ZTV"))
+            })
+            .map(|symbol| symbol.address() + slot_offset)
+            .unique()
+            .collect();
+        if slots.is_empty() {
+            return Vec::new();
+        }
+
+        // Vtables of position independent code are filled in by relocations
+        let dynamic_symbols = self.file.dynamic_symbol_table();
+        let mut relocated = HashMap::new();
+        for (address, relocation) in self.file.dynamic_relocations().into_iter().flatten() {
+            let function = match (relocation.target(), &dynamic_symbols) {
+                (object::RelocationTarget::Symbol(index), Some(dynamic_symbols)) => {
+                    match dynamic_symbols
+                        .symbol_by_index(index)
+                        .and_then(|symbol| symbol.name())
+                    {
+                        Ok(name) => self.find_symbol(name).map(|symbol| symbol.name),
+                        Err(_) => continue,
+                    }
+                }
+                (object::RelocationTarget::Absolute, _)
+                    if relocation.kind()
+                        == object::RelocationKind::Elf(object::elf::R_X86_64_RELATIVE)
+                        || relocation.kind()
+                            == object::RelocationKind::Elf(object::elf::R_AARCH64_RELATIVE) =>
+                {
+                    self.get_function_for_address(relocation.addend() as u64)
+                }
+                _ => continue,
+            };
+            relocated.insert(address, function);
+        }
+        let read_slot = |address: u64| -> Option<FunctionName> {
+            if let Some(function) = relocated.get(&address) {
+                return *function;
+            }
+            let section = self.file.sections().find(|section| {
+                section.address() <= address && address - section.address() < section.size()
+            })?;
+            let data = section_data_range(&self.file, &section, address, 8)?;
+            let pointer = <[u8; 8]>::try_from(&*data).ok()?;
+            let pointer = if self.file.is_little_endian() {
+                u64::from_le_bytes(pointer)
+            } else {
+                u64::from_be_bytes(pointer)
+            };
+            self.get_function_for_address(pointer)
+        };
+
+        let mut counts = HashMap::<FunctionName, usize>::new();
+        for function in slots.into_iter().filter_map(read_slot) {
+            // Placeholders of abstract and deleted functions
+            let name = function.unqualified().0;
+            if name.starts_with("__cxa_pure_virtual") || name.starts_with("__cxa_deleted_virtual") {
+                continue;
+            }
+            *counts.entry(function).or_default() += 1;
+        }
+        counts
+            .into_iter()
+            .sorted_by_key(|&(function, count)| (std::cmp::Reverse(count), function.0))
+            .collect()
+    }
+
     pub fn is_dynamic_symbol_address(&self, address: u64) -> bool {
         self.dynamic_symbols_ranges
             .iter()
@@ -435,8 +2074,1032 @@ impl Program {
     }
 
     pub fn is_dynamic_symbol(&self, symbol: &SymbolInfo) -> bool {
-        self.is_dynamic_symbol_address(symbol.address)
+        self.object(symbol.name)
+            .is_dynamic_symbol_address(symbol.address)
+    }
+
+    /// Program defining `function`, i.e. the shared library it is qualified
+    /// with if any. Addresses of its functions are relative to that object.
+    pub fn object(&self, function: FunctionName) -> &Program {
+        self.library_of(function).unwrap_or(self)
+    }
+
+    fn library_of(&self, function: FunctionName) -> Option<&Program> {
+        let path = function.object()?;
+        self.libraries
+            .iter()
+            .find(|library| library.file_path == path)
+    }
+
+    /// Load the shared library at `path`, if it isn't loaded yet. Names of
+    /// its functions are qualified with its path (see `FunctionName::object`)
+    /// so they can't be confused with those of the program.
+    pub fn load_library(&mut self, path: &str) -> Result<&Program, Error> {
+        let index = match self
+            .libraries
+            .iter()
+            .position(|library| library.file_path == path)
+        {
+            Some(index) => index,
+            None => {
+                let mut library = Program::new(path.to_string())?;
+                library.qualify_names();
+                library.decompiler = self.decompiler.clone();
+                library.probe_return_instructions = self.probe_return_instructions;
+                log::info!("Loaded shared library {}", path);
+                self.libraries.push(library);
+                self.libraries.len() - 1
+            }
+        };
+        Ok(&self.libraries[index])
+    }
+
+    /// Symbol of the dynamic symbol `function` in the shared library defining
+    /// it, out of `libraries` (the paths of the libraries the program links),
+    /// which is loaded if needed
+    pub fn load_dynamic_symbol(
+        &mut self,
+        function: FunctionName,
+        libraries: &[String],
+    ) -> Result<SymbolInfo, Error> {
+        // Names of dynamic symbols may be versioned, e.g. `memcpy@@GLIBC_2.14`
+        // (or `memcpy@GLIBC_2.2.5` for an older version). The version picks
+        // the library when several define the name.
+        let (name, version) = match function.0.split_once('@') {
+            Some((name, version)) => (name, Some(version.trim_start_matches('@'))),
+            None => (function.0, None),
+        };
+        if let Some(symbol) = self
+            .libraries
+            .iter()
+            .filter(|library| defines_version(&library.file, version))
+            .find_map(|library| library.find_exported(name))
+        {
+            return Ok(symbol.clone());
+        }
+        let path = libraries
+            .iter()
+            .find(|path| exports_function(path, name, version))
+            .ok_or_else(|| {
+                format!(
+                    "{} is not defined by any library {} links",
+                    name, self.file_path
+                )
+            })?;
+        self.load_library(path)?
+            .find_exported(name)
+            .cloned()
+            .ok_or_else(|| format!("Function {} not found in {}", name, path).into())
+    }
+
+    /// Names of the shared libraries the program and the loaded libraries
+    /// need, according to their `DT_NEEDED` entries
+    pub fn needed_libraries(&self) -> Vec<String> {
+        let mut needed = needed_libraries(&self.file);
+        for library in &self.libraries {
+            needed.extend(needed_libraries(&library.file));
+        }
+        needed.into_iter().unique().collect()
+    }
+
+    /// Symbol of the function with the mangled, possibly qualified, `name`
+    /// in the program or a loaded shared library
+    pub fn find_symbol(&self, name: &str) -> Option<&SymbolInfo> {
+        let object = match name.split_once(OBJECT_SEPARATOR) {
+            Some((path, _)) => self
+                .libraries
+                .iter()
+                .find(|library| library.file_path == path)?,
+            None => self,
+        };
+        object
+            .name_to_symbol
+            .values()
+            .find(|symbol| symbol.name.0 == name && symbol.alias_of.is_none())
+    }
+
+    /// Symbol of the function this library exports as `name`
+    fn find_exported(&self, name: &str) -> Option<&SymbolInfo> {
+        let symbol = self.name_to_symbol.values().find(|symbol| {
+            symbol.address != 0 && symbol.name.unqualified().0.split('@').next() == Some(name)
+        })?;
+        match symbol.alias_of {
+            Some(canonical) => self.name_to_symbol.get(&canonical),
+            None => Some(symbol),
+        }
+    }
+
+    /// Qualify the names of this shared library's functions with its path
+    fn qualify_names(&mut self) {
+        let path = self.file_path.clone();
+        let qualified: HashMap<FunctionName, FunctionName> = self
+            .name_to_symbol
+            .keys()
+            .map(|&name| {
+                let qualified = format!("{}{}{}", path, OBJECT_SEPARATOR, name.0);
+                (name, FunctionName(Box::leak(qualified.into_boxed_str())))
+            })
+            .collect();
+        let name_to_symbol = self
+            .name_to_symbol
+            .values()
+            .map(|symbol| {
+                let mut symbol = symbol.clone();
+                symbol.name = qualified[&symbol.name];
+                symbol.aliases = symbol
+                    .aliases
+                    .iter()
+                    .map(|alias| qualified[alias])
+                    .collect();
+                symbol.alias_of = symbol.alias_of.map(|canonical| qualified[&canonical]);
+                (symbol.name, symbol)
+            })
+            .collect();
+        self.name_to_symbol = Arc::new(name_to_symbol);
+        for name in self.address_to_name.values_mut() {
+            *name = qualified[name];
+        }
+    }
+
+    /// Estimated bytes used by symbol data, by kind
+    pub fn memory_usage(&self) -> Vec<(&'static str, usize)> {
+        let entry = std::mem::size_of::<(FunctionName, u64)>();
+        vec![
+            ("Mapped files (resident as used)", memory::mapped_bytes()),
+            (
+                "Symbols",
+                self.name_to_symbol
+                    .values()
+                    .map(SymbolInfo::memory_usage)
+                    .sum::<usize>()
+                    + (self.address_to_name.capacity() + self.dynamic_symbols_map.capacity())
+                        * entry,
+            ),
+            ("Demangled name cache", memory::demangle_cache_bytes()),
+        ]
+    }
+
+    /// Problems with the file that were worked around when loading it
+    pub fn get_load_warnings(&self) -> &[String] {
+        &self.load_warnings
+    }
+}
+
+fn get_producers(
+    dwarf: &gimli::Dwarf<DwarfReader>,
+) -> Result<BTreeMap<String, usize>, gimli::Error> {
+    use gimli::Reader;
+    let mut producers = BTreeMap::new();
+    let mut headers = dwarf.units();
+    while let Some(header) = headers.next()? {
+        let unit = dwarf.unit(header)?;
+        let mut entries = unit.entries();
+        // The first entry is the compilation unit itself
+        if let Some((_, entry)) = entries.next_dfs()? {
+            if let Some(value) = entry.attr_value(gimli::DW_AT_producer)? {
+                let producer = dwarf.attr_string(&unit, value)?;
+                *producers
+                    .entry(producer.to_string_lossy()?.into_owned())
+                    .or_insert(0) += 1;
+            }
+        }
+    }
+    Ok(producers)
+}
+
+/// Concrete `DW_TAG_subprogram` starting at `address`
+fn find_subprogram(
+    dwarf: &gimli::Dwarf<DwarfReader>,
+    unit: &gimli::Unit<DwarfReader>,
+    address: u64,
+) -> Result<Option<gimli::UnitOffset>, gimli::Error> {
+    let mut entries = unit.entries();
+    while let Some((_, entry)) = entries.next_dfs()? {
+        if entry.tag() != gimli::DW_TAG_subprogram {
+            continue;
+        }
+        match entry.attr_value(gimli::DW_AT_low_pc)? {
+            Some(low_pc) if dwarf.attr_address(unit, low_pc)? == Some(address) => {
+                return Ok(Some(entry.offset()))
+            }
+            _ => continue,
+        }
+    }
+    Ok(None)
+}
+
+/// Types of the concrete `DW_TAG_subprogram` starting at `address`, as its
+/// return type and its parameters along with their names
+fn get_subprogram_types(
+    dwarf: &gimli::Dwarf<DwarfReader>,
+    unit: &gimli::Unit<DwarfReader>,
+    address: u64,
+) -> Result<Option<(String, Vec<String>)>, gimli::Error> {
+    let offset = match find_subprogram(dwarf, unit, address)? {
+        Some(offset) => offset,
+        None => return Ok(None),
+    };
+    let entry = unit.entry(offset)?;
+    let return_type = match get_origin_attr(unit, &entry, gimli::DW_AT_type)? {
+        Some(value) => get_type_name(dwarf, unit, value, 0)?,
+        None => "void".to_string(),
+    };
+    let mut parameters = Vec::new();
+    let mut tree = unit.entries_tree(Some(offset))?;
+    let mut children = tree.root()?.children();
+    while let Some(child) = children.next()? {
+        let parameter = child.entry();
+        match parameter.tag() {
+            gimli::DW_TAG_formal_parameter => (),
+            gimli::DW_TAG_unspecified_parameters => {
+                parameters.push("...".to_string());
+                continue;
+            }
+            _ => continue,
+        }
+        // Skip implicit parameters like `this`
+        if let Some(gimli::AttributeValue::Flag(true)) =
+            get_origin_attr(unit, parameter, gimli::DW_AT_artificial)?
+        {
+            continue;
+        }
+        let type_name = match get_origin_attr(unit, parameter, gimli::DW_AT_type)? {
+            Some(value) => get_type_name(dwarf, unit, value, 0)?,
+            None => "?".to_string(),
+        };
+        parameters.push(match get_origin_attr(unit, parameter, gimli::DW_AT_name)? {
+            Some(name) => format!("{} {}", type_name, get_string(dwarf, unit, name)?),
+            None => type_name,
+        });
+    }
+    Ok(Some((return_type, parameters)))
+}
+
+/// Return type (`None` if `void`) and parameters of the concrete
+/// `DW_TAG_subprogram` starting at `address`, including implicit ones like
+/// `this`. Parameters after `...` are unknown, so it ends them.
+fn get_subprogram_parameters(
+    dwarf: &gimli::Dwarf<DwarfReader>,
+    unit: &gimli::Unit<DwarfReader>,
+    address: u64,
+) -> Result<Option<(Option<ValueType>, Vec<Parameter>)>, gimli::Error> {
+    let offset = match find_subprogram(dwarf, unit, address)? {
+        Some(offset) => offset,
+        None => return Ok(None),
+    };
+    let entry = unit.entry(offset)?;
+    let return_type = match get_origin_attr(unit, &entry, gimli::DW_AT_type)? {
+        Some(value) => Some(get_value_type(dwarf, unit, value, 0)?),
+        None => None,
+    };
+    let mut parameters = Vec::new();
+    let mut tree = unit.entries_tree(Some(offset))?;
+    let mut children = tree.root()?.children();
+    while let Some(child) = children.next()? {
+        let parameter = child.entry();
+        match parameter.tag() {
+            gimli::DW_TAG_formal_parameter => (),
+            gimli::DW_TAG_unspecified_parameters => break,
+            _ => continue,
+        }
+        parameters.push(get_parameter(
+            dwarf,
+            unit,
+            get_origin_attr(unit, parameter, gimli::DW_AT_name)?,
+            get_origin_attr(unit, parameter, gimli::DW_AT_type)?,
+            0,
+        )?);
+    }
+    Ok(Some((return_type, parameters)))
+}
+
+/// Parameter or struct field named `name` (if any) of type `value`
+fn get_parameter(
+    dwarf: &gimli::Dwarf<DwarfReader>,
+    unit: &gimli::Unit<DwarfReader>,
+    name: Option<gimli::AttributeValue<DwarfReader>>,
+    value: Option<gimli::AttributeValue<DwarfReader>>,
+    depth: usize,
+) -> Result<Parameter, gimli::Error> {
+    let name = match name {
+        Some(name) => get_string(dwarf, unit, name)?,
+        None => String::new(),
+    };
+    Ok(match value {
+        Some(value) => Parameter {
+            name,
+            type_name: get_type_name(dwarf, unit, value.clone(), depth)?,
+            value_type: get_value_type(dwarf, unit, value, depth)?,
+        },
+        None => Parameter {
+            name,
+            type_name: "?".to_string(),
+            value_type: ValueType::Unknown,
+        },
+    })
+}
+
+/// How a value of the type referenced by `value` is passed
+fn get_value_type(
+    dwarf: &gimli::Dwarf<DwarfReader>,
+    unit: &gimli::Unit<DwarfReader>,
+    value: gimli::AttributeValue<DwarfReader>,
+    depth: usize,
+) -> Result<ValueType, gimli::Error> {
+    let offset = match value {
+        gimli::AttributeValue::UnitRef(offset) => offset,
+        _ => return Ok(ValueType::Unknown),
+    };
+    if depth > MAX_TYPE_DEPTH {
+        return Ok(ValueType::Unknown);
+    }
+    let entry = unit.entry(offset)?;
+    let size = entry
+        .attr_value(gimli::DW_AT_byte_size)?
+        .and_then(|size| size.udata_value());
+    let inner = entry.attr_value(gimli::DW_AT_type)?;
+    Ok(match entry.tag() {
+        gimli::DW_TAG_typedef
+        | gimli::DW_TAG_const_type
+        | gimli::DW_TAG_volatile_type
+        | gimli::DW_TAG_atomic_type
+        | gimli::DW_TAG_restrict_type => match inner {
+            Some(inner) => get_value_type(dwarf, unit, inner, depth + 1)?,
+            None => ValueType::Unknown,
+        },
+        gimli::DW_TAG_pointer_type => {
+            let pointee = match inner {
+                Some(inner) => get_type_name(dwarf, unit, inner, depth + 1)?,
+                None => "void".to_string(),
+            };
+            match pointee.as_str() {
+                "char" | "const char" => ValueType::CString,
+                _ => ValueType::Pointer,
+            }
+        }
+        gimli::DW_TAG_reference_type | gimli::DW_TAG_rvalue_reference_type => ValueType::Pointer,
+        gimli::DW_TAG_base_type => {
+            let size = match size {
+                Some(size) => size,
+                None => return Ok(ValueType::Unknown),
+            };
+            match entry.attr_value(gimli::DW_AT_encoding)? {
+                Some(gimli::AttributeValue::Encoding(
+                    gimli::DW_ATE_float | gimli::DW_ATE_complex_float,
+                )) => ValueType::Float,
+                _ if size > 8 => ValueType::Other { size },
+                Some(gimli::AttributeValue::Encoding(
+                    gimli::DW_ATE_signed | gimli::DW_ATE_signed_char,
+                )) => ValueType::Integer { size, signed: true },
+                Some(gimli::AttributeValue::Encoding(
+                    gimli::DW_ATE_unsigned
+                    | gimli::DW_ATE_unsigned_char
+                    | gimli::DW_ATE_boolean
+                    | gimli::DW_ATE_UTF,
+                )) => ValueType::Integer {
+                    size,
+                    signed: false,
+                },
+                _ => ValueType::Unknown,
+            }
+        }
+        gimli::DW_TAG_enumeration_type => match (inner, size) {
+            // Underlying type, if it is known
+            (Some(inner), _) => get_value_type(dwarf, unit, inner, depth + 1)?,
+            (None, Some(size)) => ValueType::Integer { size, signed: true },
+            (None, None) => ValueType::Unknown,
+        },
+        gimli::DW_TAG_structure_type | gimli::DW_TAG_class_type | gimli::DW_TAG_union_type => {
+            match size {
+                // Declarations only
+                None => ValueType::Unknown,
+                // Passed on the stack
+                Some(size) if size > 16 => ValueType::Other { size },
+                // Assumed not to hold floating point values
+                Some(size) if entry.tag() == gimli::DW_TAG_union_type => ValueType::Other { size },
+                Some(size) => get_struct_type(dwarf, unit, offset, size, depth)?,
+            }
+        }
+        _ => ValueType::Unknown,
+    })
+}
+
+/// How a struct of at most 16 bytes at `offset` is passed, see
+/// `ValueType::Struct`
+fn get_struct_type(
+    dwarf: &gimli::Dwarf<DwarfReader>,
+    unit: &gimli::Unit<DwarfReader>,
+    offset: gimli::UnitOffset,
+    size: u64,
+    depth: usize,
+) -> Result<ValueType, gimli::Error> {
+    let mut fields = Vec::new();
+    let mut tree = unit.entries_tree(Some(offset))?;
+    let mut children = tree.root()?.children();
+    while let Some(child) = children.next()? {
+        let member = child.entry();
+        if member.tag() != gimli::DW_TAG_member {
+            continue;
+        }
+        // Static members are only declared in the struct
+        if let Some(gimli::AttributeValue::Flag(true)) =
+            member.attr_value(gimli::DW_AT_declaration)?
+        {
+            continue;
+        }
+        if member.attr_value(gimli::DW_AT_bit_size)?.is_some() {
+            return Ok(ValueType::Other { size });
+        }
+        let field_offset = match member.attr_value(gimli::DW_AT_data_member_location)? {
+            Some(location) => match location.udata_value() {
+                Some(field_offset) => field_offset,
+                None => return Ok(ValueType::Other { size }),
+            },
+            None => 0,
+        };
+        let field = get_parameter(
+            dwarf,
+            unit,
+            member.attr_value(gimli::DW_AT_name)?,
+            member.attr_value(gimli::DW_AT_type)?,
+            depth + 1,
+        )?;
+        match field.value_type {
+            ValueType::Integer { .. } | ValueType::Pointer | ValueType::CString => {
+                fields.push((field_offset, field))
+            }
+            // Passed partly in vector registers
+            ValueType::Float | ValueType::Unknown => return Ok(ValueType::Unknown),
+            ValueType::Struct { .. } | ValueType::Other { .. } => {
+                return Ok(ValueType::Other { size })
+            }
+        }
+    }
+    if fields.is_empty() {
+        return Ok(ValueType::Other { size });
+    }
+    Ok(ValueType::Struct { size, fields })
+}
+
+/// Value of `attr` of `entry`, or of the entry it is a concrete instance or
+/// definition of. Declarations of C++ methods and inlined functions only have
+/// their name and types in the abstract entry.
+fn get_origin_attr(
+    unit: &gimli::Unit<DwarfReader>,
+    entry: &gimli::DebuggingInformationEntry<DwarfReader>,
+    attr: gimli::DwAt,
+) -> Result<Option<gimli::AttributeValue<DwarfReader>>, gimli::Error> {
+    if let Some(value) = entry.attr_value(attr)? {
+        return Ok(Some(value));
+    }
+    for origin in [gimli::DW_AT_abstract_origin, gimli::DW_AT_specification] {
+        if let Some(gimli::AttributeValue::UnitRef(offset)) = entry.attr_value(origin)? {
+            return get_origin_attr(unit, &unit.entry(offset)?, attr);
+        }
+    }
+    Ok(None)
+}
+
+/// Types nested deeper than this (e.g. pointers to pointers to ...) are
+/// abbreviated
+const MAX_TYPE_DEPTH: usize = 8;
+
+/// C-like name of the type referenced by `value`, e.g. `const char*`
+fn get_type_name(
+    dwarf: &gimli::Dwarf<DwarfReader>,
+    unit: &gimli::Unit<DwarfReader>,
+    value: gimli::AttributeValue<DwarfReader>,
+    depth: usize,
+) -> Result<String, gimli::Error> {
+    let offset = match value {
+        gimli::AttributeValue::UnitRef(offset) => offset,
+        // Types in other units (e.g. with LTO or type units) aren't looked up
+        _ => return Ok("?".to_string()),
+    };
+    if depth > MAX_TYPE_DEPTH {
+        return Ok("...".to_string());
+    }
+    let entry = unit.entry(offset)?;
+    let inner = match entry.attr_value(gimli::DW_AT_type)? {
+        Some(value) => get_type_name(dwarf, unit, value, depth + 1)?,
+        None => "void".to_string(),
+    };
+    Ok(match entry.tag() {
+        gimli::DW_TAG_pointer_type => format!("{}*", inner),
+        gimli::DW_TAG_reference_type => format!("{}&", inner),
+        gimli::DW_TAG_rvalue_reference_type => format!("{}&&", inner),
+        gimli::DW_TAG_const_type => format!("const {}", inner),
+        gimli::DW_TAG_volatile_type => format!("volatile {}", inner),
+        gimli::DW_TAG_atomic_type => format!("_Atomic {}", inner),
+        gimli::DW_TAG_restrict_type => inner,
+        gimli::DW_TAG_array_type => format!("{}[]", inner),
+        gimli::DW_TAG_subroutine_type => format!("{}(...)", inner),
+        _ => match entry.attr_value(gimli::DW_AT_name)? {
+            Some(name) => get_string(dwarf, unit, name)?,
+            None => "?".to_string(),
+        },
+    })
+}
+
+fn get_string(
+    dwarf: &gimli::Dwarf<DwarfReader>,
+    unit: &gimli::Unit<DwarfReader>,
+    value: gimli::AttributeValue<DwarfReader>,
+) -> Result<String, gimli::Error> {
+    use gimli::Reader;
+    Ok(dwarf
+        .attr_string(unit, value)?
+        .to_string_lossy()?
+        .into_owned())
+}
+
+/// Split a demangled name into the part before its parameter list and the
+/// qualifiers after it, e.g. `ns::Foo::bar(int) const` into `ns::Foo::bar`
+/// and ` const`. Names without parameters are returned whole.
+fn split_parameters(name: &str) -> (&str, &str) {
+    let close = match name.rfind(')') {
+        Some(close) => close,
+        None => return (name, ""),
+    };
+    let mut depth = 0;
+    for (i, c) in name[..=close].char_indices().rev() {
+        match c {
+            ')' => depth += 1,
+            '(' => {
+                depth -= 1;
+                if depth == 0 {
+                    return (&name[..i], &name[close + 1..]);
+                }
+            }
+            _ => (),
+        }
+    }
+    (name, "")
+}
+
+/// Qualified name of a demangled function without its parameters, template
+/// arguments or return type, e.g. `ns::foo` for `int ns::foo<int>(int)`
+pub fn base_name(name: &str) -> &str {
+    let (name, _) = split_parameters(name);
+    let name = match name.strip_suffix('>') {
+        Some(stripped) => {
+            let mut depth = 1;
+            let open = stripped.char_indices().rev().find(|&(_, c)| {
+                match c {
+                    '>' => depth += 1,
+                    '<' => depth -= 1,
+                    _ => (),
+                }
+                depth == 0
+            });
+            match open {
+                Some((i, _)) => &name[..i],
+                None => name,
+            }
+        }
+        None => name,
+    };
+    // Return types are only separated by a space outside of template
+    // arguments
+    let mut depth = 0;
+    for (i, c) in name.char_indices().rev() {
+        match c {
+            '>' => depth += 1,
+            '<' => depth -= 1,
+            ' ' if depth == 0 => return &name[i + 1..],
+            _ => (),
+        }
+    }
+    name
+}
+
+/// Conditional and unconditional jumps, which may leave an inlined span
+const JUMP_MNEMONICS: &[Mnemonic] = &[
+    Mnemonic::JMP,
+    Mnemonic::JB,
+    Mnemonic::JBE,
+    Mnemonic::JCXZ,
+    Mnemonic::JECXZ,
+    Mnemonic::JL,
+    Mnemonic::JLE,
+    Mnemonic::JNB,
+    Mnemonic::JNBE,
+    Mnemonic::JNL,
+    Mnemonic::JNLE,
+    Mnemonic::JNO,
+    Mnemonic::JNP,
+    Mnemonic::JNS,
+    Mnemonic::JNZ,
+    Mnemonic::JO,
+    Mnemonic::JP,
+    Mnemonic::JRCXZ,
+    Mnemonic::JS,
+    Mnemonic::JZ,
+];
+
+/// Parse an instruction entered as `function+offset`, `+offset` for the
+/// current function or a raw address. Numbers are decimal, or hex with a `0x`
+/// prefix.
+pub fn parse_code_location(input: &str) -> Result<CodeLocation, Error> {
+    let parse_number = |number: &str| -> Result<u64, Error> {
+        let number = number.trim();
+        let parsed = match number.strip_prefix("0x") {
+            Some(hex) => u64::from_str_radix(hex, 16),
+            None => number.parse::<u64>(),
+        };
+        parsed.map_err(|_| format!("Invalid number '{}'", number).into())
+    };
+    let input = input.trim();
+    match input.rsplit_once('+') {
+        Some((function, offset)) => {
+            let function = function.trim();
+            let function = if function.is_empty() {
+                None
+            } else {
+                Some(function.to_string())
+            };
+            Ok(CodeLocation::Offset(function, parse_number(offset)?))
+        }
+        None => Ok(CodeLocation::Address(parse_number(input)?)),
+    }
+}
+
+/// (offset of jump, offset of target) of the jumps in `code`, which starts at
+/// `address`, that stay within it
+fn internal_jumps(code: &[u8], address: u64) -> Vec<(u32, u32)> {
+    let decoder = create_decoder();
+    let code_range = address..address + code.len() as u64;
+    decoder
+        .instruction_iterator(code, address)
+        .filter(|(instruction, _)| JUMP_MNEMONICS.contains(&instruction.mnemonic))
+        .filter_map(|(instruction, ip)| {
+            let target = instruction
+                .calc_absolute_address(ip, &instruction.operands[0])
+                .ok()?;
+            if !code_range.contains(&target) {
+                return None;
+            }
+            Some(((ip - address) as u32, (target - address) as u32))
+        })
+        .collect()
+}
+
+/// Offsets of the direct jumps in `code`, which starts at `address`, to
+/// somewhere outside of it, i.e. its tail calls
+fn tail_call_offsets(code: &[u8], address: u64) -> Vec<u32> {
+    let decoder = create_decoder();
+    let code_range = address..address + code.len() as u64;
+    get_instructions_with_mnemonic(&decoder, address, code, Mnemonic::JMP)
+        .filter(|(instruction, ip)| {
+            let operand = &instruction.operands[0];
+            operand.reg == Register::NONE
+                && operand.mem.base == Register::NONE
+                && operand.mem.index == Register::NONE
+                && instruction
+                    .calc_absolute_address(*ip, operand)
+                    .map_or(false, |target| !code_range.contains(&target))
+        })
+        .map(|(_, ip)| (ip - address) as u32)
+        .collect()
+}
+
+/// Loops formed by the jumps backwards among the (source, target) offsets of
+/// `jumps`. Each target of such a jump is the head of a loop.
+fn find_loops(jumps: &[(u32, u32)]) -> Vec<Loop> {
+    let mut loops: BTreeMap<u32, Vec<u32>> = BTreeMap::new();
+    for &(source, target) in jumps {
+        if target <= source {
+            loops.entry(target).or_default().push(source);
+        }
+    }
+    loops
+        .into_iter()
+        .map(|(head, mut back_edges)| {
+            back_edges.sort_unstable();
+            Loop { head, back_edges }
+        })
+        .collect()
+}
+
+/// Offsets at which control leaves the span from `start` to `end`, given the
+/// (source, target) offsets of jumps in the function
+fn span_exits(start: u32, end: u32, jumps: &[(u32, u32)]) -> Vec<u32> {
+    let span = start..end;
+    let mut exits: Vec<u32> = jumps
+        .iter()
+        .filter(|(source, target)| span.contains(source) && !span.contains(target))
+        .map(|(_, target)| *target)
+        .chain(std::iter::once(end))
+        .collect();
+    exits.sort_unstable();
+    exits.dedup();
+    exits
+}
+
+/// Functions with more instructions than this are never considered trivial
+/// wrappers
+const MAX_WRAPPER_INSTRUCTIONS: usize = 16;
+
+/// Code of the function at `address`, if its calls can be found. Code is always
+/// read from the program itself, since sections in a separate debug file have
+/// no data.
+fn function_code(
+    file: &File<'static>,
+    isa_mode: IsaMode,
+    section_index: Option<object::SectionIndex>,
+    address: u64,
+    size: u64,
+) -> Option<FunctionCode> {
+    if address == 0 || !isa_mode.can_find_calls() {
+        return None;
     }
+    let section = file.section_by_index(section_index?).ok()?;
+    // Compressed sections are decompressed into a copy, which isn't kept for
+    // every function
+    match section_data_range(file, &section, address, size)? {
+        Cow::Borrowed(code) => Some(FunctionCode {
+            code,
+            big_endian: instructions_big_endian(file),
+        }),
+        Cow::Owned(_) => None,
+    }
+}
+
+/// Root of the global debug file directory, as used by distro debuginfo
+/// packages
+const DEBUG_DIR: &str = "/usr/lib/debug";
+
+/// Sections containing stubs for calls to dynamically linked functions
+fn is_stubs_section(name: &str) -> bool {
+    // Include .plt and .plt.got for ELF
+    name.starts_with(".plt") || name == "__stubs"
+}
+
+/// Names of GNU IFUNC symbols keyed by the address of their resolver
+fn ifunc_resolvers(file: &File<'static>) -> HashMap<u64, &'static str> {
+    file.symbols()
+        .chain(file.dynamic_symbols())
+        .filter(|symbol| match symbol.flags() {
+            object::SymbolFlags::Elf { st_info, .. } => st_info & 0xf == object::elf::STT_GNU_IFUNC,
+            _ => false,
+        })
+        .filter_map(|symbol| Some((symbol.address(), symbol.name().ok()?)))
+        .collect()
+}
+
+/// Whether the shared library at `path` exports the function `name`, in
+/// `version` if given
+fn exports_function(path: &str, name: &str, version: Option<&str>) -> bool {
+    let file = match std::fs::File::open(path) {
+        Ok(file) => file,
+        Err(_) => return false,
+    };
+    // Not leaked like in `Program::parse`, most libraries are only checked
+    let mmap = match unsafe { memmap2::Mmap::map(&file) } {
+        Ok(mmap) => mmap,
+        Err(_) => return false,
+    };
+    match object::File::parse(&*mmap) {
+        Ok(file) => {
+            file.dynamic_symbols().any(|symbol| {
+                symbol.kind() == object::SymbolKind::Text
+                    && symbol.is_definition()
+                    && symbol.name().map_or(false, |n| n == name)
+            }) && defines_version(&file, version)
+        }
+        Err(_) => false,
+    }
+}
+
+/// Names of the shared libraries `file` needs according to the `DT_NEEDED`
+/// entries of its dynamic section, e.g. `libz.so.1`
+fn needed_libraries(file: &File) -> Vec<String> {
+    let (dynamic, strings) = match dynamic_sections(file, ".dynamic") {
+        Some(sections) => sections,
+        None => return Vec::new(),
+    };
+    let size = if file.is_64() { 8 } else { 4 };
+    let little_endian = file.is_little_endian();
+    dynamic
+        .chunks_exact(2 * size)
+        .filter_map(|entry| {
+            let tag = gopclntab::read_uint(entry, little_endian, 0, size)?;
+            if tag != object::elf::DT_NEEDED as u64 {
+                return None;
+            }
+            let offset = gopclntab::read_uint(entry, little_endian, size, size)?;
+            read_c_str(&strings, usize::try_from(offset).ok()?)
+        })
+        .collect()
+}
+
+/// Whether `file` defines the symbol version `version`, if one is given
+fn defines_version(file: &File, version: Option<&str>) -> bool {
+    match version {
+        Some(version) => defined_versions(file).iter().any(|v| v == version),
+        None => true,
+    }
+}
+
+/// Names of the symbol versions `file` defines (e.g. `GLIBC_2.14`), from its
+/// `.gnu.version_d` section
+fn defined_versions(file: &File) -> Vec<String> {
+    let (verdefs, strings) = match dynamic_sections(file, ".gnu.version_d") {
+        Some(sections) => sections,
+        None => return Vec::new(),
+    };
+    let little_endian = file.is_little_endian();
+    let read_u32 = |offset: usize| {
+        gopclntab::read_uint(&verdefs, little_endian, offset, 4).map(|value| value as usize)
+    };
+    // Each Elf_Verdef entry is followed by its Elf_Verdaux entries, the first
+    // of which names the version
+    let mut versions = Vec::new();
+    let mut offset = 0;
+    loop {
+        let name = read_u32(offset + 12)
+            .and_then(|aux| read_u32(offset.checked_add(aux)?))
+            .and_then(|name| read_c_str(&strings, name));
+        versions.extend(name);
+        match read_u32(offset + 16) {
+            Some(next) if next > 0 => offset += next,
+            _ => break,
+        }
+    }
+    versions
+}
+
+/// Data of section `name` of `file` and of `.dynstr`, which it refers to
+/// strings in
+fn dynamic_sections<'data>(
+    file: &File<'data>,
+    name: &str,
+) -> Option<(Cow<'data, [u8]>, Cow<'data, [u8]>)> {
+    let section = section_data(file, &file.section_by_name(name)?).ok()?;
+    let strings = section_data(file, &file.section_by_name(".dynstr")?).ok()?;
+    Some((section, strings))
+}
+
+/// Null terminated string at `offset` of `data`
+fn read_c_str(data: &[u8], offset: usize) -> Option<String> {
+    let bytes = data.get(offset..)?;
+    let len = bytes.iter().position(|&b| b == 0)?;
+    std::str::from_utf8(&bytes[..len]).ok().map(String::from)
+}
+
+/// Whether the symbol table `symbols` has any functions defined in it
+fn has_text_symbols(mut symbols: object::read::SymbolIterator) -> bool {
+    symbols.any(|symbol| symbol.kind() == object::SymbolKind::Text && symbol.address() != 0)
+}
+
+/// Path of the shared library with file name `name` (e.g. `libssl.so.3`) in
+/// the dynamic linker's cache
+pub fn find_shared_library(name: &str) -> Option<String> {
+    if name.contains('/') {
+        return None;
+    }
+    let output = Command::new("ldconfig").arg("-p").output().ok()?;
+    parse_ldconfig(&String::from_utf8_lossy(&output.stdout), name)
+}
+
+/// Lines of `ldconfig -p` output look like
+/// `libssl.so.3 (libc6,x86-64) => /lib/x86_64-linux-gnu/libssl.so.3`
+fn parse_ldconfig(output: &str, name: &str) -> Option<String> {
+    output.lines().find_map(|line| {
+        let (library, path) = line.split_once("=>")?;
+        if library.split_whitespace().next()? == name {
+            Some(path.trim().to_string())
+        } else {
+            None
+        }
+    })
+}
+
+/// (address, size) of functions according to the FDEs in `.eh_frame`, sorted
+/// by address
+fn eh_frame_functions(file: &File<'static>) -> Result<Vec<(u64, u64)>, Error> {
+    use gimli::UnwindSection;
+    let section = match file.section_by_name(".eh_frame") {
+        Some(section) => section,
+        None => return Ok(Vec::new()),
+    };
+    let data = section_data(file, &section)?;
+    let endian = if file.is_little_endian() {
+        gimli::RunTimeEndian::Little
+    } else {
+        gimli::RunTimeEndian::Big
+    };
+    let eh_frame = gimli::EhFrame::new(&data[..], endian);
+    let mut bases = gimli::BaseAddresses::default().set_eh_frame(section.address());
+    if let Some(text) = file.section_by_name(".text") {
+        bases = bases.set_text(text.address());
+    }
+    if let Some(got) = file.section_by_name(".got") {
+        bases = bases.set_got(got.address());
+    }
+
+    let err = |err: gimli::Error| format!("Failed to parse .eh_frame: {}", err);
+    let mut functions = Vec::new();
+    let mut entries = eh_frame.entries(&bases);
+    while let Some(entry) = entries.next().map_err(err)? {
+        if let gimli::CieOrFde::Fde(partial) = entry {
+            let fde = partial
+                .parse(|eh_frame, bases, offset| eh_frame.cie_from_offset(bases, offset))
+                .map_err(err)?;
+            if fde.len() > 0 {
+                functions.push((fde.initial_address(), fde.len()));
+            }
+        }
+    }
+    functions.sort_unstable();
+    functions.dedup_by_key(|(address, _)| *address);
+    Ok(functions)
+}
+
+/// Whether any code in `file` can be decoded, see `IsaMode::can_decode`.
+/// Instruction based analysis (calls, PLT entries) is skipped otherwise,
+/// including for big-endian architectures like s390x or MIPS.
+fn can_decode(file: &File<'static>) -> bool {
+    file.architecture() == object::Architecture::X86_64
+        && file.endianness() == object::Endianness::Little
+}
+
+/// Whether calls in `file` can be found, see `IsaMode::can_find_calls`. ARM
+/// code is decoded in either byte order, see `instructions_big_endian`.
+fn can_find_calls(file: &File<'static>) -> bool {
+    can_decode(file) || file.architecture() == object::Architecture::Arm
+}
+
+/// Set in the ELF header of big-endian ARM binaries in the BE8 format
+const EF_ARM_BE8: u32 = 0x0080_0000;
+
+/// Whether instructions in `file` are stored in big-endian byte order. That
+/// is usually the byte order of the file, except for big-endian ARMv6+ (BE8)
+/// binaries which only store data big-endian, and instructions little-endian.
+fn instructions_big_endian(file: &File<'static>) -> bool {
+    if file.endianness() == object::Endianness::Little {
+        return false;
+    }
+    match (file.architecture(), file.flags()) {
+        (object::Architecture::Arm, object::FileFlags::Elf { e_flags, .. }) => {
+            e_flags & EF_ARM_BE8 == 0
+        }
+        _ => true,
+    }
+}
+
+/// Calls and tail calls in the code of a function starting at `address`,
+/// which is in `isa_mode` and stored `big_endian` or not
+fn find_calls(isa_mode: IsaMode, code: &[u8], address: u64, big_endian: bool) -> Vec<Call> {
+    if isa_mode != IsaMode::X86_64 {
+        return arm::find_calls(isa_mode, code, address, big_endian);
+    }
+    let decoder = create_decoder();
+    let end_address = address + code.len() as u64;
+    // Not using `get_instructions_with_mnemonic` as it logs every call, which
+    // is far too verbose when counting calls of every function shown
+    decoder
+        .instruction_iterator(code, address)
+        .filter_map(|(instruction, ip)| {
+            let operand = &instruction.operands[0];
+            let target = match instruction.mnemonic {
+                Mnemonic::CALL => match (operand.reg, operand.mem.base) {
+                    (Register::NONE, Register::NONE) => {
+                        match instruction.calc_absolute_address(ip, operand) {
+                            Ok(target) => CallTarget::Address(target, IsaMode::X86_64),
+                            Err(_) => CallTarget::Unknown,
+                        }
+                    }
+                    (Register::NONE, base) => CallTarget::Register(
+                        base.get_string().unwrap().to_string(),
+                        Some(operand.mem.disp.displacement),
+                    ),
+                    (reg, _) => CallTarget::Register(reg.get_string().unwrap().to_string(), None),
+                },
+                // Only direct jumps can be told apart from jumps within the
+                // function, e.g. through jump tables
+                Mnemonic::JMP
+                    if operand.reg == Register::NONE
+                        && operand.mem.base == Register::NONE
+                        && operand.mem.index == Register::NONE =>
+                {
+                    match instruction.calc_absolute_address(ip, operand) {
+                        Ok(target) if !(address..end_address).contains(&target) => {
+                            CallTarget::Address(target, IsaMode::X86_64)
+                        }
+                        _ => return None,
+                    }
+                }
+                _ => return None,
+            };
+            Some(Call {
+                ip,
+                length: instruction.length,
+                tail_call: instruction.mnemonic == Mnemonic::JMP,
+                target,
+            })
+        })
+        .collect()
 }
 
 pub fn create_decoder() -> Decoder {
@@ -485,11 +3148,74 @@ impl Iterator for CallIterator<'_, '_> {
     }
 }
 
+/// ELF compression type used by e.g. `-gz=zstd`, which `object` doesn't
+/// support
+const ELFCOMPRESS_ZSTD: u32 = 2;
+
+/// Uncompressed contents of `section`. On top of what `object` decompresses
+/// (zlib `SHF_COMPRESSED` and GNU `.zdebug_*` sections), this handles
+/// `SHF_COMPRESSED` sections using zstd.
+pub fn section_data<'data: 'file, 'file, O: object::Object<'data, 'file>>(
+    file: &'file O,
+    section: &O::Section,
+) -> Result<Cow<'data, [u8]>, Error> {
+    let name = section.name().unwrap_or("<unknown>");
+    let err = match section.uncompressed_data() {
+        Ok(data) => return Ok(data),
+        Err(err) => err,
+    };
+    let is_compressed = match section.flags() {
+        object::SectionFlags::Elf { sh_flags } => {
+            sh_flags & u64::from(object::elf::SHF_COMPRESSED) != 0
+        }
+        _ => false,
+    };
+    let data = match section.data() {
+        Ok(data) if is_compressed => data,
+        _ => return Err(format!("Failed to read section {}: {}", name, err).into()),
+    };
+    // Compression header is type, (on 64-bit) reserved, uncompressed size and
+    // alignment
+    let header_size = if file.is_64() { 24 } else { 12 };
+    if data.len() < header_size {
+        return Err(format!("Section {} is too short to be compressed", name).into());
+    }
+    let ch_type = [data[0], data[1], data[2], data[3]];
+    let ch_type = if file.is_little_endian() {
+        u32::from_le_bytes(ch_type)
+    } else {
+        u32::from_be_bytes(ch_type)
+    };
+    if ch_type != ELFCOMPRESS_ZSTD {
+        return Err(format!("Failed to read section {}: {}", name, err).into());
+    }
+    zstd::stream::decode_all(&data[header_size..])
+        .map(Cow::Owned)
+        .map_err(|err| format!("Failed to decompress section {}: {}", name, err).into())
+}
+
+/// Contents of `section` in [`address`, `address` + `size`), or `None` if the
+/// range is outside of it (e.g. for sections without data in separate debug
+/// files)
+fn section_data_range<'file>(
+    file: &'file File<'static>,
+    section: &Section<'static, 'file>,
+    address: u64,
+    size: u64,
+) -> Option<Cow<'static, [u8]>> {
+    let start = usize::try_from(address.checked_sub(section.address())?).ok()?;
+    let end = start.checked_add(usize::try_from(size).ok()?)?;
+    match section_data(file, section).ok()? {
+        Cow::Borrowed(data) => data.get(start..end).map(Cow::Borrowed),
+        Cow::Owned(data) => data.get(start..end).map(|data| Cow::Owned(data.to_vec())),
+    }
+}
+
 /// Clone (plus inlining) of addr2line::ObjectContext::new, just using Arc
 /// instead of Rc.
 pub fn new_context<'data: 'file, 'file, O: object::Object<'data, 'file>>(
     file: &'file O,
-) -> Result<addr2line::Context<gimli::EndianArcSlice<gimli::RunTimeEndian>>, gimli::Error> {
+) -> Result<addr2line::Context<gimli::EndianArcSlice<gimli::RunTimeEndian>>, Error> {
     let endian = if file.is_little_endian() {
         gimli::RunTimeEndian::Little
     } else {
@@ -500,18 +3226,258 @@ pub fn new_context<'data: 'file, 'file, O: object::Object<'data, 'file>>(
         id: gimli::SectionId,
         file: &'file O,
         endian: Endian,
-    ) -> Result<gimli::EndianArcSlice<Endian>, gimli::Error>
+    ) -> Result<gimli::EndianArcSlice<Endian>, Error>
     where
         O: object::Object<'data, 'file>,
         Endian: gimli::Endianity,
     {
-        let data = file
-            .section_by_name(id.name())
-            .and_then(|section| section.uncompressed_data().ok())
-            .unwrap_or(Cow::Borrowed(&[]));
+        let data = match file.section_by_name(id.name()) {
+            Some(section) => section_data(file, &section)?,
+            None => Cow::Borrowed(&[][..]),
+        };
         Ok(gimli::EndianArcSlice::new(Arc::from(&*data), endian))
     }
 
     let dwarf = gimli::Dwarf::load(|id| load_section(id, file, endian))?;
     addr2line::Context::from_dwarf(dwarf)
+        .map_err(|err| format!("Failed to parse debug info: {}", err).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_optimization_levels() {
+        let mut producers = BTreeMap::new();
+        producers.insert(
+            "GNU C++17 9.4.0 -mtune=generic -march=x86-64 -g -O2".to_string(),
+            3,
+        );
+        producers.insert("GNU C17 9.4.0 -g -O0".to_string(), 1);
+        producers.insert("clang version 13.0.0".to_string(), 1);
+        let build_info = BuildInfo {
+            architecture: object::Architecture::X86_64,
+            endianness: object::Endianness::Little,
+            can_decode: true,
+            comments: Vec::new(),
+            producers,
+        };
+        assert_eq!(build_info.optimization_levels(), vec!["-O0", "-O2"]);
+    }
+
+    #[test]
+    fn test_parse_ldconfig() {
+        let output = "2 libs found in cache `/etc/ld.so.cache'
+\tlibssl.so.3 (libc6,x86-64) => /lib/x86_64-linux-gnu/libssl.so.3
+\tlibssl.so (libc6,x86-64) => /lib/x86_64-linux-gnu/libssl.so
+";
+        assert_eq!(
+            parse_ldconfig(output, "libssl.so").as_deref(),
+            Some("/lib/x86_64-linux-gnu/libssl.so")
+        );
+        assert_eq!(parse_ldconfig(output, "libcrypto.so.3"), None);
+    }
+
+    #[test]
+    fn test_isa_mode_from_symbol() {
+        assert_eq!(
+            IsaMode::from_symbol(object::Architecture::Arm, 0x1001),
+            (IsaMode::Thumb, 0x1000)
+        );
+        assert_eq!(
+            IsaMode::from_symbol(object::Architecture::Arm, 0x1000),
+            (IsaMode::Arm, 0x1000)
+        );
+        assert_eq!(
+            IsaMode::from_symbol(object::Architecture::X86_64, 0x1001),
+            (IsaMode::X86_64, 0x1001)
+        );
+    }
+    #[test]
+    fn test_split_parameters() {
+        assert_eq!(split_parameters("main"), ("main", ""));
+        assert_eq!(split_parameters("foo(int, char*)"), ("foo", ""));
+        assert_eq!(
+            split_parameters("ns::Foo<int>::bar(std::function<void (int)>) const"),
+            ("ns::Foo<int>::bar", " const")
+        );
+        assert_eq!(
+            split_parameters("Foo::operator()(int)"),
+            ("Foo::operator()", "")
+        );
+    }
+
+    #[test]
+    fn test_base_name() {
+        assert_eq!(base_name("main"), "main");
+        assert_eq!(base_name("ns::Foo::bar(int) const"), "ns::Foo::bar");
+        assert_eq!(base_name("int ns::foo<int>(int)"), "ns::foo");
+        assert_eq!(
+            base_name("std::vector<int, std::allocator<int> > make<std::vector<int> >()"),
+            "make"
+        );
+        assert_eq!(base_name("Foo::operator()(int)"), "Foo::operator()");
+    }
+
+    #[test]
+    fn test_span_exits() {
+        // Jumps out of the span, within it, into it and after it
+        let jumps = [(12, 40), (14, 10), (4, 12), (20, 44)];
+        assert_eq!(span_exits(10, 20, &jumps), vec![20, 40]);
+        assert_eq!(span_exits(30, 34, &[]), vec![34]);
+    }
+
+    #[test]
+    fn test_parse_code_location() {
+        assert_eq!(
+            parse_code_location("0x401a2f").unwrap(),
+            CodeLocation::Address(0x401a2f)
+        );
+        assert_eq!(
+            parse_code_location("_Z3foov+0x1a").unwrap(),
+            CodeLocation::Offset(Some("_Z3foov".to_string()), 0x1a)
+        );
+        assert_eq!(
+            parse_code_location(" +26 ").unwrap(),
+            CodeLocation::Offset(None, 26)
+        );
+        assert!(parse_code_location("_Z3foov").is_err());
+        assert!(parse_code_location("_Z3foov+0xzz").is_err());
+    }
+
+    #[test]
+    fn test_find_loops() {
+        // Two back edges of one loop, a nested loop and a forward jump
+        let jumps = [(40, 8), (30, 8), (24, 16), (12, 36)];
+        assert_eq!(
+            find_loops(&jumps),
+            vec![
+                Loop {
+                    head: 8,
+                    back_edges: vec![30, 40]
+                },
+                Loop {
+                    head: 16,
+                    back_edges: vec![24]
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_address_to_name() {
+        let symbol = |name: &'static str, demangled_name: Option<&str>, address: u64| {
+            SymbolInfo::new(
+                FunctionName(name),
+                demangled_name.map(String::from),
+                None,
+                address,
+                16,
+                IsaMode::X86_64,
+                None,
+            )
+        };
+        let mut name_to_symbol: HashMap<_, _> = vec![
+            symbol("__libc_malloc", None, 0x1000),
+            symbol("malloc", None, 0x1000),
+            symbol("_ZN3FooC2Ev", Some("Foo::Foo()"), 0x2000),
+            symbol("_ZN3FooC1Ev", Some("Foo::Foo()"), 0x2000),
+            symbol("free", None, 0x3000),
+        ]
+        .into_iter()
+        .map(|s| (s.name, s))
+        .collect();
+        let address_to_name = Program::address_to_name(&mut name_to_symbol);
+        assert_eq!(address_to_name[&0x1000], FunctionName("malloc"));
+        assert_eq!(address_to_name[&0x2000], FunctionName("_ZN3FooC1Ev"));
+        assert_eq!(
+            name_to_symbol[&FunctionName("malloc")].aliases,
+            vec![FunctionName("__libc_malloc")]
+        );
+        assert_eq!(
+            name_to_symbol[&FunctionName("_ZN3FooC2Ev")].alias_of,
+            Some(FunctionName("_ZN3FooC1Ev"))
+        );
+        assert!(name_to_symbol[&FunctionName("free")].aliases.is_empty());
+    }
+
+    #[test]
+    fn test_synthesized_address() {
+        assert_eq!(
+            FunctionName("fn_0x401230").synthesized_address(),
+            Some(0x401230)
+        );
+        let local = FunctionName("_ZL6helperv@0x401230");
+        assert_eq!(local.synthesized_address(), Some(0x401230));
+        assert_eq!(local.to_string(), "helper()@0x401230");
+        assert_eq!(
+            FunctionName("memcpy@@GLIBC_2.14").synthesized_address(),
+            None
+        );
+        assert_eq!(FunctionName("main").synthesized_address(), None);
+    }
+
+    #[test]
+    fn test_qualified_function_name() {
+        let function = FunctionName("/usr/lib/libfoo.so.1`_ZL6helperv@0x1230");
+        assert_eq!(function.object(), Some("/usr/lib/libfoo.so.1"));
+        assert_eq!(function.unqualified(), FunctionName("_ZL6helperv@0x1230"));
+        assert_eq!(function.synthesized_address(), Some(0x1230));
+        assert_eq!(function.to_string(), "libfoo.so.1`helper()@0x1230");
+        assert_eq!(FunctionName("main").object(), None);
+        assert_eq!(FunctionName("main").unqualified(), FunctionName("main"));
+    }
+
+    #[test]
+    #[ignore]
+    /// Crude fuzzer for loading binaries, feeding truncated and corrupted
+    /// copies of a binary (`WACHY_FUZZ_INPUT`, or else this test binary) to
+    /// `Program::new`, which must return an error or load rather than panic.
+    /// Run with `cargo test --release fuzz_program_new -- --ignored`.
+    fn fuzz_program_new() {
+        let input = std::env::var("WACHY_FUZZ_INPUT").unwrap_or_else(|_| {
+            std::env::current_exe()
+                .unwrap()
+                .to_string_lossy()
+                .into_owned()
+        });
+        let iterations: usize = std::env::var("WACHY_FUZZ_ITERATIONS")
+            .ok()
+            .and_then(|n| n.parse().ok())
+            .unwrap_or(200);
+        let original = std::fs::read(&input).unwrap();
+        let path = std::env::temp_dir().join(format!("wachy-fuzz-{}", std::process::id()));
+        // xorshift, so a failing run can be reproduced with `WACHY_FUZZ_SEED`
+        let mut state: u64 = std::env::var("WACHY_FUZZ_SEED")
+            .ok()
+            .and_then(|seed| seed.parse().ok())
+            .unwrap_or(0x9e3779b97f4a7c15);
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+
+        for i in 0..iterations {
+            let mut data = original.clone();
+            if i % 4 == 0 {
+                data.truncate(next() as usize % data.len());
+            } else {
+                for _ in 0..1 + next() % 64 {
+                    let index = next() as usize % data.len();
+                    data[index] = next() as u8;
+                }
+            }
+            std::fs::write(&path, &data).unwrap();
+            if let Ok(program) = Program::new(path.to_string_lossy().into_owned()) {
+                for symbol in program.symbols_generator().into_iter().take(32) {
+                    let _ = program.get_data(symbol.name);
+                    let _ = program.get_location(symbol.address);
+                }
+            }
+        }
+        let _ = std::fs::remove_file(&path);
+    }
 }
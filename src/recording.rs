@@ -0,0 +1,95 @@
+use crate::control::ControlCommand;
+use crate::error::Error;
+use crate::events::Event;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Records user actions with timestamps so a session can be replayed later.
+/// Each line of a recording is `<seconds since start> <control command>`, and
+/// lines starting with `#` are comments.
+pub struct Recorder {
+    file: File,
+    start_time: Instant,
+}
+
+impl Recorder {
+    pub fn new(path: &str, program_path: &str, function: &str) -> Result<Recorder, Error> {
+        let mut file = File::create(path)
+            .map_err(|err| format!("Failed to create recording {}: {}", path, err))?;
+        writeln!(file, "# wachy recording of {} {}", program_path, function)
+            .map_err(|err| format!("Failed to write recording {}: {}", path, err))?;
+        Ok(Recorder {
+            file,
+            start_time: Instant::now(),
+        })
+    }
+
+    pub fn record(&mut self, command: &ControlCommand) {
+        let line = format!("{:.3} {}", self.start_time.elapsed().as_secs_f64(), command);
+        log::debug!("Recording {}", line);
+        if let Err(err) = writeln!(self.file, "{}", line) {
+            log::error!("Failed to write recording: {}", err);
+        }
+    }
+}
+
+/// Parse a recording made by `Recorder`
+fn parse_recording(path: &str) -> Result<Vec<(Duration, String)>, Error> {
+    let file =
+        File::open(path).map_err(|err| format!("Failed to open recording {}: {}", path, err))?;
+    let mut commands = Vec::new();
+    for (i, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.map_err(|err| format!("Failed to read recording {}: {}", path, err))?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let invalid = || Error::from(format!("Invalid line {} in recording {}", i + 1, path));
+        let (time, command) = line.split_once(' ').ok_or_else(invalid)?;
+        let time = time.parse::<f64>().map_err(|_| invalid())?;
+        // Validate upfront rather than partway through replaying
+        ControlCommand::parse(command)
+            .map_err(|err| format!("Invalid line {} in recording {}: {}", i + 1, path, err))?;
+        commands.push((Duration::from_secs_f64(time), command.to_string()));
+    }
+    Ok(commands)
+}
+
+/// Replay a recording in the background, issuing each command at the same time
+/// (relative to when this is called) as it was recorded.
+pub fn replay(path: &str, tx: mpsc::Sender<Event>) -> Result<(), Error> {
+    let commands = parse_recording(path)?;
+    let start_time = Instant::now();
+    // This thread is never joined, it exits once the recording is complete or
+    // the controller is gone.
+    thread::spawn(move || {
+        for (time, command) in commands {
+            if let Some(delay) = time.checked_sub(start_time.elapsed()) {
+                thread::sleep(delay);
+            }
+            log::info!("Replaying {}", command);
+            let (reply_tx, reply_rx) = mpsc::channel();
+            if tx
+                .send(Event::ControlCommand {
+                    command: command.clone(),
+                    reply_tx,
+                })
+                .is_err()
+            {
+                return;
+            }
+            match reply_rx.recv() {
+                Ok(reply) if reply.starts_with("error") => {
+                    log::error!("Replaying '{}' failed: {}", command, reply)
+                }
+                Ok(_) => (),
+                Err(_) => return,
+            }
+        }
+        log::info!("Replay complete");
+    });
+    Ok(())
+}
@@ -0,0 +1,58 @@
+use crate::error::Error;
+use crate::store;
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+use std::process::Command;
+
+/// Release of the static bpftrace build used with `--bundled-runtime`
+const BPFTRACE_VERSION: &str = "v0.20.4";
+
+/// Path to a static build of bpftrace, for hosts where it can't be installed.
+/// The pinned release is downloaded from GitHub on first use and cached.
+pub fn bundled_bpftrace() -> Result<PathBuf, Error> {
+    if !cfg!(all(target_os = "linux", target_arch = "x86_64")) {
+        return Err("The bundled runtime is only available on x86_64 Linux".into());
+    }
+    let dir = store::cache_dir()
+        .ok_or("Nowhere to cache the bundled runtime, set HOME or XDG_CACHE_HOME")?
+        .join("runtime");
+    let path = dir.join(format!("bpftrace-{}", BPFTRACE_VERSION));
+    if path.is_file() {
+        return Ok(path);
+    }
+    std::fs::create_dir_all(&dir)
+        .map_err(|err| format!("Failed to create {}: {}", dir.display(), err))?;
+
+    // Download to a temporary file so an interrupted download doesn't end up
+    // in the cache
+    let tmp_path = PathBuf::from(format!("{}.tmp{}", path.display(), std::process::id()));
+    let url = format!(
+        "https://github.com/bpftrace/bpftrace/releases/download/{}/bpftrace",
+        BPFTRACE_VERSION
+    );
+    log::info!("Downloading bpftrace {} from {}", BPFTRACE_VERSION, url);
+    // Output is captured, so curl's errors don't garble the UI
+    let output = Command::new("curl")
+        .args(["--fail", "--silent", "--show-error", "--location"])
+        .args(["--proto", "=https", "--output"])
+        .arg(&tmp_path)
+        .arg(&url)
+        .output()
+        .map_err(|err| format!("Failed to run curl: {}", err))?;
+    let result: Result<(), Error> = if output.status.success() {
+        std::fs::set_permissions(&tmp_path, std::fs::Permissions::from_mode(0o755))
+            .and_then(|_| std::fs::rename(&tmp_path, &path))
+            .map_err(|err| format!("Failed to save {}: {}", path.display(), err).into())
+    } else {
+        Err(format!(
+            "Failed to download {}: {}",
+            url,
+            String::from_utf8_lossy(&output.stderr).trim_end()
+        )
+        .into())
+    };
+    if result.is_err() {
+        let _ = std::fs::remove_file(&tmp_path);
+    }
+    result.map(|_| path)
+}
@@ -0,0 +1,86 @@
+use crate::error::Error;
+use crate::program::FunctionName;
+use std::collections::HashMap;
+use std::process::Command;
+use std::time::Duration;
+
+/// Sampling frequency in Hz, odd to avoid sampling in lockstep with periodic
+/// work
+const FREQUENCY: u32 = 999;
+
+/// Sample user space instruction pointers of all CPUs for `duration` with
+/// `perf`, returning how many samples were in `function` keyed by their offset
+/// within it. Unlike tracing, the overhead doesn't grow with how often the
+/// function is called.
+pub fn sample(function: FunctionName, duration: Duration) -> Result<HashMap<u64, u64>, Error> {
+    let data_path = std::env::temp_dir().join(format!("wachy-samples-{}.data", std::process::id()));
+    log::info!("Sampling CPU time for {:?}", duration);
+    let output = Command::new("perf")
+        .args(["record", "--all-cpus", "--event", "cpu-clock:u", "--freq"])
+        .arg(FREQUENCY.to_string())
+        .arg("--output")
+        .arg(&data_path)
+        .args(["--", "sleep"])
+        .arg(duration.as_secs().to_string())
+        .output()
+        .map_err(|err| format!("Failed to run perf: {}", err))?;
+    if !output.status.success() {
+        let _ = std::fs::remove_file(&data_path);
+        return Err(format!(
+            "perf record failed, status: {:?}, stderr:\n{}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr)
+        )
+        .into());
+    }
+
+    let output = Command::new("perf")
+        .args(["script", "--input"])
+        .arg(&data_path)
+        .args(["--no-demangle", "--fields", "sym,symoff"])
+        .output();
+    let _ = std::fs::remove_file(&data_path);
+    let output = output.map_err(|err| format!("Failed to run perf: {}", err))?;
+    if !output.status.success() {
+        return Err(format!(
+            "perf script failed, status: {:?}, stderr:\n{}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr)
+        )
+        .into());
+    }
+    Ok(parse_symoff(
+        &String::from_utf8_lossy(&output.stdout),
+        function.unqualified().0,
+    ))
+}
+
+/// Count samples in `function` in `perf script` output of `symbol+offset`
+/// lines
+fn parse_symoff(output: &str, function: &str) -> HashMap<u64, u64> {
+    let mut counts = HashMap::new();
+    for sample in output.lines() {
+        let offset = match sample.trim().rsplit_once('+') {
+            Some((symbol, offset)) if symbol == function => offset,
+            _ => continue,
+        };
+        if let Ok(offset) = u64::from_str_radix(offset.trim_start_matches("0x"), 16) {
+            *counts.entry(offset).or_default() += 1;
+        }
+    }
+    counts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_symoff() {
+        let output = "  foo+0x1a\n  foo+0x1a\n  bar+0x8\n  foo+0x52\n  [unknown]\n";
+        let counts = parse_symoff(output, "foo");
+        assert_eq!(counts.len(), 2);
+        assert_eq!(counts[&0x1a], 2);
+        assert_eq!(counts[&0x52], 1);
+    }
+}
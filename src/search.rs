@@ -1,11 +1,12 @@
 use crate::error::Error;
 use crate::events::Event;
-use crate::program::{SymbolInfo, SymbolsGenerator};
+use crate::program::{self, SymbolInfo, SymbolsGenerator};
 use fuzzy_matcher::skim::SkimMatcherV2;
 use fuzzy_matcher::FuzzyMatcher;
 use itertools::Itertools;
 use std::borrow::Cow;
 use std::cmp;
+use std::collections::HashMap;
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{mpsc, Arc};
 use std::thread;
@@ -13,6 +14,8 @@ use std::thread;
 enum SearchCommand {
     SetEmptySearchResults(Vec<(String, Option<SymbolInfo>)>),
     SetFixedItems(Vec<SymbolInfo>),
+    /// Whether results should be grouped, see `group_overloads`
+    SetGroupOverloads(bool),
     /// Counter, search view name, search string and (max) number of results.
     /// Must be sent after SetEmptySearchResults and SetFixedItems. The
     /// search will be performed on fixed items combined with
@@ -41,10 +44,14 @@ impl Searcher {
         }
     }
 
+    /// If `group_overloads` is set, overloads and template instantiations of
+    /// the same function are collapsed into one result, see
+    /// `group_overloads`
     pub fn setup_search(
         &self,
         empty_search_results: Vec<(String, Option<SymbolInfo>)>,
         fixed_items: Vec<SymbolInfo>,
+        group_overloads: bool,
     ) {
         self.inc_counter();
         self.tx
@@ -53,6 +60,9 @@ impl Searcher {
         self.tx
             .send(SearchCommand::SetFixedItems(fixed_items))
             .unwrap();
+        self.tx
+            .send(SearchCommand::SetGroupOverloads(group_overloads))
+            .unwrap();
     }
 
     fn inc_counter(&self) -> u64 {
@@ -83,12 +93,14 @@ impl Searcher {
     ) {
         let mut empty_search_results = None;
         let mut fixed_items = None;
+        let mut group = false;
         for cmd in command_rx {
             match cmd {
                 SearchCommand::SetEmptySearchResults(results) => {
                     empty_search_results = Some(results)
                 }
                 SearchCommand::SetFixedItems(items) => fixed_items = Some(items),
+                SearchCommand::SetGroupOverloads(value) => group = value,
                 SearchCommand::Search(counter_val, view_name, search, n_results) => {
                     let is_cancelled_fn = || counter_val != counter.load(Ordering::Acquire);
                     if is_cancelled_fn() {
@@ -102,8 +114,19 @@ impl Searcher {
                         log::debug!("Searching for {}", search);
                         let start_time = std::time::Instant::now();
                         let it = fixed_items.as_ref().unwrap().iter().chain(&symbols);
-                        let results_opt =
-                            rank_fn_with_cancellation(it, &search, n_results, is_cancelled_fn);
+                        // Groups are formed from all matches, so that the
+                        // results aren't filled up by members of one group
+                        let results_opt = if group {
+                            rank_fn_with_cancellation(it, &search, usize::MAX, is_cancelled_fn).map(
+                                |results| {
+                                    let mut results = group_overloads(results);
+                                    results.truncate(n_results);
+                                    results
+                                },
+                            )
+                        } else {
+                            rank_fn_with_cancellation(it, &search, n_results, is_cancelled_fn)
+                        };
                         match results_opt {
                             Some(_) => log::debug!(
                                 "Completed search for {}, returning {} results in {:#?}",
@@ -150,6 +173,48 @@ impl Label for &str {
     }
 }
 
+/// Collapse results that are overloads or template instantiations of the same
+/// function (i.e. have the same `program::base_name`) into the best ranked of
+/// them, which lists the others in `SymbolInfo::overloads`. Template heavy
+/// code can otherwise have dozens of near identical results.
+fn group_overloads(
+    results: Vec<(String, Option<SymbolInfo>)>,
+) -> Vec<(String, Option<SymbolInfo>)> {
+    let mut grouped: Vec<(String, Option<SymbolInfo>)> = Vec::new();
+    // Base name to index of its group in `grouped`
+    let mut groups: HashMap<String, usize> = HashMap::new();
+    for (label, symbol) in results {
+        let symbol = match symbol {
+            Some(symbol) => symbol,
+            None => {
+                grouped.push((label, None));
+                continue;
+            }
+        };
+        let base = program::base_name(&symbol.display_name()).to_string();
+        match groups.get(&base) {
+            Some(&index) => {
+                if let Some(first) = &mut grouped[index].1 {
+                    first.overloads.push(symbol);
+                }
+            }
+            None => {
+                groups.insert(base, grouped.len());
+                grouped.push((label, Some(symbol)));
+            }
+        }
+    }
+    // Labels of groups need to show their number of overloads
+    for (label, symbol) in &mut grouped {
+        if let Some(symbol) = symbol {
+            if !symbol.overloads.is_empty() {
+                *label = symbol.to_string();
+            }
+        }
+    }
+    grouped
+}
+
 /// Rank matches using fuzzy search and return the top results
 pub fn rank_fn<'a, T, I>(it: I, search: &str, n_results: usize) -> Vec<(String, Option<T>)>
 where
@@ -0,0 +1,278 @@
+use crate::controller::Controller;
+use crate::error::Error;
+use crate::events;
+use crate::messages::Messages;
+use crate::views;
+use crate::views::TraceState;
+use cursive::traits::{Nameable, Resizable};
+use cursive::views::{Dialog, LinearLayout};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::rc::Rc;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// Messages queued for a watcher before it is considered stalled and dropped,
+/// so that a slow watcher can't hold up the session
+const CLIENT_QUEUE_LEN: usize = 64;
+/// After which a write to a watcher that stopped reading fails, so its writer
+/// thread doesn't block forever once it was dropped
+const CLIENT_WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Messages sent from a sharing session to read-only watchers, serialized as
+/// one JSON object per line.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ShareMessage {
+    /// Top frame of the trace stack changed (or was re-traced)
+    Frame {
+        function: String,
+        source_file: String,
+        /// Lines of the source shown by the session. Missing from older
+        /// sessions, in which case `source_file` is read locally.
+        #[serde(default)]
+        source_code: Option<Vec<String>>,
+        source_line: u32,
+        marked_lines: Vec<u32>,
+        /// Missing from older sessions
+        #[serde(default)]
+        inlined_lines: Vec<u32>,
+        max_line: u32,
+    },
+    /// Map from line to cumulative (duration in nanoseconds, count)
+    Lines {
+        /// Seconds the trace has been running for. Fractional so traces
+        /// shorter than a second don't divide by zero.
+        time: f64,
+        lines: HashMap<u32, (u64, u64)>,
+    },
+}
+
+/// Broadcasts session state to any number of watchers connected over a unix
+/// socket. Watchers cannot send anything back, so sharing is read-only.
+pub struct ShareServer {
+    socket_path: String,
+    /// Queues of the threads writing to each watcher
+    clients: Arc<Mutex<Vec<mpsc::SyncSender<Arc<str>>>>>,
+    /// Sent to newly connected watchers so they don't have to wait for the next
+    /// frame change.
+    last_frame: Arc<Mutex<Option<Arc<str>>>>,
+}
+
+impl ShareServer {
+    pub fn new(socket_path: &str) -> Result<ShareServer, Error> {
+        // Remove stale socket left over from a previous session
+        let _ = std::fs::remove_file(socket_path);
+        let listener = UnixListener::bind(socket_path)
+            .map_err(|err| format!("Failed to bind share socket {}: {}", socket_path, err))?;
+        let clients = Arc::new(Mutex::new(Vec::new()));
+        let last_frame = Arc::new(Mutex::new(None));
+        let clients_copy = Arc::clone(&clients);
+        let last_frame_copy = Arc::clone(&last_frame);
+        // This thread lives for the duration of the process, it is never
+        // joined.
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(err) => {
+                        log::warn!("Failed to accept share connection: {:?}", err);
+                        continue;
+                    }
+                };
+                log::info!("Watcher connected");
+                let (tx, rx) = mpsc::sync_channel(CLIENT_QUEUE_LEN);
+                // Hold the lock until the watcher is added, so that it can't
+                // miss a frame sent in between
+                let last_frame = last_frame_copy.lock().unwrap();
+                if let Some(frame) = &*last_frame {
+                    let _ = tx.try_send(Arc::clone(frame));
+                }
+                clients_copy.lock().unwrap().push(tx);
+                drop(last_frame);
+                thread::spawn(move || ShareServer::write_messages(stream, rx));
+            }
+        });
+        Ok(ShareServer {
+            socket_path: socket_path.to_string(),
+            clients,
+            last_frame,
+        })
+    }
+
+    /// Queue `message` for every watcher. Never blocks on the watchers
+    /// themselves, since this is called from the UI thread.
+    pub fn send(&self, message: &ShareMessage) {
+        let mut line = serde_json::to_string(message).unwrap();
+        line.push('\n');
+        let line: Arc<str> = line.into();
+        let mut last_frame = self.last_frame.lock().unwrap();
+        if let ShareMessage::Frame { .. } = message {
+            *last_frame = Some(Arc::clone(&line));
+        }
+        // Drop any watchers that have disconnected or stopped reading, which
+        // ends their writer threads
+        self.clients
+            .lock()
+            .unwrap()
+            .retain(|tx| match tx.try_send(Arc::clone(&line)) {
+                Ok(()) => true,
+                Err(mpsc::TrySendError::Full(_)) => {
+                    log::warn!("Dropping watcher that stopped reading");
+                    false
+                }
+                Err(mpsc::TrySendError::Disconnected(_)) => false,
+            });
+    }
+
+    /// Write queued messages to a watcher until it disconnects or is dropped
+    fn write_messages(mut stream: UnixStream, rx: mpsc::Receiver<Arc<str>>) {
+        if let Err(err) = stream.set_write_timeout(Some(CLIENT_WRITE_TIMEOUT)) {
+            log::warn!("Failed to set watcher write timeout: {:?}", err);
+        }
+        for line in rx {
+            if stream.write_all(line.as_bytes()).is_err() {
+                log::info!("Watcher disconnected");
+                return;
+            }
+        }
+        let _ = stream.shutdown(std::net::Shutdown::Both);
+    }
+}
+
+impl Drop for ShareServer {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.socket_path);
+    }
+}
+
+/// Read-only TUI that mirrors the source view of a session started with
+/// `--share`.
+pub struct Watcher;
+
+impl Watcher {
+    pub fn run(socket_path: &str, messages: Messages) -> Result<(), Error> {
+        let messages = Rc::new(messages);
+        let stream = UnixStream::connect(socket_path)
+            .map_err(|err| format!("Failed to connect to {}: {}", socket_path, err))?;
+        let mut siv = cursive::default().into_runner();
+        // Messages are handled as callbacks on the UI thread, so it only wakes
+        // up when there is something to show
+        let cb_sink = siv.cb_sink().clone();
+        thread::spawn(move || {
+            for line in BufReader::new(stream).lines() {
+                let line = match line {
+                    Ok(line) => line,
+                    Err(_) => break,
+                };
+                match serde_json::from_str::<ShareMessage>(&line) {
+                    Ok(message) => {
+                        if cb_sink
+                            .send(Box::new(move |siv| Watcher::handle_message(siv, message)))
+                            .is_err()
+                        {
+                            return;
+                        }
+                    }
+                    Err(err) => log::error!("Failed to parse share message '{}': {}", line, err),
+                }
+            }
+            // Session ended
+            let _ = cb_sink.send(Box::new(Watcher::show_ended));
+        });
+
+        siv.add_fullscreen_layer(
+            Dialog::around(
+                LinearLayout::vertical()
+                    .child(
+                        views::new_source_view(&messages)
+                            .with_name("source_view")
+                            .full_screen(),
+                    )
+                    .child(views::new_footer_view().with_name("footer_view")),
+            )
+            .title(format!("wachy (read-only) | {}", socket_path))
+            .full_screen(),
+        );
+        siv.add_global_callback('q', |siv| siv.quit());
+        let esc_messages = Rc::clone(&messages);
+        siv.add_global_callback(cursive::event::Key::Esc, move |siv| {
+            if siv.screen().len() > 1 {
+                siv.pop_layer();
+            } else {
+                siv.add_layer(views::new_quit_dialog(
+                    &esc_messages,
+                    esc_messages.get("quit.confirm"),
+                ));
+            }
+        });
+
+        siv.set_user_data(messages);
+        siv.run();
+        Ok(())
+    }
+
+    fn show_ended(siv: &mut cursive::Cursive) {
+        let messages = Rc::clone(
+            siv.user_data::<Rc<Messages>>()
+                .expect("Bug: Messages do not exist"),
+        );
+        siv.add_layer(views::new_quit_dialog(
+            &messages,
+            messages.get("share.ended"),
+        ));
+    }
+
+    fn handle_message(siv: &mut cursive::Cursive, message: ShareMessage) {
+        match message {
+            ShareMessage::Frame {
+                function,
+                source_file,
+                source_code,
+                source_line,
+                marked_lines,
+                inlined_lines,
+                max_line,
+            } => {
+                siv.call_on_name("source_view", |sview: &mut views::SourceView| {
+                    let source_code = source_code
+                        .unwrap_or_else(|| Controller::read_source_code(&source_file, max_line));
+                    views::set_source_view(
+                        sview,
+                        source_code,
+                        source_line,
+                        marked_lines,
+                        inlined_lines,
+                    );
+                });
+                siv.call_on_name("footer_view", |fview: &mut views::FooterView| {
+                    views::set_footer_view(fview, &format!("{} | {}", function, source_file));
+                });
+            }
+            ShareMessage::Lines { time, lines } => {
+                let data_time = time as f32;
+                siv.call_on_name("source_view", |sview: &mut views::SourceView| {
+                    for (line, (duration, count)) in lines {
+                        if line as usize > sview.borrow_items().len() {
+                            continue;
+                        }
+                        let info = events::TraceCumulative {
+                            duration: Duration::from_nanos(duration),
+                            count,
+                        };
+                        let latency = if info.count != 0 {
+                            TraceState::Traced(info.duration / u32::try_from(info.count).unwrap())
+                        } else {
+                            TraceState::Untraced
+                        };
+                        let frequency = TraceState::Traced(info.count as f32 / data_time);
+                        Controller::set_line_state(sview, line, latency, frequency);
+                    }
+                });
+            }
+        }
+    }
+}
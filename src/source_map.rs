@@ -0,0 +1,97 @@
+use crate::error::Error;
+use std::borrow::Cow;
+
+/// Rules for finding sources locally when the paths recorded in debug info
+/// don't exist, e.g. for binaries built in a container or on CI. Works like
+/// gdb's `set substitute-path`.
+#[derive(Default)]
+pub struct SourceMap {
+    /// (old, new) path prefixes, without trailing slashes. Later rules take
+    /// precedence.
+    rules: Vec<(String, String)>,
+}
+
+impl SourceMap {
+    /// Add a rule of the form `OLD=NEW`, replacing any existing rule for
+    /// `OLD`
+    pub fn add(&mut self, rule: &str) -> Result<(), Error> {
+        let (old, new) = match rule.split_once('=') {
+            Some((old, new)) if !old.trim_end_matches('/').is_empty() => {
+                (old.trim_end_matches('/'), new.trim_end_matches('/'))
+            }
+            _ => return Err(format!("Invalid source map {:?}, expected OLD=NEW", rule).into()),
+        };
+        self.insert(old, new);
+        Ok(())
+    }
+
+    /// Add a rule mapping the tree `source_file` is in to the one `local_path`
+    /// is in, found by dropping the trailing path components they have in
+    /// common. E.g. `/build/src/a/foo.cc` located at
+    /// `/home/me/project/a/foo.cc` maps `/build/src` to `/home/me/project`.
+    pub fn add_located(&mut self, source_file: &str, local_path: &str) {
+        let mut old: Vec<&str> = source_file.split('/').collect();
+        let mut new: Vec<&str> = local_path.split('/').collect();
+        // Keep at least one component of `old`, an empty prefix would match
+        // every path
+        while old.len() > 2 && new.len() > 1 && old.last() == new.last() {
+            old.pop();
+            new.pop();
+        }
+        self.insert(&old.join("/"), &new.join("/"));
+    }
+
+    fn insert(&mut self, old: &str, new: &str) {
+        self.rules.retain(|(o, _)| o != old);
+        self.rules.push((old.to_string(), new.to_string()));
+    }
+
+    /// `path` with its prefix replaced according to the matching rule, if
+    /// any. Prefixes only match whole path components.
+    pub fn remap<'a>(&self, path: &'a str) -> Cow<'a, str> {
+        for (old, new) in self.rules.iter().rev() {
+            if let Some(rest) = path.strip_prefix(old.as_str()) {
+                if rest.is_empty() || rest.starts_with('/') {
+                    return Cow::Owned(format!("{}{}", new, rest));
+                }
+            }
+        }
+        Cow::Borrowed(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_remap() {
+        let mut source_map = SourceMap::default();
+        source_map.add("/build/src/=/home/me/project").unwrap();
+        assert_eq!(
+            source_map.remap("/build/src/foo.cc"),
+            "/home/me/project/foo.cc"
+        );
+        assert_eq!(source_map.remap("/build/srcs/foo.cc"), "/build/srcs/foo.cc");
+        source_map.add("/build/src/third_party=/opt/deps").unwrap();
+        assert_eq!(
+            source_map.remap("/build/src/third_party/a.h"),
+            "/opt/deps/a.h"
+        );
+        assert!(source_map.add("/build/src").is_err());
+        assert!(source_map.add("/=/src").is_err());
+    }
+
+    #[test]
+    fn test_add_located() {
+        let mut source_map = SourceMap::default();
+        source_map.add_located("/build/src/a/foo.cc", "/home/me/project/a/foo.cc");
+        assert_eq!(
+            source_map.remap("/build/src/b/bar.cc"),
+            "/home/me/project/b/bar.cc"
+        );
+        source_map.add_located("/foo.cc", "/tmp/foo.cc");
+        assert_eq!(source_map.remap("/foo.cc"), "/tmp/foo.cc");
+        assert_eq!(source_map.remap("/bar.cc"), "/bar.cc");
+    }
+}
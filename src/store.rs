@@ -0,0 +1,161 @@
+use crate::program::FunctionName;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Number of recently visited and of frequently traced functions suggested
+const MAX_RECENT: usize = 5;
+const MAX_FREQUENT: usize = 5;
+
+/// `$XDG_CACHE_HOME/wachy`, defaulting to `~/.cache/wachy`. Holds state that
+/// persists across sessions.
+pub fn cache_dir() -> Option<PathBuf> {
+    let cache_home = match std::env::var_os("XDG_CACHE_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(std::env::var_os("HOME")?).join(".cache"),
+    };
+    Some(cache_home.join("wachy"))
+}
+
+/// `$XDG_CONFIG_HOME/wachy`, defaulting to `~/.config/wachy`. Holds files
+/// written by the user, like trace templates.
+pub fn config_dir() -> Option<PathBuf> {
+    let config_home = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+    };
+    Some(config_home.join("wachy"))
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct FunctionHistory {
+    /// Seconds since the epoch
+    last_visited: u64,
+    visits: u32,
+    traces: u32,
+}
+
+/// Functions visited and traced in previous sessions on the same program,
+/// used to suggest functions before anything is typed in a function picker.
+/// Saved after every change so that nothing is lost if wachy is killed.
+pub struct History {
+    /// `None` if there is nowhere to save history
+    path: Option<PathBuf>,
+    functions: HashMap<String, FunctionHistory>,
+}
+
+impl History {
+    pub fn load(program_path: &str) -> History {
+        let path = history_path(program_path);
+        let functions = path
+            .as_ref()
+            .and_then(|path| std::fs::read(path).ok())
+            .and_then(|contents| match serde_json::from_slice(&contents) {
+                Ok(functions) => Some(functions),
+                Err(err) => {
+                    log::warn!("Ignoring invalid history: {}", err);
+                    None
+                }
+            })
+            .unwrap_or_default();
+        History { path, functions }
+    }
+
+    pub fn record_visit(&mut self, function: FunctionName) {
+        let entry = self.functions.entry(function.0.to_string()).or_default();
+        entry.visits += 1;
+        entry.last_visited = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        self.save();
+    }
+
+    pub fn record_trace(&mut self, function: FunctionName) {
+        self.functions
+            .entry(function.0.to_string())
+            .or_default()
+            .traces += 1;
+        self.save();
+    }
+
+    /// Names of the most recently visited functions, followed by the most
+    /// frequently traced ones not already included
+    pub fn suggestions(&self) -> Vec<&str> {
+        let mut recent: Vec<_> = self
+            .functions
+            .iter()
+            .filter(|(_, h)| h.visits > 0)
+            .collect();
+        recent.sort_by(|(_, a), (_, b)| b.last_visited.cmp(&a.last_visited));
+        let mut frequent: Vec<_> = self
+            .functions
+            .iter()
+            .filter(|(_, h)| h.traces > 0)
+            .collect();
+        frequent.sort_by(|(_, a), (_, b)| b.traces.cmp(&a.traces));
+
+        let mut suggestions: Vec<&str> = recent
+            .into_iter()
+            .take(MAX_RECENT)
+            .map(|(name, _)| name.as_str())
+            .collect();
+        let n_recent = suggestions.len();
+        for (name, _) in frequent {
+            if suggestions.len() == n_recent + MAX_FREQUENT {
+                break;
+            }
+            if !suggestions.contains(&name.as_str()) {
+                suggestions.push(name);
+            }
+        }
+        suggestions
+    }
+
+    fn save(&self) {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return,
+        };
+        let result = path
+            .parent()
+            .map_or(Ok(()), std::fs::create_dir_all)
+            .and_then(|_| std::fs::write(path, serde_json::to_vec(&self.functions)?));
+        if let Err(err) = result {
+            log::warn!("Failed to save history to {}: {}", path.display(), err);
+        }
+    }
+}
+
+/// History of each program is kept in its own file, named after the program
+/// and a hash of its full path
+fn history_path(program_path: &str) -> Option<PathBuf> {
+    let path = std::fs::canonicalize(program_path).unwrap_or_else(|_| program_path.into());
+    let name = Path::new(&path).file_name()?.to_string_lossy();
+    let hash = md5::compute(path.to_string_lossy().as_bytes());
+    Some(
+        cache_dir()?
+            .join("history")
+            .join(format!("{}-{:x}.json", name, hash)),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_suggestions() {
+        let mut history = History {
+            path: None,
+            functions: HashMap::new(),
+        };
+        history.record_visit(FunctionName("a"));
+        history.functions.get_mut("a").unwrap().last_visited = 1;
+        history.record_visit(FunctionName("b"));
+        history.record_trace(FunctionName("c"));
+        history.record_trace(FunctionName("a"));
+        history.record_trace(FunctionName("a"));
+        assert_eq!(history.suggestions(), vec!["b", "a", "c"]);
+    }
+}
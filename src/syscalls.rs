@@ -0,0 +1,194 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// Headers defining syscall numbers as `#define __NR_<name> <number>`, which
+/// is how syscall numbers are mapped to names when installed
+#[cfg(target_arch = "x86_64")]
+const UNISTD_HEADERS: &[&str] = &[
+    "/usr/include/asm/unistd_64.h",
+    "/usr/include/x86_64-linux-gnu/asm/unistd_64.h",
+];
+/// Newer architectures use the generic syscall numbers
+#[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
+const UNISTD_HEADERS: &[&str] = &["/usr/include/asm-generic/unistd.h"];
+#[cfg(not(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+)))]
+const UNISTD_HEADERS: &[&str] = &[];
+
+/// Common x86_64 syscalls, for when no header is installed
+#[cfg(target_arch = "x86_64")]
+const COMMON_SYSCALLS: &[(i64, &str)] = &[
+    (0, "read"),
+    (1, "write"),
+    (2, "open"),
+    (3, "close"),
+    (4, "stat"),
+    (5, "fstat"),
+    (7, "poll"),
+    (8, "lseek"),
+    (9, "mmap"),
+    (10, "mprotect"),
+    (11, "munmap"),
+    (12, "brk"),
+    (16, "ioctl"),
+    (17, "pread64"),
+    (18, "pwrite64"),
+    (19, "readv"),
+    (20, "writev"),
+    (23, "select"),
+    (24, "sched_yield"),
+    (28, "madvise"),
+    (35, "nanosleep"),
+    (41, "socket"),
+    (42, "connect"),
+    (43, "accept"),
+    (44, "sendto"),
+    (45, "recvfrom"),
+    (46, "sendmsg"),
+    (47, "recvmsg"),
+    (56, "clone"),
+    (72, "fcntl"),
+    (74, "fsync"),
+    (75, "fdatasync"),
+    (202, "futex"),
+    (217, "getdents64"),
+    (228, "clock_gettime"),
+    (230, "clock_nanosleep"),
+    (232, "epoll_wait"),
+    (257, "openat"),
+    (262, "newfstatat"),
+    (270, "pselect6"),
+    (271, "ppoll"),
+    (281, "epoll_pwait"),
+    (288, "accept4"),
+    (299, "recvmmsg"),
+    (307, "sendmmsg"),
+    (318, "getrandom"),
+    (332, "statx"),
+    (426, "io_uring_enter"),
+];
+#[cfg(not(target_arch = "x86_64"))]
+const COMMON_SYSCALLS: &[(i64, &str)] = &[];
+
+/// Time spent in a syscall, how many times it was made and how many of those
+/// failed
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SyscallTimes {
+    pub duration: Duration,
+    pub count: u64,
+    pub errors: u64,
+}
+
+/// Syscalls made by calls of a function, see `parse_syscalls`
+#[derive(Debug, Default, PartialEq)]
+pub struct Syscalls {
+    /// Total time of the calls of the function
+    pub function_time: Duration,
+    pub function_calls: u64,
+    /// Keyed by syscall number
+    pub syscalls: HashMap<i64, SyscallTimes>,
+}
+
+/// Parse the maps printed by the program from
+/// `TraceStack::get_syscalls_expr`, which look like `@syscall_time[0]: 1234`
+/// or `@function_time: 5678`
+pub fn parse_syscalls(output: &str) -> Syscalls {
+    let mut result = Syscalls::default();
+    for line in output.lines() {
+        let (name, value) = match line.split_once(": ") {
+            Some((name, value)) => match value.trim().parse::<u64>() {
+                Ok(value) => (name, value),
+                Err(_) => continue,
+            },
+            None => continue,
+        };
+        let (map, key) = match name.split_once('[') {
+            Some((map, key)) => (map, key.trim_end_matches(']').parse::<i64>().ok()),
+            None => (name, None),
+        };
+        match (map, key) {
+            ("@function_time", None) => result.function_time = Duration::from_nanos(value),
+            ("@function_calls", None) => result.function_calls = value,
+            ("@syscall_time", Some(id)) => {
+                result.syscalls.entry(id).or_default().duration = Duration::from_nanos(value)
+            }
+            ("@syscall_counts", Some(id)) => result.syscalls.entry(id).or_default().count = value,
+            ("@syscall_errors", Some(id)) => result.syscalls.entry(id).or_default().errors = value,
+            _ => (),
+        }
+    }
+    result
+}
+
+/// Names of syscalls keyed by number, from the first installed header that
+/// defines them or else the common ones of the platform
+pub fn syscall_names() -> HashMap<i64, String> {
+    UNISTD_HEADERS
+        .iter()
+        .filter_map(|path| std::fs::read_to_string(path).ok())
+        .map(|header| parse_unistd(&header))
+        .find(|names| !names.is_empty())
+        .unwrap_or_else(|| {
+            COMMON_SYSCALLS
+                .iter()
+                .map(|&(id, name)| (id, name.to_string()))
+                .collect()
+        })
+}
+
+/// Parse the `#define __NR_<name> <number>` lines of a unistd header
+fn parse_unistd(header: &str) -> HashMap<i64, String> {
+    header
+        .lines()
+        .filter_map(|line| {
+            let mut words = line.split_whitespace();
+            if words.next()? != "#define" {
+                return None;
+            }
+            let name = words.next()?.strip_prefix("__NR_")?;
+            let id = words.next()?.parse().ok()?;
+            Some((id, name.to_string()))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_syscalls() {
+        let output = "Attaching 5 probes...\n\n\n@depth[42]: 1\n@function_calls: 3\n\n@function_time: 90000\n\n@syscall_counts[0]: 4\n@syscall_counts[202]: 1\n\n@syscall_errors[0]: 1\n\n@syscall_time[0]: 40000\n@syscall_time[202]: 20000\n";
+        let syscalls = parse_syscalls(output);
+        assert_eq!(syscalls.function_calls, 3);
+        assert_eq!(syscalls.function_time, Duration::from_nanos(90000));
+        assert_eq!(
+            syscalls.syscalls[&0],
+            SyscallTimes {
+                duration: Duration::from_nanos(40000),
+                count: 4,
+                errors: 1
+            }
+        );
+        assert_eq!(
+            syscalls.syscalls[&202],
+            SyscallTimes {
+                duration: Duration::from_nanos(20000),
+                count: 1,
+                errors: 0
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_unistd() {
+        let header = "#ifndef _ASM_UNISTD_64_H\n#define _ASM_UNISTD_64_H\n\n#define __NR_read 0\n#define __NR_write 1\n#define __NR_syscalls (__NR_write + 1)\n";
+        let names = parse_unistd(header);
+        assert_eq!(names.len(), 2);
+        assert_eq!(names[&0], "read");
+        assert_eq!(names[&1], "write");
+    }
+}
@@ -0,0 +1,469 @@
+use crate::error::Error;
+use crate::program::Program;
+use crate::store;
+use object::{Object, ObjectSymbol};
+use serde::Deserialize;
+use std::fmt;
+use std::path::Path;
+use std::process::Command;
+
+/// What a template captures about each call of its functions, each as a
+/// histogram
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Capture {
+    /// Call latency, in nanoseconds
+    Latency,
+    /// The argument with the given index
+    Argument(u8),
+    /// The (signed) return value
+    ReturnValue,
+}
+
+impl Capture {
+    /// Parse a capture as written in template files: `latency`, `retval` or
+    /// `arg0` to `arg5`
+    fn parse(capture: &str) -> Result<Capture, Error> {
+        match capture {
+            "latency" => Ok(Capture::Latency),
+            "retval" => Ok(Capture::ReturnValue),
+            _ => capture
+                .strip_prefix("arg")
+                .and_then(|index| index.parse().ok())
+                .filter(|index| *index <= 5)
+                .map(Capture::Argument)
+                .ok_or_else(|| {
+                    format!(
+                        "Invalid capture {}, expected latency, retval or arg0-arg5",
+                        capture
+                    )
+                    .into()
+                }),
+        }
+    }
+}
+
+/// A canned trace of functions that are worth measuring, which can be run
+/// without setting up the trace stack
+#[derive(Clone, Debug)]
+pub struct Template {
+    pub name: String,
+    /// Prefix of the file name of the library the functions are in, e.g.
+    /// `libssl.so`. `None` if they are in the program itself.
+    library: Option<String>,
+    /// Functions to trace. In a library this is passed to bpftrace as is, so
+    /// may contain its wildcards. In the program it is matched against
+    /// (mangled or demangled) symbol names, with `*` matching anything.
+    function: String,
+    captures: Vec<Capture>,
+    /// bpftrace predicate on function entry, e.g. `arg1 > 4096`
+    filter: Option<String>,
+}
+
+/// Contents of a template file, which holds any number of `[[template]]`
+/// tables
+#[derive(Deserialize)]
+struct TemplateFile {
+    #[serde(default)]
+    template: Vec<TemplateEntry>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TemplateEntry {
+    name: String,
+    library: Option<String>,
+    function: String,
+    captures: Vec<String>,
+    filter: Option<String>,
+}
+
+impl Template {
+    fn builtin(name: &str, library: &str, function: &str, capture: Capture) -> Template {
+        Template {
+            name: name.to_string(),
+            library: Some(library.to_string()),
+            function: function.to_string(),
+            captures: vec![capture],
+            filter: None,
+        }
+    }
+
+    fn from_entry(entry: TemplateEntry) -> Result<Template, Error> {
+        if entry.captures.is_empty() {
+            return Err(format!("Template {} has no captures", entry.name).into());
+        }
+        let captures = entry
+            .captures
+            .iter()
+            .map(|capture| Capture::parse(capture))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|err| format!("Template {}: {}", entry.name, err))?;
+        Ok(Template {
+            name: entry.name,
+            library: entry.library,
+            function: entry.function,
+            captures,
+            filter: entry.filter.filter(|filter| !filter.trim().is_empty()),
+        })
+    }
+
+    /// Path of the library the template applies to, out of the libraries
+    /// the program links (see `linked_libraries`)
+    fn find_library<'a>(&self, libraries: &'a [String]) -> Option<&'a str> {
+        let library = self.library.as_deref()?;
+        libraries
+            .iter()
+            .find(|path| {
+                Path::new(path)
+                    .file_name()
+                    .map_or(false, |name| name.to_string_lossy().starts_with(library))
+            })
+            .map(|path| path.as_str())
+    }
+}
+
+/// Templates for common libraries, shipped with wachy
+pub fn builtin_templates() -> Vec<Template> {
+    vec![
+        Template::builtin(
+            "OpenSSL handshake latency",
+            "libssl.so",
+            "SSL_do_handshake",
+            Capture::Latency,
+        ),
+        Template::builtin(
+            "OpenSSL read sizes",
+            "libssl.so",
+            "SSL_read",
+            Capture::ReturnValue,
+        ),
+        Template::builtin(
+            "OpenSSL write sizes",
+            "libssl.so",
+            "SSL_write",
+            Capture::ReturnValue,
+        ),
+        Template::builtin(
+            "zlib deflate latency",
+            "libz.so",
+            "deflate",
+            Capture::Latency,
+        ),
+        Template::builtin(
+            "zlib inflate latency",
+            "libz.so",
+            "inflate",
+            Capture::Latency,
+        ),
+        Template::builtin(
+            "jemalloc allocation sizes",
+            "libjemalloc.so",
+            "malloc",
+            Capture::Argument(0),
+        ),
+        Template::builtin(
+            "jemalloc arena contention",
+            "libjemalloc.so",
+            "je_malloc_mutex_lock_slow",
+            Capture::Latency,
+        ),
+    ]
+}
+
+/// User templates, from `dir` if given and otherwise from the default
+/// directory (see `store::config_dir`) if it exists
+pub fn load_user_templates(dir: Option<&str>) -> Result<Vec<Template>, Error> {
+    match dir {
+        Some(dir) => load_dir(Path::new(dir)),
+        None => match store::config_dir().map(|dir| dir.join("templates")) {
+            Some(dir) if dir.is_dir() => load_dir(&dir),
+            _ => Ok(Vec::new()),
+        },
+    }
+}
+
+/// Load the templates in all `.toml` files in `dir`, so that a team can share
+/// what is worth measuring in their codebase by checking in a directory
+fn load_dir(dir: &Path) -> Result<Vec<Template>, Error> {
+    let entries = std::fs::read_dir(dir)
+        .map_err(|err| format!("Failed to read templates in {}: {}", dir.display(), err))?;
+    let mut paths = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().map_or(false, |ext| ext == "toml"))
+        .collect::<Vec<_>>();
+    paths.sort();
+    let mut templates = Vec::new();
+    for path in paths {
+        let contents = std::fs::read_to_string(&path)
+            .map_err(|err| format!("Failed to read {}: {}", path.display(), err))?;
+        templates
+            .extend(parse_file(&contents).map_err(|err| format!("{}: {}", path.display(), err))?);
+    }
+    Ok(templates)
+}
+
+fn parse_file(contents: &str) -> Result<Vec<Template>, Error> {
+    let file: TemplateFile =
+        toml::from_str(contents).map_err(|err| format!("Invalid template file: {}", err))?;
+    file.template
+        .into_iter()
+        .map(Template::from_entry)
+        .collect()
+}
+
+/// A template together with the binary and functions it traces
+#[derive(Clone)]
+pub struct Applicable {
+    pub template: Template,
+    path: String,
+    functions: Vec<String>,
+}
+
+impl fmt::Display for Applicable {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let template = &self.template;
+        write!(
+            f,
+            "{} ({}: {}",
+            template.name,
+            template.library.as_deref().unwrap_or("program"),
+            template.function
+        )?;
+        if self.functions.len() > 1 {
+            write!(f, ", {} functions", self.functions.len())?;
+        }
+        f.write_str(")")
+    }
+}
+
+impl Applicable {
+    /// bpftrace program that traces the functions for `seconds` and prints
+    /// the captured histograms on exit
+    pub fn program(&self, seconds: u64) -> String {
+        let probes = |kind: &str| {
+            self.functions
+                .iter()
+                .map(|function| format!("{}:{}:{}", kind, self.path, function))
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        let mut entry = Vec::new();
+        let mut exit = Vec::new();
+        for capture in &self.template.captures {
+            match capture {
+                Capture::Latency => {
+                    exit.push("@latency_ns = hist(nsecs - @start[tid]);".to_string())
+                }
+                Capture::Argument(index) => {
+                    entry.push(format!("@arg{} = hist(arg{});", index, index))
+                }
+                Capture::ReturnValue => exit.push("@retval = hist((int64)retval);".to_string()),
+            }
+        }
+        if !exit.is_empty() {
+            // Only calls that passed the filter on entry are measured on exit
+            entry.insert(0, "@start[tid] = nsecs;".to_string());
+            exit.push("delete(@start[tid]);".to_string());
+        }
+
+        let mut program = probes("uprobe");
+        if let Some(filter) = &self.template.filter {
+            program += &format!(" /{}/", filter);
+        }
+        program += &format!(" {{ {} }}", entry.join(" "));
+        if !exit.is_empty() {
+            program += &format!(
+                " {} /@start[tid]/ {{ {} }}",
+                probes("uretprobe"),
+                exit.join(" ")
+            );
+        }
+        program += &format!(" interval:s:{} {{ exit(); }}", seconds);
+        if !exit.is_empty() {
+            program += " END { clear(@start); }";
+        }
+        program
+    }
+}
+
+/// Templates out of `templates` that can be applied to `program`, given the
+/// shared libraries it links. Library templates also apply when the library
+/// was linked statically.
+pub fn applicable(
+    program: &Program,
+    libraries: &[String],
+    templates: Vec<Template>,
+) -> Vec<Applicable> {
+    templates
+        .into_iter()
+        .filter_map(|template| {
+            let (path, functions) = match template.find_library(libraries) {
+                Some(path) => (path.to_string(), vec![template.function.clone()]),
+                None => (
+                    program.file_path.clone(),
+                    matching_functions(program, &template.function),
+                ),
+            };
+            if functions.is_empty() {
+                None
+            } else {
+                Some(Applicable {
+                    template,
+                    path,
+                    functions,
+                })
+            }
+        })
+        .collect()
+}
+
+/// Mangled names of the functions in `program` matching `pattern`
+fn matching_functions(program: &Program, pattern: &str) -> Vec<String> {
+    let symbols = program.symbols_generator();
+    let mut functions: Vec<String> = symbols
+        .into_iter()
+        .filter(|symbol| {
+            glob_match(pattern, symbol.name.0) || glob_match(pattern, &symbol.display_name())
+        })
+        .map(|symbol| symbol.name.0.to_string())
+        .collect();
+    functions.sort();
+    functions
+}
+
+/// Whether `text` matches `pattern`, in which `*` matches any characters
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let mut rest = match text.strip_prefix(parts.next().unwrap()) {
+        Some(rest) => rest,
+        None => return false,
+    };
+    let parts: Vec<&str> = parts.collect();
+    match parts.split_last() {
+        // No wildcards
+        None => rest.is_empty(),
+        Some((last, middle)) => {
+            for part in middle {
+                match rest.find(part) {
+                    Some(index) => rest = &rest[index + part.len()..],
+                    None => return false,
+                }
+            }
+            rest.ends_with(last)
+        }
+    }
+}
+
+/// Paths of the shared libraries `program_path` links, according to `ldd`
+pub fn linked_libraries(program_path: &str) -> Result<Vec<String>, Error> {
+    let output = Command::new("ldd")
+        .arg(program_path)
+        .output()
+        .map_err(|err| format!("Failed to run ldd: {}", err))?;
+    // ldd fails for statically linked programs, which link nothing
+    Ok(parse_ldd(&String::from_utf8_lossy(&output.stdout)))
+}
+
+/// Whether the shared library at `path` exports `symbol`
+pub fn exports(path: &str, symbol: &str) -> bool {
+    let data = match std::fs::read(path) {
+        Ok(data) => data,
+        Err(_) => return false,
+    };
+    match object::File::parse(&*data) {
+        Ok(file) => file
+            .dynamic_symbols()
+            .any(|s| s.is_definition() && s.name() == Ok(symbol)),
+        Err(_) => false,
+    }
+}
+
+/// Lines of `ldd` output look like `libz.so.1 => /lib/libz.so.1 (0x...)`
+fn parse_ldd(output: &str) -> Vec<String> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let path = line.split_once("=>")?.1.trim();
+            let path = path.split_whitespace().next()?;
+            Some(path.to_string()).filter(|path| path.starts_with('/'))
+        })
+        .collect()
+}
+
+/// What a template program printed, without bpftrace's own messages
+pub fn parse_output(output: &str) -> String {
+    output
+        .lines()
+        .filter(|line| !line.starts_with("Attaching "))
+        .collect::<Vec<_>>()
+        .join("\n")
+        .trim()
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_ldd() {
+        let output = "\tlinux-vdso.so.1 (0x00007ffd0c5f2000)\n\tlibz.so.1 => /lib/x86_64-linux-gnu/libz.so.1 (0x00007f2c1c000000)\n\tlibmissing.so => not found\n\t/lib64/ld-linux-x86-64.so.2 (0x00007f2c1c400000)\n";
+        let libraries = parse_ldd(output);
+        assert_eq!(libraries, vec!["/lib/x86_64-linux-gnu/libz.so.1"]);
+        let templates = builtin_templates();
+        assert_eq!(
+            templates[3].find_library(&libraries),
+            Some("/lib/x86_64-linux-gnu/libz.so.1")
+        );
+        assert_eq!(templates[0].find_library(&libraries), None);
+    }
+
+    #[test]
+    fn test_program() {
+        let applicable = Applicable {
+            template: builtin_templates().remove(1),
+            path: "/lib/libssl.so.3".to_string(),
+            functions: vec!["SSL_read".to_string()],
+        };
+        assert_eq!(
+            applicable.program(10),
+            "uprobe:/lib/libssl.so.3:SSL_read { @start[tid] = nsecs; } uretprobe:/lib/libssl.so.3:SSL_read /@start[tid]/ { @retval = hist((int64)retval); delete(@start[tid]); } interval:s:10 { exit(); } END { clear(@start); }"
+        );
+    }
+
+    #[test]
+    fn test_parse_file() {
+        let contents = r#"
+[[template]]
+name = "Large RPC requests"
+function = "rpc::Server::Handle*"
+captures = ["latency", "arg2"]
+filter = "arg2 > 4096"
+"#;
+        let mut templates = parse_file(contents).unwrap();
+        assert_eq!(templates.len(), 1);
+        let applicable = Applicable {
+            template: templates.remove(0),
+            path: "/bin/server".to_string(),
+            functions: vec!["_ZN3rpc6Server6HandleEv".to_string()],
+        };
+        assert_eq!(
+            applicable.program(5),
+            "uprobe:/bin/server:_ZN3rpc6Server6HandleEv /arg2 > 4096/ { @start[tid] = nsecs; @arg2 = hist(arg2); } uretprobe:/bin/server:_ZN3rpc6Server6HandleEv /@start[tid]/ { @latency_ns = hist(nsecs - @start[tid]); delete(@start[tid]); } interval:s:5 { exit(); } END { clear(@start); }"
+        );
+
+        let invalid = "[[template]]\nname = \"x\"\nfunction = \"f\"\ncaptures = [\"arg9\"]";
+        assert!(parse_file(invalid).is_err());
+        let invalid = "[[template]]\nname = \"x\"\nfunction = \"f\"\ncaptures = []";
+        assert!(parse_file(invalid).is_err());
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("deflate", "deflate"));
+        assert!(!glob_match("deflate", "deflateEnd"));
+        assert!(glob_match("rpc::*::Handle*", "rpc::Server::Handle(int)"));
+        assert!(!glob_match("rpc::*::Handle*", "rpc::Server::Close()"));
+        assert!(glob_match("*ab*ab", "abab"));
+    }
+}
@@ -1,17 +1,24 @@
 use itertools::Itertools;
 
-use crate::bpftrace_compiler::BlockType::{Uprobe, UprobeOffset, Uretprobe};
+use crate::allocations::AllocatorKind;
+use crate::backend::TraceBackend;
+use crate::bpftrace_compiler::BlockType::{ReturnInstructions, Uprobe, UprobeOffset, Uretprobe};
 use crate::bpftrace_compiler::Expression::Printf;
-use crate::bpftrace_compiler::{self, Block, BlockType, Expression};
+use crate::bpftrace_compiler::{Block, BlockType, BpftraceProgram, Expression};
+use crate::capabilities::Capabilities;
 use crate::error::Error;
-use crate::events::{Event, TraceCumulative, TraceInfo, TraceInfoMode};
-use crate::program::FunctionName;
+use crate::events::{
+    Event, GroupCount, LineStatistics, Outlier, ReturnValueCount, TraceCumulative, TraceInfo,
+    TraceInfoMode,
+};
+use crate::native::{self, NativePlan};
+use crate::program::{self, FunctionName, Parameter, ValueType};
 use std::collections::HashMap;
-use std::process::Command;
+use std::convert::TryFrom;
 use std::process::Stdio;
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::mpsc::Sender;
-use std::sync::{Mutex, MutexGuard};
+use std::sync::{Arc, Mutex, MutexGuard};
 use std::time::Duration;
 use std::{fmt, iter};
 
@@ -19,17 +26,58 @@ use std::{fmt, iter};
 /// bpftrace programs.
 pub struct TraceStack {
     counter: AtomicU64,
-    program_path: String,
+    backend: Arc<dyn TraceBackend>,
+    capabilities: Capabilities,
     /// Stack of functions being traced
     stack: Mutex<Frames>,
 }
 
 pub struct Frames {
     mode: TraceMode,
-    /// When in Breakdown mode, trace these functions
-    breakdown_functions: Vec<FunctionName>,
-    /// Guaranteed to be non-empty
+    /// When in Breakdown mode, trace these functions. Second field is the
+    /// same as `FrameInfo::return_offsets`.
+    breakdown_functions: Vec<(FunctionName, Option<Vec<u32>>)>,
+    /// Stack of the current workspace. Guaranteed to be non-empty.
     frames: Vec<FrameInfo>,
+    /// Stacks of all workspaces, each tracing its own functions. The entry of
+    /// the current workspace is left empty while its stack is in `frames`. In
+    /// Line mode, the current function of each other workspace stays timed.
+    workspaces: Vec<Vec<FrameInfo>>,
+    /// Index of the current workspace in `workspaces`
+    current_workspace: usize,
+    /// When in Line mode, also collect a latency histogram and statistics
+    /// (see `LineStatistics`) of each traced line
+    line_histograms: bool,
+    /// When in Line mode, also measure how long each traced line spent
+    /// off-CPU, i.e. switched out by the scheduler
+    off_cpu: bool,
+    /// When in Line mode, also read hardware counters around each traced line.
+    /// Only the libbpf backend can, see `NativePlan::counters`.
+    counters: bool,
+    /// When in Line mode, only count the outermost calls of the current
+    /// function on its line rather than recursive calls too
+    outermost_calls_only: bool,
+    /// Whether stacks of the program can be moved, e.g. when the Go runtime
+    /// grows a goroutine's stack. Recursive calls are told apart by their stack
+    /// pointer, which isn't possible then.
+    movable_stacks: bool,
+    /// How many times their default size bpftrace's maps and perf buffers are,
+    /// see `losses::scaled_env`
+    buffer_scale: u32,
+    /// How often trace data is reported, see `parse_interval`
+    report_interval: Duration,
+    /// Only trace calls made by these processes
+    process_filter: ProcessFilter,
+    /// When in Arguments mode, capture these arguments
+    argument_columns: Vec<ArgumentColumn>,
+    /// When in GroupBy mode, group calls by the values of this argument
+    group_by: Option<ArgumentColumn>,
+    /// When in Line mode, also trace these lines whichever frame is current
+    pinned_traces: Vec<PinnedTrace>,
+    /// Program edited by the user, run instead of the generated one as long as
+    /// that stays the same. First field is the generated program it was edited
+    /// from.
+    edited_script: Option<(String, String)>,
     /// Gets notified whenever the stack is modified (i.e. trace command
     /// get_bpftrace_expr would change).
     tx: Sender<Event>,
@@ -39,10 +87,283 @@ pub struct Frames {
 pub enum TraceMode {
     /// Trace latency per traced line in current view
     Line,
-    /// Trace histogram of latency for the current function
-    Histogram,
+    /// Trace histogram of latency for the current function, or of the time
+    /// spent on `line` in each of its calls if it is a traced callsite rather
+    /// than the function's own line
+    Histogram { line: u32 },
     /// Trace amount of time spent in each of the specified nest functions
     Breakdown,
+    /// Count how often each caller of the current function reaches `line`,
+    /// which is either the function's own line or a traced callsite.
+    /// `function_address` is the address of the current function in the
+    /// program, used to translate return addresses at runtime back to it.
+    Callers { line: u32, function_address: u64 },
+    /// Count the values returned by the call traced on `line`, or by the
+    /// current function if it is the function's own line, along with how long
+    /// the calls returning each took
+    ReturnValues { line: u32 },
+    /// Count the most frequent values of each argument of the call traced on
+    /// `line`, or of the current function if it is the function's own line.
+    /// Which arguments are captured is set with
+    /// `TraceStack::set_argument_columns`.
+    Arguments { line: u32 },
+    /// Count the calls traced on `line` (or of the current function if it is
+    /// the function's own line) and how long they took, grouped by the value
+    /// of the argument set with `TraceStack::set_group_by`
+    GroupBy { line: u32 },
+}
+
+/// Which processes and threads are traced. Applies to the whole stack, unlike
+/// the filters of each frame. Empty fields don't filter.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ProcessFilter {
+    pub pids: Vec<u32>,
+    pub tids: Vec<u32>,
+    /// Names of threads, as in `/proc/<pid>/task/<tid>/comm`. They are looked
+    /// up among the threads of `pids` (or of any process) whenever the program
+    /// is generated, see `thread_ids`.
+    pub thread_names: Vec<String>,
+    /// Process name, as in `comm`
+    pub comm: Option<String>,
+    pub uid: Option<u32>,
+    /// Path of a cgroup v2 directory, e.g. `/sys/fs/cgroup/system.slice/nginx.service`
+    pub cgroup: Option<String>,
+    /// Also trace descendants of `pids`, including ones forked while tracing
+    pub follow_forks: bool,
+}
+
+impl ProcessFilter {
+    pub fn is_empty(&self) -> bool {
+        *self == ProcessFilter::default()
+    }
+
+    /// bpftrace predicate that holds for calls that pass the filter, `None`
+    /// if it is empty
+    fn predicate(&self) -> Option<String> {
+        let any_of = |var: &str, ids: &[u32]| {
+            let conditions = ids
+                .iter()
+                .map(|id| format!("{} == {}", var, id))
+                .join(" || ");
+            format!("({})", conditions)
+        };
+        let mut predicates = Vec::new();
+        if self.follow_forks {
+            // See `fork_tracking_blocks`
+            predicates.push("@traced_pids[pid]".to_string());
+        } else if !self.pids.is_empty() {
+            predicates.push(any_of("pid", &self.pids));
+        }
+        let tids = self.thread_ids();
+        if !tids.is_empty() {
+            predicates.push(any_of("tid", &tids));
+        } else if !self.thread_names.is_empty() {
+            // None of the threads are running
+            predicates.push("tid == 0".to_string());
+        }
+        if let Some(comm) = &self.comm {
+            predicates.push(format!("comm == {:?}", comm));
+        }
+        if let Some(uid) = self.uid {
+            predicates.push(format!("uid == {}", uid));
+        }
+        if let Some(cgroup) = &self.cgroup {
+            predicates.push(format!("cgroup == cgroupid({:?})", cgroup));
+        }
+        if predicates.is_empty() {
+            None
+        } else {
+            Some(predicates.join(" && "))
+        }
+    }
+
+    /// `tids` along with the threads named `thread_names`
+    pub fn thread_ids(&self) -> Vec<u32> {
+        let mut tids = self.tids.clone();
+        for name in &self.thread_names {
+            tids.extend(threads_named(&self.pids, name));
+        }
+        tids.sort_unstable();
+        tids.dedup();
+        tids
+    }
+
+    /// Parse each field as entered by the user, empty to not filter on it.
+    /// `pids` and `tids` are lists separated by commas or spaces, and `tids`
+    /// can also list thread names.
+    pub fn from_fields(
+        pids: &str,
+        tids: &str,
+        comm: &str,
+        uid: &str,
+        cgroup: &str,
+        follow_forks: bool,
+    ) -> Result<ProcessFilter, Error> {
+        let parse_ids = |name: &str, ids: &str| -> Result<Vec<u32>, Error> {
+            ids.split(|c: char| c == ',' || c.is_whitespace())
+                .filter(|id| !id.is_empty())
+                .map(|id| {
+                    id.parse()
+                        .map_err(|_| format!("Invalid {} '{}'", name, id).into())
+                })
+                .collect()
+        };
+        let optional = |value: &str| match value.trim() {
+            "" => None,
+            value => Some(value.to_string()),
+        };
+        // The kernel truncates process and thread names to 15 bytes
+        let valid_name = |name: &str| name.len() <= 15 && !name.contains('"');
+        let comm = optional(comm);
+        if let Some(comm) = &comm {
+            if !valid_name(comm) {
+                return Err(format!("Invalid process name '{}'", comm).into());
+            }
+        }
+        let mut thread_ids = Vec::new();
+        let mut thread_names = Vec::new();
+        for thread in tids
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|thread| !thread.is_empty())
+        {
+            match thread.parse() {
+                Ok(tid) => thread_ids.push(tid),
+                Err(_) if valid_name(thread) => thread_names.push(thread.to_string()),
+                Err(_) => return Err(format!("Invalid thread name '{}'", thread).into()),
+            }
+        }
+        let uid = match optional(uid) {
+            Some(uid) => Some(uid.parse().map_err(|_| format!("Invalid uid '{}'", uid))?),
+            None => None,
+        };
+        let cgroup = optional(cgroup);
+        if cgroup.as_ref().map_or(false, |cgroup| cgroup.contains('"')) {
+            return Err("Invalid cgroup path".into());
+        }
+        let pids = parse_ids("pid", pids)?;
+        if follow_forks && pids.is_empty() {
+            return Err("Following forks requires PIDs to follow".into());
+        }
+        Ok(ProcessFilter {
+            pids,
+            tids: thread_ids,
+            thread_names,
+            comm,
+            uid,
+            cgroup,
+            follow_forks,
+        })
+    }
+
+    /// Blocks that keep `@traced_pids` up to date with `pids` and their
+    /// descendants, when following forks. `known_pids` are those already
+    /// running when tracing starts. The pid stays the same on exec, except
+    /// when exec is called from a thread other than the main one.
+    fn fork_tracking_blocks(known_pids: &[u32]) -> Vec<Block> {
+        vec![
+            Block::new(
+                BlockType::Begin,
+                None,
+                known_pids
+                    .iter()
+                    .map(|pid| format!("@traced_pids[{}] = 1", pid))
+                    .collect(),
+            ),
+            // Also called for new threads, their entries are deleted on exit
+            Block::new(
+                BlockType::Tracepoint("sched:sched_process_fork"),
+                Some("@traced_pids[pid]".to_string()),
+                vec!["@traced_pids[args->child_pid] = 1"],
+            ),
+            Block::new(
+                BlockType::Tracepoint("sched:sched_process_exec"),
+                Some("@traced_pids[args->old_pid]".to_string()),
+                vec!["@traced_pids[args->pid] = 1"],
+            ),
+            Block::new(
+                BlockType::Tracepoint("sched:sched_process_exit"),
+                Some("@traced_pids[tid]".to_string()),
+                vec!["delete(@traced_pids[tid])"],
+            ),
+        ]
+    }
+
+    /// Each field as shown to the user, the inverse of `from_fields`
+    pub fn fields(&self) -> [String; 5] {
+        let optional = |value: &Option<String>| value.clone().unwrap_or_default();
+        [
+            self.pids.iter().join(", "),
+            self.tids
+                .iter()
+                .map(|tid| tid.to_string())
+                .chain(self.thread_names.iter().cloned())
+                .join(", "),
+            optional(&self.comm),
+            self.uid.map_or(String::new(), |uid| uid.to_string()),
+            optional(&self.cgroup),
+        ]
+    }
+
+    /// Parse the output of `Display`, e.g. `pid=12,34 comm=nginx`
+    pub fn parse(filter: &str) -> Result<ProcessFilter, Error> {
+        let mut fields: HashMap<&str, &str> = HashMap::new();
+        let mut follow_forks = false;
+        for field in filter.split_whitespace() {
+            if field == "follow-forks" {
+                follow_forks = true;
+                continue;
+            }
+            match field.split_once('=') {
+                Some((key @ "pid", value))
+                | Some((key @ "tid", value))
+                | Some((key @ "comm", value))
+                | Some((key @ "uid", value))
+                | Some((key @ "cgroup", value)) => {
+                    fields.insert(key, value);
+                }
+                _ => return Err(format!("Invalid process filter '{}'", field).into()),
+            }
+        }
+        let field = |key: &str| fields.get(key).copied().unwrap_or("");
+        ProcessFilter::from_fields(
+            field("pid"),
+            field("tid"),
+            field("comm"),
+            field("uid"),
+            field("cgroup"),
+            follow_forks,
+        )
+    }
+}
+
+impl fmt::Display for ProcessFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut fields = Vec::new();
+        if !self.pids.is_empty() {
+            fields.push(format!("pid={}", self.pids.iter().join(",")));
+        }
+        if !self.tids.is_empty() || !self.thread_names.is_empty() {
+            let threads = self
+                .tids
+                .iter()
+                .map(|tid| tid.to_string())
+                .chain(self.thread_names.iter().cloned());
+            fields.push(format!("tid={}", threads.join(",")));
+        }
+        if let Some(comm) = &self.comm {
+            fields.push(format!("comm={}", comm));
+        }
+        if let Some(uid) = self.uid {
+            fields.push(format!("uid={}", uid));
+        }
+        if let Some(cgroup) = &self.cgroup {
+            fields.push(format!("cgroup={}", cgroup));
+        }
+        if self.follow_forks {
+            fields.push("follow-forks".to_string());
+        }
+        f.write_str(&fields.join(" "))
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -58,11 +379,53 @@ pub struct FrameInfo {
     /// Function calls that are actively traced. Currently we only allow one per
     /// line.
     traced_callsites: HashMap<u32, CallInstruction>,
+    /// Traced callsites of which only 1 in this many calls are timed, by line
+    sampled_callsites: HashMap<u32, u32>,
+    /// Traced callsites of which only calls taking at least this many
+    /// nanoseconds are counted, by line
+    callsite_thresholds: HashMap<u32, u64>,
     /// bpftrace filter to apply on function entry (uprobe)
     filter: Option<String>,
     /// bpftrace filter to apply on function exit (uretprobe). Necessary to
     /// support things like `$duration` which have to be evaluated on return.
     ret_filter: Option<String>,
+    /// If set, returns are traced by probing these return instruction offsets
+    /// rather than with a uretprobe (see `Program::get_return_offsets`)
+    return_offsets: Option<Vec<u32>>,
+    /// Whether `line_to_callsites` and `unattached_callsites` were dropped to
+    /// save memory while the frame isn't the current one
+    callsites_dropped: bool,
+    /// Results of the traced lines when another frame was last pushed on top
+    /// of this one
+    cached_results: Option<CachedResults>,
+    /// Line of the frame below that this one was entered from, `None` if it
+    /// was entered some other way, e.g. with `>`
+    entered_from: Option<u32>,
+}
+
+/// Results of the traced lines of a frame, kept while other frames are pushed
+/// on top of it so they can be shown again right away once it is popped back
+/// to, until fresh results arrive
+#[derive(Debug, Clone)]
+pub struct CachedResults {
+    /// Time the trace had been running for
+    pub time: Duration,
+    pub lines: HashMap<u32, TraceCumulative>,
+    /// p99 latency of the traced lines, if line histograms were collected
+    pub p99: HashMap<u32, Duration>,
+}
+
+/// Line pinned with `P`, which stays traced while other frames are pushed and
+/// popped so its stats can be followed across functions
+#[derive(Debug, Clone)]
+pub struct PinnedTrace {
+    pub function: FunctionName,
+    pub line: u32,
+    /// Call traced on the line, `None` for the function's own line which times
+    /// its calls
+    pub callsite: Option<CallInstruction>,
+    /// Same as `FrameInfo::return_offsets`
+    pub return_offsets: Option<Vec<u32>>,
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -78,6 +441,10 @@ pub enum InstructionType {
     Register(String, Option<i64>),
     /// Manually specified start/end offset for tracing
     Manual,
+    /// Span of code of an inlined function, named by its demangled name
+    Inlined(String),
+    /// Span of code from the line the trace is on to the given line
+    Span(u32),
     /// Unknown function call - doesn't correspond to any symbols
     Unknown,
 }
@@ -88,9 +455,188 @@ pub struct CallInstruction {
     relative_ip: u32,
     /// Size of instruction
     length: u32,
+    /// Source column of the call, if known from debug info. Distinguishes
+    /// multiple calls on the same line.
+    column: Option<u32>,
+    /// Offsets (relative to start of function) at which tracing the call
+    /// ends. Empty unless control can leave it in several places, otherwise
+    /// it ends after `length`.
+    exits: Vec<u32>,
+    /// Whether this is a jump to a function at the end of the current one,
+    /// which the callee then returns to the caller from. Such a call ends
+    /// when the current function returns rather than at any offset.
+    tail_call: bool,
     pub instruction: InstructionType,
 }
 
+/// Registers integer arguments are passed in under the x86-64 System V calling
+/// convention, in order. Further arguments are passed on the stack.
+const ARGUMENT_REGISTERS: [&str; 6] = ["di", "si", "dx", "cx", "r8", "r9"];
+
+/// How many of the most frequent values of each argument are shown
+const TOP_ARGUMENT_VALUES: usize = 10;
+
+/// Default of how often trace data is reported, see `parse_interval`
+pub const DEFAULT_REPORT_INTERVAL: Duration = Duration::from_secs(1);
+/// Bounds of the report interval. Reporting more often than this costs more
+/// than it shows, and less often makes the display look stuck.
+const MIN_REPORT_INTERVAL: Duration = Duration::from_millis(100);
+const MAX_REPORT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Parse how often trace data is reported, e.g. `500ms`, `2s` or `2` (in
+/// seconds)
+pub fn parse_interval(interval: &str) -> Result<Duration, Error> {
+    let interval = interval.trim();
+    let parsed = if let Some(ms) = interval.strip_suffix("ms") {
+        ms.trim().parse().ok().map(Duration::from_millis)
+    } else {
+        interval
+            .strip_suffix('s')
+            .unwrap_or(interval)
+            .trim()
+            .parse()
+            .ok()
+            .map(Duration::from_secs)
+    };
+    match parsed {
+        Some(interval) if (MIN_REPORT_INTERVAL..=MAX_REPORT_INTERVAL).contains(&interval) => {
+            Ok(interval)
+        }
+        _ => Err(format!(
+            "Invalid interval '{}', expected e.g. 500ms or 2s, between {} and {}",
+            interval,
+            format_interval(MIN_REPORT_INTERVAL),
+            format_interval(MAX_REPORT_INTERVAL)
+        )
+        .into()),
+    }
+}
+
+/// Inverse of `parse_interval`
+pub fn format_interval(interval: Duration) -> String {
+    if interval.subsec_millis() == 0 {
+        format!("{}s", interval.as_secs())
+    } else {
+        format!("{}ms", interval.as_millis())
+    }
+}
+
+/// Argument, or field of a struct argument, captured in Arguments mode
+#[derive(Clone, Debug, PartialEq)]
+pub struct ArgumentColumn {
+    /// e.g. `name`, or `point.x` for a field
+    pub label: String,
+    pub type_name: String,
+    /// Whether values are addresses, which are shown in hex
+    pub is_address: bool,
+    /// bpftrace expression of its value when the call starts
+    expr: String,
+}
+
+impl ArgumentColumn {
+    /// Thread that made the call rather than an argument, to split calls by
+    /// thread in GroupBy mode
+    pub fn thread() -> ArgumentColumn {
+        ArgumentColumn {
+            label: "thread".to_string(),
+            type_name: "tid".to_string(),
+            is_address: false,
+            expr: "tid".to_string(),
+        }
+    }
+}
+
+impl fmt::Display for ArgumentColumn {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.type_name, self.label)
+    }
+}
+
+/// Arguments of a function with `parameters` that can be captured when it is
+/// called, from the registers they are passed in. Floating point values and
+/// anything passed on the stack are skipped.
+pub fn argument_columns(
+    return_type: Option<&ValueType>,
+    parameters: &[Parameter],
+) -> Vec<ArgumentColumn> {
+    let mut register = match return_type {
+        // Large values are returned through memory the caller passes a hidden
+        // pointer to
+        Some(ValueType::Other { size }) if *size > 16 => 1,
+        Some(ValueType::Unknown) => return Vec::new(),
+        _ => 0,
+    };
+    let mut columns = Vec::new();
+    for (i, parameter) in parameters.iter().enumerate() {
+        let label = match parameter.name.as_str() {
+            "" => format!("arg{}", i),
+            name => name.to_string(),
+        };
+        let num_registers = match &parameter.value_type {
+            ValueType::Float => continue,
+            // Registers of later arguments can't be known
+            ValueType::Unknown => break,
+            ValueType::Struct { size, .. } | ValueType::Other { size } if *size > 16 => continue,
+            ValueType::Struct { size, .. } | ValueType::Other { size } => ((size + 7) / 8) as usize,
+            ValueType::Integer { .. } | ValueType::Pointer | ValueType::CString => 1,
+        };
+        // Arguments that don't fit in the remaining registers are passed on
+        // the stack, while later ones may still fit
+        if register + num_registers > ARGUMENT_REGISTERS.len() {
+            continue;
+        }
+        match &parameter.value_type {
+            ValueType::Struct { fields, .. } => {
+                for (offset, field) in fields {
+                    columns.extend(argument_column(
+                        format!("{}.{}", label, field.name),
+                        field,
+                        ARGUMENT_REGISTERS[register + (*offset / 8) as usize],
+                        offset % 8 * 8,
+                    ));
+                }
+            }
+            _ => columns.extend(argument_column(
+                label,
+                parameter,
+                ARGUMENT_REGISTERS[register],
+                0,
+            )),
+        }
+        register += num_registers;
+    }
+    columns
+}
+
+/// Column of `parameter`, an integer or pointer passed `shift` bits into
+/// `register`
+fn argument_column(
+    label: String,
+    parameter: &Parameter,
+    register: &str,
+    shift: u64,
+) -> Option<ArgumentColumn> {
+    let value = match shift {
+        0 => format!(r#"reg("{}")"#, register),
+        shift => format!(r#"(reg("{}") >> {})"#, register, shift),
+    };
+    let (expr, is_address) = match parameter.value_type {
+        ValueType::Integer { size, signed } if [1, 2, 4, 8].contains(&size) => {
+            let sign = if signed { "" } else { "u" };
+            (format!("({}int{}){}", sign, size * 8, value), false)
+        }
+        ValueType::Pointer => (value, true),
+        ValueType::CString => (format!("str({})", value), false),
+        _ => return None,
+    };
+    Some(ArgumentColumn {
+        label,
+        type_name: parameter.type_name.clone(),
+        is_address,
+        expr,
+    })
+}
+
 #[derive(serde::Deserialize, Debug)]
 struct TraceOutput {
     time: u64,
@@ -99,6 +645,82 @@ struct TraceOutput {
     histogram: Option<String>,
     // Map from (stringified) index to (duration, count)
     breakdown: Option<HashMap<String, (u64, u64)>>,
+    callers: Option<String>,
+    // Printed `@retvals` and `@retval_durations` maps
+    retvals: Option<String>,
+    retval_durations: Option<String>,
+    // Map from (stringified) argument column to its printed map
+    args: Option<HashMap<String, String>>,
+    // Printed `@group_counts` and `@group_durations` maps
+    groups: Option<String>,
+    group_durations: Option<String>,
+    // Map from (stringified) line to time spent off-CPU
+    off_cpu: Option<HashMap<String, u64>>,
+    // Map from (stringified) line to calls that never returned
+    aborted: Option<HashMap<String, u64>>,
+    // Map from (stringified) line to printed histogram
+    histograms: Option<HashMap<String, String>>,
+    // Map from (stringified) line to (min, max, sum of squares in
+    // microseconds, calls)
+    stats: Option<HashMap<String, (u64, u64, u64, u64)>>,
+    // Map from (stringified) index of pinned line to (duration, count)
+    pinned: Option<HashMap<String, (u64, u64)>>,
+    // Map from (stringified) index of workspace to (duration, count)
+    workspaces: Option<HashMap<String, (u64, u64)>>,
+}
+
+/// Reassembles what the programs of `TraceStack` print from the JSON records
+/// of bpftrace's `-f json` output, into what `TraceStack::parse` takes. The
+/// fragments printed by `printf` are concatenated, and maps printed in between
+/// are formatted like bpftrace prints them as text (escaped, as they are
+/// printed within JSON strings).
+#[derive(Default)]
+pub struct JsonOutput {
+    report: String,
+}
+
+#[derive(serde::Deserialize)]
+struct JsonRecord {
+    #[serde(rename = "type")]
+    record_type: String,
+    data: serde_json::Value,
+}
+
+impl JsonOutput {
+    /// Add a line of output, returning the report it completes if any
+    pub fn push(&mut self, line: &str) -> Result<Option<String>, serde_json::Error> {
+        let record: JsonRecord = serde_json::from_str(line)?;
+        match (record.record_type.as_str(), &record.data) {
+            ("printf", serde_json::Value::String(text)) => {
+                // Reports are JSON objects
+                if self.report.is_empty() && !text.starts_with('{') {
+                    return Ok(None);
+                }
+                self.report.push_str(text);
+            }
+            ("hist", serde_json::Value::Object(maps))
+            | ("map", serde_json::Value::Object(maps))
+                if !self.report.is_empty() =>
+            {
+                for (name, value) in maps {
+                    let text = if record.record_type == "hist" {
+                        format_histogram(name, value)
+                    } else {
+                        format_map(name, value)
+                    };
+                    let escaped = serde_json::to_string(&text)?;
+                    self.report.push_str(&escaped[1..escaped.len() - 1]);
+                }
+            }
+            // Attached probes, maps printed on exit, etc.
+            _ => return Ok(None),
+        }
+        if !self.report.ends_with('\n') {
+            return Ok(None);
+        }
+        let report = std::mem::take(&mut self.report);
+        Ok(Some(report.trim_end().to_string()))
+    }
 }
 
 impl FrameInfo {
@@ -108,6 +730,7 @@ impl FrameInfo {
         source_line: u32,
         line_to_callsites: HashMap<u32, Vec<CallInstruction>>,
         unattached_callsites: Vec<CallInstruction>,
+        return_offsets: Option<Vec<u32>>,
     ) -> FrameInfo {
         FrameInfo {
             function,
@@ -116,8 +739,14 @@ impl FrameInfo {
             line_to_callsites,
             unattached_callsites,
             traced_callsites: HashMap::new(),
+            sampled_callsites: HashMap::new(),
+            callsite_thresholds: HashMap::new(),
             filter: None,
             ret_filter: None,
+            return_offsets,
+            callsites_dropped: false,
+            cached_results: None,
+            entered_from: None,
         }
     }
 
@@ -126,6 +755,52 @@ impl FrameInfo {
         self.line_to_callsites.keys().map(|l| *l).collect()
     }
 
+    /// Source line numbers with a traced call, in order
+    pub fn traced_lines(&self) -> Vec<u32> {
+        self.traced_callsites.keys().copied().sorted().collect()
+    }
+
+    /// Traced lines timing a callee, i.e. a call or an inlined function, that
+    /// isn't within the code of another inlined function traced, so that their
+    /// times can be added up without counting any twice. Spans of lines and
+    /// manual ranges time the function's own code, so aren't callees, but
+    /// calls within them are.
+    pub fn callee_lines(&self) -> Vec<u32> {
+        let inlined: Vec<&CallInstruction> = self
+            .traced_callsites
+            .values()
+            .filter(|ci| matches!(ci.instruction, InstructionType::Inlined(_)))
+            .collect();
+        self.traced_callsites
+            .iter()
+            .filter(|(_, ci)| {
+                !matches!(
+                    ci.instruction,
+                    InstructionType::Span(_) | InstructionType::Manual
+                )
+            })
+            .filter(|(_, ci)| !inlined.iter().any(|outer| outer.contains(ci)))
+            .map(|(&line, _)| line)
+            .sorted()
+            .collect()
+    }
+
+    /// Source line numbers with calls that were inlined
+    pub fn inlined_lines(&self) -> Vec<u32> {
+        self.line_to_callsites
+            .iter()
+            .filter(|(_, cis)| {
+                cis.iter()
+                    .any(|ci| matches!(ci.instruction, InstructionType::Inlined(_)))
+            })
+            .map(|(l, _)| *l)
+            .collect()
+    }
+
+    pub fn get_function(&self) -> FunctionName {
+        self.function
+    }
+
     pub fn get_source_file(&self) -> &str {
         &self.source_file
     }
@@ -134,6 +809,14 @@ impl FrameInfo {
         self.source_line
     }
 
+    pub fn is_traced(&self, line: u32) -> bool {
+        self.traced_callsites.contains_key(&line)
+    }
+
+    pub fn get_traced_callsite(&self, line: u32) -> Option<&CallInstruction> {
+        self.traced_callsites.get(&line)
+    }
+
     /// Get largest line number for a callsite in this frame
     pub fn max_line(&self) -> u32 {
         self.line_to_callsites
@@ -141,13 +824,60 @@ impl FrameInfo {
             .max()
             .map_or(self.source_line, |l| *l)
     }
+
+    /// Whether the callsites need to be found again, see
+    /// `TraceStack::restore_callsites`
+    pub fn callsites_dropped(&self) -> bool {
+        self.callsites_dropped
+    }
+
+    /// See `TraceStack::cache_results`
+    pub fn get_cached_results(&self) -> Option<&CachedResults> {
+        self.cached_results.as_ref()
+    }
+
+    pub fn get_entered_from(&self) -> Option<u32> {
+        self.entered_from
+    }
+
+    pub fn set_entered_from(&mut self, line: Option<u32>) {
+        self.entered_from = line;
+    }
+
+    /// Rough number of bytes used by the callsites found in the function
+    fn callsites_memory_usage(&self) -> usize {
+        let callsites = self
+            .line_to_callsites
+            .values()
+            .chain(iter::once(&self.unattached_callsites));
+        callsites
+            .map(|cis| {
+                std::mem::size_of::<(u32, Vec<CallInstruction>)>()
+                    + cis.iter().map(CallInstruction::memory_usage).sum::<usize>()
+            })
+            .sum()
+    }
 }
 
 impl CallInstruction {
+    fn memory_usage(&self) -> usize {
+        let name_len = match &self.instruction {
+            InstructionType::Register(register, _) => register.capacity(),
+            InstructionType::Inlined(name) => name.capacity(),
+            _ => 0,
+        };
+        std::mem::size_of::<CallInstruction>()
+            + self.exits.capacity() * std::mem::size_of::<u32>()
+            + name_len
+    }
+
     pub fn dynamic_symbol(relative_ip: u32, length: u8, function: FunctionName) -> CallInstruction {
         CallInstruction {
             relative_ip,
             length: length as u32,
+            column: None,
+            exits: Vec::new(),
+            tail_call: false,
             instruction: InstructionType::DynamicSymbol(function),
         }
     }
@@ -156,6 +886,9 @@ impl CallInstruction {
         CallInstruction {
             relative_ip,
             length: length as u32,
+            column: None,
+            exits: Vec::new(),
+            tail_call: false,
             instruction: InstructionType::Function(function),
         }
     }
@@ -169,6 +902,9 @@ impl CallInstruction {
         CallInstruction {
             relative_ip,
             length: length as u32,
+            column: None,
+            exits: Vec::new(),
+            tail_call: false,
             instruction: InstructionType::Register(register, displacement),
         }
     }
@@ -177,14 +913,113 @@ impl CallInstruction {
         CallInstruction {
             relative_ip,
             length,
+            column: None,
+            exits: Vec::new(),
+            tail_call: false,
             instruction: InstructionType::Manual,
         }
     }
 
+    pub fn inlined(span: &program::InlinedSpan) -> CallInstruction {
+        CallInstruction {
+            relative_ip: span.start,
+            length: span.end - span.start,
+            column: span.call_column,
+            exits: span.exits.clone(),
+            tail_call: false,
+            instruction: InstructionType::Inlined(span.name.clone()),
+        }
+    }
+
+    pub fn line_span(span: &program::LineSpan, end_line: u32) -> CallInstruction {
+        CallInstruction {
+            relative_ip: span.start,
+            length: span.end - span.start,
+            column: None,
+            exits: span.exits.clone(),
+            tail_call: false,
+            instruction: InstructionType::Span(end_line),
+        }
+    }
+
+    pub fn with_column(self, column: Option<u32>) -> CallInstruction {
+        CallInstruction { column, ..self }
+    }
+
+    pub fn as_tail_call(self) -> CallInstruction {
+        CallInstruction {
+            tail_call: true,
+            ..self
+        }
+    }
+
+    pub fn is_tail_call(&self) -> bool {
+        self.tail_call
+    }
+
+    pub fn get_column(&self) -> Option<u32> {
+        self.column
+    }
+
+    pub fn get_relative_ip(&self) -> u32 {
+        self.relative_ip
+    }
+
+    pub fn get_length(&self) -> u32 {
+        self.length
+    }
+
+    /// Whether `other` starts within the code this times, and is something
+    /// smaller than it
+    pub fn contains(&self, other: &CallInstruction) -> bool {
+        let end = self.relative_ip + self.length;
+        self.relative_ip <= other.relative_ip
+            && other.relative_ip + other.length <= end
+            && (self.relative_ip, self.length) != (other.relative_ip, other.length)
+    }
+
+    /// bpftrace expression of the address an indirect call jumps to, when
+    /// evaluated at the call instruction. `None` for direct calls.
+    pub fn get_target_expr(&self) -> Option<String> {
+        let (register, displacement) = match &self.instruction {
+            InstructionType::Register(register, displacement) => (register, displacement),
+            _ => return None,
+        };
+        // Zydis names registers e.g. rax or r8, bpftrace ax or r8
+        let register = match register.strip_prefix('r') {
+            Some(name) if name.parse::<u8>().is_err() => name,
+            _ => register.as_str(),
+        };
+        Some(match displacement {
+            // Relative to the next instruction
+            Some(displacement) if register == "ip" => format!(
+                r#"*(uint64 *)(reg("ip") + {})"#,
+                i64::from(self.length) + displacement
+            ),
+            Some(displacement) => format!(r#"*(uint64 *)(reg("{}") + {})"#, register, displacement),
+            None => format!(r#"reg("{}")"#, register),
+        })
+    }
+
+    /// Offsets (relative to start of function) at which tracing the call
+    /// ends. Empty for tail calls, which end when the function returns.
+    pub fn get_end_offsets(&self) -> Vec<u32> {
+        if self.tail_call {
+            Vec::new()
+        } else if self.exits.is_empty() {
+            vec![self.relative_ip + self.length]
+        } else {
+            self.exits.clone()
+        }
+    }
+
     pub fn unknown(relative_ip: u32, length: u8) -> CallInstruction {
         CallInstruction {
             relative_ip,
             length: length as u32,
+            column: None,
+            exits: Vec::new(),
+            tail_call: false,
             instruction: InstructionType::Unknown,
         }
     }
@@ -193,6 +1028,13 @@ impl CallInstruction {
 impl fmt::Display for CallInstruction {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.write_fmt(format_args!("{}: ", self.relative_ip))?;
+        if let Some(column) = self.column {
+            // Marks which of several calls on the line this is
+            f.write_fmt(format_args!("[col {}] ", column))?;
+        }
+        if self.tail_call {
+            f.write_str("(tail) ")?;
+        }
         let i = &self.instruction;
         match i {
             InstructionType::DynamicSymbol(_) => f.write_fmt(format_args!("(D) {}", i)),
@@ -204,6 +1046,8 @@ impl fmt::Display for CallInstruction {
                 self.relative_ip + self.length
             )),
             InstructionType::Unknown => f.write_fmt(format_args!("{}", i)),
+            InstructionType::Inlined(_) => f.write_fmt(format_args!("(inlined) {}", i)),
+            InstructionType::Span(_) => f.write_fmt(format_args!("(span) {}", i)),
         }
     }
 }
@@ -219,30 +1063,65 @@ impl fmt::Display for InstructionType {
             },
             InstructionType::Manual => f.write_str("(Manual)"),
             InstructionType::Unknown => f.write_str("(UNKNOWN)"),
+            InstructionType::Inlined(name) => f.write_str(name),
+            InstructionType::Span(end_line) => f.write_fmt(format_args!("to line {}", end_line)),
         }
     }
 }
 
 impl TraceStack {
-    pub fn new(program_path: String, frame: FrameInfo, tx: Sender<Event>) -> TraceStack {
+    pub fn new(
+        backend: Arc<dyn TraceBackend>,
+        capabilities: Capabilities,
+        frame: FrameInfo,
+        tx: Sender<Event>,
+    ) -> TraceStack {
         let stack = Mutex::new(Frames {
             mode: TraceMode::Line,
             breakdown_functions: Vec::new(),
             frames: vec![frame],
+            workspaces: vec![Vec::new()],
+            current_workspace: 0,
+            line_histograms: false,
+            off_cpu: false,
+            counters: false,
+            outermost_calls_only: false,
+            movable_stacks: false,
+            buffer_scale: 1,
+            report_interval: DEFAULT_REPORT_INTERVAL,
+            process_filter: ProcessFilter::default(),
+            argument_columns: Vec::new(),
+            group_by: None,
+            pinned_traces: Vec::new(),
+            edited_script: None,
             tx,
         });
         TraceStack {
             counter: AtomicU64::new(0),
-            program_path,
+            backend,
+            capabilities,
             stack,
         }
     }
 
+    pub fn get_backend(&self) -> &dyn TraceBackend {
+        &*self.backend
+    }
+
+    pub fn get_capabilities(&self) -> &Capabilities {
+        &self.capabilities
+    }
+
     pub fn get_current_function(&self) -> FunctionName {
         let guard = self.stack.lock().unwrap();
         guard.frames.last().unwrap().function
     }
 
+    pub fn get_current_frame(&self) -> FrameInfo {
+        let guard = self.stack.lock().unwrap();
+        guard.frames.last().unwrap().clone()
+    }
+
     pub fn get_callsites(&self, line: u32) -> Vec<CallInstruction> {
         let guard = self.stack.lock().unwrap();
         let callsites = guard
@@ -265,20 +1144,56 @@ impl TraceStack {
     }
 
     /// Note: does not update counter as any existing trace data is presumed to still be valid
-    pub fn add_callsite(&self, line: u32, ci: CallInstruction) {
+    /// Start tracing `ci` on `line` of the current function. Fails if that
+    /// would attach more probes than the backend supports.
+    pub fn add_callsite(&self, line: u32, ci: CallInstruction) -> Result<(), Error> {
+        self.add_callsites(vec![(line, ci)])
+    }
+
+    /// Start tracing each of `callsites` of the current function, restarting
+    /// the trace once for all of them. Stops at the first one that would
+    /// attach more probes than the backend supports, keeping those before it.
+    pub fn add_callsites(&self, callsites: Vec<(u32, CallInstruction)>) -> Result<(), Error> {
         let mut guard = self.stack.lock().unwrap();
-        let top_frame = guard.frames.last_mut().unwrap();
-        assert!(
-            ci.instruction == InstructionType::Manual
-                || top_frame
+        let mut result = Ok(());
+        let mut added = false;
+        for (line, ci) in callsites {
+            let top_frame = guard.frames.last_mut().unwrap();
+            assert!(
+                matches!(
+                    ci.instruction,
+                    InstructionType::Manual | InstructionType::Span(_)
+                ) || top_frame
                     .line_to_callsites
                     .get(&line)
                     .map_or(false, |cis| cis.contains(&ci))
-                || top_frame.unattached_callsites.contains(&ci)
-        );
-        log::info!("Tracing callsite {}", ci);
-        top_frame.traced_callsites.insert(line, ci);
-        guard.tx.send(Event::TraceCommandModified).unwrap();
+                    || top_frame.unattached_callsites.contains(&ci)
+            );
+            log::info!("Tracing callsite {}", ci);
+            top_frame.traced_callsites.insert(line, ci);
+            if let Some(max_probes) = self.capabilities.max_probes {
+                let num_probes = self.get_program_locked(&guard).num_probes();
+                if num_probes > max_probes {
+                    guard
+                        .frames
+                        .last_mut()
+                        .unwrap()
+                        .traced_callsites
+                        .remove(&line);
+                    result = Err(format!(
+                        "Tracing line {} needs {} probes, more than the limit of {}. Stop tracing other lines first.",
+                        line, num_probes, max_probes
+                    )
+                    .into());
+                    break;
+                }
+            }
+            added = true;
+        }
+        if added {
+            guard.tx.send(Event::TraceCommandModified).unwrap();
+        }
+        result
     }
 
     fn command_modified(&self, guard: MutexGuard<Frames>) {
@@ -291,6 +1206,8 @@ impl TraceStack {
         let mut guard = self.stack.lock().unwrap();
         let top_frame = guard.frames.last_mut().unwrap();
         if top_frame.traced_callsites.remove(&line).is_some() {
+            top_frame.sampled_callsites.remove(&line);
+            top_frame.callsite_thresholds.remove(&line);
             self.command_modified(guard);
             true
         } else {
@@ -298,6 +1215,161 @@ impl TraceStack {
         }
     }
 
+    /// Only time 1 in `rate` calls on the traced `line` of the current
+    /// function, chosen at random, to bound the work done for very frequent
+    /// calls. Rate 1 times all of them again. Checks that the backend supports
+    /// it, returning its error message if not.
+    pub fn set_callsite_sampling(&self, line: u32, rate: u32) -> Result<(), Error> {
+        let mut guard = self.stack.lock().unwrap();
+        let top_frame = guard.frames.last_mut().unwrap();
+        if !top_frame.traced_callsites.contains_key(&line) {
+            return Err(format!("Line {} is not traced", line).into());
+        }
+        if rate <= 1 {
+            top_frame.sampled_callsites.remove(&line);
+            self.command_modified(guard);
+            return Ok(());
+        }
+        let prev_rate = top_frame.sampled_callsites.insert(line, rate);
+        let output = self
+            .backend
+            .check_command(&self.get_bpftrace_expr_locked(&guard).0)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .output()
+            .unwrap_or_else(|err| panic!("{} failed to start: {}", self.backend.name(), err));
+        if !output.status.success() {
+            let sampled_callsites = &mut guard.frames.last_mut().unwrap().sampled_callsites;
+            match prev_rate {
+                Some(prev_rate) => sampled_callsites.insert(line, prev_rate),
+                None => sampled_callsites.remove(&line),
+            };
+            Err(String::from_utf8_lossy(&output.stderr).into_owned().into())
+        } else {
+            self.command_modified(guard);
+            Ok(())
+        }
+    }
+
+    /// 1 in how many calls on `line` of the current function are timed
+    pub fn get_callsite_sampling(&self, line: u32) -> u32 {
+        let guard = self.stack.lock().unwrap();
+        let top_frame = guard.frames.last().unwrap();
+        top_frame.sampled_callsites.get(&line).copied().unwrap_or(1)
+    }
+
+    /// Only count calls on the traced `line` of the current function that take
+    /// at least `min_duration`, so that its latency and frequency are of the
+    /// slow calls. `None` counts all calls again.
+    pub fn set_callsite_threshold(
+        &self,
+        line: u32,
+        min_duration: Option<Duration>,
+    ) -> Result<(), Error> {
+        let mut guard = self.stack.lock().unwrap();
+        let top_frame = guard.frames.last_mut().unwrap();
+        if !top_frame.traced_callsites.contains_key(&line) {
+            return Err(format!("Line {} is not traced", line).into());
+        }
+        match min_duration {
+            Some(min_duration) => top_frame
+                .callsite_thresholds
+                .insert(line, min_duration.as_nanos() as u64),
+            None => top_frame.callsite_thresholds.remove(&line),
+        };
+        self.command_modified(guard);
+        Ok(())
+    }
+
+    /// Shortest duration of calls on `line` of the current function that are
+    /// counted, if only slow calls are
+    pub fn get_callsite_threshold(&self, line: u32) -> Option<Duration> {
+        let guard = self.stack.lock().unwrap();
+        let top_frame = guard.frames.last().unwrap();
+        top_frame
+            .callsite_thresholds
+            .get(&line)
+            .map(|&nanos| Duration::from_nanos(nanos))
+    }
+
+    /// Scale the counts and durations of sampled lines in trace data of the
+    /// current trace up to estimates for all calls
+    pub fn scale_sampled_lines(
+        &self,
+        lines: &mut HashMap<u32, TraceCumulative>,
+        off_cpu: &mut HashMap<u32, Duration>,
+    ) {
+        let guard = self.stack.lock().unwrap();
+        let top_frame = guard.frames.last().unwrap();
+        for (line, &rate) in &top_frame.sampled_callsites {
+            if let Some(trace) = lines.get_mut(line) {
+                trace.count *= u64::from(rate);
+                trace.duration *= rate;
+            }
+            if let Some(duration) = off_cpu.get_mut(line) {
+                *duration *= rate;
+            }
+        }
+    }
+
+    /// Keep `results` of the current frame, to be shown again when it is
+    /// popped back to (see `FrameInfo::get_cached_results`)
+    pub fn cache_results(&self, results: CachedResults) {
+        let mut guard = self.stack.lock().unwrap();
+        guard.frames.last_mut().unwrap().cached_results = Some(results);
+    }
+
+    /// Pin `line` of the current function so it stays traced in every frame,
+    /// or unpin it if it is already pinned. Returns whether it is now pinned.
+    /// Only traced lines and the function's own line can be pinned.
+    pub fn toggle_pinned_trace(&self, line: u32) -> Result<bool, Error> {
+        let mut guard = self.stack.lock().unwrap();
+        let function = guard.frames.last().unwrap().function;
+        if let Some(i) = guard
+            .pinned_traces
+            .iter()
+            .position(|p| p.function == function && p.line == line)
+        {
+            guard.pinned_traces.remove(i);
+            self.command_modified(guard);
+            return Ok(false);
+        }
+        let top_frame = guard.frames.last().unwrap();
+        let callsite = if line == top_frame.source_line {
+            None
+        } else {
+            match top_frame.traced_callsites.get(&line) {
+                Some(ci) if ci.is_tail_call() => {
+                    return Err("Tail calls can't be pinned".into());
+                }
+                Some(ci) => Some(ci.clone()),
+                None => {
+                    return Err(format!("Line {} must be traced to pin it", line).into());
+                }
+            }
+        };
+        let return_offsets = top_frame.return_offsets.clone();
+        guard.pinned_traces.push(PinnedTrace {
+            function,
+            line,
+            callsite,
+            return_offsets,
+        });
+        if let Some(max_probes) = self.capabilities.max_probes {
+            let num_probes = self.get_program_locked(&guard).num_probes();
+            if num_probes > max_probes {
+                guard.pinned_traces.pop();
+                return Err(format!(
+                    "Pinning line {} needs {} probes, more than the limit of {}. Unpin other lines first.",
+                    line, num_probes, max_probes
+                )
+                .into());
+            }
+        }
+        self.command_modified(guard);
+        Ok(true)
+    }
+
     pub fn push(&self, frame: FrameInfo) {
         let mut guard = self.stack.lock().unwrap();
         // TODO prevent recursive (or do we need to?)
@@ -319,12 +1391,182 @@ impl TraceStack {
         Some(frame)
     }
 
-    pub fn set_mode(&self, mode: TraceMode) {
+    /// All frames of the stack, outermost first
+    pub fn get_frames(&self) -> Vec<FrameInfo> {
+        let guard = self.stack.lock().unwrap();
+        guard.frames.clone()
+    }
+
+    /// Start a new workspace tracing `frame`, and switch to it
+    pub fn add_workspace(&self, frame: FrameInfo) {
+        let mut guard = self.stack.lock().unwrap();
+        guard.workspaces.push(vec![frame]);
+        let index = guard.workspaces.len() - 1;
+        TraceStack::switch_workspace_locked(&mut guard, index);
+        self.command_modified(guard);
+    }
+
+    /// Switch to the workspace at `index`, returning its current frame
+    pub fn switch_workspace(&self, index: usize) -> FrameInfo {
+        let mut guard = self.stack.lock().unwrap();
+        if index == guard.current_workspace {
+            return guard.frames.last().unwrap().clone();
+        }
+        TraceStack::switch_workspace_locked(&mut guard, index);
+        let frame = guard.frames.last().unwrap().clone();
+        self.command_modified(guard);
+        frame
+    }
+
+    fn switch_workspace_locked(guard: &mut MutexGuard<Frames>, index: usize) {
+        let current = guard.current_workspace;
+        let frames = std::mem::take(&mut guard.workspaces[index]);
+        let previous = std::mem::replace(&mut guard.frames, frames);
+        guard.workspaces[current] = previous;
+        guard.current_workspace = index;
+    }
+
+    /// Close the current workspace, if it is not the last one, and switch to
+    /// the one before it. Returns the current frame of that workspace.
+    pub fn close_workspace(&self) -> Option<FrameInfo> {
+        let mut guard = self.stack.lock().unwrap();
+        if guard.workspaces.len() == 1 {
+            return None;
+        }
+        let closed = guard.current_workspace;
+        let index = if closed == 0 { 1 } else { closed - 1 };
+        TraceStack::switch_workspace_locked(&mut guard, index);
+        guard.workspaces.remove(closed);
+        if closed < guard.current_workspace {
+            guard.current_workspace -= 1;
+        }
+        let frame = guard.frames.last().unwrap().clone();
+        self.command_modified(guard);
+        Some(frame)
+    }
+
+    /// Current function of each workspace, along with the index of the
+    /// current workspace
+    pub fn get_workspaces(&self) -> (Vec<FunctionName>, usize) {
+        let guard = self.stack.lock().unwrap();
+        let functions = guard
+            .workspaces
+            .iter()
+            .enumerate()
+            .map(|(i, frames)| {
+                if i == guard.current_workspace {
+                    guard.frames.last().unwrap().function
+                } else {
+                    frames.last().unwrap().function
+                }
+            })
+            .collect();
+        (functions, guard.current_workspace)
+    }
+
+    /// Estimated bytes used by the callsites of all frames
+    pub fn frames_memory_usage(&self) -> usize {
+        let guard = self.stack.lock().unwrap();
+        guard
+            .frames
+            .iter()
+            .map(FrameInfo::callsites_memory_usage)
+            .sum()
+    }
+
+    /// Drop the callsites found in all frames but the current one, they only
+    /// matter once the frame is popped back to. Traced callsites are kept.
+    /// Returns the number of frames dropped.
+    pub fn drop_frame_caches(&self) -> usize {
+        let mut guard = self.stack.lock().unwrap();
+        let len = guard.frames.len();
+        let mut dropped = 0;
+        for frame in &mut guard.frames[..len - 1] {
+            if !frame.callsites_dropped {
+                frame.line_to_callsites = HashMap::new();
+                frame.unattached_callsites = Vec::new();
+                frame.callsites_dropped = true;
+                dropped += 1;
+            }
+        }
+        dropped
+    }
+
+    /// Restore the callsites of the current frame from `frame`, a newly
+    /// created frame of the same function, after they were dropped with
+    /// `drop_frame_caches`. Returns the current frame.
+    pub fn restore_callsites(&self, frame: FrameInfo) -> FrameInfo {
+        let mut guard = self.stack.lock().unwrap();
+        let top_frame = guard.frames.last_mut().unwrap();
+        assert!(top_frame.function == frame.function);
+        top_frame.line_to_callsites = frame.line_to_callsites;
+        top_frame.unattached_callsites = frame.unattached_callsites;
+        top_frame.callsites_dropped = false;
+        top_frame.clone()
+    }
+
+    pub fn get_mode(&self) -> TraceMode {
+        self.stack.lock().unwrap().mode
+    }
+
+    pub fn set_mode(&self, mode: TraceMode) {
         let mut guard = self.stack.lock().unwrap();
         guard.mode = mode;
         self.command_modified(guard);
     }
 
+    pub fn set_line_histograms(&self, enabled: bool) {
+        let mut guard = self.stack.lock().unwrap();
+        guard.line_histograms = enabled;
+        self.command_modified(guard);
+    }
+
+    pub fn set_off_cpu(&self, enabled: bool) {
+        let mut guard = self.stack.lock().unwrap();
+        guard.off_cpu = enabled;
+        self.command_modified(guard);
+    }
+
+    pub fn set_counters(&self, enabled: bool) {
+        let mut guard = self.stack.lock().unwrap();
+        guard.counters = enabled;
+        self.command_modified(guard);
+    }
+
+    pub fn set_outermost_calls_only(&self, enabled: bool) {
+        let mut guard = self.stack.lock().unwrap();
+        guard.outermost_calls_only = enabled;
+        self.command_modified(guard);
+    }
+
+    pub fn set_movable_stacks(&self, movable: bool) {
+        let mut guard = self.stack.lock().unwrap();
+        guard.movable_stacks = movable;
+        self.command_modified(guard);
+    }
+
+    pub fn set_buffer_scale(&self, scale: u32) {
+        let mut guard = self.stack.lock().unwrap();
+        guard.buffer_scale = scale;
+        self.command_modified(guard);
+    }
+
+    pub fn get_buffer_scale(&self) -> u32 {
+        let guard = self.stack.lock().unwrap();
+        guard.buffer_scale
+    }
+
+    pub fn set_report_interval(&self, interval: Duration) {
+        let mut guard = self.stack.lock().unwrap();
+        guard.report_interval = interval;
+        self.command_modified(guard);
+    }
+
+    pub fn get_report_interval(&self) -> Duration {
+        let guard = self.stack.lock().unwrap();
+        guard.report_interval
+    }
+
     pub fn get_current_filter(&self, is_ret_filter: bool) -> Option<String> {
         let mut guard = self.stack.lock().unwrap();
         if is_ret_filter {
@@ -354,13 +1596,14 @@ impl TraceStack {
 
         let prev_filter = frame_filter.clone();
         *frame_filter = Some(filter);
-        // Run bpftrace in dry run mode to ensure filter compiles
-        let output = bpftrace_cmd()
-            .args(&["-d", "-e", &self.get_bpftrace_expr_locked(&guard).0])
+        // Run tracer in dry run mode to ensure filter compiles
+        let output = self
+            .backend
+            .check_command(&self.get_bpftrace_expr_locked(&guard).0)
             .stdout(Stdio::piped())
             .stderr(Stdio::piped())
             .output()
-            .expect("bpftrace failed to start");
+            .unwrap_or_else(|err| panic!("{} failed to start: {}", self.backend.name(), err));
         if !output.status.success() {
             // Restore old filter on error. Can't reference `frame_filter`
             // directly here due to lifetimes.
@@ -376,25 +1619,272 @@ impl TraceStack {
         }
     }
 
-    pub fn add_breakdown_function(&self, function: FunctionName) {
+    pub fn get_process_filter(&self) -> ProcessFilter {
+        self.stack.lock().unwrap().process_filter.clone()
+    }
+
+    /// Only trace calls made by processes and threads that pass `filter`.
+    /// Checks that the backend supports the filter, returning its error
+    /// message if not.
+    pub fn set_process_filter(&self, filter: ProcessFilter) -> Result<(), Error> {
+        if filter.follow_forks
+            && !(self.backend.is_local_bpftrace() || self.backend.name() == "android")
+        {
+            return Err(format!(
+                "Following forks is not supported with the {} backend",
+                self.backend.name()
+            )
+            .into());
+        }
+        if !filter.thread_names.is_empty() {
+            // Names are looked up in our own `/proc`
+            if !self.backend.is_local_bpftrace() {
+                return Err(format!(
+                    "Filtering by thread name is not supported with the {} backend",
+                    self.backend.name()
+                )
+                .into());
+            }
+            if let Some(name) = filter
+                .thread_names
+                .iter()
+                .find(|name| threads_named(&filter.pids, name).is_empty())
+            {
+                return Err(format!("No running thread is named '{}'", name).into());
+            }
+        }
+        let mut guard = self.stack.lock().unwrap();
+        let prev_filter = std::mem::replace(&mut guard.process_filter, filter);
+        if guard.process_filter.is_empty() {
+            self.command_modified(guard);
+            return Ok(());
+        }
+        let output = self
+            .backend
+            .check_command(&self.get_bpftrace_expr_locked(&guard).0)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .output()
+            .unwrap_or_else(|err| panic!("{} failed to start: {}", self.backend.name(), err));
+        if !output.status.success() {
+            guard.process_filter = prev_filter;
+            Err(String::from_utf8_lossy(&output.stderr).into_owned().into())
+        } else {
+            self.command_modified(guard);
+            Ok(())
+        }
+    }
+
+    pub fn add_breakdown_function(&self, function: FunctionName, return_offsets: Option<Vec<u32>>) {
+        let mut guard = self.stack.lock().unwrap();
+        guard.breakdown_functions.push((function, return_offsets));
+    }
+
+    /// Set the arguments captured in Arguments mode, see `argument_columns`
+    pub fn set_argument_columns(&self, columns: Vec<ArgumentColumn>) {
+        let mut guard = self.stack.lock().unwrap();
+        guard.argument_columns = columns;
+    }
+
+    pub fn get_argument_columns(&self) -> Vec<ArgumentColumn> {
+        let guard = self.stack.lock().unwrap();
+        guard.argument_columns.clone()
+    }
+
+    /// Set the argument calls are grouped by in GroupBy mode
+    pub fn set_group_by(&self, column: ArgumentColumn) {
         let mut guard = self.stack.lock().unwrap();
-        guard.breakdown_functions.push(function);
+        guard.group_by = Some(column);
+    }
+
+    pub fn get_group_by(&self) -> Option<ArgumentColumn> {
+        let guard = self.stack.lock().unwrap();
+        guard.group_by.clone()
     }
 
     pub fn get_breakdown_functions(&self) -> Vec<FunctionName> {
         let guard = self.stack.lock().unwrap();
-        guard.breakdown_functions.clone()
+        guard
+            .breakdown_functions
+            .iter()
+            .map(|(function, _)| *function)
+            .collect()
     }
 
     /// Get appropriate bpftrace expression for current state, along with
-    /// current counter value.
+    /// current counter value. This is the program edited by the user if there
+    /// is one for the current state, see `set_edited_script`.
     /// Panics if called with empty stack
     pub fn get_bpftrace_expr(&self) -> (String, u64) {
         let guard = self.stack.lock().unwrap();
-        self.get_bpftrace_expr_locked(&guard)
+        let (expr, counter) = self.get_bpftrace_expr_locked(&guard);
+        match &guard.edited_script {
+            Some((generated, edited)) if *generated == expr => (edited.clone(), counter),
+            _ => (expr, counter),
+        }
+    }
+
+    /// Whether the program edited by the user is run, rather than the
+    /// generated one
+    pub fn is_script_edited(&self) -> bool {
+        let guard = self.stack.lock().unwrap();
+        match &guard.edited_script {
+            Some((generated, _)) => *generated == self.get_bpftrace_expr_locked(&guard).0,
+            None => false,
+        }
+    }
+
+    /// Run `script` instead of the generated program, until the trace stack
+    /// changes in a way that changes the generated program. `None` goes back
+    /// to the generated program. Checks that it is valid bpftrace syntax,
+    /// returning a descriptive error message if not. wachy only shows what it
+    /// can parse of its output, i.e. the maps the generated program prints.
+    pub fn set_edited_script(&self, script: Option<String>) -> Result<(), Error> {
+        let mut guard = self.stack.lock().unwrap();
+        let script = match script {
+            Some(script) => script,
+            None => {
+                guard.edited_script = None;
+                self.command_modified(guard);
+                return Ok(());
+            }
+        };
+        let output = self
+            .backend
+            .check_command(&script)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .output()
+            .unwrap_or_else(|err| panic!("{} failed to start: {}", self.backend.name(), err));
+        if !output.status.success() {
+            return Err(String::from_utf8_lossy(&output.stderr).into_owned().into());
+        }
+        let generated = self.get_bpftrace_expr_locked(&guard).0;
+        guard.edited_script = Some((generated, script));
+        self.command_modified(guard);
+        Ok(())
     }
 
     fn get_bpftrace_expr_locked(&self, guard: &MutexGuard<Frames>) -> (String, u64) {
+        let expr = self.backend.compile(&self.get_program_locked(guard));
+        log::debug!("Current bpftrace expression: {}", expr);
+        // Since we hold lock we know counter won't change
+        (expr, self.counter.load(Ordering::Relaxed))
+    }
+
+    /// Probes that trace the current state in-process, for backends that
+    /// support it (see `TraceBackend::is_native`), along with the current
+    /// counter value. Only line mode without filters is supported so far, the
+    /// error says what keeps the current state from being traced in-process.
+    pub fn get_native_plan(&self) -> Result<(NativePlan, u64), Error> {
+        let guard = self.stack.lock().unwrap();
+        let unsupported = |what: &str| -> Result<(NativePlan, u64), Error> {
+            Err(format!("{} can't be traced in-process yet", what).into())
+        };
+        if !matches!(guard.mode, TraceMode::Line) {
+            return unsupported("This trace mode");
+        }
+        if guard.line_histograms {
+            return unsupported("Line histograms");
+        }
+        if guard.off_cpu {
+            return unsupported("Off-CPU time");
+        }
+        if !guard.pinned_traces.is_empty() {
+            return unsupported("Pinned lines");
+        }
+        if guard.workspaces.len() > 1 {
+            return unsupported("Multiple workspaces");
+        }
+        if !guard.frames.last().unwrap().sampled_callsites.is_empty() {
+            return unsupported("Sampled lines");
+        }
+        if !guard.frames.last().unwrap().callsite_thresholds.is_empty() {
+            return unsupported("Latency thresholds");
+        }
+        if guard
+            .frames
+            .last()
+            .unwrap()
+            .traced_callsites
+            .values()
+            .any(|ci| ci.is_tail_call())
+        {
+            return unsupported("Tail calls");
+        }
+        if let Some((generated, _)) = &guard.edited_script {
+            if *generated == self.get_bpftrace_expr_locked(&guard).0 {
+                return unsupported("Edited programs");
+            }
+        }
+        let process_filter = &guard.process_filter;
+        if !process_filter.tids.is_empty()
+            || !process_filter.thread_names.is_empty()
+            || process_filter.comm.is_some()
+            || process_filter.uid.is_some()
+            || process_filter.cgroup.is_some()
+            || process_filter.follow_forks
+        {
+            return unsupported("Process filters other than pids");
+        }
+        let frames = &guard.frames;
+        if frames
+            .iter()
+            .any(|frame| frame.filter.is_some() || frame.ret_filter.is_some())
+        {
+            return unsupported("Filters");
+        }
+        if frames.len() > u8::MAX as usize {
+            return unsupported("A trace stack this deep");
+        }
+
+        let last_frame = frames.last().unwrap();
+        let callsites: Vec<(u32, &CallInstruction)> = last_frame
+            .traced_callsites
+            .iter()
+            .map(|(&line, callsite)| (line, callsite))
+            .collect();
+        let lines: Vec<u32> = callsites
+            .iter()
+            .map(|(line, _)| *line)
+            .chain(iter::once(last_frame.source_line))
+            .collect();
+        if lines.len() > native::MAX_SLOTS {
+            return unsupported(&format!("More than {} lines", native::MAX_SLOTS));
+        }
+        let mut plan = NativePlan {
+            probes: Vec::new(),
+            lines,
+            pids: process_filter.pids.clone(),
+            counters: guard.counters,
+            recursive_calls: !guard.outermost_calls_only,
+            report_interval: guard.report_interval,
+        };
+        let frame_depth = (frames.len() - 1) as u8;
+        for (i, frame) in frames.iter().take(frames.len() - 1).enumerate() {
+            plan.add_frame(frame.function, i as u8, &frame.return_offsets, None);
+        }
+        // The function's own line is in the last slot
+        plan.add_frame(
+            last_frame.function,
+            frame_depth,
+            &last_frame.return_offsets,
+            Some(callsites.len() as u16),
+        );
+        for (slot, (_, callsite)) in callsites.iter().enumerate() {
+            plan.add_call(
+                last_frame.function,
+                frame_depth + 1,
+                callsite.relative_ip,
+                callsite.get_end_offsets(),
+                slot as u16,
+            );
+        }
+        log::debug!("Current native plan: {:?}", plan);
+        Ok((plan, self.counter.load(Ordering::Relaxed)))
+    }
+
+    fn get_program_locked(&self, guard: &MutexGuard<Frames>) -> BpftraceProgram {
         // General approach to codegen:
         // 1. Maintain `@depth` on function entry and exit to ensure we are
         //    following the trace stack.
@@ -413,7 +1903,7 @@ impl TraceStack {
             })
             .sum();
 
-        let mut program = bpftrace_compiler::BpftraceProgram::new();
+        let mut program = BpftraceProgram::new();
         program.add(Block::new(
             BlockType::Begin,
             None,
@@ -423,25 +1913,141 @@ impl TraceStack {
                 "@matched_retfilters[-1] = 0",
             ],
         ));
+        if guard.process_filter.follow_forks {
+            let mut known_pids = guard.process_filter.pids.clone();
+            // Other backends run traces on other hosts
+            if self.backend.is_local_bpftrace() {
+                known_pids.extend(descendants(&guard.process_filter.pids));
+            }
+            for block in ProcessFilter::fork_tracking_blocks(&known_pids) {
+                program.add(block);
+            }
+        }
+
+        // Calls only get past the outermost frame's entry, the only probe at
+        // depth 0, if they pass the process filter
+        let process_predicate = guard.process_filter.predicate();
+        // Recursive calls of a frame's function run on a lower stack pointer
+        // than the frame's own call, so the frame only returns once the stack
+        // pointer is back up to where it was on entry. Within the current
+        // function, lines are only timed in its outermost call, see `@level`
+        // below.
+        let recursion_safe = !guard.movable_stacks;
+        let depth_condition = |depth: usize| -> Option<String> {
+            match &process_predicate {
+                Some(predicate) if depth == 0 => Some(format!("@depth[tid] == 0 && {}", predicate)),
+                _ if depth == frames.len() && recursion_safe => {
+                    Some(format!("@depth[tid] == {} && !@level[tid]", depth))
+                }
+                _ => Some(format!("@depth[tid] == {}", depth)),
+            }
+        };
+        let return_condition = |frame: usize| -> Option<String> {
+            let condition = depth_condition(frame + 1);
+            if recursion_safe {
+                condition.map(|c| c + &format!(r#" && reg("sp") >= @frame_sp{}[tid]"#, frame))
+            } else {
+                condition
+            }
+        };
+        let frame_sp_exprs = |frame: usize| -> Vec<String> {
+            if recursion_safe {
+                vec![format!(r#"@frame_sp{}[tid] = reg("sp")"#, frame)]
+            } else {
+                Vec::new()
+            }
+        };
+
+        let last_frame = frames.last().unwrap();
+        let lines: Vec<u32> = last_frame
+            .traced_callsites
+            .iter()
+            .map(|(line, _)| *line)
+            .chain(iter::once(last_frame.source_line))
+            .collect();
+        let off_cpu = guard.off_cpu && matches!(guard.mode, TraceMode::Line);
+        // Lines still being timed when they should have returned were aborted
+        // by an exception or longjmp, which is counted in Line mode
+        let count_aborted = matches!(guard.mode, TraceMode::Line);
+        let abort_exprs = || -> Vec<Expression> {
+            lines
+                .iter()
+                .map(|line| {
+                    let start = format!("@start{}[tid]", line);
+                    let delete = format!("delete({})", start);
+                    if count_aborted {
+                        Expression::If {
+                            condition: start,
+                            body: vec![format!("@aborted{} += 1", line).into(), delete.into()],
+                        }
+                    } else {
+                        delete.into()
+                    }
+                })
+                .collect()
+        };
+        // A frame whose call was unwound by an exception or longjmp never
+        // returns, which is noticed on the next call of its function: one made
+        // from at least as high up the stack can't be a recursive call. The
+        // thread is put back at the frame's depth, and if it was the outermost
+        // frame the unwound call is dropped.
+        let entry_condition = |frame: usize| -> Option<String> {
+            if !recursion_safe {
+                return depth_condition(frame);
+            }
+            let unwound = format!(
+                r#"(@depth[tid] > {} && reg("sp") >= @frame_sp{}[tid])"#,
+                frame, frame
+            );
+            match &process_predicate {
+                Some(predicate) if frame == 0 => Some(format!(
+                    "(@depth[tid] == 0 || {}) && {}",
+                    unwound, predicate
+                )),
+                _ => Some(format!("(@depth[tid] == {} || {})", frame, unwound)),
+            }
+        };
+        let unwind_exprs = |frame: usize| -> Vec<Expression> {
+            if !recursion_safe {
+                return Vec::new();
+            }
+            let mut body = abort_exprs();
+            body.push("delete(@level[tid])".into());
+            if frame == 0 {
+                body.extend(
+                    TraceStack::call_cleanup_exprs(guard, &lines, off_cpu)
+                        .into_iter()
+                        .map(Expression::from),
+                );
+            }
+            body.push(format!("@depth[tid] = {}", frame).into());
+            vec![Expression::If {
+                condition: format!("@depth[tid] > {}", frame),
+                body,
+            }]
+        };
 
-        let depth_condition =
-            |depth: usize| -> Option<String> { Some(format!("@depth[tid] == {}", depth)) };
         for (i, frame) in frames.iter().take(frames.len() - 1).enumerate() {
+            let mut entry_exprs = unwind_exprs(i);
+            entry_exprs.extend(TraceStack::add_user_filter(
+                &frame.filter,
+                false,
+                vec![
+                    format!("@depth[tid] = {}", i + 1),
+                    format!("@start_frame{}[tid] = nsecs", i),
+                ]
+                .into_iter()
+                .chain(frame_sp_exprs(i))
+                .collect::<Vec<String>>(),
+            ));
             program.add(Block::new(
                 Uprobe(frame.function),
-                depth_condition(i),
-                TraceStack::add_user_filter(
-                    &frame.filter,
-                    false,
-                    vec![
-                        format!("@depth[tid] = {}", i + 1),
-                        format!("@start_frame{}[tid] = nsecs", i),
-                    ],
-                ),
+                entry_condition(i),
+                entry_exprs,
             ));
             program.add(Block::new(
-                Uretprobe(frame.function),
-                depth_condition(i + 1),
+                TraceStack::return_probe(frame.function, &frame.return_offsets),
+                return_condition(i),
                 TraceStack::add_user_filter(
                     &frame.ret_filter,
                     true,
@@ -453,71 +2059,266 @@ impl TraceStack {
             ));
         }
 
-        let last_frame = frames.last().unwrap();
-        let lines: Vec<u32> = last_frame
-            .traced_callsites
-            .iter()
-            .map(|(line, _)| *line)
-            .chain(iter::once(last_frame.source_line))
-            .collect();
         let frame_depth = frames.len() - 1;
         let line = last_frame.source_line;
         let function = last_frame.function;
 
+        let mut entry_exprs = vec![
+            format!("@start{}[tid] = nsecs", line),
+            format!("@depth[tid] = {}", frame_depth + 1),
+        ];
+        entry_exprs.extend(frame_sp_exprs(frame_depth));
+        // Off-CPU time of a line is the growth of its thread's off-CPU time
+        // (see the `sched_switch` probe below) between its start and end
+        let off_cpu_start = |line: u32| -> Vec<String> {
+            if off_cpu {
+                vec![format!("@off_cpu_start{}[tid] = @off_cpu[tid]", line)]
+            } else {
+                Vec::new()
+            }
+        };
+        let off_cpu_end = |line: u32| -> Vec<Expression> {
+            if off_cpu {
+                vec![format!(
+                    "@off_cpu_tmp{line}[tid] += @off_cpu[tid] - @off_cpu_start{line}[tid]",
+                    line = line
+                )
+                .into()]
+            } else {
+                Vec::new()
+            }
+        };
+        entry_exprs.extend(off_cpu_start(line));
+        if let TraceMode::Callers {
+            line: callers_line,
+            function_address,
+        } = guard.mode
+        {
+            // The return address is on top of the stack on entry. Subtracting
+            // the load bias (the difference between the runtime and program
+            // address of this function) gives the address in the program.
+            entry_exprs.push(format!(
+                r#"@caller[tid] = *(uint64 *)reg("sp") - (reg("ip") - {})"#,
+                function_address
+            ));
+            if callers_line == line {
+                entry_exprs.push("@callers[@caller[tid]] = count()".to_string());
+            }
+        }
+        // Arguments are still in their registers on entry
+        let argument_exprs: Vec<String> = guard
+            .argument_columns
+            .iter()
+            .enumerate()
+            .map(|(i, column)| format!("@arg{}[{}] = count()", i, column.expr))
+            .collect();
+        if let TraceMode::Arguments { line: args_line } = guard.mode {
+            if args_line == line {
+                entry_exprs.extend(argument_exprs.iter().cloned());
+            }
+        }
+        // The argument calls are grouped by is kept until they return
+        let group_key_expr = match &guard.group_by {
+            Some(column) => format!("@group_key[tid] = {}", column.expr),
+            None => "@group_key[tid] = 0".to_string(),
+        };
+        if let TraceMode::GroupBy { line: group_line } = guard.mode {
+            if group_line == line {
+                entry_exprs.push(group_key_expr.clone());
+            }
+        }
+        let mut exprs = unwind_exprs(frame_depth);
+        exprs.extend(TraceStack::add_user_filter(
+            &last_frame.filter,
+            false,
+            entry_exprs,
+        ));
         program.add(Block::new(
             Uprobe(function),
-            depth_condition(frame_depth),
-            TraceStack::add_user_filter(
-                &last_frame.filter,
-                false,
-                vec![
-                    format!("@start{}[tid] = nsecs", line),
-                    format!("@depth[tid] = {}", frame_depth + 1),
-                ],
-            ),
+            entry_condition(frame_depth),
+            exprs,
         ));
 
         match guard.mode {
             TraceMode::Line => {
-                program.add(Block::new(
-                    Uretprobe(function),
-                    depth_condition(frame_depth + 1),
-                    TraceStack::add_user_filter(
-                        &last_frame.ret_filter,
-                        true,
-                        vec![
+                let call_end_exprs = |line: u32| -> Vec<Expression> {
+                    match last_frame.callsite_thresholds.get(&line) {
+                        // Calls faster than the threshold aren't counted
+                        Some(min_duration) => vec![
+                            format!("$duration = nsecs - @start{}[tid]", line).into(),
+                            Expression::If {
+                                condition: format!("$duration >= {}", min_duration),
+                                body: vec![
+                                    format!("@duration_tmp{}[tid] += $duration", line).into(),
+                                    format!("@count_tmp{}[tid] += 1", line).into(),
+                                ]
+                                .into_iter()
+                                .chain(off_cpu_end(line))
+                                .collect(),
+                            },
+                            format!("delete(@start{}[tid])", line).into(),
+                        ],
+                        None => vec![
                             format!(
                                 "@duration_tmp{line}[tid] += (nsecs - @start{line}[tid])",
                                 line = line
-                            ),
-                            format!("$duration = @duration_tmp{}[tid]", line),
-                            format!("@count_tmp{}[tid] += 1", line),
-                            format!("delete(@start{}[tid])", line),
-                            format!("@depth[tid] = {}", frame_depth),
-                        ],
-                    ),
+                            )
+                            .into(),
+                            format!("@count_tmp{}[tid] += 1", line).into(),
+                        ]
+                        .into_iter()
+                        .chain(off_cpu_end(line))
+                        .chain(iter::once(format!("delete(@start{}[tid])", line).into()))
+                        .collect(),
+                    }
+                };
+                let mut return_exprs =
+                    TraceStack::tail_call_ends(last_frame, None, &call_end_exprs);
+                return_exprs.extend(TraceStack::add_user_filter(
+                    &last_frame.ret_filter,
+                    true,
+                    vec![
+                        format!(
+                            "@duration_tmp{line}[tid] += (nsecs - @start{line}[tid])",
+                            line = line
+                        )
+                        .into(),
+                        format!("$duration = @duration_tmp{}[tid]", line).into(),
+                        format!("@count_tmp{}[tid] += 1", line).into(),
+                    ]
+                    .into_iter()
+                    .chain(off_cpu_end(line))
+                    .chain(vec![
+                        format!("delete(@start{}[tid])", line).into(),
+                        format!("@depth[tid] = {}", frame_depth).into(),
+                    ])
+                    .collect::<Vec<Expression>>(),
                 ));
-
+                program.add(Block::new(
+                    TraceStack::return_probe(function, &last_frame.return_offsets),
+                    return_condition(frame_depth),
+                    return_exprs,
+                ));
+
                 for (&line, callsite) in &last_frame.traced_callsites {
+                    // Calls that aren't sampled are never started, so their
+                    // ends are skipped too
+                    let call_start_condition = match last_frame.sampled_callsites.get(&line) {
+                        Some(rate) => depth_condition(frame_depth + 1)
+                            .map(|c| c + &format!(" && rand % {} == 0", rate)),
+                        None => depth_condition(frame_depth + 1),
+                    };
+                    // A call still being timed when the line is reached again
+                    // never returned
+                    let aborted = Expression::If {
+                        condition: format!("@start{}[tid]", line),
+                        body: vec![format!("@aborted{} += 1", line).into()],
+                    };
                     program.add(Block::new(
                         UprobeOffset(function, callsite.relative_ip),
-                        depth_condition(frame_depth + 1),
-                        vec![format!("@start{}[tid] = nsecs", line)],
+                        call_start_condition,
+                        iter::once(aborted)
+                            .chain(iter::once(format!("@start{}[tid] = nsecs", line).into()))
+                            .chain(off_cpu_start(line).into_iter().map(Expression::from))
+                            .collect::<Vec<Expression>>(),
                     ));
                     // Ensure the tracepoint at the end of the call is only
                     // triggered if we traced the start.
                     let call_done_condition = depth_condition(frame_depth + 1)
                         .map(|c| c + &format!(" && @start{}[tid]", line));
+                    for end_offset in callsite.get_end_offsets() {
+                        program.add(Block::new(
+                            UprobeOffset(function, end_offset),
+                            call_done_condition.clone(),
+                            call_end_exprs(line),
+                        ));
+                    }
+                }
+                // Pinned lines are timed in every call, whatever the depth
+                for (i, pinned) in guard.pinned_traces.iter().enumerate() {
+                    let end_exprs = vec![
+                        format!("@pin_duration{i} += nsecs - @pin_start{i}[tid]", i = i),
+                        format!("@pin_count{} += 1", i),
+                        format!("delete(@pin_start{}[tid])", i),
+                    ];
+                    let start_exprs = vec![format!("@pin_start{}[tid] = nsecs", i)];
+                    let end_condition = Some(format!("@pin_start{}[tid]", i));
+                    match &pinned.callsite {
+                        Some(callsite) => {
+                            program.add(Block::new(
+                                UprobeOffset(pinned.function, callsite.relative_ip),
+                                process_predicate.clone(),
+                                start_exprs,
+                            ));
+                            for end_offset in callsite.get_end_offsets() {
+                                program.add(Block::new(
+                                    UprobeOffset(pinned.function, end_offset),
+                                    end_condition.clone(),
+                                    end_exprs.clone(),
+                                ));
+                            }
+                        }
+                        // Like breakdown functions, recursive calls end the
+                        // outer call's timing early
+                        None => {
+                            program.add(Block::new(
+                                Uprobe(pinned.function),
+                                process_predicate.clone(),
+                                start_exprs,
+                            ));
+                            program.add(Block::new(
+                                TraceStack::return_probe(pinned.function, &pinned.return_offsets),
+                                end_condition,
+                                end_exprs,
+                            ));
+                        }
+                    }
+                }
+                // So are the current functions of the other workspaces, the
+                // entry of the current one is empty
+                for (i, frames) in guard.workspaces.iter().enumerate() {
+                    let frame = match frames.last() {
+                        Some(frame) => frame,
+                        None => continue,
+                    };
                     program.add(Block::new(
-                        UprobeOffset(function, callsite.relative_ip + callsite.length as u32),
-                        call_done_condition,
+                        Uprobe(frame.function),
+                        process_predicate.clone(),
+                        vec![format!("@workspace_start{}[tid] = nsecs", i)],
+                    ));
+                    program.add(Block::new(
+                        TraceStack::return_probe(frame.function, &frame.return_offsets),
+                        Some(format!("@workspace_start{}[tid]", i)),
                         vec![
                             format!(
-                                "@duration_tmp{line}[tid] += (nsecs - @start{line}[tid])",
-                                line = line
+                                "@workspace_duration{i} += nsecs - @workspace_start{i}[tid]",
+                                i = i
                             ),
-                            format!("@count_tmp{}[tid] += 1", line),
-                            format!("delete(@start{}[tid])", line),
+                            format!("@workspace_count{} += 1", i),
+                            format!("delete(@workspace_start{}[tid])", i),
+                        ],
+                    ));
+                }
+                if off_cpu {
+                    // The switched out thread is the current one, and the
+                    // time it was off-CPU is only added up once it is
+                    // switched back in
+                    program.add(Block::new(
+                        BlockType::Tracepoint("sched:sched_switch"),
+                        None,
+                        vec![
+                            Expression::If {
+                                condition: format!("@depth[tid] == {}", frame_depth + 1),
+                                body: vec!["@off_cpu_since[tid] = nsecs".into()],
+                            },
+                            "$next = (uint32)args->next_pid".into(),
+                            Expression::If {
+                                condition: "@off_cpu_since[$next]".to_string(),
+                                body: vec![
+                                    "@off_cpu[$next] += nsecs - @off_cpu_since[$next]".into(),
+                                    "delete(@off_cpu_since[$next])".into(),
+                                ],
+                            },
                         ],
                     ));
                 }
@@ -536,31 +2337,168 @@ impl TraceStack {
                         args: vec![format!("@duration{}", line), format!("@count{}", line)],
                     });
                 }
+                print_exprs.push(Printf {
+                    format: r#"}, "aborted": {"#.to_string(),
+                    args: Vec::new(),
+                });
+                for (i, line) in lines.iter().enumerate() {
+                    let mut format = format!(r#""{}": %lld"#, line);
+                    if i != lines.len() - 1 {
+                        format.push_str(", ");
+                    }
+                    print_exprs.push(Printf {
+                        format,
+                        args: vec![format!("@aborted{}", line)],
+                    });
+                }
+                if off_cpu {
+                    print_exprs.push(Printf {
+                        format: r#"}, "off_cpu": {"#.to_string(),
+                        args: Vec::new(),
+                    });
+                    for (i, line) in lines.iter().enumerate() {
+                        let mut format = format!(r#""{}": %lld"#, line);
+                        if i != lines.len() - 1 {
+                            format.push_str(", ");
+                        }
+                        print_exprs.push(Printf {
+                            format,
+                            args: vec![format!("@off_cpu{}", line)],
+                        });
+                    }
+                }
+                if guard.line_histograms {
+                    print_exprs.push(Printf {
+                        format: r#"}, "histograms": {"#.to_string(),
+                        args: Vec::new(),
+                    });
+                    for (i, line) in lines.iter().enumerate() {
+                        print_exprs.push(Printf {
+                            format: format!(r#""{}": ""#, line),
+                            args: Vec::new(),
+                        });
+                        print_exprs.push(Expression::Print(format!("@histogram{}", line)));
+                        print_exprs.push(Printf {
+                            format: if i != lines.len() - 1 {
+                                r#"", "#.to_string()
+                            } else {
+                                r#"""#.to_string()
+                            },
+                            args: Vec::new(),
+                        });
+                    }
+                    print_exprs.push(Printf {
+                        format: r#"}, "stats": {"#.to_string(),
+                        args: Vec::new(),
+                    });
+                    for (i, line) in lines.iter().enumerate() {
+                        let mut format = format!(r#""{}": [%lld, %lld, %lld, %lld]"#, line);
+                        if i != lines.len() - 1 {
+                            format.push_str(", ");
+                        }
+                        print_exprs.push(Printf {
+                            format,
+                            args: ["@min", "@max", "@sum_squares", "@calls"]
+                                .iter()
+                                .map(|name| format!("{}{}", name, line))
+                                .collect(),
+                        });
+                    }
+                }
+                if !guard.pinned_traces.is_empty() {
+                    print_exprs.push(Printf {
+                        format: r#"}, "pinned": {"#.to_string(),
+                        args: Vec::new(),
+                    });
+                    let num_pinned = guard.pinned_traces.len();
+                    for i in 0..num_pinned {
+                        let mut format = format!(r#""{}": [%lld, %lld]"#, i);
+                        if i != num_pinned - 1 {
+                            format.push_str(", ");
+                        }
+                        print_exprs.push(Printf {
+                            format,
+                            args: vec![format!("@pin_duration{}", i), format!("@pin_count{}", i)],
+                        });
+                    }
+                }
+                let other_workspaces: Vec<usize> = (0..guard.workspaces.len())
+                    .filter(|&i| i != guard.current_workspace)
+                    .collect();
+                if !other_workspaces.is_empty() {
+                    print_exprs.push(Printf {
+                        format: r#"}, "workspaces": {"#.to_string(),
+                        args: Vec::new(),
+                    });
+                    for (n, i) in other_workspaces.iter().enumerate() {
+                        let mut format = format!(r#""{}": [%lld, %lld]"#, i);
+                        if n != other_workspaces.len() - 1 {
+                            format.push_str(", ");
+                        }
+                        print_exprs.push(Printf {
+                            format,
+                            args: vec![
+                                format!("@workspace_duration{}", i),
+                                format!("@workspace_count{}", i),
+                            ],
+                        });
+                    }
+                }
                 print_exprs.push(Printf {
                     format: r#"}}\n"#.to_string(),
                     args: Vec::new(),
                 });
                 program.add(Block::new(
-                    BlockType::Interval { rate_seconds: 1 },
+                    BlockType::interval(guard.report_interval),
                     None,
                     print_exprs,
                 ));
             }
-            TraceMode::Histogram => {
+            TraceMode::Histogram {
+                line: histogram_line,
+            } => {
+                let call_end_exprs = |line: u32| -> Vec<Expression> {
+                    vec![
+                        format!("@duration_tmp[tid] += nsecs - @start{}[tid]", line).into(),
+                        format!("delete(@start{}[tid])", line).into(),
+                    ]
+                };
+                let mut return_exprs = vec![format!("$duration = nsecs - @start{}[tid]", line)];
+                if histogram_line == line {
+                    return_exprs.push("@duration_tmp[tid] = $duration".to_string());
+                }
+                return_exprs.push(format!("delete(@start{}[tid])", line));
+                return_exprs.push(format!("@depth[tid] = {}", frame_depth));
+                let mut exprs =
+                    TraceStack::tail_call_ends(last_frame, Some(histogram_line), &call_end_exprs);
+                exprs.extend(TraceStack::add_user_filter(
+                    &last_frame.ret_filter,
+                    true,
+                    return_exprs,
+                ));
                 program.add(Block::new(
-                    Uretprobe(last_frame.function),
-                    depth_condition(frame_depth + 1),
-                    TraceStack::add_user_filter(
-                        &last_frame.ret_filter,
-                        true,
-                        vec![
-                            format!("@duration_tmp[tid] = nsecs - @start{}[tid]", line),
-                            "$duration = @duration_tmp[tid]".to_string(),
-                            format!("delete(@start{}[tid])", line),
-                            format!("@depth[tid] = {}", frame_depth),
-                        ],
-                    ),
+                    TraceStack::return_probe(last_frame.function, &last_frame.return_offsets),
+                    return_condition(frame_depth),
+                    exprs,
                 ));
+                if let Some(callsite) = last_frame.traced_callsites.get(&histogram_line) {
+                    // Time spent on the line adds up over the function's call,
+                    // like in Line mode
+                    program.add(Block::new(
+                        UprobeOffset(function, callsite.relative_ip),
+                        depth_condition(frame_depth + 1),
+                        vec![format!("@start{}[tid] = nsecs", histogram_line)],
+                    ));
+                    let call_done_condition = depth_condition(frame_depth + 1)
+                        .map(|c| c + &format!(" && @start{}[tid]", histogram_line));
+                    for end_offset in callsite.get_end_offsets() {
+                        program.add(Block::new(
+                            UprobeOffset(function, end_offset),
+                            call_done_condition.clone(),
+                            call_end_exprs(histogram_line),
+                        ));
+                    }
+                }
 
                 let print_exprs = vec![
                     Printf {
@@ -574,7 +2512,7 @@ impl TraceStack {
                     },
                 ];
                 program.add(Block::new(
-                    BlockType::Interval { rate_seconds: 1 },
+                    BlockType::interval(guard.report_interval),
                     None,
                     print_exprs,
                 ));
@@ -587,8 +2525,8 @@ impl TraceStack {
                 // multiple times but still have to accumulate time for all of
                 // them.
                 program.add(Block::new(
-                    Uretprobe(function),
-                    depth_condition(frame_depth + 1),
+                    TraceStack::return_probe(function, &last_frame.return_offsets),
+                    return_condition(frame_depth),
                     TraceStack::add_user_filter(
                         &last_frame.ret_filter,
                         true,
@@ -601,7 +2539,9 @@ impl TraceStack {
                         ],
                     ),
                 ));
-                for (i, &function) in guard.breakdown_functions.iter().enumerate() {
+                for (i, (function, return_offsets)) in guard.breakdown_functions.iter().enumerate()
+                {
+                    let function = *function;
                     program.add(Block::new(
                         Uprobe(function),
                         depth_condition(frame_depth + 1),
@@ -616,7 +2556,7 @@ impl TraceStack {
                     let ret_condition = depth_condition(frame_depth + 1)
                         .map(|c| c + &format!(" && @start_breakdown{}[tid]", i));
                     program.add(Block::new(
-                        Uretprobe(function),
+                        TraceStack::return_probe(function, return_offsets),
                         ret_condition,
                         vec![
                             format!(
@@ -660,13 +2600,285 @@ impl TraceStack {
                     args: Vec::new(),
                 });
                 program.add(Block::new(
-                    BlockType::Interval { rate_seconds: 1 },
+                    BlockType::interval(guard.report_interval),
+                    None,
+                    print_exprs,
+                ));
+            }
+            TraceMode::Callers {
+                line: callers_line, ..
+            } => {
+                program.add(Block::new(
+                    TraceStack::return_probe(function, &last_frame.return_offsets),
+                    return_condition(frame_depth),
+                    TraceStack::add_user_filter(
+                        &last_frame.ret_filter,
+                        true,
+                        vec![
+                            format!("$duration = nsecs - @start{}[tid]", line),
+                            format!("delete(@start{}[tid])", line),
+                            "delete(@caller[tid])".to_string(),
+                            format!("@depth[tid] = {}", frame_depth),
+                        ],
+                    ),
+                ));
+                if let Some(callsite) = last_frame.traced_callsites.get(&callers_line) {
+                    program.add(Block::new(
+                        UprobeOffset(function, callsite.relative_ip),
+                        depth_condition(frame_depth + 1),
+                        vec!["@callers[@caller[tid]] = count()"],
+                    ));
+                }
+
+                let print_exprs = vec![
+                    Printf {
+                        format: r#"{"time": %d, "callers": ""#.to_string(),
+                        args: vec!["(nsecs - @start_time) / 1000000000".to_string()],
+                    },
+                    Expression::Print("@callers".to_string()),
+                    Printf {
+                        format: r#""}\n"#.to_string(),
+                        args: Vec::new(),
+                    },
+                ];
+                program.add(Block::new(
+                    BlockType::interval(guard.report_interval),
+                    None,
+                    print_exprs,
+                ));
+            }
+            TraceMode::GroupBy { line: group_line } => {
+                let group_exprs = |start_line: u32| -> Vec<Expression> {
+                    vec![
+                        "@group_counts[@group_key[tid]] = count()".into(),
+                        format!(
+                            "@group_durations[@group_key[tid]] = sum(nsecs - @start{}[tid])",
+                            start_line
+                        )
+                        .into(),
+                        "delete(@group_key[tid])".into(),
+                    ]
+                };
+                let call_end_exprs = |line: u32| -> Vec<Expression> {
+                    let mut end_exprs = group_exprs(line);
+                    end_exprs.push(format!("delete(@start{}[tid])", line).into());
+                    end_exprs
+                };
+                let mut return_exprs =
+                    TraceStack::tail_call_ends(last_frame, Some(group_line), &call_end_exprs);
+                if group_line == line {
+                    return_exprs.extend(group_exprs(line));
+                }
+                return_exprs.push(format!("delete(@start{}[tid])", line).into());
+                return_exprs.push(format!("@depth[tid] = {}", frame_depth).into());
+                program.add(Block::new(
+                    TraceStack::return_probe(function, &last_frame.return_offsets),
+                    return_condition(frame_depth),
+                    return_exprs,
+                ));
+                if let Some(callsite) = last_frame.traced_callsites.get(&group_line) {
+                    program.add(Block::new(
+                        UprobeOffset(function, callsite.relative_ip),
+                        depth_condition(frame_depth + 1),
+                        vec![format!("@start{}[tid] = nsecs", group_line), group_key_expr],
+                    ));
+                    let call_done_condition = depth_condition(frame_depth + 1)
+                        .map(|c| c + &format!(" && @start{}[tid]", group_line));
+                    for end_offset in callsite.get_end_offsets() {
+                        program.add(Block::new(
+                            UprobeOffset(function, end_offset),
+                            call_done_condition.clone(),
+                            call_end_exprs(group_line),
+                        ));
+                    }
+                }
+
+                let print_exprs = vec![
+                    Printf {
+                        format: r#"{"time": %d, "groups": ""#.to_string(),
+                        args: vec!["(nsecs - @start_time) / 1000000000".to_string()],
+                    },
+                    Expression::Print("@group_counts".to_string()),
+                    Printf {
+                        format: r#"", "group_durations": ""#.to_string(),
+                        args: Vec::new(),
+                    },
+                    Expression::Print("@group_durations".to_string()),
+                    Printf {
+                        format: r#""}\n"#.to_string(),
+                        args: Vec::new(),
+                    },
+                ];
+                program.add(Block::new(
+                    BlockType::interval(guard.report_interval),
+                    None,
+                    print_exprs,
+                ));
+            }
+            TraceMode::Arguments { line: args_line } => {
+                program.add(Block::new(
+                    TraceStack::return_probe(function, &last_frame.return_offsets),
+                    return_condition(frame_depth),
+                    vec![
+                        format!("delete(@start{}[tid])", line),
+                        format!("@depth[tid] = {}", frame_depth),
+                    ],
+                ));
+                if let Some(callsite) = last_frame.traced_callsites.get(&args_line) {
+                    program.add(Block::new(
+                        UprobeOffset(function, callsite.relative_ip),
+                        depth_condition(frame_depth + 1),
+                        argument_exprs,
+                    ));
+                }
+
+                let mut print_exprs = vec![Printf {
+                    format: r#"{"time": %d, "args": {"#.to_string(),
+                    args: vec!["(nsecs - @start_time) / 1000000000".to_string()],
+                }];
+                for i in 0..guard.argument_columns.len() {
+                    let separator = if i == 0 { "" } else { ", " };
+                    print_exprs.push(Printf {
+                        format: format!(r#"{}"{}": ""#, separator, i),
+                        args: Vec::new(),
+                    });
+                    print_exprs.push(Expression::Print(format!(
+                        "@arg{}, {}",
+                        i, TOP_ARGUMENT_VALUES
+                    )));
+                    print_exprs.push(Printf {
+                        format: r#"""#.to_string(),
+                        args: Vec::new(),
+                    });
+                }
+                print_exprs.push(Printf {
+                    format: r#"}}\n"#.to_string(),
+                    args: Vec::new(),
+                });
+                program.add(Block::new(
+                    BlockType::interval(guard.report_interval),
+                    None,
+                    print_exprs,
+                ));
+            }
+            TraceMode::ReturnValues { line: retvals_line } => {
+                // Signed, so that error codes show up as negative
+                let count_exprs = |retval: &str| -> Vec<Expression> {
+                    vec![
+                        format!("@retvals[(int64){}] = count()", retval).into(),
+                        format!("@retval_durations[(int64){}] = sum($duration)", retval).into(),
+                    ]
+                };
+                // `retval` is only available in uretprobes
+                let function_retval = match last_frame.return_offsets {
+                    Some(_) => r#"reg("ax")"#,
+                    None => "retval",
+                };
+                // What a tail call returns is what the function returns
+                let mut return_exprs =
+                    TraceStack::tail_call_ends(last_frame, Some(retvals_line), |line| {
+                        let mut end_exprs: Vec<Expression> =
+                            vec![format!("$duration = nsecs - @start{}[tid]", line).into()];
+                        end_exprs.extend(count_exprs(function_retval));
+                        end_exprs.push(format!("delete(@start{}[tid])", line).into());
+                        end_exprs
+                    });
+                return_exprs.extend(vec![
+                    format!("$duration = nsecs - @start{}[tid]", line).into(),
+                    format!("delete(@start{}[tid])", line).into(),
+                    format!("@depth[tid] = {}", frame_depth).into(),
+                ]);
+                if retvals_line == line {
+                    return_exprs.extend(count_exprs(function_retval));
+                }
+                program.add(Block::new(
+                    TraceStack::return_probe(function, &last_frame.return_offsets),
+                    return_condition(frame_depth),
+                    return_exprs,
+                ));
+                if let Some(callsite) = last_frame.traced_callsites.get(&retvals_line) {
+                    program.add(Block::new(
+                        UprobeOffset(function, callsite.relative_ip),
+                        depth_condition(frame_depth + 1),
+                        vec![format!("@start{}[tid] = nsecs", retvals_line)],
+                    ));
+                    let call_done_condition = depth_condition(frame_depth + 1)
+                        .map(|c| c + &format!(" && @start{}[tid]", retvals_line));
+                    for end_offset in callsite.get_end_offsets() {
+                        // Right after the call returns, its return value is
+                        // still in the return register
+                        let mut end_exprs: Vec<Expression> =
+                            vec![format!("$duration = nsecs - @start{}[tid]", retvals_line).into()];
+                        end_exprs.extend(count_exprs(r#"reg("ax")"#));
+                        end_exprs.push(format!("delete(@start{}[tid])", retvals_line).into());
+                        program.add(Block::new(
+                            UprobeOffset(function, end_offset),
+                            call_done_condition.clone(),
+                            end_exprs,
+                        ));
+                    }
+                }
+
+                let print_exprs = vec![
+                    Printf {
+                        format: r#"{"time": %d, "retvals": ""#.to_string(),
+                        args: vec!["(nsecs - @start_time) / 1000000000".to_string()],
+                    },
+                    Expression::Print("@retvals".to_string()),
+                    Printf {
+                        format: r#"", "retval_durations": ""#.to_string(),
+                        args: Vec::new(),
+                    },
+                    Expression::Print("@retval_durations".to_string()),
+                    Printf {
+                        format: r#""}\n"#.to_string(),
+                        args: Vec::new(),
+                    },
+                ];
+                program.add(Block::new(
+                    BlockType::interval(guard.report_interval),
                     None,
                     print_exprs,
                 ));
             }
         };
 
+        if recursion_safe {
+            // Recursive calls of the current function are counted in `@level`,
+            // and in Line mode also timed on its line unless only outermost
+            // calls are counted. Their returns are added after the frame's
+            // own, which commits the times below.
+            let recursive_condition = Some(format!(
+                r#"@depth[tid] == {} && reg("sp") < @frame_sp{}[tid]"#,
+                frame_depth + 1,
+                frame_depth
+            ));
+            let time_recursive =
+                matches!(guard.mode, TraceMode::Line) && !guard.outermost_calls_only;
+            let mut entry_exprs = vec!["@level[tid] += 1".to_string()];
+            let mut return_exprs = Vec::new();
+            if time_recursive {
+                entry_exprs.push("@recursive_start[tid, @level[tid]] = nsecs".to_string());
+                return_exprs.push(format!(
+                    "@duration_tmp{}[tid] += nsecs - @recursive_start[tid, @level[tid]]",
+                    line
+                ));
+                return_exprs.push(format!("@count_tmp{}[tid] += 1", line));
+                return_exprs.push("delete(@recursive_start[tid, @level[tid]])".to_string());
+            }
+            return_exprs.push("@level[tid] -= 1".to_string());
+            program.add(Block::new(
+                Uprobe(function),
+                recursive_condition.clone(),
+                entry_exprs,
+            ));
+            program.add(Block::new(
+                TraceStack::return_probe(function, &last_frame.return_offsets),
+                recursive_condition,
+                return_exprs,
+            ));
+        }
+
         // Add expression to commit `_tmp` vars to their final version when
         // appropriate and always clear. This should happen in the first/topmost
         // retprobe, which can be in parent trace frame, or if there are none
@@ -674,39 +2886,62 @@ impl TraceStack {
         let last_retprobe = program
             .iter_mut()
             .find(|b| match b.get_type() {
-                Uretprobe(_) => true,
+                Uretprobe(_) | ReturnInstructions(..) => true,
                 _ => false,
             })
             .unwrap();
         match guard.mode {
             TraceMode::Line => {
-                last_retprobe.add(Expression::If {
-                    condition: format!("@matched_retfilters[tid] == {}", num_retfilters),
-                    body: lines
-                        .iter()
-                        .map(|line| {
+                let mut body: Vec<Expression> = lines
+                    .iter()
+                    .map(|line| {
+                        format!(
+                            "@duration{line} += @duration_tmp{line}[tid]; @count{line} += @count_tmp{line}[tid]",
+                            line = line
+                        )
+                    })
+                    .map(|e| e.into())
+                    .collect();
+                if guard.line_histograms {
+                    // Time spent on each line in a single call of the
+                    // function, if the line was reached
+                    body.extend(lines.iter().map(|line| Expression::If {
+                        condition: format!("@count_tmp{}[tid]", line),
+                        body: vec![
+                            format!("$line_duration = @duration_tmp{}[tid]", line).into(),
+                            format!("@histogram{} = hist($line_duration)", line).into(),
+                            format!("@calls{} += 1", line).into(),
+                            // In microseconds, so that it doesn't overflow
                             format!(
-                                "@duration{line} += @duration_tmp{line}[tid]; @count{line} += @count_tmp{line}[tid]",
-                                line = line
+                                "@sum_squares{} += ($line_duration / 1000) * ($line_duration / 1000)",
+                                line
                             )
-                        })
-                        .map(|e| e.into())
-                        .collect(),
+                            .into(),
+                            Expression::If {
+                                condition: format!(
+                                    "@min{line} == 0 || $line_duration < @min{line}",
+                                    line = line
+                                ),
+                                body: vec![format!("@min{} = $line_duration", line).into()],
+                            },
+                            Expression::If {
+                                condition: format!("$line_duration > @max{}", line),
+                                body: vec![format!("@max{} = $line_duration", line).into()],
+                            },
+                        ],
+                    }));
+                }
+                if off_cpu {
+                    body.extend(lines.iter().map(|line| {
+                        format!("@off_cpu{line} += @off_cpu_tmp{line}[tid]", line = line).into()
+                    }));
+                }
+                last_retprobe.add(Expression::If {
+                    condition: format!("@matched_retfilters[tid] == {}", num_retfilters),
+                    body,
                 });
-                last_retprobe.extend(
-                    lines
-                        .iter()
-                        .map(|line| {
-                            format!(
-                                "delete(@duration_tmp{line}[tid]); delete(@count_tmp{line}[tid])",
-                                line = line
-                            )
-                        })
-                        .chain(iter::once("delete(@matched_retfilters[tid])".to_string()))
-                        .collect(),
-                );
             }
-            TraceMode::Histogram => {
+            TraceMode::Histogram { .. } => {
                 last_retprobe.add(Expression::If {
                     // We may not have actually reached the place where
                     // `@duration_tmp` is set, so check that it is non-zero.
@@ -718,10 +2953,6 @@ impl TraceStack {
                     ),
                     body: vec!["@histogram = hist(@duration_tmp[tid])".into()],
                 });
-                last_retprobe.extend(vec![
-                    "delete(@duration_tmp[tid])",
-                    "delete(@matched_retfilters[tid])",
-                ]);
             }
             TraceMode::Breakdown => {
                 last_retprobe.add(Expression::If {
@@ -743,45 +2974,753 @@ impl TraceStack {
                         .map(|e| e.into())
                         .collect(),
                 });
-                last_retprobe.extend(
-                    guard
-                        .breakdown_functions
-                        .iter()
-                        .enumerate()
-                        .map(|(i, _)| {
-                            format!(
-                                "delete(@duration_breakdown_tmp{i}[tid]); delete(@count_breakdown_tmp{i}[tid])",
-                                i = i
-                            )
-                        })
-                        .chain(iter::once("delete(@matched_retfilters[tid]); delete(@duration_tmp[tid]); delete(@count_tmp[tid])".to_string()))
-                        .collect(),
-                );
             }
+            // Callers and arguments are counted as soon as they reach the line,
+            // so exit filters don't apply. Neither do they to return values and
+            // groups, which are counted as calls return.
+            TraceMode::Callers { .. }
+            | TraceMode::ReturnValues { .. }
+            | TraceMode::Arguments { .. }
+            | TraceMode::GroupBy { .. } => (),
         };
+        // Calls of lines still being timed once the outermost function returns
+        // never returned themselves
+        last_retprobe.extend(abort_exprs());
+        last_retprobe.extend(TraceStack::call_cleanup_exprs(guard, &lines, off_cpu));
 
-        let expr = program.compile(&self.program_path);
-        log::debug!("Current bpftrace expression: {}", expr);
-        // Since we hold lock we know counter won't change
-        (expr, self.counter.load(Ordering::Relaxed))
+        program
     }
 
-    fn add_user_filter<T>(
-        filter: &Option<String>,
-        is_ret_filter: bool,
-        exprs: Vec<T>,
-    ) -> Vec<Expression>
-    where
-        T: Into<Expression>,
-    {
-        let mut exprs = exprs.into_iter().map(|e| e.into()).collect();
-        match filter {
-            None => exprs,
-            Some(f) => {
-                // If this is a ret filter, we need to update depth (i.e. run
-                // `exprs`) unconditionally, but maintain
-                // `@matched_retfilters[tid]` depending on the filter. For an
-                // entry filter, we skip updating depth if it doesn't match.
+    /// Expressions clearing what `get_program_locked` gathers during a call of
+    /// the outermost function, once it is committed or the call was unwound
+    fn call_cleanup_exprs(guard: &Frames, lines: &[u32], off_cpu: bool) -> Vec<String> {
+        let mut exprs = vec!["delete(@matched_retfilters[tid])".to_string()];
+        match guard.mode {
+            TraceMode::Line => {
+                exprs.extend(lines.iter().map(|line| {
+                    format!(
+                        "delete(@duration_tmp{line}[tid]); delete(@count_tmp{line}[tid])",
+                        line = line
+                    )
+                }));
+                if off_cpu {
+                    exprs.extend(lines.iter().map(|line| {
+                        format!(
+                            "delete(@off_cpu_tmp{line}[tid]); delete(@off_cpu_start{line}[tid])",
+                            line = line
+                        )
+                    }));
+                    exprs.push("delete(@off_cpu[tid])".to_string());
+                }
+            }
+            TraceMode::Histogram { .. } => exprs.push("delete(@duration_tmp[tid])".to_string()),
+            TraceMode::Breakdown => {
+                exprs.extend(guard.breakdown_functions.iter().enumerate().map(|(i, _)| {
+                    format!(
+                        "delete(@duration_breakdown_tmp{i}[tid]); delete(@count_breakdown_tmp{i}[tid])",
+                        i = i
+                    )
+                }));
+                exprs.push("delete(@duration_tmp[tid]); delete(@count_tmp[tid])".to_string());
+            }
+            TraceMode::Callers { .. }
+            | TraceMode::ReturnValues { .. }
+            | TraceMode::Arguments { .. }
+            | TraceMode::GroupBy { .. } => (),
+        }
+        exprs
+    }
+
+    /// bpftrace program that captures the arguments, stack (if supported),
+    /// return value and duration of the next call of the current function matching its entry
+    /// filter, then exits so that its probes are removed. Unlike
+    /// `get_bpftrace_expr`, the rest of the trace stack is ignored. Output is
+    /// plain text, see `TraceStack::parse_single_call`.
+    pub fn get_single_call_expr(&self) -> String {
+        let guard = self.stack.lock().unwrap();
+        let frame = guard.frames.last().unwrap();
+        let mut capture_exprs: Vec<Expression> = vec![
+            "@tid = tid".into(),
+            "@depth = 1".into(),
+            "@start = nsecs".into(),
+            Printf {
+                format: r#"Thread %d (%s)\nArguments: 0x%lx 0x%lx 0x%lx 0x%lx 0x%lx 0x%lx\n"#
+                    .to_string(),
+                args: [
+                    "tid", "comm", "arg0", "arg1", "arg2", "arg3", "arg4", "arg5",
+                ]
+                .iter()
+                .map(|a| a.to_string())
+                .collect(),
+            },
+        ];
+        if self.capabilities.stack_traces {
+            capture_exprs.push(Printf {
+                format: r#"Stack:%s\n"#.to_string(),
+                args: vec!["ustack".to_string()],
+            });
+        }
+        let mut program = BpftraceProgram::new();
+        // `@depth` counts recursive calls on the captured thread, so that we
+        // wait for the outermost one to return
+        program.add(Block::new(
+            Uprobe(frame.function),
+            None,
+            vec![
+                Expression::If {
+                    condition: "@tid == tid".to_string(),
+                    body: vec!["@depth += 1".into()],
+                },
+                Expression::If {
+                    condition: "!@tid".to_string(),
+                    body: TraceStack::add_user_filter(&frame.filter, false, capture_exprs),
+                },
+            ],
+        ));
+        // `retval` is only available in uretprobes
+        let retval = match frame.return_offsets {
+            Some(_) => r#"reg("ax")"#,
+            None => "retval",
+        };
+        program.add(Block::new(
+            TraceStack::return_probe(frame.function, &frame.return_offsets),
+            Some("@tid == tid".to_string()),
+            vec![
+                "@depth -= 1".into(),
+                Expression::If {
+                    condition: "@depth == 0".to_string(),
+                    body: vec![
+                        Printf {
+                            format: r#"Returned 0x%lx after %lld ns\n"#.to_string(),
+                            args: vec![retval.to_string(), "nsecs - @start".to_string()],
+                        },
+                        "exit()".into(),
+                    ],
+                },
+            ],
+        ));
+
+        let expr = self.backend.compile(&program);
+        log::debug!("Single call bpftrace expression: {}", expr);
+        expr
+    }
+
+    /// bpftrace program that prints the arguments, stack (if supported) and
+    /// duration of each call of the current function matching its entry
+    /// filter that takes at least `threshold`, until it is killed. Like
+    /// `get_single_call_expr` the rest of the trace stack is ignored. Each call
+    /// is printed as a record ending with `End`, see `TraceStack::parse_outlier`.
+    pub fn get_outliers_expr(&self, threshold: Duration) -> String {
+        let guard = self.stack.lock().unwrap();
+        let frame = guard.frames.last().unwrap();
+        let stash_exprs: Vec<String> = iter::once("@start[tid] = nsecs".to_string())
+            .chain((0..6).map(|i| format!("@arg{i}[tid] = arg{i}", i = i)))
+            .collect();
+        let mut print_exprs: Vec<Expression> = vec![
+            Printf {
+                format: r#"Outlier %s %lld %d %s\n"#.to_string(),
+                args: [r#"strftime("%H:%M:%S", nsecs)"#, "$duration", "tid", "comm"]
+                    .iter()
+                    .map(|a| a.to_string())
+                    .collect(),
+            },
+            Printf {
+                format: r#"Arguments: 0x%lx 0x%lx 0x%lx 0x%lx 0x%lx 0x%lx\n"#.to_string(),
+                args: (0..6).map(|i| format!("@arg{}[tid]", i)).collect(),
+            },
+        ];
+        // The stack is that of the caller, as it is read when the call returns
+        if self.capabilities.stack_traces {
+            print_exprs.push(Printf {
+                format: r#"Stack:%s\n"#.to_string(),
+                args: vec!["ustack".to_string()],
+            });
+        }
+        print_exprs.push(Printf {
+            format: r#"End\n"#.to_string(),
+            args: Vec::new(),
+        });
+        let mut program = BpftraceProgram::new();
+        // `@depth` counts recursive calls, so that only the outermost one is
+        // timed
+        program.add(Block::new(
+            Uprobe(frame.function),
+            None,
+            vec![
+                "@depth[tid] += 1".into(),
+                Expression::If {
+                    condition: "@depth[tid] == 1".to_string(),
+                    body: TraceStack::add_user_filter(&frame.filter, false, stash_exprs),
+                },
+            ],
+        ));
+        program.add(Block::new(
+            TraceStack::return_probe(frame.function, &frame.return_offsets),
+            Some("@depth[tid]".to_string()),
+            vec![
+                "@depth[tid] -= 1".into(),
+                Expression::If {
+                    condition: "@depth[tid] == 0".to_string(),
+                    body: vec![
+                        "$duration = nsecs - @start[tid]".into(),
+                        Expression::If {
+                            // `@start` isn't set if the call didn't match the
+                            // entry filter
+                            condition: format!(
+                                "@start[tid] && $duration >= {}",
+                                threshold.as_nanos()
+                            ),
+                            body: print_exprs,
+                        },
+                        iter::once("delete(@depth[tid]); delete(@start[tid])".to_string())
+                            .chain((0..6).map(|i| format!("delete(@arg{}[tid])", i)))
+                            .join("; ")
+                            .into(),
+                    ],
+                },
+            ],
+        ));
+
+        let expr = self.backend.compile(&program);
+        log::debug!("Outliers bpftrace expression: {}", expr);
+        expr
+    }
+
+    /// bpftrace program that sums up how long calls of the current function
+    /// matching its entry filter wait for locks, keyed by user stack, and
+    /// exits after `seconds`. Waits are calls of `mutex_lock` (if set) that
+    /// block, and futex waits outside of it, e.g. of condition variables or
+    /// locks that don't use pthreads. See `contention::parse_waits`.
+    pub fn get_lock_waits_expr(&self, mutex_lock: Option<FunctionName>, seconds: u64) -> String {
+        let guard = self.stack.lock().unwrap();
+        let frame = guard.frames.last().unwrap();
+        let record_exprs = |start: &str| -> Vec<String> {
+            vec![
+                format!("@waits[ustack] = sum(nsecs - {})", start),
+                "@wait_counts[ustack] = count()".to_string(),
+            ]
+        };
+        let mut program = BpftraceProgram::new();
+        // `@depth` counts recursive calls, so that the filter is only checked
+        // on the outermost one
+        program.add(Block::new(
+            Uprobe(frame.function),
+            None,
+            vec![
+                "@depth[tid] += 1".into(),
+                Expression::If {
+                    condition: "@depth[tid] == 1".to_string(),
+                    body: TraceStack::add_user_filter(
+                        &frame.filter,
+                        false,
+                        vec!["@matched[tid] = 1"],
+                    ),
+                },
+            ],
+        ));
+        program.add(Block::new(
+            TraceStack::return_probe(frame.function, &frame.return_offsets),
+            Some("@depth[tid]".to_string()),
+            vec![
+                "@depth[tid] -= 1".into(),
+                Expression::If {
+                    condition: "@depth[tid] == 0".to_string(),
+                    body: vec!["delete(@depth[tid]); delete(@matched[tid])".into()],
+                },
+            ],
+        ));
+        if let Some(mutex_lock) = mutex_lock {
+            program.add(Block::new(
+                Uprobe(mutex_lock),
+                Some("@matched[tid]".to_string()),
+                vec!["@lock_start[tid] = nsecs"],
+            ));
+            // Acquiring an uncontended mutex takes well under a microsecond
+            program.add(Block::new(
+                Uretprobe(mutex_lock),
+                Some("@lock_start[tid]".to_string()),
+                vec![
+                    Expression::If {
+                        condition: "nsecs - @lock_start[tid] >= 1000".to_string(),
+                        body: record_exprs("@lock_start[tid]")
+                            .into_iter()
+                            .map(|e| e.into())
+                            .collect(),
+                    },
+                    "delete(@lock_start[tid])".into(),
+                ],
+            ));
+        }
+        // FUTEX_WAIT and FUTEX_WAIT_BITSET, without the private flag
+        program.add(Block::new(
+            BlockType::Tracepoint("syscalls:sys_enter_futex"),
+            Some(
+                "@matched[tid] && !@lock_start[tid] && ((args->op & 127) == 0 || (args->op & 127) == 9)"
+                    .to_string(),
+            ),
+            vec!["@futex_start[tid] = nsecs"],
+        ));
+        program.add(Block::new(
+            BlockType::Tracepoint("syscalls:sys_exit_futex"),
+            Some("@futex_start[tid]".to_string()),
+            record_exprs("@futex_start[tid]")
+                .into_iter()
+                .chain(iter::once("delete(@futex_start[tid])".to_string()))
+                .collect::<Vec<String>>(),
+        ));
+        program.add(Block::new(
+            BlockType::Interval {
+                rate_seconds: i32::try_from(seconds).unwrap_or(i32::MAX),
+            },
+            None,
+            vec!["exit()"],
+        ));
+
+        let expr = self.backend.compile(&program);
+        log::debug!("Lock waits bpftrace expression: {}", expr);
+        expr
+    }
+
+    /// bpftrace program that counts allocations and frees made by calls of the
+    /// current function matching its entry filter, and the bytes allocated,
+    /// keyed by user stack. Exits after `seconds`. See
+    /// `allocations::parse_allocations`.
+    pub fn get_allocations_expr(
+        &self,
+        allocators: &[(FunctionName, AllocatorKind)],
+        seconds: u64,
+    ) -> String {
+        let guard = self.stack.lock().unwrap();
+        let frame = guard.frames.last().unwrap();
+        let mut program = BpftraceProgram::new();
+        // `@depth` counts recursive calls, so that the filter is only checked
+        // on the outermost one
+        program.add(Block::new(
+            Uprobe(frame.function),
+            None,
+            vec![
+                "@depth[tid] += 1".into(),
+                Expression::If {
+                    condition: "@depth[tid] == 1".to_string(),
+                    body: TraceStack::add_user_filter(
+                        &frame.filter,
+                        false,
+                        vec!["@matched[tid] = 1"],
+                    ),
+                },
+            ],
+        ));
+        program.add(Block::new(
+            TraceStack::return_probe(frame.function, &frame.return_offsets),
+            Some("@depth[tid]".to_string()),
+            vec![
+                "@depth[tid] -= 1".into(),
+                Expression::If {
+                    condition: "@depth[tid] == 0".to_string(),
+                    body: vec!["delete(@depth[tid]); delete(@matched[tid])".into()],
+                },
+            ],
+        ));
+        let has_operator_new = allocators
+            .iter()
+            .any(|(_, kind)| *kind == AllocatorKind::OperatorNew);
+        for &(function, kind) in allocators {
+            let exprs = match kind.size_expr() {
+                Some(size) => vec![
+                    "@alloc_counts[ustack] = count()".to_string(),
+                    format!("@alloc_bytes[ustack] = sum({})", size),
+                ],
+                None => vec!["@frees[ustack] = count()".to_string()],
+            };
+            // Allocations within `operator new` are counted by it rather
+            // than by `malloc`
+            let condition = match kind {
+                AllocatorKind::OperatorNew => "@matched[tid]",
+                AllocatorKind::Free => "@matched[tid] && arg0",
+                _ if has_operator_new => "@matched[tid] && !@in_new[tid]",
+                _ => "@matched[tid]",
+            };
+            if kind == AllocatorKind::OperatorNew {
+                program.add(Block::new(
+                    Uprobe(function),
+                    Some(condition.to_string()),
+                    exprs
+                        .into_iter()
+                        .chain(iter::once("@in_new[tid] += 1".to_string()))
+                        .collect::<Vec<String>>(),
+                ));
+                program.add(Block::new(
+                    Uretprobe(function),
+                    Some("@in_new[tid]".to_string()),
+                    vec![
+                        "@in_new[tid] -= 1".into(),
+                        Expression::If {
+                            condition: "@in_new[tid] == 0".to_string(),
+                            body: vec!["delete(@in_new[tid])".into()],
+                        },
+                    ],
+                ));
+            } else {
+                program.add(Block::new(
+                    Uprobe(function),
+                    Some(condition.to_string()),
+                    exprs,
+                ));
+            }
+        }
+        program.add(Block::new(
+            BlockType::Interval {
+                rate_seconds: i32::try_from(seconds).unwrap_or(i32::MAX),
+            },
+            None,
+            vec!["exit()"],
+        ));
+
+        let expr = self.backend.compile(&program);
+        log::debug!("Allocations bpftrace expression: {}", expr);
+        expr
+    }
+
+    /// bpftrace program that sums up the time calls of the current function
+    /// matching its entry filter spend in each syscall, keyed by syscall
+    /// number, along with the time spent in the function itself. Exits after
+    /// `seconds`. See `syscalls::parse_syscalls`.
+    pub fn get_syscalls_expr(&self, seconds: u64) -> String {
+        let guard = self.stack.lock().unwrap();
+        let frame = guard.frames.last().unwrap();
+        let mut program = BpftraceProgram::new();
+        // `@depth` counts recursive calls, so that the filter is only checked
+        // on the outermost one
+        program.add(Block::new(
+            Uprobe(frame.function),
+            None,
+            vec![
+                "@depth[tid] += 1".into(),
+                Expression::If {
+                    condition: "@depth[tid] == 1".to_string(),
+                    body: TraceStack::add_user_filter(
+                        &frame.filter,
+                        false,
+                        vec!["@matched[tid] = 1; @function_start[tid] = nsecs"],
+                    ),
+                },
+            ],
+        ));
+        program.add(Block::new(
+            TraceStack::return_probe(frame.function, &frame.return_offsets),
+            Some("@depth[tid]".to_string()),
+            vec![
+                "@depth[tid] -= 1".into(),
+                Expression::If {
+                    condition: "@depth[tid] == 0".to_string(),
+                    body: vec![
+                        Expression::If {
+                            condition: "@matched[tid]".to_string(),
+                            body: vec![
+                                "@function_time = sum(nsecs - @function_start[tid])".into(),
+                                "@function_calls = count()".into(),
+                            ],
+                        },
+                        "delete(@depth[tid]); delete(@matched[tid]); delete(@function_start[tid])"
+                            .into(),
+                    ],
+                },
+            ],
+        ));
+        program.add(Block::new(
+            BlockType::Tracepoint("raw_syscalls:sys_enter"),
+            Some("@matched[tid]".to_string()),
+            vec!["@syscall_start[tid] = nsecs"],
+        ));
+        program.add(Block::new(
+            BlockType::Tracepoint("raw_syscalls:sys_exit"),
+            Some("@syscall_start[tid]".to_string()),
+            vec![
+                "@syscall_time[args->id] = sum(nsecs - @syscall_start[tid])".into(),
+                "@syscall_counts[args->id] = count()".into(),
+                Expression::If {
+                    condition: "args->ret < 0".to_string(),
+                    body: vec!["@syscall_errors[args->id] = count()".into()],
+                },
+                "delete(@syscall_start[tid])".into(),
+            ],
+        ));
+        program.add(Block::new(
+            BlockType::Interval {
+                rate_seconds: i32::try_from(seconds).unwrap_or(i32::MAX),
+            },
+            None,
+            vec!["exit()"],
+        ));
+
+        let expr = self.backend.compile(&program);
+        log::debug!("Syscalls bpftrace expression: {}", expr);
+        expr
+    }
+
+    /// bpftrace program that counts how many times calls of the current
+    /// function matching its entry filter reach each of `heads` (offsets of
+    /// loop heads), along with the number of calls. Exits after `seconds`.
+    /// See `loops::parse_iterations`.
+    pub fn get_loop_iterations_expr(&self, heads: &[u32], seconds: u64) -> String {
+        let guard = self.stack.lock().unwrap();
+        let frame = guard.frames.last().unwrap();
+        let mut program = BpftraceProgram::new();
+        // `@depth` counts recursive calls, so that the filter is only checked
+        // on the outermost one
+        program.add(Block::new(
+            Uprobe(frame.function),
+            None,
+            vec![
+                "@depth[tid] += 1".into(),
+                Expression::If {
+                    condition: "@depth[tid] == 1".to_string(),
+                    body: TraceStack::add_user_filter(
+                        &frame.filter,
+                        false,
+                        vec!["@matched[tid] = 1"],
+                    ),
+                },
+            ],
+        ));
+        program.add(Block::new(
+            TraceStack::return_probe(frame.function, &frame.return_offsets),
+            Some("@depth[tid]".to_string()),
+            vec![
+                "@depth[tid] -= 1".into(),
+                Expression::If {
+                    condition: "@depth[tid] == 0".to_string(),
+                    body: vec![
+                        Expression::If {
+                            condition: "@matched[tid]".to_string(),
+                            body: vec!["@function_calls = count()".into()],
+                        },
+                        "delete(@depth[tid]); delete(@matched[tid])".into(),
+                    ],
+                },
+            ],
+        ));
+        for &head in heads {
+            program.add(Block::new(
+                UprobeOffset(frame.function, head),
+                Some("@matched[tid]".to_string()),
+                vec![format!("@iterations[{}] = count()", head)],
+            ));
+        }
+        program.add(Block::new(
+            BlockType::Interval {
+                rate_seconds: i32::try_from(seconds).unwrap_or(i32::MAX),
+            },
+            None,
+            vec!["exit()"],
+        ));
+
+        let expr = self.backend.compile(&program);
+        log::debug!("Loop iterations bpftrace expression: {}", expr);
+        expr
+    }
+
+    /// bpftrace program that counts the addresses `callsites` (indirect calls
+    /// of the current function, which is at `function_address` in the
+    /// program) jump to, and exits after `seconds`. See `parse_call_targets`.
+    pub fn get_call_targets_expr(
+        &self,
+        callsites: &[CallInstruction],
+        function_address: u64,
+        seconds: u64,
+    ) -> String {
+        let guard = self.stack.lock().unwrap();
+        let function = guard.frames.last().unwrap().function;
+        let mut program = BpftraceProgram::new();
+        for callsite in callsites {
+            let target = match callsite.get_target_expr() {
+                Some(target) => target,
+                None => continue,
+            };
+            // Like for callers, subtracting the load bias gives the address
+            // in the program
+            program.add(Block::new(
+                UprobeOffset(function, callsite.relative_ip),
+                None,
+                vec![format!(
+                    r#"@targets[{} - (reg("ip") - {})] = count()"#,
+                    target,
+                    function_address + u64::from(callsite.relative_ip)
+                )],
+            ));
+        }
+        program.add(Block::new(
+            BlockType::Interval {
+                rate_seconds: i32::try_from(seconds).unwrap_or(i32::MAX),
+            },
+            None,
+            vec!["exit()"],
+        ));
+
+        let expr = self.backend.compile(&program);
+        log::debug!("Call targets bpftrace expression: {}", expr);
+        expr
+    }
+
+    /// Program counting how often each of `cis` in the current function is
+    /// reached in one second, regardless of filters, to estimate the overhead
+    /// of tracing them before they are traced
+    pub fn get_call_rate_expr(&self, cis: &[CallInstruction]) -> String {
+        let function = self.get_current_function();
+        let mut program = BpftraceProgram::new();
+        let offsets: Vec<u32> = cis
+            .iter()
+            .map(|ci| ci.get_relative_ip())
+            .sorted()
+            .dedup()
+            .collect();
+        for offset in offsets {
+            program.add(Block::new(
+                UprobeOffset(function, offset),
+                None,
+                vec![format!("@calls[{}] = count()", offset)],
+            ));
+        }
+        program.add(Block::new(
+            BlockType::Interval { rate_seconds: 1 },
+            None,
+            vec![Expression::Print("@calls".to_string()), "exit()".into()],
+        ));
+        self.backend.compile(&program)
+    }
+
+    /// Calls per second keyed by offset within the function, from the output
+    /// of the program from `get_call_rate_expr`. Offsets that weren't reached
+    /// aren't printed.
+    pub fn parse_call_rate(output: &str) -> HashMap<u32, u64> {
+        output
+            .lines()
+            .filter_map(|line| {
+                let (offset, calls) = line.strip_prefix("@calls[")?.split_once("]: ")?;
+                Some((offset.parse().ok()?, calls.trim().parse().ok()?))
+            })
+            .collect()
+    }
+
+    /// Sum of the values of each map printed by a program with maps keyed by
+    /// user stack (e.g. `@waits[\n\tfoo+26\n\tmain+42\n]: 12000`), keyed by
+    /// the offset within `function` of the innermost frame in it. Entries
+    /// without a frame in `function` are dropped.
+    pub fn parse_stack_maps(output: &str, function: &str) -> HashMap<u64, HashMap<String, u64>> {
+        let mut sums: HashMap<u64, HashMap<String, u64>> = HashMap::new();
+        let mut entry: Option<(&str, Option<u64>)> = None;
+        for line in output.lines() {
+            let line = line.trim();
+            if let Some(map) = line.strip_suffix('[') {
+                entry = Some((map, None));
+                continue;
+            }
+            let (map, offset) = match &mut entry {
+                Some(entry) => entry,
+                None => continue,
+            };
+            match line.strip_prefix("]: ") {
+                Some(value) => {
+                    if let (Some(offset), Ok(value)) = (offset, value.parse::<u64>()) {
+                        *sums
+                            .entry(*offset)
+                            .or_default()
+                            .entry(map.to_string())
+                            .or_default() += value;
+                    }
+                    entry = None;
+                }
+                None if offset.is_none() => {
+                    *offset = line
+                        .rsplit_once('+')
+                        .filter(|(symbol, _)| *symbol == function)
+                        .and_then(|(_, offset)| offset.parse().ok());
+                }
+                None => (),
+            }
+        }
+        sums
+    }
+
+    /// Extract what was printed by the program from `get_single_call_expr`,
+    /// dropping bpftrace's own messages and the maps it prints on exit
+    pub fn parse_single_call(output: &str) -> String {
+        output
+            .lines()
+            .filter(|line| !line.starts_with("Attaching "))
+            .take_while(|line| !line.starts_with('@'))
+            .join("\n")
+            .trim_end()
+            .to_string()
+    }
+
+    /// Parse a record printed by the program from `get_outliers_expr`
+    pub fn parse_outlier(record: &str) -> Option<Outlier> {
+        let mut lines = record.lines();
+        let mut header = lines.next()?.strip_prefix("Outlier ")?.splitn(4, ' ');
+        let time = header.next()?.to_string();
+        let duration = Duration::from_nanos(header.next()?.parse().ok()?);
+        let thread = format!("{} ({})", header.next()?, header.next().unwrap_or(""));
+        let arguments = lines.next()?.strip_prefix("Arguments: ")?.to_string();
+        let stack = lines
+            .take_while(|line| *line != "End")
+            .join("\n")
+            .strip_prefix("Stack:")
+            .map(|stack| stack.trim_end().to_string());
+        Some(Outlier {
+            time,
+            duration,
+            thread,
+            arguments,
+            stack,
+        })
+    }
+
+    /// Block type to trace returns from `function`, see
+    /// `FrameInfo::return_offsets`
+    fn return_probe(function: FunctionName, return_offsets: &Option<Vec<u32>>) -> BlockType {
+        match return_offsets {
+            Some(offsets) => ReturnInstructions(function, offsets.clone()),
+            None => Uretprobe(function),
+        }
+    }
+
+    /// Expressions ending the traced tail calls of `frame`, those on `line`
+    /// only if set, to run first in its return probe. A tail call jumps to its
+    /// callee, which then returns straight to the caller of `frame`, so the
+    /// call ends when `frame` returns. `end_exprs` end the call on a line.
+    fn tail_call_ends<F>(frame: &FrameInfo, line: Option<u32>, end_exprs: F) -> Vec<Expression>
+    where
+        F: Fn(u32) -> Vec<Expression>,
+    {
+        frame
+            .traced_callsites
+            .iter()
+            .filter(|(l, ci)| ci.is_tail_call() && line.map_or(true, |line| line == **l))
+            .sorted_by_key(|(l, _)| **l)
+            .map(|(&l, _)| Expression::If {
+                condition: format!("@start{}[tid]", l),
+                body: end_exprs(l),
+            })
+            .collect()
+    }
+
+    fn add_user_filter<T>(
+        filter: &Option<String>,
+        is_ret_filter: bool,
+        exprs: Vec<T>,
+    ) -> Vec<Expression>
+    where
+        T: Into<Expression>,
+    {
+        let mut exprs = exprs.into_iter().map(|e| e.into()).collect();
+        match filter {
+            None => exprs,
+            Some(f) => {
+                // If this is a ret filter, we need to update depth (i.e. run
+                // `exprs`) unconditionally, but maintain
+                // `@matched_retfilters[tid]` depending on the filter. For an
+                // entry filter, we skip updating depth if it doesn't match.
 
                 // TODO need to use bitwise `|=` rather than ++
                 if is_ret_filter {
@@ -812,6 +3751,37 @@ impl TraceStack {
                 count: tuple.1,
             }
         };
+        let off_cpu = info.off_cpu.map(|off_cpu| {
+            off_cpu
+                .into_iter()
+                .map(|(line, duration)| {
+                    (line.parse::<u32>().unwrap(), Duration::from_nanos(duration))
+                })
+                .collect()
+        });
+        let aborted = info.aborted.map(|aborted| {
+            aborted
+                .into_iter()
+                .map(|(line, count)| (line.parse::<u32>().unwrap(), count))
+                .collect()
+        });
+        let statistics = info.stats.map(|stats| {
+            stats
+                .into_iter()
+                .map(|(line, (min, max, sum_squares, calls))| {
+                    // Same as the sum of the histogram's values
+                    let duration = info
+                        .lines
+                        .as_ref()
+                        .and_then(|lines| lines.get(&line))
+                        .map_or(0, |&(duration, _)| duration);
+                    (
+                        line.parse::<u32>().unwrap(),
+                        LineStatistics::new(min, max, duration, sum_squares, calls),
+                    )
+                })
+                .collect()
+        });
         let traces = if let Some(lines) = info.lines {
             TraceInfoMode::Lines(
                 lines
@@ -827,6 +3797,24 @@ impl TraceStack {
             )
         } else if let Some(histogram) = info.histogram {
             TraceInfoMode::Histogram(histogram)
+        } else if let Some(callers) = info.callers {
+            TraceInfoMode::Callers(TraceStack::parse_callers(&callers))
+        } else if let Some(args) = info.args {
+            TraceInfoMode::Arguments(
+                (0..args.len())
+                    .map(|i| TraceStack::parse_argument_values(&args[&i.to_string()], i))
+                    .collect(),
+            )
+        } else if let Some(groups) = info.groups {
+            TraceInfoMode::Groups(TraceStack::parse_groups(
+                &groups,
+                &info.group_durations.unwrap_or_default(),
+            ))
+        } else if let Some(retvals) = info.retvals {
+            TraceInfoMode::ReturnValues(TraceStack::parse_return_values(
+                &retvals,
+                &info.retval_durations.unwrap_or_default(),
+            ))
         } else {
             let breakdown = info.breakdown.unwrap();
             TraceInfoMode::Breakdown {
@@ -840,18 +3828,804 @@ impl TraceStack {
                     .collect(),
             }
         };
+        let pinned = info.pinned.map(|pinned| {
+            pinned
+                .into_iter()
+                .map(|(i, value)| {
+                    (
+                        i.parse::<usize>().unwrap(),
+                        tuple_to_trace_cumulative(value),
+                    )
+                })
+                .sorted_by_key(|(i, _)| *i)
+                .map(|(_, v)| v)
+                .collect()
+        });
+        let workspaces = info.workspaces.map(|workspaces| {
+            workspaces
+                .into_iter()
+                .map(|(i, value)| {
+                    (
+                        i.parse::<usize>().unwrap(),
+                        tuple_to_trace_cumulative(value),
+                    )
+                })
+                .collect()
+        });
+        let histograms = info.histograms.map(|histograms| {
+            histograms
+                .into_iter()
+                .map(|(line, histogram)| (line.parse::<u32>().unwrap(), histogram))
+                .collect()
+        });
         Ok(TraceInfo {
             counter,
             time: Duration::from_secs(info.time),
             traces,
+            histograms,
+            statistics,
+            off_cpu,
+            aborted,
+            counters: None,
+            pinned,
+            workspaces,
         })
     }
 
+    /// Parse the printed `@callers` map, lines of which look like
+    /// `@callers[<return address>]: <count>`. Most frequent callers are
+    /// returned first.
+    fn parse_callers(callers: &str) -> Vec<(u64, u64)> {
+        TraceStack::parse_address_counts(callers, "@callers")
+    }
+
+    /// Parse the `@targets` map printed by the program from
+    /// `get_call_targets_expr`, like `parse_callers`
+    pub fn parse_call_targets(output: &str) -> Vec<(u64, u64)> {
+        TraceStack::parse_address_counts(output, "@targets")
+    }
+
+    fn parse_address_counts(printed: &str, map: &str) -> Vec<(u64, u64)> {
+        let prefix = format!("{}[", map);
+        printed
+            .lines()
+            .filter_map(|line| {
+                let (address, count) = line.strip_prefix(&prefix)?.split_once("]: ")?;
+                // Addresses outside of the program can end up negative
+                let address = address.parse::<i64>().ok()? as u64;
+                Some((address, count.trim().parse::<u64>().ok()?))
+            })
+            .sorted_by_key(|&(address, count)| (std::cmp::Reverse(count), address))
+            .collect()
+    }
+
+    /// Parse the printed `@arg<column>` map, lines of which look like
+    /// `@arg0[<value>]: <count>`. Most frequent values are returned first.
+    fn parse_argument_values(printed: &str, column: usize) -> Vec<(String, u64)> {
+        let prefix = format!("@arg{}[", column);
+        printed
+            .lines()
+            .filter_map(|line| {
+                // Strings can contain anything, but not end in the count
+                let (value, count) = line.strip_prefix(&prefix)?.rsplit_once("]: ")?;
+                Some((value.to_string(), count.trim().parse::<u64>().ok()?))
+            })
+            .sorted_by(|(a_value, a_count), (b_value, b_count)| {
+                b_count.cmp(a_count).then_with(|| a_value.cmp(b_value))
+            })
+            .collect()
+    }
+
+    /// Parse the printed `@retvals` and `@retval_durations` maps, lines of
+    /// which look like `@retvals[<value>]: <count>`. Most frequent values are
+    /// returned first.
+    fn parse_return_values(retvals: &str, durations: &str) -> Vec<ReturnValueCount> {
+        let parse_map = |map: &str, name: &str| -> HashMap<i64, u64> {
+            map.lines()
+                .filter_map(|line| {
+                    let (value, number) = line.strip_prefix(name)?.split_once("]: ")?;
+                    Some((value.parse().ok()?, number.trim().parse().ok()?))
+                })
+                .collect()
+        };
+        let durations = parse_map(durations, "@retval_durations[");
+        parse_map(retvals, "@retvals[")
+            .into_iter()
+            .map(|(value, count)| ReturnValueCount {
+                value,
+                count,
+                duration: Duration::from_nanos(durations.get(&value).copied().unwrap_or(0)),
+            })
+            .sorted_by_key(|r| (std::cmp::Reverse(r.count), r.value))
+            .collect()
+    }
+
+    /// Parse the printed `@group_counts` and `@group_durations` maps, lines of
+    /// which look like `@group_counts[<value>]: <count>`. Most frequent values
+    /// are returned first.
+    fn parse_groups(counts: &str, durations: &str) -> Vec<GroupCount> {
+        let parse_map = |map: &str, name: &str| -> HashMap<String, u64> {
+            map.lines()
+                .filter_map(|line| {
+                    // Strings can contain anything, but not end in the number
+                    let (value, number) = line.strip_prefix(name)?.rsplit_once("]: ")?;
+                    Some((value.to_string(), number.trim().parse().ok()?))
+                })
+                .collect()
+        };
+        let durations = parse_map(durations, "@group_durations[");
+        parse_map(counts, "@group_counts[")
+            .into_iter()
+            .map(|(value, count)| GroupCount {
+                duration: Duration::from_nanos(durations.get(&value).copied().unwrap_or(0)),
+                value,
+                count,
+            })
+            .sorted_by(|a, b| b.count.cmp(&a.count).then_with(|| a.value.cmp(&b.value)))
+            .collect()
+    }
+
     pub fn is_counter_current(&self, counter: u64) -> bool {
         counter == self.counter.load(Ordering::Acquire)
     }
 }
 
-pub fn bpftrace_cmd() -> Command {
-    Command::new("bpftrace")
+/// Text of histogram `name` as bpftrace prints it, from its buckets in JSON
+/// output like `{"min": 4, "max": 7, "count": 3}`. The bucket of negative
+/// values has no `min`.
+fn format_histogram(name: &str, buckets: &serde_json::Value) -> String {
+    const BAR_WIDTH: u64 = 52;
+    let buckets: Vec<(Option<i64>, i64, u64)> = buckets
+        .as_array()
+        .map(Vec::as_slice)
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|bucket| {
+            Some((
+                bucket["min"].as_i64(),
+                bucket["max"].as_i64()?,
+                bucket["count"].as_u64()?,
+            ))
+        })
+        .collect();
+    if buckets.is_empty() {
+        return String::new();
+    }
+    let max_count = buckets
+        .iter()
+        .map(|(_, _, count)| *count)
+        .max()
+        .unwrap_or(0);
+    let mut text = format!("{}:\n", name);
+    for (min, max, count) in buckets {
+        let label = match min {
+            None => "(..., 0)".to_string(),
+            Some(min) if min == max => format!("[{}]", min),
+            Some(min) => format!("[{}, {})", histogram_label(min), histogram_label(max + 1)),
+        };
+        let bar_width = if max_count == 0 {
+            0
+        } else {
+            count * BAR_WIDTH / max_count
+        };
+        text += &format!(
+            "{:<16}{:>8} |{:<width$}|\n",
+            label,
+            count,
+            "@".repeat(bar_width as usize),
+            width = BAR_WIDTH as usize
+        );
+    }
+    text
+}
+
+/// Bucket bound as bpftrace labels it, e.g. `4K` for 4096
+fn histogram_label(value: i64) -> String {
+    const SUFFIXES: [(i64, &str); 4] = [
+        (1 << 40, "T"),
+        (1 << 30, "G"),
+        (1 << 20, "M"),
+        (1 << 10, "K"),
+    ];
+    if value == 0 {
+        return "0".to_string();
+    }
+    match SUFFIXES.iter().find(|(unit, _)| value % unit == 0) {
+        Some((unit, suffix)) => format!("{}{}", value / unit, suffix),
+        None => value.to_string(),
+    }
+}
+
+/// Value of a bucket bound as labelled by `histogram_label`, e.g. 2048 for `2K`
+fn parse_histogram_label(label: &str) -> Option<u64> {
+    let (number, unit) = match label.chars().last()? {
+        'K' => (&label[..label.len() - 1], 1 << 10),
+        'M' => (&label[..label.len() - 1], 1 << 20),
+        'G' => (&label[..label.len() - 1], 1 << 30),
+        'T' => (&label[..label.len() - 1], 1 << 40),
+        _ => (label, 1),
+    };
+    Some(number.parse::<u64>().ok()? * unit)
+}
+
+/// `percentile` (between 0 and 100) of the durations in a histogram printed
+/// by bpftrace (see `format_histogram`), interpolated linearly within the
+/// power of 2 bucket it falls in. `None` if the histogram is empty.
+pub fn histogram_percentile(histogram: &str, percentile: f64) -> Option<Duration> {
+    let buckets: Vec<(u64, u64, u64)> = histogram
+        .lines()
+        .filter_map(|line| {
+            let (bucket, _) = line.split_once(" |")?;
+            let (label, count) = bucket.trim_end().rsplit_once(' ')?;
+            let count = count.parse().ok()?;
+            let label = label.trim();
+            if let Some(value) = label.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                let value = parse_histogram_label(value)?;
+                return Some((value, value, count));
+            }
+            let (min, max) = label
+                .strip_prefix('[')?
+                .strip_suffix(')')?
+                .split_once(", ")?;
+            Some((
+                parse_histogram_label(min)?,
+                parse_histogram_label(max)?,
+                count,
+            ))
+        })
+        .collect();
+    let total: u64 = buckets.iter().map(|(_, _, count)| count).sum();
+    if total == 0 {
+        return None;
+    }
+    let target = total as f64 * percentile / 100.0;
+    let mut cumulative = 0;
+    for &(min, max, count) in &buckets {
+        if count > 0 && (cumulative + count) as f64 >= target {
+            let fraction = (target - cumulative as f64) / count as f64;
+            let value = min as f64 + fraction * (max - min) as f64;
+            return Some(Duration::from_nanos(value as u64));
+        }
+        cumulative += count;
+    }
+    buckets.last().map(|&(_, max, _)| Duration::from_nanos(max))
+}
+
+/// Text of map `name` as bpftrace prints it, e.g. `@callers[42]: 3` for each
+/// key
+fn format_map(name: &str, map: &serde_json::Value) -> String {
+    match map {
+        serde_json::Value::Object(entries) => entries
+            .iter()
+            .map(|(key, value)| format!("{}[{}]: {}\n", name, key, value))
+            .collect(),
+        value => format!("{}: {}\n", name, value),
+    }
+}
+
+/// Parent pid from the contents of `/proc/<pid>/stat`
+fn parse_ppid(stat: &str) -> Option<u32> {
+    // The process name in parentheses can contain spaces and parentheses
+    let (_, fields) = stat.rsplit_once(')')?;
+    fields.split_whitespace().nth(1)?.parse().ok()
+}
+
+/// Tids of the threads named `name` of `pids`, or of any process if empty,
+/// from `/proc/<pid>/task/<tid>/comm`
+fn threads_named(pids: &[u32], name: &str) -> Vec<u32> {
+    let pids: Vec<u32> = if pids.is_empty() {
+        std::fs::read_dir("/proc")
+            .into_iter()
+            .flatten()
+            .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok())
+            .collect()
+    } else {
+        pids.to_vec()
+    };
+    pids.iter()
+        .filter_map(|pid| std::fs::read_dir(format!("/proc/{}/task", pid)).ok())
+        .flatten()
+        .filter_map(|entry| {
+            let entry = entry.ok()?;
+            let tid = entry.file_name().to_str()?.parse().ok()?;
+            // Threads can exit while we're reading
+            let comm = std::fs::read_to_string(entry.path().join("comm")).ok()?;
+            (comm.trim_end() == name).then(|| tid)
+        })
+        .collect()
+}
+
+/// Pids of the running descendants of `pids`, from `/proc`
+fn descendants(pids: &[u32]) -> Vec<u32> {
+    let entries = match std::fs::read_dir("/proc") {
+        Ok(entries) => entries,
+        Err(err) => {
+            log::warn!("Failed to list processes: {}", err);
+            return Vec::new();
+        }
+    };
+    let mut children: HashMap<u32, Vec<u32>> = HashMap::new();
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let pid = match entry
+            .file_name()
+            .to_str()
+            .and_then(|n| n.parse::<u32>().ok())
+        {
+            Some(pid) => pid,
+            None => continue,
+        };
+        // Processes can exit while we're reading
+        if let Some(ppid) = std::fs::read_to_string(entry.path().join("stat"))
+            .ok()
+            .and_then(|stat| parse_ppid(&stat))
+        {
+            children.entry(ppid).or_default().push(pid);
+        }
+    }
+    let mut descendants = Vec::new();
+    let mut queue: Vec<u32> = pids.to_vec();
+    while let Some(pid) = queue.pop() {
+        for &child in children.get(&pid).into_iter().flatten() {
+            if !pids.contains(&child) && !descendants.contains(&child) {
+                descendants.push(child);
+                queue.push(child);
+            }
+        }
+    }
+    descendants
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_callers() {
+        let callers = "@callers[4198716]: 3\n@callers[-12]: 1\n@callers[4199012]: 10\n\n";
+        assert_eq!(
+            TraceStack::parse_callers(callers),
+            vec![(4199012, 10), (4198716, 3), (-12i64 as u64, 1)]
+        );
+    }
+
+    #[test]
+    fn test_call_targets() {
+        let output = "Attaching 2 probes...\n\n\n@targets[4198400]: 20\n@targets[4198656]: 70\n";
+        assert_eq!(
+            TraceStack::parse_call_targets(output),
+            vec![(4198656, 70), (4198400, 20)]
+        );
+        assert_eq!(
+            CallInstruction::register(16, 2, "rax".to_string(), None).get_target_expr(),
+            Some(r#"reg("ax")"#.to_string())
+        );
+        assert_eq!(
+            CallInstruction::register(16, 4, "r12".to_string(), Some(24)).get_target_expr(),
+            Some(r#"*(uint64 *)(reg("r12") + 24)"#.to_string())
+        );
+        assert_eq!(
+            CallInstruction::register(16, 6, "rip".to_string(), Some(4096)).get_target_expr(),
+            Some(r#"*(uint64 *)(reg("ip") + 4102)"#.to_string())
+        );
+        assert_eq!(CallInstruction::manual(16, 5).get_target_expr(), None);
+    }
+
+    #[test]
+    fn test_parse_single_call() {
+        let output = "Attaching 2 probes...\nThread 42 (server)\nArguments: 0x1 0x0 0x0 0x0 0x0 0x0\nStack:\n\tfoo+0\n\tmain+20\n\nReturned 0x0 after 1200 ns\n\n\n@depth: 0\n@start: 123\n@tid: 42\n";
+        assert_eq!(
+            TraceStack::parse_single_call(output),
+            "Thread 42 (server)\nArguments: 0x1 0x0 0x0 0x0 0x0 0x0\nStack:\n\tfoo+0\n\tmain+20\n\nReturned 0x0 after 1200 ns"
+        );
+    }
+
+    #[test]
+    fn test_parse_outlier() {
+        let record = "Outlier 12:03:04 2500000 42 server worker\nArguments: 0x1 0x0 0x0 0x0 0x0 0x0\nStack:\n\tmain+20\n\nEnd\n";
+        let outlier = TraceStack::parse_outlier(record).unwrap();
+        assert_eq!(outlier.time, "12:03:04");
+        assert_eq!(outlier.duration, Duration::from_micros(2500));
+        assert_eq!(outlier.thread, "42 (server worker)");
+        assert_eq!(outlier.arguments, "0x1 0x0 0x0 0x0 0x0 0x0");
+        assert_eq!(outlier.stack.as_deref(), Some("\n\tmain+20"));
+
+        let record =
+            "Outlier 12:03:04 2500000 42 server\nArguments: 0x1 0x0 0x0 0x0 0x0 0x0\nEnd\n";
+        assert_eq!(TraceStack::parse_outlier(record).unwrap().stack, None);
+        assert!(TraceStack::parse_outlier("Attaching 2 probes...\n").is_none());
+    }
+
+    #[test]
+    fn test_argument_columns() {
+        let parameter = |name: &str, type_name: &str, value_type: ValueType| Parameter {
+            name: name.to_string(),
+            type_name: type_name.to_string(),
+            value_type,
+        };
+        let int = ValueType::Integer {
+            size: 4,
+            signed: true,
+        };
+        let point = ValueType::Struct {
+            size: 16,
+            fields: vec![
+                (0, parameter("x", "int", int.clone())),
+                (
+                    4,
+                    parameter(
+                        "y",
+                        "unsigned short",
+                        ValueType::Integer {
+                            size: 2,
+                            signed: false,
+                        },
+                    ),
+                ),
+                (8, parameter("label", "const char*", ValueType::CString)),
+            ],
+        };
+        let parameters = vec![
+            parameter("this", "Foo*", ValueType::Pointer),
+            parameter("scale", "double", ValueType::Float),
+            parameter("", "int", int.clone()),
+            parameter("big", "Big", ValueType::Other { size: 64 }),
+            parameter("p", "Point", point),
+            parameter("n", "int", int.clone()),
+            parameter("rest", "?", ValueType::Unknown),
+            parameter("last", "int", int),
+        ];
+        let columns: Vec<(String, String)> = argument_columns(None, &parameters)
+            .into_iter()
+            .map(|c| (c.label, c.expr))
+            .collect();
+        let expected = vec![
+            ("this", r#"reg("di")"#),
+            ("arg2", r#"(int32)reg("si")"#),
+            ("p.x", r#"(int32)reg("dx")"#),
+            ("p.y", r#"(uint16)(reg("dx") >> 32)"#),
+            ("p.label", r#"str(reg("cx"))"#),
+            ("n", r#"(int32)reg("r8")"#),
+        ];
+        assert_eq!(
+            columns,
+            expected
+                .into_iter()
+                .map(|(l, e)| (l.to_string(), e.to_string()))
+                .collect::<Vec<_>>()
+        );
+
+        // A hidden pointer to the returned value takes the first register
+        let columns = argument_columns(Some(&ValueType::Other { size: 32 }), &parameters);
+        assert_eq!(columns[0].expr, r#"reg("si")"#);
+    }
+
+    #[test]
+    fn test_parse_argument_values() {
+        let printed = "@arg1[foo]: 3\n@arg1[a]: b]: 7\n@arg1[]: 3\n";
+        assert_eq!(
+            TraceStack::parse_argument_values(printed, 1),
+            vec![
+                ("a]: b".to_string(), 7),
+                ("".to_string(), 3),
+                ("foo".to_string(), 3)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_histogram_percentile() {
+        let histogram = "@histogram10:\n[1]                    2 |@@|\n[1K, 2K)               4 |@@@@|\n[2K, 4K)               4 |@@@@|\n";
+        let percentile = |p: f64| histogram_percentile(histogram, p).map(|d| d.as_nanos());
+        assert_eq!(percentile(10.0), Some(1));
+        assert_eq!(percentile(60.0), Some(2048));
+        assert_eq!(percentile(80.0), Some(3072));
+        assert_eq!(percentile(100.0), Some(4096));
+        assert_eq!(histogram_percentile("", 50.0), None);
+        assert_eq!(parse_histogram_label("16M"), Some(16 << 20));
+    }
+
+    #[test]
+    fn test_parse_groups() {
+        let counts = "@group_counts[GET]: 40\n@group_counts[POST]: 2\n@group_counts[]: 2\n";
+        let durations = "@group_durations[GET]: 4000\n@group_durations[POST]: 900\n";
+        let groups: Vec<(String, u64, u64)> = TraceStack::parse_groups(counts, durations)
+            .into_iter()
+            .map(|g| (g.value, g.count, g.duration.as_nanos() as u64))
+            .collect();
+        assert_eq!(
+            groups,
+            vec![
+                ("GET".to_string(), 40, 4000),
+                ("".to_string(), 2, 0),
+                ("POST".to_string(), 2, 900)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_return_values() {
+        let retvals = "@retvals[-11]: 3\n@retvals[0]: 120\n@retvals[4096]: 3\n";
+        let durations =
+            "@retval_durations[-11]: 900\n@retval_durations[0]: 6000\n@retval_durations[4096]: 30\n";
+        let counts = TraceStack::parse_return_values(retvals, durations);
+        let values: Vec<(i64, u64, u64)> = counts
+            .iter()
+            .map(|r| (r.value, r.count, r.duration.as_nanos() as u64))
+            .collect();
+        assert_eq!(values, vec![(0, 120, 6000), (-11, 3, 900), (4096, 3, 30)]);
+    }
+
+    #[test]
+    fn test_parse_call_rate() {
+        let output = "Attaching 3 probes...\n@calls[16]: 731204\n@calls[42]: 12\n\n\n";
+        let rates = TraceStack::parse_call_rate(output);
+        assert_eq!(rates.len(), 2);
+        assert_eq!(rates[&16], 731204);
+        assert_eq!(rates[&42], 12);
+        assert!(TraceStack::parse_call_rate("Attaching 3 probes...\n").is_empty());
+    }
+
+    #[test]
+    fn test_parse_line_histograms() {
+        let line = "{\"time\": 3, \"lines\": {\"10\": [400, 2], \"12\": [0, 0]}, \"histograms\": {\"10\": \"@histogram10:\n[128, 256)  2 |@@|\n\", \"12\": \"\"}, \"stats\": {\"10\": [150, 250, 62500, 2], \"12\": [0, 0, 0, 0]}}";
+        let info = TraceStack::parse(line, 1).unwrap();
+        let histograms = info.histograms.unwrap();
+        assert_eq!(histograms[&10], "@histogram10:\n[128, 256)  2 |@@|\n");
+        assert_eq!(histograms[&12], "");
+        let statistics = info.statistics.unwrap();
+        assert_eq!(statistics[&10].min, Duration::from_nanos(150));
+        assert_eq!(statistics[&10].max, Duration::from_nanos(250));
+        assert_eq!(statistics[&12].calls, 0);
+    }
+
+    #[test]
+    fn test_parse_off_cpu() {
+        let line = "{\"time\": 3, \"lines\": {\"10\": [400, 2], \"12\": [0, 0]}, \"off_cpu\": {\"10\": 300, \"12\": 0}}";
+        let info = TraceStack::parse(line, 1).unwrap();
+        let off_cpu = info.off_cpu.unwrap();
+        assert_eq!(off_cpu[&10], Duration::from_nanos(300));
+        assert_eq!(off_cpu[&12], Duration::from_nanos(0));
+        assert!(info.statistics.is_none());
+    }
+
+    #[test]
+    fn test_parse_aborted() {
+        let line = "{\"time\": 3, \"lines\": {\"10\": [400, 2], \"12\": [0, 0]}, \"aborted\": {\"10\": 0, \"12\": 5}}";
+        let info = TraceStack::parse(line, 1).unwrap();
+        let aborted = info.aborted.unwrap();
+        assert_eq!(aborted[&10], 0);
+        assert_eq!(aborted[&12], 5);
+        assert!(info.off_cpu.is_none());
+    }
+
+    #[test]
+    fn test_parse_pinned() {
+        let line = "{\"time\": 3, \"lines\": {\"10\": [400, 2]}, \"aborted\": {\"10\": 0}, \"pinned\": {\"1\": [900, 3], \"0\": [0, 0]}}";
+        let info = TraceStack::parse(line, 1).unwrap();
+        let pinned = info.pinned.unwrap();
+        assert_eq!(pinned.len(), 2);
+        assert_eq!(pinned[0].count, 0);
+        assert_eq!(pinned[1].duration, Duration::from_nanos(900));
+        assert_eq!(pinned[1].count, 3);
+    }
+
+    #[test]
+    fn test_json_output() {
+        let mut output = JsonOutput::default();
+        let records = [
+            r#"{"type": "attached_probes", "data": {"probes": 4}}"#,
+            r#"{"type": "printf", "data": "{\"time\": 2, \"histogram\": \""}"#,
+            r#"{"type": "hist", "data": {"@histogram": [{"min": 1, "max": 1, "count": 1}, {"min": 2048, "max": 4095, "count": 4}]}}"#,
+        ];
+        for record in &records {
+            assert_eq!(output.push(record).unwrap(), None);
+        }
+        let report = output
+            .push(r#"{"type": "printf", "data": "\"}\n"}"#)
+            .unwrap()
+            .unwrap();
+        let info = TraceStack::parse(&report, 1).unwrap();
+        match info.traces {
+            TraceInfoMode::Histogram(histogram) => assert_eq!(
+                histogram,
+                format!(
+                    "@histogram:\n[1]                    1 |{:<52}|\n[2K, 4K)               4 |{}|\n",
+                    "@".repeat(13),
+                    "@".repeat(52)
+                )
+            ),
+            _ => panic!("Expected a histogram"),
+        }
+        // Maps printed on exit
+        assert_eq!(
+            output
+                .push(r#"{"type": "map", "data": {"@callers": {"42": 3}}}"#)
+                .unwrap(),
+            None
+        );
+        assert_eq!(
+            format_map("@callers", &serde_json::json!({"42": 3})),
+            "@callers[42]: 3\n"
+        );
+        assert!(output.push("Attaching 4 probes...").is_err());
+    }
+
+    #[test]
+    fn test_call_instruction_column() {
+        let ci = CallInstruction::function(12, 5, FunctionName("foo"));
+        assert_eq!(ci.to_string(), "12: foo");
+        let ci = ci.with_column(Some(17));
+        assert_eq!(ci.get_column(), Some(17));
+        assert_eq!(ci.to_string(), "12: [col 17] foo");
+    }
+
+    #[test]
+    fn test_callee_lines() {
+        let mut frame = FrameInfo::new(
+            FunctionName("main"),
+            "main.c".to_string(),
+            1,
+            HashMap::new(),
+            Vec::new(),
+            None,
+        );
+        let inlined = CallInstruction {
+            relative_ip: 10,
+            length: 40,
+            column: None,
+            exits: Vec::new(),
+            tail_call: false,
+            instruction: InstructionType::Inlined("bar".to_string()),
+        };
+        let span = CallInstruction {
+            instruction: InstructionType::Span(9),
+            ..CallInstruction::manual(60, 40)
+        };
+        let traced = vec![
+            (2, inlined),
+            (3, CallInstruction::function(20, 5, FunctionName("baz"))),
+            (5, span),
+            (6, CallInstruction::function(70, 5, FunctionName("qux"))),
+            (7, CallInstruction::manual(80, 10)),
+            (10, CallInstruction::function(120, 5, FunctionName("quux"))),
+        ];
+        frame.traced_callsites.extend(traced);
+        // The call within the inlined function is timed as part of it, the
+        // one within the span isn't
+        assert_eq!(frame.callee_lines(), vec![2, 6, 10]);
+    }
+
+    #[test]
+    fn test_tail_call() {
+        let ci = CallInstruction::function(12, 5, FunctionName("foo"));
+        assert_eq!(ci.get_end_offsets(), vec![17]);
+        let ci = ci.as_tail_call();
+        assert!(ci.is_tail_call());
+        assert_eq!(ci.to_string(), "12: (tail) foo");
+        assert!(ci.get_end_offsets().is_empty());
+    }
+
+    #[test]
+    fn test_recursive_program() {
+        let frame = |function| {
+            FrameInfo::new(
+                FunctionName(function),
+                "fib.c".to_string(),
+                1,
+                HashMap::new(),
+                Vec::new(),
+                None,
+            )
+        };
+        let (tx, _rx) = std::sync::mpsc::channel();
+        let stack = TraceStack::new(
+            Arc::new(crate::backend::BpftraceBackend::new(
+                "/bin/fib".to_string(),
+                "bpftrace".into(),
+            )),
+            Capabilities::unknown(),
+            frame("main"),
+            tx,
+        );
+        stack.push(frame("fib"));
+        let program = stack.get_bpftrace_expr().0;
+        // Each frame returns once the stack pointer is back up to where it
+        // was on entry, and lines are only timed in the outermost call of the
+        // current function
+        for expected in &[
+            r#"@frame_sp0[tid] = reg("sp");"#,
+            r#"@frame_sp1[tid] = reg("sp");"#,
+            r#"/@depth[tid] == 1 && reg("sp") >= @frame_sp0[tid]/"#,
+            r#"/@depth[tid] == 2 && !@level[tid] && reg("sp") >= @frame_sp1[tid]/"#,
+            r#"/@depth[tid] == 2 && reg("sp") < @frame_sp1[tid]/ { @level[tid] += 1; @recursive_start[tid, @level[tid]] = nsecs; }"#,
+        ] {
+            assert!(
+                program.contains(expected),
+                "{} not in {}",
+                expected,
+                program
+            );
+        }
+        assert!(stack.get_native_plan().unwrap().0.recursive_calls);
+
+        stack.set_outermost_calls_only(true);
+        let program = stack.get_bpftrace_expr().0;
+        assert!(program.contains(
+            r#"/@depth[tid] == 2 && reg("sp") < @frame_sp1[tid]/ { @level[tid] += 1; }"#
+        ));
+        assert!(!program.contains("@recursive_start"));
+        assert!(!stack.get_native_plan().unwrap().0.recursive_calls);
+
+        // Stacks of Go programs move as they grow
+        stack.set_movable_stacks(true);
+        let program = stack.get_bpftrace_expr().0;
+        assert!(!program.contains(r#"reg("sp")"#));
+        assert!(!program.contains("@level"));
+    }
+
+    #[test]
+    fn test_process_filter() {
+        assert_eq!(ProcessFilter::default().predicate(), None);
+        let filter = ProcessFilter::from_fields("12, 34", "", "nginx", "0", "", false).unwrap();
+        assert_eq!(
+            filter.predicate().unwrap(),
+            r#"(pid == 12 || pid == 34) && comm == "nginx" && uid == 0"#
+        );
+        assert_eq!(filter.to_string(), "pid=12,34 comm=nginx uid=0");
+        assert_eq!(ProcessFilter::parse(&filter.to_string()).unwrap(), filter);
+        let fields = filter.fields();
+        assert_eq!(
+            ProcessFilter::from_fields(
+                &fields[0], &fields[1], &fields[2], &fields[3], &fields[4], false
+            )
+            .unwrap(),
+            filter
+        );
+        assert!(ProcessFilter::parse("").unwrap().is_empty());
+        assert!(ProcessFilter::from_fields("12 x", "", "", "", "", false).is_err());
+
+        let filter =
+            ProcessFilter::from_fields("", "7, worker, io_thread", "", "", "", false).unwrap();
+        assert_eq!(filter.tids, vec![7]);
+        assert_eq!(filter.thread_names, vec!["worker", "io_thread"]);
+        assert_eq!(filter.to_string(), "tid=7,worker,io_thread");
+        assert_eq!(ProcessFilter::parse(&filter.to_string()).unwrap(), filter);
+        assert_eq!(filter.fields()[1], "7, worker, io_thread");
+        assert!(
+            ProcessFilter::from_fields("", "a_very_long_thread_name", "", "", "", false).is_err()
+        );
+        // No process has this pid, so only the tid is traced
+        let filter = ProcessFilter::from_fields("0", "7 worker", "", "", "", false).unwrap();
+        assert_eq!(filter.predicate().unwrap(), "(pid == 0) && (tid == 7)");
+        assert!(
+            ProcessFilter::from_fields("", "", "a_very_long_process_name", "", "", false).is_err()
+        );
+
+        let filter = ProcessFilter::parse("pid=12 follow-forks").unwrap();
+        assert!(filter.follow_forks);
+        assert_eq!(filter.predicate().unwrap(), "@traced_pids[pid]");
+        assert_eq!(filter.to_string(), "pid=12 follow-forks");
+        assert!(ProcessFilter::parse("follow-forks").is_err());
+    }
+
+    #[test]
+    fn test_parse_interval() {
+        assert_eq!(parse_interval("500ms").unwrap(), Duration::from_millis(500));
+        assert_eq!(parse_interval("2s").unwrap(), Duration::from_secs(2));
+        assert_eq!(parse_interval(" 3 ").unwrap(), Duration::from_secs(3));
+        assert!(parse_interval("10ms").is_err());
+        assert!(parse_interval("5m").is_err());
+        assert!(parse_interval("0").is_err());
+        assert_eq!(format_interval(Duration::from_millis(1500)), "1500ms");
+        assert_eq!(format_interval(Duration::from_secs(2)), "2s");
+    }
+
+    #[test]
+    fn test_parse_ppid() {
+        assert_eq!(
+            parse_ppid("1234 (my (weird) prog) S 42 1234 1234 0 -1 4194560"),
+            Some(42)
+        );
+        assert_eq!(parse_ppid("garbage"), None);
+    }
 }
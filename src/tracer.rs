@@ -1,16 +1,98 @@
 use crate::error::Error;
 use crate::events::Event;
-use crate::trace_structs::{bpftrace_cmd, TraceStack};
-use std::io::{BufRead, Read};
+use crate::losses;
+use crate::native::NativePlan;
+use crate::trace_structs::{JsonOutput, TraceStack};
+use std::collections::VecDeque;
+use std::io::BufRead;
 use std::process::Stdio;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::{mpsc, Arc};
+use std::sync::{mpsc, Arc, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
+
+/// Lines of stderr kept in `TracerStatus::warnings`
+const MAX_WARNINGS: usize = 20;
 
 /// Encapsulates a scheme for tracing a particular program and its functions
 pub struct Tracer {
     tx: mpsc::Sender<TraceCommand>,
     command_thread: Option<thread::JoinHandle<()>>,
+    status: Arc<Mutex<TracerStatus>>,
+}
+
+/// State of the current trace, to tell whether anything is running when no
+/// results show up
+#[derive(Clone, Debug, Default)]
+pub struct TracerStatus {
+    /// Process id of the program run by the backend, `None` when tracing
+    /// in-process (see `TraceBackend::run_native`)
+    pub pid: Option<u32>,
+    /// When the trace was last (re)started, `None` until it first is
+    pub started: Option<Instant>,
+    /// Times the trace was restarted, e.g. because the trace stack changed
+    pub restarts: u64,
+    /// Probes attached, as printed by bpftrace or planned in-process
+    pub probes: Option<u64>,
+    /// Lines of output read since the trace started
+    pub records: u64,
+    /// Reports parsed into trace data since the trace started
+    pub reports: u64,
+    /// How the program exited, if it did on its own
+    pub exit_status: Option<String>,
+    /// Last lines printed to stderr, oldest first
+    pub warnings: VecDeque<String>,
+}
+
+impl TracerStatus {
+    /// Time since the trace was last (re)started
+    pub fn uptime(&self) -> Duration {
+        self.started
+            .map_or(Duration::ZERO, |started| started.elapsed())
+    }
+
+    /// Average share of a CPU the program used since it started, from
+    /// `/proc/<pid>/stat`
+    pub fn cpu_usage(&self) -> Option<f64> {
+        let stat = std::fs::read_to_string(format!("/proc/{}/stat", self.pid?)).ok()?;
+        let ticks = parse_cpu_ticks(&stat)?;
+        let ticks_per_second = unsafe { libc::sysconf(libc::_SC_CLK_TCK) };
+        let uptime = self.uptime().as_secs_f64();
+        if ticks_per_second <= 0 || uptime == 0.0 {
+            return None;
+        }
+        Some(ticks as f64 / ticks_per_second as f64 / uptime)
+    }
+}
+
+/// CPU time in clock ticks, user and system, from the contents of
+/// `/proc/<pid>/stat`
+fn parse_cpu_ticks(stat: &str) -> Option<u64> {
+    // The command name is in parentheses and may contain spaces, `utime` and
+    // `stime` are the 12th and 13th fields after it
+    let mut fields = stat.get(stat.rfind(')')? + 1..)?.split_whitespace();
+    let utime: u64 = fields.nth(11)?.parse().ok()?;
+    let stime: u64 = fields.next()?.parse().ok()?;
+    Some(utime + stime)
+}
+
+/// Number of probes from bpftrace's `Attaching 4 probes...` line, or its JSON
+/// record `{"type": "attached_probes", "data": {"probes": 4}}`
+fn parse_attached_probes(line: &str, is_json: bool) -> Option<u64> {
+    if is_json {
+        if !line.contains("\"attached_probes\"") {
+            return None;
+        }
+        let record: serde_json::Value = serde_json::from_str(line).ok()?;
+        record["data"]["probes"].as_u64()
+    } else {
+        line.strip_prefix("Attaching ")?
+            .split_once(' ')
+            .filter(|(_, rest)| rest.starts_with("probe"))?
+            .0
+            .parse()
+            .ok()
+    }
 }
 
 enum TraceCommand {
@@ -20,22 +102,6 @@ enum TraceCommand {
 }
 
 impl Tracer {
-    pub fn run_prechecks() -> Result<(), Error> {
-        match bpftrace_cmd().arg("--version").output() {
-            Ok(output) => log::trace!("bpftrace version: {:?}", output),
-            Err(err) => {
-                let msg = match err.kind() {
-                    std::io::ErrorKind::NotFound => format!("bpftrace not found. See https://github.com/iovisor/bpftrace/blob/master/INSTALL.md for installation instructions."),
-                    _ => format!("Error running bpftrace: {:?}", err),
-                };
-                return Err(msg.into());
-            }
-        }
-        // TODO ensure is root
-
-        Ok(())
-    }
-
     /// tx is used to transmit trace data in response to the requests given to
     /// this class.
     pub fn new(
@@ -43,12 +109,15 @@ impl Tracer {
         data_tx: mpsc::Sender<Event>,
     ) -> Result<Tracer, Error> {
         let (command_tx, command_rx) = mpsc::channel();
+        let status = Arc::new(Mutex::new(TracerStatus::default()));
+        let handler_status = Arc::clone(&status);
         let command_thread = thread::spawn(move || {
-            TraceCommandHandler::new(trace_stack, data_tx).run(command_rx);
+            TraceCommandHandler::new(trace_stack, data_tx, handler_status).run(command_rx);
         });
         let tracer = Tracer {
             tx: command_tx,
             command_thread: Some(command_thread),
+            status,
         };
 
         Ok(tracer)
@@ -62,6 +131,10 @@ impl Tracer {
     pub fn rerun_tracer(&self) {
         self.tx.send(TraceCommand::RerunTracer).unwrap()
     }
+
+    pub fn status(&self) -> TracerStatus {
+        self.status.lock().unwrap().clone()
+    }
 }
 
 impl Drop for Tracer {
@@ -85,16 +158,27 @@ struct TraceCommandHandler {
     /// this to track if we tried to kill it and if so ignore the error,
     /// otherwise display an error and exit ourselves.
     is_killing: Arc<AtomicBool>,
+    /// Set to stop the current trace when it runs in-process (see
+    /// `TraceBackend::run_native`)
+    stop_native: Arc<AtomicBool>,
+    /// Shared with `Tracer`, see `Tracer::status`
+    status: Arc<Mutex<TracerStatus>>,
 }
 
 impl TraceCommandHandler {
-    fn new(trace_stack: Arc<TraceStack>, data_tx: mpsc::Sender<Event>) -> TraceCommandHandler {
+    fn new(
+        trace_stack: Arc<TraceStack>,
+        data_tx: mpsc::Sender<Event>,
+        status: Arc<Mutex<TracerStatus>>,
+    ) -> TraceCommandHandler {
         TraceCommandHandler {
             data_tx,
             trace_stack,
             program_id: None,
             output_processor: None,
             is_killing: Arc::new(AtomicBool::new(false)),
+            stop_native: Arc::new(AtomicBool::new(false)),
+            status,
         }
     }
 
@@ -110,84 +194,231 @@ impl TraceCommandHandler {
 
     fn rerun_bpftrace(&mut self) {
         self.is_killing.store(true, Ordering::Release);
-        self.program_id.map(|pid| unsafe {
+        self.program_id.take().map(|pid| unsafe {
             libc::kill(pid as i32, libc::SIGTERM);
         });
+        self.stop_native.store(true, Ordering::Release);
         self.output_processor.take().map(|t| t.join());
         self.is_killing.store(false, Ordering::Release);
+        {
+            let mut status = self.status.lock().unwrap();
+            let restarts = status.restarts + u64::from(status.started.is_some());
+            *status = TracerStatus {
+                started: Some(Instant::now()),
+                restarts,
+                ..Default::default()
+            };
+        }
+
+        if self.trace_stack.get_backend().is_native() {
+            match self.trace_stack.get_native_plan() {
+                Ok((plan, counter)) => {
+                    self.run_native(plan, counter);
+                    return;
+                }
+                // Fall back to running a program
+                Err(err) => log::info!("{}", err),
+            }
+        }
 
         let (expr, counter) = self.trace_stack.get_bpftrace_expr();
-        let mut program = bpftrace_cmd()
-            .args(&["-e", &expr])
+        let backend = self.trace_stack.get_backend();
+        let backend_name = backend.name();
+        // Records are parsed more reliably than text, when the backend prints
+        // them
+        let (mut command, is_json) = match backend.json_command(&expr) {
+            Some(command) => (command, true),
+            None => (backend.run_command(&expr), false),
+        };
+        let buffer_scale = self.trace_stack.get_buffer_scale();
+        if buffer_scale > 1 && backend.is_local_bpftrace() {
+            command.envs(losses::scaled_env(buffer_scale));
+        }
+        let mut program = command
             .stdout(Stdio::piped())
             .stderr(Stdio::piped())
             .spawn()
-            .expect("bpftrace failed to start");
+            .unwrap_or_else(|err| panic!("{} failed to start: {}", backend_name, err));
         self.program_id = Some(program.id());
+        self.status.lock().unwrap().pid = self.program_id;
         log::trace!("bpftrace program_id: {:?}", self.program_id);
         let tx = self.data_tx.clone();
         let is_killing_copy = Arc::clone(&self.is_killing);
+        // Read stderr as it's printed, since bpftrace warns about full maps
+        // there while running
+        let stderr = program.stderr.take().unwrap();
+        let stderr_tx = self.data_tx.clone();
+        let stderr_status = Arc::clone(&self.status);
+        let status = Arc::clone(&self.status);
+        let stderr_reader = thread::spawn(move || {
+            let mut text = String::new();
+            for line in std::io::BufReader::new(stderr).lines() {
+                let line = match line {
+                    Err(err) => {
+                        log::error!("Failed to read {} stderr: {:?}", backend_name, err);
+                        break;
+                    }
+                    Ok(line) => line,
+                };
+                if let Some(losses) = losses::parse_line(&line) {
+                    // Controller may have exited in the meantime
+                    let _ = stderr_tx.send(Event::TraceLosses(losses));
+                }
+                {
+                    let mut status = stderr_status.lock().unwrap();
+                    if status.warnings.len() == MAX_WARNINGS {
+                        status.warnings.pop_front();
+                    }
+                    status.warnings.push_back(line.clone());
+                }
+                text += &line;
+                text += "\n";
+            }
+            text
+        });
         self.output_processor = Some(thread::spawn(move || {
             let stdout = program.stdout.as_mut().unwrap();
             let stdout_reader = std::io::BufReader::new(stdout);
             log::trace!("Starting!");
             let mut json_buf = String::new();
+            let mut json_output = JsonOutput::default();
             for line in stdout_reader.lines() {
-                log::trace!("bpftrace stdout: {:?}", line);
+                log::trace!("{} stdout: {:?}", backend_name, line);
                 let line = match line {
                     Err(_) => continue,
                     Ok(line) => line,
                 };
-                // Histograms are printed across multiple lines - we need to
-                // collect and send them all in one call. We detect line ending
-                // in `}` and use that to assume end of JSON.
-                // Is there a better way to do this?
-                if !json_buf.is_empty() {
-                    json_buf += "\n";
-                    json_buf += &line;
-                } else if !line.starts_with("{") {
-                    // bpftrace prints all maps on exit, which we want to ignore
-                    continue;
+                {
+                    let mut status = status.lock().unwrap();
+                    status.records += 1;
+                    if let Some(probes) = parse_attached_probes(&line, is_json) {
+                        status.probes = Some(probes);
+                    }
+                }
+                let losses = if is_json {
+                    losses::parse_json(&line)
                 } else {
-                    json_buf = line;
+                    losses::parse_line(&line)
+                };
+                if let Some(losses) = losses {
+                    tx.send(Event::TraceLosses(losses)).unwrap();
+                    continue;
                 }
-                if json_buf.ends_with("}") {
-                    let parsed = TraceStack::parse(&json_buf, counter);
-                    let parsed = match parsed {
+                let report = if is_json {
+                    match json_output.push(&line) {
+                        Ok(Some(report)) => report,
+                        Ok(None) => continue,
                         Err(err) => {
-                            tx.send(Event::FatalTraceError {
-                                error_message: format!(
-                                    "Failed to parse bpftrace output '{}': {:?}",
-                                    json_buf, err
-                                ),
-                            })
-                            .unwrap();
+                            log::warn!("Unexpected {} output '{}': {}", backend_name, line, err);
                             continue;
                         }
-                        Ok(parsed) => parsed,
-                    };
-                    tx.send(Event::TraceData(parsed)).unwrap();
-                    json_buf.clear();
-                }
+                    }
+                } else {
+                    // Histograms are printed across multiple lines - we need to
+                    // collect and send them all in one call. We detect line
+                    // ending in `}` and use that to assume end of JSON.
+                    if !json_buf.is_empty() {
+                        json_buf += "\n";
+                        json_buf += &line;
+                    } else if !line.starts_with("{") {
+                        // bpftrace prints all maps on exit, which we want to
+                        // ignore
+                        continue;
+                    } else {
+                        json_buf = line;
+                    }
+                    if !json_buf.ends_with("}") {
+                        continue;
+                    }
+                    std::mem::take(&mut json_buf)
+                };
+                let parsed = match TraceStack::parse(&report, counter) {
+                    Err(err) => {
+                        tx.send(Event::FatalTraceError {
+                            error_message: format!(
+                                "Failed to parse {} output '{}': {:?}",
+                                backend_name, report, err
+                            ),
+                        })
+                        .unwrap();
+                        continue;
+                    }
+                    Ok(parsed) => parsed,
+                };
+                status.lock().unwrap().reports += 1;
+                tx.send(Event::TraceData(parsed)).unwrap();
             }
-            let status = program.wait().unwrap();
-            log::trace!("Done, status: {}!", status);
-            let mut stderr = String::new();
-            match program.stderr.unwrap().read_to_string(&mut stderr) {
-                Err(err) => log::error!("Failed to read bpftrace stderr: {:?}", err),
-                _ => (),
+            let exit_status = program.wait().unwrap();
+            log::trace!("Done, status: {}!", exit_status);
+            let stderr = stderr_reader.join().unwrap_or_default();
+            let is_killing = is_killing_copy.load(Ordering::Acquire);
+            if !is_killing {
+                status.lock().unwrap().exit_status = Some(exit_status.to_string());
             }
-            if !status.success() && !is_killing_copy.load(Ordering::Acquire) {
+            if !exit_status.success() && !is_killing {
                 tx.send(Event::FatalTraceError {
                     error_message: format!(
-                        "bpftrace command '{}' failed, status: {:?}, stderr:\n{}",
-                        expr, status, stderr
+                        "{} command '{}' failed, status: {:?}, stderr:\n{}",
+                        backend_name, expr, exit_status, stderr
                     ),
                 })
                 .unwrap();
             } else if !stderr.is_empty() {
-                log::info!("bpftrace stderr:\n{}", stderr);
+                log::info!("{} stderr:\n{}", backend_name, stderr);
+            }
+        }));
+    }
+
+    fn run_native(&mut self, plan: NativePlan, counter: u64) {
+        self.status.lock().unwrap().probes = Some(plan.probes.len() as u64);
+        self.stop_native = Arc::new(AtomicBool::new(false));
+        let stop = Arc::clone(&self.stop_native);
+        let trace_stack = Arc::clone(&self.trace_stack);
+        let tx = self.data_tx.clone();
+        self.output_processor = Some(thread::spawn(move || {
+            let backend = trace_stack.get_backend();
+            if let Err(err) = backend.run_native(&plan, counter, &tx, &stop) {
+                // Controller may have exited in the meantime
+                let _ = tx.send(Event::FatalTraceError {
+                    error_message: format!("{} failed: {}", backend.name(), err),
+                });
             }
         }));
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_cpu_ticks() {
+        let stat = "1234 (my (weird) prog) S 42 1234 1234 0 -1 4194560 150 0 0 0 37 5 0 0 20 0 1 0 100 0 0";
+        assert_eq!(parse_cpu_ticks(stat), Some(42));
+        assert_eq!(parse_cpu_ticks("garbage"), None);
+    }
+
+    #[test]
+    fn test_parse_attached_probes() {
+        assert_eq!(
+            parse_attached_probes("Attaching 4 probes...", false),
+            Some(4)
+        );
+        assert_eq!(
+            parse_attached_probes("Attaching 1 probe...", false),
+            Some(1)
+        );
+        assert_eq!(parse_attached_probes("{\"time\": 1}", false), None);
+        assert_eq!(
+            parse_attached_probes(
+                r#"{"type": "attached_probes", "data": {"probes": 7}}"#,
+                true
+            ),
+            Some(7)
+        );
+        assert_eq!(
+            parse_attached_probes(r#"{"type": "printf", "data": "Attaching"}"#, true),
+            None
+        );
+    }
+}
@@ -1,13 +1,15 @@
+use crate::messages::Messages;
 use crate::search;
 use core::cmp::Ordering;
 use cursive::theme::{BaseColor, Color, ColorStyle};
 use cursive::utils::markup::StyledString;
-use cursive::view::{Nameable, Resizable};
+use cursive::view::{Nameable, Resizable, Selector};
 use cursive::views::{
-    Dialog, EditView, Layer, LinearLayout, PaddedView, ResizedView, ScrollView, SelectView,
-    TextView,
+    Dialog, EditView, HideableView, Layer, LinearLayout, NamedView, OnEventView, PaddedView,
+    ResizedView, ScrollView, SelectView, TextArea, TextView,
 };
-use cursive::Cursive;
+use cursive::{Cursive, Vec2, View};
+use std::collections::HashSet;
 use std::rc::Rc;
 
 #[derive(Clone, Copy, Debug)]
@@ -22,6 +24,7 @@ pub mod formatting {
     const SIGNIFICANT_FIGURES: usize = 3;
     const LATENCY_LABELS: &'static [&'static str] = &["ns", "us", "ms", "s"];
     const FREQUENCY_LABELS: &'static [&'static str] = &["/s", "K/s", "M/s"];
+    const COUNT_LABELS: &'static [&'static str] = &["", "K", "M", "G"];
 
     /// Given labels representing increasing order of magnitude values,
     /// format to display SIGNIFICANT_FIGURES.
@@ -55,6 +58,63 @@ pub mod formatting {
         format(freq_per_sec as f64, FREQUENCY_LABELS)
     }
 
+    /// Format a ratio of counts, e.g. instructions per cycle
+    pub fn format_count(count: f32) -> String {
+        format(count as f64, COUNT_LABELS)
+    }
+
+    /// Mark the buckets of a histogram printed by bpftrace that its 50th, 90th
+    /// and 99th percentiles fall in, e.g. `[1K, 2K)  4 |@@@@|  p50 p90`
+    pub fn annotate_percentiles(histogram: &str) -> String {
+        let bucket_count = |line: &str| -> Option<u64> {
+            let (bucket, _) = line.split_once(" |")?;
+            bucket.split_whitespace().last()?.parse().ok()
+        };
+        let total: u64 = histogram.lines().filter_map(bucket_count).sum();
+        let mut cumulative = 0;
+        let mut percentiles = [50, 90, 99].iter().peekable();
+        histogram
+            .lines()
+            .map(|line| {
+                let count = match bucket_count(line) {
+                    Some(count) => count,
+                    None => return line.to_string(),
+                };
+                cumulative += count;
+                let mut line = line.to_string();
+                while let Some(&&percentile) = percentiles.peek() {
+                    if cumulative * 100 < total * percentile {
+                        break;
+                    }
+                    line += &format!("  p{}", percentile);
+                    percentiles.next();
+                }
+                line
+            })
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+
+    /// One bar per value, scaled between the smallest and largest value. Gaps
+    /// (`None`) are shown as spaces.
+    pub fn sparkline(values: &[Option<f64>]) -> String {
+        const BARS: &[char] = &['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+        let present = values.iter().flatten();
+        let min = present.clone().cloned().fold(f64::INFINITY, f64::min);
+        let max = present.cloned().fold(f64::NEG_INFINITY, f64::max);
+        values
+            .iter()
+            .map(|value| match value {
+                Some(_) if max <= min => BARS[0],
+                Some(value) => {
+                    let scaled = (value - min) / (max - min) * (BARS.len() - 1) as f64;
+                    BARS[scaled.round() as usize]
+                }
+                None => ' ',
+            })
+            .collect()
+    }
+
     #[cfg(test)]
     mod tests {
         use super::*;
@@ -62,6 +122,25 @@ pub mod formatting {
         #[test]
         fn test_formatting() {
             assert_eq!(format_frequency(0.02934924), "0.03/s");
+            assert_eq!(format_count(1.2345), "1.23");
+            assert_eq!(format_count(15300.0), "15.3K");
+        }
+
+        #[test]
+        fn test_annotate_percentiles() {
+            let histogram =
+                "@histogram:\n[1K, 2K)    45 |@@@@|\n[2K, 4K)    50 |@@@@@|\n[4K, 8K)     5 ||";
+            assert_eq!(
+                annotate_percentiles(histogram),
+                "@histogram:\n[1K, 2K)    45 |@@@@|\n[2K, 4K)    50 |@@@@@|  p50 p90\n[4K, 8K)     5 ||  p99"
+            );
+        }
+
+        #[test]
+        fn test_sparkline() {
+            assert_eq!(sparkline(&[]), "");
+            assert_eq!(sparkline(&[Some(5.0), Some(5.0)]), "▁▁");
+            assert_eq!(sparkline(&[Some(1.0), None, Some(8.0), Some(4.5)]), "▁ █▅");
         }
     }
 }
@@ -77,6 +156,9 @@ mod source_view {
     pub enum Column {
         Latency,
         Frequency,
+        /// Hardware counter value selected with `e`, only added while shown
+        Counter,
+        ParentShare,
         LineNumber,
         Line,
     }
@@ -89,6 +171,26 @@ mod source_view {
         pub line_number: u32,
         pub line: String,
         pub marked: bool,
+        /// Whether calls on the line were inlined
+        pub inlined: bool,
+        /// Shown after the line, e.g. for branch misprediction rates
+        pub annotation: Option<String>,
+        /// If set, shown in place of the frequency: number of events in the
+        /// last reporting interval
+        pub count_delta: Option<u64>,
+        /// If set, shown after the latency: share of it spent off-CPU
+        pub off_cpu: Option<f32>,
+        /// Shown in the counter column, e.g. instructions per cycle
+        pub counter: Option<f32>,
+        /// Calls that never returned, shown after the line if any
+        pub aborted: u64,
+        /// Set on the line of the function itself when calls in it are
+        /// traced: average time per call spent outside of the traced calls,
+        /// and its share of the function's time
+        pub self_time: Option<(Duration, f32)>,
+        /// Shown in the share column: share of the function's time spent on
+        /// the line
+        pub parent_share: Option<f32>,
     }
 
     impl Item {
@@ -96,7 +198,14 @@ mod source_view {
 
         fn format_latency(&self) -> String {
             match self.latency {
-                TraceState::Traced(l) => super::formatting::format_latency(l),
+                TraceState::Traced(l) => match self.off_cpu {
+                    Some(share) => format!(
+                        "{} {:3.0}%",
+                        super::formatting::format_latency(l),
+                        share * 100.0
+                    ),
+                    None => super::formatting::format_latency(l),
+                },
                 TraceState::Pending => Self::PENDING_STR.into(),
                 TraceState::Untraced => String::new(),
             }
@@ -104,11 +213,32 @@ mod source_view {
 
         fn format_frequency(&self) -> String {
             match self.frequency {
+                TraceState::Traced(_) if self.count_delta.is_some() => {
+                    format!("+{}", self.count_delta.unwrap())
+                }
                 TraceState::Traced(f) => super::formatting::format_frequency(f),
                 TraceState::Pending => Self::PENDING_STR.into(),
                 TraceState::Untraced => String::new(),
             }
         }
+
+        fn format_parent_share(&self) -> String {
+            match (self.frequency, self.parent_share) {
+                (TraceState::Traced(_), Some(share)) => format!("{:.1}%", share * 100.0),
+                (TraceState::Untraced, _) => String::new(),
+                // The function itself may not have returned yet
+                _ => Self::PENDING_STR.into(),
+            }
+        }
+
+        fn format_counter(&self) -> String {
+            match (self.frequency, self.counter) {
+                (TraceState::Traced(_), Some(c)) => super::formatting::format_count(c),
+                (TraceState::Untraced, _) => String::new(),
+                // Counters may not have been read for any call yet
+                _ => Self::PENDING_STR.into(),
+            }
+        }
     }
 
     impl cursive_table_view::TableViewItem<Column> for Item {
@@ -116,12 +246,41 @@ mod source_view {
             match column {
                 Column::Latency => self.format_latency(),
                 Column::Frequency => self.format_frequency(),
+                Column::Counter => self.format_counter(),
+                Column::ParentShare => self.format_parent_share(),
                 Column::LineNumber => {
-                    let call_annotation = if self.marked { " ▶" } else { "  " };
+                    let call_annotation = match (self.marked, self.inlined) {
+                        (true, true) => "▶ⁱ",
+                        (false, true) => " ⁱ",
+                        (true, false) => " ▶",
+                        (false, false) => "  ",
+                    };
                     assert_eq!(call_annotation.chars().count(), CALL_ANNOTATION_LEN);
                     format!("{}{}", self.line_number, call_annotation)
                 }
-                Column::Line => self.line.clone(),
+                Column::Line => {
+                    let aborted =
+                        (self.aborted > 0).then(|| format!("{} aborted calls", self.aborted));
+                    let self_time = self.self_time.map(|(time, share)| {
+                        format!(
+                            "self {} ({:.0}%)",
+                            super::formatting::format_latency(time).trim(),
+                            share * 100.0
+                        )
+                    });
+                    let annotations: Vec<&str> = self
+                        .annotation
+                        .as_deref()
+                        .into_iter()
+                        .chain(self_time.as_deref())
+                        .chain(aborted.as_deref())
+                        .collect();
+                    if annotations.is_empty() {
+                        self.line.clone()
+                    } else {
+                        format!("{}    <- {}", self.line, annotations.join(", "))
+                    }
+                }
             }
         }
 
@@ -133,13 +292,23 @@ mod source_view {
 
 pub type SourceView = cursive_table_view::TableView<source_view::Item, source_view::Column>;
 
+const LATENCY_COLUMN_WIDTH: usize = 8;
+/// Wide enough for the latency followed by the share spent off-CPU
+const OFF_CPU_LATENCY_COLUMN_WIDTH: usize = 13;
+const COUNTER_COLUMN_WIDTH: usize = 10;
+const PARENT_SHARE_COLUMN_WIDTH: usize = 8;
+
 /// View to display source code files with inline tracing info.
-pub fn new_source_view() -> SourceView {
+pub fn new_source_view(messages: &Messages) -> SourceView {
     use source_view::Column;
     let line_num_width = source_view::LINE_NUMBER_LEN + source_view::CALL_ANNOTATION_LEN + 1;
     let mut table = cursive_table_view::TableView::<source_view::Item, Column>::new()
-        .column(Column::Latency, "Latency", |c| c.width(8))
-        .column(Column::Frequency, "Frequency", |c| c.width(8))
+        .column(Column::Latency, messages.get("column.latency"), |c| {
+            c.width(LATENCY_COLUMN_WIDTH)
+        })
+        .column(Column::Frequency, messages.get("column.frequency"), |c| {
+            c.width(8)
+        })
         .column(Column::LineNumber, "", |c| {
             c.width(line_num_width).align(cursive::align::HAlign::Right)
         })
@@ -148,11 +317,56 @@ pub fn new_source_view() -> SourceView {
     table
 }
 
+/// Change the title of the latency column, e.g. when it shows a percentile
+/// rather than the average, and widen it if it also shows off-CPU time
+pub fn set_latency_column(sview: &mut SourceView, title: &str, off_cpu: bool) {
+    let width = if off_cpu {
+        OFF_CPU_LATENCY_COLUMN_WIDTH
+    } else {
+        LATENCY_COLUMN_WIDTH
+    };
+    sview.remove_column(0);
+    sview.insert_column(0, source_view::Column::Latency, title, |c| c.width(width));
+}
+
+/// Add the hardware counter column with `title` after the frequency column,
+/// or remove it if `title` is `None`. `shown` is whether it is currently shown.
+pub fn set_counter_column(sview: &mut SourceView, title: Option<&str>, shown: bool) {
+    if shown {
+        sview.remove_column(2);
+    }
+    if let Some(title) = title {
+        sview.insert_column(2, source_view::Column::Counter, title, |c| {
+            c.width(COUNTER_COLUMN_WIDTH)
+        });
+    }
+}
+
+/// Add the column with each line's share of the function's time after the
+/// frequency and counter columns, or remove it. `counter_shown` is whether the
+/// counter column is shown.
+pub fn set_parent_share_column(
+    sview: &mut SourceView,
+    title: &str,
+    show: bool,
+    counter_shown: bool,
+) {
+    let index = if counter_shown { 3 } else { 2 };
+    if show {
+        sview.insert_column(index, source_view::Column::ParentShare, title, |c| {
+            c.width(PARENT_SHARE_COLUMN_WIDTH)
+        });
+    } else {
+        sview.remove_column(index);
+    }
+}
+
 pub fn set_source_view(
     sview: &mut SourceView,
     source_code: Vec<String>,
     selected_line: u32,
     marked_lines: Vec<u32>,
+    inlined_lines: Vec<u32>,
 ) {
     use source_view::Item;
     let mut items: Vec<Item> = source_code
@@ -174,12 +388,23 @@ pub fn set_source_view(
                 line_number: i as u32 + 1,
                 line,
                 marked: false,
+                inlined: false,
+                annotation: None,
+                count_delta: None,
+                off_cpu: None,
+                counter: None,
+                aborted: 0,
+                self_time: None,
+                parent_share: None,
             }
         })
         .collect();
     for line in marked_lines {
         items.get_mut(line as usize - 1).unwrap().marked = true;
     }
+    for line in inlined_lines {
+        items.get_mut(line as usize - 1).unwrap().inlined = true;
+    }
     // Set this twice - once before to prevent out of bounds, second time to
     // ensure the table actually scrolls to the right place.
     sview.set_selected_row(selected_line as usize - 1);
@@ -211,12 +436,131 @@ pub fn set_footer_view(fview: &mut FooterView, content: &str) {
         .set_content(StyledString::styled(content, footer_style()))
 }
 
+/// Current content of the footer, e.g. to restore it later with
+/// `set_footer_view_styled`
+pub fn get_footer_view(fview: &FooterView) -> StyledString {
+    StyledString::clone(&fview.get_inner().get_inner().get_content())
+}
+
+pub fn set_footer_view_styled(fview: &mut FooterView, content: StyledString) {
+    fview.get_inner_mut().get_inner_mut().set_content(content)
+}
+
+fn warning_style() -> ColorStyle {
+    ColorStyle::new(Color::Light(BaseColor::White), Color::Dark(BaseColor::Red))
+}
+
+/// Footer with a prominent `warning` after `content`
+pub fn set_footer_view_with_warning(fview: &mut FooterView, content: &str, warning: &str) {
+    let mut styled = StyledString::styled(content, footer_style());
+    styled.append_styled(format!("  {}", warning), warning_style());
+    fview.get_inner_mut().get_inner_mut().set_content(styled)
+}
+
+/// Add a prominent `warning` after the current content of the footer
+pub fn append_footer_warning(fview: &mut FooterView, warning: &str) {
+    let mut styled = get_footer_view(fview);
+    styled.append_styled(format!("  {}", warning), warning_style());
+    fview.get_inner_mut().get_inner_mut().set_content(styled)
+}
+
+pub type LossesView = HideableView<PaddedView<Layer<TextView>>>;
+
+/// Warning next to the footer that data was dropped while tracing, hidden
+/// until it was
+pub fn new_losses_view() -> LossesView {
+    HideableView::new(PaddedView::lrtb(
+        1,
+        0,
+        1,
+        0,
+        Layer::with_color(TextView::new(""), footer_style()),
+    ))
+    .hidden()
+}
+
+/// Show `warning` in the losses view, or hide it if `None`
+pub fn set_losses_view(lview: &mut LossesView, warning: Option<&str>) {
+    lview.set_visible(warning.is_some());
+    lview
+        .get_inner_mut()
+        .get_inner_mut()
+        .get_inner_mut()
+        .set_content(StyledString::styled(warning.unwrap_or(""), warning_style()));
+}
+
+pub type PinnedView = HideableView<PaddedView<Layer<TextView>>>;
+
+/// Pane above the source view showing the stats of a pinned line, hidden
+/// until a line is pinned
+pub fn new_pinned_view() -> PinnedView {
+    HideableView::new(PaddedView::lrtb(
+        0,
+        0,
+        0,
+        1,
+        Layer::with_color(TextView::new(""), footer_style()),
+    ))
+    .hidden()
+}
+
+/// Show `content` in the pinned view, or hide it if `None`
+pub fn set_pinned_view(pview: &mut PinnedView, content: Option<&str>) {
+    pview.set_visible(content.is_some());
+    pview
+        .get_inner_mut()
+        .get_inner_mut()
+        .get_inner_mut()
+        .set_content(StyledString::styled(content.unwrap_or(""), footer_style()));
+}
+
+pub type PinnedTracesView = HideableView<PaddedView<Layer<TextView>>>;
+
+/// Pane above the source view listing the lines pinned with `P` along with
+/// their stats, hidden while none are
+pub fn new_pinned_traces_view() -> PinnedTracesView {
+    new_pinned_view()
+}
+
+/// Show `content` in the pinned traces view, or hide it if `None`
+pub fn set_pinned_traces_view(pview: &mut PinnedTracesView, content: Option<&str>) {
+    set_pinned_view(pview, content)
+}
+
+pub type HistogramPane = HideableView<ResizedView<PaddedView<ScrollView<TextView>>>>;
+
+/// Wide enough for the bars of bpftrace's histograms and their percentile
+/// markers
+const HISTOGRAM_PANE_WIDTH: usize = 94;
+
+/// Pane to the right of the source view showing the latency histogram of the
+/// selected line, hidden until toggled on
+pub fn new_histogram_pane() -> HistogramPane {
+    HideableView::new(
+        PaddedView::lrtb(2, 0, 0, 0, ScrollView::new(TextView::new("")))
+            .fixed_width(HISTOGRAM_PANE_WIDTH),
+    )
+    .hidden()
+}
+
+pub fn set_histogram_pane(hview: &mut HistogramPane, content: &str) {
+    hview
+        .get_inner_mut()
+        .get_inner_mut()
+        .get_inner_mut()
+        .get_inner_mut()
+        .set_content(content);
+}
+
 pub type SearchView = ResizedView<Dialog>;
 
 const SEARCH_VIEW_WIDTH: usize = 70;
 const SEARCH_VIEW_HEIGHT: usize = 8;
 const SEARCH_VIEW_MAX_RESULTS: usize = 50;
 
+/// Prefix of labels of items marked in a `new_multi_search_view`
+const MARKED_PREFIX: &str = "[*] ";
+
 /// `title` must be unique (it is used in the name of the view). Parameters of
 /// `edit_search_fn` are search view name, search string, and (max) number of
 /// results.
@@ -230,11 +574,121 @@ where
     F: Fn(&mut Cursive, &str, &str, usize) + 'static,
     T: 'static,
     G: Fn(&mut Cursive, &T) + 'static,
+{
+    build_search_view(
+        title,
+        initial_results,
+        edit_search_fn,
+        None,
+        move |siv: &mut Cursive, name: &str| {
+            let selection = siv
+                .find_name::<SelectView<Option<T>>>(name)
+                .unwrap()
+                .selection();
+            if let Some(sel) = selection {
+                if let Some(item) = &*sel {
+                    siv.pop_layer();
+                    submit_fn(siv, item);
+                }
+            }
+        },
+    )
+}
+
+/// Same as `new_search_view`, but results can be marked with space (once
+/// focused on the result list) to apply `submit_fn` to all of them. Marked
+/// results stay at the top while searching. If nothing is marked `submit_fn`
+/// gets just the selected result. Unlike `new_search_view` the view is left
+/// open, `submit_fn` should pop it if appropriate.
+pub fn new_multi_search_view<T, F, G>(
+    title: &str,
+    initial_results: Vec<(String, Option<T>)>,
+    edit_search_fn: F,
+    submit_fn: G,
+) -> SearchView
+where
+    F: Fn(&mut Cursive, &str, &str, usize) + 'static,
+    T: Clone + 'static,
+    G: Fn(&mut Cursive, Vec<T>) + 'static,
+{
+    build_search_view(
+        title,
+        initial_results,
+        edit_search_fn,
+        Some(toggle_mark::<T> as fn(&mut Cursive, &str)),
+        move |siv: &mut Cursive, name: &str| {
+            let items = {
+                let select_view = siv.find_name::<SelectView<Option<T>>>(name).unwrap();
+                let marked: Vec<T> = select_view
+                    .iter()
+                    .filter(|(label, _)| label.starts_with(MARKED_PREFIX))
+                    .filter_map(|(_, item)| item.clone())
+                    .collect();
+                if !marked.is_empty() {
+                    marked
+                } else {
+                    select_view
+                        .selection()
+                        .and_then(|sel| (*sel).clone())
+                        .into_iter()
+                        .collect()
+                }
+            };
+            if !items.is_empty() {
+                submit_fn(siv, items);
+            }
+        },
+    )
+}
+
+/// Toggle whether the selected result of search view `name` is marked. Marked
+/// results are moved to the top.
+fn toggle_mark<T>(siv: &mut Cursive, name: &str)
+where
+    T: Clone + 'static,
+{
+    let mut select_view = match siv.find_name::<SelectView<Option<T>>>(name) {
+        Some(select_view) => select_view,
+        None => return,
+    };
+    let id = match select_view.selected_id() {
+        Some(id) => id,
+        None => return,
+    };
+    let (label, item) = match select_view.get_item(id) {
+        Some((label, Some(item))) => (label.to_string(), item.clone()),
+        _ => return,
+    };
+    let num_marked = select_view
+        .iter()
+        .take_while(|(label, _)| label.starts_with(MARKED_PREFIX))
+        .count();
+    let _ = select_view.remove_item(id);
+    let (index, label) = match label.strip_prefix(MARKED_PREFIX) {
+        Some(unmarked) => (num_marked - 1, unmarked.to_string()),
+        None => (num_marked, format!("{}{}", MARKED_PREFIX, label)),
+    };
+    select_view.insert_item(index, label, Some(item));
+    let _ = select_view.set_selection(index);
+}
+
+fn build_search_view<T, F, G>(
+    title: &str,
+    initial_results: Vec<(String, Option<T>)>,
+    edit_search_fn: F,
+    mark_fn: Option<fn(&mut Cursive, &str)>,
+    submit_fn: G,
+) -> SearchView
+where
+    F: Fn(&mut Cursive, &str, &str, usize) + 'static,
+    T: 'static,
+    G: Fn(&mut Cursive, &str) + 'static,
 {
     let submit_cb = Rc::new(submit_fn);
     let submit_cb_copy = Rc::clone(&submit_cb);
     let name = format!("select_{}", title);
     let name_copy = name.clone();
+    let name_copy2 = name.clone();
 
     // SelectView value of None will be a no-op to hit enter on.
     let mut select_view = SelectView::<Option<T>>::new();
@@ -242,16 +696,17 @@ where
         select_view.add_item(label, value);
     }
 
-    let select_view = ScrollView::new(
+    let mut select_view = OnEventView::new(
         select_view
-            .on_submit(move |siv: &mut Cursive, sel: &Option<T>| {
-                if let Some(item) = sel {
-                    siv.pop_layer();
-                    submit_cb(siv, item);
-                }
-            })
-            .with_name(&name)
-            .full_width(), // ScrollView adds 2 characters for scrollbar
+            .on_submit(move |siv: &mut Cursive, _: &Option<T>| submit_cb(siv, &name_copy))
+            .with_name(&name),
+    );
+    if let Some(mark_fn) = mark_fn {
+        let name_copy = name.clone();
+        select_view.set_on_event(' ', move |siv| mark_fn(siv, &name_copy));
+    }
+    let select_view = ScrollView::new(
+        select_view.full_width(), // ScrollView adds 2 characters for scrollbar
     )
     .scroll_x(true)
     .fixed_size((SEARCH_VIEW_WIDTH, SEARCH_VIEW_HEIGHT));
@@ -262,15 +717,7 @@ where
     let edit_view = EditView::new()
         .filler(" ")
         .on_edit_mut(update_edit_view)
-        .on_submit(move |siv: &mut Cursive, _| {
-            let select_view = siv.find_name::<SelectView<Option<T>>>(&name_copy).unwrap();
-            if let Some(sel) = select_view.selection() {
-                if let Some(item) = &*sel {
-                    siv.pop_layer();
-                    submit_cb_copy(siv, item);
-                }
-            }
-        })
+        .on_submit(move |siv: &mut Cursive, _| submit_cb_copy(siv, &name_copy2))
         .with_name(format!("search_{}", title))
         .fixed_width(SEARCH_VIEW_WIDTH);
 
@@ -279,7 +726,8 @@ where
         .fixed_width(SEARCH_VIEW_WIDTH + 4) // Dialog adds 2 character border on each side
 }
 
-/// Update search view, return true if it was found (and updated)
+/// Update search view, return true if it was found (and updated). Marked
+/// results (see `new_multi_search_view`) are kept.
 pub fn update_search_view<T>(
     siv: &mut Cursive,
     search_view_name: &str,
@@ -291,9 +739,22 @@ where
     let found_opt = siv
         .find_name::<SelectView<Option<T>>>(&search_view_name)
         .map(|mut select_view| {
-            select_view.clear();
+            let num_marked = select_view
+                .iter()
+                .take_while(|(label, _)| label.starts_with(MARKED_PREFIX))
+                .count();
+            let marked: HashSet<String> = select_view
+                .iter()
+                .take(num_marked)
+                .map(|(label, _)| label[MARKED_PREFIX.len()..].to_string())
+                .collect();
+            for id in (num_marked..select_view.len()).rev() {
+                let _ = select_view.remove_item(id);
+            }
             for (label, value) in results {
-                select_view.add_item(label, value);
+                if !marked.contains(&label) {
+                    select_view.add_item(label, value);
+                }
             }
         });
     return found_opt.is_some();
@@ -326,32 +787,125 @@ pub fn new_dialog(text: &str) -> Dialog {
 
 pub type TextDialogView = TextView;
 
-pub fn new_text_dialog_view<F>(text: &str, name: &str, close_fn: F) -> Dialog
+/// Dialog with text that can be longer than the screen (e.g. histograms), so
+/// it is scrollable and can be searched. Matches of the search are highlighted
+/// and the first one is scrolled to, hitting enter jumps to the next one. Use
+/// `set_text_dialog_view` to update the text.
+pub fn new_text_dialog_view<F>(messages: &Messages, text: &str, name: &str, close_fn: F) -> Dialog
 where
     F: 'static + Fn(&mut Cursive),
 {
-    Dialog::around(TextView::new(text).with_name(name)).button("Close", close_fn)
-}
-
-/// Check if this is a view created by `new_histogram_view` with the given `name`
-pub fn is_text_dialog_view(view: &Box<dyn cursive::View>, name: &str) -> bool {
-    if let Some(dialog_view) = view.downcast_ref::<Dialog>() {
-        if let Some(named_view) = dialog_view
-            .get_content()
-            .downcast_ref::<cursive::views::NamedView<TextDialogView>>()
-        {
-            if named_view.name() == name {
-                return true;
-            }
+    let text_name = name.to_string();
+    let text_name_copy = name.to_string();
+    let edit_view = EditView::new()
+        .filler(" ")
+        .on_edit(move |siv, search, _| {
+            highlight_text_dialog_view(siv, &text_name, search);
+            scroll_to_match(siv, &text_name, search, 0);
+        })
+        .on_submit(move |siv, search| {
+            let next_line = match siv.find_name::<ScrollView<NamedView<TextDialogView>>>(&format!(
+                "scroll_{}",
+                text_name_copy
+            )) {
+                Some(scroll_view) => scroll_view.content_viewport().top() + 1,
+                None => return,
+            };
+            scroll_to_match(siv, &text_name_copy, search, next_line);
+        })
+        .with_name(format!("search_{}", name));
+
+    Dialog::around(
+        LinearLayout::vertical()
+            .child(
+                ScrollView::new(TextView::new(text).with_name(name))
+                    .scroll_x(true)
+                    .with_name(format!("scroll_{}", name)),
+            )
+            .child(
+                LinearLayout::horizontal()
+                    .child(TextView::new(messages.get("search.prompt")))
+                    .child(edit_view.full_width()),
+            ),
+    )
+    .button(messages.get("button.close"), close_fn)
+}
+
+/// Set text of a view created by `new_text_dialog_view`, keeping search
+/// matches highlighted
+pub fn set_text_dialog_view(siv: &mut Cursive, name: &str, text: &str) {
+    if let Some(mut text_view) = siv.find_name::<TextDialogView>(name) {
+        text_view.set_content(text);
+    }
+    let search = match siv.find_name::<EditView>(&format!("search_{}", name)) {
+        Some(edit_view) => edit_view.get_content(),
+        None => return,
+    };
+    highlight_text_dialog_view(siv, name, &search);
+}
+
+fn highlight_text_dialog_view(siv: &mut Cursive, name: &str, search: &str) {
+    let mut text_view = match siv.find_name::<TextDialogView>(name) {
+        Some(text_view) => text_view,
+        None => return,
+    };
+    let text = text_view.get_content().source().to_string();
+    let mut styled = StyledString::new();
+    let mut remaining = text.as_str();
+    if !search.is_empty() {
+        while let Some(index) = remaining.find(search) {
+            styled.append_plain(&remaining[..index]);
+            styled.append_styled(search, ColorStyle::highlight());
+            remaining = &remaining[index + search.len()..];
         }
     }
-    false
+    styled.append_plain(remaining);
+    text_view.set_content(styled);
+}
+
+/// Scroll to the first line at or after `from_line` containing `search`,
+/// wrapping around to the start
+fn scroll_to_match(siv: &mut Cursive, name: &str, search: &str, from_line: usize) {
+    if search.is_empty() {
+        return;
+    }
+    let text = match siv.find_name::<TextDialogView>(name) {
+        Some(text_view) => text_view.get_content().source().to_string(),
+        None => return,
+    };
+    let matches: Vec<usize> = text
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| line.contains(search))
+        .map(|(i, _)| i)
+        .collect();
+    let line = match matches
+        .iter()
+        .find(|&&i| i >= from_line)
+        .or(matches.first())
+    {
+        Some(&line) => line,
+        None => return,
+    };
+    if let Some(mut scroll_view) =
+        siv.find_name::<ScrollView<NamedView<TextDialogView>>>(&format!("scroll_{}", name))
+    {
+        scroll_view.set_offset(Vec2::new(0, line));
+    }
+}
+
+/// Check if this is a view created by `new_text_dialog_view` with the given
+/// `name`
+pub fn is_text_dialog_view(view: &mut Box<dyn cursive::View>, name: &str) -> bool {
+    let mut found = false;
+    view.call_on_any(&Selector::Name(name), &mut |_| found = true);
+    found
 }
 
-pub fn new_quit_dialog(text: &str) -> Dialog {
+pub fn new_quit_dialog(messages: &Messages, text: &str) -> Dialog {
     Dialog::text(text)
-        .button("Quit", Cursive::quit)
-        .button("Cancel", |siv| {
+        .button(messages.get("button.quit"), Cursive::quit)
+        .button(messages.get("button.cancel"), |siv| {
             siv.pop_layer();
         })
 }
@@ -368,6 +922,40 @@ where
     Dialog::around(edit_view).title(title)
 }
 
+/// Dialog to edit multi-line text (e.g. a program) with `submit_fn` called on
+/// the text when `submit_label` is pressed
+pub fn new_text_area_view<F>(
+    messages: &Messages,
+    title: &str,
+    name: &str,
+    content: &str,
+    submit_label: &str,
+    submit_fn: F,
+) -> Dialog
+where
+    F: Fn(&mut Cursive, &str) + 'static,
+{
+    let text_name = name.to_string();
+    Dialog::around(
+        TextArea::new()
+            .content(content)
+            .with_name(name)
+            .full_screen(),
+    )
+    .title(title)
+    .button(submit_label, move |siv| {
+        let text = siv
+            .call_on_name(&text_name, |view: &mut TextArea| {
+                view.get_content().to_string()
+            })
+            .unwrap();
+        submit_fn(siv, &text);
+    })
+    .button(messages.get("button.cancel"), |siv| {
+        siv.pop_layer();
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;